@@ -13,8 +13,8 @@ struct FibonacciRpcClient<'a> {
 }
 
 impl<'a> FibonacciRpcClient<'a> {
-    fn new(channel: &Channel) -> Result<FibonacciRpcClient> {
-        let exchange = Exchange::direct(&channel);
+    fn new(channel: &Channel) -> Result<FibonacciRpcClient<'_>> {
+        let exchange = Exchange::direct(channel);
 
         let queue = channel.queue_declare(
             "",
@@ -40,7 +40,7 @@ fn main() -> Result<()> {
 
                 // Sleep for n seconds, where n is the number of '.' chars in the body,
                 // before we ack the message.
-                #[allow(clippy::clippy::naive_bytecount)]
+                #[allow(clippy::naive_bytecount)]
                 let dits = delivery.body.iter().filter(|&&b| b == b'.').count();
                 thread::sleep(Duration::from_secs(dits as u64));
                 println!("({:>3}) ... done sleeping", i);
@@ -0,0 +1,597 @@
+use crate::errors::*;
+use crate::{AmqpProperties, AmqpValue, FieldTable};
+use amq_protocol::types::DecimalValue;
+use serde_json::{Map, Number, Value};
+use std::convert::TryFrom;
+
+// Tags for values whose type plain JSON cannot carry. A tagged value is an object with exactly one
+// key, the tag.
+const TABLE: &str = "$table";
+const SHORT_SHORT_INT: &str = "$short-short-int";
+const SHORT_SHORT_UINT: &str = "$short-short-uint";
+const SHORT_INT: &str = "$short-int";
+const SHORT_UINT: &str = "$short-uint";
+const LONG_INT: &str = "$long-int";
+const LONG_UINT: &str = "$long-uint";
+const FLOAT: &str = "$float";
+const DOUBLE: &str = "$double";
+const DECIMAL: &str = "$decimal";
+const TIMESTAMP: &str = "$timestamp";
+const BYTES: &str = "$bytes";
+
+/// Convert a field table (e.g., message headers) to JSON that
+/// [`field_table_from_json`](fn.field_table_from_json.html) converts back to an identical table.
+///
+/// Values JSON represents natively are written as such: `Void` as `null`, `Boolean` as a
+/// boolean, `LongString` as a string, `LongLongInt` as an integer, a finite `Double` as a
+/// floating point number, and arrays and tables as arrays and objects. Every other value is
+/// written as an object whose only key names its type:
+///
+/// * `ShortShortInt`, `ShortShortUInt`, `ShortInt`, `ShortUInt`, `LongInt`, and `LongUInt` as
+///   `{"$short-short-int": -1}`, `{"$short-short-uint": 1}`, `{"$short-int": -1}`,
+///   `{"$short-uint": 1}`, `{"$long-int": -1}`, and `{"$long-uint": 1}`.
+/// * `Float` as `{"$float": 1.5}`.
+/// * Non-finite `Float`s and `Double`s as `{"$float": "NaN"}`, `{"$double": "Infinity"}`,
+///   `{"$double": "-Infinity"}`, and so on.
+/// * `DecimalValue` as a string with as many digits after the point as its scale, e.g.
+///   `{"$decimal": "12.50"}`.
+/// * `Timestamp` in milliseconds since the Unix epoch, e.g. `{"$timestamp": 1600000000000}`.
+/// * `ByteArray` in standard base64 with padding, e.g. `{"$bytes": "AQID"}`.
+/// * A nested table with a key starting with `$` (which could otherwise be mistaken for one of
+///   these) as `{"$table": {...}}`.
+///
+/// Fails with [`JsonConversion`](enum.Error.html#variant.JsonConversion) only if a timestamp is
+/// too far in the future to be written in milliseconds.
+///
+/// This function is only available if amiquip is built with the `serde` feature.
+pub fn field_table_to_json(table: &FieldTable) -> Result<Value> {
+    let mut object = Map::new();
+    for (key, value) in table {
+        object.insert(key.clone(), value_to_json(value)?);
+    }
+    Ok(Value::Object(object))
+}
+
+/// Convert JSON written by [`field_table_to_json`](fn.field_table_to_json.html) back to a field
+/// table. Other JSON is accepted too: objects become tables and integers become `LongLongInt`s.
+///
+/// Fails with [`JsonConversion`](enum.Error.html#variant.JsonConversion) rather than change a
+/// value: for instance, on an integer above `i64::MAX`, a `$long-int` outside the 32-bit range, a
+/// `$float` that is not exactly an `f32`, or a `$timestamp` that is not a whole number of
+/// seconds.
+///
+/// This function is only available if amiquip is built with the `serde` feature.
+pub fn field_table_from_json(json: &Value) -> Result<FieldTable> {
+    match json {
+        Value::Object(object) => table_from_json(object, true),
+        other => invalid(format!("expected an object, got {}", other)),
+    }
+}
+
+/// Convert message properties to a JSON object with one key per property that is set, named as
+/// the property is in the AMQP specification (`content_type`, `headers`, `type`, and so on).
+/// `headers` is converted as by [`field_table_to_json`](fn.field_table_to_json.html), and
+/// `timestamp` is written in milliseconds since the Unix epoch; every other property is a string
+/// or an integer. [`properties_from_json`](fn.properties_from_json.html) converts the object back.
+///
+/// This function is only available if amiquip is built with the `serde` feature.
+///
+/// # Example
+///
+/// ```rust
+/// # use amiquip::{properties_from_json, properties_to_json, AmqpProperties, Result};
+/// # fn round_trip() -> Result<()> {
+/// let properties = AmqpProperties::default()
+///     .with_content_type("application/json".to_string())
+///     .with_timestamp(1_600_000_000);
+/// let json = properties_to_json(&properties)?;
+/// assert_eq!(
+///     json.to_string(),
+///     r#"{"content_type":"application/json","timestamp":1600000000000}"#
+/// );
+/// assert_eq!(properties_from_json(&json)?, properties);
+/// # Ok(())
+/// # }
+/// # round_trip().unwrap();
+/// ```
+pub fn properties_to_json(properties: &AmqpProperties) -> Result<Value> {
+    let mut object = Map::new();
+    let mut string = |name: &str, value: &Option<String>| {
+        if let Some(value) = value {
+            object.insert(name.to_string(), Value::String(value.clone()));
+        }
+    };
+    string("content_type", properties.content_type());
+    string("content_encoding", properties.content_encoding());
+    string("correlation_id", properties.correlation_id());
+    string("reply_to", properties.reply_to());
+    string("expiration", properties.expiration());
+    string("message_id", properties.message_id());
+    string("type", properties.type_());
+    string("user_id", properties.user_id());
+    string("app_id", properties.app_id());
+    string("cluster_id", properties.cluster_id());
+    if let Some(headers) = properties.headers() {
+        object.insert("headers".to_string(), field_table_to_json(headers)?);
+    }
+    if let Some(delivery_mode) = properties.delivery_mode() {
+        object.insert("delivery_mode".to_string(), (*delivery_mode).into());
+    }
+    if let Some(priority) = properties.priority() {
+        object.insert("priority".to_string(), (*priority).into());
+    }
+    if let Some(timestamp) = properties.timestamp() {
+        object.insert("timestamp".to_string(), timestamp_to_millis(*timestamp)?);
+    }
+    Ok(Value::Object(object))
+}
+
+/// Convert a JSON object written by [`properties_to_json`](fn.properties_to_json.html) back to
+/// message properties.
+///
+/// Fails with [`JsonConversion`](enum.Error.html#variant.JsonConversion) on keys that are not
+/// property names, values of the wrong type, or values that would have to change to fit (e.g., a
+/// `priority` above 255 or a `timestamp` that is not a whole number of seconds).
+///
+/// This function is only available if amiquip is built with the `serde` feature.
+pub fn properties_from_json(json: &Value) -> Result<AmqpProperties> {
+    let object = match json {
+        Value::Object(object) => object,
+        other => return invalid(format!("expected an object, got {}", other)),
+    };
+    let mut properties = AmqpProperties::default();
+    for (name, value) in object {
+        let string = || match value {
+            Value::String(s) => Ok(s.clone()),
+            other => invalid(format!("property {} must be a string, got {}", name, other)),
+        };
+        let octet = || match value.as_u64().map(u8::try_from) {
+            Some(Ok(n)) => Ok(n),
+            _ => invalid(format!(
+                "property {} must be an integer from 0 to 255, got {}",
+                name, value
+            )),
+        };
+        properties = match name.as_str() {
+            "content_type" => properties.with_content_type(string()?),
+            "content_encoding" => properties.with_content_encoding(string()?),
+            "headers" => properties.with_headers(field_table_from_json(value)?),
+            "delivery_mode" => properties.with_delivery_mode(octet()?),
+            "priority" => properties.with_priority(octet()?),
+            "correlation_id" => properties.with_correlation_id(string()?),
+            "reply_to" => properties.with_reply_to(string()?),
+            "expiration" => properties.with_expiration(string()?),
+            "message_id" => properties.with_message_id(string()?),
+            "timestamp" => properties.with_timestamp(timestamp_from_millis(value)?),
+            "type" => properties.with_type_(string()?),
+            "user_id" => properties.with_user_id(string()?),
+            "app_id" => properties.with_app_id(string()?),
+            "cluster_id" => properties.with_cluster_id(string()?),
+            other => return invalid(format!("unknown property {:?}", other)),
+        };
+    }
+    Ok(properties)
+}
+
+fn invalid<T>(message: String) -> Result<T> {
+    JsonConversionSnafu { message }.fail()
+}
+
+fn tagged<V: Into<Value>>(tag: &str, value: V) -> Value {
+    let mut object = Map::new();
+    object.insert(tag.to_string(), value.into());
+    Value::Object(object)
+}
+
+fn float_to_json(tag: &str, f: f64) -> Value {
+    match Number::from_f64(f) {
+        Some(n) => tagged(tag, n),
+        None if f.is_nan() => tagged(tag, "NaN"),
+        None if f > 0.0 => tagged(tag, "Infinity"),
+        None => tagged(tag, "-Infinity"),
+    }
+}
+
+fn timestamp_to_millis(secs: u64) -> Result<Value> {
+    match secs.checked_mul(1000) {
+        Some(millis) => Ok(millis.into()),
+        None => invalid(format!(
+            "timestamp {} is too large to write in milliseconds",
+            secs
+        )),
+    }
+}
+
+fn timestamp_from_millis(json: &Value) -> Result<u64> {
+    match json.as_u64() {
+        Some(millis) if millis % 1000 == 0 => Ok(millis / 1000),
+        _ => invalid(format!(
+            "timestamp must be a non-negative whole number of seconds in milliseconds, got {}",
+            json
+        )),
+    }
+}
+
+fn decimal_to_string(decimal: &DecimalValue) -> String {
+    let digits = format!(
+        "{:0width$}",
+        decimal.value,
+        width = usize::from(decimal.scale) + 1
+    );
+    let (whole, fraction) = digits.split_at(digits.len() - usize::from(decimal.scale));
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+fn decimal_from_string(s: &str) -> Option<DecimalValue> {
+    let (whole, fraction) = match s.find('.') {
+        Some(point) => (&s[..point], &s[point + 1..]),
+        None => (s, ""),
+    };
+    if whole.is_empty()
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+    let scale = u8::try_from(fraction.len()).ok()?;
+    let value = format!("{}{}", whole, fraction).parse().ok()?;
+    Some(DecimalValue { scale, value })
+}
+
+fn value_to_json(value: &AmqpValue) -> Result<Value> {
+    Ok(match value {
+        AmqpValue::Void => Value::Null,
+        AmqpValue::Boolean(b) => Value::Bool(*b),
+        AmqpValue::LongString(s) => Value::String(s.clone()),
+        AmqpValue::LongLongInt(n) => (*n).into(),
+        AmqpValue::ShortShortInt(n) => tagged(SHORT_SHORT_INT, *n),
+        AmqpValue::ShortShortUInt(n) => tagged(SHORT_SHORT_UINT, *n),
+        AmqpValue::ShortInt(n) => tagged(SHORT_INT, *n),
+        AmqpValue::ShortUInt(n) => tagged(SHORT_UINT, *n),
+        AmqpValue::LongInt(n) => tagged(LONG_INT, *n),
+        AmqpValue::LongUInt(n) => tagged(LONG_UINT, *n),
+        AmqpValue::Float(f) => float_to_json(FLOAT, f64::from(*f)),
+        AmqpValue::Double(f) => match Number::from_f64(*f) {
+            Some(n) => Value::Number(n),
+            None => float_to_json(DOUBLE, *f),
+        },
+        AmqpValue::DecimalValue(decimal) => tagged(DECIMAL, decimal_to_string(decimal)),
+        AmqpValue::Timestamp(secs) => tagged(TIMESTAMP, timestamp_to_millis(*secs)?),
+        AmqpValue::ByteArray(bytes) => tagged(BYTES, base64::encode(bytes)),
+        AmqpValue::FieldArray(values) => {
+            Value::Array(values.iter().map(value_to_json).collect::<Result<_>>()?)
+        }
+        AmqpValue::FieldTable(table) => {
+            let json = field_table_to_json(table)?;
+            if table.keys().any(|key| key.starts_with('$')) {
+                tagged(TABLE, json)
+            } else {
+                json
+            }
+        }
+    })
+}
+
+// Plain JSON for formats that have no type tags, such as RabbitMQ's definitions files. Integers of
+// every width and timestamps (in seconds) become numbers, decimals and floats become possibly
+// inexact numbers (`null` if not finite), and byte arrays become arrays of numbers.
+pub(crate) fn plain_table_to_json(table: &FieldTable) -> Value {
+    Value::Object(
+        table
+            .iter()
+            .map(|(key, value)| (key.clone(), plain_value_to_json(value)))
+            .collect(),
+    )
+}
+
+fn plain_value_to_json(value: &AmqpValue) -> Value {
+    let float = |f: f64| Number::from_f64(f).map_or(Value::Null, Value::Number);
+    match value {
+        AmqpValue::Void => Value::Null,
+        AmqpValue::Boolean(b) => (*b).into(),
+        AmqpValue::LongString(s) => s.clone().into(),
+        AmqpValue::LongLongInt(n) => (*n).into(),
+        AmqpValue::ShortShortInt(n) => (*n).into(),
+        AmqpValue::ShortShortUInt(n) => (*n).into(),
+        AmqpValue::ShortInt(n) => (*n).into(),
+        AmqpValue::ShortUInt(n) => (*n).into(),
+        AmqpValue::LongInt(n) => (*n).into(),
+        AmqpValue::LongUInt(n) => (*n).into(),
+        AmqpValue::Float(f) => float(f64::from(*f)),
+        AmqpValue::Double(f) => float(*f),
+        AmqpValue::DecimalValue(d) => float(f64::from(d.value) / 10f64.powi(i32::from(d.scale))),
+        AmqpValue::Timestamp(secs) => (*secs).into(),
+        AmqpValue::ByteArray(bytes) => bytes.clone().into(),
+        AmqpValue::FieldArray(values) => {
+            Value::Array(values.iter().map(plain_value_to_json).collect())
+        }
+        AmqpValue::FieldTable(table) => plain_table_to_json(table),
+    }
+}
+
+// The inverse of `plain_value_to_json` as far as it goes: objects are always tables, and numbers
+// are `LongLongInt`s or `Double`s.
+pub(crate) fn plain_value_from_json(json: &Value) -> Result<AmqpValue> {
+    value_from_json(json, false)
+}
+
+// With `tags`, an object with a single key starting with `$` is a tagged value rather than a
+// table.
+fn table_from_json(object: &Map<String, Value>, tags: bool) -> Result<FieldTable> {
+    let mut table = FieldTable::new();
+    for (key, value) in object {
+        table.insert(key.clone(), value_from_json(value, tags)?);
+    }
+    Ok(table)
+}
+
+fn value_from_json(json: &Value, tags: bool) -> Result<AmqpValue> {
+    Ok(match json {
+        Value::Null => AmqpValue::Void,
+        Value::Bool(b) => AmqpValue::Boolean(*b),
+        Value::String(s) => AmqpValue::LongString(s.clone()),
+        Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(n), _) => AmqpValue::LongLongInt(n),
+            (None, _) if n.is_u64() => return invalid(format!("integer {} is out of range", n)),
+            (None, Some(f)) => AmqpValue::Double(f),
+            (None, None) => return invalid(format!("{} is not a number", n)),
+        },
+        Value::Array(values) => AmqpValue::FieldArray(
+            values
+                .iter()
+                .map(|value| value_from_json(value, tags))
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(object) => match single_tag(object).filter(|_| tags) {
+            Some((tag, value)) => tagged_from_json(tag, value)?,
+            None => AmqpValue::FieldTable(table_from_json(object, tags)?),
+        },
+    })
+}
+
+fn single_tag(object: &Map<String, Value>) -> Option<(&str, &Value)> {
+    let mut entries = object.iter();
+    match (entries.next(), entries.next()) {
+        (Some((tag, value)), None) if tag.starts_with('$') => Some((tag, value)),
+        _ => None,
+    }
+}
+
+fn tagged_from_json(tag: &str, json: &Value) -> Result<AmqpValue> {
+    fn integer<T: TryFrom<i64>>(tag: &str, json: &Value) -> Result<T> {
+        match json.as_i64().map(T::try_from) {
+            Some(Ok(n)) => Ok(n),
+            _ => invalid(format!("{} is out of range for {}", json, tag)),
+        }
+    }
+    fn float(tag: &str, json: &Value) -> Result<f64> {
+        match json {
+            Value::Number(n) => n.as_f64(),
+            Value::String(s) if s == "NaN" => Some(f64::NAN),
+            Value::String(s) if s == "Infinity" => Some(f64::INFINITY),
+            Value::String(s) if s == "-Infinity" => Some(f64::NEG_INFINITY),
+            _ => None,
+        }
+        .map_or_else(|| invalid(format!("{} is not a valid {}", json, tag)), Ok)
+    }
+    Ok(match tag {
+        TABLE => match json {
+            Value::Object(object) => AmqpValue::FieldTable(table_from_json(object, true)?),
+            other => return invalid(format!("{} is not a valid {}", other, tag)),
+        },
+        SHORT_SHORT_INT => AmqpValue::ShortShortInt(integer(tag, json)?),
+        SHORT_SHORT_UINT => AmqpValue::ShortShortUInt(integer(tag, json)?),
+        SHORT_INT => AmqpValue::ShortInt(integer(tag, json)?),
+        SHORT_UINT => AmqpValue::ShortUInt(integer(tag, json)?),
+        LONG_INT => AmqpValue::LongInt(integer(tag, json)?),
+        LONG_UINT => AmqpValue::LongUInt(integer(tag, json)?),
+        FLOAT => {
+            let f = float(tag, json)?;
+            let narrowed = f as f32;
+            // NaN is the only value not equal to itself, and it narrows to NaN.
+            if f64::from(narrowed) != f && !f.is_nan() {
+                return invalid(format!("{} cannot be represented exactly as {}", json, tag));
+            }
+            AmqpValue::Float(narrowed)
+        }
+        DOUBLE => AmqpValue::Double(float(tag, json)?),
+        DECIMAL => match json.as_str().and_then(decimal_from_string) {
+            Some(decimal) => AmqpValue::DecimalValue(decimal),
+            None => return invalid(format!("{} is not a valid {}", json, tag)),
+        },
+        TIMESTAMP => AmqpValue::Timestamp(timestamp_from_millis(json)?),
+        BYTES => match json.as_str().map(base64::decode) {
+            Some(Ok(bytes)) => AmqpValue::ByteArray(bytes),
+            _ => return invalid(format!("{} is not valid base64 for {}", json, tag)),
+        },
+        other => return invalid(format!("unknown type tag {:?}", other)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use serde_json::json;
+
+    fn every_type() -> FieldTable {
+        let mut nested = FieldTable::new();
+        nested.insert("$ref".to_string(), AmqpValue::LongString("x".to_string()));
+        let mut plain = FieldTable::new();
+        plain.insert(
+            "region".to_string(),
+            AmqpValue::LongString("eu".to_string()),
+        );
+
+        let values = vec![
+            ("void", AmqpValue::Void),
+            ("bool", AmqpValue::Boolean(true)),
+            ("string", AmqpValue::LongString("hello".to_string())),
+            ("i8", AmqpValue::ShortShortInt(-8)),
+            ("u8", AmqpValue::ShortShortUInt(8)),
+            ("i16", AmqpValue::ShortInt(-16)),
+            ("u16", AmqpValue::ShortUInt(16)),
+            ("i32", AmqpValue::LongInt(-32)),
+            ("u32", AmqpValue::LongUInt(32)),
+            ("i64", AmqpValue::LongLongInt(-64)),
+            ("f32", AmqpValue::Float(1.5)),
+            ("f64", AmqpValue::Double(0.25)),
+            ("nan", AmqpValue::Double(f64::NAN)),
+            ("inf", AmqpValue::Float(f32::NEG_INFINITY)),
+            (
+                "decimal",
+                AmqpValue::DecimalValue(DecimalValue {
+                    scale: 3,
+                    value: 1250,
+                }),
+            ),
+            ("time", AmqpValue::Timestamp(1_600_000_000)),
+            ("bytes", AmqpValue::ByteArray(vec![1, 2, 3])),
+            (
+                "array",
+                AmqpValue::FieldArray(vec![AmqpValue::LongInt(1), AmqpValue::Void]),
+            ),
+            ("nested", AmqpValue::FieldTable(nested)),
+            ("plain", AmqpValue::FieldTable(plain)),
+        ];
+        values
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect()
+    }
+
+    // NaN is not equal to itself, so compare tables through their debug output.
+    fn assert_same(a: &FieldTable, b: &FieldTable) {
+        assert_eq!(format!("{:?}", a), format!("{:?}", b));
+    }
+
+    #[test]
+    fn field_table_round_trips_through_json_text() {
+        let table = every_type();
+        let text = field_table_to_json(&table).unwrap().to_string();
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        assert_same(&field_table_from_json(&parsed).unwrap(), &table);
+    }
+
+    #[test]
+    fn amqp_specific_types_are_written_as_documented() {
+        let json = field_table_to_json(&every_type()).unwrap();
+        assert_eq!(json["string"], json!("hello"));
+        assert_eq!(json["i64"], json!(-64));
+        assert_eq!(json["i32"], json!({"$long-int": -32}));
+        assert_eq!(json["f64"], json!(0.25));
+        assert_eq!(json["nan"], json!({"$double": "NaN"}));
+        assert_eq!(json["inf"], json!({"$float": "-Infinity"}));
+        assert_eq!(json["decimal"], json!({"$decimal": "1.250"}));
+        assert_eq!(json["time"], json!({"$timestamp": 1_600_000_000_000u64}));
+        assert_eq!(json["bytes"], json!({"$bytes": "AQID"}));
+        assert_eq!(json["nested"], json!({"$table": {"$ref": "x"}}));
+        assert_eq!(json["plain"], json!({"region": "eu"}));
+    }
+
+    #[test]
+    fn decimals_keep_their_scale() {
+        for &(scale, value, text) in &[(0, 7, "7"), (2, 5, "0.05"), (2, 1200, "12.00")] {
+            let decimal = DecimalValue { scale, value };
+            assert_eq!(decimal_to_string(&decimal), text);
+            assert_eq!(decimal_from_string(text), Some(decimal));
+        }
+        assert_eq!(decimal_from_string("-1.5"), None);
+        assert_eq!(decimal_from_string(".5"), None);
+    }
+
+    #[test]
+    fn refuses_lossy_conversions() {
+        for json in &[
+            json!({"big": u64::MAX}),
+            json!({"v": {"$long-int": 1u64 << 40}}),
+            json!({"v": {"$short-short-uint": -1}}),
+            json!({"v": {"$float": 0.1}}),
+            json!({"v": {"$timestamp": 1500}}),
+            json!({"v": {"$decimal": "1e5"}}),
+            json!({"v": {"$bytes": "not base64!"}}),
+            json!({"v": {"$unknown": 1}}),
+            json!([1, 2]),
+        ] {
+            match field_table_from_json(json) {
+                Err(Error::JsonConversion { .. }) => (),
+                other => panic!("{} converted to {:?}", json, other),
+            }
+        }
+    }
+
+    #[test]
+    fn plain_json_has_no_tags() {
+        let json = plain_table_to_json(&every_type());
+        assert_eq!(json["i32"], json!(-32));
+        assert_eq!(json["nan"], json!(null));
+        assert_eq!(json["decimal"], json!(1.25));
+        assert_eq!(json["time"], json!(1_600_000_000));
+        assert_eq!(json["bytes"], json!([1, 2, 3]));
+        assert_eq!(json["nested"], json!({"$ref": "x"}));
+
+        let mut table = FieldTable::new();
+        table.insert(
+            "$bytes".to_string(),
+            AmqpValue::LongString("AQID".to_string()),
+        );
+        assert_eq!(
+            plain_value_from_json(&json!({"$bytes": "AQID"})).unwrap(),
+            AmqpValue::FieldTable(table)
+        );
+    }
+
+    #[test]
+    fn properties_round_trip() {
+        let mut headers = FieldTable::new();
+        headers.insert("attempt".to_string(), AmqpValue::LongInt(2));
+        let properties = AmqpProperties::default()
+            .with_content_type("text/plain".to_string())
+            .with_content_encoding("gzip".to_string())
+            .with_headers(headers)
+            .with_delivery_mode(2)
+            .with_priority(5)
+            .with_correlation_id("corr".to_string())
+            .with_reply_to("replies".to_string())
+            .with_expiration("60000".to_string())
+            .with_message_id("id-1".to_string())
+            .with_timestamp(1_600_000_000)
+            .with_type_("order.created".to_string())
+            .with_user_id("guest".to_string())
+            .with_app_id("shop".to_string())
+            .with_cluster_id("c1".to_string());
+        let json = properties_to_json(&properties).unwrap();
+        assert_eq!(json["headers"], json!({"attempt": {"$long-int": 2}}));
+        assert_eq!(json["timestamp"], json!(1_600_000_000_000u64));
+        assert_eq!(json["type"], json!("order.created"));
+        assert_eq!(properties_from_json(&json).unwrap(), properties);
+
+        assert_eq!(
+            properties_to_json(&AmqpProperties::default()).unwrap(),
+            json!({})
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_properties() {
+        for json in &[
+            json!({"priority": 300}),
+            json!({"delivery_mode": "persistent"}),
+            json!({"content_type": 1}),
+            json!({"contentType": "text/plain"}),
+            json!({"timestamp": 1_600_000_000_001u64}),
+            json!("text/plain"),
+        ] {
+            match properties_from_json(json) {
+                Err(Error::JsonConversion { .. }) => (),
+                other => panic!("{} converted to {:?}", json, other),
+            }
+        }
+    }
+}
@@ -1,9 +1,16 @@
+use crate::errors::*;
+
+#[cfg(feature = "scram")]
+mod scram;
+
+#[cfg(feature = "scram")]
+pub use scram::ScramSha256;
+
 /// A trait encapsulating the operations required to authenticate to an AMQP server.
 ///
-/// # Warning
-///
-/// SASL mechanisms that require AMQP secure / secure-ok exchanges are currently
-/// not supported.
+/// Mechanisms that need more than one round-trip implement
+/// [`respond_to_challenge`](#method.respond_to_challenge), which is called once for each AMQP
+/// `connection.secure` challenge the server sends before it accepts or rejects the credentials.
 pub trait Sasl: Default + Clone + Send + 'static {
     /// The SASL mechanism to report. The server must support this mechanism
     /// for authentication to succeed.
@@ -11,6 +18,24 @@ pub trait Sasl: Default + Clone + Send + 'static {
 
     /// The response body to send along with the mechanism.
     fn response(&self) -> String;
+
+    /// The response to send to a `connection.secure` challenge from the server. The response
+    /// must be valid UTF-8.
+    ///
+    /// The default implementation, used by the built-in mechanisms (which never expect a
+    /// challenge), returns
+    /// [`Error::SaslSecureNotSupported`](enum.Error.html#variant.SaslSecureNotSupported).
+    fn respond_to_challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let _ = challenge;
+        SaslSecureNotSupportedSnafu.fail()
+    }
+
+    /// Called when the server accepts our credentials by moving on to connection tuning. Mechanisms
+    /// that authenticate the server as well as the client can fail here if the server never
+    /// proved its identity. The default implementation does nothing.
+    fn verify_server(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 /// Built-in authentication mechanisms.
@@ -0,0 +1,305 @@
+use crate::errors::*;
+use crate::Sasl;
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::fmt;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// base64("n,,"): no channel binding, no authorization identity.
+const CHANNEL_BINDING: &str = "biws";
+
+/// SCRAM-SHA-256 authentication ([RFC 7677](https://tools.ietf.org/html/rfc7677)).
+///
+/// Unlike PLAIN, SCRAM never sends the password to the server, and the server has to prove that
+/// it knows the password too: connecting fails with
+/// [`Error::ScramServerSignatureMismatch`](enum.Error.html#variant.ScramServerSignatureMismatch)
+/// if it cannot. Wrong credentials are reported as
+/// [`Error::InvalidCredentials`](enum.Error.html#variant.InvalidCredentials), as for PLAIN.
+///
+/// The password is used as-is; it is not normalized with SASLprep, so passwords containing
+/// non-ASCII characters may not be accepted.
+///
+/// The [`default`](#impl-Default) implementation uses the username and password `guest`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use amiquip::{Connection, ConnectionOptions, ConnectionTuning, Result, ScramSha256};
+/// use mio::net::TcpStream;
+///
+/// # fn main() -> Result<()> {
+/// let stream = TcpStream::connect("127.0.0.1:5672".parse().unwrap()).unwrap();
+/// let options = ConnectionOptions::default().auth(ScramSha256::new("user", "pencil"));
+/// let tuning = ConnectionTuning::default();
+/// let connection = Connection::insecure_open_stream(stream, options, tuning)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ScramSha256 {
+    username: String,
+    password: String,
+    // Set when the client-first message is sent. `Sasl::response` only gets `&self`, and each
+    // connection must use a fresh nonce, so it cannot be chosen when the mechanism is created.
+    client_first_bare: RefCell<Option<ClientFirst>>,
+    state: State,
+}
+
+#[derive(Clone)]
+struct ClientFirst {
+    nonce: String,
+    message: String,
+}
+
+#[derive(Clone)]
+enum State {
+    Start,
+    ClientFinalSent { server_signature: Vec<u8> },
+    Verified,
+}
+
+impl ScramSha256 {
+    /// Authenticate as `username` with `password`.
+    pub fn new<U: Into<String>, P: Into<String>>(username: U, password: P) -> ScramSha256 {
+        ScramSha256 {
+            username: username.into(),
+            password: password.into(),
+            client_first_bare: RefCell::new(None),
+            state: State::Start,
+        }
+    }
+
+    fn client_first(&self, nonce: String) -> String {
+        let message = format!("n={},r={}", escape_username(&self.username), nonce);
+        let response = format!("n,,{}", message);
+        *self.client_first_bare.borrow_mut() = Some(ClientFirst { nonce, message });
+        response
+    }
+
+    fn client_final(&mut self, server_first: &str) -> Result<Vec<u8>> {
+        let client_first = match self.client_first_bare.get_mut() {
+            Some(client_first) => client_first,
+            None => return protocol_error("challenge received before client-first message"),
+        };
+
+        let attrs = parse_attributes(server_first)?;
+        let nonce = attribute(&attrs, 'r')?;
+        let salt = match base64::decode(attribute(&attrs, 's')?) {
+            Ok(salt) => salt,
+            Err(_) => return protocol_error("invalid salt"),
+        };
+        let iterations = match attribute(&attrs, 'i')?.parse::<u32>() {
+            Ok(iterations) if iterations > 0 => iterations,
+            _ => return protocol_error("invalid iteration count"),
+        };
+        if !nonce.starts_with(&client_first.nonce) || nonce.len() == client_first.nonce.len() {
+            return protocol_error("server nonce does not extend client nonce");
+        }
+
+        let mut salted_password = [0; 32];
+        pbkdf2::pbkdf2::<HmacSha256>(
+            self.password.as_bytes(),
+            &salt,
+            iterations,
+            &mut salted_password,
+        );
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let server_key = hmac(&salted_password, b"Server Key");
+
+        let without_proof = format!("c={},r={}", CHANNEL_BINDING, nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            client_first.message, server_first, without_proof
+        );
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let proof = client_key
+            .iter()
+            .zip(&client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect::<Vec<_>>();
+
+        self.state = State::ClientFinalSent {
+            server_signature: hmac(&server_key, auth_message.as_bytes()),
+        };
+        Ok(format!("{},p={}", without_proof, base64::encode(&proof)).into_bytes())
+    }
+}
+
+impl Default for ScramSha256 {
+    fn default() -> ScramSha256 {
+        ScramSha256::new("guest", "guest")
+    }
+}
+
+impl fmt::Debug for ScramSha256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ScramSha256")
+            .field("username", &self.username)
+            .finish()
+    }
+}
+
+impl Sasl for ScramSha256 {
+    fn mechanism(&self) -> String {
+        "SCRAM-SHA-256".to_string()
+    }
+
+    fn response(&self) -> String {
+        let mut nonce = [0; 18];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        self.client_first(base64::encode(nonce))
+    }
+
+    fn respond_to_challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let challenge = match std::str::from_utf8(challenge) {
+            Ok(challenge) => challenge,
+            Err(_) => return protocol_error("challenge is not valid UTF-8"),
+        };
+        match &self.state {
+            State::Start => self.client_final(challenge),
+            State::ClientFinalSent { server_signature } => {
+                let attrs = parse_attributes(challenge)?;
+                let verifier = match base64::decode(attribute(&attrs, 'v')?) {
+                    Ok(verifier) => verifier,
+                    Err(_) => return protocol_error("invalid server signature"),
+                };
+                if verifier != *server_signature {
+                    return ScramServerSignatureMismatchSnafu.fail();
+                }
+                self.state = State::Verified;
+                Ok(Vec::new())
+            }
+            State::Verified => protocol_error("unexpected challenge after server-final message"),
+        }
+    }
+
+    fn verify_server(&self) -> Result<()> {
+        match self.state {
+            State::Verified => Ok(()),
+            _ => protocol_error("server accepted credentials without sending its signature"),
+        }
+    }
+}
+
+fn protocol_error<T>(message: &str) -> Result<T> {
+    ScramProtocolSnafu { message }.fail()
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// Usernames are sent as `saslname`s, in which `=` and `,` must be escaped.
+fn escape_username(username: &str) -> String {
+    username.replace('=', "=3D").replace(',', "=2C")
+}
+
+// Split a server message into its `name=value` attributes. A server error (`e=...`) is returned
+// as an error.
+fn parse_attributes(message: &str) -> Result<Vec<(char, &str)>> {
+    let mut attrs = Vec::new();
+    for attr in message.split(',') {
+        let mut chars = attr.chars();
+        match (chars.next(), chars.next()) {
+            (Some('e'), Some('=')) => {
+                return ScramProtocolSnafu {
+                    message: format!("server error: {}", &attr[2..]),
+                }
+                .fail()
+            }
+            (Some(name), Some('=')) if name.is_ascii_alphabetic() => attrs.push((name, &attr[2..])),
+            _ => return protocol_error("malformed server message"),
+        }
+    }
+    Ok(attrs)
+}
+
+fn attribute<'a>(attrs: &[(char, &'a str)], name: char) -> Result<&'a str> {
+    match attrs.iter().find(|(n, _)| *n == name) {
+        Some((_, value)) => Ok(value),
+        None => ScramProtocolSnafu {
+            message: format!("server message missing `{}` attribute", name),
+        }
+        .fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example exchange from RFC 7677, section 3.
+    const CLIENT_NONCE: &str = "rOprNGfwEbeRWgbNEkqO";
+    const SERVER_FIRST: &str = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+
+    fn started() -> ScramSha256 {
+        let scram = ScramSha256::new("user", "pencil");
+        assert_eq!(
+            scram.client_first(CLIENT_NONCE.to_string()),
+            "n,,n=user,r=rOprNGfwEbeRWgbNEkqO"
+        );
+        // Connections clone their options when the handshake moves past start.
+        scram.clone()
+    }
+
+    #[test]
+    fn rfc_7677_exchange() {
+        let mut scram = started();
+        let client_final = scram.respond_to_challenge(SERVER_FIRST.as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(client_final).unwrap(), CLIENT_FINAL);
+        assert!(scram.verify_server().is_err());
+        let empty = scram.respond_to_challenge(SERVER_FINAL.as_bytes()).unwrap();
+        assert!(empty.is_empty());
+        scram.verify_server().unwrap();
+    }
+
+    #[test]
+    fn wrong_server_signature() {
+        let mut scram = started();
+        scram.respond_to_challenge(SERVER_FIRST.as_bytes()).unwrap();
+        match scram.respond_to_challenge(b"v=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=") {
+            Err(Error::ScramServerSignatureMismatch) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn server_nonce_must_extend_client_nonce() {
+        let mut scram = started();
+        match scram.respond_to_challenge(b"r=somethingelse,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096") {
+            Err(Error::ScramProtocol { .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn server_error_is_reported() {
+        let mut scram = started();
+        scram.respond_to_challenge(SERVER_FIRST.as_bytes()).unwrap();
+        match scram.respond_to_challenge(b"e=invalid-proof") {
+            Err(Error::ScramProtocol { message }) => assert!(message.contains("invalid-proof")),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn usernames_are_escaped() {
+        assert_eq!(escape_username("a=b,c"), "a=3Db=2Cc");
+    }
+
+    #[test]
+    fn each_connection_uses_a_fresh_nonce() {
+        let scram = ScramSha256::new("user", "pencil");
+        assert_ne!(scram.response(), scram.clone().response());
+    }
+}
@@ -1,15 +1,20 @@
-use crate::io_loop::ChannelHandle;
+use crate::exchange::describe_delayed_declare_error;
+use crate::io_loop::{ChannelHandle, ReturnHandler};
+use crate::logging::enter_span;
+use crate::properties::check_expiration;
+use crate::publish_defaults::SharedPublishDefaults;
+use crate::queue::describe_declare_error;
 use crate::serialize::{IntoAmqpClass, TryFromAmqpClass};
+use crate::tag::SeqNo;
+use crate::topology::TopologyStep;
 use crate::{
-    Confirm, Consumer, ConsumerOptions, Delivery, Exchange, ExchangeDeclareOptions, ExchangeType,
-    Get, Publish, Queue, QueueDeclareOptions, QueueDeleteOptions, Result, Return,
+    AmqpProperties, AmqpReplyCode, BindingDestination, ChannelStats, Confirm, ConfirmOutcome,
+    Error, Exchange, ExchangeDeclareOptions, ExchangeType, Publish, PublishDefaults, PublishResult,
+    Publisher, Queue, QueueDeclareOptions, QueueDeleteOptions, QueueInfo, Result, Return, Topology,
+    TopologyMismatch, TopologyReport,
 };
 use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
-use amq_protocol::protocol::basic::Get as AmqpGet;
 use amq_protocol::protocol::basic::Publish as AmqpPublish;
-use amq_protocol::protocol::basic::{
-    Ack, Cancel, CancelOk, Consume, Nack, Qos, QosOk, Recover, RecoverOk, Reject,
-};
 use amq_protocol::protocol::confirm::AMQPMethod as AmqpConfirm;
 use amq_protocol::protocol::confirm::Select as ConfirmSelect;
 use amq_protocol::protocol::confirm::SelectOk as ConfirmSelectOk;
@@ -30,10 +35,34 @@ use amq_protocol::protocol::queue::Purge as QueuePurge;
 use amq_protocol::protocol::queue::PurgeOk as QueuePurgeOk;
 use amq_protocol::protocol::queue::Unbind as QueueUnbind;
 use amq_protocol::protocol::queue::UnbindOk as QueueUnbindOk;
+use amq_protocol::protocol::tx::AMQPMethod as AmqpTx;
+use amq_protocol::protocol::tx::{
+    Commit as TxCommit, CommitOk as TxCommitOk, Rollback as TxRollback, RollbackOk as TxRollbackOk,
+    Select as TxSelect, SelectOk as TxSelectOk,
+};
 use amq_protocol::types::FieldTable;
-use crossbeam_channel::Receiver;
-use std::cell::RefCell;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use std::cell::{Cell, RefCell};
 use std::fmt::Debug;
+use std::io::Read;
+use std::result::Result as StdResult;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "consume")]
+use crate::consumer::ConsumerBuffer;
+#[cfg(feature = "consume")]
+use crate::dispatcher::{Dispatcher, Registration};
+#[cfg(feature = "consume")]
+use crate::{Acker, CallbackConsumer, Consumer, ConsumerMessage, ConsumerOptions, Delivery, Get};
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::Get as AmqpGet;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::{
+    Ack, Cancel, CancelOk, Nack, Qos, QosOk, Recover, RecoverOk, Reject,
+};
+#[cfg(feature = "consume")]
+use std::sync::Arc;
 
 /// Handle for an AMQP channel.
 ///
@@ -51,16 +80,18 @@ use std::fmt::Debug;
 /// to also grow in an unbounded way. There are two ways an unbounded in-memory channel gets
 /// created:
 ///
-/// * Creating a consumer; the channel for delivering messages is unbounded.
+/// * Creating a consumer; the channel for delivering messages is unbounded unless
+///   [`ConsumerOptions::buffer_bound`](struct.ConsumerOptions.html#structfield.buffer_bound) is
+///   set.
 /// * Attaching a [returned message listener](#method.listen_for_returns); the channel for
-/// delivering returned messages is unbounded.
+///   delivering returned messages is unbounded.
 ///
 /// To control the memory usage of consumers, avoid the use of `no_ack` consumers. If the consumer
 /// is set up to acknowledge messages, the server will not send messages until previous messages
 /// have been acknowledged, and you can use [`qos`](#method.qos) to control how many outstanding
-/// unacknowledged messages are allowed. `no_ack` consumers do provide higher performance, but
-/// amiquip does not have a mechanism for avoiding unbounded growth on the consumer channel if the
-/// consumer is not processing messages fast enough to keep up with deliveries from the server.
+/// unacknowledged messages are allowed. `no_ack` consumers do provide higher performance; to keep
+/// one that cannot keep up with deliveries from the server from growing without limit, bound its
+/// buffer and choose an [`OverflowPolicy`](enum.OverflowPolicy.html).
 ///
 /// There is no built-in mechanism to limit memory growth on a channel's returned message listener.
 /// If the returned message listener cannot keep up with the rate of returned messages, consider
@@ -74,40 +105,92 @@ use std::fmt::Debug;
 /// [`Connection::close`](struct.Connection.html#method.close) for a strategy to deal with this.
 pub struct Channel {
     inner: RefCell<ChannelHandle>,
+    #[cfg(feature = "consume")]
+    dispatcher: Arc<Dispatcher>,
+    // The sequence number the server will assign our next publish, once confirms are enabled.
+    next_seq_no: Cell<Option<SeqNo>>,
+    transactional: Cell<bool>,
+    publish_defaults: SharedPublishDefaults,
     closed: bool,
 }
 
+// Rolls back the transaction `Channel::with_transaction` is running if its closure panics.
+struct RollbackOnPanic<'a>(&'a Channel);
+
+impl Drop for RollbackOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.0.tx_rollback();
+        }
+    }
+}
+
 impl Drop for Channel {
     fn drop(&mut self) {
-        let _ = self.close_impl();
+        if !self.closed {
+            self.closed = true;
+            self.inner.get_mut().close_on_drop();
+        }
     }
 }
 
 impl Channel {
-    pub(crate) fn new(handle: ChannelHandle) -> Channel {
+    pub(crate) fn new(
+        handle: ChannelHandle,
+        #[cfg(feature = "consume")] dispatcher: Arc<Dispatcher>,
+    ) -> Channel {
         Channel {
             inner: RefCell::new(handle),
+            #[cfg(feature = "consume")]
+            dispatcher,
+            next_seq_no: Cell::new(None),
+            transactional: Cell::new(false),
+            publish_defaults: SharedPublishDefaults::default(),
             closed: false,
         }
     }
 
     /// Synchronously close this channel. This method blocks until the server confirms that the
     /// channel has been closed (or an error occurs).
+    ///
+    /// Dropping a channel without calling this method also closes it, but without waiting: the
+    /// I/O thread sends the close, and frees the channel's ID once the server confirms it. Any
+    /// consumers on the channel receive
+    /// [`ConsumerMessage::ClientClosedChannel`](enum.ConsumerMessage.html#variant.ClientClosedChannel).
     pub fn close(mut self) -> Result<()> {
-        self.close_impl()
+        // Set closed first so dropping us afterwards does not also try to close.
+        self.closed = true;
+        self.inner.get_mut().close()
     }
 
-    fn close_impl(&mut self) -> Result<()> {
-        // this can only happen if we're called from drop (since close() takes self),
-        // in which case the return value doesn't matter.
-        if self.closed {
-            return Ok(());
-        }
+    // Whether another user could take over this channel as if it were newly opened: it is still
+    // open, and it is not in a mode that can't be switched off again.
+    pub(crate) fn is_reusable(&self) -> bool {
+        !self.transactional.get()
+            && self.next_seq_no.get().is_none()
+            && self.inner.borrow_mut().is_open()
+    }
 
-        // go ahead and set closed to trigger the above codepath if we're about to
-        // be dropped.
-        self.closed = true;
-        self.inner.borrow_mut().close()
+    #[cfg(feature = "consume")]
+    pub(crate) fn set_consumer_paused(&self, consumer_tag: &str, paused: bool) -> Result<()> {
+        self.inner
+            .borrow_mut()
+            .set_consumer_paused(consumer_tag, paused)
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn acker(&self) -> Acker {
+        Acker::new(self.inner.borrow().sender())
+    }
+
+    // Opens another channel on the same connection as this one.
+    fn open_sibling(&self) -> Result<Channel> {
+        let handle = self.inner.borrow().open_sibling()?;
+        Ok(Channel::new(
+            handle,
+            #[cfg(feature = "consume")]
+            Arc::clone(&self.dispatcher),
+        ))
     }
 
     /// Return integral ID of this channel. No two open channels on the same connection may have
@@ -117,6 +200,15 @@ impl Channel {
         self.inner.borrow().channel_id()
     }
 
+    /// Get a snapshot of this channel's counters: publishes, deliveries, acks and nacks sent,
+    /// returned messages, active consumers, and whether confirm or transaction mode is on.
+    ///
+    /// The counters are maintained by the I/O thread and are cheap to read. Once the channel is
+    /// closed (by either side), they keep their final values.
+    pub fn stats(&self) -> ChannelStats {
+        self.inner.borrow().stats()
+    }
+
     fn call<M: IntoAmqpClass + Debug, T: TryFromAmqpClass>(&self, method: M) -> Result<T> {
         self.inner.borrow_mut().call(method)
     }
@@ -125,6 +217,20 @@ impl Channel {
         self.inner.borrow_mut().call_nowait(method)
     }
 
+    // Acks, nacks and rejects are not held up behind the channel's publishes; see
+    // `IoLoopHandle::settle`.
+    #[cfg(feature = "consume")]
+    fn settle<M: IntoAmqpClass + Debug>(
+        &self,
+        method: M,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> Result<()> {
+        self.inner
+            .borrow_mut()
+            .settle(method, delivery_tag, multiple)
+    }
+
     /// Specify the prefetching window.
     ///
     /// If `prefetch_size` is greater than 0, instructs the server to go ahead and send messages up
@@ -142,6 +248,13 @@ impl Channel {
     /// means the settings apply to all consumers on this channel, and `global: false` means the
     /// settings apply only to consumers created on this channel after this call to `qos`, not
     /// affecting previously-created consumers.
+    ///
+    /// This may be called while consumers on this channel are active; use `global: true` to change
+    /// the limit for consumers that already exist. Deliveries the server sent before it received
+    /// the new settings were sent under the old ones, so a consumer can briefly hold more
+    /// unacknowledged messages than a lowered `prefetch_count` allows. Once this method returns,
+    /// the server has applied the new settings and every later delivery respects them.
+    #[cfg(feature = "consume")]
     pub fn qos(&self, prefetch_size: u32, prefetch_count: u16, global: bool) -> Result<()> {
         self.call::<_, QosOk>(AmqpBasic::Qos(Qos {
             prefetch_size,
@@ -154,6 +267,7 @@ impl Channel {
     /// Ask the server to redeliver all unacknowledged messages on this channel. If `requeue` is
     /// false, the server will attempt to redeliver to the original recipient. If it is true, it
     /// will attempt to requeue the message, potentially delivering it to a different recipient.
+    #[cfg(feature = "consume")]
     pub fn recover(&self, requeue: bool) -> Result<()> {
         self.call::<_, RecoverOk>(AmqpBasic::Recover(Recover { requeue }))
             .map(|_recover_ok| ())
@@ -162,22 +276,152 @@ impl Channel {
     /// Publish a message to `exchange`. If the exchange does not exist, the server will close this
     /// channel. Consider using one of the [`exchange_declare`](#method.exchange_declare) methods
     /// and then [`Exchange::publish`](struct.Exchange.html#method.publish) to avoid this.
-    pub fn basic_publish<S: Into<String>>(&self, exchange: S, publish: Publish) -> Result<()> {
+    pub fn basic_publish<S: Into<String>>(&self, exchange: S, mut publish: Publish) -> Result<()> {
+        let exchange = exchange.into();
+        enter_span!(
+            DEBUG,
+            "publish",
+            channel_id = self.channel_id(),
+            exchange = %exchange,
+            routing_key = %publish.routing_key,
+            body_size = publish.body.len()
+        );
+        publish.properties = self.publish_defaults.apply(publish.properties);
+        check_expiration(&publish.properties)?;
         let mut inner = self.inner.borrow_mut();
         inner.call_nowait(AmqpBasic::Publish(AmqpPublish {
             ticket: 0,
-            exchange: exchange.into(),
+            exchange,
             routing_key: publish.routing_key,
             mandatory: publish.mandatory,
             immediate: publish.immediate,
         }))?;
+        self.count_publish();
         inner.send_content(
-            publish.body,
+            &publish.body,
             AmqpPublish::get_class_id(),
             &publish.properties,
         )
     }
 
+    /// Publish a message to `exchange` without blocking. Returns `Ok(true)` if the message was
+    /// handed to the I/O thread, or `Ok(false)` (having sent nothing) if doing so would block
+    /// because the I/O thread is busy or has more than
+    /// [`buffered_writes_high_water`](struct.ConnectionTuning.html#structfield.buffered_writes_high_water)
+    /// bytes waiting to be written. [`basic_publish`](#method.basic_publish) waits in these
+    /// cases instead.
+    ///
+    /// As with `basic_publish`, `Ok(true)` does not mean the server has received the message;
+    /// use [publisher confirms](#method.enable_publisher_confirms) for that.
+    pub fn try_publish<S: Into<String>>(&self, exchange: S, mut publish: Publish) -> Result<bool> {
+        let exchange = exchange.into();
+        enter_span!(
+            DEBUG,
+            "try_publish",
+            channel_id = self.channel_id(),
+            exchange = %exchange,
+            routing_key = %publish.routing_key,
+            body_size = publish.body.len()
+        );
+        publish.properties = self.publish_defaults.apply(publish.properties);
+        check_expiration(&publish.properties)?;
+        let sent = self.inner.borrow_mut().try_send_method_with_content(
+            AmqpBasic::Publish(AmqpPublish {
+                ticket: 0,
+                exchange,
+                routing_key: publish.routing_key,
+                mandatory: publish.mandatory,
+                immediate: publish.immediate,
+            }),
+            &publish.body,
+            AmqpPublish::get_class_id(),
+            &publish.properties,
+        )?;
+        if sent {
+            self.count_publish();
+        }
+        Ok(sent)
+    }
+
+    /// Create a [`Publisher`](struct.Publisher.html) for this channel: a `Send + Sync + Clone`
+    /// handle for publishing on it from other threads. Publishers cannot be used for confirmed
+    /// publishing; see the `Publisher` documentation.
+    pub fn publisher(&self) -> Publisher {
+        Publisher::new(
+            self.inner.borrow().publish_sender(),
+            self.publish_defaults.clone(),
+        )
+    }
+
+    /// Fill in `defaults` on every message published on this channel from now on, including
+    /// through [`Publisher`](struct.Publisher.html)s already created from it. Properties a
+    /// message sets itself are left alone. Replaces any defaults set before; pass
+    /// `PublishDefaults::default()` to stop filling in properties.
+    pub fn set_publish_defaults(&self, defaults: PublishDefaults) {
+        self.publish_defaults.set(defaults);
+    }
+
+    /// Publish a message to `exchange` whose `len`-byte body is read from `reader`, so the whole
+    /// body never needs to be in memory at once. Body frames are read from `reader` one at a
+    /// time as the connection's I/O thread takes them, subject to the same backpressure as
+    /// [`basic_publish`](#method.basic_publish) (see
+    /// [`ConnectionTuning`](struct.ConnectionTuning.html)). Exactly `len` bytes are read.
+    ///
+    /// Once the content header announcing `len` bytes has been sent, the body cannot be cut
+    /// short. If reading from `reader` fails (including ending before `len` bytes), this channel
+    /// is closed and [`PublishSourceFailed`](enum.Error.html#variant.PublishSourceFailed) is
+    /// returned. The server may treat the unfinished message as a connection error; RabbitMQ
+    /// closes the whole connection with `UNEXPECTED_FRAME`.
+    pub fn basic_publish_from_reader<S, R>(
+        &self,
+        exchange: S,
+        routing_key: S,
+        reader: R,
+        len: u64,
+        properties: AmqpProperties,
+    ) -> Result<()>
+    where
+        S: Into<String>,
+        R: Read,
+    {
+        let exchange = exchange.into();
+        let routing_key = routing_key.into();
+        enter_span!(
+            DEBUG,
+            "publish_from_reader",
+            channel_id = self.channel_id(),
+            exchange = %exchange,
+            routing_key = %routing_key,
+            body_size = len
+        );
+        let properties = self.publish_defaults.apply(properties);
+        check_expiration(&properties)?;
+        let mut inner = self.inner.borrow_mut();
+        inner.call_nowait(AmqpBasic::Publish(AmqpPublish {
+            ticket: 0,
+            exchange,
+            routing_key,
+            mandatory: false,
+            immediate: false,
+        }))?;
+        self.count_publish();
+        inner.send_content_from_reader(reader, len, AmqpPublish::get_class_id(), &properties)
+    }
+
+    fn count_publish(&self) {
+        if let Some(seq_no) = self.next_seq_no.get() {
+            self.next_seq_no.set(Some(seq_no.next()));
+        }
+    }
+
+    // The server numbers publishes from 1 starting with the first confirm.select; selecting
+    // again does not restart the count.
+    fn start_counting_publishes(&self) {
+        if self.next_seq_no.get().is_none() {
+            self.next_seq_no.set(Some(SeqNo::new(1)));
+        }
+    }
+
     /// Open a crossbeam channel to receive publisher confirmations from the server.
     ///
     /// You should call this method before either calling
@@ -204,19 +448,163 @@ impl Channel {
     /// Synchronously enable [publisher confirms](https://www.rabbitmq.com/confirms.html) on this
     /// channel. Confirmations will be delivered to the channel registered via
     /// [`listen_for_publisher_confirms`](#method.listen_for_publisher_confirms).
+    ///
+    /// Fails with an error of [kind
+    /// `ConfirmsWithTransactions`](enum.Error.html#variant.ConfirmsWithTransactions) if this
+    /// channel is [transactional](#method.tx_select).
     pub fn enable_publisher_confirms(&self) -> Result<()> {
+        if self.transactional.get() {
+            return Err(Error::ConfirmsWithTransactions);
+        }
         let mut inner = self.inner.borrow_mut();
-        inner
-            .call::<_, ConfirmSelectOk>(AmqpConfirm::Select(ConfirmSelect { nowait: false }))
-            .map(|_select_ok| ())
+        inner.call::<_, ConfirmSelectOk>(AmqpConfirm::Select(ConfirmSelect { nowait: false }))?;
+        self.start_counting_publishes();
+        Ok(())
     }
 
     /// Asynchronously enable [publisher confirms](https://www.rabbitmq.com/confirms.html) on this
     /// channel. Confirmations will be delivered to the channel registered via
     /// [`listen_for_publisher_confirms`](#method.listen_for_publisher_confirms).
+    ///
+    /// Fails with an error of [kind
+    /// `ConfirmsWithTransactions`](enum.Error.html#variant.ConfirmsWithTransactions) if this
+    /// channel is [transactional](#method.tx_select).
     pub fn enable_publisher_confirms_nowait(&self) -> Result<()> {
+        if self.transactional.get() {
+            return Err(Error::ConfirmsWithTransactions);
+        }
         let mut inner = self.inner.borrow_mut();
-        inner.call_nowait(AmqpConfirm::Select(ConfirmSelect { nowait: true }))
+        inner.call_nowait(AmqpConfirm::Select(ConfirmSelect { nowait: true }))?;
+        self.start_counting_publishes();
+        Ok(())
+    }
+
+    /// The sequence number the server will give the next message published on this channel,
+    /// which is the delivery tag of the [`Confirm`](enum.Confirm.html) that settles it. Useful
+    /// with [`ConfirmTracker`](struct.ConfirmTracker.html).
+    ///
+    /// Sequence numbers start at 1 when publisher confirms are enabled; before that, this
+    /// returns 0.
+    pub fn next_publish_seq_no(&self) -> u64 {
+        self.next_seq_no.get().map_or(0, SeqNo::get)
+    }
+
+    /// Publish a message to `exchange` and wait for the server to confirm it, enabling
+    /// [publisher confirms](#method.enable_publisher_confirms) on this channel first if needed.
+    ///
+    /// Returns [`ConfirmOutcome::Acked`](enum.ConfirmOutcome.html#variant.Acked) or
+    /// [`Nacked`](enum.ConfirmOutcome.html#variant.Nacked) once the server settles the message
+    /// (individually or as part of a `multiple` confirmation), or
+    /// [`Returned`](enum.ConfirmOutcome.html#variant.Returned) if it was published as `mandatory`
+    /// and could not be routed; such a return is not also sent to the channel's [return
+    /// listener](#method.listen_for_returns). Confirmations are still sent to any [confirm
+    /// listener](#method.listen_for_publisher_confirms), so this can be mixed with other
+    /// publishes on the same channel.
+    ///
+    /// If `timeout` is given and the server has not settled the message within it, returns an
+    /// error with [kind `PublisherConfirmTimeout`](enum.Error.html#variant.PublisherConfirmTimeout).
+    /// If the channel or connection is closed while waiting, returns the corresponding error.
+    pub fn publish_confirmed<S: Into<String>>(
+        &self,
+        exchange: S,
+        publish: Publish,
+        timeout: Option<Duration>,
+    ) -> Result<ConfirmOutcome> {
+        if self.next_seq_no.get().is_none() {
+            self.enable_publisher_confirms()?;
+        }
+        // unwrap is safe: enabling confirms starts the count.
+        let seq_no = self.next_seq_no.get().unwrap();
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.inner.borrow_mut().await_confirm(seq_no, tx)?;
+        self.basic_publish(exchange, publish)?;
+        match timeout {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(outcome) => outcome,
+                Err(RecvTimeoutError::Timeout) => Err(Error::PublisherConfirmTimeout),
+                Err(RecvTimeoutError::Disconnected) => {
+                    Err(self.inner.borrow().event_loop_dropped())
+                }
+            },
+            None => rx
+                .recv()
+                .map_err(|_| self.inner.borrow().event_loop_dropped())?,
+        }
+    }
+
+    /// Synchronously put this channel in [transactional
+    /// mode](https://www.rabbitmq.com/semantics.html#tx). Messages published and acks sent on the
+    /// channel from then on take effect only when [committed](#method.tx_commit), and are
+    /// discarded if [rolled back](#method.tx_rollback). A transactional channel stays
+    /// transactional; each commit or rollback starts a new transaction.
+    ///
+    /// Fails with an error of [kind
+    /// `ConfirmsWithTransactions`](enum.Error.html#variant.ConfirmsWithTransactions) if [publisher
+    /// confirms](#method.enable_publisher_confirms) are enabled on this channel.
+    pub fn tx_select(&self) -> Result<()> {
+        if self.next_seq_no.get().is_some() {
+            return Err(Error::ConfirmsWithTransactions);
+        }
+        self.call::<_, TxSelectOk>(AmqpTx::Select(TxSelect {}))?;
+        self.transactional.set(true);
+        Ok(())
+    }
+
+    /// Synchronously commit the current transaction on this [transactional](#method.tx_select)
+    /// channel.
+    pub fn tx_commit(&self) -> Result<()> {
+        self.call::<_, TxCommitOk>(AmqpTx::Commit(TxCommit {}))?;
+        Ok(())
+    }
+
+    /// Synchronously roll back the current transaction on this [transactional](#method.tx_select)
+    /// channel. Acks that are rolled back leave their messages unacknowledged.
+    pub fn tx_rollback(&self) -> Result<()> {
+        self.call::<_, TxRollbackOk>(AmqpTx::Rollback(TxRollback {}))?;
+        Ok(())
+    }
+
+    /// Run `f` in a transaction on this channel, [making the channel
+    /// transactional](#method.tx_select) first if needed. If `f` returns `Ok`, the transaction is
+    /// [committed](#method.tx_commit); if it returns `Err` or panics, the transaction is [rolled
+    /// back](#method.tx_rollback).
+    ///
+    /// If `f` fails, its error is returned even if the rollback also fails.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use amiquip::{Channel, Publish, Result};
+    ///
+    /// fn publish_both(channel: &Channel) -> Result<()> {
+    ///     channel.with_transaction(|channel| {
+    ///         channel.basic_publish("", Publish::new(b"debit", "ledger"))?;
+    ///         channel.basic_publish("", Publish::new(b"credit", "ledger"))
+    ///     })
+    /// }
+    /// ```
+    pub fn with_transaction<T, E, F>(&self, f: F) -> StdResult<T, E>
+    where
+        F: FnOnce(&Channel) -> StdResult<T, E>,
+        E: From<Error>,
+    {
+        if !self.transactional.get() {
+            self.tx_select()?;
+        }
+        let result = {
+            let _rollback = RollbackOnPanic(self);
+            f(self)
+        };
+        match result {
+            Ok(value) => {
+                self.tx_commit()?;
+                Ok(value)
+            }
+            Err(err) => {
+                let _ = self.tx_rollback();
+                Err(err)
+            }
+        }
     }
 
     /// Open a crossbeam channel to receive returned messages from the server (i.e., messages
@@ -226,34 +614,112 @@ impl Channel {
     /// There can be only one return listener per channel. If you call this method a second (or
     /// more) time, the I/O thread will drop the sending side of previously returned channels.
     ///
-    /// Dropping the `Receiver` returned by this method is harmless. If the I/O loop receives a
-    /// returned message and there is no listener registered or the previously-registered listener
-    /// has been dropped, it will discard the message.
+    /// Messages the server returns before the first listener is registered (e.g., because it was
+    /// registered just after publishing) are held for that listener, up to a small limit, and
+    /// logged at warn level. Dropping the `Receiver` returned by this method is harmless: the I/O
+    /// thread logs and discards any later returns, and counts them in
+    /// [`ChannelStats::returns_dropped`](struct.ChannelStats.html#structfield.returns_dropped).
+    ///
+    /// The channel is unbounded, so returns pile up if nothing reads them; see
+    /// [`listen_for_returns_bounded`](#method.listen_for_returns_bounded) for a bounded one.
     pub fn listen_for_returns(&self) -> Result<Receiver<Return>> {
+        let (handler, rx) = ReturnHandler::unbounded();
+        self.inner.borrow_mut().set_return_handler(handler)?;
+        Ok(rx)
+    }
+
+    /// Like [`listen_for_returns`](#method.listen_for_returns), but the crossbeam channel holds at
+    /// most `capacity` returned messages. When a return arrives and the channel is full, the I/O
+    /// thread discards the oldest return in it to make room, logs it at warn level, and counts it
+    /// in [`ChannelStats::returns_dropped`](struct.ChannelStats.html#structfield.returns_dropped).
+    ///
+    /// The I/O thread keeps its own handle on the channel to do so, so it keeps queueing (and
+    /// dropping) returns even if the `Receiver` is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn listen_for_returns_bounded(&self, capacity: usize) -> Result<Receiver<Return>> {
+        assert!(
+            capacity > 0,
+            "return listener must hold at least one return"
+        );
+        let (handler, rx) = ReturnHandler::bounded(capacity);
+        self.inner.borrow_mut().set_return_handler(handler)?;
+        Ok(rx)
+    }
+
+    /// Open a crossbeam channel to receive a [`PublishResult`](struct.PublishResult.html) for
+    /// every message published on this channel from now on, enabling [publisher
+    /// confirms](#method.enable_publisher_confirms) first if needed.
+    ///
+    /// Each result pairs a message's sequence number (see
+    /// [`next_publish_seq_no`](#method.next_publish_seq_no)) with how the server handled it. The
+    /// server returns an unroutable `mandatory` message just before acking it, so the I/O thread
+    /// reports a return as [`Unroutable`](enum.PublishOutcome.html#variant.Unroutable) for the
+    /// message at the delivery tag of the ack that follows it. Confirms for `multiple` messages are
+    /// reported as one result per message. Results arrive in the order the server settles
+    /// messages, which is not necessarily the order they were published.
+    ///
+    /// While a publish result listener is registered, returned messages are not also sent to the
+    /// [return listener](#method.listen_for_returns). Confirms are still sent to the [confirm
+    /// listener](#method.listen_for_publisher_confirms).
+    ///
+    /// There can be only one publish result listener per channel. If you call this method a
+    /// second (or more) time, the I/O thread will drop the sending side of previously returned
+    /// channels. If the `Receiver` is dropped, the I/O thread stops tracking results and returns
+    /// go to the return listener again.
+    pub fn listen_for_publish_results(&self) -> Result<Receiver<PublishResult>> {
+        if self.next_seq_no.get().is_none() {
+            self.enable_publisher_confirms()?;
+        }
+        // unwrap is safe: enabling confirms starts the count.
+        let first_seq_no = self.next_seq_no.get().unwrap();
         let (tx, rx) = crossbeam_channel::unbounded();
-        self.inner.borrow_mut().set_return_handler(Some(tx))?;
+        self.inner
+            .borrow_mut()
+            .set_publish_result_handler(tx, first_seq_no)?;
         Ok(rx)
     }
 
+    /// Wait until the server has processed every method sent on this channel so far, including
+    /// asynchronous ones like [`queue_declare_nowait`](#method.queue_declare_nowait) and
+    /// [`queue_bind_nowait`](#method.queue_bind_nowait).
+    ///
+    /// The server closes the channel if any of those methods fail; in that case, this returns
+    /// [`Error::ServerClosedChannel`](enum.Error.html#variant.ServerClosedChannel) describing the
+    /// first failure. This sends a passive declare of the `amq.direct` exchange (which every AMQP
+    /// 0-9-1 server provides) and waits for the reply.
+    pub fn sync(&self) -> Result<()> {
+        self.exchange_declare_passive("amq.direct").map(|_| ())
+    }
+
     /// Synchronously declare a queue named `queue` with the given options.
     ///
     /// If `queue` is `""` (the empty string), the server will assign an automatically generated
     /// queue name; use [`Queue::name`](struct.Queue.html#method.name) to get access to that name.
     ///
     /// If the server cannot declare the queue (e.g., if the queue already exists with options that
-    /// conflict with `options`), it will close this channel.
+    /// conflict with `options`), it will close this channel. If the server names the conflicting
+    /// argument or flag, the error is
+    /// [`Error::InequivalentQueueArgument`](enum.Error.html#variant.InequivalentQueueArgument).
     pub fn queue_declare<S: Into<String>>(
         &self,
         queue: S,
         options: QueueDeclareOptions,
-    ) -> Result<Queue> {
-        let declare = AmqpQueue::Declare(options.into_declare(queue.into(), false, false));
-        let ok = self.call::<_, QueueDeclareOk>(declare)?;
+    ) -> Result<Queue<'_>> {
+        let queue = queue.into();
+        let max_priority = options.max_priority();
+        let declare = AmqpQueue::Declare(options.into_declare(queue.clone(), false, false)?);
+        let ok = self
+            .call::<_, QueueDeclareOk>(declare)
+            .map_err(|err| describe_declare_error(err, &queue))?;
         Ok(Queue::new(
             self,
             ok.queue,
             Some(ok.message_count),
             Some(ok.consumer_count),
+            Some(max_priority),
         ))
     }
 
@@ -270,20 +736,21 @@ impl Channel {
         &self,
         queue: S,
         options: QueueDeclareOptions,
-    ) -> Result<Queue> {
+    ) -> Result<Queue<'_>> {
         let queue = queue.into();
         assert!(
-            queue != "",
+            !queue.is_empty(),
             "cannot asynchronously declare auto-named queues"
         );
-        let declare = AmqpQueue::Declare(options.into_declare(queue.clone(), false, true));
+        let max_priority = options.max_priority();
+        let declare = AmqpQueue::Declare(options.into_declare(queue.clone(), false, true)?);
         self.call_nowait(declare)?;
-        Ok(Queue::new(self, queue, None, None))
+        Ok(Queue::new(self, queue, None, None, Some(max_priority)))
     }
 
     /// Passively declare that a queue exists. This asks the server to confirm that a queue named
     /// `queue` already exists; it will close the channel if it does not.
-    pub fn queue_declare_passive<S: Into<String>>(&self, queue: S) -> Result<Queue> {
+    pub fn queue_declare_passive<S: Into<String>>(&self, queue: S) -> Result<Queue<'_>> {
         // per spec, if passive is set all other fields are ignored except nowait (which
         // must be false to be meaningful)
         let options = QueueDeclareOptions {
@@ -292,16 +759,41 @@ impl Channel {
             auto_delete: false,
             arguments: FieldTable::new(),
         };
-        let declare = AmqpQueue::Declare(options.into_declare(queue.into(), true, false));
+        let declare = AmqpQueue::Declare(options.into_declare(queue.into(), true, false)?);
         let ok = self.call::<_, QueueDeclareOk>(declare)?;
         Ok(Queue::new(
             self,
             ok.queue,
             Some(ok.message_count),
             Some(ok.consumer_count),
+            None,
         ))
     }
 
+    /// Check whether a queue named `queue` exists, returning its message and consumer counts if it
+    /// does.
+    ///
+    /// Unlike [`queue_declare_passive`](#method.queue_declare_passive), this leaves this channel
+    /// open either way: the passive declare is sent on a short-lived channel that this method
+    /// opens (and closes) on the same connection.
+    pub fn queue_exists<S: Into<String>>(&self, queue: S) -> Result<Option<QueueInfo>> {
+        let channel = self.open_sibling()?;
+        let info = match channel.queue_declare_passive(queue) {
+            Ok(queue) => QueueInfo {
+                name: queue.name().to_string(),
+                message_count: queue.declared_message_count().unwrap_or(0),
+                consumer_count: queue.declared_consumer_count().unwrap_or(0),
+            },
+            Err(Error::ServerClosedChannel {
+                reply_code: AmqpReplyCode::NotFound,
+                ..
+            }) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        channel.close()?;
+        Ok(Some(info))
+    }
+
     /// Synchronously get a single message from `queue`. If the queue does not exist, the server
     /// will close this channel. Consider using one of the [`queue_declare`](#method.queue_declare)
     /// methods and then [`Queue::get`](struct.Queue.html#method.get) to avoid this.
@@ -310,8 +802,13 @@ impl Channel {
     /// were no messages in the queue. If `no_ack` is false, you are responsible for acknowledging
     /// the returned message, typically via [`Get::ack`](struct.Get.html#method.ack).
     ///
+    /// It is fine to call this on a channel with active consumers; their deliveries are
+    /// unaffected. The returned delivery's tag comes from the same per-channel sequence as theirs,
+    /// so it is acknowledged on this channel like any other delivery.
+    ///
     /// Prefer using [`basic_consume`](#method.basic_consume) to allow the server to push messages
     /// to you on demand instead of polling with `get`.
+    #[cfg(feature = "consume")]
     pub fn basic_get<S: Into<String>>(&self, queue: S, no_ack: bool) -> Result<Option<Get>> {
         self.inner.borrow_mut().get(AmqpGet {
             ticket: 0,
@@ -323,27 +820,135 @@ impl Channel {
     /// Synchronously set up a consumer on `queue`. If the queue does not exist, the server will
     /// close this channel. Consider using one of the [`queue_declare`](#method.queue_declare)
     /// methods and then [`Queue::consume`](struct.Queue.html#method.consume) to avoid this.
+    #[cfg(feature = "consume")]
     pub fn basic_consume<S: Into<String>>(
         &self,
         queue: S,
         options: ConsumerOptions,
-    ) -> Result<Consumer> {
-        // NOTE: We currently don't support nowait consumers for two reasons:
-        // 1. We always let the server pick the consumption tag, so without
-        //    the consume-ok we don't have a tag to cancel.
-        // 2. The I/O loop allocates the channel to send deliveries when it
-        //    receives consume-ok.
-        let (tag, rx) = self.inner.borrow_mut().consume(Consume {
-            ticket: 0,
-            queue: queue.into(),
-            consumer_tag: String::new(),
-            no_local: options.no_local,
-            no_ack: options.no_ack,
-            exclusive: options.exclusive,
-            nowait: false,
-            arguments: options.arguments,
-        })?;
-        Ok(Consumer::new(self, tag, rx))
+    ) -> Result<Consumer<'_>> {
+        self.basic_consume_with_tag(queue.into(), String::new(), options)
+    }
+
+    /// Synchronously set up a consumer on `queue` whose messages are handled by `callback`.
+    ///
+    /// `callback` runs on one of the connection's dispatch threads (see
+    /// [`ConnectionTuning::dispatch_threads`](struct.ConnectionTuning.html#structfield.dispatch_threads)),
+    /// never on the thread calling this method and never on the connection's I/O thread, so a slow
+    /// callback cannot delay heartbeats or other channels' traffic. It is invoked with every
+    /// message for this consumer, in order, one at a time. Deliveries should be acknowledged via
+    /// the [`Acker`](struct.Acker.html) passed to the callback. The final invocation is with a
+    /// message other than [`ConsumerMessage::Delivery`](enum.ConsumerMessage.html#variant.Delivery),
+    /// after which the callback is dropped. If the callback panics, it is dropped and receives no
+    /// further messages: the delivery it was handling is nacked with `requeue` set (unless it had
+    /// already been settled) and the consumer is cancelled, and any deliveries that arrive before
+    /// the cancellation completes are requeued as well.
+    ///
+    /// If the queue does not exist, the server will close this channel. Consider using one of the
+    /// [`queue_declare`](#method.queue_declare) methods and then
+    /// [`Queue::consume_with_callback`](struct.Queue.html#method.consume_with_callback) to avoid
+    /// this.
+    #[cfg(feature = "consume")]
+    pub fn basic_consume_with_callback<S, F>(
+        &self,
+        queue: S,
+        options: ConsumerOptions,
+        callback: F,
+    ) -> Result<CallbackConsumer<'_>>
+    where
+        S: Into<String>,
+        F: FnMut(ConsumerMessage, &Acker) + Send + 'static,
+    {
+        let queue = queue.into();
+        enter_span!(DEBUG, "consume", channel_id = self.channel_id(), queue = %queue);
+        let buffer = options.buffer();
+        self.select_confirms_for_parking(&buffer)?;
+        let ack_policy = options.effective_ack_policy();
+        let no_ack = options.no_ack;
+        let (tag, rx) = self
+            .inner
+            .borrow_mut()
+            .consume(options.into_consume(queue, String::new(), false)?, buffer)?;
+        let consumer = CallbackConsumer::new(self, tag.clone());
+        let acker = self.acker();
+        self.dispatcher.register(Registration::new(
+            tag,
+            rx,
+            acker,
+            ack_policy,
+            no_ack,
+            Box::new(callback),
+        ))?;
+        Ok(consumer)
+    }
+
+    // Start a consumer with a client-chosen tag. An empty `consumer_tag` asks the server to pick
+    // one; this is what `basic_consume` does.
+    #[cfg(feature = "consume")]
+    pub(crate) fn basic_consume_with_tag(
+        &self,
+        queue: String,
+        consumer_tag: String,
+        options: ConsumerOptions,
+    ) -> Result<Consumer<'_>> {
+        enter_span!(DEBUG, "consume", channel_id = self.channel_id(), queue = %queue);
+        let buffer = options.buffer();
+        self.select_confirms_for_parking(&buffer)?;
+        let ack_policy = options.effective_ack_policy();
+        let (tag, rx) = self
+            .inner
+            .borrow_mut()
+            .consume(options.into_consume(queue, consumer_tag, false)?, buffer)?;
+        Ok(Consumer::new(self, tag, rx, ack_policy))
+    }
+
+    // The I/O thread only parks deliveries (see `RedeliveryPolicy`) on channels in confirm mode,
+    // so it can tell whether the server accepted the republish before acking the original.
+    #[cfg(feature = "consume")]
+    fn select_confirms_for_parking(&self, buffer: &ConsumerBuffer) -> Result<()> {
+        if buffer.redelivery_policy.is_some() && self.next_seq_no.get().is_none() {
+            self.enable_publisher_confirms()?;
+        }
+        Ok(())
+    }
+
+    /// Asynchronously set up a consumer on `queue` with the given `consumer_tag`, without waiting
+    /// for the server to confirm it. Deliveries may arrive on the returned consumer as soon as the
+    /// server has processed the request.
+    ///
+    /// If the queue does not exist (or `consumer_tag` is already in use on this channel), the
+    /// server will close this channel; the consumer receives
+    /// [`ConsumerMessage::ServerClosedChannel`](enum.ConsumerMessage.html#variant.ServerClosedChannel).
+    /// Use [`sync`](#method.sync) to find out whether it started.
+    ///
+    /// If `options` has a [`redelivery_policy`](struct.ConsumerOptions.html#structfield.redelivery_policy)
+    /// and publisher confirms are not enabled yet, this first enables them synchronously.
+    ///
+    /// # Panics
+    ///
+    /// This method will panic if `consumer_tag` is `""` (the empty string), as we would not
+    /// receive a reply from the server telling us what tag it generated.
+    #[cfg(feature = "consume")]
+    pub fn basic_consume_nowait<S0: Into<String>, S1: Into<String>>(
+        &self,
+        queue: S0,
+        consumer_tag: S1,
+        options: ConsumerOptions,
+    ) -> Result<Consumer<'_>> {
+        let queue = queue.into();
+        let consumer_tag = consumer_tag.into();
+        assert!(
+            !consumer_tag.is_empty(),
+            "cannot asynchronously start consumers with server-generated tags"
+        );
+        enter_span!(DEBUG, "consume", channel_id = self.channel_id(), queue = %queue);
+        let buffer = options.buffer();
+        self.select_confirms_for_parking(&buffer)?;
+        let ack_policy = options.effective_ack_policy();
+        let rx = self.inner.borrow_mut().consume_nowait(
+            options.into_consume(queue, consumer_tag.clone(), true)?,
+            buffer,
+        )?;
+        Ok(Consumer::new(self, consumer_tag, rx, ack_policy))
     }
 
     /// Syncronously bind `queue` to `exchange` with the given routing key and arguments.
@@ -395,6 +1000,7 @@ impl Channel {
     }
 
     /// Syncronously unbind `queue` from `exchange` with the given routing key and arguments.
+    /// Unbinding a binding that does not exist is not an error.
     ///
     /// If either the queue or the exchange do not exist, the server will close this channel.
     /// Consider using the [`queue_declare`](#method.queue_declare) and
@@ -477,6 +1083,196 @@ impl Channel {
         self.call_nowait(delete)
     }
 
+    /// Synchronously declare every exchange and queue in `topology`, then create its bindings.
+    ///
+    /// The default exchange (`""`) is skipped, and exchanges whose names begin with `amq.` are
+    /// declared passively, as the server does not allow clients to declare them. As with the
+    /// individual declare methods, if the server rejects any declaration (e.g., because a queue
+    /// already exists with different options), it will close this channel and this method will
+    /// return an error; entities declared before the failure are not removed.
+    ///
+    /// To bound the time taken by the whole topology rather than each declaration, call this
+    /// method inside [`with_deadline`](fn.with_deadline.html). To avoid waiting for the server
+    /// after each declaration, use [`apply`](#method.apply).
+    pub fn apply_topology(&self, topology: &Topology) -> Result<()> {
+        for step in topology.steps() {
+            match step {
+                TopologyStep::VerifyExchange(exchange) => {
+                    self.exchange_declare_passive(exchange.name.clone())?;
+                }
+                TopologyStep::DeclareExchange(exchange) => {
+                    self.exchange_declare(
+                        exchange.type_.clone(),
+                        exchange.name.clone(),
+                        exchange.options.clone(),
+                    )?;
+                }
+                TopologyStep::DeclareQueue(queue) => {
+                    self.queue_declare(queue.name.clone(), queue.options.clone())?;
+                }
+                TopologyStep::Bind(binding) => match binding.destination_type {
+                    BindingDestination::Queue => self.queue_bind(
+                        binding.destination.clone(),
+                        binding.source.clone(),
+                        binding.routing_key.clone(),
+                        binding.arguments.clone(),
+                    )?,
+                    BindingDestination::Exchange => self.exchange_bind(
+                        binding.destination.clone(),
+                        binding.source.clone(),
+                        binding.routing_key.clone(),
+                        binding.arguments.clone(),
+                    )?,
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Declare every exchange and queue in `topology` and create its bindings like
+    /// [`apply_topology`](#method.apply_topology), but asynchronously, waiting only once (with
+    /// [`sync`](#method.sync)) for the server to process them all. This saves a round trip to
+    /// the server per declaration.
+    ///
+    /// An unnamed queue is declared synchronously instead, as the server only reports the name it
+    /// chose in its reply; that name is the one included in the returned report.
+    ///
+    /// If the server rejects any declaration, it will close this channel and this method will
+    /// return the error for the first one rejected; entities declared before the failure are not
+    /// removed.
+    pub fn apply(&self, topology: &Topology) -> Result<TopologyReport> {
+        let mut report = TopologyReport::default();
+        for step in topology.steps() {
+            match step {
+                TopologyStep::VerifyExchange(exchange) => {
+                    // per spec, if passive is set all other fields are ignored
+                    let options = ExchangeDeclareOptions::default();
+                    let declare = AmqpExchange::Declare(options.into_declare(
+                        ExchangeType::Direct,
+                        exchange.name.clone(),
+                        true,
+                        true,
+                    ));
+                    self.call_nowait(declare)?;
+                    report.exchanges_verified.push(exchange.name.clone());
+                }
+                TopologyStep::DeclareExchange(exchange) => {
+                    self.exchange_declare_nowait(
+                        exchange.type_.clone(),
+                        exchange.name.clone(),
+                        exchange.options.clone(),
+                    )?;
+                    report.exchanges_declared.push(exchange.name.clone());
+                }
+                TopologyStep::DeclareQueue(queue) if queue.name.is_empty() => {
+                    let declared = self.queue_declare("", queue.options.clone())?;
+                    report.queues_declared.push(declared.name().to_string());
+                }
+                TopologyStep::DeclareQueue(queue) => {
+                    self.queue_declare_nowait(queue.name.clone(), queue.options.clone())?;
+                    report.queues_declared.push(queue.name.clone());
+                }
+                TopologyStep::Bind(binding) => {
+                    match binding.destination_type {
+                        BindingDestination::Queue => self.queue_bind_nowait(
+                            binding.destination.clone(),
+                            binding.source.clone(),
+                            binding.routing_key.clone(),
+                            binding.arguments.clone(),
+                        )?,
+                        BindingDestination::Exchange => self.exchange_bind_nowait(
+                            binding.destination.clone(),
+                            binding.source.clone(),
+                            binding.routing_key.clone(),
+                            binding.arguments.clone(),
+                        )?,
+                    }
+                    report.bindings_created += 1;
+                }
+            }
+        }
+        self.sync()?;
+        Ok(report)
+    }
+
+    /// Check that every exchange and queue in `topology` exists on the server with the type and
+    /// options `topology` gives it, returning the differences found (empty if there are none).
+    ///
+    /// Each entity is declared passively to check that it exists, then (unless it is a
+    /// predeclared `amq.` exchange) declared again with the topology's options, which has no
+    /// effect if they match and makes the server report a difference if they don't; nothing is
+    /// created. Like [`queue_exists`](#method.queue_exists), this uses a short-lived channel on
+    /// the same connection (opening another whenever the server closes one), so this channel is
+    /// left open. Bindings are not checked, as AMQP provides no way to look them up.
+    pub fn verify(&self, topology: &Topology) -> Result<Vec<TopologyMismatch>> {
+        let mut mismatches = Vec::new();
+        let mut channel = self.open_sibling()?;
+        for exchange in &topology.exchanges {
+            if exchange.name.is_empty() {
+                continue;
+            }
+            let result = channel
+                .exchange_declare_passive(exchange.name.clone())
+                .and_then(|_| {
+                    if exchange.name.starts_with("amq.") {
+                        return Ok(());
+                    }
+                    channel
+                        .exchange_declare(
+                            exchange.type_.clone(),
+                            exchange.name.clone(),
+                            exchange.options.clone(),
+                        )
+                        .map(|_| ())
+                });
+            match result {
+                Ok(()) => continue,
+                Err(Error::ServerClosedChannel {
+                    reply_code: AmqpReplyCode::NotFound,
+                    ..
+                }) => mismatches.push(TopologyMismatch::MissingExchange(exchange.name.clone())),
+                Err(Error::ServerClosedChannel {
+                    reply_code: AmqpReplyCode::PreconditionFailed,
+                    message,
+                    ..
+                }) => mismatches.push(TopologyMismatch::ExchangeDiffers {
+                    name: exchange.name.clone(),
+                    message,
+                }),
+                Err(err) => return Err(err),
+            }
+            channel = self.open_sibling()?;
+        }
+        for queue in &topology.queues {
+            let result = channel
+                .queue_declare_passive(queue.name.clone())
+                .and_then(|_| channel.queue_declare(queue.name.clone(), queue.options.clone()))
+                .map(|_| ());
+            match result {
+                Ok(()) => continue,
+                Err(Error::ServerClosedChannel {
+                    reply_code: AmqpReplyCode::NotFound,
+                    ..
+                }) => mismatches.push(TopologyMismatch::MissingQueue(queue.name.clone())),
+                Err(Error::ServerClosedChannel {
+                    reply_code: AmqpReplyCode::PreconditionFailed,
+                    message,
+                    ..
+                })
+                | Err(Error::InequivalentQueueArgument { message, .. }) => {
+                    mismatches.push(TopologyMismatch::QueueDiffers {
+                        name: queue.name.clone(),
+                        message,
+                    })
+                }
+                Err(err) => return Err(err),
+            }
+            channel = self.open_sibling()?;
+        }
+        channel.close()?;
+        Ok(mismatches)
+    }
+
     /// Synchronously declare an exchange named `exchange` with the given type and options.
     ///
     /// If the server cannot declare the exchange (e.g., if the exchange already exists with a
@@ -486,12 +1282,16 @@ impl Channel {
         type_: ExchangeType,
         exchange: S,
         options: ExchangeDeclareOptions,
-    ) -> Result<Exchange> {
+    ) -> Result<Exchange<'_>> {
         let exchange = exchange.into();
+        let delayed = options.is_delayed();
         let declare =
             AmqpExchange::Declare(options.into_declare(type_, exchange.clone(), false, false));
-        self.call::<_, ExchangeDeclareOk>(declare)
-            .map(|_ok| Exchange::new(self, exchange))
+        match self.call::<_, ExchangeDeclareOk>(declare) {
+            Ok(_) => Ok(Exchange::new(self, exchange)),
+            Err(err) if delayed => Err(describe_delayed_declare_error(err, &exchange)),
+            Err(err) => Err(err),
+        }
     }
 
     /// Asynchronously declare an exchange named `exchange` with the given type and options.
@@ -503,7 +1303,7 @@ impl Channel {
         type_: ExchangeType,
         exchange: S,
         options: ExchangeDeclareOptions,
-    ) -> Result<Exchange> {
+    ) -> Result<Exchange<'_>> {
         let exchange = exchange.into();
         let declare =
             AmqpExchange::Declare(options.into_declare(type_, exchange.clone(), false, true));
@@ -513,7 +1313,7 @@ impl Channel {
 
     /// Passively declare that a exchange exists. This asks the server to confirm that a exchange
     /// named `exchange` already exists; it will close the channel if it does not.
-    pub fn exchange_declare_passive<S: Into<String>>(&self, exchange: S) -> Result<Exchange> {
+    pub fn exchange_declare_passive<S: Into<String>>(&self, exchange: S) -> Result<Exchange<'_>> {
         let exchange = exchange.into();
         // per spec, if passive is set all other fields are ignored except nowait (which
         // must be false to be meaningful)
@@ -530,6 +1330,25 @@ impl Channel {
             .map(|_ok| Exchange::new(self, exchange))
     }
 
+    /// Check whether an exchange named `exchange` exists.
+    ///
+    /// Unlike [`exchange_declare_passive`](#method.exchange_declare_passive), this leaves this
+    /// channel open either way: the passive declare is sent on a short-lived channel that this
+    /// method opens (and closes) on the same connection.
+    pub fn exchange_exists<S: Into<String>>(&self, exchange: S) -> Result<bool> {
+        let channel = self.open_sibling()?;
+        match channel.exchange_declare_passive(exchange) {
+            Ok(_) => (),
+            Err(Error::ServerClosedChannel {
+                reply_code: AmqpReplyCode::NotFound,
+                ..
+            }) => return Ok(false),
+            Err(err) => return Err(err),
+        }
+        channel.close()?;
+        Ok(true)
+    }
+
     /// Synchronously bind an exchange to an exchange with the given routing key and arguments.
     ///
     /// If either the source or destination exchanges do not exist, the server will close this
@@ -587,6 +1406,7 @@ impl Channel {
     }
 
     /// Synchronously unbind an exchange from an exchange with the given routing key and arguments.
+    /// Unbinding a binding that does not exist is not an error.
     ///
     /// If either the source or destination exchanges do not exist, the server will close this
     /// channel. Consider using [`exchange_declare`](#method.exchange_declare) and then
@@ -682,59 +1502,168 @@ impl Channel {
 
     /// Asynchronously acknowledge all messages consumers on this channel have received that have
     /// not yet been acknowledged.
+    #[cfg(feature = "consume")]
     pub fn ack_all(&self) -> Result<()> {
-        self.call_nowait(AmqpBasic::Ack(Ack {
-            delivery_tag: 0,
-            multiple: true,
-        }))
+        self.settle(
+            AmqpBasic::Ack(Ack {
+                delivery_tag: 0,
+                multiple: true,
+            }),
+            0,
+            true,
+        )
     }
 
+    /// Asynchronously acknowledge the message with the given delivery tag and all unacknowledged
+    /// messages received on this channel with smaller tags, in a single frame.
+    ///
+    /// Delivery tags restart at 1 on every channel, so `delivery_tag` must come from a
+    /// [`Delivery`](struct.Delivery.html) received on this channel; prefer
+    /// [`Delivery::ack_multiple`](struct.Delivery.html#method.ack_multiple), which checks this.
+    #[cfg(feature = "consume")]
+    pub fn ack_multiple(&self, delivery_tag: u64) -> Result<()> {
+        enter_span!(DEBUG, "ack", channel_id = self.channel_id(), delivery_tag);
+        self.settle(
+            AmqpBasic::Ack(Ack {
+                delivery_tag,
+                multiple: true,
+            }),
+            delivery_tag,
+            true,
+        )
+    }
+
+    // The tag to settle `delivery` with on this channel, or `Error::StaleDelivery` if it was
+    // received on a previous channel with the same id.
+    #[cfg(feature = "consume")]
+    pub(crate) fn delivery_tag_of(&self, delivery: &Delivery) -> Result<u64> {
+        delivery.tag_for(self.inner.borrow().epoch())
+    }
+
+    // Ack `delivery` under `AckPolicy::Batched`; the I/O thread decides when to send it.
+    #[cfg(feature = "consume")]
+    pub(crate) fn batched_ack(
+        &self,
+        delivery: Delivery,
+        max_count: usize,
+        max_delay: Duration,
+    ) -> Result<()> {
+        assert_eq!(
+            delivery.channel_id(),
+            self.channel_id(),
+            "cannot ack delivery on different channel"
+        );
+        let delivery_tag = self.delivery_tag_of(&delivery)?;
+        enter_span!(DEBUG, "ack", channel_id = self.channel_id(), delivery_tag);
+        self.inner
+            .borrow()
+            .sender()
+            .batched_ack(delivery_tag, max_count, max_delay)
+    }
+
+    #[cfg(feature = "consume")]
     pub(crate) fn basic_ack(&self, delivery: Delivery, multiple: bool) -> Result<()> {
-        self.call_nowait(AmqpBasic::Ack(Ack {
-            delivery_tag: delivery.delivery_tag(),
+        let delivery_tag = self.delivery_tag_of(&delivery)?;
+        enter_span!(DEBUG, "ack", channel_id = self.channel_id(), delivery_tag);
+        self.settle(
+            AmqpBasic::Ack(Ack {
+                delivery_tag,
+                multiple,
+            }),
+            delivery_tag,
             multiple,
-        }))
+        )
     }
 
     /// Asynchronously reject all messages consumers on this channel have received that have
     /// not yet been acknowledged. If `requeue` is true, instructs the server to attempt to requeue
     /// all such messages.
+    #[cfg(feature = "consume")]
     pub fn nack_all(&self, requeue: bool) -> Result<()> {
-        self.call_nowait(AmqpBasic::Nack(Nack {
-            delivery_tag: 0,
-            multiple: true,
-            requeue,
-        }))
+        self.settle(
+            AmqpBasic::Nack(Nack {
+                delivery_tag: 0,
+                multiple: true,
+                requeue,
+            }),
+            0,
+            true,
+        )
     }
 
+    /// Asynchronously reject the message with the given delivery tag and all unacknowledged
+    /// messages received on this channel with smaller tags, in a single frame. If `requeue` is
+    /// true, instructs the server to attempt to requeue them.
+    ///
+    /// As with [`ack_multiple`](#method.ack_multiple), `delivery_tag` must come from a
+    /// [`Delivery`](struct.Delivery.html) received on this channel.
+    #[cfg(feature = "consume")]
+    pub fn nack_multiple(&self, delivery_tag: u64, requeue: bool) -> Result<()> {
+        enter_span!(DEBUG, "nack", channel_id = self.channel_id(), delivery_tag);
+        self.settle(
+            AmqpBasic::Nack(Nack {
+                delivery_tag,
+                multiple: true,
+                requeue,
+            }),
+            delivery_tag,
+            true,
+        )
+    }
+
+    #[cfg(feature = "consume")]
     pub(crate) fn basic_nack(
         &self,
         delivery: Delivery,
         multiple: bool,
         requeue: bool,
     ) -> Result<()> {
-        self.call_nowait(AmqpBasic::Nack(Nack {
-            delivery_tag: delivery.delivery_tag(),
+        let delivery_tag = self.delivery_tag_of(&delivery)?;
+        enter_span!(DEBUG, "nack", channel_id = self.channel_id(), delivery_tag);
+        self.settle(
+            AmqpBasic::Nack(Nack {
+                delivery_tag,
+                multiple,
+                requeue,
+            }),
+            delivery_tag,
             multiple,
-            requeue,
-        }))
+        )
     }
 
+    #[cfg(feature = "consume")]
     pub(crate) fn basic_reject(&self, delivery: Delivery, requeue: bool) -> Result<()> {
-        self.call_nowait(AmqpBasic::Reject(Reject {
-            delivery_tag: delivery.delivery_tag(),
-            requeue,
-        }))
+        let delivery_tag = self.delivery_tag_of(&delivery)?;
+        enter_span!(
+            DEBUG,
+            "reject",
+            channel_id = self.channel_id(),
+            delivery_tag
+        );
+        self.settle(
+            AmqpBasic::Reject(Reject {
+                delivery_tag,
+                requeue,
+            }),
+            delivery_tag,
+            false,
+        )
     }
 
-    pub(crate) fn basic_cancel(&self, consumer: &Consumer) -> Result<()> {
+    #[cfg(feature = "consume")]
+    pub(crate) fn basic_cancel(&self, consumer_tag: &str) -> Result<()> {
         // NOTE: We currently don't support nowait cancel for related reasons
         // to not supproting nowait consume - we want the cancel-ok to clean
         // up channels in the I/O loop.
         self.call::<_, CancelOk>(AmqpBasic::Cancel(Cancel {
-            consumer_tag: consumer.consumer_tag().to_string(),
+            consumer_tag: consumer_tag.to_string(),
             nowait: false,
         }))
         .map(|_ok| ())
     }
+
+    #[cfg(test)]
+    pub(crate) fn inject_io_thread_panic(&self, message: &'static str) -> Result<()> {
+        self.inner.borrow_mut().inject_io_thread_panic(message)
+    }
 }
@@ -0,0 +1,218 @@
+use crate::deadline::Deadline;
+use crate::errors::*;
+use crate::io_loop::ChannelOpener;
+use crate::logging::{debug, trace};
+use crate::Channel;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+#[cfg(feature = "consume")]
+use crate::dispatcher::Dispatcher;
+
+/// A pool of channels on one connection, for code that needs a channel briefly and often (e.g.,
+/// once per request) and would rather not pay for opening and closing one each time.
+///
+/// Create a pool with [`Connection::channel_pool`](struct.Connection.html#method.channel_pool).
+/// Pools are `Send + Sync + Clone`; clones share the same channels. [`get`](#method.get) hands out
+/// a [`PooledChannel`](struct.PooledChannel.html), which derefs to
+/// [`Channel`](struct.Channel.html) and returns the channel to the pool when dropped.
+///
+/// A channel is only returned to the pool if it could be handed to the next user as if newly
+/// opened. Channels that have been closed (including by the server, e.g., after publishing to a
+/// nonexistent exchange), that are owed a reply to a call abandoned because of a
+/// [deadline](fn.with_deadline.html), or that have been put in
+/// [confirm](struct.Channel.html#method.enable_publisher_confirms) or
+/// [transaction](struct.Channel.html#method.tx_select) mode are discarded instead, and a new
+/// channel is opened in their place when one is next needed. Idle channels are checked again
+/// before being handed out, in case the server closed them in the meantime. Other changes to a
+/// channel's state (e.g., [`qos`](struct.Channel.html#method.qos) or a [returned message
+/// listener](struct.Channel.html#method.listen_for_returns)) carry over to the next user; undo
+/// them before dropping the `PooledChannel`.
+#[derive(Clone)]
+pub struct ChannelPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    opener: ChannelOpener,
+    #[cfg(feature = "consume")]
+    dispatcher: Arc<Dispatcher>,
+    max: usize,
+    state: Mutex<State>,
+    returned: Condvar,
+}
+
+struct State {
+    idle: Vec<Channel>,
+    // Channels that exist (idle or checked out), plus any being opened.
+    open: usize,
+}
+
+impl fmt::Debug for ChannelPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.inner.lock();
+        f.debug_struct("ChannelPool")
+            .field("max", &self.inner.max)
+            .field("open", &state.open)
+            .field("idle", &state.idle.len())
+            .finish()
+    }
+}
+
+impl Inner {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // Nothing we do while holding the lock can panic partway through an update.
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn open(&self) -> Result<Channel> {
+        let handle = self.opener.open()?;
+        Ok(Channel::new(
+            handle,
+            #[cfg(feature = "consume")]
+            Arc::clone(&self.dispatcher),
+        ))
+    }
+
+    fn checkin(&self, channel: Channel) {
+        let reusable = channel.is_reusable();
+        let mut state = self.lock();
+        if reusable {
+            trace!("returning channel {} to pool", channel.channel_id());
+            state.idle.push(channel);
+        } else {
+            debug!(
+                "discarding pooled channel {} - no longer reusable",
+                channel.channel_id()
+            );
+            state.open -= 1;
+            drop(state);
+            drop(channel);
+        }
+        self.returned.notify_one();
+    }
+}
+
+impl ChannelPool {
+    pub(crate) fn new(
+        opener: ChannelOpener,
+        #[cfg(feature = "consume")] dispatcher: Arc<Dispatcher>,
+        max: usize,
+    ) -> ChannelPool {
+        assert!(max > 0, "channel pool must allow at least one channel");
+        ChannelPool {
+            inner: Arc::new(Inner {
+                opener,
+                #[cfg(feature = "consume")]
+                dispatcher,
+                max,
+                state: Mutex::new(State {
+                    idle: Vec::new(),
+                    open: 0,
+                }),
+                returned: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Check out a channel: an idle one if there is one, or a newly opened one if fewer than the
+    /// pool's maximum exist. Otherwise, block until another user returns one (or, if a
+    /// [deadline](fn.with_deadline.html) is in effect, fail with
+    /// [`Error::DeadlineExceeded`](enum.Error.html#variant.DeadlineExceeded) once it passes).
+    ///
+    /// Returns the error from opening a channel if that fails (e.g., because the connection has
+    /// closed).
+    pub fn get(&self) -> Result<PooledChannel> {
+        let deadline = Deadline::current();
+        let mut state = self.inner.lock();
+        loop {
+            while let Some(channel) = state.idle.pop() {
+                if channel.is_reusable() {
+                    return Ok(self.pooled(channel));
+                }
+                debug!(
+                    "discarding idle pooled channel {} - no longer open",
+                    channel.channel_id()
+                );
+                state.open -= 1;
+            }
+            if state.open < self.inner.max {
+                state.open += 1;
+                drop(state);
+                return match self.inner.open() {
+                    Ok(channel) => Ok(self.pooled(channel)),
+                    Err(err) => {
+                        self.inner.lock().open -= 1;
+                        self.inner.returned.notify_one();
+                        Err(err)
+                    }
+                };
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if deadline.instant() <= now {
+                        return DeadlineExceededSnafu.fail();
+                    }
+                    self.inner
+                        .returned
+                        .wait_timeout(state, deadline.instant() - now)
+                        .unwrap_or_else(|err| err.into_inner())
+                        .0
+                }
+                None => self
+                    .inner
+                    .returned
+                    .wait(state)
+                    .unwrap_or_else(|err| err.into_inner()),
+            };
+        }
+    }
+
+    /// The most channels this pool will have open at once.
+    pub fn max(&self) -> usize {
+        self.inner.max
+    }
+
+    fn pooled(&self, channel: Channel) -> PooledChannel {
+        trace!("checked out pooled channel {}", channel.channel_id());
+        PooledChannel {
+            channel: Some(channel),
+            pool: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// A channel checked out of a [`ChannelPool`](struct.ChannelPool.html). Derefs to
+/// [`Channel`](struct.Channel.html); dropping it returns the channel to the pool (or discards
+/// it, if it can't be reused).
+pub struct PooledChannel {
+    // Only `None` while being dropped.
+    channel: Option<Channel>,
+    pool: Arc<Inner>,
+}
+
+impl fmt::Debug for PooledChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PooledChannel {{ channel_id: {}, .. }}", self.channel_id())
+    }
+}
+
+impl Deref for PooledChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Channel {
+        // unwrap is safe; channel is only taken in drop.
+        self.channel.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledChannel {
+    fn drop(&mut self) {
+        if let Some(channel) = self.channel.take() {
+            self.pool.checkin(channel);
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::logging::{debug, warn};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// A fault for the I/O thread to simulate, returned by the hooks of a
+/// [`FaultInjector`](trait.FaultInjector.html).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fault {
+    /// Proceed normally.
+    None,
+
+    /// Sleep on the I/O thread for the given duration, then proceed normally.
+    Delay(Duration),
+
+    /// Behave as though the socket returned `WouldBlock` instead of writing anything. The write
+    /// is retried the next time the I/O thread polls the socket. Only honored by
+    /// [`before_write`](trait.FaultInjector.html#method.before_write).
+    WouldBlock,
+
+    /// Write at most this many bytes (but at least 1) in this call. Only honored by
+    /// [`before_write`](trait.FaultInjector.html#method.before_write).
+    ShortWrite(usize),
+
+    /// Fail as though the socket returned an I/O error of the given kind. This is fatal to the
+    /// connection.
+    Error(io::ErrorKind),
+}
+
+/// Hooks consulted by the I/O thread at key points, allowing tests to inject faults without an
+/// intermediary proxy. Available with the `chaos` feature; see
+/// [`ConnectionOptions::fault_injector`](struct.ConnectionOptions.html#method.fault_injector).
+///
+/// Every hook defaults to [`Fault::None`](enum.Fault.html#variant.None). Hooks run on the I/O
+/// thread, so injectors that keep state must synchronize it themselves. Faults a hook does not
+/// support are ignored.
+pub trait FaultInjector: Send + Sync {
+    /// Called before each write to the socket, with the number of bytes waiting to be written.
+    fn before_write(&self, pending: usize) -> Fault {
+        let _ = pending;
+        Fault::None
+    }
+
+    /// Called after each frame is read from the socket and processed, with the number of frames
+    /// received on this connection so far (including those received during the handshake).
+    fn after_read(&self, frames_received: u64) -> Fault {
+        let _ = frames_received;
+        Fault::None
+    }
+
+    /// Called each time a heartbeat timer fires.
+    fn on_heartbeat(&self) -> Fault {
+        Fault::None
+    }
+}
+
+/// A [`FaultInjector`](trait.FaultInjector.html) that delays socket reads and writes by a
+/// pseudorandom amount of time.
+///
+/// The delays are drawn from a deterministic generator, so two injectors created with the same
+/// seed produce the same sequence of delays.
+#[derive(Debug)]
+pub struct RandomLatency {
+    max_delay: Duration,
+    state: AtomicU64,
+}
+
+impl RandomLatency {
+    /// Create an injector that delays every read and write by up to `max_delay`, with delays
+    /// drawn from a generator seeded with `seed`.
+    pub fn new(max_delay: Duration, seed: u64) -> RandomLatency {
+        RandomLatency {
+            max_delay,
+            // xorshift gets stuck at 0
+            state: AtomicU64::new(seed | 1),
+        }
+    }
+
+    fn next_delay(&self) -> Fault {
+        let mut x = self.state.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state.store(x, Ordering::Relaxed);
+        let max_nanos = self.max_delay.as_nanos() as u64;
+        if max_nanos == 0 {
+            return Fault::None;
+        }
+        Fault::Delay(Duration::from_nanos(x % (max_nanos + 1)))
+    }
+}
+
+impl FaultInjector for RandomLatency {
+    fn before_write(&self, _pending: usize) -> Fault {
+        self.next_delay()
+    }
+
+    fn after_read(&self, _frames_received: u64) -> Fault {
+        self.next_delay()
+    }
+}
+
+/// A [`FaultInjector`](trait.FaultInjector.html) that fails the connection with a
+/// `ConnectionReset` error once it has received a given number of frames.
+///
+/// The count is per connection, so reusing the same options for a new connection (e.g., to test
+/// reconnection logic) starts the count over.
+#[derive(Debug, Clone, Copy)]
+pub struct DropAfterNFrames {
+    frames: u64,
+}
+
+impl DropAfterNFrames {
+    /// Create an injector that fails the connection as soon as it has received `frames` frames.
+    /// The count includes the three frames the server sends during the handshake.
+    pub fn new(frames: u64) -> DropAfterNFrames {
+        DropAfterNFrames { frames }
+    }
+}
+
+impl FaultInjector for DropAfterNFrames {
+    fn after_read(&self, frames_received: u64) -> Fault {
+        if frames_received >= self.frames {
+            Fault::Error(io::ErrorKind::ConnectionReset)
+        } else {
+            Fault::None
+        }
+    }
+}
+
+// Shared, comparable handle to a user's injector, so ConnectionOptions can stay Clone + Debug +
+// PartialEq.
+#[derive(Clone)]
+pub(crate) struct FaultInjectorHandle(Arc<dyn FaultInjector>);
+
+impl FaultInjectorHandle {
+    pub(crate) fn new<F: FaultInjector + 'static>(injector: F) -> FaultInjectorHandle {
+        FaultInjectorHandle(Arc::new(injector))
+    }
+
+    // Returns the maximum number of bytes to write now.
+    pub(crate) fn before_write(&self, pending: usize) -> io::Result<usize> {
+        match self.0.before_write(pending) {
+            Fault::ShortWrite(n) => Ok(usize::max(n, 1)),
+            Fault::WouldBlock => Err(io::ErrorKind::WouldBlock.into()),
+            fault => apply("before_write", fault).map(|()| pending),
+        }
+    }
+
+    pub(crate) fn after_read(&self, frames_received: u64) -> io::Result<()> {
+        apply("after_read", self.0.after_read(frames_received))
+    }
+
+    pub(crate) fn on_heartbeat(&self) -> io::Result<()> {
+        apply("on_heartbeat", self.0.on_heartbeat())
+    }
+}
+
+fn apply(hook: &str, fault: Fault) -> io::Result<()> {
+    match fault {
+        Fault::None => Ok(()),
+        Fault::Delay(delay) => {
+            thread::sleep(delay);
+            Ok(())
+        }
+        Fault::Error(kind) => {
+            debug!("injecting {:?} error in {}", kind, hook);
+            Err(io::Error::new(kind, "injected fault"))
+        }
+        fault @ Fault::WouldBlock | fault @ Fault::ShortWrite(_) => {
+            warn!("ignoring unsupported fault {:?} from {}", fault, hook);
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Debug for FaultInjectorHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FaultInjector {{ .. }}")
+    }
+}
+
+impl PartialEq for FaultInjectorHandle {
+    fn eq(&self, other: &FaultInjectorHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_latency_is_deterministic_and_bounded() {
+        let max_delay = Duration::from_millis(5);
+        let a = RandomLatency::new(max_delay, 42);
+        let b = RandomLatency::new(max_delay, 42);
+        for _ in 0..100 {
+            let delay = a.before_write(0);
+            assert_eq!(delay, b.before_write(0));
+            match delay {
+                Fault::Delay(delay) => assert!(delay <= max_delay),
+                other => panic!("unexpected fault {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn drop_after_n_frames() {
+        let injector = DropAfterNFrames::new(3);
+        assert_eq!(injector.after_read(2), Fault::None);
+        assert_eq!(
+            injector.after_read(3),
+            Fault::Error(io::ErrorKind::ConnectionReset)
+        );
+    }
+
+    #[test]
+    fn short_write_is_at_least_one_byte() {
+        struct ZeroWrite;
+        impl FaultInjector for ZeroWrite {
+            fn before_write(&self, _pending: usize) -> Fault {
+                Fault::ShortWrite(0)
+            }
+        }
+        let handle = FaultInjectorHandle::new(ZeroWrite);
+        assert_eq!(handle.before_write(10).unwrap(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::Write;
+
+#[cfg(feature = "consume")]
+use crate::errors::*;
+#[cfg(feature = "consume")]
+use crate::AmqpProperties;
+#[cfg(feature = "consume")]
+use flate2::read::{GzDecoder, ZlibDecoder};
+#[cfg(feature = "consume")]
+use snafu::ResultExt;
+#[cfg(feature = "consume")]
+use std::borrow::Cow;
+#[cfg(feature = "consume")]
+use std::io::Read;
+
+/// Default limit on the size of a body inflated by
+/// [`Delivery::decompressed_body`](struct.Delivery.html#method.decompressed_body): 64 MiB.
+pub const DEFAULT_MAX_DECOMPRESSED_LEN: usize = 64 * 1024 * 1024;
+
+/// Compression algorithms for message bodies, identified to consumers by the `content_encoding`
+/// property.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip (RFC 1952); `content_encoding: gzip`.
+    Gzip,
+
+    /// zlib-wrapped deflate (RFC 1950), as produced by e.g. Python's `zlib.compress`;
+    /// `content_encoding: deflate`.
+    Deflate,
+}
+
+impl Compression {
+    /// The `content_encoding` property value identifying this algorithm.
+    pub fn content_encoding(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Deflate => "deflate",
+        }
+    }
+
+    pub(crate) fn compress(self, body: &[u8]) -> Vec<u8> {
+        let level = flate2::Compression::default();
+        // unwrap is safe: writing to a Vec cannot fail
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), level);
+                encoder.write_all(body).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    #[cfg(feature = "consume")]
+    fn from_content_encoding(content_encoding: &str) -> Option<Compression> {
+        let content_encoding = content_encoding.trim();
+        if content_encoding.eq_ignore_ascii_case("gzip") {
+            Some(Compression::Gzip)
+        } else if content_encoding.eq_ignore_ascii_case("deflate") {
+            Some(Compression::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "consume")]
+pub(crate) fn decompress<'a>(
+    properties: &AmqpProperties,
+    body: &'a [u8],
+    max_len: usize,
+) -> Result<Cow<'a, [u8]>> {
+    let compression = match properties
+        .content_encoding()
+        .as_ref()
+        .and_then(|encoding| Compression::from_content_encoding(encoding))
+    {
+        Some(compression) => compression,
+        None => return Ok(Cow::Borrowed(body)),
+    };
+    let decoder: Box<dyn Read + 'a> = match compression {
+        Compression::Gzip => Box::new(GzDecoder::new(body)),
+        Compression::Deflate => Box::new(ZlibDecoder::new(body)),
+    };
+    // Read at most one byte past the limit, so a decompression bomb never inflates further than
+    // that.
+    let mut inflated = Vec::new();
+    decoder
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut inflated)
+        .context(DecompressSnafu {
+            content_encoding: compression.content_encoding(),
+        })?;
+    if inflated.len() > max_len {
+        return DecompressedBodyTooLargeSnafu { max_len }.fail();
+    }
+    Ok(Cow::Owned(inflated))
+}
+
+#[cfg(all(test, feature = "consume"))]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    fn encoded(content_encoding: &str) -> AmqpProperties {
+        AmqpProperties::default().with_content_encoding(content_encoding.to_string())
+    }
+
+    #[test]
+    fn round_trips_both_algorithms() {
+        let body = b"hello hello hello hello hello hello".repeat(10);
+        for &compression in &[Compression::Gzip, Compression::Deflate] {
+            let compressed = compression.compress(&body);
+            assert!(compressed.len() < body.len());
+            let properties = encoded(compression.content_encoding());
+            let inflated = decompress(&properties, &compressed, body.len()).unwrap();
+            assert_eq!(&*inflated, &body[..]);
+        }
+    }
+
+    #[test]
+    fn other_encodings_are_passed_through() {
+        for properties in &[AmqpProperties::default(), encoded("identity")] {
+            match decompress(properties, b"raw", 0).unwrap() {
+                Cow::Borrowed(body) => assert_eq!(body, b"raw"),
+                Cow::Owned(_) => panic!("body should not be copied"),
+            }
+        }
+    }
+
+    #[test]
+    fn enforces_size_limit() {
+        let body = vec![0; 1 << 20];
+        let compressed = Compression::Gzip.compress(&body);
+        let properties = encoded("gzip");
+        assert!(decompress(&properties, &compressed, body.len()).is_ok());
+        match decompress(&properties, &compressed, body.len() - 1) {
+            Err(Error::DecompressedBodyTooLarge { max_len }) => assert_eq!(max_len, body.len() - 1),
+            other => panic!("unexpected result {:?}", other.map(|body| body.len())),
+        }
+    }
+
+    #[test]
+    fn corrupt_body_is_an_error() {
+        match decompress(&encoded("gzip"), b"not gzip", 1024) {
+            Err(Error::Decompress {
+                content_encoding, ..
+            }) => assert_eq!(content_encoding, "gzip"),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
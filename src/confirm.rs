@@ -1,4 +1,9 @@
-use std::collections::HashMap;
+use crate::errors::*;
+use crate::tag::SeqNo;
+use crate::Return;
+use crossbeam_channel::{Receiver, RecvTimeoutError, TryRecvError};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
 
 /// Payload for a publisher confirmation message (either an [ack](enum.Confirm.html#variant.Ack) or
 /// a [nack](enum.Confirm.html#variant.Nack)) from the server.
@@ -26,6 +31,49 @@ pub enum Confirm {
     Nack(ConfirmPayload),
 }
 
+/// How the server settled a message published with
+/// [`Channel::publish_confirmed`](struct.Channel.html#method.publish_confirmed).
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum ConfirmOutcome {
+    /// The server acked the message.
+    Acked,
+
+    /// The server nacked the message; it may or may not have been delivered.
+    Nacked,
+
+    /// The message was published as `mandatory` and the server returned it as unroutable (the
+    /// server acks such messages after returning them).
+    Returned(Return),
+}
+
+/// The outcome of one message published on a channel with a [publish result
+/// listener](struct.Channel.html#method.listen_for_publish_results).
+#[derive(Debug, Clone)]
+pub struct PublishResult {
+    /// The message's publisher confirm sequence number (see
+    /// [`Channel::next_publish_seq_no`](struct.Channel.html#method.next_publish_seq_no)).
+    pub seq_no: u64,
+
+    /// How the server handled the message.
+    pub outcome: PublishOutcome,
+}
+
+/// How the server handled a message reported in a [`PublishResult`](struct.PublishResult.html).
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum PublishOutcome {
+    /// The server acked the message and did not return it.
+    Routed,
+
+    /// The message was published as `mandatory`, and the server returned it as unroutable before
+    /// acking it.
+    Unroutable(Return),
+
+    /// The server nacked the message; it may or may not have been delivered.
+    Nacked,
+}
+
 /// Helper to smooth out of order and/or `multiple: true` publisher confirmation messages.
 ///
 /// If publisher confirms are enabled, the server may confirm messages out of order and/or may
@@ -82,8 +130,8 @@ pub enum Confirm {
 /// ```
 #[derive(Debug, Clone)]
 pub struct ConfirmSmoother {
-    expected: u64,
-    out_of_order: HashMap<u64, Confirm>,
+    expected: SeqNo,
+    out_of_order: HashMap<SeqNo, Confirm>,
 }
 
 impl Default for ConfirmSmoother {
@@ -103,7 +151,7 @@ impl ConfirmSmoother {
     /// received from the server to be `expected`.
     pub fn with_expected_delivery_tag(expected: u64) -> ConfirmSmoother {
         ConfirmSmoother {
-            expected,
+            expected: SeqNo::new(expected),
             out_of_order: HashMap::new(),
         }
     }
@@ -135,7 +183,6 @@ impl ConfirmSmoother {
         Iter {
             parent: self,
             payload,
-            next: None,
             to_confirm: move |tag| {
                 to_confirm(ConfirmPayload {
                     delivery_tag: tag,
@@ -150,7 +197,6 @@ impl ConfirmSmoother {
 struct Iter<'a, F: Fn(u64) -> Confirm> {
     parent: &'a mut ConfirmSmoother,
     payload: ConfirmPayload,
-    next: Option<Confirm>,
     to_confirm: F,
     done: bool,
 }
@@ -177,53 +223,184 @@ where
             return None;
         }
 
-        let payload = self.payload;
+        let expected = self.parent.expected;
+        let tag = SeqNo::new(self.payload.delivery_tag);
+
+        // A tag the server already confirmed on its own keeps that confirmation, even if our
+        // payload is a `multiple` confirm that also covers it.
+        let confirm = if let Some(stashed) = self.parent.out_of_order.remove(&expected) {
+            stashed
+        } else if expected.is_settled_by(tag, self.payload.multiple) {
+            (self.to_confirm)(expected.get())
+        } else {
+            // Either we're done with the payload and anything stashed right after it, or the
+            // payload is a single confirm for a future tag we need to hold onto until we get
+            // there. Anything else is a repeat of a tag we've already confirmed.
+            if tag > expected {
+                self.parent
+                    .out_of_order
+                    .insert(tag, (self.to_confirm)(tag.get()));
+            }
+            self.done = true;
+            return None;
+        };
+
+        self.parent.expected = expected.next();
+        Some(confirm)
+    }
+}
 
-        if payload.delivery_tag == self.parent.expected {
-            // exact match - we'll return this tag, and set next to an out-of-order
-            // entry for the next tag if we had one
-            self.parent.expected += 1;
-            self.next = self.parent.out_of_order.remove(&self.parent.expected);
-            return Some((self.to_confirm)(payload.delivery_tag));
+/// Tracks which messages published on a channel the server has yet to confirm, for publishers
+/// that keep a window of unconfirmed messages in flight rather than waiting on each one.
+///
+/// Create a tracker from the receiver returned by
+/// [`Channel::listen_for_publisher_confirms`](struct.Channel.html#method.listen_for_publisher_confirms),
+/// and [`track`](#method.track) each message's sequence number (from
+/// [`Channel::next_publish_seq_no`](struct.Channel.html#method.next_publish_seq_no)) _before_
+/// publishing it. The tracker reads confirms from the receiver whenever one of its methods is
+/// called.
+///
+/// If the channel closes (e.g., because the server closed it after a failed publish), the
+/// receiver disconnects and every message still outstanding is lost; the next
+/// [`wait_until_below`](#method.wait_until_below) returns an error with [kind
+/// `UnconfirmedPublishesLost`](enum.Error.html#variant.UnconfirmedPublishesLost) listing them.
+///
+/// # Example
+///
+/// ```rust
+/// use amiquip::{Channel, ConfirmTracker, Publish, Result};
+///
+/// fn publish_all(channel: &Channel, bodies: &[&[u8]]) -> Result<Vec<u64>> {
+///     let mut tracker = ConfirmTracker::new(channel.listen_for_publisher_confirms()?);
+///     channel.enable_publisher_confirms()?;
+///     for body in bodies {
+///         // keep at most 100 messages in flight
+///         tracker.wait_until_below(100, None)?;
+///         tracker.track(channel.next_publish_seq_no());
+///         channel.basic_publish("", Publish::new(body, "queue"))?;
+///     }
+///     tracker.wait_until_below(1, None)?;
+///     Ok(tracker.drain_nacked().collect())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ConfirmTracker {
+    confirms: Receiver<Confirm>,
+    outstanding: BTreeSet<SeqNo>,
+    nacked: Vec<u64>,
+    disconnected: bool,
+}
+
+impl ConfirmTracker {
+    /// Create a tracker reading confirms from `confirms`, with nothing outstanding.
+    pub fn new(confirms: Receiver<Confirm>) -> ConfirmTracker {
+        ConfirmTracker {
+            confirms,
+            outstanding: BTreeSet::new(),
+            nacked: Vec::new(),
+            disconnected: false,
         }
+    }
 
-        if payload.delivery_tag > self.parent.expected {
-            // tag is in the future; if it's "multiple", keep sending all tags in
-            // between where we are now and payload.delivery_tag
-            if payload.multiple {
-                let ret = (self.to_confirm)(self.parent.expected);
-                self.parent.expected += 1;
-                return Some(ret);
-            } else {
-                // if it's _not_ multiple, stash it away in out_of_order
-                self.parent.out_of_order.insert(
-                    payload.delivery_tag,
-                    (self.to_confirm)(payload.delivery_tag),
-                );
-                self.done = true;
-                return None;
+    /// Record that the message with sequence number `seq_no` is about to be published.
+    pub fn track(&mut self, seq_no: u64) {
+        self.outstanding.insert(SeqNo::new(seq_no));
+    }
+
+    /// The number of tracked messages the server has not yet acked or nacked.
+    pub fn outstanding(&mut self) -> usize {
+        self.process_available();
+        self.outstanding.len()
+    }
+
+    /// Block until fewer than `n` tracked messages are outstanding. Use `n = 1` to wait for
+    /// every tracked message to be confirmed.
+    ///
+    /// If `timeout` is given and it passes first, returns an error with [kind
+    /// `PublisherConfirmTimeout`](enum.Error.html#variant.PublisherConfirmTimeout). If the channel
+    /// closed with messages outstanding, returns an error with [kind
+    /// `UnconfirmedPublishesLost`](enum.Error.html#variant.UnconfirmedPublishesLost) and stops
+    /// tracking them.
+    pub fn wait_until_below(&mut self, n: usize, timeout: Option<Duration>) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            self.process_available();
+            if self.outstanding.len() < n {
+                return Ok(());
             }
+            if self.disconnected {
+                let seq_nos = std::mem::take(&mut self.outstanding);
+                return UnconfirmedPublishesLostSnafu {
+                    seq_nos: seq_nos.into_iter().map(SeqNo::get).collect::<Vec<_>>(),
+                }
+                .fail();
+            }
+            let confirm = match deadline {
+                Some(deadline) => match self.confirms.recv_deadline(deadline) {
+                    Ok(confirm) => confirm,
+                    Err(RecvTimeoutError::Timeout) => return PublisherConfirmTimeoutSnafu.fail(),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        self.disconnected = true;
+                        continue;
+                    }
+                },
+                None => match self.confirms.recv() {
+                    Ok(confirm) => confirm,
+                    Err(_) => {
+                        self.disconnected = true;
+                        continue;
+                    }
+                },
+            };
+            self.process(confirm);
         }
+    }
 
-        match self.next.take() {
-            Some(next) => {
-                // self.next is Some() only if a previous call to next() hit the tag==expected
-                // case _and_ out_of_order held a confirm for the next expected tag
-                self.parent.expected += 1;
-                self.next = self.parent.out_of_order.remove(&self.parent.expected);
-                Some(next)
-            }
-            None => {
-                self.done = true;
-                None
+    /// Removes and returns the sequence numbers of tracked messages the server has nacked, in the
+    /// order the nacks arrived.
+    pub fn drain_nacked(&mut self) -> impl Iterator<Item = u64> + '_ {
+        self.process_available();
+        self.nacked.drain(..)
+    }
+
+    fn process_available(&mut self) {
+        loop {
+            match self.confirms.try_recv() {
+                Ok(confirm) => self.process(confirm),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.disconnected = true;
+                    return;
+                }
             }
         }
     }
+
+    fn process(&mut self, confirm: Confirm) {
+        let (payload, acked) = match confirm {
+            Confirm::Ack(payload) => (payload, true),
+            Confirm::Nack(payload) => (payload, false),
+        };
+        let tag = SeqNo::new(payload.delivery_tag);
+        let settled = if payload.multiple {
+            let rest = self.outstanding.split_off(&tag.next());
+            std::mem::replace(&mut self.outstanding, rest)
+        } else if self.outstanding.remove(&tag) {
+            Some(tag).into_iter().collect()
+        } else {
+            BTreeSet::new()
+        };
+        if !acked {
+            self.nacked.extend(settled.into_iter().map(SeqNo::get));
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
+    use std::collections::{BTreeMap, BTreeSet};
 
     fn single(delivery_tag: u64, f: fn(ConfirmPayload) -> Confirm) -> Confirm {
         f(ConfirmPayload {
@@ -307,6 +484,124 @@ mod tests {
         );
     }
 
+    // A server-side model of publisher confirms: messages 1..=published start out
+    // unconfirmed, and confirming a tag settles it alone or (if `multiple`) along with every
+    // unconfirmed message before it.
+    struct Model {
+        unconfirmed: BTreeSet<u64>,
+        verdicts: BTreeMap<u64, Confirm>,
+        smoother: ConfirmSmoother,
+        smoothed: Vec<Confirm>,
+    }
+
+    impl Model {
+        fn confirm(&mut self, tag: u64, multiple: bool, ack: bool) {
+            let to_confirm = if ack { Confirm::Ack } else { Confirm::Nack };
+            let settled = if multiple {
+                self.unconfirmed.range(..=tag).cloned().collect()
+            } else {
+                vec![tag]
+            };
+            for settled in settled {
+                self.unconfirmed.remove(&settled);
+                self.verdicts.insert(settled, single(settled, to_confirm));
+            }
+            let raw = to_confirm(ConfirmPayload {
+                delivery_tag: tag,
+                multiple,
+            });
+            self.smoothed.extend(self.smoother.process(raw));
+        }
+    }
+
+    fn check_random_confirms(
+        published: u64,
+        steps: Vec<(prop::sample::Index, bool, bool)>,
+    ) -> Result<(), TestCaseError> {
+        let mut model = Model {
+            unconfirmed: (1..=published).collect(),
+            verdicts: BTreeMap::new(),
+            smoother: ConfirmSmoother::new(),
+            smoothed: Vec::new(),
+        };
+        for (index, multiple, ack) in steps {
+            if model.unconfirmed.is_empty() {
+                break;
+            }
+            let unconfirmed = model.unconfirmed.iter().cloned().collect::<Vec<_>>();
+            model.confirm(*index.get(&unconfirmed), multiple, ack);
+        }
+        // Settle whatever is left so every message ends up confirmed.
+        if !model.unconfirmed.is_empty() {
+            model.confirm(published, true, true);
+        }
+
+        // Every message comes out exactly once, in order, with the server's verdict for it.
+        let verdicts = model.verdicts.values().cloned().collect::<Vec<_>>();
+        prop_assert_eq!(model.smoothed, verdicts);
+        prop_assert!(model.smoother.out_of_order.is_empty());
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn random_confirms_are_settled_exactly_once(
+            published in 1u64..64,
+            steps in prop::collection::vec(any::<(prop::sample::Index, bool, bool)>(), 0..64),
+        ) {
+            check_random_confirms(published, steps)?;
+        }
+    }
+
+    #[test]
+    fn tracker_multiple_confirms_settle_ranges() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut tracker = ConfirmTracker::new(rx);
+        for seq_no in 1..=6 {
+            tracker.track(seq_no);
+        }
+        tx.send(single(5, Confirm::Ack)).unwrap();
+        tx.send(multiple(3, Confirm::Nack)).unwrap();
+        assert_eq!(tracker.outstanding(), 2);
+        assert_eq!(tracker.drain_nacked().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        // A multiple confirm covering tags already settled only settles the rest.
+        tx.send(multiple(6, Confirm::Ack)).unwrap();
+        tracker
+            .wait_until_below(1, Some(Duration::from_secs(1)))
+            .unwrap();
+        assert_eq!(tracker.drain_nacked().count(), 0);
+    }
+
+    #[test]
+    fn tracker_wait_times_out() {
+        let (_tx, rx) = crossbeam_channel::unbounded();
+        let mut tracker = ConfirmTracker::new(rx);
+        tracker.track(1);
+        tracker.wait_until_below(2, None).unwrap();
+        match tracker.wait_until_below(1, Some(Duration::from_millis(10))) {
+            Err(Error::PublisherConfirmTimeout) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tracker_channel_close_loses_outstanding() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut tracker = ConfirmTracker::new(rx);
+        for seq_no in 1..=3 {
+            tracker.track(seq_no);
+        }
+        tx.send(single(2, Confirm::Ack)).unwrap();
+        drop(tx);
+        match tracker.wait_until_below(1, None) {
+            Err(Error::UnconfirmedPublishesLost { seq_nos }) => assert_eq!(seq_nos, vec![1, 3]),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(tracker.outstanding(), 0);
+        tracker.wait_until_below(1, None).unwrap();
+    }
+
     #[test]
     fn drop_without_running_iter_to_completion() {
         let mut flat = ConfirmSmoother::new();
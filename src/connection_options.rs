@@ -1,16 +1,25 @@
+use crate::endpoint::Endpoint;
 use crate::errors::*;
-use crate::Sasl;
+use crate::frame_tap::FrameTapHandle;
+use crate::observer::ObserverHandle;
+use crate::proxy::Proxy;
+use crate::{ConnectionObserver, FrameTapEvent, Sasl};
 use amq_protocol::protocol::connection::{Open, Start, StartOk, Tune, TuneOk};
 use amq_protocol::protocol::constants::FRAME_MIN_SIZE;
 use amq_protocol::types::{AMQPValue, FieldTable};
 use std::time::Duration;
 
+#[cfg(feature = "chaos")]
+use crate::chaos::{FaultInjector, FaultInjectorHandle};
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use crate::ClientIdentity;
+
 /// Options that control the overall AMQP connection.
 ///
 /// `ConnectionOptions` uses the builder pattern. The default settings are equivalent to
 ///
 /// ```rust
-/// use amiquip::{Auth, ConnectionOptions};
+/// use amiquip::{Auth, ConnectionOptions, FieldTable};
 ///
 /// # fn default_connection_options() -> ConnectionOptions<Auth> {
 /// ConnectionOptions::default()
@@ -22,6 +31,7 @@ use std::time::Duration;
 ///     .heartbeat(60)
 ///     .connection_timeout(None)
 ///     .information(None)
+///     .client_properties(FieldTable::new())
 /// # }
 /// ```
 #[derive(Clone, Debug, PartialEq)]
@@ -33,7 +43,24 @@ pub struct ConnectionOptions<Auth: Sasl> {
     pub(crate) frame_max: u32,
     pub(crate) heartbeat: u16,
     pub(crate) connection_timeout: Option<Duration>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) handshake_timeout: Option<Duration>,
     information: Option<String>,
+    connection_name: Option<String>,
+    client_properties: FieldTable,
+    pub(crate) observer: Option<ObserverHandle>,
+    pub(crate) frame_tap: Option<FrameTapHandle>,
+    pub(crate) endpoints: Vec<Endpoint>,
+    pub(crate) shuffle_endpoints: bool,
+    pub(crate) proxy: Option<Proxy>,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub(crate) client_identity: Option<ClientIdentity>,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub(crate) tls_server_name: Option<String>,
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub(crate) danger_accept_invalid_hostnames: bool,
+    #[cfg(feature = "chaos")]
+    pub(crate) fault_injector: Option<FaultInjectorHandle>,
 }
 
 impl<Auth: Sasl> Default for ConnectionOptions<Auth> {
@@ -47,7 +74,24 @@ impl<Auth: Sasl> Default for ConnectionOptions<Auth> {
             frame_max: 0,
             heartbeat: 60,
             connection_timeout: None,
+            connect_timeout: None,
+            handshake_timeout: None,
             information: None,
+            connection_name: None,
+            client_properties: FieldTable::new(),
+            observer: None,
+            frame_tap: None,
+            endpoints: Vec::new(),
+            shuffle_endpoints: false,
+            proxy: None,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            client_identity: None,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            tls_server_name: None,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            danger_accept_invalid_hostnames: false,
+            #[cfg(feature = "chaos")]
+            fault_injector: None,
         }
     }
 }
@@ -101,13 +145,24 @@ impl<Auth: Sasl> ConnectionOptions<Auth> {
 
     /// Sets the heartbeat interval in seconds. Setting this value to 0 disables heartbeats. If
     /// this value is greater than 0 but different than the server's requested heartbeat interval,
-    /// the lower of the two will be used.
+    /// the lower of the two will be used. amiquip sends heartbeats at half the negotiated interval
+    /// (but no more than once per second).
     pub fn heartbeat(self, heartbeat: u16) -> Self {
         ConnectionOptions { heartbeat, ..self }
     }
 
     /// Sets the timeout for the initial TCP connection. If None (the default), there is no
     /// timeout.
+    ///
+    /// This timeout applies to each wait for the socket while connecting and handshaking, so a
+    /// server that keeps responding slowly can hold up opening a connection for longer. Once the
+    /// connection is open, it only applies while data is waiting to be written to the socket, and
+    /// the connection fails with
+    /// [`Error::ConnectionTimeout`](enum.Error.html#variant.ConnectionTimeout) if the socket
+    /// accepts none of it in time. An idle connection stays open indefinitely, and only
+    /// [heartbeats](#method.heartbeat) detect a server that has gone away. Use
+    /// [`connect_timeout`](#method.connect_timeout) and
+    /// [`handshake_timeout`](#method.handshake_timeout) to bound the total time instead.
     pub fn connection_timeout(self, connection_timeout: Option<Duration>) -> Self {
         ConnectionOptions {
             connection_timeout,
@@ -115,6 +170,28 @@ impl<Auth: Sasl> ConnectionOptions<Auth> {
         }
     }
 
+    /// Sets the maximum time to wait for the TCP connection to be established. If it expires,
+    /// opening the connection fails with
+    /// [`Error::ConnectTimeout`](enum.Error.html#variant.ConnectTimeout). By default there is no
+    /// limit.
+    pub fn connect_timeout(self, connect_timeout: Duration) -> Self {
+        ConnectionOptions {
+            connect_timeout: Some(connect_timeout),
+            ..self
+        }
+    }
+
+    /// Sets the maximum time from the TCP connection being established until the AMQP handshake
+    /// (including the TLS handshake, if any) completes. If it expires, opening the connection
+    /// fails with [`Error::HandshakeTimeout`](enum.Error.html#variant.HandshakeTimeout). By
+    /// default there is no limit.
+    pub fn handshake_timeout(self, handshake_timeout: Duration) -> Self {
+        ConnectionOptions {
+            handshake_timeout: Some(handshake_timeout),
+            ..self
+        }
+    }
+
     /// Sets the "information" string reported during handshaking to the server. This string
     /// is displayed in the RabbitMQ management interface under "Client properties" of a
     /// connection.
@@ -125,6 +202,156 @@ impl<Auth: Sasl> ConnectionOptions<Auth> {
         }
     }
 
+    /// Sets the "connection_name" client property reported during handshaking to the server.
+    /// RabbitMQ displays this name in its management interface and in `rabbitmqctl
+    /// list_connections`, which makes it easier to find the connection belonging to a
+    /// particular process.
+    pub fn connection_name<T: Into<String>>(self, connection_name: T) -> Self {
+        ConnectionOptions {
+            connection_name: Some(connection_name.into()),
+            ..self
+        }
+    }
+
+    /// Sets additional client properties reported during handshaking to the server. These are
+    /// merged with the properties amiquip sends on its own (`product`, `version`, `platform`, and
+    /// those set by [`information`](#method.information) and
+    /// [`connection_name`](#method.connection_name)); entries in `client_properties` take
+    /// precedence, except that a `capabilities` table is merged with the capabilities amiquip
+    /// requires rather than replacing them.
+    pub fn client_properties(self, client_properties: FieldTable) -> Self {
+        ConnectionOptions {
+            client_properties,
+            ..self
+        }
+    }
+
+    /// Sets a [`ConnectionObserver`](trait.ConnectionObserver.html) the I/O thread will notify
+    /// of handshake, frame, heartbeat, and close events. There is no observer by default.
+    ///
+    /// Connections opened with clones of these options share the observer.
+    pub fn observer(self, observer: Box<dyn ConnectionObserver>) -> Self {
+        ConnectionOptions {
+            observer: Some(ObserverHandle::new(observer)),
+            ..self
+        }
+    }
+
+    /// Sets a frame tap, which the I/O thread calls with every frame it reads from or writes to
+    /// the server (including heartbeats and the protocol header), for wire-level debugging. There
+    /// is no frame tap by default.
+    ///
+    /// Received frames are passed to the tap before amiquip processes them; sent frames are
+    /// passed once they have been written to the socket. The tap gets its own copy of each
+    /// frame, so it cannot change what is sent or received, but the I/O thread does nothing else
+    /// (including heartbeats) while it runs, so it should return quickly. A tap that panics is
+    /// caught and logged. Connections opened with clones of these options share the tap.
+    pub fn frame_tap(self, tap: Box<dyn Fn(FrameTapEvent) + Send>) -> Self {
+        ConnectionOptions {
+            frame_tap: Some(FrameTapHandle::new(tap)),
+            ..self
+        }
+    }
+
+    /// Sets the brokers [`Connection::insecure_open_endpoints`](struct.Connection.html#method.insecure_open_endpoints)
+    /// and [`Connection::open_endpoints`](struct.Connection.html#method.open_endpoints) try, in
+    /// order, until one accepts the connection. The connect and handshake timeouts apply to each
+    /// endpoint separately. There are no endpoints by default; the other ways of opening a
+    /// connection ignore them.
+    pub fn endpoints(self, endpoints: Vec<Endpoint>) -> Self {
+        ConnectionOptions { endpoints, ..self }
+    }
+
+    /// If true, [`endpoints`](#method.endpoints) are tried in a random order instead of the order
+    /// given, to spread connections from many clients across the brokers. Defaults to false.
+    pub fn shuffle_endpoints(self, shuffle_endpoints: bool) -> Self {
+        ConnectionOptions {
+            shuffle_endpoints,
+            ..self
+        }
+    }
+
+    /// Sets a [`Proxy`](enum.Proxy.html) that
+    /// [`Connection::insecure_open_endpoints`](struct.Connection.html#method.insecure_open_endpoints)
+    /// and [`Connection::open_endpoints`](struct.Connection.html#method.open_endpoints) connect
+    /// through; for TLS connections, the TLS handshake runs through the proxy as well. The
+    /// [connect timeout](#method.connect_timeout) bounds connecting to the proxy and each step of
+    /// the exchange with it. There is no proxy by default.
+    ///
+    /// To use a proxy with a stream-based method such as
+    /// [`Connection::insecure_open_stream`](struct.Connection.html#method.insecure_open_stream),
+    /// open the stream with [`Proxy::connect`](enum.Proxy.html#method.connect).
+    pub fn proxy(self, proxy: Proxy) -> Self {
+        ConnectionOptions {
+            proxy: Some(proxy),
+            ..self
+        }
+    }
+
+    /// Sets a [`ClientIdentity`](struct.ClientIdentity.html) (a client certificate and its private
+    /// key) to present to the server when opening a TLS connection, in addition to whatever the
+    /// [`TlsConnector`](struct.TlsConnector.html) is configured with. There is no identity by
+    /// default.
+    ///
+    /// native-tls connectors cannot be changed once they are built, so with native-tls the
+    /// connection is made with native-tls's default settings and this identity, rather than the
+    /// settings of the connector passed to the opening method. To combine a client certificate
+    /// with other native-tls settings, set the identity on the `native_tls::TlsConnectorBuilder`
+    /// instead.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn client_identity(self, client_identity: ClientIdentity) -> Self {
+        ConnectionOptions {
+            client_identity: Some(client_identity),
+            ..self
+        }
+    }
+
+    /// Sets the name to send to the server (SNI) and to verify its certificate against when
+    /// opening a TLS connection, in place of the domain or host being connected to. The TCP
+    /// connection is still made to the original address. This is useful when connecting by IP
+    /// address, or through a tunnel or load balancer whose address does not match the broker's
+    /// certificate. By default, the domain or host being connected to is used.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn tls_server_name<T: Into<String>>(self, tls_server_name: T) -> Self {
+        ConnectionOptions {
+            tls_server_name: Some(tls_server_name.into()),
+            ..self
+        }
+    }
+
+    /// Sets whether to accept a server certificate that is not valid for the server name. The
+    /// certificate chain is still verified. Defaults to false.
+    ///
+    /// **This is dangerous**: anyone holding any certificate issued by a trusted authority can
+    /// impersonate the server. Prefer [`tls_server_name`](#method.tls_server_name) if the
+    /// certificate is valid for some name other than the one you are connecting to.
+    ///
+    /// As with [`client_identity`](#method.client_identity), native-tls connections are made with
+    /// native-tls's default settings when this is set. rustls connections require a connector
+    /// whose root certificates are known, i.e., one created with
+    /// [`TlsConnector::rustls_with_roots`](struct.TlsConnector.html#method.rustls_with_roots) or
+    /// [`TlsConnector::rustls_with_webpki_roots`](struct.TlsConnector.html#method.rustls_with_webpki_roots);
+    /// other rustls connectors fail with [kind
+    /// `CannotSkipHostnameVerification`](enum.Error.html#variant.CannotSkipHostnameVerification).
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    pub fn danger_accept_invalid_hostnames(self, danger_accept_invalid_hostnames: bool) -> Self {
+        ConnectionOptions {
+            danger_accept_invalid_hostnames,
+            ..self
+        }
+    }
+
+    /// Sets a [`FaultInjector`](trait.FaultInjector.html) the I/O thread will consult to
+    /// simulate network faults. Only available with the `chaos` feature, which is intended for
+    /// testing; there is no injector by default.
+    #[cfg(feature = "chaos")]
+    pub fn fault_injector<F: FaultInjector + 'static>(self, fault_injector: F) -> Self {
+        ConnectionOptions {
+            fault_injector: Some(FaultInjectorHandle::new(fault_injector)),
+            ..self
+        }
+    }
+
     pub(crate) fn make_start_ok(&self, start: Start) -> Result<(StartOk, FieldTable)> {
         // helper to search space-separated strings (mechanisms and locales)
         fn server_supports(server: &str, client: &str) -> bool {
@@ -166,7 +393,23 @@ impl<Auth: Sasl> ConnectionOptions<Auth> {
         if let Some(information) = &self.information {
             set_prop("information", information.to_string());
         }
+        if let Some(connection_name) = &self.connection_name {
+            set_prop("connection_name", connection_name.to_string());
+        }
+
+        // merge in user-provided properties, keeping any capabilities they ask for alongside
+        // (but never instead of) the ones we depend on
         let mut capabilities = FieldTable::new();
+        for (k, v) in &self.client_properties {
+            match (k.as_str(), v) {
+                ("capabilities", AMQPValue::FieldTable(user_capabilities)) => {
+                    capabilities.extend(user_capabilities.clone())
+                }
+                _ => {
+                    client_properties.insert(k.clone(), v.clone());
+                }
+            }
+        }
         let mut set_cap = |k: &str| {
             capabilities.insert(k.to_string(), AMQPValue::Boolean(true));
         };
@@ -191,13 +434,13 @@ impl<Auth: Sasl> ConnectionOptions<Auth> {
     pub(crate) fn make_tune_ok(&self, tune: Tune) -> Result<TuneOk> {
         fn promote_0_u16(mut val: u16) -> u16 {
             if val == 0 {
-                val = u16::max_value();
+                val = u16::MAX;
             }
             val
         }
         fn promote_0_u32(mut val: u32) -> u32 {
             if val == 0 {
-                val = u32::max_value();
+                val = u32::MAX;
             }
             val
         }
@@ -236,6 +479,24 @@ impl<Auth: Sasl> ConnectionOptions<Auth> {
     }
 }
 
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+impl ConnectionOptions<crate::Auth> {
+    /// Default options that authenticate with a client certificate: the `EXTERNAL` mechanism
+    /// (as used by RabbitMQ's `rabbitmq_auth_mechanism_ssl` plugin), with `identity` presented
+    /// during the TLS handshake. See [`client_identity`](#method.client_identity) for how the
+    /// identity combines with the connector.
+    ///
+    /// If the server refuses the certificate, opening the connection fails with [kind
+    /// `InvalidCredentials`](enum.Error.html#variant.InvalidCredentials), as for rejected PLAIN
+    /// credentials. (With native-tls, this relies on recognizing OpenSSL's error messages; other
+    /// platforms report a TLS error.)
+    pub fn external_with_client_cert(identity: ClientIdentity) -> Self {
+        ConnectionOptions::default()
+            .auth(crate::Auth::External)
+            .client_identity(identity)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -326,7 +587,7 @@ mod tests {
         let options = ConnectionOptions::<Auth>::default().frame_max(frame_max);
 
         let tune = Tune {
-            channel_max: u16::max_value(),
+            channel_max: u16::MAX,
             frame_max: 1 << 17,
             heartbeat: 60,
         };
@@ -338,4 +599,55 @@ mod tests {
             err => panic!("unexpected error {}", err),
         }
     }
+
+    #[test]
+    fn client_properties_are_merged() {
+        let mut user_capabilities = FieldTable::new();
+        user_capabilities.insert("connection.blocked".to_string(), AMQPValue::Boolean(false));
+        user_capabilities.insert("custom".to_string(), AMQPValue::Boolean(true));
+        let mut user_properties = FieldTable::new();
+        user_properties.insert(
+            "app_id".to_string(),
+            AMQPValue::LongString("billing".to_string()),
+        );
+        user_properties.insert(
+            "capabilities".to_string(),
+            AMQPValue::FieldTable(user_capabilities),
+        );
+
+        let options = ConnectionOptions::<Auth>::default()
+            .connection_name("billing-1")
+            .client_properties(user_properties);
+        let start = Start {
+            version_major: 0,
+            version_minor: 9,
+            server_properties: FieldTable::new(),
+            mechanisms: "PLAIN".to_string(),
+            locales: options.locale.clone(),
+        };
+        let (start_ok, _) = options.make_start_ok(start).unwrap();
+        let props = start_ok.client_properties;
+
+        let long_string = |k: &str| match props.get(k) {
+            Some(AMQPValue::LongString(s)) => s.as_str(),
+            other => panic!("unexpected {} property {:?}", k, other),
+        };
+        assert_eq!(long_string("connection_name"), "billing-1");
+        assert_eq!(long_string("app_id"), "billing");
+        assert_eq!(long_string("product"), crate::built_info::PKG_NAME);
+
+        let capabilities = match props.get("capabilities") {
+            Some(AMQPValue::FieldTable(capabilities)) => capabilities,
+            other => panic!("unexpected capabilities {:?}", other),
+        };
+        assert_eq!(
+            capabilities.get("connection.blocked"),
+            Some(&AMQPValue::Boolean(true))
+        );
+        assert_eq!(
+            capabilities.get("consumer_cancel_notify"),
+            Some(&AMQPValue::Boolean(true))
+        );
+        assert_eq!(capabilities.get("custom"), Some(&AMQPValue::Boolean(true)));
+    }
 }
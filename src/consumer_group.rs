@@ -0,0 +1,378 @@
+use crate::errors::*;
+use crate::logging::{debug, warn};
+use crate::{Channel, Connection, ConsumerMessage, ConsumerOptions, Delivery};
+use crossbeam_channel::{select, Receiver, Sender};
+use snafu::ResultExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Options for starting a [`ConsumerGroup`](struct.ConsumerGroup.html).
+///
+/// The [`default`](#impl-Default) implementation uses a single channel, no consumer tag prefix,
+/// no prefetch limit, and the default [`ConsumerOptions`](struct.ConsumerOptions.html).
+#[derive(Clone, Debug)]
+pub struct ConsumerGroupOptions {
+    /// Total number of channels (and therefore consumers) to start. Channels are spread
+    /// round-robin across the connections given to
+    /// [`ConsumerGroup::start`](struct.ConsumerGroup.html#method.start).
+    pub channels: usize,
+
+    /// Options used for every consumer in the group.
+    /// [`stream_bodies_above`](struct.ConsumerOptions.html#structfield.stream_bodies_above) is
+    /// ignored; members always receive whole deliveries.
+    pub consumer: ConsumerOptions,
+
+    /// If set, member `i` will use the consumer tag `"{prefix}-{i}"`. If unset, the server will
+    /// assign consumer tags.
+    pub tag_prefix: Option<String>,
+
+    /// If set, each member calls [`Channel::qos`](struct.Channel.html#method.qos) with this
+    /// prefetch count before starting its consumer.
+    pub prefetch_count: Option<u16>,
+}
+
+impl Default for ConsumerGroupOptions {
+    fn default() -> ConsumerGroupOptions {
+        ConsumerGroupOptions {
+            channels: 1,
+            consumer: ConsumerOptions::default(),
+            tag_prefix: None,
+            prefetch_count: None,
+        }
+    }
+}
+
+/// A delivery received by one member of a [`ConsumerGroup`](struct.ConsumerGroup.html).
+///
+/// Deliveries are tied to the channel they were received on; pass this back to the group's
+/// [`ack`](struct.ConsumerGroup.html#method.ack) (or related) methods to route the
+/// acknowledgment to the correct channel.
+#[derive(Debug)]
+pub struct GroupDelivery {
+    member: usize,
+
+    /// The underlying delivery.
+    pub delivery: Delivery,
+}
+
+impl GroupDelivery {
+    /// The index of the group member that received this delivery.
+    #[inline]
+    pub fn member(&self) -> usize {
+        self.member
+    }
+}
+
+/// Messages delivered to the merged receiver of a [`ConsumerGroup`](struct.ConsumerGroup.html).
+// Clippy warns about Delivery being much larger than the other variants, but we expect almost all
+// instances of ConsumerGroupMessage to be Deliveries.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum ConsumerGroupMessage {
+    /// A delivered message.
+    Delivery(GroupDelivery),
+
+    /// A member's consumer ended; `reason` is the non-delivery
+    /// [`ConsumerMessage`](enum.ConsumerMessage.html) that ended it. The rest of the group
+    /// continues to run; the member can be restarted with
+    /// [`ConsumerGroup::replace_member`](struct.ConsumerGroup.html#method.replace_member).
+    MemberStopped {
+        member: usize,
+        reason: ConsumerMessage,
+    },
+
+    /// A member failed to acknowledge a delivery and has stopped. The rest of the group
+    /// continues to run.
+    MemberFailed { member: usize, error: Error },
+}
+
+enum MemberCommand {
+    Ack(Delivery),
+    Nack(Delivery, bool),
+    Reject(Delivery, bool),
+}
+
+struct Member {
+    tx: Sender<MemberCommand>,
+    running: Arc<AtomicBool>,
+    join_handle: JoinHandle<()>,
+}
+
+/// A single logical subscription to a queue spread across multiple channels (and optionally
+/// multiple connections).
+///
+/// Each member of the group owns one channel and runs one consumer on a dedicated thread.
+/// Deliveries from every member are merged into a single receiver. Acknowledgments must go back
+/// through the group so they are sent on the channel that received the delivery.
+///
+/// If one member fails (e.g., because the server closed its channel), the failure is reported
+/// through the merged receiver and the remaining members keep running.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use amiquip::{
+///     Connection, ConsumerGroup, ConsumerGroupMessage, ConsumerGroupOptions, Result,
+/// };
+///
+/// fn consume(connections: &mut [Connection]) -> Result<()> {
+///     let options = ConsumerGroupOptions {
+///         channels: 4,
+///         prefetch_count: Some(10),
+///         ..ConsumerGroupOptions::default()
+///     };
+///     let group = ConsumerGroup::start(connections, "work", options)?;
+///     for message in group.receiver().iter() {
+///         match message {
+///             ConsumerGroupMessage::Delivery(delivery) => group.ack(delivery)?,
+///             other => println!("group member ended: {:?}", other),
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct ConsumerGroup {
+    queue: String,
+    options: ConsumerGroupOptions,
+    members: Vec<Option<Member>>,
+    tx: Sender<ConsumerGroupMessage>,
+    rx: Receiver<ConsumerGroupMessage>,
+}
+
+impl Drop for ConsumerGroup {
+    fn drop(&mut self) {
+        for member in self.members.drain(..).flatten() {
+            // Dropping the command sender tells the member thread to cancel its consumer and
+            // close its channel.
+            drop(member.tx);
+            let _ = member.join_handle.join();
+        }
+    }
+}
+
+impl ConsumerGroup {
+    /// Start a group of `options.channels` consumers on `queue`, opening channels round-robin
+    /// across `connections`.
+    ///
+    /// If any member fails to start, the members started so far are shut down and the error is
+    /// returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `connections` is empty.
+    pub fn start<S: Into<String>>(
+        connections: &mut [Connection],
+        queue: S,
+        options: ConsumerGroupOptions,
+    ) -> Result<ConsumerGroup> {
+        assert!(
+            !connections.is_empty(),
+            "consumer group requires at least one connection"
+        );
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut group = ConsumerGroup {
+            queue: queue.into(),
+            members: Vec::with_capacity(options.channels),
+            options,
+            tx,
+            rx,
+        };
+        for i in 0..group.options.channels {
+            let connection = &mut connections[i % connections.len()];
+            let member = group.spawn_member(i, connection)?;
+            group.members.push(Some(member));
+        }
+        Ok(group)
+    }
+
+    /// The receiver on which deliveries (and member failures) from every member of the group are
+    /// delivered. The group keeps the sending side open (so stopped members can be
+    /// [replaced](#method.replace_member)), so this receiver does not disconnect when every member
+    /// has stopped; use [`running_members`](#method.running_members) to detect that case.
+    #[inline]
+    pub fn receiver(&self) -> &Receiver<ConsumerGroupMessage> {
+        &self.rx
+    }
+
+    /// Number of members in the group, including members that have stopped.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns true if the group has no members.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Returns the indices of members that are still running.
+    pub fn running_members(&self) -> Vec<usize> {
+        self.members
+            .iter()
+            .enumerate()
+            .filter_map(|(i, member)| match member {
+                Some(member) if member.running.load(Ordering::SeqCst) => Some(i),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Replace member `member` (typically after receiving
+    /// [`MemberStopped`](enum.ConsumerGroupMessage.html#variant.MemberStopped) or
+    /// [`MemberFailed`](enum.ConsumerGroupMessage.html#variant.MemberFailed) for it) with a new
+    /// channel and consumer opened on `connection`. If the old member is still running, it is
+    /// shut down first.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `member` is not less than [`len`](#method.len).
+    pub fn replace_member(&mut self, member: usize, connection: &mut Connection) -> Result<()> {
+        if let Some(old) = self.members[member].take() {
+            drop(old.tx);
+            let _ = old.join_handle.join();
+        }
+        let new = self.spawn_member(member, connection)?;
+        self.members[member] = Some(new);
+        Ok(())
+    }
+
+    /// Acknowledge `delivery` on the channel of the member that received it.
+    pub fn ack(&self, delivery: GroupDelivery) -> Result<()> {
+        self.send_command(delivery.member, MemberCommand::Ack(delivery.delivery))
+    }
+
+    /// Nack `delivery` on the channel of the member that received it.
+    pub fn nack(&self, delivery: GroupDelivery, requeue: bool) -> Result<()> {
+        self.send_command(
+            delivery.member,
+            MemberCommand::Nack(delivery.delivery, requeue),
+        )
+    }
+
+    /// Reject `delivery` on the channel of the member that received it.
+    pub fn reject(&self, delivery: GroupDelivery, requeue: bool) -> Result<()> {
+        self.send_command(
+            delivery.member,
+            MemberCommand::Reject(delivery.delivery, requeue),
+        )
+    }
+
+    fn send_command(&self, member: usize, command: MemberCommand) -> Result<()> {
+        let stopped = || ConsumerGroupMemberStoppedSnafu { member }.build();
+        let handle = self.members[member].as_ref().ok_or_else(stopped)?;
+        handle.tx.send(command).map_err(|_| stopped())
+    }
+
+    fn spawn_member(&self, member: usize, connection: &mut Connection) -> Result<Member> {
+        let channel = connection.open_channel(None)?;
+        let queue = self.queue.clone();
+        let options = self.options.clone();
+        let events = self.tx.clone();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let (started_tx, started_rx) = crossbeam_channel::bounded(1);
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = Arc::clone(&running);
+
+        let join_handle = thread::Builder::new()
+            .name(format!("amiquip-group-{}", member))
+            .spawn(move || {
+                run_member(member, channel, queue, options, events, rx, started_tx);
+                thread_running.store(false, Ordering::SeqCst);
+            })
+            .context(ForkFailedSnafu)?;
+
+        match started_rx.recv() {
+            Ok(Ok(())) => Ok(Member {
+                tx,
+                running,
+                join_handle,
+            }),
+            Ok(Err(err)) => {
+                let _ = join_handle.join();
+                Err(err)
+            }
+            Err(_) => {
+                let _ = join_handle.join();
+                ConsumerGroupMemberStoppedSnafu { member }.fail()
+            }
+        }
+    }
+}
+
+fn run_member(
+    member: usize,
+    channel: Channel,
+    queue: String,
+    options: ConsumerGroupOptions,
+    events: Sender<ConsumerGroupMessage>,
+    commands: Receiver<MemberCommand>,
+    started: Sender<Result<()>>,
+) {
+    let consumer_tag = match &options.tag_prefix {
+        Some(prefix) => format!("{}-{}", prefix, member),
+        None => String::new(),
+    };
+    let consumer = options
+        .prefetch_count
+        .map_or(Ok(()), |count| channel.qos(0, count, false))
+        .and_then(|()| {
+            let consumer_options = ConsumerOptions {
+                stream_bodies_above: None,
+                ..options.consumer
+            };
+            channel.basic_consume_with_tag(queue, consumer_tag, consumer_options)
+        });
+    let consumer = match consumer {
+        Ok(consumer) => {
+            let _ = started.send(Ok(()));
+            consumer
+        }
+        Err(err) => {
+            let _ = started.send(Err(err));
+            return;
+        }
+    };
+    debug!(
+        "consumer group member {} started on channel {} ({})",
+        member,
+        channel.channel_id(),
+        consumer.consumer_tag()
+    );
+
+    loop {
+        select! {
+            recv(consumer.receiver()) -> message => match message {
+                Ok(ConsumerMessage::Delivery(delivery)) => {
+                    let delivery = GroupDelivery { member, delivery };
+                    if events.send(ConsumerGroupMessage::Delivery(delivery)).is_err() {
+                        break;
+                    }
+                }
+                Ok(reason) => {
+                    warn!("consumer group member {} stopped: {:?}", member, reason);
+                    let _ = events.send(ConsumerGroupMessage::MemberStopped { member, reason });
+                    break;
+                }
+                Err(_) => break,
+            },
+            recv(commands) -> command => {
+                let result = match command {
+                    Ok(MemberCommand::Ack(delivery)) => consumer.ack(delivery),
+                    Ok(MemberCommand::Nack(delivery, requeue)) => consumer.nack(delivery, requeue),
+                    Ok(MemberCommand::Reject(delivery, requeue)) => {
+                        consumer.reject(delivery, requeue)
+                    }
+                    // group has been dropped or is replacing this member
+                    Err(_) => break,
+                };
+                if let Err(error) = result {
+                    warn!("consumer group member {} failed: {}", member, error);
+                    let _ = events.send(ConsumerGroupMessage::MemberFailed { member, error });
+                    break;
+                }
+            },
+        }
+    }
+}
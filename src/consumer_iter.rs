@@ -0,0 +1,242 @@
+use crate::logging::debug;
+use crate::{Consumer, ConsumerMessage, Delivery, Result};
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+use std::thread;
+
+/// When the iterator returned by [`Consumer::iter_ack`](struct.Consumer.html#method.iter_ack)
+/// acknowledges the deliveries it yields.
+///
+/// In the two automatic modes, a delivery that is still unsettled when the thread panics (e.g.,
+/// because the loop body handling it panicked) is nacked with `requeue` set instead, so the
+/// server redelivers it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IterAckMode {
+    /// Ack each delivery when its [`IterDelivery`](struct.IterDelivery.html) is dropped; in a
+    /// `for` loop, at the end of the iteration that handled it.
+    AckOnDrop,
+
+    /// Ack each delivery when the next one is requested, or when the iterator is dropped, even if
+    /// its [`IterDelivery`](struct.IterDelivery.html) is still held.
+    AckBeforeYieldNext,
+
+    /// Never ack automatically. Settle each delivery with the methods on
+    /// [`IterDelivery`](struct.IterDelivery.html), or take it with
+    /// [`into_delivery`](struct.IterDelivery.html#method.into_delivery) and settle it through the
+    /// consumer.
+    Manual,
+}
+
+// A copy of an in-flight delivery without its body, for settling it. Whoever takes it out (the
+// `IterDelivery`, or the iterator in `AckBeforeYieldNext` mode) settles it.
+type SettleSlot = Rc<RefCell<Option<Delivery>>>;
+
+fn settle(consumer: &Consumer, delivery: Delivery) {
+    let delivery_tag = delivery.delivery_tag();
+    let result = if thread::panicking() {
+        consumer.nack(delivery, true)
+    } else {
+        consumer.ack(delivery)
+    };
+    if let Err(err) = result {
+        debug!("failed to settle delivery {}: {}", delivery_tag, err);
+    }
+}
+
+/// Iterator over the deliveries of a [`Consumer`](struct.Consumer.html), created by
+/// [`Consumer::iter_ack`](struct.Consumer.html#method.iter_ack).
+pub struct ConsumerIter<'c> {
+    consumer: &'c Consumer<'c>,
+    mode: IterAckMode,
+    in_flight: Option<SettleSlot>,
+}
+
+impl Drop for ConsumerIter<'_> {
+    fn drop(&mut self) {
+        self.settle_in_flight();
+    }
+}
+
+impl<'c> ConsumerIter<'c> {
+    pub(crate) fn new(consumer: &'c Consumer<'c>, mode: IterAckMode) -> ConsumerIter<'c> {
+        ConsumerIter {
+            consumer,
+            mode,
+            in_flight: None,
+        }
+    }
+
+    fn settle_in_flight(&mut self) {
+        if let Some(slot) = self.in_flight.take() {
+            if let Some(delivery) = slot.borrow_mut().take() {
+                settle(self.consumer, delivery);
+            }
+        }
+    }
+
+    fn track(&mut self, mut delivery: Delivery) -> IterDelivery<'c> {
+        let slot = match self.mode {
+            IterAckMode::Manual => None,
+            IterAckMode::AckOnDrop | IterAckMode::AckBeforeYieldNext => {
+                let body = mem::take(&mut delivery.body);
+                let slot = Rc::new(RefCell::new(Some(delivery.clone())));
+                delivery.body = body;
+                if self.mode == IterAckMode::AckBeforeYieldNext {
+                    self.in_flight = Some(Rc::clone(&slot));
+                }
+                Some(slot)
+            }
+        };
+        IterDelivery {
+            consumer: self.consumer,
+            mode: self.mode,
+            delivery: Some(delivery),
+            slot,
+        }
+    }
+}
+
+impl<'c> Iterator for ConsumerIter<'c> {
+    type Item = IterDelivery<'c>;
+
+    fn next(&mut self) -> Option<IterDelivery<'c>> {
+        self.settle_in_flight();
+        loop {
+            let delivery = match self.consumer.receiver().recv().ok()? {
+                ConsumerMessage::Delivery(delivery) => delivery,
+                ConsumerMessage::DeliveryStream(stream) => match stream.read_whole() {
+                    (delivery, Ok(())) => delivery,
+                    // The channel closed mid-body, so the consumer's final message follows; give
+                    // the delivery back in case the server has not requeued it already.
+                    (delivery, Err(err)) => {
+                        debug!("failed to read streamed delivery: {}", err);
+                        let delivery_tag = delivery.delivery_tag();
+                        if let Err(err) = self.consumer.nack(delivery, true) {
+                            debug!("failed to requeue delivery {}: {}", delivery_tag, err);
+                        }
+                        continue;
+                    }
+                },
+                message => {
+                    self.consumer.set_termination(message);
+                    return None;
+                }
+            };
+            return Some(self.track(delivery));
+        }
+    }
+}
+
+/// A delivery yielded by [`ConsumerIter`](struct.ConsumerIter.html). It dereferences to the
+/// [`Delivery`](struct.Delivery.html), and is acknowledged according to the iterator's
+/// [`IterAckMode`](enum.IterAckMode.html) unless settled explicitly with one of its methods.
+pub struct IterDelivery<'c> {
+    consumer: &'c Consumer<'c>,
+    mode: IterAckMode,
+    delivery: Option<Delivery>,
+    slot: Option<SettleSlot>,
+}
+
+impl Drop for IterDelivery<'_> {
+    fn drop(&mut self) {
+        let settle_now = match self.mode {
+            IterAckMode::AckOnDrop => true,
+            IterAckMode::AckBeforeYieldNext => thread::panicking(),
+            IterAckMode::Manual => false,
+        };
+        if settle_now {
+            if let Some(delivery) = self.disarm() {
+                settle(self.consumer, delivery);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for IterDelivery<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IterDelivery")
+            .field("mode", &self.mode)
+            .field("delivery", &self.delivery)
+            .finish()
+    }
+}
+
+impl Deref for IterDelivery<'_> {
+    type Target = Delivery;
+
+    fn deref(&self) -> &Delivery {
+        // unwrap is safe: only methods consuming `self` take the delivery.
+        self.delivery.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for IterDelivery<'_> {
+    fn deref_mut(&mut self) -> &mut Delivery {
+        self.delivery.as_mut().unwrap()
+    }
+}
+
+impl IterDelivery<'_> {
+    // Stop the automatic ack, returning the copy it would have used if it had not happened yet.
+    fn disarm(&mut self) -> Option<Delivery> {
+        self.slot.take().and_then(|slot| slot.borrow_mut().take())
+    }
+
+    /// Take the delivery, turning off its automatic acknowledgment. It must then be settled
+    /// through the consumer (e.g., with [`Consumer::ack`](struct.Consumer.html#method.ack)).
+    ///
+    /// In [`AckBeforeYieldNext`](enum.IterAckMode.html#variant.AckBeforeYieldNext) mode, the
+    /// delivery may already have been acknowledged if a later one has been requested.
+    pub fn into_delivery(mut self) -> Delivery {
+        self.disarm();
+        // unwrap is safe: only methods consuming `self` take the delivery.
+        self.delivery.take().unwrap()
+    }
+
+    /// Acknowledge the delivery now, through the consumer (so following its
+    /// [`AckPolicy`](enum.AckPolicy.html)). Does nothing if it was already acknowledged
+    /// automatically.
+    pub fn ack(self) -> Result<()> {
+        let consumer = self.consumer;
+        match self.take_unsettled() {
+            Some(delivery) => consumer.ack(delivery),
+            None => Ok(()),
+        }
+    }
+
+    /// Nack the delivery now, asking the server to requeue it if `requeue` is true. Does nothing
+    /// if it was already acknowledged automatically.
+    pub fn nack(self, requeue: bool) -> Result<()> {
+        let consumer = self.consumer;
+        match self.take_unsettled() {
+            Some(delivery) => consumer.nack(delivery, requeue),
+            None => Ok(()),
+        }
+    }
+
+    /// Reject the delivery now, asking the server to requeue it if `requeue` is true. Does
+    /// nothing if it was already acknowledged automatically.
+    pub fn reject(self, requeue: bool) -> Result<()> {
+        let consumer = self.consumer;
+        match self.take_unsettled() {
+            Some(delivery) => consumer.reject(delivery, requeue),
+            None => Ok(()),
+        }
+    }
+
+    // The delivery, if it has not been settled yet. In manual mode nothing settles it for us.
+    fn take_unsettled(mut self) -> Option<Delivery> {
+        let unsettled = match self.slot.take() {
+            Some(slot) => slot.borrow_mut().take().is_some(),
+            None => true,
+        };
+        if unsettled {
+            self.delivery.take()
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use crate::errors::*;
+use crate::{AmqpProperties, AmqpValue, FieldTable};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const X_DEATH: &str = "x-death";
+const X_DELIVERY_COUNT: &str = "x-delivery-count";
+
+/// One entry of a message's `x-death` header, recording how many times the message was
+/// dead-lettered from a queue for a given reason.
+///
+/// The server keeps one entry per queue and reason, most recent first. Keys missing from an entry
+/// are left empty (or zero, or `None`), and keys this type does not know about are ignored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XDeath {
+    /// The queue the message was dead-lettered from.
+    pub queue: String,
+
+    /// The exchange the message was published to before it was dead-lettered from `queue`.
+    pub exchange: String,
+
+    /// Why the message was dead-lettered (e.g., `rejected`, `expired`, `maxlen`, or
+    /// `delivery_limit`).
+    pub reason: String,
+
+    /// How many times the message was dead-lettered from `queue` for `reason`.
+    pub count: u64,
+
+    /// When the message was first dead-lettered from `queue` for `reason`.
+    pub time: Option<SystemTime>,
+
+    /// The routing keys (including `CC` and `BCC` keys) the message was published with.
+    pub routing_keys: Vec<String>,
+}
+
+impl XDeath {
+    fn from_table(table: &FieldTable) -> Result<XDeath> {
+        let mut death = XDeath {
+            queue: String::new(),
+            exchange: String::new(),
+            reason: String::new(),
+            count: 0,
+            time: None,
+            routing_keys: Vec::new(),
+        };
+        for (key, value) in table {
+            match key.as_str() {
+                "queue" => death.queue = string(key, value)?,
+                "exchange" => death.exchange = string(key, value)?,
+                "reason" => death.reason = string(key, value)?,
+                "count" => death.count = integer(key, value)?,
+                "time" => {
+                    let secs = match value {
+                        AmqpValue::Timestamp(secs) => *secs,
+                        value => integer(key, value)?,
+                    };
+                    death.time = Some(UNIX_EPOCH + Duration::from_secs(secs));
+                }
+                "routing-keys" => match value {
+                    AmqpValue::FieldArray(keys) => {
+                        death.routing_keys = keys
+                            .iter()
+                            .map(|routing_key| string(key, routing_key))
+                            .collect::<Result<_>>()?;
+                    }
+                    value => return malformed(format!("routing-keys is {:?}", value)),
+                },
+                _ => (),
+            }
+        }
+        Ok(death)
+    }
+}
+
+pub(crate) fn death_history(properties: &AmqpProperties) -> Result<Vec<XDeath>> {
+    let entries = match header(properties, X_DEATH) {
+        Some(AmqpValue::FieldArray(entries)) => entries,
+        Some(value) => return malformed(format!("expected an array, got {:?}", value)),
+        None => return Ok(Vec::new()),
+    };
+    entries
+        .iter()
+        .map(|entry| match entry {
+            AmqpValue::FieldTable(table) => XDeath::from_table(table),
+            entry => malformed(format!("expected a table, got {:?}", entry)),
+        })
+        .collect()
+}
+
+pub(crate) fn delivery_count(properties: &AmqpProperties) -> Result<Option<u32>> {
+    let value = match header(properties, X_DELIVERY_COUNT) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let count = match integer(X_DELIVERY_COUNT, value).map(u32::try_from) {
+        Ok(Ok(count)) => count,
+        _ => {
+            return MalformedHeaderSnafu {
+                header: X_DELIVERY_COUNT,
+                message: format!("expected a 32-bit count, got {:?}", value),
+            }
+            .fail()
+        }
+    };
+    Ok(Some(count))
+}
+
+fn header<'a>(properties: &'a AmqpProperties, name: &str) -> Option<&'a AmqpValue> {
+    properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.get(name))
+}
+
+fn malformed<T>(message: String) -> Result<T> {
+    MalformedHeaderSnafu {
+        header: X_DEATH,
+        message,
+    }
+    .fail()
+}
+
+fn string(key: &str, value: &AmqpValue) -> Result<String> {
+    match value {
+        AmqpValue::LongString(s) => Ok(s.clone()),
+        value => malformed(format!("{} is {:?}, expected a string", key, value)),
+    }
+}
+
+// Accepts any non-negative integer, whatever its width; the server's choice of width has varied.
+fn integer(key: &str, value: &AmqpValue) -> Result<u64> {
+    let n = match *value {
+        AmqpValue::ShortShortInt(n) => i64::from(n),
+        AmqpValue::ShortShortUInt(n) => i64::from(n),
+        AmqpValue::ShortInt(n) => i64::from(n),
+        AmqpValue::ShortUInt(n) => i64::from(n),
+        AmqpValue::LongInt(n) => i64::from(n),
+        AmqpValue::LongUInt(n) => i64::from(n),
+        AmqpValue::LongLongInt(n) => n,
+        _ => -1,
+    };
+    match u64::try_from(n) {
+        Ok(n) => Ok(n),
+        Err(_) => malformed(format!(
+            "{} is {:?}, expected a non-negative integer",
+            key, value
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    fn properties(name: &str, value: AmqpValue) -> AmqpProperties {
+        let mut headers = FieldTable::new();
+        headers.insert(name.to_string(), value);
+        AmqpProperties::default().with_headers(headers)
+    }
+
+    fn string(s: &str) -> AmqpValue {
+        AmqpValue::LongString(s.to_string())
+    }
+
+    #[test]
+    fn parses_death_history() {
+        let mut first = FieldTable::new();
+        first.insert("queue".to_string(), string("work"));
+        first.insert("exchange".to_string(), string("jobs"));
+        first.insert("reason".to_string(), string("rejected"));
+        first.insert("count".to_string(), AmqpValue::LongLongInt(3));
+        first.insert("time".to_string(), AmqpValue::Timestamp(1_600_000_000));
+        first.insert(
+            "routing-keys".to_string(),
+            AmqpValue::FieldArray(vec![string("a"), string("b")]),
+        );
+        first.insert("original-expiration".to_string(), string("1000"));
+        let mut second = FieldTable::new();
+        second.insert("queue".to_string(), string("retry"));
+        second.insert("count".to_string(), AmqpValue::LongInt(1));
+
+        let history = death_history(&properties(
+            X_DEATH,
+            AmqpValue::FieldArray(vec![
+                AmqpValue::FieldTable(first),
+                AmqpValue::FieldTable(second),
+            ]),
+        ))
+        .unwrap();
+        assert_eq!(
+            history,
+            vec![
+                XDeath {
+                    queue: "work".to_string(),
+                    exchange: "jobs".to_string(),
+                    reason: "rejected".to_string(),
+                    count: 3,
+                    time: Some(UNIX_EPOCH + Duration::from_secs(1_600_000_000)),
+                    routing_keys: vec!["a".to_string(), "b".to_string()],
+                },
+                XDeath {
+                    queue: "retry".to_string(),
+                    exchange: String::new(),
+                    reason: String::new(),
+                    count: 1,
+                    time: None,
+                    routing_keys: Vec::new(),
+                },
+            ]
+        );
+
+        assert!(death_history(&AmqpProperties::default())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn malformed_death_history_is_an_error() {
+        let mut bad_count = FieldTable::new();
+        bad_count.insert("count".to_string(), string("3"));
+        let mut negative_count = FieldTable::new();
+        negative_count.insert("count".to_string(), AmqpValue::LongInt(-1));
+        let cases = vec![
+            string("not an array"),
+            AmqpValue::FieldArray(vec![string("not a table")]),
+            AmqpValue::FieldArray(vec![AmqpValue::FieldTable(bad_count)]),
+            AmqpValue::FieldArray(vec![AmqpValue::FieldTable(negative_count)]),
+        ];
+        for value in cases {
+            match death_history(&properties(X_DEATH, value)) {
+                Err(Error::MalformedHeader { header, .. }) => assert_eq!(header, X_DEATH),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn parses_delivery_count() {
+        let count = |value| delivery_count(&properties(X_DELIVERY_COUNT, value));
+        assert_eq!(count(AmqpValue::LongLongInt(2)).unwrap(), Some(2));
+        assert_eq!(count(AmqpValue::LongInt(5)).unwrap(), Some(5));
+        assert_eq!(delivery_count(&AmqpProperties::default()).unwrap(), None);
+        for value in [
+            string("2"),
+            AmqpValue::LongInt(-1),
+            AmqpValue::LongLongInt(1 << 40),
+        ] {
+            match count(value) {
+                Err(Error::MalformedHeader { header, .. }) => {
+                    assert_eq!(header, X_DELIVERY_COUNT)
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::errors::*;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    // `const` thread_local initializers need a newer compiler than our MSRV.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static CURRENT: Cell<Option<Deadline>> = Cell::new(None);
+}
+
+// A point in time after which the calling thread no longer wants an answer. Deadlines are
+// captured from the thread-local set by `with_deadline` when a request is made and travel with
+// the request into the I/O thread, so the I/O thread can refuse work whose caller has already
+// given up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Deadline(Instant);
+
+impl Deadline {
+    // The deadline in effect on this thread, if any.
+    pub(crate) fn current() -> Option<Deadline> {
+        CURRENT.with(|current| current.get())
+    }
+
+    // Fail with `DeadlineExceeded` if the deadline in effect on this thread has already passed.
+    pub(crate) fn check_current() -> Result<()> {
+        match Deadline::current() {
+            Some(deadline) if deadline.has_passed() => DeadlineExceededSnafu.fail(),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn has_passed(self) -> bool {
+        Instant::now() >= self.0
+    }
+
+    pub(crate) fn instant(self) -> Instant {
+        self.0
+    }
+}
+
+// Restores the previous deadline even if the scoped closure panics.
+struct Restore(Option<Deadline>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        let previous = self.0;
+        CURRENT.with(|current| current.set(previous));
+    }
+}
+
+/// Run `f`, bounding every amiquip call it makes on the current thread to a shared budget of
+/// `timeout`.
+///
+/// Any synchronous method (e.g., [`Channel::queue_declare`](struct.Channel.html#method.queue_declare))
+/// or publish started inside `f` after the budget is spent fails with
+/// [`Error::DeadlineExceeded`](enum.Error.html#variant.DeadlineExceeded), as does a synchronous
+/// method whose reply does not arrive in time. Requests that are still waiting to be picked up by
+/// the I/O thread when the budget runs out are answered with the same error instead of being sent
+/// to the server. Because the budget is shared, a compound operation such as
+/// [`Channel::apply_topology`](struct.Channel.html#method.apply_topology) is bounded as a whole
+/// rather than per declaration.
+///
+/// Calls to `with_deadline` may be nested; the inner call can shorten but never extend the
+/// budget of the outer one.
+///
+/// A method abandoned after it was sent to the server may still take effect on the server. Its
+/// reply is discarded when it eventually arrives, so later calls on the same channel are not
+/// affected. Publishing is only checked before the message is handed to the I/O thread; once any
+/// part of a message has been queued, the rest of it is always sent.
+///
+/// ```rust
+/// use amiquip::{with_deadline, Channel, QueueDeclareOptions, Result};
+/// use std::time::Duration;
+///
+/// fn declare_queues(channel: &Channel) -> Result<()> {
+///     with_deadline(Duration::from_secs(5), || {
+///         channel.queue_declare("first", QueueDeclareOptions::default())?;
+///         channel.queue_declare("second", QueueDeclareOptions::default())?;
+///         Ok(())
+///     })
+/// }
+/// ```
+pub fn with_deadline<T, F: FnOnce() -> T>(timeout: Duration, f: F) -> T {
+    let deadline = Deadline(Instant::now() + timeout);
+    let previous = Deadline::current();
+    let effective = match previous {
+        Some(previous) => previous.min(deadline),
+        None => deadline,
+    };
+    CURRENT.with(|current| current.set(Some(effective)));
+    let _restore = Restore(previous);
+    f()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    #[test]
+    fn nested_deadline_cannot_extend_outer() {
+        with_deadline(Duration::from_secs(1), || {
+            let outer = Deadline::current().unwrap();
+            with_deadline(Duration::from_secs(60), || {
+                assert_eq!(Deadline::current(), Some(outer));
+            });
+            with_deadline(Duration::from_millis(1), || {
+                assert!(Deadline::current().unwrap() < outer);
+            });
+            assert_eq!(Deadline::current(), Some(outer));
+        });
+        assert_eq!(Deadline::current(), None);
+    }
+
+    #[test]
+    fn deadline_restored_after_panic() {
+        let result = panic::catch_unwind(|| {
+            with_deadline(Duration::from_secs(1), || panic!("boom"));
+        });
+        assert!(result.is_err());
+        assert_eq!(Deadline::current(), None);
+    }
+
+    #[test]
+    fn expired_deadline_fails_check() {
+        assert!(Deadline::check_current().is_ok());
+        with_deadline(Duration::from_secs(0), || match Deadline::check_current() {
+            Err(Error::DeadlineExceeded) => (),
+            other => panic!("unexpected result {:?}", other),
+        });
+    }
+}
@@ -1,11 +1,21 @@
-use crate::{AmqpProperties, Channel, Result};
-use amq_protocol::protocol::basic::{Deliver, GetOk};
+use crate::dead_letter::{self, XDeath};
+use crate::errors::*;
+use crate::io_loop::ChannelSender;
+use crate::logging::enter_span;
+use crate::properties::AmqpPropertiesExt;
+use crate::tag::{ChannelEpoch, DeliveryTag};
+use crate::{AmqpProperties, Channel, Publish};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Ack, Deliver, GetOk, Nack, Reject};
+#[cfg(feature = "compression")]
+use std::borrow::Cow;
+use std::time::Duration;
 
 /// A message delivered to a consumer.
 #[derive(Clone, Debug)]
 pub struct Delivery {
     channel_id: u16,
-    delivery_tag: u64,
+    tag: DeliveryTag,
 
     /// If true, this message has previously been delivered to this or another consumer.
     pub redelivered: bool,
@@ -17,7 +27,7 @@ pub struct Delivery {
     /// The routing key specified when this message was published.
     pub routing_key: String,
 
-    /// The content body containing the message.
+    /// The content body containing the message. May be empty.
     pub body: Vec<u8>,
 
     /// Properties associated with the message.
@@ -27,6 +37,7 @@ pub struct Delivery {
 impl Delivery {
     pub(crate) fn new(
         channel_id: u16,
+        epoch: ChannelEpoch,
         deliver: Deliver,
         body: Vec<u8>,
         properties: AmqpProperties,
@@ -35,7 +46,7 @@ impl Delivery {
             deliver.consumer_tag,
             Delivery {
                 channel_id,
-                delivery_tag: deliver.delivery_tag,
+                tag: DeliveryTag::new(epoch, deliver.delivery_tag),
                 redelivered: deliver.redelivered,
                 exchange: deliver.exchange,
                 routing_key: deliver.routing_key,
@@ -47,13 +58,14 @@ impl Delivery {
 
     pub(crate) fn new_get_ok(
         channel_id: u16,
+        epoch: ChannelEpoch,
         get_ok: GetOk,
         body: Vec<u8>,
         properties: AmqpProperties,
     ) -> Delivery {
         Delivery {
             channel_id,
-            delivery_tag: get_ok.delivery_tag,
+            tag: DeliveryTag::new(epoch, get_ok.delivery_tag),
             redelivered: get_ok.redelivered,
             exchange: get_ok.exchange,
             routing_key: get_ok.routing_key,
@@ -65,7 +77,118 @@ impl Delivery {
     /// The server-assigned delivery tag for this message. Delivery tags are channel-specific.
     #[inline]
     pub fn delivery_tag(&self) -> u64 {
-        self.delivery_tag
+        self.tag.get()
+    }
+
+    /// Deserialize this delivery's body from JSON.
+    ///
+    /// Fails with [`UnexpectedContentType`](enum.Error.html#variant.UnexpectedContentType) unless
+    /// the `content_type` property is `application/json` (parameters such as `charset` are
+    /// allowed), and with [`DecodeJson`](enum.Error.html#variant.DecodeJson) if the body cannot
+    /// be deserialized as `T`.
+    ///
+    /// This method is only available if amiquip is built with the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn decode_json<T: serde_crate::de::DeserializeOwned>(&self) -> Result<T> {
+        crate::json::decode(&self.properties, &self.body)
+    }
+
+    /// The body, decompressed if the `content_encoding` property is `gzip` or `deflate`; other
+    /// bodies are returned as-is without copying. Fails if the body inflates to more than
+    /// [`DEFAULT_MAX_DECOMPRESSED_LEN`](constant.DEFAULT_MAX_DECOMPRESSED_LEN.html) bytes; use
+    /// [`decompressed_body_with_limit`](#method.decompressed_body_with_limit) to choose a
+    /// different limit.
+    ///
+    /// This method is only available if amiquip is built with the `compression` feature.
+    #[cfg(feature = "compression")]
+    pub fn decompressed_body(&self) -> Result<Cow<'_, [u8]>> {
+        self.decompressed_body_with_limit(crate::DEFAULT_MAX_DECOMPRESSED_LEN)
+    }
+
+    /// Like [`decompressed_body`](#method.decompressed_body), but fails with
+    /// [`DecompressedBodyTooLarge`](enum.Error.html#variant.DecompressedBodyTooLarge) if the body
+    /// inflates to more than `max_len` bytes. Decompression stops as soon as the limit is
+    /// exceeded, so a small malicious body cannot inflate into an arbitrarily large allocation.
+    #[cfg(feature = "compression")]
+    pub fn decompressed_body_with_limit(&self, max_len: usize) -> Result<Cow<'_, [u8]>> {
+        crate::compression::decompress(&self.properties, &self.body, max_len)
+    }
+
+    /// Create a message to republish this delivery's body with the given routing key, starting
+    /// from a copy of this delivery's properties (correlation ID, headers, content type, etc.).
+    ///
+    /// The returned [`Publish`](struct.Publish.html) borrows the body rather than copying it.
+    /// Its builder methods (e.g., [`with_header`](struct.Publish.html#method.with_header)) adjust
+    /// the properties before it is published:
+    ///
+    /// ```rust
+    /// # use amiquip::{Channel, Delivery, Exchange, Result};
+    /// # fn forward(channel: &Channel, exchange: &Exchange, delivery: Delivery) -> Result<()> {
+    /// exchange.publish(
+    ///     delivery
+    ///         .to_publish("audit")
+    ///         .with_header("forwarded", true)
+    ///         .without_header("x-death"),
+    /// )?;
+    /// delivery.ack(channel)
+    /// # }
+    /// ```
+    pub fn to_publish<S: Into<String>>(&self, routing_key: S) -> Publish<'_> {
+        Publish::with_properties(&self.body, routing_key, self.properties.clone())
+    }
+
+    /// The `x-delivery-count` header quorum queues set on redelivered messages: how many times
+    /// this message has previously been delivered and then returned to the queue. Returns `None`
+    /// if the header is absent (e.g., on a first delivery, or from another type of queue).
+    ///
+    /// Fails with [`Error::MalformedHeader`](enum.Error.html#variant.MalformedHeader) if the
+    /// header is not a non-negative 32-bit integer.
+    pub fn delivery_count(&self) -> Result<Option<u32>> {
+        dead_letter::delivery_count(&self.properties)
+    }
+
+    /// The dead-letter history of this message, parsed from its `x-death` header; empty if the
+    /// message has never been dead-lettered.
+    ///
+    /// Fails with [`Error::MalformedHeader`](enum.Error.html#variant.MalformedHeader) if the
+    /// header (or a known key within it) does not have the type the server gives it.
+    pub fn death_history(&self) -> Result<Vec<XDeath>> {
+        dead_letter::death_history(&self.properties)
+    }
+
+    /// The offset of this message in the [stream queue](https://www.rabbitmq.com/streams.html)
+    /// it was consumed from, read from its `x-stream-offset` header; `None` for messages from
+    /// other queues. See [`StreamOffset::Offset`](enum.StreamOffset.html#variant.Offset) to
+    /// resume consuming from a given message.
+    ///
+    /// Fails with [`Error::MalformedHeader`](enum.Error.html#variant.MalformedHeader) if the
+    /// header is not a non-negative integer.
+    pub fn stream_offset(&self) -> Result<Option<u64>> {
+        crate::stream_offset::delivery_offset(&self.properties)
+    }
+
+    /// The per-message TTL this message was published with (its `expiration` property), or
+    /// `None` if it has none or it is not a string of whole milliseconds. The server does not
+    /// count down the property, and removes it when dead-lettering a message (recording it as
+    /// `original-expiration` in the `x-death` header).
+    pub fn expiration(&self) -> Option<Duration> {
+        self.properties.expiration_duration()
+    }
+
+    pub(crate) fn channel_id(&self) -> u16 {
+        self.channel_id
+    }
+
+    // The delivery tag to send when settling this delivery on the channel incarnation `epoch`.
+    pub(crate) fn tag_for(&self, epoch: ChannelEpoch) -> Result<u64> {
+        if self.tag.epoch() != epoch {
+            return StaleDeliverySnafu {
+                channel_id: self.channel_id,
+                delivery_tag: self.tag.get(),
+            }
+            .fail();
+        }
+        Ok(self.tag.get())
     }
 
     /// Acknowledge this delivery, which must have been received on the given channel. If
@@ -79,6 +202,9 @@ impl Delivery {
     /// `Delivery`/`Channel` pairing will not be detected at runtime. Always ack deliveries with
     /// the channel they were received on; the result of failing to do this is unspecified by the
     /// AMQP specification.
+    ///
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if this delivery's
+    /// channel has since been closed and `channel` is a new channel that reused its ID.
     #[inline]
     pub fn ack(self, channel: &Channel) -> Result<()> {
         assert_eq!(
@@ -100,6 +226,9 @@ impl Delivery {
     /// `Delivery`/`Channel` pairing will not be detected at runtime. Always ack deliveries with
     /// the channel they were received on; the result of failing to do this is unspecified by the
     /// AMQP specification.
+    ///
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if this delivery's
+    /// channel has since been closed and `channel` is a new channel that reused its ID.
     #[inline]
     pub fn ack_multiple(self, channel: &Channel) -> Result<()> {
         assert_eq!(
@@ -120,6 +249,9 @@ impl Delivery {
     /// `Delivery`/`Channel` pairing will not be detected at runtime. Always ack deliveries with
     /// the channel they were received on; the result of failing to do this is unspecified by the
     /// AMQP specification.
+    ///
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if this delivery's
+    /// channel has since been closed and `channel` is a new channel that reused its ID.
     #[inline]
     pub fn nack(self, channel: &Channel, requeue: bool) -> Result<()> {
         assert_eq!(
@@ -142,6 +274,9 @@ impl Delivery {
     /// `Delivery`/`Channel` pairing will not be detected at runtime. Always ack deliveries with
     /// the channel they were received on; the result of failing to do this is unspecified by the
     /// AMQP specification.
+    ///
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if this delivery's
+    /// channel has since been closed and `channel` is a new channel that reused its ID.
     #[inline]
     pub fn nack_multiple(self, channel: &Channel, requeue: bool) -> Result<()> {
         assert_eq!(
@@ -162,6 +297,9 @@ impl Delivery {
     /// `Delivery`/`Channel` pairing will not be detected at runtime. Always ack deliveries with
     /// the channel they were received on; the result of failing to do this is unspecified by the
     /// AMQP specification.
+    ///
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if this delivery's
+    /// channel has since been closed and `channel` is a new channel that reused its ID.
     #[inline]
     pub fn reject(self, channel: &Channel, requeue: bool) -> Result<()> {
         assert_eq!(
@@ -171,4 +309,234 @@ impl Delivery {
         );
         channel.basic_reject(self, requeue)
     }
+
+    /// Dead-letter this delivery, which must have been received on the given channel, recording
+    /// why: publish a copy of it to `exchange` (with its original routing key) with the added
+    /// headers `x-rejection-reason` (set to `reason`), `x-original-exchange`, and
+    /// `x-original-routing-key`, then ack the original.
+    ///
+    /// Unlike a reject, which the server dead-letters unchanged to the queue's configured
+    /// dead-letter exchange, this lets the dead-lettered copy carry the reason. `exchange` is
+    /// typically that same dead-letter exchange.
+    ///
+    /// If the publish fails, the original is nacked with requeue instead, so the message is not
+    /// lost, and the publish error is returned. Note that a successful publish only means the
+    /// message was handed to the I/O thread, not that the server has routed it.
+    ///
+    /// # Panics
+    ///
+    /// This method will attempt to panic if `channel` does not match the channel this delivery was
+    /// received on, before publishing anything. See [`ack`](#method.ack).
+    pub fn dead_letter_with_reason<S: Into<String>>(
+        self,
+        channel: &Channel,
+        exchange: S,
+        reason: &str,
+    ) -> Result<()> {
+        assert_eq!(
+            self.channel_id,
+            channel.channel_id(),
+            "cannot dead-letter delivery on different channel"
+        );
+        let publish = self
+            .to_publish(self.routing_key.as_str())
+            .with_header("x-rejection-reason", reason)
+            .with_header("x-original-exchange", self.exchange.as_str())
+            .with_header("x-original-routing-key", self.routing_key.as_str());
+        match channel.basic_publish(exchange, publish) {
+            Ok(()) => self.ack(channel),
+            Err(err) => {
+                // The publish error is the one worth reporting; if the nack fails too, the
+                // channel is gone and the server will requeue the message anyway.
+                let _ = self.nack(channel, true);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// A handle for acknowledging deliveries from inside a consumer callback.
+///
+/// Callbacks registered with
+/// [`Channel::basic_consume_with_callback`](struct.Channel.html#method.basic_consume_with_callback)
+/// run on one of the connection's dispatch threads, where the [`Channel`](struct.Channel.html)
+/// that created the consumer is not available. An `Acker` is passed to each callback invocation
+/// instead; it implements `Send` and `Clone`, so it may also be moved to another thread to
+/// acknowledge a delivery later. Acknowledgments are asynchronous, exactly like the
+/// corresponding methods on [`Delivery`](struct.Delivery.html).
+#[derive(Clone, Debug)]
+pub struct Acker {
+    sender: ChannelSender,
+}
+
+impl Acker {
+    pub(crate) fn new(sender: ChannelSender) -> Acker {
+        Acker { sender }
+    }
+
+    /// Acknowledge `delivery`. See [`Delivery::ack`](struct.Delivery.html#method.ack).
+    ///
+    /// # Panics
+    ///
+    /// This method will attempt to panic if `delivery` was not received on this acker's channel.
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if `delivery` was
+    /// received on a previous channel with the same ID.
+    pub fn ack(&self, delivery: Delivery) -> Result<()> {
+        let delivery_tag = self.check_channel(&delivery, "ack")?;
+        enter_span!(
+            DEBUG,
+            "ack",
+            channel_id = self.sender.channel_id(),
+            delivery_tag
+        );
+        self.sender.settle(
+            AmqpBasic::Ack(Ack {
+                delivery_tag,
+                multiple: false,
+            }),
+            delivery_tag,
+            false,
+        )
+    }
+
+    /// Acknowledge `delivery` and all prior unacknowledged deliveries on the same channel. See
+    /// [`Delivery::ack_multiple`](struct.Delivery.html#method.ack_multiple).
+    ///
+    /// # Panics
+    ///
+    /// This method will attempt to panic if `delivery` was not received on this acker's channel.
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if `delivery` was
+    /// received on a previous channel with the same ID.
+    pub fn ack_multiple(&self, delivery: Delivery) -> Result<()> {
+        let delivery_tag = self.check_channel(&delivery, "ack")?;
+        enter_span!(
+            DEBUG,
+            "ack",
+            channel_id = self.sender.channel_id(),
+            delivery_tag
+        );
+        self.sender.settle(
+            AmqpBasic::Ack(Ack {
+                delivery_tag,
+                multiple: true,
+            }),
+            delivery_tag,
+            true,
+        )
+    }
+
+    /// Nack `delivery`. See [`Delivery::nack`](struct.Delivery.html#method.nack).
+    ///
+    /// # Panics
+    ///
+    /// This method will attempt to panic if `delivery` was not received on this acker's channel.
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if `delivery` was
+    /// received on a previous channel with the same ID.
+    pub fn nack(&self, delivery: Delivery, requeue: bool) -> Result<()> {
+        let delivery_tag = self.check_channel(&delivery, "nack")?;
+        enter_span!(
+            DEBUG,
+            "nack",
+            channel_id = self.sender.channel_id(),
+            delivery_tag
+        );
+        self.sender.settle(
+            AmqpBasic::Nack(Nack {
+                delivery_tag,
+                multiple: false,
+                requeue,
+            }),
+            delivery_tag,
+            false,
+        )
+    }
+
+    /// Nack `delivery` and all prior unacknowledged deliveries on the same channel. See
+    /// [`Delivery::nack_multiple`](struct.Delivery.html#method.nack_multiple).
+    ///
+    /// # Panics
+    ///
+    /// This method will attempt to panic if `delivery` was not received on this acker's channel.
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if `delivery` was
+    /// received on a previous channel with the same ID.
+    pub fn nack_multiple(&self, delivery: Delivery, requeue: bool) -> Result<()> {
+        let delivery_tag = self.check_channel(&delivery, "nack")?;
+        enter_span!(
+            DEBUG,
+            "nack",
+            channel_id = self.sender.channel_id(),
+            delivery_tag
+        );
+        self.sender.settle(
+            AmqpBasic::Nack(Nack {
+                delivery_tag,
+                multiple: true,
+                requeue,
+            }),
+            delivery_tag,
+            true,
+        )
+    }
+
+    /// Reject `delivery`. See [`Delivery::reject`](struct.Delivery.html#method.reject).
+    ///
+    /// # Panics
+    ///
+    /// This method will attempt to panic if `delivery` was not received on this acker's channel.
+    /// Returns [`Error::StaleDelivery`](enum.Error.html#variant.StaleDelivery) if `delivery` was
+    /// received on a previous channel with the same ID.
+    pub fn reject(&self, delivery: Delivery, requeue: bool) -> Result<()> {
+        let delivery_tag = self.check_channel(&delivery, "reject")?;
+        enter_span!(
+            DEBUG,
+            "reject",
+            channel_id = self.sender.channel_id(),
+            delivery_tag
+        );
+        self.sender.settle(
+            AmqpBasic::Reject(Reject {
+                delivery_tag,
+                requeue,
+            }),
+            delivery_tag,
+            false,
+        )
+    }
+
+    // Ack the delivery with `delivery_tag`, which was received on this acker's channel, under
+    // `AckPolicy::Batched`.
+    pub(crate) fn batched_ack(
+        &self,
+        delivery_tag: u64,
+        max_count: usize,
+        max_delay: Duration,
+    ) -> Result<()> {
+        enter_span!(
+            DEBUG,
+            "ack",
+            channel_id = self.sender.channel_id(),
+            delivery_tag
+        );
+        self.sender.batched_ack(delivery_tag, max_count, max_delay)
+    }
+
+    // Requeue the delivery with `in_flight` as its tag (if it is still unsettled) and cancel the
+    // consumer with `consumer_tag`, whose callback panicked while handling it.
+    pub(crate) fn cancel_panicked(
+        &self,
+        consumer_tag: String,
+        in_flight: Option<u64>,
+    ) -> Result<()> {
+        self.sender.cancel_panicked(consumer_tag, in_flight)
+    }
+
+    fn check_channel(&self, delivery: &Delivery, action: &str) -> Result<u64> {
+        assert_eq!(
+            delivery.channel_id,
+            self.sender.channel_id(),
+            "cannot {} delivery on different channel",
+            action
+        );
+        delivery.tag_for(self.sender.epoch())
+    }
 }
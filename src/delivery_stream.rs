@@ -0,0 +1,178 @@
+use crate::errors::*;
+use crate::{Channel, Delivery};
+use crossbeam_channel::Receiver;
+use std::io::{self, Read};
+
+/// A message delivered to a consumer whose body is read as it arrives from the server.
+///
+/// Sent as [`ConsumerMessage::DeliveryStream`](enum.ConsumerMessage.html#variant.DeliveryStream)
+/// instead of a [`Delivery`](struct.Delivery.html) for messages larger than
+/// [`ConsumerOptions::stream_bodies_above`](struct.ConsumerOptions.html#structfield.stream_bodies_above).
+/// The body is read through this type's `Read` implementation; body frames that arrive before
+/// they are read are held in memory, so a stream that is read promptly never holds more than a
+/// few frames at once.
+///
+/// The connection's I/O thread never waits for a stream to be read: heartbeats and other
+/// channels are serviced as usual while the body is in flight, and further deliveries for this
+/// consumer are queued behind it.
+///
+/// # Memory Use
+///
+/// There is no backpressure on a stream. All channels share the connection's socket, so the
+/// I/O thread cannot stop reading one body without stalling everything else; frames are
+/// instead held for as long as the stream falls behind. A reader slower than the network can
+/// therefore end up holding most (at worst all) of the body in memory, as if it had not been
+/// streamed. Read streams promptly, or [abandon](#method.abandon) one that cannot keep up, which
+/// discards the rest of its body as it arrives.
+///
+/// The message can only be acknowledged (or nacked or rejected) once its body has been read to
+/// the end or the stream has been [abandoned](#method.abandon); until then those methods fail
+/// with [`Error::DeliveryStreamUnfinished`](enum.Error.html#variant.DeliveryStreamUnfinished).
+#[derive(Debug)]
+pub struct DeliveryStream {
+    delivery: Delivery,
+    body_size: u64,
+    chunks: Option<Receiver<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    received: u64,
+}
+
+impl DeliveryStream {
+    pub(crate) fn new(delivery: Delivery, body_size: u64, chunks: Receiver<Vec<u8>>) -> Self {
+        DeliveryStream {
+            delivery,
+            body_size,
+            chunks: Some(chunks),
+            chunk: Vec::new(),
+            pos: 0,
+            received: 0,
+        }
+    }
+
+    /// The message's metadata: delivery tag, exchange, routing key, and properties. Its `body` is
+    /// always empty.
+    #[inline]
+    pub fn delivery(&self) -> &Delivery {
+        &self.delivery
+    }
+
+    /// The server-assigned delivery tag for this message.
+    #[inline]
+    pub fn delivery_tag(&self) -> u64 {
+        self.delivery.delivery_tag()
+    }
+
+    /// The total size of the body in bytes, as announced by the server.
+    #[inline]
+    pub fn body_size(&self) -> u64 {
+        self.body_size
+    }
+
+    /// The number of body bytes read so far.
+    #[inline]
+    pub fn bytes_read(&self) -> u64 {
+        self.received - (self.chunk.len() - self.pos) as u64
+    }
+
+    /// Returns true if the body has been read to the end or the stream has been abandoned, i.e.,
+    /// if the message may be acknowledged.
+    pub fn is_finished(&self) -> bool {
+        self.chunks.is_none() || self.bytes_read() == self.body_size
+    }
+
+    /// Stop reading the body. The rest of it is discarded as it arrives, and further reads fail.
+    /// The message may then be acknowledged, nacked, or rejected; typically it is nacked or
+    /// rejected so the server can requeue it.
+    pub fn abandon(&mut self) {
+        self.chunks = None;
+        self.chunk = Vec::new();
+        self.pos = 0;
+    }
+
+    /// Give up the stream for its [`Delivery`](#method.delivery) (with an empty body), e.g. to
+    /// acknowledge it through a [`Consumer`](struct.Consumer.html) or an
+    /// [`Acker`](struct.Acker.html). Fails with
+    /// [`Error::DeliveryStreamUnfinished`](enum.Error.html#variant.DeliveryStreamUnfinished)
+    /// unless the stream [is finished](#method.is_finished).
+    pub fn finish(self) -> Result<Delivery> {
+        if !self.is_finished() {
+            return DeliveryStreamUnfinishedSnafu {
+                delivery_tag: self.delivery_tag(),
+                bytes_read: self.bytes_read(),
+                body_size: self.body_size,
+            }
+            .fail();
+        }
+        Ok(self.delivery)
+    }
+
+    // Read the body to the end, so the delivery can be handled like any other. This only fails if
+    // the channel or connection closes mid-body; the stream is then abandoned and its delivery is
+    // returned with an empty body alongside the error, so the caller can still settle it.
+    pub(crate) fn read_whole(mut self) -> (Delivery, io::Result<()>) {
+        let mut body = Vec::with_capacity(self.body_size as usize);
+        let result = self.read_to_end(&mut body).map(|_| ());
+        match result {
+            Ok(()) => self.delivery.body = body,
+            Err(_) => self.abandon(),
+        }
+        (self.delivery, result)
+    }
+
+    /// Calls [`Delivery::ack`](struct.Delivery.html#method.ack) on the
+    /// [finished](#method.finish) delivery.
+    #[inline]
+    pub fn ack(self, channel: &Channel) -> Result<()> {
+        self.finish()?.ack(channel)
+    }
+
+    /// Calls [`Delivery::nack`](struct.Delivery.html#method.nack) on the
+    /// [finished](#method.finish) delivery.
+    #[inline]
+    pub fn nack(self, channel: &Channel, requeue: bool) -> Result<()> {
+        self.finish()?.nack(channel, requeue)
+    }
+
+    /// Calls [`Delivery::reject`](struct.Delivery.html#method.reject) on the
+    /// [finished](#method.finish) delivery.
+    #[inline]
+    pub fn reject(self, channel: &Channel, requeue: bool) -> Result<()> {
+        self.finish()?.reject(channel, requeue)
+    }
+}
+
+impl Read for DeliveryStream {
+    /// Reads body bytes, waiting for the next body frame if none are buffered. Fails with
+    /// `UnexpectedEof` if the channel or connection closes before the whole body arrives, and
+    /// with `InvalidInput` after [`abandon`](#method.abandon).
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Loop in case the server sends an empty body frame.
+        while self.pos == self.chunk.len() {
+            if self.received == self.body_size {
+                return Ok(0);
+            }
+            let chunks = match &self.chunks {
+                Some(chunks) => chunks,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "delivery stream was abandoned",
+                    ))
+                }
+            };
+            self.chunk = chunks.recv().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "channel closed before the whole body arrived",
+                )
+            })?;
+            self.pos = 0;
+            self.received += self.chunk.len() as u64;
+        }
+        let n = usize::min(buf.len(), self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
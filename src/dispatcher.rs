@@ -0,0 +1,207 @@
+use crate::errors::*;
+use crate::logging::{debug, error};
+use crate::{AckPolicy, Acker, ConsumerMessage};
+use crossbeam_channel::{Receiver, Select, Sender};
+use snafu::ResultExt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread::Builder;
+
+pub(crate) type ConsumerCallback = Box<dyn FnMut(ConsumerMessage, &Acker) + Send>;
+
+pub(crate) struct Registration {
+    consumer_tag: String,
+    rx: Receiver<ConsumerMessage>,
+    acker: Acker,
+    ack_policy: AckPolicy,
+    no_ack: bool,
+    callback: ConsumerCallback,
+    // Set once the callback has panicked. The consumer has been cancelled, and deliveries that
+    // arrive before it ends are requeued without running the callback.
+    panicked: bool,
+}
+
+impl Registration {
+    pub(crate) fn new(
+        consumer_tag: String,
+        rx: Receiver<ConsumerMessage>,
+        acker: Acker,
+        ack_policy: AckPolicy,
+        no_ack: bool,
+        callback: ConsumerCallback,
+    ) -> Registration {
+        Registration {
+            consumer_tag,
+            rx,
+            acker,
+            ack_policy,
+            no_ack,
+            callback,
+            panicked: false,
+        }
+    }
+
+    // Run the callback on `message`, then ack it if this consumer acks automatically. Returns
+    // false once this consumer is finished, i.e., once `message` is terminal.
+    //
+    // If the callback panics, the delivery it was handling is requeued and the consumer is
+    // cancelled; otherwise the server would keep the delivery (and any that followed it) unacked
+    // against a consumer nobody is reading, until the channel closed.
+    fn dispatch(&mut self, message: ConsumerMessage) -> bool {
+        let (terminal, delivery_tag) = match &message {
+            ConsumerMessage::Delivery(delivery) => (false, Some(delivery.delivery_tag())),
+            ConsumerMessage::DeliveryStream(stream) => (false, Some(stream.delivery_tag())),
+            _ => (true, None),
+        };
+        if self.panicked {
+            self.requeue(message);
+            return !terminal;
+        }
+        let callback = &mut self.callback;
+        let acker = &self.acker;
+        match panic::catch_unwind(AssertUnwindSafe(|| callback(message, acker))) {
+            Ok(()) => {
+                if let (
+                    Some(delivery_tag),
+                    AckPolicy::Batched {
+                        max_count,
+                        max_delay,
+                    },
+                ) = (delivery_tag, self.ack_policy)
+                {
+                    if let Err(err) = self.acker.batched_ack(delivery_tag, max_count, max_delay) {
+                        debug!(
+                            "failed to ack delivery {} for consumer {}: {}",
+                            delivery_tag, self.consumer_tag, err
+                        );
+                    }
+                }
+            }
+            Err(_) => {
+                error!(
+                    "callback for consumer {} panicked; cancelling consumer",
+                    self.consumer_tag
+                );
+                self.panicked = true;
+                let in_flight = if self.no_ack { None } else { delivery_tag };
+                if let Err(err) = self
+                    .acker
+                    .cancel_panicked(self.consumer_tag.clone(), in_flight)
+                {
+                    debug!("failed to cancel consumer {}: {}", self.consumer_tag, err);
+                }
+            }
+        }
+        !terminal
+    }
+
+    // Give back a delivery that arrived after the callback panicked.
+    fn requeue(&self, message: ConsumerMessage) {
+        if self.no_ack {
+            return;
+        }
+        let delivery = match message {
+            ConsumerMessage::Delivery(delivery) => delivery,
+            ConsumerMessage::DeliveryStream(mut stream) => {
+                stream.abandon();
+                // unwrap is safe: an abandoned stream is finished.
+                stream.finish().unwrap()
+            }
+            _ => return,
+        };
+        let delivery_tag = delivery.delivery_tag();
+        if let Err(err) = self.acker.nack(delivery, true) {
+            debug!(
+                "failed to requeue delivery {} for consumer {}: {}",
+                delivery_tag, self.consumer_tag, err
+            );
+        }
+    }
+}
+
+// Runs consumer callbacks on threads owned by a connection, so user code never runs on (and can
+// never stall) the I/O thread. Each consumer is pinned to a single dispatch thread, so callbacks
+// for a given consumer are always invoked in delivery order, one at a time. Threads are spawned
+// lazily the first time a callback consumer is registered.
+#[derive(Debug)]
+pub(crate) struct Dispatcher {
+    num_threads: usize,
+    workers: Mutex<Vec<Sender<Registration>>>,
+    next_worker: AtomicUsize,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(num_threads: usize) -> Dispatcher {
+        Dispatcher {
+            num_threads: usize::max(num_threads, 1),
+            workers: Mutex::new(Vec::new()),
+            next_worker: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn register(&self, registration: Registration) -> Result<()> {
+        let mut workers = self.workers.lock().unwrap();
+        if workers.is_empty() {
+            for i in 0..self.num_threads {
+                let (tx, rx) = crossbeam_channel::unbounded();
+                Builder::new()
+                    .name(format!("amiquip-dispatch-{}", i))
+                    .spawn(move || run_worker(rx))
+                    .context(ForkFailedSnafu)?;
+                workers.push(tx);
+            }
+        }
+        let i = self.next_worker.fetch_add(1, Ordering::Relaxed) % workers.len();
+        debug!(
+            "dispatching consumer {} on dispatch thread {}",
+            registration.consumer_tag, i
+        );
+        // Workers only exit once we drop their sender, so this cannot fail.
+        workers[i]
+            .send(registration)
+            .expect("dispatch thread exited unexpectedly");
+        Ok(())
+    }
+}
+
+// Worker threads exit once the dispatcher has been dropped and every consumer they are running
+// has finished.
+fn run_worker(control: Receiver<Registration>) {
+    let mut control = Some(control);
+    let mut registrations: Vec<Registration> = Vec::new();
+
+    loop {
+        let mut select = Select::new();
+        for registration in &registrations {
+            select.recv(&registration.rx);
+        }
+        let control_index = control.as_ref().map(|control| select.recv(control));
+        if control_index.is_none() && registrations.is_empty() {
+            return;
+        }
+
+        let op = select.select();
+        let index = op.index();
+        if Some(index) == control_index {
+            // unwrap is safe: control_index is only Some if control is
+            let registration = op.recv(control.as_ref().unwrap());
+            drop(select);
+            match registration {
+                Ok(registration) => registrations.push(registration),
+                Err(_) => control = None,
+            }
+            continue;
+        }
+
+        let message = op.recv(&registrations[index].rx);
+        drop(select);
+        let keep = match message {
+            Ok(message) => registrations[index].dispatch(message),
+            Err(_) => false,
+        };
+        if !keep {
+            registrations.remove(index);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::errors::*;
+use crate::logging::debug;
+use crate::{Connection, Proxy};
+use mio::net::TcpStream;
+use snafu::ResultExt;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The address of a broker to connect to: a host name or IP address, and a port.
+///
+/// Endpoints are given to
+/// [`ConnectionOptions::endpoints`](struct.ConnectionOptions.html#method.endpoints) and used by
+/// [`Connection::insecure_open_endpoints`](struct.Connection.html#method.insecure_open_endpoints)
+/// and [`Connection::open_endpoints`](struct.Connection.html#method.open_endpoints). They can be
+/// parsed from `"host:port"` strings (with IPv6 addresses in brackets, e.g., `"[::1]:5672"`) or
+/// converted from a `SocketAddr`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    host: String,
+    port: u16,
+}
+
+impl Endpoint {
+    /// Creates an endpoint for `host` (a host name or IP address) and `port`.
+    pub fn new<S: Into<String>>(host: S, port: u16) -> Endpoint {
+        Endpoint {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// The host name or IP address of this endpoint. For TLS connections, this is also the
+    /// domain the server's certificate is verified against.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The port of this endpoint.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl From<SocketAddr> for Endpoint {
+    fn from(addr: SocketAddr) -> Endpoint {
+        Endpoint::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Endpoint> {
+        let invalid = || InvalidEndpointSnafu { endpoint: s }.fail();
+        let colon = match s.rfind(':') {
+            Some(colon) => colon,
+            None => return invalid(),
+        };
+        let (host, port) = (&s[..colon], &s[colon + 1..]);
+        let host = if host.starts_with('[') && host.ends_with(']') {
+            &host[1..host.len() - 1]
+        } else {
+            host
+        };
+        match port.parse() {
+            Ok(port) if !host.is_empty() && !host.contains(&['[', ']'][..]) => {
+                Ok(Endpoint::new(host, port))
+            }
+            _ => invalid(),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+// Shuffles `items` in place (Fisher-Yates). This only spreads connections across brokers, so the
+// per-process random keys of std's `RandomState` are random enough.
+pub(crate) fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        let j = (hasher.finish() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+// Tries `open` on each of `endpoints` in order until one succeeds. Each endpoint's host may resolve
+// to several addresses, which are tried in turn; with a proxy, the proxy resolves it instead. If
+// every endpoint fails, returns `AllEndpointsFailed` with each endpoint's (last) error.
+pub(crate) fn open_any<F>(
+    endpoints: &[Endpoint],
+    proxy: Option<&Proxy>,
+    connect_timeout: Option<Duration>,
+    mut open: F,
+) -> Result<Connection>
+where
+    F: FnMut(&Endpoint, TcpStream) -> Result<Connection>,
+{
+    if endpoints.is_empty() {
+        return NoEndpointsSnafu.fail();
+    }
+    let mut failures = Vec::with_capacity(endpoints.len());
+    for endpoint in endpoints {
+        let result = match proxy {
+            Some(proxy) => proxy
+                .connect(endpoint, connect_timeout)
+                .and_then(|stream| open(endpoint, stream)),
+            None => open_endpoint(endpoint, &mut open),
+        };
+        match result {
+            Ok(connection) => return Ok(connection),
+            Err(err) => {
+                debug!("failed to connect to {}: {}", endpoint, err);
+                failures.push((endpoint.to_string(), err));
+            }
+        }
+    }
+    AllEndpointsFailedSnafu { failures }.fail()
+}
+
+fn open_endpoint<F>(endpoint: &Endpoint, open: &mut F) -> Result<Connection>
+where
+    F: FnMut(&Endpoint, TcpStream) -> Result<Connection>,
+{
+    let addrs = (endpoint.host.as_str(), endpoint.port)
+        .to_socket_addrs()
+        .context(ResolveEndpointSnafu {
+            endpoint: endpoint.to_string(),
+        })?;
+    let mut last_err = None;
+    for addr in addrs {
+        let result = TcpStream::connect(addr)
+            .context(ConnectEndpointSnafu {
+                endpoint: endpoint.to_string(),
+            })
+            .and_then(|stream| open(endpoint, stream));
+        match result {
+            Ok(connection) => return Ok(connection),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| Error::ResolveEndpoint {
+        endpoint: endpoint.to_string(),
+        source: io::Error::new(io::ErrorKind::NotFound, "no addresses found"),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_endpoints() {
+        let parse = |s: &str| s.parse::<Endpoint>().ok();
+        assert_eq!(
+            parse("rabbit-1:5672"),
+            Some(Endpoint::new("rabbit-1", 5672))
+        );
+        assert_eq!(
+            parse("10.0.0.1:5671"),
+            Some(Endpoint::new("10.0.0.1", 5671))
+        );
+        assert_eq!(parse("[::1]:5672"), Some(Endpoint::new("::1", 5672)));
+        assert_eq!(parse("rabbit-1"), None);
+        assert_eq!(parse("rabbit-1:"), None);
+        assert_eq!(parse(":5672"), None);
+        assert_eq!(parse("rabbit-1:99999"), None);
+        assert_eq!(parse("[::1:5672"), None);
+
+        for s in &["rabbit-1:5672", "[::1]:5672"] {
+            assert_eq!(parse(s).unwrap().to_string(), *s);
+        }
+        let addr: SocketAddr = "[::1]:5672".parse().unwrap();
+        assert_eq!(Endpoint::from(addr), Endpoint::new("::1", 5672));
+    }
+
+    #[test]
+    fn shuffle_keeps_every_item() {
+        let mut items: Vec<u32> = (0..20).collect();
+        shuffle(&mut items);
+        items.sort_unstable();
+        assert_eq!(items, (0..20).collect::<Vec<_>>());
+    }
+}
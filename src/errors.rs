@@ -1,14 +1,21 @@
+use crate::AmqpReplyCode;
 use snafu::Snafu;
-//use std::sync::Arc;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{io, result};
 use url::Url;
 
 /// A type alias for handling errors throughout amiquip.
 pub type Result<T, E = Error> = result::Result<T, E>;
 
+/// An [`Error`](enum.Error.html) that is reported to more than one place; see
+/// [`Connection::on_close`](struct.Connection.html#method.on_close).
+pub type ArcError = Arc<Error>;
+
 /// Specific error cases returned by amiquip.
 #[derive(Snafu, Debug)]
 #[snafu(visibility(pub(crate)))]
+#[allow(clippy::manual_non_exhaustive)]
 pub enum Error {
     /// URL parsing failed.
     #[snafu(display("could not parse url: {}", source))]
@@ -43,6 +50,21 @@ pub enum Error {
     #[snafu(display("received malformed data - expected AMQP frame"))]
     MalformedFrame,
 
+    /// We received data that could not be parsed as an AMQP frame, and
+    /// [`ConnectionTuning::dump_malformed_frames`](struct.ConnectionTuning.html#structfield.dump_malformed_frames)
+    /// is enabled. `dump` contains a hex dump of the malformed frame and the bytes that preceded it.
+    #[snafu(display("received malformed data - expected AMQP frame{}", dump))]
+    MalformedFrameDump { dump: String },
+
+    /// The server sent a frame larger than the negotiated `frame_max`. The connection is closed
+    /// with a `FRAME_ERROR` reply code; `size` and `max` include the frame header and end byte.
+    #[snafu(display(
+        "received frame of {} bytes, larger than negotiated frame_max of {}",
+        size,
+        max
+    ))]
+    ReceivedFrameTooLarge { size: usize, max: usize },
+
     /// Failed to resolve a URL into an IP address (or addresses).
     #[snafu(display("URL did not resolve to an IP address: {}", url))]
     UrlNoSocketAddrs { url: Url },
@@ -85,6 +107,13 @@ pub enum Error {
         source: std::num::ParseIntError,
     },
 
+    /// Could not parse frame_max parameter of URL.
+    #[snafu(display("could not parse frame_max parameter of URL {}: {}", url, source))]
+    UrlParseFrameMax {
+        url: Url,
+        source: std::num::ParseIntError,
+    },
+
     /// Could not parse connection_timeout parameter of URL.
     #[snafu(display(
         "could not parse connection_timeout parameter of URL {}: {}",
@@ -98,7 +127,7 @@ pub enum Error {
 
     /// Invalid auth mechanism requested in URL.
     #[snafu(display(
-        "invalid auth mechanism for URL {}: {} (expected `external`)",
+        "invalid auth mechanism for URL {}: {} (expected `plain` or `external`)",
         url,
         mechanism
     ))]
@@ -112,6 +141,14 @@ pub enum Error {
     #[snafu(display("failed to create polling handle: {}", source))]
     CreatePollHandle { source: io::Error },
 
+    /// Could not set a socket option from
+    /// [`ConnectionTuning::tcp_options`](struct.ConnectionTuning.html#structfield.tcp_options).
+    #[snafu(display("failed to set socket option {}: {}", option, source))]
+    SetTcpOption {
+        option: &'static str,
+        source: io::Error,
+    },
+
     /// Could not register descriptor with Poll handle.
     #[snafu(display("failed to register object with polling handle: {}", source))]
     RegisterWithPollHandle { source: io::Error },
@@ -127,12 +164,102 @@ pub enum Error {
     /// The TLS handshake failed.
     #[cfg(feature = "native-tls")]
     #[snafu(display("TLS handshake failed: {}", source))]
-    TlsHandshake { source: native_tls::Error },
+    TlsHandshake {
+        #[snafu(source(from(native_tls::Error, Arc::new)))]
+        source: Arc<native_tls::Error>,
+    },
 
     /// Error from underlying TLS implementation.
     #[cfg(feature = "native-tls")]
     #[snafu(display("could not create TLS connector: {}", source))]
-    CreateTlsConnector { source: native_tls::Error },
+    CreateTlsConnector {
+        #[snafu(source(from(native_tls::Error, Arc::new)))]
+        source: Arc<native_tls::Error>,
+    },
+
+    /// The TLS handshake failed.
+    #[cfg(feature = "rustls")]
+    #[snafu(display("TLS handshake failed: {}", source))]
+    RustlsHandshake { source: rustls_crate::Error },
+
+    /// The domain given for a TLS connection is not a valid DNS name or IP address.
+    #[cfg(feature = "rustls")]
+    #[snafu(display("invalid TLS domain: {}", domain))]
+    InvalidTlsDomain { domain: String },
+
+    /// A client certificate or its private key could not be loaded, or does not match the TLS
+    /// backend of the connector it is used with.
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    #[snafu(display("invalid TLS client identity: {}", message))]
+    InvalidClientIdentity { message: String },
+
+    /// Hostname verification cannot be disabled for a rustls connector whose root certificates
+    /// are unknown (i.e., one converted from a `rustls::ClientConfig`); see
+    /// [`ConnectionOptions::danger_accept_invalid_hostnames`](struct.ConnectionOptions.html#method.danger_accept_invalid_hostnames).
+    #[cfg(feature = "rustls")]
+    #[snafu(display(
+        "cannot skip hostname verification for a rustls connector with unknown root certificates"
+    ))]
+    CannotSkipHostnameVerification,
+
+    /// A definitions file could not be parsed as JSON.
+    #[cfg(feature = "serde")]
+    #[snafu(display("could not parse definitions: {}", source))]
+    ParseDefinitions { source: serde_json::Error },
+
+    /// A definitions file was valid JSON but did not describe a valid topology.
+    #[cfg(feature = "serde")]
+    #[snafu(display("invalid definitions: {}", message))]
+    InvalidDefinitions { message: String },
+
+    /// A value could not be serialized as JSON.
+    #[cfg(feature = "serde")]
+    #[snafu(display("could not encode message as JSON: {}", source))]
+    EncodeJson { source: serde_json::Error },
+
+    /// A message body could not be deserialized from JSON.
+    #[cfg(feature = "serde")]
+    #[snafu(display("could not decode JSON message: {}", source))]
+    DecodeJson { source: serde_json::Error },
+
+    /// A message's `content_type` property was missing or was not the expected type.
+    #[cfg(feature = "serde")]
+    #[snafu(display("expected content type {}, got {:?}", expected, content_type))]
+    UnexpectedContentType {
+        expected: String,
+        content_type: Option<String>,
+    },
+
+    /// A value could not be converted between JSON and AMQP without changing it (see
+    /// [`field_table_from_json`](fn.field_table_from_json.html) and
+    /// [`properties_from_json`](fn.properties_from_json.html)).
+    #[cfg(feature = "serde")]
+    #[snafu(display("cannot convert between JSON and AMQP: {}", message))]
+    JsonConversion { message: String },
+
+    /// The server's SCRAM messages were malformed or did not continue the exchange we started.
+    #[cfg(feature = "scram")]
+    #[snafu(display("SCRAM authentication failed: {}", message))]
+    ScramProtocol { message: String },
+
+    /// The server's SCRAM signature did not match the one derived from our password, meaning the
+    /// server does not know our credentials.
+    #[cfg(feature = "scram")]
+    #[snafu(display("SCRAM server signature mismatch"))]
+    ScramServerSignatureMismatch,
+
+    /// A message body could not be decompressed according to its `content_encoding`.
+    #[cfg(feature = "compression")]
+    #[snafu(display("could not decompress {} message body: {}", content_encoding, source))]
+    Decompress {
+        content_encoding: String,
+        source: io::Error,
+    },
+
+    /// A compressed message body inflated to more than the allowed number of bytes.
+    #[cfg(feature = "compression")]
+    #[snafu(display("decompressed message body exceeds {} bytes", max_len))]
+    DecompressedBodyTooLarge { max_len: usize },
 
     /// The server does not support the requested auth mechanism.
     #[snafu(display(
@@ -164,31 +291,71 @@ pub enum Error {
     ))]
     FrameMaxTooSmall { min: u32, requested: u32 },
 
-    /// Timeout occurred while performing the initial TCP connection.
+    /// [`ConnectionTuning::missed_heartbeat_limit`](struct.ConnectionTuning.html#structfield.missed_heartbeat_limit)
+    /// was 0.
+    #[snafu(display("missed heartbeat limit must be at least 1"))]
+    InvalidMissedHeartbeatLimit,
+
+    /// Timeout occurred while performing the initial TCP connection, or while waiting to write
+    /// queued data to the socket of an open connection (see
+    /// [`ConnectionOptions::connection_timeout`](struct.ConnectionOptions.html#method.connection_timeout)).
     #[snafu(display("timeout occurred while waiting for TCP connection"))]
     ConnectionTimeout,
 
-    /// The server requested a Secure/Secure-Ok exchange, which are currently unsupported.
+    /// The TCP connection was not established within
+    /// [`ConnectionOptions::connect_timeout`](struct.ConnectionOptions.html#method.connect_timeout).
+    #[snafu(display("timed out establishing TCP connection"))]
+    ConnectTimeout,
+
+    /// The TLS and AMQP handshakes did not complete within
+    /// [`ConnectionOptions::handshake_timeout`](struct.ConnectionOptions.html#method.handshake_timeout).
+    #[snafu(display("timed out waiting for connection handshake to complete"))]
+    HandshakeTimeout,
+
+    /// The server requested a Secure/Secure-Ok exchange, which the SASL mechanism in use does
+    /// not support.
     #[snafu(display("SASL secure/secure-ok exchanges are not supported"))]
     SaslSecureNotSupported,
 
+    /// The SASL mechanism in use responded to a secure challenge with a response that is not
+    /// valid UTF-8.
+    #[snafu(display("SASL challenge response is not valid UTF-8"))]
+    SaslResponseNotUtf8,
+
     /// The supplied authentication credentials were not accepted by the server.
     #[snafu(display("invalid credentials"))]
     InvalidCredentials,
 
-    /// The server missed too many successive heartbeats.
-    #[snafu(display("missed heartbeats from server"))]
-    MissedServerHeartbeats,
+    /// The server missed too many successive heartbeats. `since_last_rx` is how long it had been
+    /// since any data was received from it.
+    #[snafu(display(
+        "missed heartbeats from server (last data received {} seconds ago)",
+        since_last_rx.as_secs()
+    ))]
+    MissedServerHeartbeats { since_last_rx: Duration },
 
-    /// The server closed the connection with the given reply code and text.
+    /// Data queued for the server went unwritten for longer than
+    /// [`ConnectionTuning::write_stall_timeout`](struct.ConnectionTuning.html#structfield.write_stall_timeout),
+    /// typically because the server stopped reading from the socket.
+    #[snafu(display("unable to write to socket for {:?}", stalled_for))]
+    WriteStalled { stalled_for: Duration },
+
+    /// The server closed the connection with the given reply code and text. `method` names the
+    /// method that caused the close (e.g., `"queue.declare"`), if the server said.
     #[snafu(display("server closed connection (code={} message={})", code, message))]
-    ServerClosedConnection { code: u16, message: String },
+    ServerClosedConnection {
+        code: u16,
+        message: String,
+        reply_code: AmqpReplyCode,
+        method: Option<&'static str>,
+    },
 
     /// The client closed the connection.
     #[snafu(display("client closed connection"))]
     ClientClosedConnection,
 
-    /// The server closed the given channel with the given reply code and text.
+    /// The server closed the given channel with the given reply code and text. `method` names the
+    /// method that caused the close (e.g., `"queue.declare"`), if the server said.
     #[snafu(display(
         "server closed channel {} (code={}, message={})",
         channel_id,
@@ -199,6 +366,8 @@ pub enum Error {
         channel_id: u16,
         code: u16,
         message: String,
+        reply_code: AmqpReplyCode,
+        method: Option<&'static str>,
     },
 
     /// The client closed the channel.
@@ -225,15 +394,17 @@ pub enum Error {
     #[snafu(display("fork failed: {}", source))]
     ForkFailed { source: io::Error },
 
-    /// No more channels can be opened because there are already
-    /// [`channel_max`](struct.ConnectionOptions.html#method.channel_max) channels open.
-    #[snafu(display("no more channel ids are available"))]
-    ExhaustedChannelIds,
+    /// An explicit channel ID was requested, but there is already an open channel with that ID
+    /// (channel 0 is always in use by the connection itself).
+    #[snafu(display("requested channel id ({}) is already in use", channel_id))]
+    ChannelIdInUse { channel_id: u16 },
 
-    /// An explicit channel ID was requested, but that channel is unavailable for use (e.g.,
-    /// because there is another open channel with the same ID).
-    #[snafu(display("requested channel id ({}) is unavailable", channel_id))]
-    UnavailableChannelId { channel_id: u16 },
+    /// A channel could not be opened within the `channel_max` negotiated with the server (see
+    /// [`ConnectionOptions::channel_max`](struct.ConnectionOptions.html#method.channel_max)):
+    /// either every channel ID up to it is in use, or an explicit channel ID above it was
+    /// requested.
+    #[snafu(display("channel_max ({}) reached", channel_max))]
+    ChannelMaxReached { channel_max: u16 },
 
     /// The client sent an AMQP exception to the server and closed the connection.
     #[snafu(display("internal client exception - received unhandled frames from server"))]
@@ -243,9 +414,13 @@ pub enum Error {
     #[snafu(display("received message for nonexistent channel {}", channel_id))]
     ReceivedFrameWithBogusChannelId { channel_id: u16 },
 
-    /// The I/O thread panicked.
-    #[snafu(display("I/O thread panicked"))]
-    IoThreadPanic,
+    /// The I/O thread panicked. Calls that find the I/O thread gone return this instead of
+    /// [`EventLoopDropped`](#variant.EventLoopDropped) once it has panicked, as does
+    /// [`Connection::close`](struct.Connection.html#method.close). `message` is the panic message
+    /// (if it was a string) and `stack` is a backtrace captured where the panic happened, or empty
+    /// unless [`install_panic_hook`](fn.install_panic_hook.html) has been called.
+    #[snafu(display("I/O thread panicked: {}", message))]
+    EventLoopPanicked { message: String, stack: String },
 
     /// The server sent us a consumer tag that is equal to another consumer tag we already have on
     /// the same channel.
@@ -270,10 +445,734 @@ pub enum Error {
         consumer_tag: String,
     },
 
+    /// A [`ConsumerGroup`](struct.ConsumerGroup.html) member is not running (e.g., because its
+    /// channel was closed), so it cannot acknowledge deliveries or be started.
+    #[snafu(display("consumer group member {} is not running", member))]
+    ConsumerGroupMemberStopped { member: usize },
+
+    /// An [`RpcServer`](struct.RpcServer.html) thread exited unexpectedly (e.g., because it
+    /// panicked).
+    #[snafu(display("RPC server stopped unexpectedly"))]
+    RpcServerStopped,
+
+    /// A [`Shovel`](struct.Shovel.html) thread exited unexpectedly (e.g., because it panicked).
+    #[snafu(display("shovel stopped unexpectedly"))]
+    ShovelStopped,
+
+    /// A consumer was started with a
+    /// [`stream_offset`](struct.ConsumerOptions.html#structfield.stream_offset) and `no_ack` set.
+    /// Stream queues only accept consumers that acknowledge their deliveries.
+    #[snafu(display("consumers with a stream offset must not set no_ack"))]
+    StreamOffsetRequiresManualAck,
+
+    /// [`Consumer::recv_timeout`](struct.Consumer.html#method.recv_timeout) was called after the
+    /// consumer yielded its final message; no more messages will arrive.
+    #[snafu(display("consumer {} has ended", consumer_tag))]
+    ConsumerEnded { consumer_tag: String },
+
+    /// The connection did not finish shutting down within the allotted time (see
+    /// [`ConnectionTuning::drop_timeout`](struct.ConnectionTuning.html#structfield.drop_timeout)).
+    #[snafu(display("timed out waiting for connection to shut down"))]
+    ShutdownTimeout,
+
+    /// The budget set by [`with_deadline`](fn.with_deadline.html) ran out before the operation
+    /// completed.
+    #[snafu(display("deadline exceeded"))]
+    DeadlineExceeded,
+
+    /// The server did not ack or nack a message published with
+    /// [`Channel::publish_confirmed`](struct.Channel.html#method.publish_confirmed) within the
+    /// given timeout.
+    #[snafu(display("timed out waiting for publisher confirm"))]
+    PublisherConfirmTimeout,
+
+    /// Reading the body of a message passed to
+    /// [`Exchange::publish_from_reader`](struct.Exchange.html#method.publish_from_reader) failed
+    /// after `sent` of its `len` bytes had been sent. The channel has been closed, since a
+    /// message body cannot be cut short.
+    #[snafu(display(
+        "failed to read message body after sending {} of {} bytes: {}",
+        sent,
+        len,
+        source
+    ))]
+    PublishSourceFailed {
+        sent: u64,
+        len: u64,
+        source: io::Error,
+    },
+
+    /// The channel closed before the server confirmed the messages tracked by a
+    /// [`ConfirmTracker`](struct.ConfirmTracker.html) with these sequence numbers; the server may
+    /// or may not have received them.
+    #[snafu(display("channel closed with {} unconfirmed publishes", seq_nos.len()))]
+    UnconfirmedPublishesLost { seq_nos: Vec<u64> },
+
+    /// Publisher confirms cannot be enabled on a channel in transactional mode (or vice versa);
+    /// the server would close the channel.
+    #[snafu(display("publisher confirms and transactions cannot be used on the same channel"))]
+    ConfirmsWithTransactions,
+
+    /// A delivery was acknowledged, nacked, or rejected on a channel other than the one it was
+    /// received on, which had since been closed and its ID reused. Delivery tags restart on every
+    /// new channel, so acknowledging it would settle an unrelated message.
+    #[snafu(display(
+        "delivery {} belongs to a channel {} that has since been closed",
+        delivery_tag,
+        channel_id
+    ))]
+    StaleDelivery { channel_id: u16, delivery_tag: u64 },
+
+    /// A consumer using [`OverflowPolicy::Error`](enum.OverflowPolicy.html#variant.Error) was
+    /// sent a delivery while its buffer was full, so its channel was closed.
+    #[snafu(display(
+        "consumer {} on channel {} overflowed its delivery buffer",
+        consumer_tag,
+        channel_id
+    ))]
+    ConsumerBufferOverflow {
+        channel_id: u16,
+        consumer_tag: String,
+    },
+
+    /// A [`DeliveryStream`](struct.DeliveryStream.html) was acknowledged, nacked, or rejected
+    /// before its body was read to the end or the stream was abandoned.
+    #[snafu(display(
+        "delivery {} cannot be settled until its body is read or abandoned ({} of {} bytes read)",
+        delivery_tag,
+        bytes_read,
+        body_size
+    ))]
+    DeliveryStreamUnfinished {
+        delivery_tag: u64,
+        bytes_read: u64,
+        body_size: u64,
+    },
+
+    /// A string could not be parsed as an [`Endpoint`](struct.Endpoint.html) (`host:port`).
+    #[snafu(display("invalid endpoint (expected host:port): {}", endpoint))]
+    InvalidEndpoint { endpoint: String },
+
+    /// A connection was requested from a list of endpoints, but the list was empty.
+    #[snafu(display("no endpoints to connect to"))]
+    NoEndpoints,
+
+    /// Error resolving an endpoint's host into an IP address (or addresses).
+    #[snafu(display("failed to resolve IP address of {}: {}", endpoint, source))]
+    ResolveEndpoint { endpoint: String, source: io::Error },
+
+    /// Failed to open a TCP connection to an endpoint.
+    #[snafu(display("failed to connect to {}: {}", endpoint, source))]
+    ConnectEndpoint { endpoint: String, source: io::Error },
+
+    /// Every endpoint given to a multi-endpoint open (e.g.,
+    /// [`Connection::insecure_open_endpoints`](struct.Connection.html#method.insecure_open_endpoints))
+    /// failed. `failures` holds each endpoint, in the order it was tried, with the error that
+    /// connecting to it failed with.
+    #[snafu(display("failed to connect to any endpoint: {}", display_failures(failures)))]
+    AllEndpointsFailed { failures: Vec<(String, Error)> },
+
+    /// A [`Proxy`](enum.Proxy.html) failed or refused to connect us to the server.
+    #[snafu(display("proxy error: {}", message))]
+    ProxyError { message: String },
+
+    /// A [`QueueArguments`](struct.QueueArguments.html) setter was given a value the server would
+    /// reject.
+    #[snafu(display("invalid queue argument {}: {}", argument, message))]
+    InvalidQueueArgument { argument: String, message: String },
+
+    /// A queue declaration combined options the server would reject for the queue's type (e.g., an
+    /// `auto_delete` quorum queue). Nothing was sent to the server.
+    #[snafu(display("invalid queue declaration: {}", message))]
+    InvalidQueueDeclaration { message: String },
+
+    /// The server refused to declare `queue` because it already exists with a different value of
+    /// `argument` (an `x-` argument such as `x-queue-type`, or a flag such as `durable`). The
+    /// server has closed the channel, as it does for
+    /// [`ServerClosedChannel`](#variant.ServerClosedChannel) errors; `message` is its reply
+    /// text.
+    #[snafu(display(
+        "queue {} already exists with a different {}: {}",
+        queue,
+        argument,
+        message
+    ))]
+    InequivalentQueueArgument {
+        queue: String,
+        argument: String,
+        message: String,
+    },
+
+    /// A message's priority is above the `x-max-priority` of the queue it is meant for, or the
+    /// queue is not a priority queue (`max_priority` is `None`). See
+    /// [`Queue::check_priority`](struct.Queue.html#method.check_priority).
+    #[snafu(display("{}", display_unsupported_priority(queue, *priority, *max_priority)))]
+    UnsupportedMessagePriority {
+        queue: String,
+        priority: u8,
+        max_priority: Option<u8>,
+    },
+
+    /// The server refused to declare `exchange` as a delayed-message exchange (see
+    /// [`ExchangeDeclareOptions::delayed`](struct.ExchangeDeclareOptions.html#method.delayed)),
+    /// most likely because the `rabbitmq_delayed_message_exchange` plugin is not enabled. The
+    /// server has closed the channel (or, depending on its version, the connection); `message` is
+    /// its reply text.
+    #[snafu(display(
+        "cannot declare delayed-message exchange {} (is the rabbitmq_delayed_message_exchange \
+         plugin enabled?): {}",
+        exchange,
+        message
+    ))]
+    DelayedMessagePluginMissing { exchange: String, message: String },
+
+    /// A well-known message header (e.g., `x-death`) did not have the structure the server gives
+    /// it.
+    #[snafu(display("malformed {} header: {}", header, message))]
+    MalformedHeader { header: String, message: String },
+
+    /// A message's `expiration` property is not a string of whole milliseconds the server accepts
+    /// (at most `i64::MAX`). The message was not published. Use
+    /// [`Publish::with_ttl`](struct.Publish.html#method.with_ttl) to set it from a `Duration`.
+    #[snafu(display(
+        "invalid expiration property {:?}: must be a whole number of milliseconds no greater than {}",
+        expiration,
+        i64::MAX
+    ))]
+    InvalidExpiration { expiration: String },
+
+    /// The value of `key` in a field table could not be read as the requested type (see
+    /// [`FieldTableExt::try_get`](trait.FieldTableExt.html#tymethod.try_get)). `actual` is the
+    /// AMQP type the value has, followed by the value itself if it is an integer.
+    #[snafu(display("field {:?} cannot be read as {}: it is {}", key, expected, actual))]
+    FieldTypeMismatch {
+        key: String,
+        expected: &'static str,
+        actual: String,
+    },
+
     #[doc(hidden)]
     __Nonexhaustive,
 }
 
+fn display_unsupported_priority(queue: &str, priority: u8, max_priority: Option<u8>) -> String {
+    match max_priority {
+        Some(max_priority) => format!(
+            "message priority {} exceeds x-max-priority {} of queue {}",
+            priority, max_priority, queue
+        ),
+        None => format!(
+            "message priority {} set, but queue {} is not a priority queue",
+            priority, queue
+        ),
+    }
+}
+
+fn display_failures(failures: &[(String, Error)]) -> String {
+    let failures = failures
+        .iter()
+        .map(|(endpoint, err)| format!("{} ({})", endpoint, err))
+        .collect::<Vec<_>>();
+    failures.join(", ")
+}
+
+impl Error {
+    /// The reply code the server gave if this error is
+    /// [`ServerClosedChannel`](#variant.ServerClosedChannel) or
+    /// [`ServerClosedConnection`](#variant.ServerClosedConnection), for matching on specific
+    /// failures such as [`PreconditionFailed`](enum.AmqpReplyCode.html#variant.PreconditionFailed).
+    pub fn reply_code(&self) -> Option<AmqpReplyCode> {
+        match self {
+            Error::ServerClosedChannel { reply_code, .. }
+            | Error::ServerClosedConnection { reply_code, .. } => Some(*reply_code),
+            Error::InequivalentQueueArgument { .. } => Some(AmqpReplyCode::PreconditionFailed),
+            Error::DelayedMessagePluginMissing { .. } => Some(AmqpReplyCode::CommandInvalid),
+            _ => None,
+        }
+    }
+
+    // A copy of this error, for reporting one failure in several places (see
+    // `Connection::on_close`). Sources that can't be cloned are copied as their kind and message.
+    pub(crate) fn duplicate(&self) -> Error {
+        // NOTE: Keep this match exhaustive, so adding a variant forces a decision here.
+        match self {
+            Error::UrlParseError { source } => Error::UrlParseError { source: *source },
+            Error::TlsFeatureNotEnabled => Error::TlsFeatureNotEnabled,
+            Error::InsecureUrl { url } => Error::InsecureUrl { url: url.clone() },
+            Error::UnexpectedSocketClose => Error::UnexpectedSocketClose,
+            Error::IoErrorReadingSocket { source } => Error::IoErrorReadingSocket {
+                source: duplicate_io_error(source),
+            },
+            Error::IoErrorWritingSocket { source } => Error::IoErrorWritingSocket {
+                source: duplicate_io_error(source),
+            },
+            Error::MalformedFrame => Error::MalformedFrame,
+            Error::MalformedFrameDump { dump } => Error::MalformedFrameDump { dump: dump.clone() },
+            Error::ReceivedFrameTooLarge { size, max } => Error::ReceivedFrameTooLarge {
+                size: *size,
+                max: *max,
+            },
+            Error::UrlNoSocketAddrs { url } => Error::UrlNoSocketAddrs { url: url.clone() },
+            Error::ResolveUrlToSocketAddr { url, source } => Error::ResolveUrlToSocketAddr {
+                url: url.clone(),
+                source: duplicate_io_error(source),
+            },
+            Error::FailedToConnect { url, source } => Error::FailedToConnect {
+                url: url.clone(),
+                source: duplicate_io_error(source),
+            },
+            Error::SpecifyUrlPort { url } => Error::SpecifyUrlPort { url: url.clone() },
+            Error::InvalidUrlScheme { url } => Error::InvalidUrlScheme { url: url.clone() },
+            Error::UrlMissingDomain { url } => Error::UrlMissingDomain { url: url.clone() },
+            Error::ExtraUrlPathSegments { url } => Error::ExtraUrlPathSegments { url: url.clone() },
+            Error::UrlParseHeartbeat { url, source } => Error::UrlParseHeartbeat {
+                url: url.clone(),
+                source: source.clone(),
+            },
+            Error::UrlParseChannelMax { url, source } => Error::UrlParseChannelMax {
+                url: url.clone(),
+                source: source.clone(),
+            },
+            Error::UrlParseFrameMax { url, source } => Error::UrlParseFrameMax {
+                url: url.clone(),
+                source: source.clone(),
+            },
+            Error::UrlParseConnectionTimeout { url, source } => Error::UrlParseConnectionTimeout {
+                url: url.clone(),
+                source: source.clone(),
+            },
+            Error::UrlInvalidAuthMechanism { url, mechanism } => Error::UrlInvalidAuthMechanism {
+                url: url.clone(),
+                mechanism: mechanism.clone(),
+            },
+            Error::UrlUnsupportedParameter { url, parameter } => Error::UrlUnsupportedParameter {
+                url: url.clone(),
+                parameter: parameter.clone(),
+            },
+            Error::CreatePollHandle { source } => Error::CreatePollHandle {
+                source: duplicate_io_error(source),
+            },
+            Error::SetTcpOption { option, source } => Error::SetTcpOption {
+                option,
+                source: duplicate_io_error(source),
+            },
+            Error::RegisterWithPollHandle { source } => Error::RegisterWithPollHandle {
+                source: duplicate_io_error(source),
+            },
+            Error::DeregisterWithPollHandle { source } => Error::DeregisterWithPollHandle {
+                source: duplicate_io_error(source),
+            },
+            Error::FailedToPoll { source } => Error::FailedToPoll {
+                source: duplicate_io_error(source),
+            },
+            #[cfg(feature = "native-tls")]
+            Error::TlsHandshake { source } => Error::TlsHandshake {
+                source: Arc::clone(source),
+            },
+            #[cfg(feature = "native-tls")]
+            Error::CreateTlsConnector { source } => Error::CreateTlsConnector {
+                source: Arc::clone(source),
+            },
+            #[cfg(feature = "rustls")]
+            Error::RustlsHandshake { source } => Error::RustlsHandshake {
+                source: source.clone(),
+            },
+            #[cfg(feature = "rustls")]
+            Error::InvalidTlsDomain { domain } => Error::InvalidTlsDomain {
+                domain: domain.clone(),
+            },
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            Error::InvalidClientIdentity { message } => Error::InvalidClientIdentity {
+                message: message.clone(),
+            },
+            #[cfg(feature = "rustls")]
+            Error::CannotSkipHostnameVerification => Error::CannotSkipHostnameVerification,
+            #[cfg(feature = "serde")]
+            Error::ParseDefinitions { source } => Error::ParseDefinitions {
+                source: duplicate_json_error(source),
+            },
+            #[cfg(feature = "serde")]
+            Error::InvalidDefinitions { message } => Error::InvalidDefinitions {
+                message: message.clone(),
+            },
+            #[cfg(feature = "serde")]
+            Error::EncodeJson { source } => Error::EncodeJson {
+                source: duplicate_json_error(source),
+            },
+            #[cfg(feature = "serde")]
+            Error::DecodeJson { source } => Error::DecodeJson {
+                source: duplicate_json_error(source),
+            },
+            #[cfg(feature = "serde")]
+            Error::UnexpectedContentType {
+                expected,
+                content_type,
+            } => Error::UnexpectedContentType {
+                expected: expected.clone(),
+                content_type: content_type.clone(),
+            },
+            #[cfg(feature = "serde")]
+            Error::JsonConversion { message } => Error::JsonConversion {
+                message: message.clone(),
+            },
+            #[cfg(feature = "scram")]
+            Error::ScramProtocol { message } => Error::ScramProtocol {
+                message: message.clone(),
+            },
+            #[cfg(feature = "scram")]
+            Error::ScramServerSignatureMismatch => Error::ScramServerSignatureMismatch,
+            #[cfg(feature = "compression")]
+            Error::Decompress {
+                content_encoding,
+                source,
+            } => Error::Decompress {
+                content_encoding: content_encoding.clone(),
+                source: duplicate_io_error(source),
+            },
+            #[cfg(feature = "compression")]
+            Error::DecompressedBodyTooLarge { max_len } => {
+                Error::DecompressedBodyTooLarge { max_len: *max_len }
+            }
+            Error::UnsupportedAuthMechanism {
+                available,
+                requested,
+            } => Error::UnsupportedAuthMechanism {
+                available: available.clone(),
+                requested: requested.clone(),
+            },
+            Error::UnsupportedLocale {
+                available,
+                requested,
+            } => Error::UnsupportedLocale {
+                available: available.clone(),
+                requested: requested.clone(),
+            },
+            Error::FrameMaxTooSmall { min, requested } => Error::FrameMaxTooSmall {
+                min: *min,
+                requested: *requested,
+            },
+            Error::InvalidMissedHeartbeatLimit => Error::InvalidMissedHeartbeatLimit,
+            Error::ConnectionTimeout => Error::ConnectionTimeout,
+            Error::ConnectTimeout => Error::ConnectTimeout,
+            Error::HandshakeTimeout => Error::HandshakeTimeout,
+            Error::SaslSecureNotSupported => Error::SaslSecureNotSupported,
+            Error::SaslResponseNotUtf8 => Error::SaslResponseNotUtf8,
+            Error::InvalidCredentials => Error::InvalidCredentials,
+            Error::MissedServerHeartbeats { since_last_rx } => Error::MissedServerHeartbeats {
+                since_last_rx: *since_last_rx,
+            },
+            Error::WriteStalled { stalled_for } => Error::WriteStalled {
+                stalled_for: *stalled_for,
+            },
+            Error::ServerClosedConnection {
+                code,
+                message,
+                reply_code,
+                method,
+            } => Error::ServerClosedConnection {
+                code: *code,
+                message: message.clone(),
+                reply_code: *reply_code,
+                method: *method,
+            },
+            Error::ClientClosedConnection => Error::ClientClosedConnection,
+            Error::ServerClosedChannel {
+                channel_id,
+                code,
+                message,
+                reply_code,
+                method,
+            } => Error::ServerClosedChannel {
+                channel_id: *channel_id,
+                code: *code,
+                message: message.clone(),
+                reply_code: *reply_code,
+                method: *method,
+            },
+            Error::ClientClosedChannel => Error::ClientClosedChannel,
+            Error::EventLoopClientDropped => Error::EventLoopClientDropped,
+            Error::EventLoopDropped => Error::EventLoopDropped,
+            Error::FrameUnexpected => Error::FrameUnexpected,
+            Error::ForkFailed { source } => Error::ForkFailed {
+                source: duplicate_io_error(source),
+            },
+            Error::ChannelIdInUse { channel_id } => Error::ChannelIdInUse {
+                channel_id: *channel_id,
+            },
+            Error::ChannelMaxReached { channel_max } => Error::ChannelMaxReached {
+                channel_max: *channel_max,
+            },
+            Error::ClientException => Error::ClientException,
+            Error::ReceivedFrameWithBogusChannelId { channel_id } => {
+                Error::ReceivedFrameWithBogusChannelId {
+                    channel_id: *channel_id,
+                }
+            }
+            Error::EventLoopPanicked { message, stack } => Error::EventLoopPanicked {
+                message: message.clone(),
+                stack: stack.clone(),
+            },
+            Error::DuplicateConsumerTag {
+                channel_id,
+                consumer_tag,
+            } => Error::DuplicateConsumerTag {
+                channel_id: *channel_id,
+                consumer_tag: consumer_tag.clone(),
+            },
+            Error::UnknownConsumerTag {
+                channel_id,
+                consumer_tag,
+            } => Error::UnknownConsumerTag {
+                channel_id: *channel_id,
+                consumer_tag: consumer_tag.clone(),
+            },
+            Error::ConsumerGroupMemberStopped { member } => {
+                Error::ConsumerGroupMemberStopped { member: *member }
+            }
+            Error::RpcServerStopped => Error::RpcServerStopped,
+            Error::ShovelStopped => Error::ShovelStopped,
+            Error::StreamOffsetRequiresManualAck => Error::StreamOffsetRequiresManualAck,
+            Error::ConsumerEnded { consumer_tag } => Error::ConsumerEnded {
+                consumer_tag: consumer_tag.clone(),
+            },
+            Error::ShutdownTimeout => Error::ShutdownTimeout,
+            Error::DeadlineExceeded => Error::DeadlineExceeded,
+            Error::PublisherConfirmTimeout => Error::PublisherConfirmTimeout,
+            Error::PublishSourceFailed { sent, len, source } => Error::PublishSourceFailed {
+                sent: *sent,
+                len: *len,
+                source: duplicate_io_error(source),
+            },
+            Error::UnconfirmedPublishesLost { seq_nos } => Error::UnconfirmedPublishesLost {
+                seq_nos: seq_nos.clone(),
+            },
+            Error::ConfirmsWithTransactions => Error::ConfirmsWithTransactions,
+            Error::StaleDelivery {
+                channel_id,
+                delivery_tag,
+            } => Error::StaleDelivery {
+                channel_id: *channel_id,
+                delivery_tag: *delivery_tag,
+            },
+            Error::ConsumerBufferOverflow {
+                channel_id,
+                consumer_tag,
+            } => Error::ConsumerBufferOverflow {
+                channel_id: *channel_id,
+                consumer_tag: consumer_tag.clone(),
+            },
+            Error::DeliveryStreamUnfinished {
+                delivery_tag,
+                bytes_read,
+                body_size,
+            } => Error::DeliveryStreamUnfinished {
+                delivery_tag: *delivery_tag,
+                bytes_read: *bytes_read,
+                body_size: *body_size,
+            },
+            Error::InvalidEndpoint { endpoint } => Error::InvalidEndpoint {
+                endpoint: endpoint.clone(),
+            },
+            Error::NoEndpoints => Error::NoEndpoints,
+            Error::ResolveEndpoint { endpoint, source } => Error::ResolveEndpoint {
+                endpoint: endpoint.clone(),
+                source: duplicate_io_error(source),
+            },
+            Error::ConnectEndpoint { endpoint, source } => Error::ConnectEndpoint {
+                endpoint: endpoint.clone(),
+                source: duplicate_io_error(source),
+            },
+            Error::AllEndpointsFailed { failures } => Error::AllEndpointsFailed {
+                failures: failures
+                    .iter()
+                    .map(|(endpoint, err)| (endpoint.clone(), err.duplicate()))
+                    .collect(),
+            },
+            Error::ProxyError { message } => Error::ProxyError {
+                message: message.clone(),
+            },
+            Error::InvalidQueueArgument { argument, message } => Error::InvalidQueueArgument {
+                argument: argument.clone(),
+                message: message.clone(),
+            },
+            Error::InvalidQueueDeclaration { message } => Error::InvalidQueueDeclaration {
+                message: message.clone(),
+            },
+            Error::InequivalentQueueArgument {
+                queue,
+                argument,
+                message,
+            } => Error::InequivalentQueueArgument {
+                queue: queue.clone(),
+                argument: argument.clone(),
+                message: message.clone(),
+            },
+            Error::UnsupportedMessagePriority {
+                queue,
+                priority,
+                max_priority,
+            } => Error::UnsupportedMessagePriority {
+                queue: queue.clone(),
+                priority: *priority,
+                max_priority: *max_priority,
+            },
+            Error::DelayedMessagePluginMissing { exchange, message } => {
+                Error::DelayedMessagePluginMissing {
+                    exchange: exchange.clone(),
+                    message: message.clone(),
+                }
+            }
+            Error::MalformedHeader { header, message } => Error::MalformedHeader {
+                header: header.clone(),
+                message: message.clone(),
+            },
+            Error::InvalidExpiration { expiration } => Error::InvalidExpiration {
+                expiration: expiration.clone(),
+            },
+            Error::FieldTypeMismatch {
+                key,
+                expected,
+                actual,
+            } => Error::FieldTypeMismatch {
+                key: key.clone(),
+                expected,
+                actual: actual.clone(),
+            },
+            Error::__Nonexhaustive => Error::__Nonexhaustive,
+        }
+    }
+
+    /// Whether this error means the connection it came from is gone (or could not be
+    /// established) for a reason that may be transient, so opening a new connection to the same
+    /// server is worth trying: the socket failed or timed out, the server stopped responding,
+    /// or an operator forced the connection closed (reply code 320, e.g., during a broker
+    /// restart).
+    ///
+    /// Errors caused by configuration or by the server rejecting what we asked for (bad
+    /// credentials, an unsupported auth mechanism, a too-small `frame_max`, a channel closed by
+    /// the server, and so on) are not recoverable: retrying would fail the same way.
+    pub fn is_recoverable(&self) -> bool {
+        // NOTE: Keep this match exhaustive, so adding a variant forces a decision here.
+        match self {
+            Error::UnexpectedSocketClose
+            | Error::IoErrorReadingSocket { .. }
+            | Error::IoErrorWritingSocket { .. }
+            | Error::ResolveUrlToSocketAddr { .. }
+            | Error::FailedToConnect { .. }
+            | Error::ResolveEndpoint { .. }
+            | Error::ConnectEndpoint { .. }
+            | Error::FailedToPoll { .. }
+            | Error::ConnectionTimeout
+            | Error::ConnectTimeout
+            | Error::HandshakeTimeout
+            | Error::MissedServerHeartbeats { .. }
+            | Error::WriteStalled { .. }
+            | Error::EventLoopDropped
+            | Error::EventLoopPanicked { .. } => true,
+            Error::ServerClosedConnection { reply_code, .. } => {
+                *reply_code == AmqpReplyCode::ConnectionForced
+            }
+            Error::AllEndpointsFailed { failures } => {
+                failures.iter().any(|(_, err)| err.is_recoverable())
+            }
+            Error::UrlParseError { .. }
+            | Error::TlsFeatureNotEnabled
+            | Error::InsecureUrl { .. }
+            | Error::MalformedFrame
+            | Error::MalformedFrameDump { .. }
+            | Error::ReceivedFrameTooLarge { .. }
+            | Error::UrlNoSocketAddrs { .. }
+            | Error::SpecifyUrlPort { .. }
+            | Error::InvalidUrlScheme { .. }
+            | Error::UrlMissingDomain { .. }
+            | Error::ExtraUrlPathSegments { .. }
+            | Error::UrlParseHeartbeat { .. }
+            | Error::UrlParseChannelMax { .. }
+            | Error::UrlParseFrameMax { .. }
+            | Error::UrlParseConnectionTimeout { .. }
+            | Error::UrlInvalidAuthMechanism { .. }
+            | Error::UrlUnsupportedParameter { .. }
+            | Error::CreatePollHandle { .. }
+            | Error::SetTcpOption { .. }
+            | Error::RegisterWithPollHandle { .. }
+            | Error::DeregisterWithPollHandle { .. }
+            | Error::UnsupportedAuthMechanism { .. }
+            | Error::UnsupportedLocale { .. }
+            | Error::FrameMaxTooSmall { .. }
+            | Error::InvalidMissedHeartbeatLimit
+            | Error::SaslSecureNotSupported
+            | Error::SaslResponseNotUtf8
+            | Error::InvalidCredentials
+            | Error::ClientClosedConnection
+            | Error::ServerClosedChannel { .. }
+            | Error::ClientClosedChannel
+            | Error::EventLoopClientDropped
+            | Error::FrameUnexpected
+            | Error::ForkFailed { .. }
+            | Error::ChannelIdInUse { .. }
+            | Error::ChannelMaxReached { .. }
+            | Error::ClientException
+            | Error::ReceivedFrameWithBogusChannelId { .. }
+            | Error::DuplicateConsumerTag { .. }
+            | Error::UnknownConsumerTag { .. }
+            | Error::ConsumerGroupMemberStopped { .. }
+            | Error::RpcServerStopped
+            | Error::ShovelStopped
+            | Error::StreamOffsetRequiresManualAck
+            | Error::ConsumerEnded { .. }
+            | Error::ShutdownTimeout
+            | Error::DeadlineExceeded
+            | Error::PublisherConfirmTimeout
+            | Error::PublishSourceFailed { .. }
+            | Error::UnconfirmedPublishesLost { .. }
+            | Error::ConfirmsWithTransactions
+            | Error::StaleDelivery { .. }
+            | Error::ConsumerBufferOverflow { .. }
+            | Error::DeliveryStreamUnfinished { .. }
+            | Error::InvalidEndpoint { .. }
+            | Error::NoEndpoints
+            | Error::ProxyError { .. }
+            | Error::InvalidQueueArgument { .. }
+            | Error::InvalidQueueDeclaration { .. }
+            | Error::InequivalentQueueArgument { .. }
+            | Error::DelayedMessagePluginMissing { .. }
+            | Error::UnsupportedMessagePriority { .. }
+            | Error::MalformedHeader { .. }
+            | Error::InvalidExpiration { .. }
+            | Error::FieldTypeMismatch { .. }
+            | Error::__Nonexhaustive => false,
+            #[cfg(feature = "native-tls")]
+            Error::TlsHandshake { .. } | Error::CreateTlsConnector { .. } => false,
+            #[cfg(feature = "rustls")]
+            Error::RustlsHandshake { .. }
+            | Error::InvalidTlsDomain { .. }
+            | Error::CannotSkipHostnameVerification => false,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            Error::InvalidClientIdentity { .. } => false,
+            #[cfg(feature = "serde")]
+            Error::ParseDefinitions { .. }
+            | Error::InvalidDefinitions { .. }
+            | Error::EncodeJson { .. }
+            | Error::DecodeJson { .. }
+            | Error::UnexpectedContentType { .. }
+            | Error::JsonConversion { .. } => false,
+            #[cfg(feature = "scram")]
+            Error::ScramProtocol { .. } | Error::ScramServerSignatureMismatch => false,
+            #[cfg(feature = "compression")]
+            Error::Decompress { .. } | Error::DecompressedBodyTooLarge { .. } => false,
+        }
+    }
+}
+
+fn duplicate_io_error(err: &io::Error) -> io::Error {
+    io::Error::new(err.kind(), err.to_string())
+}
+
+#[cfg(feature = "serde")]
+fn duplicate_json_error(err: &serde_json::Error) -> serde_json::Error {
+    <serde_json::Error as serde_crate::de::Error>::custom(err)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -283,4 +1182,460 @@ mod tests {
         fn is_err<T: std::error::Error>() {}
         is_err::<Error>();
     }
+
+    #[test]
+    fn errors_convert_to_boxed_std_errors_with_sources() {
+        fn open() -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
+            Err(Error::IoErrorReadingSocket { source: io_err() })?;
+            Ok(())
+        }
+        let err = open().unwrap_err();
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "reset");
+        assert!(source.downcast_ref::<io::Error>().is_some());
+    }
+
+    // The classification is_recoverable is expected to make, written out separately so a change
+    // to either has to be made deliberately. Like is_recoverable, this match is exhaustive: a new
+    // variant does not compile until it has been classified here too (and given a sample below).
+    fn expected_recoverable(err: &Error) -> bool {
+        match err {
+            Error::UnexpectedSocketClose
+            | Error::IoErrorReadingSocket { .. }
+            | Error::IoErrorWritingSocket { .. }
+            | Error::ResolveUrlToSocketAddr { .. }
+            | Error::FailedToConnect { .. }
+            | Error::ResolveEndpoint { .. }
+            | Error::ConnectEndpoint { .. }
+            | Error::FailedToPoll { .. }
+            | Error::ConnectionTimeout
+            | Error::ConnectTimeout
+            | Error::HandshakeTimeout
+            | Error::MissedServerHeartbeats { .. }
+            | Error::WriteStalled { .. }
+            | Error::EventLoopDropped
+            | Error::EventLoopPanicked { .. } => true,
+            Error::ServerClosedConnection { code, .. } => *code == 320,
+            Error::AllEndpointsFailed { failures } => {
+                failures.iter().any(|(_, err)| expected_recoverable(err))
+            }
+            Error::UrlParseError { .. }
+            | Error::TlsFeatureNotEnabled
+            | Error::InsecureUrl { .. }
+            | Error::MalformedFrame
+            | Error::MalformedFrameDump { .. }
+            | Error::ReceivedFrameTooLarge { .. }
+            | Error::UrlNoSocketAddrs { .. }
+            | Error::SpecifyUrlPort { .. }
+            | Error::InvalidUrlScheme { .. }
+            | Error::UrlMissingDomain { .. }
+            | Error::ExtraUrlPathSegments { .. }
+            | Error::UrlParseHeartbeat { .. }
+            | Error::UrlParseChannelMax { .. }
+            | Error::UrlParseFrameMax { .. }
+            | Error::UrlParseConnectionTimeout { .. }
+            | Error::UrlInvalidAuthMechanism { .. }
+            | Error::UrlUnsupportedParameter { .. }
+            | Error::CreatePollHandle { .. }
+            | Error::SetTcpOption { .. }
+            | Error::RegisterWithPollHandle { .. }
+            | Error::DeregisterWithPollHandle { .. }
+            | Error::UnsupportedAuthMechanism { .. }
+            | Error::UnsupportedLocale { .. }
+            | Error::FrameMaxTooSmall { .. }
+            | Error::InvalidMissedHeartbeatLimit
+            | Error::SaslSecureNotSupported
+            | Error::SaslResponseNotUtf8
+            | Error::InvalidCredentials
+            | Error::ClientClosedConnection
+            | Error::ServerClosedChannel { .. }
+            | Error::ClientClosedChannel
+            | Error::EventLoopClientDropped
+            | Error::FrameUnexpected
+            | Error::ForkFailed { .. }
+            | Error::ChannelIdInUse { .. }
+            | Error::ChannelMaxReached { .. }
+            | Error::ClientException
+            | Error::ReceivedFrameWithBogusChannelId { .. }
+            | Error::DuplicateConsumerTag { .. }
+            | Error::UnknownConsumerTag { .. }
+            | Error::ConsumerGroupMemberStopped { .. }
+            | Error::RpcServerStopped
+            | Error::ShovelStopped
+            | Error::StreamOffsetRequiresManualAck
+            | Error::ConsumerEnded { .. }
+            | Error::ShutdownTimeout
+            | Error::DeadlineExceeded
+            | Error::PublisherConfirmTimeout
+            | Error::PublishSourceFailed { .. }
+            | Error::UnconfirmedPublishesLost { .. }
+            | Error::ConfirmsWithTransactions
+            | Error::StaleDelivery { .. }
+            | Error::ConsumerBufferOverflow { .. }
+            | Error::DeliveryStreamUnfinished { .. }
+            | Error::InvalidEndpoint { .. }
+            | Error::NoEndpoints
+            | Error::ProxyError { .. }
+            | Error::InvalidQueueArgument { .. }
+            | Error::InvalidQueueDeclaration { .. }
+            | Error::InequivalentQueueArgument { .. }
+            | Error::DelayedMessagePluginMissing { .. }
+            | Error::UnsupportedMessagePriority { .. }
+            | Error::MalformedHeader { .. }
+            | Error::InvalidExpiration { .. }
+            | Error::FieldTypeMismatch { .. }
+            | Error::__Nonexhaustive => false,
+            #[cfg(feature = "native-tls")]
+            Error::TlsHandshake { .. } | Error::CreateTlsConnector { .. } => false,
+            #[cfg(feature = "rustls")]
+            Error::RustlsHandshake { .. }
+            | Error::InvalidTlsDomain { .. }
+            | Error::CannotSkipHostnameVerification => false,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            Error::InvalidClientIdentity { .. } => false,
+            #[cfg(feature = "serde")]
+            Error::ParseDefinitions { .. }
+            | Error::InvalidDefinitions { .. }
+            | Error::EncodeJson { .. }
+            | Error::DecodeJson { .. }
+            | Error::UnexpectedContentType { .. }
+            | Error::JsonConversion { .. } => false,
+            #[cfg(feature = "scram")]
+            Error::ScramProtocol { .. } | Error::ScramServerSignatureMismatch => false,
+            #[cfg(feature = "compression")]
+            Error::Decompress { .. } | Error::DecompressedBodyTooLarge { .. } => false,
+        }
+    }
+
+    fn io_err() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "reset")
+    }
+
+    fn server_closed_connection(code: u16) -> Error {
+        Error::ServerClosedConnection {
+            code,
+            message: String::new(),
+            reply_code: AmqpReplyCode::from_code(code),
+            method: None,
+        }
+    }
+
+    fn samples() -> Vec<Error> {
+        let url = Url::parse("amqp://localhost").unwrap();
+        let parse_int = || "x".parse::<u16>().unwrap_err();
+        // Only mutated when some optional features are enabled.
+        #[allow(unused_mut)]
+        let mut samples = vec![
+            Error::UrlParseError {
+                source: url::ParseError::EmptyHost,
+            },
+            Error::TlsFeatureNotEnabled,
+            Error::InsecureUrl { url: url.clone() },
+            Error::UnexpectedSocketClose,
+            Error::IoErrorReadingSocket { source: io_err() },
+            Error::IoErrorWritingSocket { source: io_err() },
+            Error::MalformedFrame,
+            Error::MalformedFrameDump {
+                dump: String::new(),
+            },
+            Error::ReceivedFrameTooLarge {
+                size: 131_080,
+                max: 131_072,
+            },
+            Error::UrlNoSocketAddrs { url: url.clone() },
+            Error::ResolveUrlToSocketAddr {
+                url: url.clone(),
+                source: io_err(),
+            },
+            Error::FailedToConnect {
+                url: url.clone(),
+                source: io_err(),
+            },
+            Error::SpecifyUrlPort { url: url.clone() },
+            Error::InvalidUrlScheme { url: url.clone() },
+            Error::UrlMissingDomain { url: url.clone() },
+            Error::ExtraUrlPathSegments { url: url.clone() },
+            Error::UrlParseHeartbeat {
+                url: url.clone(),
+                source: parse_int(),
+            },
+            Error::UrlParseChannelMax {
+                url: url.clone(),
+                source: parse_int(),
+            },
+            Error::UrlParseFrameMax {
+                url: url.clone(),
+                source: parse_int(),
+            },
+            Error::UrlParseConnectionTimeout {
+                url: url.clone(),
+                source: parse_int(),
+            },
+            Error::UrlInvalidAuthMechanism {
+                url: url.clone(),
+                mechanism: String::new(),
+            },
+            Error::UrlUnsupportedParameter {
+                url,
+                parameter: String::new(),
+            },
+            Error::CreatePollHandle { source: io_err() },
+            Error::SetTcpOption {
+                option: "nodelay",
+                source: io_err(),
+            },
+            Error::RegisterWithPollHandle { source: io_err() },
+            Error::DeregisterWithPollHandle { source: io_err() },
+            Error::FailedToPoll { source: io_err() },
+            Error::UnsupportedAuthMechanism {
+                available: String::new(),
+                requested: String::new(),
+            },
+            Error::UnsupportedLocale {
+                available: String::new(),
+                requested: String::new(),
+            },
+            Error::FrameMaxTooSmall {
+                min: 4096,
+                requested: 0,
+            },
+            Error::InvalidMissedHeartbeatLimit,
+            Error::ConnectionTimeout,
+            Error::ConnectTimeout,
+            Error::HandshakeTimeout,
+            Error::SaslSecureNotSupported,
+            Error::SaslResponseNotUtf8,
+            Error::InvalidCredentials,
+            Error::MissedServerHeartbeats {
+                since_last_rx: Duration::from_secs(30),
+            },
+            Error::WriteStalled {
+                stalled_for: Duration::from_secs(1),
+            },
+            server_closed_connection(320),
+            server_closed_connection(541),
+            Error::ClientClosedConnection,
+            Error::ServerClosedChannel {
+                channel_id: 1,
+                code: 406,
+                message: String::new(),
+                reply_code: AmqpReplyCode::PreconditionFailed,
+                method: None,
+            },
+            Error::ClientClosedChannel,
+            Error::EventLoopClientDropped,
+            Error::EventLoopDropped,
+            Error::FrameUnexpected,
+            Error::ForkFailed { source: io_err() },
+            Error::ChannelIdInUse { channel_id: 1 },
+            Error::ChannelMaxReached { channel_max: 1 },
+            Error::ClientException,
+            Error::ReceivedFrameWithBogusChannelId { channel_id: 1 },
+            Error::EventLoopPanicked {
+                message: "oops".to_string(),
+                stack: String::new(),
+            },
+            Error::DuplicateConsumerTag {
+                channel_id: 1,
+                consumer_tag: String::new(),
+            },
+            Error::UnknownConsumerTag {
+                channel_id: 1,
+                consumer_tag: String::new(),
+            },
+            Error::ConsumerGroupMemberStopped { member: 0 },
+            Error::RpcServerStopped,
+            Error::ShovelStopped,
+            Error::StreamOffsetRequiresManualAck,
+            Error::ConsumerEnded {
+                consumer_tag: String::new(),
+            },
+            Error::ShutdownTimeout,
+            Error::DeadlineExceeded,
+            Error::PublisherConfirmTimeout,
+            Error::PublishSourceFailed {
+                sent: 0,
+                len: 0,
+                source: io_err(),
+            },
+            Error::UnconfirmedPublishesLost {
+                seq_nos: Vec::new(),
+            },
+            Error::ConfirmsWithTransactions,
+            Error::StaleDelivery {
+                channel_id: 1,
+                delivery_tag: 1,
+            },
+            Error::ConsumerBufferOverflow {
+                channel_id: 1,
+                consumer_tag: String::new(),
+            },
+            Error::DeliveryStreamUnfinished {
+                delivery_tag: 1,
+                bytes_read: 0,
+                body_size: 1,
+            },
+            Error::InvalidEndpoint {
+                endpoint: String::new(),
+            },
+            Error::NoEndpoints,
+            Error::ResolveEndpoint {
+                endpoint: String::new(),
+                source: io_err(),
+            },
+            Error::ConnectEndpoint {
+                endpoint: String::new(),
+                source: io_err(),
+            },
+            Error::AllEndpointsFailed {
+                failures: vec![
+                    (String::new(), Error::InvalidCredentials),
+                    (String::new(), Error::ConnectTimeout),
+                ],
+            },
+            Error::AllEndpointsFailed {
+                failures: vec![(String::new(), Error::InvalidCredentials)],
+            },
+            Error::ProxyError {
+                message: String::new(),
+            },
+            Error::InvalidQueueArgument {
+                argument: String::new(),
+                message: String::new(),
+            },
+            Error::InvalidQueueDeclaration {
+                message: String::new(),
+            },
+            Error::InequivalentQueueArgument {
+                queue: String::new(),
+                argument: String::new(),
+                message: String::new(),
+            },
+            Error::UnsupportedMessagePriority {
+                queue: String::new(),
+                priority: 0,
+                max_priority: None,
+            },
+            Error::DelayedMessagePluginMissing {
+                exchange: String::new(),
+                message: String::new(),
+            },
+            Error::MalformedHeader {
+                header: String::new(),
+                message: String::new(),
+            },
+            Error::InvalidExpiration {
+                expiration: String::new(),
+            },
+            Error::FieldTypeMismatch {
+                key: String::new(),
+                expected: "",
+                actual: String::new(),
+            },
+        ];
+        #[cfg(feature = "native-tls")]
+        {
+            let tls_err = || match native_tls::Certificate::from_pem(b"not a certificate") {
+                Ok(_) => unreachable!(),
+                Err(err) => err,
+            };
+            samples.push(Error::TlsHandshake {
+                source: Arc::new(tls_err()),
+            });
+            samples.push(Error::CreateTlsConnector {
+                source: Arc::new(tls_err()),
+            });
+        }
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        samples.push(Error::InvalidClientIdentity {
+            message: String::new(),
+        });
+        #[cfg(feature = "rustls")]
+        {
+            samples.push(Error::RustlsHandshake {
+                source: rustls_crate::Error::DecryptError,
+            });
+            samples.push(Error::InvalidTlsDomain {
+                domain: String::new(),
+            });
+            samples.push(Error::CannotSkipHostnameVerification);
+        }
+        #[cfg(feature = "serde")]
+        {
+            samples.push(Error::ParseDefinitions {
+                source: serde_json::from_str::<u8>("x").unwrap_err(),
+            });
+            samples.push(Error::InvalidDefinitions {
+                message: String::new(),
+            });
+            samples.push(Error::EncodeJson {
+                source: serde_json::from_str::<u8>("x").unwrap_err(),
+            });
+            samples.push(Error::DecodeJson {
+                source: serde_json::from_str::<u8>("x").unwrap_err(),
+            });
+            samples.push(Error::UnexpectedContentType {
+                expected: String::new(),
+                content_type: None,
+            });
+            samples.push(Error::JsonConversion {
+                message: String::new(),
+            });
+        }
+        #[cfg(feature = "scram")]
+        {
+            samples.push(Error::ScramProtocol {
+                message: String::new(),
+            });
+            samples.push(Error::ScramServerSignatureMismatch);
+        }
+        #[cfg(feature = "compression")]
+        {
+            samples.push(Error::Decompress {
+                content_encoding: String::new(),
+                source: io_err(),
+            });
+            samples.push(Error::DecompressedBodyTooLarge { max_len: 0 });
+        }
+        samples
+    }
+
+    #[test]
+    fn recoverable_classification() {
+        for err in samples() {
+            assert_eq!(
+                err.is_recoverable(),
+                expected_recoverable(&err),
+                "misclassified {:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_keeps_kind_and_message() {
+        for err in samples() {
+            let dup = err.duplicate();
+            assert_eq!(
+                std::mem::discriminant(&dup),
+                std::mem::discriminant(&err),
+                "changed kind of {:?}",
+                err
+            );
+            assert_eq!(dup.to_string(), err.to_string());
+        }
+    }
+
+    #[test]
+    fn only_connection_forced_server_close_is_recoverable() {
+        assert!(server_closed_connection(320).is_recoverable());
+        assert!(!server_closed_connection(200).is_recoverable());
+        assert!(!server_closed_connection(403).is_recoverable());
+        assert!(!server_closed_connection(541).is_recoverable());
+        assert!(Error::MissedServerHeartbeats {
+            since_last_rx: Duration::from_secs(30)
+        }
+        .is_recoverable());
+        assert!(!Error::InvalidCredentials.is_recoverable());
+    }
 }
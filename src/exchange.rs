@@ -1,5 +1,14 @@
-use crate::{AmqpProperties, Channel, FieldTable, Result};
+use crate::errors::*;
+#[cfg(feature = "compression")]
+use crate::Compression;
+use crate::properties::AmqpPropertiesExt;
+use crate::{AmqpProperties, AmqpReplyCode, AmqpValue, Channel, FieldTable, HeaderValue};
 use amq_protocol::protocol::exchange::Declare;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Types of AMQP exchanges.
 #[derive(Debug, Clone)]
@@ -34,10 +43,14 @@ impl AsRef<str> for ExchangeType {
     }
 }
 
+// The exchange type and argument of the delayed-message exchange plugin.
+const DELAYED_MESSAGE_TYPE: &str = "x-delayed-message";
+const X_DELAYED_TYPE: &str = "x-delayed-type";
+
 /// Options passed to the server when declaring an exchange.
 ///
 /// The [`default`](#impl-Default) implementation sets all boolean fields to false and has an empty
-/// set of arguments.
+/// set of arguments. [`delayed`](#method.delayed) creates options for a delayed-message exchange.
 #[derive(Clone, Debug, Default)]
 pub struct ExchangeDeclareOptions {
     /// If true, declares exchange as durable (survives server restarts); if false, declares
@@ -59,6 +72,47 @@ pub struct ExchangeDeclareOptions {
 }
 
 impl ExchangeDeclareOptions {
+    /// Options for an exchange of the [delayed-message exchange
+    /// plugin](https://github.com/rabbitmq/rabbitmq-delayed-message-exchange), which holds each
+    /// message for the delay set with [`Publish::with_delay`](struct.Publish.html#method.with_delay)
+    /// and then routes it as an exchange of type `routing` would.
+    ///
+    /// This sets the `x-delayed-type` argument, and an exchange declared with these options has
+    /// type `x-delayed-message`, whatever type is passed to
+    /// [`Channel::exchange_declare`](struct.Channel.html#method.exchange_declare). If the server
+    /// does not have the plugin enabled, the declaration fails with
+    /// [`Error::DelayedMessagePluginMissing`](enum.Error.html#variant.DelayedMessagePluginMissing).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use amiquip::{Channel, ExchangeDeclareOptions, ExchangeType, Publish, Result};
+    /// # use std::time::Duration;
+    /// # fn remind(channel: &Channel) -> Result<()> {
+    /// let exchange = channel.exchange_declare(
+    ///     ExchangeType::Direct,
+    ///     "reminders",
+    ///     ExchangeDeclareOptions::delayed(ExchangeType::Direct),
+    /// )?;
+    /// exchange.publish(Publish::new(b"stand up", "me").with_delay(Duration::from_secs(60 * 60)))
+    /// # }
+    /// ```
+    pub fn delayed(routing: ExchangeType) -> ExchangeDeclareOptions {
+        let mut arguments = FieldTable::new();
+        arguments.insert(
+            X_DELAYED_TYPE.to_string(),
+            AmqpValue::LongString(routing.as_ref().to_string()),
+        );
+        ExchangeDeclareOptions {
+            arguments,
+            ..ExchangeDeclareOptions::default()
+        }
+    }
+
+    pub(crate) fn is_delayed(&self) -> bool {
+        self.arguments.contains_key(X_DELAYED_TYPE)
+    }
+
     pub(crate) fn into_declare(
         self,
         type_: ExchangeType,
@@ -66,11 +120,16 @@ impl ExchangeDeclareOptions {
         passive: bool,
         nowait: bool,
     ) -> Declare {
+        let type_ = if self.is_delayed() {
+            DELAYED_MESSAGE_TYPE.to_string()
+        } else {
+            type_.as_ref().to_string()
+        };
         Declare {
             ticket: 0,
             exchange: name,
             passive,
-            type_: type_.as_ref().to_string(),
+            type_,
             durable: self.durable,
             auto_delete: self.auto_delete,
             internal: self.internal,
@@ -80,11 +139,95 @@ impl ExchangeDeclareOptions {
     }
 }
 
+// A server without the delayed-message plugin refuses to declare an exchange of its type with
+// COMMAND_INVALID ("unknown exchange type 'x-delayed-message'"), closing the connection or (on some
+// versions) the channel.
+pub(crate) fn describe_delayed_declare_error(err: Error, exchange: &str) -> Error {
+    match err {
+        Error::ServerClosedChannel {
+            reply_code: AmqpReplyCode::CommandInvalid,
+            message,
+            ..
+        }
+        | Error::ServerClosedConnection {
+            reply_code: AmqpReplyCode::CommandInvalid,
+            message,
+            ..
+        } => Error::DelayedMessagePluginMissing {
+            exchange: exchange.to_string(),
+            message,
+        },
+        err => err,
+    }
+}
+
+/// The body of a [`Publish`](struct.Publish.html). Dereferences to the body's bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublishBody<'a> {
+    /// A borrowed body, copied into the connection's output buffer when published.
+    Borrowed(&'a [u8]),
+
+    /// An owned body (e.g., one replaced by a builder method such as
+    /// [`with_compression`](struct.Publish.html#method.with_compression)), copied into the
+    /// connection's output buffer when published.
+    Owned(Vec<u8>),
+
+    /// A shared body. Publishing it does not copy it: the connection's I/O thread holds a
+    /// reference and writes body frames straight from it, so the same body can be published any
+    /// number of times (e.g., fanned out to many routing keys) for the cost of serializing only
+    /// the method and header frames.
+    Shared(Arc<[u8]>),
+}
+
+impl Deref for PublishBody<'_> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self {
+            PublishBody::Borrowed(body) => body,
+            PublishBody::Owned(body) => body,
+            PublishBody::Shared(body) => body,
+        }
+    }
+}
+
+impl AsRef<[u8]> for PublishBody<'_> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl<'a> From<&'a [u8]> for PublishBody<'a> {
+    #[inline]
+    fn from(body: &'a [u8]) -> Self {
+        PublishBody::Borrowed(body)
+    }
+}
+
+impl From<Vec<u8>> for PublishBody<'_> {
+    #[inline]
+    fn from(body: Vec<u8>) -> Self {
+        PublishBody::Owned(body)
+    }
+}
+
+impl From<Arc<[u8]>> for PublishBody<'_> {
+    #[inline]
+    fn from(body: Arc<[u8]>) -> Self {
+        PublishBody::Shared(body)
+    }
+}
+
 /// Wrapper for a message to be published.
 #[derive(Debug, Clone)]
 pub struct Publish<'a> {
-    /// Body of content to send.
-    pub body: &'a [u8],
+    /// Body of content to send. This may be empty (e.g., for messages that only carry
+    /// properties), in which case only a content header is sent. The body is borrowed unless it
+    /// was created [shared](#method.shared) or a builder method (e.g., `with_compression`) had to
+    /// replace it.
+    pub body: PublishBody<'a>,
 
     /// Routing key.
     pub routing_key: String,
@@ -104,9 +247,9 @@ pub struct Publish<'a> {
 impl<'a> Publish<'a> {
     /// Helper to create a message with the given body and routing key; `mandatory` and
     /// `immediate` will be set to false, and `properties` will be empty.
-    pub fn new<S: Into<String>>(body: &[u8], routing_key: S) -> Publish {
+    pub fn new<S: Into<String>>(body: &[u8], routing_key: S) -> Publish<'_> {
         Publish {
-            body,
+            body: PublishBody::Borrowed(body),
             routing_key: routing_key.into(),
             mandatory: false,
             immediate: false,
@@ -120,15 +263,151 @@ impl<'a> Publish<'a> {
         body: &[u8],
         routing_key: S,
         properties: AmqpProperties,
-    ) -> Publish {
+    ) -> Publish<'_> {
         Publish {
-            body,
+            body: PublishBody::Borrowed(body),
             routing_key: routing_key.into(),
             mandatory: false,
             immediate: false,
             properties,
         }
     }
+
+    /// Helper to create a message with a shared body and the given routing key; `mandatory` and
+    /// `immediate` will be set to false, and `properties` will be empty. Publishing the message
+    /// does not copy the body (see [`PublishBody::Shared`](enum.PublishBody.html#variant.Shared)).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use amiquip::{Exchange, Publish, Result};
+    /// # use std::sync::Arc;
+    /// // Publish the same body to several routing keys without copying it.
+    /// fn fan_out(exchange: &Exchange, body: Vec<u8>, routing_keys: &[&str]) -> Result<()> {
+    ///     let body: Arc<[u8]> = body.into();
+    ///     for routing_key in routing_keys {
+    ///         exchange.publish(Publish::shared(Arc::clone(&body), *routing_key))?;
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn shared<S: Into<String>>(body: Arc<[u8]>, routing_key: S) -> Publish<'static> {
+        Publish::shared_with_properties(body, routing_key, AmqpProperties::default())
+    }
+
+    /// Helper to create a message with a shared body, the given routing key, and properties;
+    /// `mandatory` and `immediate` will be set to false.
+    pub fn shared_with_properties<S: Into<String>>(
+        body: Arc<[u8]>,
+        routing_key: S,
+        properties: AmqpProperties,
+    ) -> Publish<'static> {
+        Publish {
+            body: PublishBody::Shared(body),
+            routing_key: routing_key.into(),
+            mandatory: false,
+            immediate: false,
+            properties,
+        }
+    }
+
+    /// Set the header `name` to `value`, replacing any existing header with that name.
+    pub fn with_header<S: Into<String>, V: HeaderValue>(mut self, name: S, value: V) -> Self {
+        let mut headers = self.properties.headers().clone().unwrap_or_default();
+        headers.insert(name.into(), value.into_amqp_value());
+        self.properties = self.properties.with_headers(headers);
+        self
+    }
+
+    /// Remove the header `name`, if present.
+    pub fn without_header(mut self, name: &str) -> Self {
+        if let Some(mut headers) = self.properties.headers().clone() {
+            headers.remove(name);
+            self.properties = self.properties.with_headers(headers);
+        }
+        self
+    }
+
+    /// Compress the body with `compression` and set the `content_encoding` property to match,
+    /// replacing any existing `content_encoding`. Compression happens here, on the calling
+    /// thread, rather than on the connection's I/O thread.
+    ///
+    /// This method is only available if amiquip is built with the `compression` feature.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.body = PublishBody::Owned(compression.compress(&self.body));
+        self.properties = self
+            .properties
+            .with_content_encoding(compression.content_encoding().to_string());
+        self
+    }
+
+    /// Set the per-message TTL (the `expiration` property), in milliseconds. Same as
+    /// [`with_ttl`](#method.with_ttl).
+    pub fn with_expiration(self, expiration: Duration) -> Self {
+        self.with_ttl(expiration)
+    }
+
+    /// Set the per-message TTL (the `expiration` property) as a string of whole milliseconds,
+    /// the only form the server honors. A message still queued when its TTL runs out is
+    /// discarded, or dead-lettered if its queue has a dead-letter exchange. Publishing fails with
+    /// [`InvalidExpiration`](enum.Error.html#variant.InvalidExpiration) if `ttl` is longer than
+    /// `i64::MAX` milliseconds.
+    ///
+    /// # Example
+    ///
+    /// Retrying failed work after a delay: a "wait" queue with no consumers dead-letters expired
+    /// messages back onto the work queue, so each retry can wait a different amount of time.
+    ///
+    /// ```rust
+    /// # use amiquip::{Channel, Publish, QueueArguments, QueueDeclareOptions, Result};
+    /// # use std::time::Duration;
+    /// # fn retry_later(channel: &Channel, job: &[u8], attempt: u32) -> Result<()> {
+    /// let work = channel.queue_declare("work", QueueDeclareOptions::default())?;
+    /// let wait = channel.queue_declare(
+    ///     "work.wait",
+    ///     QueueDeclareOptions {
+    ///         arguments: QueueArguments::new()
+    ///             .dead_letter_exchange("")
+    ///             .dead_letter_routing_key(work.name())
+    ///             .build()?,
+    ///         ..QueueDeclareOptions::default()
+    ///     },
+    /// )?;
+    ///
+    /// // Back off exponentially: 1s, 2s, 4s, ...
+    /// let backoff = Duration::from_secs(1 << attempt.min(10));
+    /// channel.basic_publish("", Publish::new(job, wait.name()).with_ttl(backoff))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.properties = self.properties.with_expiration_duration(ttl);
+        self
+    }
+
+    /// Set the message's priority (the `priority` property). Only queues declared with an
+    /// `x-max-priority` argument honor it; see
+    /// [`Queue::check_priority`](struct.Queue.html#method.check_priority) to catch priorities a
+    /// queue will ignore.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.properties = self.properties.with_priority(priority);
+        self
+    }
+
+    /// The message's priority, if one has been set.
+    #[inline]
+    pub fn priority(&self) -> Option<u8> {
+        *self.properties.priority()
+    }
+
+    /// Set the `x-delay` header, in milliseconds: how long an exchange declared with
+    /// [`ExchangeDeclareOptions::delayed`](struct.ExchangeDeclareOptions.html#method.delayed)
+    /// holds this message before routing it. Other exchanges ignore the header.
+    pub fn with_delay(self, delay: Duration) -> Self {
+        let millis = i64::try_from(delay.as_millis()).unwrap_or(i64::MAX);
+        self.with_header("x-delay", AmqpValue::LongLongInt(millis))
+    }
 }
 
 /// Handle for a declared AMQP exchange.
@@ -138,14 +417,14 @@ pub struct Exchange<'a> {
 }
 
 impl Exchange<'_> {
-    pub(crate) fn new(channel: &Channel, name: String) -> Exchange {
+    pub(crate) fn new(channel: &Channel, name: String) -> Exchange<'_> {
         Exchange { channel, name }
     }
 
     /// Construct a handle for the direct exchange on the given `channel`. This is an entirely
     /// local operation; the default exchange (named `""`) is guaranteed to exist and does not need
     /// to be declared.
-    pub fn direct(channel: &Channel) -> Exchange {
+    pub fn direct(channel: &Channel) -> Exchange<'_> {
         let name = "".to_string();
         Exchange { channel, name }
     }
@@ -160,6 +439,51 @@ impl Exchange<'_> {
         self.channel.basic_publish(self.name(), publish)
     }
 
+    /// Serialize `value` as JSON and publish it to this exchange with `routing_key`. The
+    /// `content_type` of `properties` is replaced with `application/json`.
+    ///
+    /// This method is only available if amiquip is built with the `serde` feature.
+    #[cfg(feature = "serde")]
+    pub fn publish_json<S, T>(
+        &self,
+        routing_key: S,
+        value: &T,
+        properties: AmqpProperties,
+    ) -> Result<()>
+    where
+        S: Into<String>,
+        T: serde_crate::Serialize + ?Sized,
+    {
+        let body = crate::json::encode(value)?;
+        let properties = properties.with_content_type(crate::json::CONTENT_TYPE.to_string());
+        self.publish(Publish::with_properties(&body, routing_key, properties))
+    }
+
+    /// Publish a message to this exchange whose `len`-byte body is streamed from `reader`; see
+    /// [`Channel::basic_publish_from_reader`](struct.Channel.html#method.basic_publish_from_reader),
+    /// including what happens if `reader` fails partway through.
+    pub fn publish_from_reader<S: Into<String>, R: Read>(
+        &self,
+        routing_key: S,
+        reader: R,
+        len: u64,
+        properties: AmqpProperties,
+    ) -> Result<()> {
+        self.channel.basic_publish_from_reader(
+            self.name.clone(),
+            routing_key.into(),
+            reader,
+            len,
+            properties,
+        )
+    }
+
+    /// Publish a message to this exchange without blocking; see
+    /// [`Channel::try_publish`](struct.Channel.html#method.try_publish).
+    pub fn try_publish(&self, publish: Publish) -> Result<bool> {
+        self.channel.try_publish(self.name(), publish)
+    }
+
     /// Synchronously bind this exchange (as destination) to the `source` exchange with the given
     /// routing key and arguments. Exchange-to-exchange binding is a RabbitMQ extension; you can
     /// examine the connection's [server
@@ -0,0 +1,316 @@
+use crate::errors::*;
+use crate::{AmqpValue, FieldTable};
+use std::convert::TryFrom;
+
+/// Conversion from a borrowed [`AmqpValue`](type.AmqpValue.html), used by
+/// [`FieldTableExt::try_get`](trait.FieldTableExt.html#tymethod.try_get).
+///
+/// Every integer variant converts to any integer type the value fits in, and `Float` and
+/// `Double` both convert to `f64`. Strings of either AMQP type arrive as `LongString`.
+pub trait FromAmqpValue<'a>: Sized {
+    /// What this type is called in
+    /// [`FieldTypeMismatch`](enum.Error.html#variant.FieldTypeMismatch) errors.
+    const EXPECTED: &'static str;
+
+    /// Convert `value`, or return `None` if it is of an incompatible type or out of range.
+    fn from_amqp_value(value: &'a AmqpValue) -> Option<Self>;
+}
+
+fn as_i64(value: &AmqpValue) -> Option<i64> {
+    match *value {
+        AmqpValue::ShortShortInt(n) => Some(i64::from(n)),
+        AmqpValue::ShortShortUInt(n) => Some(i64::from(n)),
+        AmqpValue::ShortInt(n) => Some(i64::from(n)),
+        AmqpValue::ShortUInt(n) => Some(i64::from(n)),
+        AmqpValue::LongInt(n) => Some(i64::from(n)),
+        AmqpValue::LongUInt(n) => Some(i64::from(n)),
+        AmqpValue::LongLongInt(n) => Some(n),
+        _ => None,
+    }
+}
+
+macro_rules! impl_from_amqp_integer {
+    ($($ty:ty,)*) => {
+        $(
+            impl FromAmqpValue<'_> for $ty {
+                const EXPECTED: &'static str = stringify!($ty);
+
+                fn from_amqp_value(value: &AmqpValue) -> Option<$ty> {
+                    as_i64(value).and_then(|n| <$ty>::try_from(n).ok())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_amqp_integer! {
+    i8, u8, i16, u16, i32, u32, i64, u64,
+}
+
+impl FromAmqpValue<'_> for bool {
+    const EXPECTED: &'static str = "bool";
+
+    fn from_amqp_value(value: &AmqpValue) -> Option<bool> {
+        match *value {
+            AmqpValue::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl FromAmqpValue<'_> for f64 {
+    const EXPECTED: &'static str = "f64";
+
+    fn from_amqp_value(value: &AmqpValue) -> Option<f64> {
+        match *value {
+            AmqpValue::Float(f) => Some(f64::from(f)),
+            AmqpValue::Double(d) => Some(d),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromAmqpValue<'a> for &'a str {
+    const EXPECTED: &'static str = "string";
+
+    fn from_amqp_value(value: &'a AmqpValue) -> Option<&'a str> {
+        match value {
+            AmqpValue::LongString(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl FromAmqpValue<'_> for String {
+    const EXPECTED: &'static str = "string";
+
+    fn from_amqp_value(value: &AmqpValue) -> Option<String> {
+        <&str>::from_amqp_value(value).map(str::to_string)
+    }
+}
+
+impl<'a> FromAmqpValue<'a> for &'a FieldTable {
+    const EXPECTED: &'static str = "table";
+
+    fn from_amqp_value(value: &'a AmqpValue) -> Option<&'a FieldTable> {
+        match value {
+            AmqpValue::FieldTable(table) => Some(table),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromAmqpValue<'a> for &'a [AmqpValue] {
+    const EXPECTED: &'static str = "array";
+
+    fn from_amqp_value(value: &'a AmqpValue) -> Option<&'a [AmqpValue]> {
+        match value {
+            AmqpValue::FieldArray(array) => Some(array),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> FromAmqpValue<'a> for &'a [u8] {
+    const EXPECTED: &'static str = "byte array";
+
+    fn from_amqp_value(value: &'a AmqpValue) -> Option<&'a [u8]> {
+        match value {
+            AmqpValue::ByteArray(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+// Integers are shown with their value, so an out-of-range error says what was out of range.
+fn describe(value: &AmqpValue) -> String {
+    match as_i64(value) {
+        Some(n) => format!("{:?} {}", value.get_type(), n),
+        None => format!("{:?}", value.get_type()),
+    }
+}
+
+/// Typed getters for a [`FieldTable`](type.FieldTable.html), such as message headers or
+/// queue arguments, so reading a value does not need a match on every
+/// [`AmqpValue`](type.AmqpValue.html) variant it might have been sent as.
+///
+/// The `get_*` methods return `None` if the key is missing or its value cannot be converted;
+/// [`try_get`](#tymethod.try_get) tells the two apart.
+///
+/// # Example
+///
+/// ```rust
+/// # use amiquip::{AmqpProperties, FieldTableExt, Result};
+/// # fn log_trace(properties: &AmqpProperties) -> Result<()> {
+/// if let Some(headers) = properties.headers() {
+///     let trace_id = headers.get_str("trace-id").unwrap_or("-");
+///     let attempt: u32 = headers.try_get("attempt")?.unwrap_or(0);
+///     println!("trace {} attempt {}", trace_id, attempt);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub trait FieldTableExt {
+    /// The string value of `key`.
+    fn get_str(&self, key: &str) -> Option<&str>;
+
+    /// The value of `key`, which may be of any integer type.
+    fn get_i64(&self, key: &str) -> Option<i64>;
+
+    /// The value of `key`, which may be a `Float` or `Double`.
+    fn get_f64(&self, key: &str) -> Option<f64>;
+
+    /// The boolean value of `key`.
+    fn get_bool(&self, key: &str) -> Option<bool>;
+
+    /// The nested table value of `key`.
+    fn get_table(&self, key: &str) -> Option<&FieldTable>;
+
+    /// The array value of `key`. Its elements can be converted with
+    /// [`FromAmqpValue`](trait.FromAmqpValue.html).
+    fn get_array(&self, key: &str) -> Option<&[AmqpValue]>;
+
+    /// The value of `key` converted to `T`, or `None` if the key is missing. Fails with
+    /// [`FieldTypeMismatch`](enum.Error.html#variant.FieldTypeMismatch), naming the key and the
+    /// type it actually has, if the value cannot be converted.
+    fn try_get<'a, T: FromAmqpValue<'a>>(&'a self, key: &str) -> Result<Option<T>>;
+}
+
+impl FieldTableExt for FieldTable {
+    fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(FromAmqpValue::from_amqp_value)
+    }
+
+    fn get_i64(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(FromAmqpValue::from_amqp_value)
+    }
+
+    fn get_f64(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(FromAmqpValue::from_amqp_value)
+    }
+
+    fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(FromAmqpValue::from_amqp_value)
+    }
+
+    fn get_table(&self, key: &str) -> Option<&FieldTable> {
+        self.get(key).and_then(FromAmqpValue::from_amqp_value)
+    }
+
+    fn get_array(&self, key: &str) -> Option<&[AmqpValue]> {
+        self.get(key).and_then(FromAmqpValue::from_amqp_value)
+    }
+
+    fn try_get<'a, T: FromAmqpValue<'a>>(&'a self, key: &str) -> Result<Option<T>> {
+        let value = match self.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match T::from_amqp_value(value) {
+            Some(converted) => Ok(Some(converted)),
+            None => FieldTypeMismatchSnafu {
+                key,
+                expected: T::EXPECTED,
+                actual: describe(value),
+            }
+            .fail(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use amq_protocol::types::generation::gen_field_table;
+    use amq_protocol::types::parsing::parse_field_table;
+
+    // A headers table as encoded by the RabbitMQ Java client (`ValueWriter.writeTable`) from:
+    //   attempt: Integer 3, big: Long 5000000000, flag: Boolean true, level: Byte -2,
+    //   nested: Map {region: "eu-west-1"}, payload: byte[] {1, 2, 3}, ratio: Double 0.5,
+    //   retries: Short 2, tags: List ["a", Integer 7], trace-id: String "abc-123",
+    //   weight: Float 1.5
+    const JAVA_HEADERS: &[u8] = include_bytes!("integration_tests/testdata/java_headers.bin");
+
+    fn java_headers() -> FieldTable {
+        let (rest, table) = parse_field_table(JAVA_HEADERS).unwrap();
+        assert!(rest.is_empty());
+        table
+    }
+
+    #[test]
+    fn reads_java_client_table() {
+        let headers = java_headers();
+        assert_eq!(headers.get_i64("attempt"), Some(3));
+        assert_eq!(headers.get_i64("big"), Some(5_000_000_000));
+        assert_eq!(headers.get_bool("flag"), Some(true));
+        assert_eq!(headers.get_i64("level"), Some(-2));
+        assert_eq!(
+            headers
+                .get_table("nested")
+                .and_then(|nested| nested.get_str("region")),
+            Some("eu-west-1")
+        );
+        assert_eq!(
+            headers.try_get::<&[u8]>("payload").unwrap(),
+            Some(&[1, 2, 3][..])
+        );
+        assert_eq!(headers.get_f64("ratio"), Some(0.5));
+        assert_eq!(headers.get_i64("retries"), Some(2));
+        let tags = headers.get_array("tags").unwrap();
+        assert_eq!(<&str>::from_amqp_value(&tags[0]), Some("a"));
+        assert_eq!(u8::from_amqp_value(&tags[1]), Some(7));
+        assert_eq!(headers.get_str("trace-id"), Some("abc-123"));
+        assert_eq!(headers.get_f64("weight"), Some(1.5));
+        assert_eq!(headers.get_str("missing"), None);
+    }
+
+    #[test]
+    fn java_client_table_round_trips() {
+        let headers = java_headers();
+        let mut buf = vec![0; JAVA_HEADERS.len()];
+        let (_, len) = gen_field_table((&mut buf, 0), &headers).unwrap();
+        assert_eq!(&buf[..len], JAVA_HEADERS);
+    }
+
+    #[test]
+    fn integers_coerce_when_in_range() {
+        let mut table = FieldTable::new();
+        table.insert("u32".to_string(), AmqpValue::LongUInt(u32::MAX));
+        table.insert("negative".to_string(), AmqpValue::ShortInt(-5));
+        assert_eq!(table.get_i64("u32"), Some(i64::from(u32::MAX)));
+        assert_eq!(table.try_get::<u32>("u32").unwrap(), Some(u32::MAX));
+        assert_eq!(table.try_get::<i16>("negative").unwrap(), Some(-5));
+        assert_eq!(table.try_get::<u64>("missing").unwrap(), None);
+
+        match table.try_get::<i32>("u32") {
+            Err(Error::FieldTypeMismatch {
+                key,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(key, "u32");
+                assert_eq!(expected, "i32");
+                assert_eq!(actual, format!("LongUInt {}", u32::MAX));
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(table.try_get::<u64>("negative").is_err());
+    }
+
+    #[test]
+    fn mismatch_names_key_and_actual_type() {
+        let headers = java_headers();
+        assert_eq!(headers.get_bool("trace-id"), None);
+        let err = headers.try_get::<bool>("trace-id").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "field \"trace-id\" cannot be read as bool: it is LongString"
+        );
+        let err = headers.try_get::<&FieldTable>("tags").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "field \"tags\" cannot be read as table: it is FieldArray"
+        );
+    }
+}
@@ -1,26 +1,138 @@
 use crate::errors::*;
+use crate::frame_tap::{FrameDirection, FrameTapHandle};
+use crate::logging::{enter_span, trace};
 use amq_protocol::frame::{parse_frame, AMQPFrame};
 use amq_protocol::types::parsing::parse_long_uint;
 use bytes::Buf;
-use input_buffer::{InputBuffer, MIN_READ};
-use log::trace;
+use input_buffer::InputBuffer;
 use snafu::ResultExt;
+use std::collections::VecDeque;
+use std::fmt::Write;
 use std::io;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-pub struct FrameBuffer(Inner<AmqpFrameKind>);
+/// Counts of frames received from the server, broken down by frame type.
+///
+/// Returned by [`Connection::frame_stats`](struct.Connection.html#method.frame_stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Number of method frames received.
+    pub method: u64,
+
+    /// Number of content header frames received.
+    pub header: u64,
+
+    /// Number of content body frames received.
+    pub body: u64,
+
+    /// Number of heartbeat frames received.
+    pub heartbeat: u64,
+
+    /// Total number of bytes read from the underlying stream.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FrameCounters {
+    method: AtomicU64,
+    header: AtomicU64,
+    body: AtomicU64,
+    heartbeat: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl FrameCounters {
+    fn record_frame(&self, frame: &AMQPFrame) {
+        let counter = match frame {
+            AMQPFrame::Method(_, _) => &self.method,
+            AMQPFrame::Header(_, _, _) => &self.header,
+            AMQPFrame::Body(_, _) => &self.body,
+            AMQPFrame::Heartbeat(_) => &self.heartbeat,
+            AMQPFrame::ProtocolHeader => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> FrameStats {
+        FrameStats {
+            method: self.method.load(Ordering::Relaxed),
+            header: self.header.load(Ordering::Relaxed),
+            body: self.body.load(Ordering::Relaxed),
+            heartbeat: self.heartbeat.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct FrameBuffer {
+    inner: Inner<AmqpFrameKind>,
+    counters: Arc<FrameCounters>,
+    tap: Option<FrameTapHandle>,
+}
 
 impl FrameBuffer {
-    pub fn new() -> FrameBuffer {
-        FrameBuffer(Inner::new())
+    // If `dump_window` is Some(n), malformed frame errors will include a hex dump of (at most) n
+    // bytes around the failure. See `ConnectionTuning` for `read_size` and `shrink_above`.
+    pub fn new(dump_window: Option<usize>, read_size: usize, shrink_above: usize) -> FrameBuffer {
+        FrameBuffer {
+            inner: Inner::with_options(dump_window, read_size, shrink_above),
+            counters: Arc::default(),
+            tap: None,
+        }
+    }
+
+    pub fn set_tap(&mut self, tap: Option<FrameTapHandle>) {
+        self.tap = tap;
+    }
+
+    // Reject frames larger than `frame_max` (as negotiated during Tune; 0 means no limit) before
+    // buffering them.
+    pub fn set_frame_max(&mut self, frame_max: u32) {
+        self.inner.frame_max = match frame_max {
+            0 => None,
+            n => Some(n as usize),
+        };
     }
 
-    pub fn read_from<S, F>(&mut self, stream: &mut S, handler: F) -> Result<usize>
+    pub fn counters(&self) -> Arc<FrameCounters> {
+        Arc::clone(&self.counters)
+    }
+
+    // Stops early (without reading more from `stream`) if `is_paused` returns true; it is checked
+    // before each frame.
+    pub fn read_from<S, P, F>(
+        &mut self,
+        stream: &mut S,
+        is_paused: P,
+        mut handler: F,
+    ) -> Result<usize>
     where
         S: io::Read,
+        P: Fn() -> bool,
         F: FnMut(AMQPFrame) -> Result<()>,
     {
-        self.0.read_from(stream, handler)
+        let counters = &self.counters;
+        let tap = &self.tap;
+        let n = self.inner.read_frames(
+            stream,
+            is_paused,
+            |frame| {
+                counters.record_frame(&frame);
+                if let Some(tap) = tap {
+                    tap.tap_frame(FrameDirection::Rx, &frame);
+                }
+                handler(frame)
+            },
+            |bytes| {
+                if let Some(tap) = tap {
+                    tap.tap_bytes(FrameDirection::Rx, bytes);
+                }
+            },
+        )?;
+        counters.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
     }
 }
 
@@ -74,43 +186,195 @@ impl FrameKind for AmqpFrameKind {
     }
 }
 
+// Ring of the most recently parsed bytes, kept so we can show what preceded a malformed frame.
+struct RecentBytes {
+    buf: VecDeque<u8>,
+    window: usize,
+}
+
+impl RecentBytes {
+    fn new(window: usize) -> RecentBytes {
+        RecentBytes {
+            buf: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(self.window)..];
+        let overflow = (self.buf.len() + bytes.len()).saturating_sub(self.window);
+        self.buf.drain(..overflow);
+        self.buf.extend(bytes);
+    }
+
+    // Build a dump of at most `window` bytes: as much of the malformed frame as fits, preceded by
+    // as much recent context as fits in what's left.
+    fn dump(&self, frame: &[u8]) -> String {
+        let frame = &frame[..usize::min(frame.len(), self.window)];
+        let context_len = usize::min(self.buf.len(), self.window - frame.len());
+        let mut bytes = Vec::with_capacity(context_len + frame.len());
+        bytes.extend(self.buf.iter().skip(self.buf.len() - context_len));
+        bytes.extend_from_slice(frame);
+        hex_dump(&bytes, context_len)
+    }
+}
+
+// Format `bytes` as a classic hex dump. Offsets are relative to the start of the malformed frame
+// (which begins at `frame_start`), so preceding context has negative offsets.
+fn hex_dump(bytes: &[u8], frame_start: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let offset = (i * 16) as isize - frame_start as isize;
+        let _ = write!(out, "\n{:+06}  ", offset);
+        for j in 0..16 {
+            match line.get(j) {
+                Some(b) => {
+                    let _ = write!(out, "{:02x} ", b);
+                }
+                None => out.push_str("   "),
+            }
+        }
+        out.push_str(" |");
+        out.extend(line.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        out.push('|');
+    }
+    out
+}
+
 struct Inner<Kind: FrameKind> {
+    // Reused for every read; grows to fit oversized frames and is shrunk back to `read_size`
+    // once its capacity exceeds `shrink_above` and what's left in it fits in `read_size`.
     buf: InputBuffer,
+    read_size: usize,
+    shrink_above: usize,
+    // Largest frame (including header and frame-end) we'll accept; checked as soon as a frame's
+    // size is known, so an oversized frame never causes us to grow `buf` to fit it.
+    frame_max: Option<usize>,
+    recent: Option<RecentBytes>,
     phantom: PhantomData<Kind>,
 }
 
 impl<Kind: FrameKind> Inner<Kind> {
+    #[cfg(test)]
     fn new() -> Inner<Kind> {
+        Self::with_dump_window(None)
+    }
+
+    #[cfg(test)]
+    fn with_dump_window(dump_window: Option<usize>) -> Inner<Kind> {
+        Self::with_options(dump_window, input_buffer::MIN_READ, usize::MAX)
+    }
+
+    fn with_options(
+        dump_window: Option<usize>,
+        read_size: usize,
+        shrink_above: usize,
+    ) -> Inner<Kind> {
+        let read_size = usize::max(read_size, 1);
         Inner {
-            buf: InputBuffer::new(),
+            buf: InputBuffer::with_capacity(read_size),
+            read_size,
+            shrink_above: usize::max(shrink_above, read_size),
+            frame_max: None,
+            recent: dump_window.map(RecentBytes::new),
             phantom: PhantomData,
         }
     }
 
-    fn read_from<S, F>(&mut self, stream: &mut S, mut handler: F) -> Result<usize>
+    fn capacity(&self) -> usize {
+        self.buf.as_cursor().get_ref().capacity()
+    }
+
+    // Give back the memory an oversized frame left behind. Only done once the unconsumed data
+    // fits in `read_size`, so a burst of buffered frames isn't copied down once per frame.
+    fn maybe_shrink(&mut self) {
+        if self.capacity() > self.shrink_above && self.buf.remaining() <= self.read_size {
+            self.buf.remove_garbage();
+            self.buf.as_cursor_mut().get_mut().shrink_to(self.read_size);
+            trace!("shrunk read buffer to {} bytes", self.capacity());
+        }
+    }
+
+    #[cfg(test)]
+    fn read_from<S, F>(&mut self, stream: &mut S, handler: F) -> Result<usize>
     where
         S: io::Read,
         F: FnMut(Kind::Frame) -> Result<()>,
+    {
+        self.read_frames(stream, || false, handler, |_| ())
+    }
+
+    // Like `read_from`, but also passes the bytes of a frame that fails to parse to `malformed`
+    // before returning the error, and stops early once `is_paused` returns true.
+    fn read_frames<S, P, F, M>(
+        &mut self,
+        stream: &mut S,
+        is_paused: P,
+        mut handler: F,
+        mut malformed: M,
+    ) -> Result<usize>
+    where
+        S: io::Read,
+        P: Fn() -> bool,
+        F: FnMut(Kind::Frame) -> Result<()>,
+        M: FnMut(&[u8]),
     {
         let mut bytes_read = 0;
 
         loop {
+            if is_paused() {
+                return Ok(bytes_read);
+            }
             let bytes = self.buf.chunk();
             let frame_size = Kind::parse_size(bytes);
-            let mut reserve = MIN_READ;
+            let mut reserve = self.read_size;
 
             // if we already have enough data buffered to read a frame, do that before
             // trying to read from the stream.
             if let Some(frame_size) = frame_size {
+                if let Some(max) = self.frame_max {
+                    if frame_size > max {
+                        return ReceivedFrameTooLargeSnafu {
+                            size: frame_size,
+                            max,
+                        }
+                        .fail();
+                    }
+                }
                 if bytes.len() >= frame_size {
-                    let frame = Kind::parse_frame(&bytes[..frame_size])?;
+                    let frame_bytes = &bytes[..frame_size];
+                    let parsed = Kind::parse_frame(frame_bytes);
+                    if parsed.is_err() {
+                        malformed(frame_bytes);
+                    }
+                    let frame = match (parsed, &self.recent) {
+                        (Ok(frame), _) => frame,
+                        (Err(Error::MalformedFrame), Some(recent)) => {
+                            return MalformedFrameDumpSnafu {
+                                dump: recent.dump(frame_bytes),
+                            }
+                            .fail();
+                        }
+                        (Err(err), _) => return Err(err),
+                    };
+                    if let Some(recent) = &mut self.recent {
+                        recent.push(frame_bytes);
+                    }
+                    enter_span!(TRACE, "frame", frame_size);
                     handler(frame)?;
                     self.buf.advance(frame_size);
+                    self.maybe_shrink();
                     continue;
                 } else {
                     // not enough data, but we know how much we need; try to read that
-                    // much from the stream if it's larger than MIN_READ
-                    reserve = usize::max(MIN_READ, frame_size);
+                    // much from the stream if it's larger than read_size
+                    reserve = usize::max(self.read_size, frame_size);
                 }
             }
 
@@ -123,6 +387,8 @@ impl<Kind: FrameKind> Inner<Kind> {
                 }
                 Err(err) => match err.kind() {
                     io::ErrorKind::WouldBlock => return Ok(bytes_read),
+                    // A signal arrived before we read anything; nothing is lost by trying again.
+                    io::ErrorKind::Interrupted => (),
                     _ => return Err(err).context(IoErrorReadingSocketSnafu),
                 },
             }
@@ -132,9 +398,10 @@ impl<Kind: FrameKind> Inner<Kind> {
 
 #[cfg(test)]
 mod tests {
-    use super::{FrameKind, Inner, Result};
+    use super::{AmqpFrameKind, FrameKind, Inner, RecentBytes, Result};
     use crate::errors::*;
     use mockstream::FailingMockStream;
+    use std::cell::Cell;
     use std::io::{self, Cursor, Read};
 
     struct FakeFrameKind {}
@@ -272,6 +539,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn pause_stops_before_next_frame() {
+        let frame0 = b"a\x04aa";
+        let frame1 = b"b\x04bb";
+        let mut c = Cursor::new(b"a\x04aab\x04bb")
+            .chain(would_block())
+            .chain(would_block());
+
+        let paused = Cell::new(false);
+        let mut got = Vec::new();
+        let mut buf = make_buffer();
+        let n = buf
+            .read_frames(
+                &mut c,
+                || paused.get(),
+                |f| {
+                    got.push(f);
+                    paused.set(true);
+                    Ok(())
+                },
+                |_| (),
+            )
+            .unwrap();
+        assert_eq!(n, 8);
+        assert_eq!(got, vec![Vec::from(&frame0[..])]);
+
+        // Nothing more is read while paused, even though a whole frame is buffered.
+        let n = buf
+            .read_frames(&mut c, || true, |_| panic!("paused"), |_| ())
+            .unwrap();
+        assert_eq!(n, 0);
+
+        let n = buf
+            .read_from(&mut c, |f| {
+                got.push(f);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(n, 0);
+        assert_eq!(got, vec![Vec::from(&frame0[..]), Vec::from(&frame1[..])]);
+    }
+
     #[test]
     fn parse_fail() {
         let mut c = Cursor::new(b"x\x06fail").chain(would_block());
@@ -285,6 +594,128 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_fail_with_dump() {
+        let mut c = Cursor::new(b"a\x04aab\x04bbx\x06fail").chain(would_block());
+
+        let mut buf = Inner::<FakeFrameKind>::with_dump_window(Some(512));
+        let res = buf.read_from(&mut c, |_| Ok(()));
+        let dump = match res.unwrap_err() {
+            Error::MalformedFrameDump { dump } => dump,
+            err => panic!("unexpected error {}", err),
+        };
+        // context from the two good frames precedes the malformed one at offset 0
+        assert_eq!(
+            dump,
+            "\n-00008  61 04 61 61 62 04 62 62 78 06 66 61 69 6c        \
+             |a.aab.bbx.fail|"
+        );
+    }
+
+    #[test]
+    fn parse_fail_dump_is_bounded() {
+        let mut frames = Vec::new();
+        for _ in 0..20 {
+            frames.extend_from_slice(b"a\x04aa");
+        }
+        frames.extend_from_slice(b"x\x06fail");
+        let mut c = Cursor::new(frames).chain(would_block());
+
+        let mut buf = Inner::<FakeFrameKind>::with_dump_window(Some(10));
+        let res = buf.read_from(&mut c, |_| Ok(()));
+        let dump = match res.unwrap_err() {
+            Error::MalformedFrameDump { dump } => dump,
+            err => panic!("unexpected error {}", err),
+        };
+        // 4 bytes of context + the 6 byte malformed frame
+        assert_eq!(
+            dump,
+            "\n-00004  61 04 61 61 78 06 66 61 69 6c                    \
+             |a.aax.fail|"
+        );
+    }
+
+    #[test]
+    fn recent_bytes_truncates_large_frame() {
+        let mut recent = RecentBytes::new(4);
+        recent.push(b"abcdef");
+        assert_eq!(recent.buf, b"cdef".to_vec());
+        recent.push(b"gh");
+        assert_eq!(recent.buf, b"efgh".to_vec());
+        assert_eq!(
+            recent.dump(b"0123456789"),
+            "\n+00000  30 31 32 33                                      |0123|"
+        );
+    }
+
+    #[test]
+    fn buffer_shrinks_after_oversized_frame() {
+        let mut huge = vec![b'h'; 200];
+        huge[1] = 200;
+        let mut c = Cursor::new(b"a\x04aa".to_vec())
+            .chain(Cursor::new(huge.clone()))
+            .chain(would_block())
+            .chain(Cursor::new(b"b\x04bbc\x04cc".to_vec()))
+            .chain(would_block());
+
+        let mut got = Vec::new();
+        let mut buf = Inner::<FakeFrameKind>::with_options(None, 8, 64);
+        let initial_capacity = buf.capacity();
+        assert!(initial_capacity < 64);
+
+        buf.read_from(&mut c, |f| {
+            got.push(f);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(got, vec![b"a\x04aa".to_vec(), huge]);
+        assert!(buf.capacity() <= 64, "capacity {}", buf.capacity());
+
+        buf.read_from(&mut c, |f| {
+            got.push(f);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(got[2..], [b"b\x04bb".to_vec(), b"c\x04cc".to_vec()]);
+        assert!(buf.capacity() <= 64, "capacity {}", buf.capacity());
+    }
+
+    #[test]
+    fn frame_larger_than_frame_max() {
+        let mut c = Cursor::new(b"a\x04aab\x09").chain(would_block());
+
+        let mut got = Vec::new();
+        let mut buf = make_buffer();
+        buf.frame_max = Some(8);
+        let res = buf.read_from(&mut c, |f| {
+            got.push(f);
+            Ok(())
+        });
+        assert_eq!(got, vec![b"a\x04aa".to_vec()]);
+        match res.unwrap_err() {
+            Error::ReceivedFrameTooLarge { size: 9, max: 8 } => (),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn oversized_amqp_frame_header_is_not_allocated() {
+        // method frame on channel 1 declaring a ~4GB payload; nothing after the header arrives
+        let header = b"\x01\x00\x01\xff\xff\xff\xf0";
+        let mut c = Cursor::new(header).chain(would_block());
+
+        let mut buf = Inner::<AmqpFrameKind>::with_options(None, 64, 64);
+        buf.frame_max = Some(131_072);
+        let res = buf.read_from(&mut c, |_| panic!("should not be called"));
+        match res.unwrap_err() {
+            Error::ReceivedFrameTooLarge { size, max: 131_072 } => {
+                assert_eq!(size, 0xffff_fff0 + 8)
+            }
+            err => panic!("unexpected error {}", err),
+        }
+        assert!(buf.capacity() <= 64, "capacity {}", buf.capacity());
+    }
+
     #[test]
     fn callback_fail() {
         let mut c = Cursor::new(b"a\x04aa").chain(would_block());
@@ -312,6 +743,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn interrupted_read_is_retried() {
+        let mut c = Cursor::new(b"a\x04")
+            .chain(FailingMockStream::new(io::ErrorKind::Interrupted, "", 1))
+            .chain(Cursor::new(b"aa"))
+            .chain(would_block());
+
+        let mut got = None;
+        let mut buf = make_buffer();
+        let n = buf
+            .read_from(&mut c, |f| {
+                got = Some(f);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(n, 4);
+        assert_eq!(got, Some(b"a\x04aa".to_vec()));
+    }
+
     #[test]
     fn io_fail() {
         let mut c = Cursor::new(b"a\x04a").chain(FailingMockStream::new(
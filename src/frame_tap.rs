@@ -0,0 +1,112 @@
+use crate::logging::error;
+use amq_protocol::frame::AMQPFrame;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+
+/// The direction a frame seen by a frame tap was travelling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameDirection {
+    /// Received from the server.
+    Rx,
+
+    /// Sent to the server.
+    Tx,
+}
+
+/// A frame seen by a frame tap.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TappedFrame {
+    /// A frame that was parsed successfully.
+    Parsed(AMQPFrame),
+
+    /// The bytes of a frame that could not be parsed. A received frame that fails to parse is
+    /// reported this way just before the connection fails with
+    /// [`MalformedFrame`](enum.Error.html#variant.MalformedFrame) (or
+    /// [`MalformedFrameDump`](enum.Error.html#variant.MalformedFrameDump)).
+    Raw(Vec<u8>),
+}
+
+/// A frame crossing the wire, passed to the tap set by
+/// [`ConnectionOptions::frame_tap`](struct.ConnectionOptions.html#method.frame_tap).
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameTapEvent {
+    /// Whether the frame was received or sent.
+    pub direction: FrameDirection,
+
+    /// The channel the frame was on (0 for connection-level frames, including heartbeats and the
+    /// protocol header).
+    pub channel_id: u16,
+
+    /// The frame itself.
+    pub frame: TappedFrame,
+}
+
+// Shared, comparable handle to a user's frame tap, so ConnectionOptions can stay Clone + Debug +
+// PartialEq. The tap is only `Send`, so it sits behind a mutex; only the I/O thread ever calls it,
+// so the lock is never contended.
+#[derive(Clone)]
+pub(crate) struct FrameTapHandle(Arc<Mutex<FrameTap>>);
+
+type FrameTap = Box<dyn Fn(FrameTapEvent) + Send>;
+
+impl FrameTapHandle {
+    pub(crate) fn new(tap: FrameTap) -> FrameTapHandle {
+        FrameTapHandle(Arc::new(Mutex::new(tap)))
+    }
+
+    pub(crate) fn tap(&self, direction: FrameDirection, channel_id: u16, frame: TappedFrame) {
+        let event = FrameTapEvent {
+            direction,
+            channel_id,
+            frame,
+        };
+        let tap = match self.0.lock() {
+            Ok(tap) => tap,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if panic::catch_unwind(AssertUnwindSafe(|| tap(event))).is_err() {
+            error!("frame tap panicked");
+        }
+    }
+
+    pub(crate) fn tap_frame(&self, direction: FrameDirection, frame: &AMQPFrame) {
+        let channel_id = match frame {
+            AMQPFrame::Method(channel_id, _)
+            | AMQPFrame::Header(channel_id, _, _)
+            | AMQPFrame::Body(channel_id, _)
+            | AMQPFrame::Heartbeat(channel_id) => *channel_id,
+            AMQPFrame::ProtocolHeader => 0,
+        };
+        self.tap(direction, channel_id, TappedFrame::Parsed(frame.clone()));
+    }
+
+    // Reports the frame in `bytes`, a single whole frame (or the protocol header).
+    pub(crate) fn tap_bytes(&self, direction: FrameDirection, bytes: &[u8]) {
+        if bytes.starts_with(b"AMQP") {
+            self.tap(direction, 0, TappedFrame::Parsed(AMQPFrame::ProtocolHeader));
+            return;
+        }
+        let channel_id = match bytes {
+            [_, hi, lo, ..] => u16::from_be_bytes([*hi, *lo]),
+            _ => 0,
+        };
+        let frame = match amq_protocol::frame::parse_frame(bytes) {
+            Ok((&[], frame)) => TappedFrame::Parsed(frame),
+            _ => TappedFrame::Raw(bytes.to_vec()),
+        };
+        self.tap(direction, channel_id, frame);
+    }
+}
+
+impl fmt::Debug for FrameTapHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FrameTap {{ .. }}")
+    }
+}
+
+impl PartialEq for FrameTapHandle {
+    fn eq(&self, other: &FrameTapHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{AmqpProperties, AmqpValue, FieldTable};
+
+/// Values that can be used as message headers, and how each is encoded.
+///
+/// A headers exchange only matches a message header against a binding header of the same type
+/// class (e.g., an integer header never matches a string header, even if they read the same), so
+/// [`HeaderMatch`](struct.HeaderMatch.html) encodes binding and message headers through this
+/// trait to keep them consistent.
+pub trait HeaderValue {
+    /// Convert `self` into the AMQP value sent to the server.
+    fn into_amqp_value(self) -> AmqpValue;
+}
+
+macro_rules! impl_header_value {
+    ($($ty:ty => $variant:ident,)*) => {
+        $(
+            impl HeaderValue for $ty {
+                fn into_amqp_value(self) -> AmqpValue {
+                    AmqpValue::$variant(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_header_value! {
+    bool => Boolean,
+    i8 => ShortShortInt,
+    u8 => ShortShortUInt,
+    i16 => ShortInt,
+    u16 => ShortUInt,
+    i32 => LongInt,
+    u32 => LongUInt,
+    i64 => LongLongInt,
+    f32 => Float,
+    f64 => Double,
+    String => LongString,
+}
+
+impl HeaderValue for &str {
+    fn into_amqp_value(self) -> AmqpValue {
+        AmqpValue::LongString(self.to_string())
+    }
+}
+
+impl HeaderValue for AmqpValue {
+    fn into_amqp_value(self) -> AmqpValue {
+        self
+    }
+}
+
+/// Builder for binding to a headers exchange.
+///
+/// [`arguments`](#method.arguments) produces the binding arguments (the match headers plus
+/// `x-match`) to pass to [`Queue::bind`](struct.Queue.html#method.bind) or
+/// [`Exchange::bind_to_source`](struct.Exchange.html#method.bind_to_source), and
+/// [`apply_to`](#method.apply_to) sets the same headers, encoded the same way, on the properties
+/// of a message to publish.
+///
+/// # Example
+///
+/// ```rust
+/// # use amiquip::{AmqpProperties, Exchange, HeaderMatch, Publish, Queue, Result};
+/// # fn bind_and_publish(queue: &Queue, exchange: &Exchange) -> Result<()> {
+/// let pdfs = HeaderMatch::all().header("format", "pdf").header("size", 42i32);
+/// queue.bind(exchange, "", pdfs.arguments())?;
+///
+/// let properties = pdfs.apply_to(AmqpProperties::default());
+/// exchange.publish(Publish::with_properties(b"...", "", properties))?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderMatch {
+    x_match: &'static str,
+    headers: FieldTable,
+}
+
+impl HeaderMatch {
+    /// Match messages that have all of the headers (`x-match: all`).
+    pub fn all() -> Self {
+        HeaderMatch {
+            x_match: "all",
+            headers: FieldTable::new(),
+        }
+    }
+
+    /// Match messages that have any of the headers (`x-match: any`).
+    pub fn any() -> Self {
+        HeaderMatch {
+            x_match: "any",
+            headers: FieldTable::new(),
+        }
+    }
+
+    /// Add a header to match.
+    pub fn header<S: Into<String>, V: HeaderValue>(mut self, name: S, value: V) -> Self {
+        self.headers.insert(name.into(), value.into_amqp_value());
+        self
+    }
+
+    /// The headers to match, without `x-match`.
+    pub fn headers(&self) -> &FieldTable {
+        &self.headers
+    }
+
+    /// The binding arguments: the headers to match plus `x-match`.
+    pub fn arguments(&self) -> FieldTable {
+        let mut arguments = self.headers.clone();
+        arguments.insert(
+            "x-match".to_string(),
+            AmqpValue::LongString(self.x_match.to_string()),
+        );
+        arguments
+    }
+
+    /// Add this match's headers to `properties`, replacing any existing headers with the same
+    /// names and keeping the rest.
+    pub fn apply_to(&self, properties: AmqpProperties) -> AmqpProperties {
+        let mut headers = properties.headers().clone().unwrap_or_default();
+        headers.extend(
+            self.headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+        properties.with_headers(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arguments_include_x_match_and_typed_headers() {
+        let all = HeaderMatch::all()
+            .header("format", "pdf")
+            .header("size", 42i32)
+            .header("big", 42i64)
+            .header("urgent", true);
+        let arguments = all.arguments();
+        assert_eq!(
+            arguments.get("x-match"),
+            Some(&AmqpValue::LongString("all".to_string()))
+        );
+        assert_eq!(
+            arguments.get("format"),
+            Some(&AmqpValue::LongString("pdf".to_string()))
+        );
+        assert_eq!(arguments.get("size"), Some(&AmqpValue::LongInt(42)));
+        assert_eq!(arguments.get("big"), Some(&AmqpValue::LongLongInt(42)));
+        assert_eq!(arguments.get("urgent"), Some(&AmqpValue::Boolean(true)));
+        assert_eq!(arguments.len(), 5);
+        assert!(!all.headers().contains_key("x-match"));
+
+        assert_eq!(
+            HeaderMatch::any().arguments().get("x-match"),
+            Some(&AmqpValue::LongString("any".to_string()))
+        );
+    }
+
+    #[test]
+    fn apply_to_merges_headers() {
+        let mut existing = FieldTable::new();
+        existing.insert("size".to_string(), AmqpValue::LongString("42".to_string()));
+        existing.insert("trace".to_string(), AmqpValue::Boolean(true));
+        let properties = AmqpProperties::default()
+            .with_headers(existing)
+            .with_priority(3);
+
+        let properties = HeaderMatch::any()
+            .header("size", 42i32)
+            .apply_to(properties);
+        let headers = properties.headers().as_ref().unwrap();
+        assert_eq!(headers.get("size"), Some(&AmqpValue::LongInt(42)));
+        assert_eq!(headers.get("trace"), Some(&AmqpValue::Boolean(true)));
+        assert!(!headers.contains_key("x-match"));
+        assert_eq!(properties.priority(), &Some(3));
+    }
+}
@@ -1,11 +1,13 @@
-use log::trace;
-use mio_extras::timer::{Timeout, Timer};
+use crate::logging::trace;
+use crate::timer::{Timeout, Timer};
 use std::fmt::Debug;
 use std::time::{Duration, Instant};
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum HeartbeatState {
     StillRunning,
+    // This many intervals in a row have passed without activity, but fewer than the limit.
+    Missed(u32),
     Expired,
 }
 
@@ -15,14 +17,28 @@ pub struct Heartbeat<T: Copy + Debug> {
     last: Instant,
     timeout: Timeout,
     interval: Duration,
+    missed_limit: u32,
+    missed: u32,
 }
 
 impl<T: Copy + Debug> Heartbeat<T> {
     pub fn start(val: T, interval: Duration, timer: &mut Timer<T>) -> Heartbeat<T> {
+        Self::start_with_limit(val, interval, 1, timer)
+    }
+
+    // Expires once `missed_limit` intervals in a row pass without activity, reporting each
+    // missed interval before that.
+    pub fn start_with_limit(
+        val: T,
+        interval: Duration,
+        missed_limit: u32,
+        timer: &mut Timer<T>,
+    ) -> Heartbeat<T> {
         assert!(
             interval > Duration::from_millis(0),
             "timer interval cannot be 0"
         );
+        assert!(missed_limit > 0, "missed limit cannot be 0");
         let last = Instant::now();
         let timeout = timer.set_timeout(interval, val);
         Heartbeat {
@@ -30,11 +46,14 @@ impl<T: Copy + Debug> Heartbeat<T> {
             last,
             timeout,
             interval,
+            missed_limit,
+            missed: 0,
         }
     }
 
     pub fn record_activity(&mut self) {
         self.last = Instant::now();
+        self.missed = 0;
     }
 
     pub fn fire(&mut self, timer: &mut Timer<T>) -> HeartbeatState {
@@ -49,10 +68,19 @@ impl<T: Copy + Debug> Heartbeat<T> {
         // want to count that as expired anyway. AMQP heartbeats are scaled in
         // seconds, so a few ms is harmless.
         let elapsed = self.last.elapsed();
-        let (when, state) = if self.interval <= elapsed + Duration::from_millis(5) {
+        let missed = ((elapsed + Duration::from_millis(5)).as_nanos() / self.interval.as_nanos())
+            .min(u128::from(self.missed_limit)) as u32;
+        let (when, state) = if missed >= self.missed_limit {
             (self.interval, HeartbeatState::Expired)
         } else {
-            (self.interval - elapsed, HeartbeatState::StillRunning)
+            // Wake at the end of the interval we're in, whether or not it's a new miss.
+            let when = (self.interval * (missed + 1)).saturating_sub(elapsed);
+            if missed > self.missed {
+                self.missed = missed;
+                (when, HeartbeatState::Missed(missed))
+            } else {
+                (when, HeartbeatState::StillRunning)
+            }
         };
 
         trace!(
@@ -70,43 +98,25 @@ impl<T: Copy + Debug> Heartbeat<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use mio::{Events, Poll, PollOpt, Ready, Token};
-    use mio_extras::timer::Builder;
+    use std::thread;
 
+    #[derive(Default)]
     struct Harness {
-        poll: Poll,
-        events: Events,
         timer: Timer<u32>,
     }
 
     impl Harness {
-        const TOKEN: Token = Token(0);
-
         fn new() -> Harness {
-            let poll = Poll::new().unwrap();
-            let events = Events::with_capacity(16);
-            let timer = Builder::default().tick_duration(millis(10)).build();
-            poll.register(&timer, Self::TOKEN, Ready::readable(), PollOpt::edge())
-                .unwrap();
-            Harness {
-                poll,
-                events,
-                timer,
-            }
-        }
-
-        fn poll(&mut self, timeout: Duration) {
-            self.poll.poll(&mut self.events, Some(timeout)).unwrap();
+            Harness::default()
         }
 
+        // Sleep until each deadline passes, as the I/O loop would poll until it.
         fn poll_until_fire(&mut self, h: &mut Heartbeat<u32>) -> HeartbeatState {
             loop {
-                self.poll.poll(&mut self.events, None).unwrap();
-                for ev in &self.events {
-                    assert_eq!(ev.token(), Self::TOKEN);
-                    if self.timer.poll().is_some() {
-                        return h.fire(&mut self.timer);
-                    }
+                let deadline = self.timer.next_deadline().expect("no timeout set");
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                if self.timer.poll().is_some() {
+                    return h.fire(&mut self.timer);
                 }
             }
         }
@@ -142,9 +152,9 @@ mod tests {
         let start = Instant::now();
 
         // timer shouldn't fire yet
-        t.poll(millis(200));
+        thread::sleep(millis(200));
         assert_duration_is_about(start.elapsed(), millis(200));
-        assert!(t.events.is_empty());
+        assert_eq!(t.timer.poll(), None);
         h.record_activity();
 
         // timer should fire, but should be set back to "still running"
@@ -157,4 +167,33 @@ mod tests {
         assert_duration_is_about(start.elapsed(), millis(600));
         assert_eq!(state, HeartbeatState::Expired);
     }
+
+    #[test]
+    fn report_misses_before_expiring() {
+        let mut t = Harness::new();
+        let mut h = Heartbeat::start_with_limit(0, millis(200), 3, &mut t.timer);
+        let start = Instant::now();
+
+        let state = t.poll_until_fire(&mut h);
+        assert_duration_is_about(start.elapsed(), millis(200));
+        assert_eq!(state, HeartbeatState::Missed(1));
+
+        let state = t.poll_until_fire(&mut h);
+        assert_duration_is_about(start.elapsed(), millis(400));
+        assert_eq!(state, HeartbeatState::Missed(2));
+
+        // activity resets the count; the next miss is the first again
+        h.record_activity();
+        let state = t.poll_until_fire(&mut h);
+        assert_duration_is_about(start.elapsed(), millis(600));
+        assert_eq!(state, HeartbeatState::Missed(1));
+
+        let state = t.poll_until_fire(&mut h);
+        assert_duration_is_about(start.elapsed(), millis(800));
+        assert_eq!(state, HeartbeatState::Missed(2));
+
+        let state = t.poll_until_fire(&mut h);
+        assert_duration_is_about(start.elapsed(), millis(1000));
+        assert_eq!(state, HeartbeatState::Expired);
+    }
 }
@@ -0,0 +1,121 @@
+use super::mock_server::{recv_delivery, MockServer};
+use crate::{AckPolicy, Connection, ConsumerMessage, ConsumerOptions};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::AMQPClass;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn callback_consumer_acks_in_batches_by_count_then_time() {
+    let max_delay = Duration::from_millis(100);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for tag in 1..=7 {
+            conn.deliver(n, tag, b"body");
+        }
+
+        for expected in &[3, 6] {
+            let ack = conn.recv_ack(n);
+            assert_eq!(ack.delivery_tag, *expected);
+            assert!(ack.multiple);
+        }
+
+        // The last one is flushed by the timer, well before the client cancels.
+        let start = Instant::now();
+        let ack = conn.recv_ack(n);
+        assert_eq!(ack.delivery_tag, 7);
+        assert!(ack.multiple);
+        assert!(start.elapsed() < max_delay * 5, "{:?}", start.elapsed());
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        ack_policy: AckPolicy::Batched {
+            max_count: 3,
+            max_delay,
+        },
+        ..ConsumerOptions::default()
+    };
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let consumer = channel
+        .basic_consume_with_callback("q", options, move |message, _acker| {
+            if let ConsumerMessage::Delivery(delivery) = message {
+                tx.send(delivery.delivery_tag()).unwrap();
+            }
+        })
+        .unwrap();
+
+    for tag in 1..=7 {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), tag);
+    }
+    thread::sleep(max_delay * 10);
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn batched_acks_are_flushed_before_later_methods() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for tag in 1..=3 {
+            conn.deliver(n, tag, b"body");
+        }
+
+        let ack = conn.recv_ack(n);
+        assert_eq!(ack.delivery_tag, 2);
+        assert!(ack.multiple);
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                assert_eq!(nack.delivery_tag, 3);
+                assert!(!nack.multiple);
+            }
+            other => panic!("expected nack, got {:?}", other),
+        }
+
+        conn.deliver(n, 4, b"body");
+        let ack = conn.recv_ack(n);
+        assert_eq!(ack.delivery_tag, 4);
+        assert!(ack.multiple);
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        ack_policy: AckPolicy::Batched {
+            max_count: 100,
+            max_delay: Duration::from_secs(60),
+        },
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel.basic_consume("q", options).unwrap();
+
+    for _ in 0..2 {
+        consumer.ack(recv_delivery(consumer.receiver())).unwrap();
+    }
+    consumer
+        .nack(recv_delivery(consumer.receiver()), true)
+        .unwrap();
+
+    consumer.ack(recv_delivery(consumer.receiver())).unwrap();
+    consumer.cancel().unwrap();
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
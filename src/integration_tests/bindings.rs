@@ -0,0 +1,176 @@
+use super::mock_server::{MockServer, ServerConn};
+use super::with_chan;
+use crate::{
+    Connection, ExchangeDeclareOptions, ExchangeType, FieldTable, Publish, QueueDeclareOptions,
+};
+use amq_protocol::protocol::exchange::AMQPMethod as AmqpExchange;
+use amq_protocol::protocol::exchange::{
+    BindOk as ExchangeBindOk, DeclareOk as ExchangeDeclareOk, UnbindOk as ExchangeUnbindOk,
+};
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::queue::{DeclareOk as QueueDeclareOk, UnbindOk as QueueUnbindOk};
+use amq_protocol::protocol::AMQPClass;
+
+fn accept_exchange_declare(conn: &mut ServerConn, channel_id: u16, name: &str) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == channel_id => {
+            assert_eq!(declare.exchange, name);
+        }
+        other => panic!("expected exchange.declare, got {:?}", other),
+    }
+    conn.send_method(channel_id, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+}
+
+#[test]
+fn unbind_and_exchange_bindings_wait_for_ok() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        accept_exchange_declare(&mut conn, n, "src");
+        accept_exchange_declare(&mut conn, n, "dst");
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                conn.send_method(
+                    n,
+                    AmqpQueue::DeclareOk(QueueDeclareOk {
+                        queue: declare.queue,
+                        message_count: 0,
+                        consumer_count: 0,
+                    }),
+                );
+            }
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Unbind(unbind))) if ch == n => {
+                assert_eq!(unbind.queue, "q");
+                assert_eq!(unbind.exchange, "dst");
+                assert_eq!(unbind.routing_key, "a.*");
+            }
+            other => panic!("expected queue.unbind, got {:?}", other),
+        }
+        conn.send_method(n, AmqpQueue::UnbindOk(QueueUnbindOk {}));
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Exchange(AmqpExchange::Bind(bind))) if ch == n => {
+                assert_eq!(bind.destination, "dst");
+                assert_eq!(bind.source, "src");
+                assert_eq!(bind.routing_key, "a.#");
+                assert!(!bind.nowait);
+            }
+            other => panic!("expected exchange.bind, got {:?}", other),
+        }
+        conn.send_method(n, AmqpExchange::BindOk(ExchangeBindOk {}));
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Exchange(AmqpExchange::Unbind(unbind))) if ch == n => {
+                assert_eq!(unbind.destination, "dst");
+                assert_eq!(unbind.source, "src");
+                assert_eq!(unbind.routing_key, "a.#");
+                assert!(!unbind.nowait);
+            }
+            other => panic!("expected exchange.unbind, got {:?}", other),
+        }
+        conn.send_method(n, AmqpExchange::UnbindOk(ExchangeUnbindOk {}));
+
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let source = channel
+        .exchange_declare(
+            ExchangeType::Topic,
+            "src",
+            ExchangeDeclareOptions::default(),
+        )
+        .unwrap();
+    let destination = channel
+        .exchange_declare(
+            ExchangeType::Topic,
+            "dst",
+            ExchangeDeclareOptions::default(),
+        )
+        .unwrap();
+    let queue = channel
+        .queue_declare("q", QueueDeclareOptions::default())
+        .unwrap();
+
+    queue
+        .unbind(&destination, "a.*", FieldTable::default())
+        .unwrap();
+    destination
+        .bind_to_source(&source, "a.#", FieldTable::default())
+        .unwrap();
+    source
+        .unbind_from_destination(&destination, "a.#", FieldTable::default())
+        .unwrap();
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_rewire_topic_routing() {
+    with_chan(|chan| {
+        let options = ExchangeDeclareOptions {
+            auto_delete: true,
+            ..ExchangeDeclareOptions::default()
+        };
+        let source = chan
+            .exchange_declare(
+                ExchangeType::Topic,
+                "amiquip-test-rewire-src",
+                options.clone(),
+            )
+            .unwrap();
+        let destination = chan
+            .exchange_declare(ExchangeType::Fanout, "amiquip-test-rewire-dst", options)
+            .unwrap();
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..QueueDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        queue.bind(&destination, "", FieldTable::default()).unwrap();
+
+        // Unbinding bindings that don't exist is a no-op.
+        queue
+            .unbind(&destination, "no.such.key", FieldTable::default())
+            .unwrap();
+        destination
+            .unbind_from_source(&source, "no.such.key", FieldTable::default())
+            .unwrap();
+
+        let publish_and_count = |routing_key: &str| {
+            source.publish(Publish::new(b"hello", routing_key)).unwrap();
+            let mut count = 0;
+            while queue.get(true).unwrap().is_some() {
+                count += 1;
+            }
+            count
+        };
+
+        destination
+            .bind_to_source(&source, "a.*", FieldTable::default())
+            .unwrap();
+        assert_eq!(publish_and_count("a.b"), 1);
+        assert_eq!(publish_and_count("b.a"), 0);
+
+        destination
+            .unbind_from_source(&source, "a.*", FieldTable::default())
+            .unwrap();
+        destination
+            .bind_to_source(&source, "b.*", FieldTable::default())
+            .unwrap();
+        assert_eq!(publish_and_count("a.b"), 0);
+        assert_eq!(publish_and_count("b.a"), 1);
+    })
+}
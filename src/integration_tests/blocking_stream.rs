@@ -0,0 +1,70 @@
+use super::mock_server::MockServer;
+use crate::{Auth, BlockingStream, Connection, ConnectionOptions, ConnectionTuning};
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+
+// Hangs up when dropped, like a tunnel closing its socket when amiquip is done with it.
+struct ClosingWriter(TcpStream);
+
+impl Write for ClosingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for ClosingWriter {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown(Shutdown::Both);
+    }
+}
+
+fn server() -> MockServer {
+    MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.send_heartbeat();
+        let n = conn.accept_channel();
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    })
+}
+
+fn open_and_close<F: FnOnce(TcpStream) -> Connection>(open: F) {
+    let server = server();
+    let socket = TcpStream::connect(server.addr()).unwrap();
+    let mut connection = open(socket);
+    let channel = connection.open_channel(None).unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn open_over_blocking_transport() {
+    open_and_close(|socket| {
+        let stream = BlockingStream::new(socket.try_clone().unwrap(), ClosingWriter(socket));
+        Connection::insecure_open_stream(
+            stream,
+            ConnectionOptions::<Auth>::default(),
+            ConnectionTuning::default(),
+        )
+        .unwrap()
+    });
+}
+
+#[test]
+fn open_over_connected_std_socket() {
+    open_and_close(|socket| {
+        socket.set_nonblocking(true).unwrap();
+        let stream = mio::net::TcpStream::from_std(socket);
+        Connection::insecure_open_stream(
+            stream,
+            ConnectionOptions::<Auth>::default(),
+            ConnectionTuning::default(),
+        )
+        .unwrap()
+    });
+}
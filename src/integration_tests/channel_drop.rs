@@ -0,0 +1,91 @@
+use super::mock_server::{MockServer, DEFAULT_TUNE};
+use crate::Connection;
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::{CloseOk as ChannelCloseOk, OpenOk as ChannelOpenOk};
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::{CloseOk as ConnectionCloseOk, Tune};
+use amq_protocol::protocol::AMQPClass;
+
+#[cfg(feature = "consume")]
+use crate::{ConsumerMessage, ConsumerOptions};
+#[cfg(feature = "consume")]
+use std::time::Duration;
+
+const CHANNEL_MAX: u16 = 16;
+
+#[test]
+fn dropped_channels_are_closed_and_their_ids_reused() {
+    const CHANNELS: usize = 10_000;
+
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            channel_max: CHANNEL_MAX,
+            ..DEFAULT_TUNE
+        });
+        // A dropped channel's close and the next channel's open may reach us in either order.
+        let mut closes = 0;
+        loop {
+            match conn.recv_method() {
+                (n, AMQPClass::Channel(AmqpChannel::Open(_))) => {
+                    assert!(n <= CHANNEL_MAX);
+                    conn.send_method(
+                        n,
+                        AmqpChannel::OpenOk(ChannelOpenOk {
+                            channel_id: String::new(),
+                        }),
+                    );
+                }
+                (n, AMQPClass::Channel(AmqpChannel::Close(_))) => {
+                    conn.send_method(n, AmqpChannel::CloseOk(ChannelCloseOk {}));
+                    closes += 1;
+                }
+                (0, AMQPClass::Connection(AmqpConnection::Close(_))) => {
+                    conn.send_method(0, AmqpConnection::CloseOk(ConnectionCloseOk {}));
+                    break;
+                }
+                other => panic!("unexpected method {:?}", other),
+            }
+        }
+        // Every dropped channel, plus the one closed explicitly at the end.
+        assert_eq!(closes, CHANNELS + 1);
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    for _ in 0..CHANNELS {
+        let channel = connection.open_channel(None).unwrap();
+        assert!(channel.channel_id() <= CHANNEL_MAX);
+    }
+    // Make sure the last close reaches the server before the connection close.
+    connection.open_channel(None).unwrap().close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[cfg(feature = "consume")]
+#[test]
+fn dropping_channel_ends_its_consumers() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let rx = consumer.receiver().clone();
+    // Dropping the consumer would cancel it; leak it so only the channel going away ends it.
+    std::mem::forget(consumer);
+    drop(channel);
+
+    match rx.recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::ClientClosedChannel) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    connection.close().unwrap();
+    server.join();
+}
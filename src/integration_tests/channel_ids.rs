@@ -0,0 +1,80 @@
+use super::mock_server::{MockServer, DEFAULT_TUNE};
+use crate::{Connection, Error};
+use amq_protocol::protocol::connection::Tune;
+
+const CHANNEL_MAX: u16 = 4;
+
+#[test]
+fn closed_channel_ids_are_reused() {
+    const ITERATIONS: usize = 100;
+
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            channel_max: CHANNEL_MAX,
+            ..DEFAULT_TUNE
+        });
+        let keep = conn.accept_channel();
+        for _ in 0..ITERATIONS {
+            let n = conn.accept_channel();
+            assert!(n != keep && n <= CHANNEL_MAX);
+            conn.accept_channel_close(n);
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let _keep = connection.open_channel(None).unwrap();
+    for _ in 0..ITERATIONS {
+        connection.open_channel(None).unwrap().close().unwrap();
+    }
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn explicit_channel_id_errors() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            channel_max: CHANNEL_MAX,
+            ..DEFAULT_TUNE
+        });
+        for _ in 0..CHANNEL_MAX {
+            conn.accept_channel();
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let _first = connection.open_channel(Some(1)).unwrap();
+    let expect_err = |result: crate::Result<crate::Channel>| match result {
+        Ok(_) => panic!("opened a channel that should have been refused"),
+        Err(err) => err,
+    };
+
+    match expect_err(connection.open_channel(Some(1))) {
+        Error::ChannelIdInUse { channel_id: 1 } => (),
+        err => panic!("unexpected error {}", err),
+    }
+    match expect_err(connection.open_channel(Some(0))) {
+        Error::ChannelIdInUse { channel_id: 0 } => (),
+        err => panic!("unexpected error {}", err),
+    }
+    match expect_err(connection.open_channel(Some(CHANNEL_MAX + 1))) {
+        Error::ChannelMaxReached {
+            channel_max: CHANNEL_MAX,
+        } => (),
+        err => panic!("unexpected error {}", err),
+    }
+
+    let _rest = (1..CHANNEL_MAX)
+        .map(|_| connection.open_channel(None).unwrap())
+        .collect::<Vec<_>>();
+    match expect_err(connection.open_channel(None)) {
+        Error::ChannelMaxReached {
+            channel_max: CHANNEL_MAX,
+        } => (),
+        err => panic!("unexpected error {}", err),
+    }
+    connection.close().unwrap();
+    server.join();
+}
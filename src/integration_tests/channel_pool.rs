@@ -0,0 +1,127 @@
+use super::mock_server::MockServer;
+use crate::{with_deadline, Connection, Error, Publish};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::OpenOk as ChannelOpenOk;
+use amq_protocol::protocol::channel::{Close as ChannelClose, CloseOk as ChannelCloseOk};
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::CloseOk as ConnectionCloseOk;
+use amq_protocol::protocol::AMQPClass;
+use std::collections::HashSet;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn many_threads_share_a_few_channels() {
+    const MAX: usize = 4;
+    const THREADS: usize = 16;
+    const ITERATIONS: usize = 50;
+
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let mut opened = HashSet::new();
+        let mut publishes = 0;
+        loop {
+            match conn.recv_frame() {
+                AMQPFrame::Method(n, AMQPClass::Channel(AmqpChannel::Open(_))) => {
+                    assert!(opened.insert(n), "channel {} opened twice", n);
+                    conn.send_method(
+                        n,
+                        AmqpChannel::OpenOk(ChannelOpenOk {
+                            channel_id: String::new(),
+                        }),
+                    );
+                }
+                AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::Publish(_))) => {
+                    assert!(opened.contains(&n));
+                    publishes += 1;
+                }
+                AMQPFrame::Method(n, AMQPClass::Channel(AmqpChannel::Close(_))) => {
+                    conn.send_method(n, AmqpChannel::CloseOk(ChannelCloseOk {}));
+                }
+                AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Close(_))) => {
+                    conn.send_method(0, AmqpConnection::CloseOk(ConnectionCloseOk {}));
+                    break;
+                }
+                AMQPFrame::Header(..) | AMQPFrame::Body(..) | AMQPFrame::Heartbeat(_) => (),
+                other => panic!("unexpected frame {:?}", other),
+            }
+        }
+        // No channel was ever discarded, so none beyond the pool's maximum was opened.
+        assert!(opened.len() <= MAX, "opened {} channels", opened.len());
+        assert_eq!(publishes, THREADS * ITERATIONS);
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    let pool = connection.channel_pool(MAX);
+    let threads = (0..THREADS)
+        .map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for _ in 0..ITERATIONS {
+                    let channel = pool.get().unwrap();
+                    channel.basic_publish("", Publish::new(b"x", "k")).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    drop(pool);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn channel_closed_by_server_is_not_reused() {
+    let (closed_tx, closed_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.recv_publish(n);
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 404,
+                reply_text: "NOT_FOUND - no exchange 'missing'".to_string(),
+                class_id: 60,
+                method_id: 40,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected close-ok, got {:?}", other),
+        }
+        closed_tx.send(()).unwrap();
+
+        let m = conn.accept_channel();
+        conn.recv_publish(m);
+        conn.accept_channel_close(m);
+        conn.accept_connection_close();
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    let pool = connection.channel_pool(1);
+    {
+        let channel = pool.get().unwrap();
+        channel
+            .basic_publish("missing", Publish::new(b"x", "k"))
+            .unwrap();
+    }
+    closed_rx.recv().unwrap();
+
+    let channel = pool.get().unwrap();
+    // The pool is full while we hold its only channel.
+    match with_deadline(Duration::from_millis(50), || pool.get()) {
+        Err(Error::DeadlineExceeded) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("pool handed out more than its maximum"),
+    }
+    channel.basic_publish("", Publish::new(b"x", "k")).unwrap();
+    drop(channel);
+    drop(pool);
+    connection.close().unwrap();
+    server.join();
+}
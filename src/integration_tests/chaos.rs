@@ -0,0 +1,450 @@
+use super::mock_server::{recv_delivery, MockServer};
+use crate::{
+    Auth, Connection, ConnectionOptions, ConnectionTuning, ConsumerMessage, ConsumerOptions,
+    DropAfterNFrames, Error, Exchange, Fault, FaultInjector, Publish, RandomLatency, Result,
+};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::CloseOk as ChannelCloseOk;
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::CloseOk;
+use amq_protocol::protocol::AMQPClass;
+use mio::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Cycles through a WouldBlock, a 7 byte write, and an unrestricted write, so every buffer the
+// I/O thread sends is split up and retried.
+#[derive(Default)]
+struct Stutter {
+    writes: AtomicUsize,
+}
+
+impl FaultInjector for Stutter {
+    fn before_write(&self, _pending: usize) -> Fault {
+        match self.writes.fetch_add(1, Ordering::Relaxed) % 3 {
+            0 => Fault::WouldBlock,
+            1 => Fault::ShortWrite(7),
+            _ => Fault::None,
+        }
+    }
+}
+
+fn open(server: &MockServer, options: ConnectionOptions<Auth>) -> Connection {
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    Connection::insecure_open_stream(stream, options, ConnectionTuning::default()).unwrap()
+}
+
+#[test]
+fn partial_writes_deliver_intact_stream() {
+    let body = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let expected = body.clone();
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == n => (),
+            other => panic!("expected publish, got {:?}", other),
+        }
+        let mut received = Vec::new();
+        match conn.recv_frame() {
+            AMQPFrame::Header(ch, _, header) if ch == n => {
+                assert_eq!(header.body_size, expected.len() as u64)
+            }
+            other => panic!("expected content header, got {:?}", other),
+        }
+        while received.len() < expected.len() {
+            match conn.recv_frame() {
+                AMQPFrame::Body(ch, chunk) if ch == n => received.extend_from_slice(&chunk),
+                other => panic!("expected content body, got {:?}", other),
+            }
+        }
+        assert!(received == expected, "body corrupted in transit");
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let options = ConnectionOptions::default().fault_injector(Stutter::default());
+    let mut connection = open(&server, options);
+    let channel = connection.open_channel(None).unwrap();
+    Exchange::direct(&channel)
+        .publish(Publish::new(&body, "q"))
+        .unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn random_latency_preserves_ordering() {
+    const DELIVERIES: u64 = 10;
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for tag in 1..=DELIVERIES {
+            conn.deliver(n, tag, &tag.to_be_bytes());
+        }
+        conn.accept_connection_close();
+    });
+
+    let options = ConnectionOptions::default()
+        .fault_injector(RandomLatency::new(Duration::from_millis(2), 7));
+    let mut connection = open(&server, options);
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    for tag in 1..=DELIVERIES {
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => {
+                assert_eq!(delivery.delivery_tag(), tag)
+            }
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+    }
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn dropped_connection_can_be_reopened() {
+    // Start, Tune, and OpenOk during the handshake, then channel OpenOk and ConsumeOk, then
+    // three frames per delivery: fail partway through the third delivery.
+    const FRAMES: u64 = 5 + 2 * 3 + 1;
+
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for tag in 1..=3 {
+            conn.deliver(n, tag, &tag.to_be_bytes());
+        }
+        conn.wait_for_client_eof();
+    });
+
+    let options = ConnectionOptions::default().fault_injector(DropAfterNFrames::new(FRAMES));
+    let mut connection = open(&server, options.clone());
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    for tag in 1..=2 {
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => {
+                assert_eq!(delivery.delivery_tag(), tag)
+            }
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+    }
+    assert!(consumer
+        .receiver()
+        .recv_timeout(Duration::from_secs(5))
+        .is_err());
+    match connection.close() {
+        Err(Error::IoErrorReadingSocket { .. }) => (),
+        other => panic!("unexpected close result {:?}", other),
+    }
+    server.join();
+
+    // The frame count starts over on a new connection with the same options.
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+    let mut connection = open(&server, options);
+    let channel = connection.open_channel(None).unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+// Stops accepting writes once `stalled` is set, as if the server stopped reading.
+struct StallWhenSet {
+    stalled: Arc<AtomicBool>,
+}
+
+impl FaultInjector for StallWhenSet {
+    fn before_write(&self, _pending: usize) -> Fault {
+        if self.stalled.load(Ordering::SeqCst) {
+            Fault::WouldBlock
+        } else {
+            Fault::None
+        }
+    }
+}
+
+#[test]
+fn stalled_writes_fail_connection() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_channel();
+        // Nothing else arrives; wait for the client to give up and hang up.
+        conn.wait_for_client_eof();
+    });
+
+    let stalled = Arc::new(AtomicBool::new(false));
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default().fault_injector(StallWhenSet {
+        stalled: Arc::clone(&stalled),
+    });
+    let tuning = ConnectionTuning::default().write_stall_timeout(Some(Duration::from_millis(200)));
+    let mut connection = Connection::insecure_open_stream(stream, options, tuning).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+
+    stalled.store(true, Ordering::SeqCst);
+    Exchange::direct(&channel)
+        .publish(Publish::new(b"hello", "q"))
+        .unwrap();
+    match connection.close() {
+        Err(Error::WriteStalled { .. }) => (),
+        other => panic!("unexpected close result {:?}", other),
+    }
+    server.join();
+}
+
+#[test]
+fn publishers_block_above_high_water_but_close_completes() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_channel();
+        // Skip over everything the publisher managed to send until the connection close.
+        while !matches!(
+            conn.recv_frame(),
+            AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Close(_)))
+        ) {}
+        conn.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+        conn.wait_for_client_eof();
+    });
+
+    let stalled = Arc::new(AtomicBool::new(false));
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default().fault_injector(StallWhenSet {
+        stalled: Arc::clone(&stalled),
+    });
+    let tuning = ConnectionTuning::default()
+        .mem_channel_bound(1)
+        .buffered_writes_high_water(64 << 10);
+    let mut connection = Connection::insecure_open_stream(stream, options, tuning).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+
+    stalled.store(true, Ordering::SeqCst);
+    let published = Arc::new(AtomicUsize::new(0));
+    let publisher = {
+        let published = Arc::clone(&published);
+        thread::spawn(move || -> Result<()> {
+            let body = [0; 1024];
+            loop {
+                Exchange::direct(&channel).publish(Publish::new(&body, "q"))?;
+                published.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+    };
+
+    // Once the buffer passes the high water mark, the publisher stops making progress. Wait
+    // for that rather than assuming how long it takes on a busy machine.
+    let mut before = published.load(Ordering::SeqCst);
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(200));
+        let now = published.load(Ordering::SeqCst);
+        if now == before {
+            break;
+        }
+        before = now;
+    }
+    assert_eq!(published.load(Ordering::SeqCst), before);
+    assert!(
+        before < 128,
+        "published {} messages past the high water mark",
+        before
+    );
+
+    // The connection close is not held up behind the blocked channel.
+    stalled.store(false, Ordering::SeqCst);
+    connection.close().unwrap();
+    match publisher.join().unwrap() {
+        Err(Error::ClientClosedConnection) | Err(Error::EventLoopDropped) => (),
+        other => panic!("unexpected publisher result {:?}", other),
+    }
+    server.join();
+}
+
+#[test]
+fn try_publish_refuses_instead_of_blocking() {
+    let (count_tx, count_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        conn.accept_channel();
+        let mut publishes = 0;
+        loop {
+            match conn.recv_frame() {
+                AMQPFrame::Method(_, AMQPClass::Basic(AmqpBasic::Publish(_))) => publishes += 1,
+                AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Close(_))) => break,
+                _ => (),
+            }
+        }
+        count_tx.send(publishes).unwrap();
+        conn.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+        conn.wait_for_client_eof();
+    });
+
+    let stalled = Arc::new(AtomicBool::new(false));
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default().fault_injector(StallWhenSet {
+        stalled: Arc::clone(&stalled),
+    });
+    let tuning = ConnectionTuning::default()
+        .mem_channel_bound(1)
+        .buffered_writes_high_water(64 << 10);
+    let mut connection = Connection::insecure_open_stream(stream, options, tuning).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let exchange = Exchange::direct(&channel);
+    let body = [0; 1024];
+
+    stalled.store(true, Ordering::SeqCst);
+    let mut accepted = 0;
+    loop {
+        if !exchange.try_publish(Publish::new(&body, "q")).unwrap() {
+            // The I/O thread may just not have emptied its mailbox yet; refusals continue only
+            // once the buffer is over the high water mark.
+            thread::sleep(Duration::from_millis(10));
+            if !exchange.try_publish(Publish::new(&body, "q")).unwrap() {
+                break;
+            }
+        }
+        accepted += 1;
+        assert!(accepted < 1000, "try_publish never refused a message");
+    }
+
+    // ...and accepted again once it drains.
+    stalled.store(false, Ordering::SeqCst);
+    let start = Instant::now();
+    while !exchange.try_publish(Publish::new(&body, "q")).unwrap() {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "buffer never drained"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    accepted += 1;
+
+    connection.close().unwrap();
+    assert_eq!(count_rx.recv().unwrap(), accepted);
+    server.join();
+}
+
+#[test]
+fn acks_and_channel_close_bypass_high_water() {
+    let (count_tx, count_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        conn.accept_channel();
+        conn.accept_consume(1);
+        conn.deliver(1, 1, b"job");
+        conn.accept_cancel(1);
+        let mut publishes = 0;
+        let mut acked = false;
+        loop {
+            match conn.recv_frame() {
+                AMQPFrame::Method(1, AMQPClass::Basic(AmqpBasic::Publish(_))) => publishes += 1,
+                AMQPFrame::Method(1, AMQPClass::Basic(AmqpBasic::Ack(ack))) => {
+                    assert_eq!(ack.delivery_tag, 1);
+                    acked = true;
+                }
+                AMQPFrame::Method(1, AMQPClass::Channel(AmqpChannel::Close(_))) => {
+                    count_tx.send((publishes, acked)).unwrap();
+                    conn.send_method(1, AmqpChannel::CloseOk(ChannelCloseOk {}));
+                }
+                AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Close(_))) => break,
+                _ => (),
+            }
+        }
+        conn.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+        conn.wait_for_client_eof();
+    });
+
+    let stalled = Arc::new(AtomicBool::new(false));
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default().fault_injector(StallWhenSet {
+        stalled: Arc::clone(&stalled),
+    });
+    let tuning = ConnectionTuning::default()
+        .mem_channel_bound(1)
+        .buffered_writes_high_water(64 << 10);
+    let mut connection = Connection::insecure_open_stream(stream, options, tuning).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let delivery = {
+        let consumer = channel
+            .basic_consume("q", ConsumerOptions::default())
+            .unwrap();
+        recv_delivery(consumer.receiver())
+    };
+
+    // Publish until the I/O thread stops taking publishes, leaving one waiting in the channel's
+    // mailbox.
+    stalled.store(true, Ordering::SeqCst);
+    let published = Arc::new(AtomicUsize::new(0));
+    let publisher = {
+        let published = Arc::clone(&published);
+        let publisher = channel.publisher();
+        thread::spawn(move || -> Result<()> {
+            let body = [0; 1024];
+            loop {
+                publisher.publish("", Publish::new(&body, "q"))?;
+                published.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+    };
+    let mut before = published.load(Ordering::SeqCst);
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(200));
+        let now = published.load(Ordering::SeqCst);
+        if now == before {
+            break;
+        }
+        before = now;
+    }
+    assert_eq!(published.load(Ordering::SeqCst), before);
+
+    // The ack is still taken...
+    delivery.ack(&channel).unwrap();
+    let start = Instant::now();
+    while channel.stats().acks == 0 {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "ack held up behind publishes"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // ...and so is the close, which follows the publishes sent before it.
+    let unstall = {
+        let stalled = Arc::clone(&stalled);
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            stalled.store(false, Ordering::SeqCst);
+        })
+    };
+    let sent = published.load(Ordering::SeqCst);
+    channel.close().unwrap();
+    unstall.join().unwrap();
+    let (publishes, acked) = count_rx.recv().unwrap();
+    assert!(acked);
+    assert!(
+        publishes >= sent,
+        "close overtook publishes ({} of {} sent first)",
+        publishes,
+        sent
+    );
+    // Publishes the publisher went on to send after the close fail (or are lost with the
+    // channel).
+    let _ = publisher.join().unwrap();
+
+    connection.close().unwrap();
+    server.join();
+}
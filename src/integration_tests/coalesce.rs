@@ -0,0 +1,193 @@
+use super::mock_server::{MockServer, DEFAULT_TUNE};
+use crate::{Auth, Connection, ConnectionOptions, ConnectionTuning, IoStream, Publish};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::connection::{AMQPMethod as AmqpConnection, CloseOk, Tune};
+use amq_protocol::protocol::AMQPClass;
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use std::io::{self, IoSlice, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const THREADS: usize = 4;
+const PUBLISHES: usize = 10_000;
+
+// What the I/O thread asked of the socket: writes, and changes to its registration with mio.
+#[derive(Default)]
+struct Syscalls {
+    writes: AtomicUsize,
+    reregisters: AtomicUsize,
+}
+
+struct CountingStream {
+    stream: TcpStream,
+    syscalls: Arc<Syscalls>,
+}
+
+impl Read for CountingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for CountingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.syscalls.writes.fetch_add(1, Ordering::Relaxed);
+        self.stream.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.syscalls.writes.fetch_add(1, Ordering::Relaxed);
+        self.stream.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Source for CountingStream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.stream.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.syscalls.reregisters.fetch_add(1, Ordering::Relaxed);
+        self.stream.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.stream.deregister(registry)
+    }
+}
+
+impl IoStream for CountingStream {}
+
+// Many small publishes from several threads should be gathered into far fewer writes than
+// publishes, rather than each being written (and the socket reregistered) on its own.
+#[test]
+fn concurrent_publishes_share_writes() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for _ in 0..PUBLISHES {
+            let (_, body) = conn.recv_publish(n);
+            assert_eq!(body, b"x");
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let syscalls = Arc::new(Syscalls::default());
+    let socket = std::net::TcpStream::connect(server.addr()).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let stream = CountingStream {
+        stream: TcpStream::from_std(socket),
+        syscalls: Arc::clone(&syscalls),
+    };
+    let mut connection = Connection::insecure_open_stream(
+        stream,
+        ConnectionOptions::<Auth>::default(),
+        ConnectionTuning::default(),
+    )
+    .unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let publisher = channel.publisher();
+
+    let writes_before = syscalls.writes.load(Ordering::Relaxed);
+    let reregisters_before = syscalls.reregisters.load(Ordering::Relaxed);
+    let threads = (0..THREADS)
+        .map(|_| {
+            let publisher = publisher.clone();
+            thread::spawn(move || {
+                for _ in 0..PUBLISHES / THREADS {
+                    publisher.publish("", Publish::new(b"x", "q")).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    channel.close().unwrap();
+    let writes = syscalls.writes.load(Ordering::Relaxed) - writes_before;
+    let reregisters = syscalls.reregisters.load(Ordering::Relaxed) - reregisters_before;
+    // Writing each publish as it arrived took a write and two reregistrations apiece.
+    assert!(
+        writes + reregisters < PUBLISHES,
+        "{} publishes took {} writes and {} reregisters",
+        PUBLISHES,
+        writes,
+        reregisters
+    );
+
+    connection.close().unwrap();
+    server.join();
+}
+
+// Frames queued together still go out in order: no heartbeat lands inside a publish's content
+// (the server expects a publish's header and body frames to follow it directly), the channel
+// close follows every publish, and heartbeats keep flowing once the connection is idle.
+#[test]
+fn heartbeats_and_close_follow_coalesced_publishes() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            heartbeat: 1,
+            ..DEFAULT_TUNE
+        });
+        let n = conn.accept_channel();
+        for _ in 0..PUBLISHES / 10 {
+            conn.recv_publish(n);
+        }
+        conn.accept_channel_close(n);
+        let mut heartbeats = 0;
+        loop {
+            match conn.recv_frame() {
+                AMQPFrame::Heartbeat(_) => {
+                    heartbeats += 1;
+                    conn.send_heartbeat();
+                }
+                AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Close(_))) => break,
+                other => panic!("expected heartbeat or connection close, got {:?}", other),
+            }
+        }
+        assert!(heartbeats > 0);
+        conn.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+        conn.wait_for_client_eof();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let publisher = channel.publisher();
+    let threads = (0..THREADS)
+        .map(|_| {
+            let publisher = publisher.clone();
+            thread::spawn(move || {
+                for _ in 0..PUBLISHES / 10 / THREADS {
+                    publisher.publish("", Publish::new(b"x", "q")).unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    channel.close().unwrap();
+
+    thread::sleep(Duration::from_millis(1500));
+    connection.close().unwrap();
+    server.join();
+}
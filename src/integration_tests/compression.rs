@@ -0,0 +1,108 @@
+use super::mock_server::MockServer;
+use super::with_chan;
+use crate::{
+    AmqpProperties, Compression, Connection, ConsumerMessage, ConsumerOptions, Error, Exchange,
+    Publish, QueueDeclareOptions,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::Deliver;
+use flate2::read::GzDecoder;
+use std::io::Read;
+use std::time::Duration;
+
+const PIKA_BODY: &[u8] = br#"{"greeting": "hello from pika"}"#;
+
+// `gzip.compress(PIKA_BODY, mtime=0)` from Python 3, as a pika publisher would send it with
+// `BasicProperties(content_encoding="gzip")`.
+const PIKA_GZIP: &[u8] = b"\x1f\x8b\x08\x00\x00\x00\x00\x00\x02\x03\xab\x56\x4a\x2f\x4a\x4d\
+    \x2d\xc9\xcc\x4b\x57\xb2\x52\x50\xca\x48\xcd\xc9\xc9\x57\x48\x2b\xca\xcf\x55\x28\xc8\xcc\x4e\
+    \x54\xaa\x05\x00\x66\xf9\x19\x02\x1f\x00\x00\x00";
+
+#[test]
+fn gzip_interop_with_python_publisher() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.send_method(
+            n,
+            AmqpBasic::Deliver(Deliver {
+                consumer_tag: "ctag".to_string(),
+                delivery_tag: 1,
+                redelivered: false,
+                exchange: String::new(),
+                routing_key: "in".to_string(),
+            }),
+        );
+        let properties = AmqpProperties::default()
+            .with_content_type("application/json".to_string())
+            .with_content_encoding("gzip".to_string());
+        conn.send_content(n, PIKA_GZIP, &properties);
+
+        // What we send back must be readable by anything that speaks gzip.
+        let (_, properties, body) = conn.recv_publish_with_properties(n);
+        assert_eq!(properties.content_encoding(), &Some("gzip".to_string()));
+        let mut inflated = Vec::new();
+        GzDecoder::new(&body[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, PIKA_BODY);
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("in", ConsumerOptions::default())
+        .unwrap();
+    let delivery = match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => delivery,
+        other => panic!("unexpected consumer message {:?}", other),
+    };
+    assert_eq!(&*delivery.decompressed_body().unwrap(), PIKA_BODY);
+    match delivery.decompressed_body_with_limit(PIKA_BODY.len() - 1) {
+        Err(Error::DecompressedBodyTooLarge { .. }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    Exchange::direct(&channel)
+        .publish(Publish::new(PIKA_BODY, "out").with_compression(Compression::Gzip))
+        .unwrap();
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_compressed_round_trip() {
+    with_chan(|chan| {
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..QueueDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let exchange = Exchange::direct(chan);
+        let body = b"compress me ".repeat(1000);
+        for &compression in &[Compression::Gzip, Compression::Deflate] {
+            exchange
+                .publish(Publish::new(&body, queue.name()).with_compression(compression))
+                .unwrap();
+            let get = queue.get(true).unwrap().unwrap();
+            assert!(get.delivery.body.len() < body.len());
+            assert_eq!(
+                get.delivery.properties.content_encoding().as_deref(),
+                Some(compression.content_encoding())
+            );
+            assert_eq!(&*get.delivery.decompressed_body().unwrap(), &body[..]);
+        }
+    })
+}
@@ -0,0 +1,304 @@
+use super::mock_server::MockServer;
+use crate::{
+    AmqpProperties, Confirm, ConfirmOutcome, ConfirmTracker, Connection, Error, Publish,
+    PublishOutcome,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Ack, Nack, Return};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+fn ack(delivery_tag: u64, multiple: bool) -> AmqpBasic {
+    AmqpBasic::Ack(Ack {
+        delivery_tag,
+        multiple,
+    })
+}
+
+#[test]
+fn publish_confirmed_outcomes() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+
+        conn.recv_publish(n);
+        conn.send_method(n, ack(1, false));
+
+        conn.recv_publish(n);
+        conn.send_method(
+            n,
+            AmqpBasic::Nack(Nack {
+                delivery_tag: 2,
+                multiple: false,
+                requeue: false,
+            }),
+        );
+
+        let (publish, body) = conn.recv_publish(n);
+        assert!(publish.mandatory);
+        conn.send_method(
+            n,
+            AmqpBasic::Return(Return {
+                reply_code: 312,
+                reply_text: "NO_ROUTE".to_string(),
+                exchange: publish.exchange,
+                routing_key: publish.routing_key,
+            }),
+        );
+        conn.send_content(n, &body, &AmqpProperties::default());
+        conn.send_method(n, ack(3, false));
+
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let returns = channel.listen_for_returns().unwrap();
+
+    let outcome = channel
+        .publish_confirmed("", Publish::new(b"one", "q"), None)
+        .unwrap();
+    assert!(matches!(outcome, ConfirmOutcome::Acked));
+    let outcome = channel
+        .publish_confirmed("", Publish::new(b"two", "q"), None)
+        .unwrap();
+    assert!(matches!(outcome, ConfirmOutcome::Nacked));
+    let publish = Publish {
+        mandatory: true,
+        ..Publish::new(b"three", "nowhere")
+    };
+    match channel.publish_confirmed("", publish, None).unwrap() {
+        ConfirmOutcome::Returned(return_) => {
+            assert_eq!(return_.reply_code, 312);
+            assert_eq!(return_.routing_key, "nowhere");
+            assert_eq!(return_.content, b"three");
+        }
+        other => panic!("unexpected outcome {:?}", other),
+    }
+    assert!(returns.try_recv().is_err());
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn multiple_ack_settles_earlier_publishes() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        for _ in 0..3 {
+            conn.recv_publish(n);
+        }
+        conn.send_method(n, ack(3, true));
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let confirms = channel.listen_for_publisher_confirms().unwrap();
+    channel.enable_publisher_confirms().unwrap();
+    for body in &[b"one", b"two"] {
+        channel.basic_publish("", Publish::new(*body, "q")).unwrap();
+    }
+    let outcome = channel
+        .publish_confirmed("", Publish::new(b"three", "q"), None)
+        .unwrap();
+    assert!(matches!(outcome, ConfirmOutcome::Acked));
+
+    // Other listeners still see the raw confirm.
+    match confirms.recv().unwrap() {
+        Confirm::Ack(payload) => {
+            assert_eq!(payload.delivery_tag, 3);
+            assert!(payload.multiple);
+        }
+        other => panic!("unexpected confirm {:?}", other),
+    }
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn publish_confirmed_times_out() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.recv_publish(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    match channel.publish_confirmed(
+        "",
+        Publish::new(b"one", "q"),
+        Some(Duration::from_millis(50)),
+    ) {
+        Err(Error::PublisherConfirmTimeout) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn channel_close_fails_waiting_publish() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.recv_publish(n);
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 404,
+                reply_text: "NOT_FOUND - no exchange 'missing'".to_string(),
+                class_id: 60,
+                method_id: 40,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    match channel.publish_confirmed("missing", Publish::new(b"one", "q"), None) {
+        Err(Error::ServerClosedChannel { code, .. }) => assert_eq!(code, 404),
+        other => panic!("unexpected result {:?}", other),
+    }
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn confirm_tracker_follows_publishes() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        for _ in 0..3 {
+            conn.recv_publish(n);
+        }
+        conn.send_method(n, ack(2, true));
+        conn.recv_publish(n);
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 406,
+                reply_text: "PRECONDITION_FAILED".to_string(),
+                class_id: 60,
+                method_id: 40,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let mut tracker = ConfirmTracker::new(channel.listen_for_publisher_confirms().unwrap());
+    assert_eq!(channel.next_publish_seq_no(), 0);
+    channel.enable_publisher_confirms().unwrap();
+    for body in &[b"one", b"two", b"six"] {
+        tracker.track(channel.next_publish_seq_no());
+        channel.basic_publish("", Publish::new(*body, "q")).unwrap();
+    }
+    assert_eq!(channel.next_publish_seq_no(), 4);
+    tracker
+        .wait_until_below(2, Some(Duration::from_secs(5)))
+        .unwrap();
+
+    tracker.track(channel.next_publish_seq_no());
+    channel
+        .basic_publish("", Publish::new(b"ten", "q"))
+        .unwrap();
+    match tracker.wait_until_below(1, Some(Duration::from_secs(5))) {
+        Err(Error::UnconfirmedPublishesLost { seq_nos }) => assert_eq!(seq_nos, vec![3, 4]),
+        other => panic!("unexpected result {:?}", other),
+    }
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn publish_results_pair_returns_with_acks() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        for _ in 0..2 {
+            conn.recv_publish(n);
+        }
+        let (publish, body) = conn.recv_publish(n);
+        conn.send_method(
+            n,
+            AmqpBasic::Return(Return {
+                reply_code: 312,
+                reply_text: "NO_ROUTE".to_string(),
+                exchange: publish.exchange,
+                routing_key: publish.routing_key,
+            }),
+        );
+        conn.send_content(n, &body, &AmqpProperties::default());
+        conn.send_method(n, ack(3, false));
+        conn.send_method(
+            n,
+            AmqpBasic::Nack(Nack {
+                delivery_tag: 2,
+                multiple: true,
+                requeue: false,
+            }),
+        );
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let returns = channel.listen_for_returns().unwrap();
+    let results = channel.listen_for_publish_results().unwrap();
+    for key in &["a", "b", "nowhere"] {
+        let publish = Publish {
+            mandatory: true,
+            ..Publish::new(b"body", *key)
+        };
+        channel.basic_publish("", publish).unwrap();
+    }
+
+    let result = results.recv().unwrap();
+    assert_eq!(result.seq_no, 3);
+    match result.outcome {
+        PublishOutcome::Unroutable(return_) => assert_eq!(return_.routing_key, "nowhere"),
+        other => panic!("unexpected outcome {:?}", other),
+    }
+    for seq_no in 1..=2 {
+        let result = results.recv().unwrap();
+        assert_eq!(result.seq_no, seq_no);
+        assert!(matches!(result.outcome, PublishOutcome::Nacked));
+    }
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+    assert!(returns.try_recv().is_err());
+}
@@ -0,0 +1,134 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{Connection, ConnectionManager};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::{CloseOk as ChannelCloseOk, OpenOk as ChannelOpenOk};
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::CloseOk;
+use amq_protocol::protocol::AMQPClass;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Accept channel opens and closes in whatever order they arrive until the client closes the
+// connection.
+fn serve_channels(mut conn: ServerConn) {
+    conn.handshake();
+    loop {
+        match conn.recv_method() {
+            (n, AMQPClass::Channel(AmqpChannel::Open(_))) => conn.send_method(
+                n,
+                AmqpChannel::OpenOk(ChannelOpenOk {
+                    channel_id: String::new(),
+                }),
+            ),
+            (n, AMQPClass::Channel(AmqpChannel::Close(_))) => {
+                conn.send_method(n, AmqpChannel::CloseOk(ChannelCloseOk {}))
+            }
+            (0, AMQPClass::Connection(AmqpConnection::Close(_))) => {
+                conn.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+                conn.wait_for_client_eof();
+                return;
+            }
+            other => panic!("unexpected method {:?}", other),
+        }
+    }
+}
+
+// A manager whose opener ignores the requested URL and connects to each of `addrs` in turn,
+// counting how many connections it has opened.
+fn manager(addrs: Vec<SocketAddr>, opened: Arc<AtomicUsize>) -> ConnectionManager {
+    let addrs = Mutex::new(addrs.into_iter());
+    ConnectionManager::with_opener(move |_url| {
+        opened.fetch_add(1, Ordering::SeqCst);
+        // Widen the window for a second thread to race us.
+        thread::sleep(Duration::from_millis(50));
+        let addr = addrs.lock().unwrap().next().expect("too many connections");
+        Connection::insecure_open(&format!("amqp://{}", addr))
+    })
+}
+
+#[test]
+fn concurrent_requests_share_one_connection() {
+    const THREADS: usize = 4;
+    let server = MockServer::start(serve_channels);
+    let opened = Arc::new(AtomicUsize::new(0));
+    let manager = Arc::new(manager(vec![server.addr()], Arc::clone(&opened)));
+
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let threads = (0..THREADS)
+        .map(|_| {
+            let manager = Arc::clone(&manager);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                let channel = manager.open_channel("amqp://tenant-a").unwrap();
+                channel.close().unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(opened.load(Ordering::SeqCst), 1);
+    let connections = manager.connections();
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].url, "amqp://tenant-a");
+
+    drop(manager);
+    server.join();
+}
+
+#[test]
+fn dead_connection_is_replaced() {
+    let dying = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_channel_close(n);
+        // Hang up without closing the connection.
+    });
+    let healthy = MockServer::start(serve_channels);
+    let opened = Arc::new(AtomicUsize::new(0));
+    let manager = manager(vec![dying.addr(), healthy.addr()], Arc::clone(&opened));
+
+    manager
+        .open_channel("amqp://tenant-a")
+        .unwrap()
+        .close()
+        .unwrap();
+    dying.join();
+
+    manager
+        .open_channel("amqp://tenant-a")
+        .unwrap()
+        .close()
+        .unwrap();
+    assert_eq!(opened.load(Ordering::SeqCst), 2);
+    assert_eq!(manager.connections().len(), 1);
+
+    drop(manager);
+    healthy.join();
+}
+
+#[test]
+fn idle_connections_are_evicted() {
+    let server = MockServer::start(serve_channels);
+    let opened = Arc::new(AtomicUsize::new(0));
+    let manager =
+        manager(vec![server.addr()], Arc::clone(&opened)).idle_ttl(Duration::from_millis(100));
+
+    manager
+        .open_channel("amqp://tenant-a")
+        .unwrap()
+        .close()
+        .unwrap();
+    assert_eq!(manager.evict_idle(), 0);
+    assert_eq!(manager.connections().len(), 1);
+
+    thread::sleep(Duration::from_millis(150));
+    assert_eq!(manager.evict_idle(), 1);
+    assert!(manager.connections().is_empty());
+    server.join();
+}
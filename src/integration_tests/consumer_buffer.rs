@@ -0,0 +1,163 @@
+use super::mock_server::{recv_delivery, MockServer, DEFAULT_TUNE};
+use crate::{Connection, ConsumerMessage, ConsumerOptions, Error, OverflowPolicy};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::CancelOk;
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::CloseOk as ChannelCloseOk;
+use amq_protocol::protocol::connection::Tune;
+use amq_protocol::protocol::AMQPClass;
+use crossbeam_channel::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// With a 1 second heartbeat, the client gives up on a silent server after 2 seconds; the
+// consumer leaves its buffer full for longer than that.
+const STALL: Duration = Duration::from_secs(3);
+
+#[test]
+fn blocked_consumer_pauses_reads_but_keeps_heartbeating() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            heartbeat: 1,
+            ..DEFAULT_TUNE
+        });
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for tag in 1..=3 {
+            conn.deliver(n, tag, b"body");
+        }
+
+        // Our heartbeats queue up behind the deliveries the client isn't reading; theirs should
+        // keep coming until the consumer catches up and is cancelled.
+        conn.stream()
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let deadline = Instant::now() + STALL + Duration::from_secs(5);
+        let mut last_sent = Instant::now();
+        let mut heartbeats = 0;
+        loop {
+            assert!(Instant::now() < deadline, "never received cancel");
+            if last_sent.elapsed() >= Duration::from_millis(500) {
+                conn.send_heartbeat();
+                last_sent = Instant::now();
+            }
+            match conn.try_recv_frame() {
+                Some(AMQPFrame::Heartbeat(_)) => heartbeats += 1,
+                Some(AMQPFrame::Method(ch, AMQPClass::Basic(AmqpBasic::Cancel(_)))) => {
+                    assert_eq!(ch, n);
+                    break;
+                }
+                Some(frame) => panic!("unexpected frame {:?}", frame),
+                None => (),
+            }
+        }
+        assert!(
+            heartbeats >= STALL.as_secs() - 1,
+            "only received {} heartbeats while the consumer was full",
+            heartbeats
+        );
+        conn.stream()
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        conn.send_method(
+            n,
+            AmqpBasic::CancelOk(CancelOk {
+                consumer_tag: "ctag".to_string(),
+            }),
+        );
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        no_ack: true,
+        buffer_bound: Some(1),
+        overflow_policy: OverflowPolicy::Block,
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel.basic_consume("q", options).unwrap();
+
+    // The second delivery doesn't fit, so the client reads it and then stops; the third stays
+    // in the socket.
+    thread::sleep(Duration::from_millis(500));
+    let stalled = connection.frame_stats();
+    assert_eq!((stalled.header, stalled.body), (2, 2));
+    thread::sleep(STALL);
+    assert_eq!(connection.frame_stats(), stalled);
+    assert_eq!(consumer.receiver().len(), 1);
+
+    for tag in 1..=3 {
+        assert_eq!(recv_delivery(consumer.receiver()).delivery_tag(), tag);
+    }
+
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn overflowing_consumer_closes_channel() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"body");
+        conn.deliver(n, 2, b"body");
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::Close(close))) if ch == n => {
+                assert_eq!(close.reply_code, 406);
+                assert_eq!((close.class_id, close.method_id), (60, 60));
+            }
+            other => panic!("expected channel close, got {:?}", other),
+        }
+        // Sent before we saw the client's close; it must be ignored.
+        conn.deliver(n, 3, b"body");
+        conn.send_method(n, AmqpChannel::CloseOk(ChannelCloseOk {}));
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        no_ack: true,
+        buffer_bound: Some(1),
+        overflow_policy: OverflowPolicy::Error,
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel.basic_consume("q", options).unwrap();
+
+    assert_eq!(recv_delivery(consumer.receiver()).delivery_tag(), 1);
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::ClientClosedChannel) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    assert_eq!(
+        consumer
+            .receiver()
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap_err(),
+        RecvTimeoutError::Disconnected
+    );
+
+    match channel.qos(0, 10, false) {
+        Err(Error::ConsumerBufferOverflow {
+            channel_id,
+            consumer_tag,
+        }) => {
+            assert_eq!(channel_id, channel.channel_id());
+            assert_eq!(consumer_tag, "ctag");
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    drop(consumer);
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,147 @@
+use super::mock_server::MockServer;
+use crate::{Connection, ConsumerMessage, ConsumerOptions, Error};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Cancel, CancelOk};
+use amq_protocol::protocol::AMQPClass;
+use crossbeam_channel::RecvTimeoutError;
+use std::time::Duration;
+
+#[test]
+fn server_cancel_ends_only_that_consumer() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume_as(n, "doomed");
+        conn.accept_consume_as(n, "survivor");
+        conn.deliver_as(n, "doomed", 1, b"body");
+        conn.send_method(
+            n,
+            AmqpBasic::Cancel(Cancel {
+                consumer_tag: "doomed".to_string(),
+                nowait: false,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::CancelOk(cancel_ok))) if ch == n => {
+                assert_eq!(cancel_ok.consumer_tag, "doomed");
+            }
+            other => panic!("expected cancel-ok, got {:?}", other),
+        }
+        conn.deliver_as(n, "survivor", 2, b"body");
+
+        // Dropping the cancelled consumer still tells the server, which ignores the unknown tag.
+        assert_eq!(conn.accept_cancel(n), "doomed");
+        assert_eq!(conn.accept_cancel(n), "survivor");
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        no_ack: true,
+        ..ConsumerOptions::default()
+    };
+    let doomed = channel.basic_consume("q", options.clone()).unwrap();
+    let survivor = channel.basic_consume("q", options).unwrap();
+
+    let timeout = Duration::from_secs(5);
+    match doomed.receiver().recv_timeout(timeout) {
+        Ok(ConsumerMessage::Delivery(delivery)) => assert_eq!(delivery.delivery_tag(), 1),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    match doomed.receiver().recv_timeout(timeout) {
+        Ok(ConsumerMessage::ServerCancelled(consumer_tag)) => assert_eq!(consumer_tag, "doomed"),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    assert_eq!(
+        doomed.receiver().recv_timeout(timeout).unwrap_err(),
+        RecvTimeoutError::Disconnected
+    );
+
+    match survivor.receiver().recv_timeout(timeout) {
+        Ok(ConsumerMessage::Delivery(delivery)) => assert_eq!(delivery.delivery_tag(), 2),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+
+    drop(doomed);
+    drop(survivor);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn client_cancel_drains_deliveries_before_cancel_ok() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume_as(n, "ctag");
+
+        // Deliveries that race with the client's cancel arrive ahead of our cancel-ok.
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Cancel(cancel))) if ch == n => {
+                assert_eq!(cancel.consumer_tag, "ctag");
+            }
+            other => panic!("expected cancel, got {:?}", other),
+        }
+        for tag in 1..=3 {
+            conn.deliver(n, tag, b"body");
+        }
+        conn.send_method(
+            n,
+            AmqpBasic::CancelOk(CancelOk {
+                consumer_tag: "ctag".to_string(),
+            }),
+        );
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                assert_eq!(ack.delivery_tag, 3);
+                assert!(ack.multiple);
+            }
+            other => panic!("expected ack, got {:?}", other),
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+
+    assert!(consumer
+        .recv_timeout(Duration::from_millis(100))
+        .unwrap()
+        .is_none());
+    consumer.cancel().unwrap();
+
+    // Everything is already buffered once cancel returns, so we don't need to wait.
+    let no_wait = Duration::from_secs(0);
+    let mut last = None;
+    for tag in 1..=3 {
+        match consumer.recv_timeout(no_wait) {
+            Ok(Some(ConsumerMessage::Delivery(delivery))) => {
+                assert_eq!(delivery.delivery_tag(), tag);
+                last = Some(delivery);
+            }
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+    }
+    match consumer.recv_timeout(no_wait) {
+        Ok(Some(ConsumerMessage::ClientCancelled)) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    match consumer.recv_timeout(no_wait) {
+        Err(Error::ConsumerEnded { consumer_tag }) => assert_eq!(consumer_tag, "ctag"),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    consumer.ack_multiple(last.unwrap()).unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
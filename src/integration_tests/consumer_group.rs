@@ -0,0 +1,60 @@
+use super::with_conn;
+use crate::{
+    ConsumerGroup, ConsumerGroupMessage, ConsumerGroupOptions, ConsumerMessage, Exchange, Publish,
+    QueueDeclareOptions,
+};
+use std::collections::HashSet;
+use std::slice;
+use std::time::Duration;
+
+#[test]
+fn test_consumer_group_merges_and_acks() {
+    with_conn(|conn| {
+        let chan = conn.open_channel(None).unwrap();
+        let queue = chan
+            .queue_declare("", QueueDeclareOptions::default())
+            .unwrap();
+        let options = ConsumerGroupOptions {
+            channels: 3,
+            tag_prefix: Some("amiquip-test-group".to_string()),
+            prefetch_count: Some(1),
+            ..ConsumerGroupOptions::default()
+        };
+        let group = ConsumerGroup::start(slice::from_mut(conn), queue.name(), options).unwrap();
+        assert_eq!(group.running_members(), vec![0, 1, 2]);
+
+        let exchange = Exchange::direct(&chan);
+        for i in 0..30u32 {
+            exchange
+                .publish(Publish::new(&i.to_be_bytes(), queue.name()))
+                .unwrap();
+        }
+
+        let mut bodies = HashSet::new();
+        while bodies.len() < 30 {
+            match group.receiver().recv_timeout(Duration::from_secs(5)) {
+                Ok(ConsumerGroupMessage::Delivery(delivery)) => {
+                    assert!(delivery.member() < 3);
+                    bodies.insert(delivery.delivery.body.clone());
+                    group.ack(delivery).unwrap();
+                }
+                other => panic!("unexpected group message {:?}", other),
+            }
+        }
+
+        // Deleting the queue cancels every member, but the group itself stays usable.
+        queue.delete(Default::default()).unwrap();
+        let mut stopped = HashSet::new();
+        while stopped.len() < 3 {
+            match group.receiver().recv_timeout(Duration::from_secs(5)) {
+                Ok(ConsumerGroupMessage::MemberStopped {
+                    member,
+                    reason: ConsumerMessage::ServerCancelled(_),
+                }) => {
+                    stopped.insert(member);
+                }
+                other => panic!("unexpected group message {:?}", other),
+            }
+        }
+    })
+}
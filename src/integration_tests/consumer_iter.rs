@@ -0,0 +1,201 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{AmqpProperties, Connection, ConsumerMessage, ConsumerOptions, IterAckMode};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::Cancel;
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::AMQPClass;
+use std::panic::{self, AssertUnwindSafe};
+
+#[test]
+fn ack_on_drop_nacks_on_panic_and_ends_on_server_cancel() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"body");
+        conn.deliver(n, 2, b"body");
+
+        assert_eq!(conn.recv_single_ack(n), 1);
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                assert_eq!(nack.delivery_tag, 2);
+                assert!(nack.requeue);
+            }
+            other => panic!("expected nack, got {:?}", other),
+        }
+
+        conn.send_method(
+            n,
+            AmqpBasic::Cancel(Cancel {
+                consumer_tag: "ctag".to_string(),
+                nowait: false,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::CancelOk(_))) if ch == n => (),
+            other => panic!("expected cancel-ok, got {:?}", other),
+        }
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        for delivery in consumer.iter_ack(IterAckMode::AckOnDrop) {
+            assert_eq!(&delivery.body[..], b"body");
+            if delivery.delivery_tag() == 2 {
+                panic!("handler failed");
+            }
+        }
+    }));
+    assert!(result.is_err());
+    assert!(consumer.termination().is_none());
+
+    assert!(consumer.iter_ack(IterAckMode::AckOnDrop).next().is_none());
+    match consumer.termination() {
+        Some(ConsumerMessage::ServerCancelled(consumer_tag)) => assert_eq!(consumer_tag, "ctag"),
+        other => panic!("unexpected termination {:?}", other),
+    }
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn ack_before_yield_next_acks_previous_delivery() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"body");
+        conn.deliver(n, 2, b"body");
+
+        // Delivery 1 is acked when delivery 2 is requested, and delivery 2 when the iterator is
+        // dropped, even though the client still holds both.
+        assert_eq!(conn.recv_single_ack(n), 1);
+        assert_eq!(conn.recv_single_ack(n), 2);
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+
+    let mut iter = consumer.iter_ack(IterAckMode::AckBeforeYieldNext);
+    let first = iter.next().unwrap();
+    let second = iter.next().unwrap();
+    drop(iter);
+    assert_eq!(first.delivery_tag(), 1);
+    assert_eq!(second.delivery_tag(), 2);
+
+    // Both were acked already; explicit acks are no-ops.
+    first.ack().unwrap();
+    second.ack().unwrap();
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn manual_mode_leaves_settling_to_the_caller() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for tag in 1..=3 {
+            conn.deliver(n, tag, b"body");
+        }
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Reject(reject))) if ch == n => {
+                assert_eq!(reject.delivery_tag, 1);
+                assert!(!reject.requeue);
+            }
+            other => panic!("expected reject, got {:?}", other),
+        }
+        // Delivery 2 is dropped without being settled.
+        assert_eq!(conn.recv_single_ack(n), 3);
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+
+    let mut iter = consumer.iter_ack(IterAckMode::Manual);
+    iter.next().unwrap().reject(false).unwrap();
+    drop(iter.next().unwrap());
+    let third = iter.next().unwrap().into_delivery();
+    assert_eq!(third.delivery_tag(), 3);
+    consumer.ack(third).unwrap();
+    drop(iter);
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn streamed_delivery_cut_off_mid_body_ends_iteration() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.send_method(n, AmqpBasic::Deliver(ServerConn::deliver_method(1)));
+        conn.send_content_header(n, 64, &AmqpProperties::default());
+        conn.send_content_body(n, b"partial");
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 320,
+                reply_text: "CONNECTION_FORCED".to_string(),
+                class_id: 0,
+                method_id: 0,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default().stream_bodies(16))
+        .unwrap();
+
+    // The half-read delivery is given back rather than yielded, and the channel's close ends the
+    // iteration.
+    assert!(consumer.iter_ack(IterAckMode::AckOnDrop).next().is_none());
+    match consumer.termination() {
+        Some(ConsumerMessage::ServerClosedChannel(_)) => (),
+        other => panic!("unexpected termination {:?}", other),
+    }
+
+    drop(consumer);
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,134 @@
+use super::mock_server::{MockServer, ServerConn};
+use super::with_conn;
+use crate::{
+    AmqpReplyCode, AmqpValue, Connection, ConsumerMessage, ConsumerOptions, Error, Publish,
+    QueueDeclareOptions, QueueDeleteOptions,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Consume, ConsumeOk};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+fn recv_consume(conn: &mut ServerConn, channel_id: u16) -> Consume {
+    match conn.recv_method() {
+        (ch, AMQPClass::Basic(AmqpBasic::Consume(consume))) if ch == channel_id => consume,
+        other => panic!("expected consume, got {:?}", other),
+    }
+}
+
+#[test]
+fn second_exclusive_consumer_fails_with_reply_code() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let first = conn.accept_channel();
+        let consume = recv_consume(&mut conn, first);
+        assert!(consume.exclusive);
+        assert_eq!(
+            consume.arguments.get("x-priority"),
+            Some(&AmqpValue::LongInt(10))
+        );
+        conn.send_method(
+            first,
+            AmqpBasic::ConsumeOk(ConsumeOk {
+                consumer_tag: "ctag".to_string(),
+            }),
+        );
+        conn.deliver(first, 1, b"body");
+
+        let second = conn.accept_channel();
+        recv_consume(&mut conn, second);
+        conn.send_method(
+            second,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 403,
+                reply_text: "ACCESS_REFUSED - queue 'q' in vhost '/' in exclusive use"
+                    .to_string(),
+                class_id: 60,
+                method_id: 20,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == second => (),
+            other => panic!("expected channel.close-ok, got {:?}", other),
+        }
+
+        // The first consumer is unaffected.
+        conn.deliver(first, 2, b"body");
+        conn.accept_cancel(first);
+        conn.accept_channel_close(first);
+        conn.accept_connection_close();
+    });
+
+    let options = ConsumerOptions {
+        no_ack: true,
+        exclusive: true,
+        priority: Some(10),
+        ..ConsumerOptions::default()
+    };
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel.basic_consume("q", options.clone()).unwrap();
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => assert_eq!(delivery.delivery_tag(), 1),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+
+    let other_channel = connection.open_channel(None).unwrap();
+    match other_channel.basic_consume("q", options) {
+        Err(Error::ServerClosedChannel {
+            reply_code: AmqpReplyCode::AccessRefused,
+            method: Some("basic.consume"),
+            ..
+        }) => (),
+        Err(err) => panic!("unexpected error {:?}", err),
+        Ok(_) => panic!("second exclusive consumer unexpectedly started"),
+    }
+    drop(other_channel);
+
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => assert_eq!(delivery.delivery_tag(), 2),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_exclusive_consumer() {
+    with_conn(|conn| {
+        let chan = conn.open_channel(None).unwrap();
+        let queue = chan
+            .queue_declare("", QueueDeclareOptions::default())
+            .unwrap();
+        let options = ConsumerOptions {
+            no_ack: true,
+            exclusive: true,
+            priority: Some(5),
+            ..ConsumerOptions::default()
+        };
+        let consumer = queue.consume(options.clone()).unwrap();
+
+        let other_chan = conn.open_channel(None).unwrap();
+        let err = match other_chan.basic_consume(queue.name(), options) {
+            Ok(_) => panic!("second exclusive consumer unexpectedly started"),
+            Err(err) => err,
+        };
+        assert_eq!(err.reply_code(), Some(AmqpReplyCode::AccessRefused));
+        drop(other_chan);
+
+        chan.basic_publish("", Publish::new(b"still here", queue.name()))
+            .unwrap();
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => assert_eq!(delivery.body, b"still here"),
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+        consumer.cancel().unwrap();
+        drop(consumer);
+        queue.delete(QueueDeleteOptions::default()).unwrap();
+    })
+}
@@ -0,0 +1,122 @@
+use super::mock_server::{recv_delivery, MockServer, ServerConn};
+use crate::{Connection, ConsumerMessage, ConsumerOptions};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::QosOk;
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+// The client calls `qos` to tell us it has paused; our reply follows the deliveries we send
+// after it, so once the client has it, those deliveries have reached its I/O thread.
+fn accept_qos(conn: &mut ServerConn, channel_id: u16) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Basic(AmqpBasic::Qos(_))) if ch == channel_id => (),
+        other => panic!("expected qos, got {:?}", other),
+    }
+    conn.send_method(channel_id, AmqpBasic::QosOk(QosOk {}));
+}
+
+#[test]
+fn paused_consumer_holds_deliveries_until_resumed() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"body");
+        accept_qos(&mut conn, n);
+        for tag in 2..=4 {
+            conn.deliver(n, tag, b"body");
+        }
+        accept_qos(&mut conn, n);
+
+        // Acking a delivery received before pausing works while paused.
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                assert_eq!(ack.delivery_tag, 1)
+            }
+            other => panic!("expected ack, got {:?}", other),
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        buffer_bound: Some(1),
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel.basic_consume("q", options).unwrap();
+    let first = match consumer.recv_timeout(Duration::from_secs(5)) {
+        Ok(Some(ConsumerMessage::Delivery(delivery))) => delivery,
+        other => panic!("unexpected consumer message {:?}", other),
+    };
+
+    consumer.pause().unwrap();
+    channel.qos(0, 10, false).unwrap();
+    channel.qos(0, 10, false).unwrap();
+    assert!(consumer.receiver().is_empty());
+    consumer.ack(first).unwrap();
+
+    // The buffer only has room for one, so the rest are handed over as we make room.
+    consumer.resume().unwrap();
+    for tag in 2..=4 {
+        assert_eq!(recv_delivery(consumer.receiver()).delivery_tag(), tag);
+    }
+
+    // Leak the consumer so only the channel close ends it.
+    std::mem::forget(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn cancelling_paused_consumer_requeues_what_does_not_fit() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        accept_qos(&mut conn, n);
+        for tag in 1..=3 {
+            conn.deliver(n, tag, b"body");
+        }
+        accept_qos(&mut conn, n);
+
+        conn.accept_cancel(n);
+        for tag in 2..=3 {
+            match conn.recv_method() {
+                (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                    assert_eq!(nack.delivery_tag, tag);
+                    assert!(!nack.multiple);
+                    assert!(nack.requeue);
+                }
+                other => panic!("expected nack, got {:?}", other),
+            }
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        buffer_bound: Some(1),
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel.basic_consume("q", options).unwrap();
+    consumer.pause().unwrap();
+    channel.qos(0, 10, false).unwrap();
+    channel.qos(0, 10, false).unwrap();
+
+    consumer.cancel().unwrap();
+    assert_eq!(recv_delivery(consumer.receiver()).delivery_tag(), 1);
+    match consumer.recv_timeout(Duration::from_secs(5)) {
+        Ok(Some(ConsumerMessage::ClientCancelled)) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    drop(consumer);
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
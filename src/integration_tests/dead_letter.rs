@@ -0,0 +1,183 @@
+use super::mock_server::MockServer;
+use super::with_chan;
+use crate::{
+    AmqpProperties, AmqpValue, Channel, Connection, ConsumerMessage, ConsumerOptions,
+    ExchangeDeclareOptions, ExchangeType, FieldTable, Get, Publish, QueueArguments,
+    QueueDeclareOptions,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::Deliver;
+use amq_protocol::protocol::AMQPClass;
+use std::thread;
+use std::time::Duration;
+
+fn get_eventually(chan: &Channel, queue: &str) -> Get {
+    // Dead-lettering happens asynchronously on the server.
+    for _ in 0..100 {
+        if let Some(get) = chan.basic_get(queue, false).unwrap() {
+            return get;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("no message arrived on {}", queue);
+}
+
+#[test]
+fn test_death_history_after_reject() {
+    with_chan(|chan| {
+        let exclusive = QueueDeclareOptions {
+            exclusive: true,
+            ..QueueDeclareOptions::default()
+        };
+        let dead = chan.queue_declare("", exclusive.clone()).unwrap();
+        let work = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    arguments: QueueArguments::new()
+                        .dead_letter_exchange("")
+                        .dead_letter_routing_key(dead.name())
+                        .build()
+                        .unwrap(),
+                    ..exclusive
+                },
+            )
+            .unwrap();
+
+        chan.basic_publish("", Publish::new(b"job", work.name()))
+            .unwrap();
+        let get = get_eventually(chan, work.name());
+        assert!(get.delivery.death_history().unwrap().is_empty());
+        assert_eq!(get.delivery.delivery_count().unwrap(), None);
+        get.reject(chan, false).unwrap();
+
+        let get = get_eventually(chan, dead.name());
+        let history = get.delivery.death_history().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].queue, work.name());
+        assert_eq!(history[0].reason, "rejected");
+        assert_eq!(history[0].count, 1);
+        assert_eq!(history[0].routing_keys, vec![work.name().to_string()]);
+        assert!(history[0].time.is_some());
+        get.ack(chan).unwrap();
+    })
+}
+
+#[test]
+fn dead_letter_with_reason_publishes_then_acks() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.send_method(
+            n,
+            AmqpBasic::Deliver(Deliver {
+                consumer_tag: "ctag".to_string(),
+                delivery_tag: 1,
+                redelivered: false,
+                exchange: "jobs".to_string(),
+                routing_key: "jobs.resize".to_string(),
+            }),
+        );
+        let properties = AmqpProperties::default().with_correlation_id("corr".to_string());
+        conn.send_content(n, b"job", &properties);
+
+        let (publish, properties, body) = conn.recv_publish_with_properties(n);
+        assert_eq!(publish.exchange, "dlx");
+        assert_eq!(publish.routing_key, "jobs.resize");
+        assert_eq!(body, b"job");
+        assert_eq!(properties.correlation_id(), &Some("corr".to_string()));
+        let header = |name: &str| properties.headers().as_ref().unwrap().get(name).cloned();
+        let string = |s: &str| Some(AmqpValue::LongString(s.to_string()));
+        assert_eq!(header("x-rejection-reason"), string("image too large"));
+        assert_eq!(header("x-original-exchange"), string("jobs"));
+        assert_eq!(header("x-original-routing-key"), string("jobs.resize"));
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                assert_eq!(ack.delivery_tag, 1);
+            }
+            other => panic!("expected ack, got {:?}", other),
+        }
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("jobs", ConsumerOptions::default())
+        .unwrap();
+    let delivery = match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => delivery,
+        other => panic!("unexpected consumer message {:?}", other),
+    };
+    delivery
+        .dead_letter_with_reason(&channel, "dlx", "image too large")
+        .unwrap();
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_dead_letter_with_reason() {
+    with_chan(|chan| {
+        let dlx = chan
+            .exchange_declare(
+                ExchangeType::Fanout,
+                "amiquip-test-dead-letter-with-reason",
+                ExchangeDeclareOptions {
+                    auto_delete: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let exclusive = QueueDeclareOptions {
+            exclusive: true,
+            ..QueueDeclareOptions::default()
+        };
+        let dead = chan.queue_declare("", exclusive.clone()).unwrap();
+        dead.bind(&dlx, "", FieldTable::new()).unwrap();
+        let work = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    arguments: QueueArguments::new()
+                        .dead_letter_exchange(dlx.name())
+                        .build()
+                        .unwrap(),
+                    ..exclusive
+                },
+            )
+            .unwrap();
+
+        chan.basic_publish("", Publish::new(b"job", work.name()))
+            .unwrap();
+        let get = get_eventually(chan, work.name());
+        get.delivery
+            .dead_letter_with_reason(chan, dlx.name(), "bad input")
+            .unwrap();
+
+        let get = get_eventually(chan, dead.name());
+        let headers = get.delivery.properties.headers().clone().unwrap();
+        let string = |s: &str| Some(AmqpValue::LongString(s.to_string()));
+        assert_eq!(
+            headers.get("x-rejection-reason").cloned(),
+            string("bad input")
+        );
+        assert_eq!(headers.get("x-original-exchange").cloned(), string(""));
+        assert_eq!(
+            headers.get("x-original-routing-key").cloned(),
+            string(work.name())
+        );
+        // It was republished rather than rejected, so the server has no death history for it.
+        assert!(get.delivery.death_history().unwrap().is_empty());
+        get.ack(chan).unwrap();
+
+        assert!(chan.basic_get(work.name(), true).unwrap().is_none());
+    })
+}
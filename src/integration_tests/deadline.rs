@@ -0,0 +1,96 @@
+use super::mock_server::MockServer;
+use crate::{with_deadline, Connection, Error, Exchange, Publish, QueueDeclareOptions};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::queue::DeclareOk;
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+#[test]
+fn abandoned_call_reply_is_discarded() {
+    let (gave_up_tx, gave_up_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for name in &["slow", "fast"] {
+            match conn.recv_method() {
+                (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                    assert_eq!(&declare.queue, name)
+                }
+                other => panic!("expected queue declare, got {:?}", other),
+            }
+            if *name == "slow" {
+                // Only answer once the client has stopped waiting.
+                gave_up_rx.recv().unwrap();
+            }
+            conn.send_method(
+                n,
+                AmqpQueue::DeclareOk(DeclareOk {
+                    queue: name.to_string(),
+                    message_count: 0,
+                    consumer_count: 0,
+                }),
+            );
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let result = with_deadline(Duration::from_millis(200), || {
+        channel
+            .queue_declare("slow", QueueDeclareOptions::default())
+            .map(|queue| queue.name().to_string())
+    });
+    match result {
+        Err(Error::DeadlineExceeded) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    gave_up_tx.send(()).unwrap();
+
+    // The late reply to "slow" must not be mistaken for the reply to "fast".
+    let queue = channel
+        .queue_declare("fast", QueueDeclareOptions::default())
+        .unwrap();
+    assert_eq!(queue.name(), "fast");
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn publish_after_deadline_is_not_sent() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Publish(publish))) if ch == n => {
+                assert_eq!(publish.routing_key, "sent")
+            }
+            other => panic!("expected publish, got {:?}", other),
+        }
+        // content header and body
+        conn.recv_frame();
+        conn.recv_frame();
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let exchange = Exchange::direct(&channel);
+    let result = with_deadline(Duration::from_secs(0), || {
+        exchange.publish(Publish::new(b"late", "dropped"))
+    });
+    match result {
+        Err(Error::DeadlineExceeded) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    exchange.publish(Publish::new(b"on time", "sent")).unwrap();
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,154 @@
+use super::mock_server::MockServer;
+use crate::{
+    AmqpReplyCode, AmqpValue, Connection, Error, ExchangeDeclareOptions, ExchangeType, Publish,
+};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::exchange::AMQPMethod as AmqpExchange;
+use amq_protocol::protocol::exchange::DeclareOk;
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+#[cfg(feature = "consume")]
+use super::with_chan;
+#[cfg(feature = "consume")]
+use crate::{QueueDeclareOptions, QueueDeleteOptions};
+#[cfg(feature = "consume")]
+use std::env;
+#[cfg(feature = "consume")]
+use std::time::Instant;
+
+#[test]
+fn delayed_exchange_declare_and_missing_plugin() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == n => {
+                assert_eq!(declare.exchange, "later");
+                assert_eq!(declare.type_, "x-delayed-message");
+                assert_eq!(
+                    declare.arguments.get("x-delayed-type"),
+                    Some(&AmqpValue::LongString("topic".to_string()))
+                );
+            }
+            other => panic!("expected exchange.declare, got {:?}", other),
+        }
+        conn.send_method(n, AmqpExchange::DeclareOk(DeclareOk {}));
+
+        let (publish, properties, _) = conn.recv_publish_with_properties(n);
+        assert_eq!(publish.exchange, "later");
+        assert_eq!(
+            properties
+                .headers()
+                .as_ref()
+                .and_then(|headers| headers.get("x-delay")),
+            Some(&AmqpValue::LongLongInt(1500))
+        );
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == n => {
+                assert_eq!(declare.type_, "x-delayed-message");
+            }
+            other => panic!("expected exchange.declare, got {:?}", other),
+        }
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 503,
+                reply_text: "COMMAND_INVALID - unknown exchange type 'x-delayed-message'"
+                    .to_string(),
+                class_id: 40,
+                method_id: 10,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel.close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let exchange = channel
+        .exchange_declare(
+            ExchangeType::Direct,
+            "later",
+            ExchangeDeclareOptions::delayed(ExchangeType::Topic),
+        )
+        .unwrap();
+    exchange
+        .publish(Publish::new(b"hello", "greetings").with_delay(Duration::from_millis(1500)))
+        .unwrap();
+
+    let err = match channel.exchange_declare(
+        ExchangeType::Direct,
+        "later",
+        ExchangeDeclareOptions::delayed(ExchangeType::Direct),
+    ) {
+        Ok(_) => panic!("declare without plugin unexpectedly succeeded"),
+        Err(err) => err,
+    };
+    assert_eq!(err.reply_code(), Some(AmqpReplyCode::CommandInvalid));
+    match &err {
+        Error::DelayedMessagePluginMissing { exchange, .. } => assert_eq!(exchange, "later"),
+        other => panic!("unexpected error {:?}", other),
+    }
+    assert!(err
+        .to_string()
+        .contains("rabbitmq_delayed_message_exchange"));
+
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
+
+// Requires a server with the rabbitmq_delayed_message_exchange plugin enabled; set
+// AMIQUIP_TEST_DELAYED_EXCHANGE (as well as AMIQUIP_TEST_URL) to run it.
+#[cfg(feature = "consume")]
+#[test]
+fn delayed_message_is_routed_after_delay() {
+    if env::var_os("AMIQUIP_TEST_DELAYED_EXCHANGE").is_none() {
+        return;
+    }
+    with_chan(|chan| {
+        let exchange = chan
+            .exchange_declare(
+                ExchangeType::Direct,
+                "amiquip-test-delayed",
+                ExchangeDeclareOptions {
+                    auto_delete: true,
+                    ..ExchangeDeclareOptions::delayed(ExchangeType::Direct)
+                },
+            )
+            .unwrap();
+        let queue = chan
+            .queue_declare("", QueueDeclareOptions::default())
+            .unwrap();
+        queue
+            .bind(&exchange, queue.name(), Default::default())
+            .unwrap();
+
+        let delay = Duration::from_millis(1000);
+        let published = Instant::now();
+        exchange
+            .publish(Publish::new(b"delayed", queue.name()).with_delay(delay))
+            .unwrap();
+        assert!(queue.get(true).unwrap().is_none());
+
+        loop {
+            if let Some(get) = queue.get(true).unwrap() {
+                assert_eq!(get.delivery.body, b"delayed");
+                assert!(published.elapsed() >= delay / 2);
+                break;
+            }
+            assert!(
+                published.elapsed() < delay * 10,
+                "delayed message never arrived"
+            );
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        queue.delete(QueueDeleteOptions::default()).unwrap();
+    })
+}
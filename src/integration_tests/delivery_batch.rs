@@ -0,0 +1,76 @@
+use super::mock_server::{recv_delivery, MockServer};
+use crate::{Connection, ConsumerOptions};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::AMQPClass;
+use std::panic::{self, AssertUnwindSafe};
+
+const DELIVERIES: u64 = 25;
+const BATCH_SIZE: usize = 10;
+
+#[test]
+fn batch_settles_with_one_frame_per_batch() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for tag in 1..=DELIVERIES {
+            conn.deliver(n, tag, b"body");
+        }
+
+        // Two full batches are acked; the remainder is requeued when the panicking batch drops.
+        for expected in &[10, 20] {
+            match conn.recv_method() {
+                (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                    assert_eq!(ack.delivery_tag, *expected);
+                    assert!(ack.multiple);
+                }
+                other => panic!("expected ack, got {:?}", other),
+            }
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                assert_eq!(nack.delivery_tag, DELIVERIES);
+                assert!(nack.multiple);
+                assert!(nack.requeue);
+            }
+            other => panic!("expected nack, got {:?}", other),
+        }
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+
+    let mut batch = consumer.batch();
+    for _ in 0..2 * BATCH_SIZE {
+        batch.push(recv_delivery(consumer.receiver())).unwrap();
+        if batch.len() >= BATCH_SIZE {
+            batch.ack().unwrap();
+        }
+    }
+    assert!(batch.is_empty());
+    drop(batch);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut batch = consumer.batch();
+        for _ in 2 * BATCH_SIZE as u64..DELIVERIES {
+            batch.push(recv_delivery(consumer.receiver())).unwrap();
+        }
+        panic!(
+            "handler failed with {} deliveries in the batch",
+            batch.len()
+        );
+    }));
+    assert!(result.is_err());
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,197 @@
+use super::mock_server::{MockServer, ServerConn, DEFAULT_TUNE};
+use crate::{AmqpProperties, Connection, ConsumerMessage, ConsumerOptions, DeliveryStream, Error};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::connection::Tune;
+use amq_protocol::protocol::AMQPClass;
+use crossbeam_channel::Receiver;
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+fn recv_stream(rx: &Receiver<ConsumerMessage>) -> DeliveryStream {
+    match rx.recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::DeliveryStream(stream)) => stream,
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+}
+
+#[test]
+fn streamed_body_is_read_while_connection_is_serviced() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            heartbeat: 1,
+            ..DEFAULT_TUNE
+        });
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.send_method(n, AmqpBasic::Deliver(ServerConn::deliver_method(1)));
+        conn.send_content_header(n, 10, &AmqpProperties::default());
+        conn.send_content_body(n, b"hello");
+
+        // With half the body delivered, the client can still use other channels...
+        let other = conn.accept_channel();
+
+        // ...and keeps heartbeating while its consumer waits for the rest.
+        conn.stream()
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut last_sent = Instant::now();
+        loop {
+            assert!(Instant::now() < deadline, "never received a heartbeat");
+            if last_sent.elapsed() >= Duration::from_millis(500) {
+                conn.send_heartbeat();
+                last_sent = Instant::now();
+            }
+            match conn.try_recv_frame() {
+                Some(AMQPFrame::Heartbeat(_)) => break,
+                Some(frame) => panic!("unexpected frame {:?}", frame),
+                None => (),
+            }
+        }
+        conn.stream()
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        conn.send_content_body(n, b"world");
+        // Deliveries under the threshold are still collected as usual.
+        conn.deliver(n, 2, b"tiny");
+        for delivery_tag in 1..=2 {
+            assert_eq!(conn.recv_ack(n).delivery_tag, delivery_tag);
+        }
+
+        conn.accept_channel_close(other);
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("big", ConsumerOptions::default().stream_bodies(8))
+        .unwrap();
+
+    let mut stream = recv_stream(consumer.receiver());
+    assert_eq!(stream.delivery_tag(), 1);
+    assert_eq!(stream.body_size(), 10);
+    assert_eq!(stream.delivery().routing_key, "q");
+    let mut hello = [0; 5];
+    stream.read_exact(&mut hello).unwrap();
+    assert_eq!(&hello, b"hello");
+    assert!(!stream.is_finished());
+
+    let other = connection.open_channel(None).unwrap();
+
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"world");
+    assert!(stream.is_finished());
+    stream.ack(&channel).unwrap();
+
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => {
+            assert_eq!(delivery.body, b"tiny");
+            consumer.ack(delivery).unwrap();
+        }
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+
+    other.close().unwrap();
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn streams_settle_only_when_finished_or_abandoned() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        for delivery_tag in 1..=2 {
+            conn.send_method(
+                n,
+                AmqpBasic::Deliver(ServerConn::deliver_method(delivery_tag)),
+            );
+            conn.send_content_header(n, 10, &AmqpProperties::default());
+            conn.send_content_body(n, b"hello");
+            conn.send_content_body(n, b"world");
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Reject(reject))) if ch == n => {
+                assert_eq!(reject.delivery_tag, 2);
+                assert!(reject.requeue);
+            }
+            other => panic!("expected reject, got {:?}", other),
+        }
+
+        // The channel closing mid-body cuts the stream short.
+        conn.send_method(n, AmqpBasic::Deliver(ServerConn::deliver_method(3)));
+        conn.send_content_header(n, 10, &AmqpProperties::default());
+        conn.send_content_body(n, b"hello");
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 320,
+                reply_text: "CONNECTION_FORCED".to_string(),
+                class_id: 0,
+                method_id: 0,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("big", ConsumerOptions::default().stream_bodies(8))
+        .unwrap();
+
+    let stream = recv_stream(consumer.receiver());
+    match stream.ack(&channel) {
+        Err(Error::DeliveryStreamUnfinished {
+            delivery_tag,
+            bytes_read,
+            body_size,
+        }) => assert_eq!((delivery_tag, bytes_read, body_size), (1, 0, 10)),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut stream = recv_stream(consumer.receiver());
+    let mut buf = [0; 3];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(stream.bytes_read(), 3);
+    stream.abandon();
+    assert!(stream.is_finished());
+    assert_eq!(
+        stream.read(&mut buf).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+    stream.reject(&channel, true).unwrap();
+
+    let mut stream = recv_stream(consumer.receiver());
+    let mut body = Vec::new();
+    let err = stream.read_to_end(&mut body).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(body, b"hello");
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::ServerClosedChannel(Error::ServerClosedChannel { code, .. })) => {
+            assert_eq!(code, 320);
+        }
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+
+    drop(consumer);
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,320 @@
+use super::mock_server::{MockServer, ServerConn, DEFAULT_TUNE};
+use super::with_conn;
+use crate::{
+    Connection, ConnectionTuning, ConsumerMessage, ConsumerOptions, Error, Exchange, Publish,
+    QueueDeclareOptions,
+};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::CancelOk;
+use amq_protocol::protocol::connection::Tune;
+use amq_protocol::protocol::AMQPClass;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// With a 1 second heartbeat, the client gives up on the server after 2 silent seconds and the
+// server expects a client heartbeat every second; a handler sleeping this long would kill the
+// connection several times over if it ran on the I/O thread.
+const HANDLER_SLEEP: Duration = Duration::from_secs(5);
+
+fn thread_name() -> String {
+    thread::current().name().unwrap_or("").to_string()
+}
+
+#[test]
+fn slow_callback_does_not_stall_heartbeats() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            heartbeat: 1,
+            ..DEFAULT_TUNE
+        });
+        let n = conn.accept_channel();
+        conn.accept_consume_as(n, "ctag");
+        conn.deliver(n, 1, b"slow");
+
+        // Keep the client alive with our own heartbeats and count theirs until the handler
+        // finishes sleeping and acks.
+        conn.stream()
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let deadline = Instant::now() + HANDLER_SLEEP + Duration::from_secs(5);
+        let mut last_sent = Instant::now();
+        let mut heartbeats = 0;
+        loop {
+            assert!(Instant::now() < deadline, "never received ack");
+            if last_sent.elapsed() >= Duration::from_millis(500) {
+                conn.send_heartbeat();
+                last_sent = Instant::now();
+            }
+            match conn.try_recv_frame() {
+                Some(AMQPFrame::Heartbeat(_)) => heartbeats += 1,
+                Some(AMQPFrame::Method(ch, AMQPClass::Basic(AmqpBasic::Ack(ack)))) => {
+                    assert_eq!(ch, n);
+                    assert_eq!(ack.delivery_tag, 1);
+                    break;
+                }
+                Some(frame) => panic!("unexpected frame {:?}", frame),
+                None => (),
+            }
+        }
+        assert!(
+            heartbeats >= HANDLER_SLEEP.as_secs() - 1,
+            "only received {} heartbeats while handler slept",
+            heartbeats
+        );
+        conn.stream()
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let consumer = channel
+        .basic_consume_with_callback("q", ConsumerOptions::default(), move |message, acker| {
+            if let ConsumerMessage::Delivery(delivery) = message {
+                thread::sleep(HANDLER_SLEEP);
+                acker.ack(delivery).unwrap();
+                tx.send(thread_name()).unwrap();
+            }
+        })
+        .unwrap();
+    let handler_thread = rx.recv_timeout(HANDLER_SLEEP * 2).unwrap();
+    assert!(
+        handler_thread.starts_with("amiquip-dispatch-"),
+        "callback ran on {:?}",
+        handler_thread
+    );
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn callbacks_run_in_order_per_consumer() {
+    const DELIVERIES: u64 = 20;
+    let tags = ["ctag-0", "ctag-1"];
+
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for tag in &tags {
+            conn.accept_consume_as(n, tag);
+        }
+        for i in 0..DELIVERIES {
+            let tag = tags[i as usize % tags.len()];
+            conn.deliver_as(n, tag, i + 1, &i.to_be_bytes());
+        }
+        for _ in &tags {
+            conn.accept_cancel(n);
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let tuning = ConnectionTuning::default().dispatch_threads(2);
+    let mut connection = Connection::insecure_open_tuned(&server.url(), tuning).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let options = ConsumerOptions {
+        no_ack: true,
+        ..ConsumerOptions::default()
+    };
+    let consumers = (0..tags.len())
+        .map(|_| {
+            let tx = tx.clone();
+            let mut first = true;
+            channel
+                .basic_consume_with_callback("q", options.clone(), move |message, _| {
+                    if let ConsumerMessage::Delivery(delivery) = message {
+                        // stall the first delivery so later ones would overtake it if
+                        // dispatch were not ordered
+                        if first {
+                            thread::sleep(Duration::from_millis(200));
+                            first = false;
+                        }
+                        let mut body = [0; 8];
+                        body.copy_from_slice(&delivery.body);
+                        tx.send((thread_name(), u64::from_be_bytes(body))).unwrap();
+                    }
+                })
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let mut seen = vec![Vec::new(); tags.len()];
+    let mut threads = vec![None; tags.len()];
+    for _ in 0..DELIVERIES {
+        let (thread, i) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let consumer = i as usize % tags.len();
+        assert!(thread.starts_with("amiquip-dispatch-"));
+        // every callback for a consumer runs on the same dispatch thread
+        assert_eq!(threads[consumer].get_or_insert(thread.clone()), &thread);
+        seen[consumer].push(i);
+    }
+    for (consumer, seen) in seen.iter().enumerate() {
+        let expected = (0..DELIVERIES)
+            .filter(|i| *i as usize % tags.len() == consumer)
+            .collect::<Vec<_>>();
+        assert_eq!(seen, &expected);
+    }
+    // two dispatch threads, two consumers: they should not share a thread
+    assert_ne!(threads[0], threads[1]);
+
+    drop(consumers);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_consume_with_callback() {
+    with_conn(|conn| {
+        let chan = conn.open_channel(None).unwrap();
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..QueueDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let consumer = queue
+            .consume_with_callback(
+                ConsumerOptions::default(),
+                move |message, acker| match message {
+                    ConsumerMessage::Delivery(delivery) => {
+                        tx.send(Some(delivery.body.clone())).unwrap();
+                        acker.ack(delivery).unwrap();
+                    }
+                    _ => tx.send(None).unwrap(),
+                },
+            )
+            .unwrap();
+        Exchange::direct(&chan)
+            .publish(Publish::new(b"hello", queue.name()))
+            .unwrap();
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            Some(b"hello".to_vec())
+        );
+        consumer.cancel().unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), None);
+    })
+}
+
+#[test]
+fn delivery_from_closed_channel_is_not_acked_on_reopened_channel() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume_as(n, "ctag");
+        conn.deliver(n, 1, b"stale");
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+
+        // The reopened channel gets the same id; any ack of the stale delivery would arrive
+        // before the close and fail this expectation.
+        assert_eq!(conn.accept_channel(), n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let channel_id = channel.channel_id();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let delivery = match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => delivery,
+        other => panic!("unexpected consumer message {:?}", other),
+    };
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+
+    let channel = connection.open_channel(Some(channel_id)).unwrap();
+    match delivery.ack(&channel) {
+        Err(Error::StaleDelivery {
+            channel_id: id,
+            delivery_tag,
+        }) => {
+            assert_eq!(id, channel_id);
+            assert_eq!(delivery_tag, 1);
+        }
+        other => panic!("unexpected ack result {:?}", other),
+    }
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn panicking_callback_requeues_and_cancels() {
+    let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume_as(n, "ctag");
+        conn.deliver(n, 1, b"boom");
+
+        let recv_requeue = |conn: &mut ServerConn, delivery_tag| match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                assert_eq!(nack.delivery_tag, delivery_tag);
+                assert!(nack.requeue && !nack.multiple);
+            }
+            other => panic!("expected nack, got {:?}", other),
+        };
+        recv_requeue(&mut conn, 1);
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Cancel(cancel))) if ch == n => {
+                assert_eq!(cancel.consumer_tag, "ctag");
+            }
+            other => panic!("expected cancel, got {:?}", other),
+        }
+        // A delivery already on its way when the cancel arrived goes back too.
+        conn.deliver(n, 2, b"late");
+        recv_requeue(&mut conn, 2);
+        conn.send_method(
+            n,
+            AmqpBasic::CancelOk(CancelOk {
+                consumer_tag: "ctag".to_string(),
+            }),
+        );
+        done_tx.send(()).unwrap();
+
+        // Dropping the `CallbackConsumer` still cancels it.
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let (tx, rx) = crossbeam_channel::unbounded();
+    let consumer = channel
+        .basic_consume_with_callback("q", ConsumerOptions::default(), move |message, _acker| {
+            if let ConsumerMessage::Delivery(delivery) = message {
+                tx.send(delivery.delivery_tag()).unwrap();
+                panic!("callback failed");
+            }
+        })
+        .unwrap();
+    done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    // The callback is never invoked again once it has panicked.
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![1]);
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
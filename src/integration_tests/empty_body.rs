@@ -0,0 +1,193 @@
+use super::mock_server::MockServer;
+use super::with_chan;
+use crate::{
+    AmqpProperties, Connection, ConsumerMessage, ConsumerOptions, Exchange, Publish,
+    QueueDeclareOptions,
+};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{ConsumeOk, Deliver, GetOk};
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+fn text_properties() -> AmqpProperties {
+    AmqpProperties::default().with_content_type("text/plain".to_string())
+}
+
+#[test]
+fn publish_empty_body_sends_no_body_frame() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for expected in &[AmqpProperties::default(), text_properties()] {
+            match conn.recv_method() {
+                (ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == n => (),
+                other => panic!("expected publish, got {:?}", other),
+            }
+            match conn.recv_frame() {
+                AMQPFrame::Header(ch, 60, header) => {
+                    assert_eq!(ch, n);
+                    assert_eq!(header.body_size, 0);
+                    assert_eq!(&header.properties, expected);
+                }
+                other => panic!("expected content header, got {:?}", other),
+            }
+        }
+        // The next frame must be the channel close, not a (bogus) empty body frame.
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let exchange = Exchange::direct(&channel);
+    exchange.publish(Publish::new(&[], "q")).unwrap();
+    exchange
+        .publish(Publish::with_properties(&[], "q", text_properties()))
+        .unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn consume_empty_body_completes_on_header() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Consume(_))) if ch == n => (),
+            other => panic!("expected consume, got {:?}", other),
+        }
+        let consumer_tag = "ctag".to_string();
+        conn.send_method(
+            n,
+            AmqpBasic::ConsumeOk(ConsumeOk {
+                consumer_tag: consumer_tag.clone(),
+            }),
+        );
+        for (delivery_tag, properties) in [AmqpProperties::default(), text_properties()]
+            .iter()
+            .enumerate()
+        {
+            conn.send_method(
+                n,
+                AmqpBasic::Deliver(Deliver {
+                    consumer_tag: consumer_tag.clone(),
+                    delivery_tag: delivery_tag as u64 + 1,
+                    redelivered: false,
+                    exchange: String::new(),
+                    routing_key: "q".to_string(),
+                }),
+            );
+            conn.send_content(n, &[], properties);
+        }
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    for expected in &[AmqpProperties::default(), text_properties()] {
+        // The server never sends a body frame; a hang here means we waited for one.
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => {
+                assert!(delivery.body.is_empty());
+                assert_eq!(&delivery.properties, expected);
+            }
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+    }
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn get_empty_body_completes_on_header() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for (delivery_tag, properties) in [AmqpProperties::default(), text_properties()]
+            .iter()
+            .enumerate()
+        {
+            match conn.recv_method() {
+                (ch, AMQPClass::Basic(AmqpBasic::Get(_))) if ch == n => (),
+                other => panic!("expected get, got {:?}", other),
+            }
+            conn.send_method(
+                n,
+                AmqpBasic::GetOk(GetOk {
+                    delivery_tag: delivery_tag as u64 + 1,
+                    redelivered: false,
+                    exchange: String::new(),
+                    routing_key: "q".to_string(),
+                    message_count: 0,
+                }),
+            );
+            conn.send_content(n, &[], properties);
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    for expected in &[AmqpProperties::default(), text_properties()] {
+        let get = channel.basic_get("q", true).unwrap().unwrap();
+        assert!(get.delivery.body.is_empty());
+        assert_eq!(&get.delivery.properties, expected);
+        assert_eq!(get.message_count, 0);
+    }
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_empty_body_round_trip() {
+    with_chan(|chan| {
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..QueueDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let exchange = Exchange::direct(chan);
+        for properties in &[AmqpProperties::default(), text_properties()] {
+            exchange
+                .publish(Publish::with_properties(
+                    &[],
+                    queue.name(),
+                    properties.clone(),
+                ))
+                .unwrap();
+        }
+
+        // first message via basic.get...
+        let get = queue.get(true).unwrap().unwrap();
+        assert!(get.delivery.body.is_empty());
+        assert_eq!(get.delivery.properties, AmqpProperties::default());
+
+        // ...second via a consumer
+        let consumer = queue.consume(ConsumerOptions::default()).unwrap();
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => {
+                assert!(delivery.body.is_empty());
+                assert_eq!(delivery.properties, text_properties());
+                consumer.ack(delivery).unwrap();
+            }
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+    })
+}
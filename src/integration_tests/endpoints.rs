@@ -0,0 +1,80 @@
+use super::mock_server::MockServer;
+use crate::{Auth, Connection, ConnectionOptions, ConnectionTuning, Endpoint, Error};
+use std::net::{SocketAddr, TcpListener};
+
+// An address nothing is listening on.
+fn closed_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[test]
+fn open_endpoints_fails_over_to_next_endpoint() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    let options = ConnectionOptions::<Auth>::default().endpoints(vec![
+        Endpoint::from(closed_addr()),
+        Endpoint::from(server.addr()),
+    ]);
+    let connection =
+        Connection::insecure_open_endpoints(options, ConnectionTuning::default()).unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn open_endpoints_reports_every_failure() {
+    let endpoints = vec![Endpoint::from(closed_addr()), Endpoint::from(closed_addr())];
+    let options = ConnectionOptions::<Auth>::default()
+        .endpoints(endpoints.clone())
+        .shuffle_endpoints(true);
+    let err = match Connection::insecure_open_endpoints(options, ConnectionTuning::default()) {
+        Ok(_) => panic!("connected to a closed port"),
+        Err(err) => err,
+    };
+    assert!(err.is_recoverable());
+    let failures = match &err {
+        Error::AllEndpointsFailed { failures } => failures,
+        err => panic!("unexpected error {:?}", err),
+    };
+    assert_eq!(failures.len(), 2);
+    for endpoint in &endpoints {
+        assert!(failures.iter().any(|(e, _)| *e == endpoint.to_string()));
+        assert!(err.to_string().contains(&endpoint.to_string()));
+    }
+}
+
+#[test]
+fn open_any_tries_each_url() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    let closed = format!("amqp://user:secret@{}", closed_addr());
+    let url = server.url();
+    let connection =
+        Connection::insecure_open_any(&[&closed, &url], ConnectionTuning::default()).unwrap();
+    connection.close().unwrap();
+    server.join();
+
+    let err = match Connection::insecure_open_any(&[&closed], ConnectionTuning::default()) {
+        Ok(_) => panic!("connected to a closed port"),
+        Err(err) => err,
+    };
+    match err {
+        Error::AllEndpointsFailed { failures } => {
+            assert_eq!(failures.len(), 1);
+            assert!(!failures[0].0.contains("secret"));
+        }
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    match Connection::insecure_open_any(&[], ConnectionTuning::default()) {
+        Err(Error::NoEndpoints) => (),
+        _ => panic!("expected NoEndpoints"),
+    }
+}
@@ -0,0 +1,149 @@
+use super::mock_server::{MockServer, ServerConn};
+use super::with_chan;
+use crate::{Connection, ExchangeDeclareOptions, ExchangeType, QueueDeclareOptions, QueueInfo};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::exchange::AMQPMethod as AmqpExchange;
+use amq_protocol::protocol::exchange::DeclareOk as ExchangeDeclareOk;
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::queue::DeclareOk as QueueDeclareOk;
+use amq_protocol::protocol::AMQPClass;
+
+fn close_not_found(conn: &mut ServerConn, channel_id: u16, class_id: u16) {
+    conn.send_method(
+        channel_id,
+        AmqpChannel::Close(ChannelClose {
+            reply_code: 404,
+            reply_text: "NOT_FOUND - no such thing".to_string(),
+            class_id,
+            method_id: 10,
+        }),
+    );
+    match conn.recv_method() {
+        (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == channel_id => (),
+        other => panic!("expected channel.close-ok, got {:?}", other),
+    }
+}
+
+fn recv_queue_declare(conn: &mut ServerConn, channel_id: u16) -> String {
+    match conn.recv_method() {
+        (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == channel_id => declare.queue,
+        other => panic!("expected queue.declare, got {:?}", other),
+    }
+}
+
+fn recv_exchange_declare(conn: &mut ServerConn, channel_id: u16) -> String {
+    match conn.recv_method() {
+        (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == channel_id => {
+            declare.exchange
+        }
+        other => panic!("expected exchange.declare, got {:?}", other),
+    }
+}
+
+#[test]
+fn exists_checks_leave_channel_open() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+
+        // Each check runs on its own channel, which the 404 closes.
+        let m = conn.accept_channel();
+        assert_ne!(m, n);
+        assert_eq!(recv_queue_declare(&mut conn, m), "missing");
+        close_not_found(&mut conn, m, 50);
+
+        let m = conn.accept_channel();
+        assert_eq!(recv_exchange_declare(&mut conn, m), "missing");
+        close_not_found(&mut conn, m, 40);
+
+        let m = conn.accept_channel();
+        let queue = recv_queue_declare(&mut conn, m);
+        conn.send_method(
+            m,
+            AmqpQueue::DeclareOk(QueueDeclareOk {
+                queue,
+                message_count: 7,
+                consumer_count: 2,
+            }),
+        );
+        conn.accept_channel_close(m);
+
+        let m = conn.accept_channel();
+        assert_eq!(recv_exchange_declare(&mut conn, m), "present");
+        conn.send_method(m, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+        conn.accept_channel_close(m);
+
+        // The caller's channel is still usable.
+        let queue = recv_queue_declare(&mut conn, n);
+        conn.send_method(
+            n,
+            AmqpQueue::DeclareOk(QueueDeclareOk {
+                queue,
+                message_count: 0,
+                consumer_count: 0,
+            }),
+        );
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    assert_eq!(channel.queue_exists("missing").unwrap(), None);
+    assert!(!channel.exchange_exists("missing").unwrap());
+    assert_eq!(
+        channel.queue_exists("present").unwrap(),
+        Some(QueueInfo {
+            name: "present".to_string(),
+            message_count: 7,
+            consumer_count: 2,
+        })
+    );
+    assert!(channel.exchange_exists("present").unwrap());
+    channel
+        .queue_declare("q", QueueDeclareOptions::default())
+        .unwrap();
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_exists() {
+    with_chan(|chan| {
+        assert_eq!(
+            chan.queue_exists("amiquip-test-no-such-queue").unwrap(),
+            None
+        );
+        assert!(!chan
+            .exchange_exists("amiquip-test-no-such-exchange")
+            .unwrap());
+
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..QueueDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let info = chan.queue_exists(queue.name()).unwrap().unwrap();
+        assert_eq!(info.name, queue.name());
+        assert_eq!(info.message_count, 0);
+
+        let exchange = chan
+            .exchange_declare(
+                ExchangeType::Fanout,
+                "amiquip-test-exists",
+                ExchangeDeclareOptions {
+                    auto_delete: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        assert!(chan.exchange_exists(exchange.name()).unwrap());
+    })
+}
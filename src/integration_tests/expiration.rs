@@ -0,0 +1,66 @@
+use super::mock_server::MockServer;
+use crate::{AmqpProperties, AmqpPropertiesExt, Connection, Error, Publish};
+use std::time::Duration;
+
+#[test]
+fn ttl_is_sent_as_milliseconds_and_out_of_range_ttl_is_not_sent() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for &expected in &["1500", "0", "250"] {
+            let (_, properties, body) = conn.recv_publish_with_properties(n);
+            assert_eq!(body, b"ok");
+            assert_eq!(properties.expiration(), &Some(expected.to_string()));
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let too_long = Duration::from_millis(i64::MAX as u64 + 1);
+    let assert_invalid = |result: crate::Result<()>| match result {
+        Err(Error::InvalidExpiration { expiration }) => {
+            assert_eq!(expiration, "9223372036854775808")
+        }
+        other => panic!("unexpected result {:?}", other),
+    };
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let publisher = channel.publisher();
+
+    assert_invalid(channel.basic_publish("", Publish::new(b"bad", "q").with_ttl(too_long)));
+    assert_invalid(
+        channel
+            .try_publish("", Publish::new(b"bad", "q").with_ttl(too_long))
+            .map(|_| ()),
+    );
+    assert_invalid(publisher.publish("", Publish::new(b"bad", "q").with_ttl(too_long)));
+
+    // None of the rejected messages reached the server, and the channel is still open.
+    channel
+        .basic_publish(
+            "",
+            Publish::new(b"ok", "q").with_ttl(Duration::from_micros(1_500_999)),
+        )
+        .unwrap();
+    publisher
+        .publish(
+            "",
+            Publish::new(b"ok", "q").with_ttl(Duration::from_millis(0)),
+        )
+        .unwrap();
+    channel
+        .basic_publish(
+            "",
+            Publish::with_properties(
+                b"ok",
+                "q",
+                AmqpProperties::default().with_expiration_duration(Duration::from_millis(250)),
+            ),
+        )
+        .unwrap();
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
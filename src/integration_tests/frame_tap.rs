@@ -0,0 +1,113 @@
+use super::mock_server::MockServer;
+use crate::{
+    AmqpFrame, Auth, Connection, ConnectionOptions, ConnectionTuning, Exchange, FrameDirection,
+    FrameTapEvent, Publish, TappedFrame,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::AMQPClass;
+use mio::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+fn open_tapped(server: &MockServer, events: &Arc<Mutex<Vec<FrameTapEvent>>>) -> Connection {
+    let events = Arc::clone(events);
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default()
+        .frame_tap(Box::new(move |event| events.lock().unwrap().push(event)));
+    Connection::insecure_open_stream(stream, options, ConnectionTuning::default()).unwrap()
+}
+
+#[test]
+fn frame_tap_sees_frames_in_both_directions() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.send_heartbeat();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == n => (),
+            other => panic!("expected publish, got {:?}", other),
+        }
+        conn.recv_frame();
+        conn.recv_frame();
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut connection = open_tapped(&server, &events);
+    let channel = connection.open_channel(None).unwrap();
+    let n = channel.channel_id();
+    Exchange::direct(&channel)
+        .publish(Publish::new(b"hello", "q"))
+        .unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+
+    let events = events.lock().unwrap();
+    let position = |direction: FrameDirection, f: &dyn Fn(u16, &AmqpFrame) -> bool| {
+        events
+            .iter()
+            .position(|e| match &e.frame {
+                TappedFrame::Parsed(frame) => e.direction == direction && f(e.channel_id, frame),
+                TappedFrame::Raw(_) => false,
+            })
+            .unwrap_or_else(|| panic!("frame not tapped in {:#?}", events))
+    };
+
+    let header = position(FrameDirection::Tx, &|ch, f| {
+        ch == 0 && *f == AmqpFrame::ProtocolHeader
+    });
+    let start = position(FrameDirection::Rx, &|ch, f| {
+        ch == 0
+            && matches!(
+                f,
+                AmqpFrame::Method(_, AMQPClass::Connection(AmqpConnection::Start(_)))
+            )
+    });
+    assert!(header < start);
+    position(FrameDirection::Rx, &|ch, f| {
+        ch == 0 && matches!(f, AmqpFrame::Heartbeat(_))
+    });
+    let publish = position(FrameDirection::Tx, &|ch, f| {
+        ch == n
+            && matches!(
+                f,
+                AmqpFrame::Method(_, AMQPClass::Basic(AmqpBasic::Publish(_)))
+            )
+    });
+    let body = position(FrameDirection::Tx, &|ch, f| {
+        ch == n && *f == AmqpFrame::Body(n, b"hello".to_vec())
+    });
+    assert!(publish < body);
+    assert!(events
+        .iter()
+        .all(|e| matches!(e.frame, TappedFrame::Parsed(_))));
+}
+
+#[test]
+fn frame_tap_sees_raw_bytes_of_malformed_frames() {
+    // A method frame on channel 3 whose payload is not a valid method.
+    const MALFORMED: &[u8] = b"\x01\x00\x03\x00\x00\x00\x02\xff\xff\xce";
+
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.send_raw(MALFORMED);
+        // The client may or may not get a connection.close out before it reads the bad frame.
+        while conn.try_recv_frame().is_some() {}
+    });
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let connection = open_tapped(&server, &events);
+    assert!(connection.close().is_err());
+    server.join();
+
+    let events = events.lock().unwrap();
+    let last = events
+        .iter()
+        .rev()
+        .find(|e| e.direction == FrameDirection::Rx)
+        .unwrap();
+    assert_eq!(last.channel_id, 3);
+    assert_eq!(last.frame, TappedFrame::Raw(MALFORMED.to_vec()));
+}
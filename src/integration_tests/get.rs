@@ -0,0 +1,92 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{AmqpProperties, Connection, ConsumerMessage, ConsumerOptions};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{GetEmpty, GetOk};
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+fn expect_get(conn: &mut ServerConn, channel_id: u16) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Basic(AmqpBasic::Get(get))) if ch == channel_id => {
+            assert_eq!(get.queue, "polled");
+            assert!(!get.no_ack);
+        }
+        other => panic!("expected get, got {:?}", other),
+    }
+}
+
+#[test]
+fn get_alongside_active_consumer() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+
+        // Deliveries to the consumer arrive on either side of the get-ok, and all three share
+        // the channel's delivery tags.
+        conn.deliver(n, 1, b"consumed");
+        expect_get(&mut conn, n);
+        conn.send_method(
+            n,
+            AmqpBasic::GetOk(GetOk {
+                delivery_tag: 2,
+                redelivered: false,
+                exchange: String::new(),
+                routing_key: "polled".to_string(),
+                message_count: 5,
+            }),
+        );
+        conn.send_content(n, b"polled", &AmqpProperties::default());
+        conn.deliver(n, 3, b"consumed");
+
+        expect_get(&mut conn, n);
+        conn.send_method(
+            n,
+            AmqpBasic::GetEmpty(GetEmpty {
+                cluster_id: String::new(),
+            }),
+        );
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                assert_eq!(ack.delivery_tag, 2);
+                assert!(!ack.multiple);
+            }
+            other => panic!("expected ack, got {:?}", other),
+        }
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        no_ack: true,
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel.basic_consume("consumed", options).unwrap();
+
+    let get = channel.basic_get("polled", false).unwrap().unwrap();
+    assert_eq!(get.message_count, 5);
+    assert_eq!(get.delivery.delivery_tag(), 2);
+    assert_eq!(get.delivery.routing_key, "polled");
+    assert_eq!(get.delivery.body, b"polled");
+    assert!(channel.basic_get("polled", false).unwrap().is_none());
+
+    for tag in &[1, 3] {
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => {
+                assert_eq!(delivery.delivery_tag(), *tag);
+                assert_eq!(delivery.body, b"consumed");
+            }
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+    }
+
+    get.ack(&channel).unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
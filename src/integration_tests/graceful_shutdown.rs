@@ -0,0 +1,186 @@
+use super::mock_server::MockServer;
+use crate::{Connection, Publish, ShutdownReport};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::Ack;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "consume")]
+use crate::{ConsumerMessage, ConsumerOptions};
+#[cfg(feature = "consume")]
+use amq_protocol::frame::AMQPFrame;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::CancelOk;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::AMQPClass;
+
+#[cfg(feature = "consume")]
+#[test]
+fn shutdown_waits_for_cancels_and_confirms() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.accept_consume(n);
+        for delivery_tag in 1..=2 {
+            conn.deliver(n, delivery_tag, b"body");
+        }
+
+        // The cancel is sent on behalf of the connection, so it may arrive before or after the
+        // publishes and ack the client sent just before shutting down.
+        let (mut publishes, mut acked, mut cancelled) = (0, false, false);
+        while publishes < 3 || !acked || !cancelled {
+            match conn.recv_frame() {
+                AMQPFrame::Method(ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == n => {
+                    publishes += 1
+                }
+                AMQPFrame::Method(ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                    assert_eq!(ack.delivery_tag, 1);
+                    acked = true;
+                }
+                AMQPFrame::Method(ch, AMQPClass::Basic(AmqpBasic::Cancel(cancel))) if ch == n => {
+                    assert_eq!(cancel.consumer_tag, "ctag");
+                    cancelled = true;
+                }
+                AMQPFrame::Header(..) | AMQPFrame::Body(..) | AMQPFrame::Heartbeat(_) => (),
+                other => panic!("unexpected frame {:?}", other),
+            }
+        }
+        conn.send_method(
+            n,
+            AmqpBasic::Ack(Ack {
+                delivery_tag: 3,
+                multiple: true,
+            }),
+        );
+        conn.send_method(
+            n,
+            AmqpBasic::CancelOk(CancelOk {
+                consumer_tag: "ctag".to_string(),
+            }),
+        );
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    channel.enable_publisher_confirms().unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let mut deliveries = Vec::new();
+    for _ in 0..2 {
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => deliveries.push(delivery),
+            other => panic!("unexpected consumer message {:?}", other),
+        }
+    }
+    deliveries.remove(0).ack(&channel).unwrap();
+    for _ in 0..3 {
+        channel
+            .basic_publish("", Publish::new(b"hello", "q"))
+            .unwrap();
+    }
+
+    let report = connection.shutdown(Duration::from_secs(5)).unwrap();
+    assert_eq!(
+        report,
+        ShutdownReport {
+            unacked_deliveries: 1,
+            ..ShutdownReport::default()
+        }
+    );
+    assert!(report.is_complete());
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::ClientCancelled) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    server.join();
+}
+
+#[test]
+fn shutdown_reports_what_it_abandons() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.recv_publish(n);
+        conn.recv_publish(n);
+        conn.send_method(
+            n,
+            AmqpBasic::Ack(Ack {
+                delivery_tag: 1,
+                multiple: false,
+            }),
+        );
+        // Never confirm the second publish.
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    channel.enable_publisher_confirms().unwrap();
+    channel
+        .basic_publish("", Publish::new(b"one", "q"))
+        .unwrap();
+    channel
+        .basic_publish("", Publish::new(b"two", "q"))
+        .unwrap();
+
+    let timeout = Duration::from_millis(200);
+    let start = Instant::now();
+    let report = connection.shutdown(timeout).unwrap();
+    assert!(start.elapsed() >= timeout);
+    assert_eq!(
+        report,
+        ShutdownReport {
+            unconfirmed_publishes: 1,
+            // The deadline has passed by the time the close is sent, so its close-ok can only
+            // beat it by luck.
+            close_abandoned: report.close_abandoned,
+            ..ShutdownReport::default()
+        }
+    );
+    assert!(!report.is_complete());
+    server.join();
+}
+
+#[test]
+fn shutdown_abandons_close_the_server_never_answers() {
+    let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        conn.expect_connection_close();
+        // Never send close-ok; hold the socket open until the client has given up.
+        done_rx.recv().unwrap();
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    let start = Instant::now();
+    let report = connection.shutdown(Duration::from_millis(200)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        report,
+        ShutdownReport {
+            close_abandoned: true,
+            ..ShutdownReport::default()
+        }
+    );
+    assert!(!report.is_complete());
+    done_tx.send(()).unwrap();
+    server.join();
+}
+
+#[test]
+fn shutdown_without_outstanding_work_is_immediate() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    let start = Instant::now();
+    let report = connection.shutdown(Duration::from_secs(30)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(report.is_complete());
+    server.join();
+}
@@ -0,0 +1,431 @@
+use super::mock_server::{MockServer, ServerConn, DEFAULT_TUNE};
+use crate::{
+    Auth, Connection, ConnectionBlockedNotification, ConnectionOptions, ConnectionTuning, Error,
+    IoStream, Publish, Result, Sasl, TcpOptions,
+};
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::{Blocked, CloseOk, OpenOk, Secure, Tune, Unblocked};
+use amq_protocol::protocol::AMQPClass;
+use amq_protocol::types::AMQPValue;
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use socket2::SockRef;
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "scram")]
+use crate::ScramSha256;
+
+// RabbitMQ's RABBIT-CR-DEMO mechanism: the username goes in the start-ok response, and the
+// password is sent in reply to a single challenge.
+#[derive(Debug, Clone, Default)]
+struct CrDemo {
+    username: String,
+    password: String,
+}
+
+impl Sasl for CrDemo {
+    fn mechanism(&self) -> String {
+        "RABBIT-CR-DEMO".to_string()
+    }
+
+    fn response(&self) -> String {
+        self.username.clone()
+    }
+
+    fn respond_to_challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        assert_eq!(challenge, b"Please tell me your password");
+        Ok(format!("My password is {}", self.password).into_bytes())
+    }
+}
+
+fn start_with_mechanisms(conn: &mut ServerConn, mechanisms: &str) -> String {
+    let mut start = ServerConn::start_method();
+    start.mechanisms = mechanisms.to_string();
+    let start_ok = conn.start_handshake(start);
+    assert_eq!(start_ok.mechanism, mechanisms);
+    start_ok.response
+}
+
+fn open<A: Sasl>(server: &MockServer, auth: A) -> Result<Connection> {
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    Connection::insecure_open_stream(
+        stream,
+        ConnectionOptions::default().auth(auth),
+        ConnectionTuning::default(),
+    )
+}
+
+#[test]
+fn secure_challenge_proceeds_to_tune() {
+    let server = MockServer::start(|mut conn| {
+        assert_eq!(start_with_mechanisms(&mut conn, "RABBIT-CR-DEMO"), "alice");
+        assert_eq!(
+            conn.challenge("Please tell me your password"),
+            "My password is secret"
+        );
+        conn.finish_handshake(DEFAULT_TUNE);
+        conn.accept_connection_close();
+    });
+
+    let auth = CrDemo {
+        username: "alice".to_string(),
+        password: "secret".to_string(),
+    };
+    let connection = open(&server, auth).unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn builtin_mechanisms_reject_secure_challenge() {
+    let server = MockServer::start(|mut conn| {
+        start_with_mechanisms(&mut conn, "PLAIN");
+        conn.send_method(
+            0,
+            AmqpConnection::Secure(Secure {
+                challenge: "unexpected".to_string(),
+            }),
+        );
+        conn.wait_for_client_eof();
+    });
+
+    match open(&server, Auth::default()) {
+        Err(Error::SaslSecureNotSupported) => (),
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+    server.join();
+}
+
+#[cfg(feature = "scram")]
+#[test]
+fn scram_rejected_by_server_is_invalid_credentials() {
+    let server = MockServer::start(|mut conn| {
+        let client_first = start_with_mechanisms(&mut conn, "SCRAM-SHA-256");
+        let client_nonce = client_first.rsplit("r=").next().unwrap().to_string();
+        let client_final = conn.challenge(&format!(
+            "r={}servernonce,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            client_nonce
+        ));
+        assert!(client_final.contains(",p="));
+        // Like RabbitMQ, reject the proof by hanging up.
+    });
+
+    match open(&server, ScramSha256::new("user", "wrong")) {
+        Err(Error::InvalidCredentials) => (),
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+    server.join();
+}
+
+#[test]
+fn blocked_during_open_does_not_abort_handshake() {
+    let (unblock_tx, unblock_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.start_handshake(ServerConn::start_method());
+        conn.send_method(0, AmqpConnection::Tune(DEFAULT_TUNE));
+        match conn.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::TuneOk(_))) => (),
+            other => panic!("expected tune-ok, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::Open(_))) => (),
+            other => panic!("expected open, got {:?}", other),
+        }
+        conn.send_method(
+            0,
+            AmqpConnection::Blocked(Blocked {
+                reason: "low on memory".to_string(),
+            }),
+        );
+        conn.send_method(
+            0,
+            AmqpConnection::OpenOk(OpenOk {
+                known_hosts: String::new(),
+            }),
+        );
+        unblock_rx.recv().unwrap();
+        conn.send_method(0, AmqpConnection::Unblocked(Unblocked {}));
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let blocked = connection.listen_for_connection_blocked().unwrap();
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        blocked.recv_timeout(timeout).unwrap(),
+        ConnectionBlockedNotification::Blocked("low on memory".to_string())
+    );
+    unblock_tx.send(()).unwrap();
+    assert_eq!(
+        blocked.recv_timeout(timeout).unwrap(),
+        ConnectionBlockedNotification::Unblocked
+    );
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn zero_missed_heartbeat_limit_is_rejected() {
+    let server = MockServer::start(|_| ());
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let tuning = ConnectionTuning::default().missed_heartbeat_limit(0);
+    match Connection::insecure_open_stream(stream, ConnectionOptions::<Auth>::default(), tuning) {
+        Err(Error::InvalidMissedHeartbeatLimit) => (),
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+    server.join();
+}
+
+#[test]
+fn negotiated_tune_parameters_are_exposed() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            channel_max: 100,
+            frame_max: 8192,
+            heartbeat: 30,
+        });
+        conn.accept_connection_close();
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default()
+        .channel_max(200)
+        .frame_max(1 << 20)
+        .heartbeat(10);
+    let connection =
+        Connection::insecure_open_stream(stream, options, ConnectionTuning::default()).unwrap();
+    assert_eq!(connection.channel_max(), 100);
+    assert_eq!(connection.frame_max(), 8192);
+    assert_eq!(connection.heartbeat(), 10);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn server_properties_are_exposed() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    assert!(connection.server_supports("publisher_confirms"));
+    assert!(!connection.server_supports("per_consumer_qos"));
+    assert_eq!(connection.server_product(), Some("amiquip-mock"));
+    assert_eq!(connection.server_version(), None);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn connection_name_is_sent_in_client_properties() {
+    let server = MockServer::start(|mut conn| {
+        let start_ok = conn.start_handshake(ServerConn::start_method());
+        match start_ok.client_properties.get("connection_name") {
+            Some(AMQPValue::LongString(name)) => assert_eq!(name, "billing-1"),
+            other => panic!("unexpected connection_name {:?}", other),
+        }
+        conn.finish_handshake(DEFAULT_TUNE);
+        conn.accept_connection_close();
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default().connection_name("billing-1");
+    let connection =
+        Connection::insecure_open_stream(stream, options, ConnectionTuning::default()).unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn close_with_sends_reply_code_and_text() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let close = conn.expect_connection_close();
+        assert_eq!(close.reply_code, 320);
+        assert_eq!(close.reply_text, "invalid configuration");
+        conn.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+        conn.wait_for_client_eof();
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    connection.close_with(320, "invalid configuration").unwrap();
+    server.join();
+}
+
+#[test]
+fn tcp_options_are_applied_to_stream() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    let socket = std::net::TcpStream::connect(server.addr()).unwrap();
+    socket.set_nonblocking(true).unwrap();
+    let stream = TcpStream::from_std(socket.try_clone().unwrap());
+    assert!(!socket.nodelay().unwrap());
+    let tuning = ConnectionTuning::default().tcp_options(
+        TcpOptions::default()
+            .nodelay(true)
+            .keepalive(Some(Duration::from_secs(60))),
+    );
+    let connection =
+        Connection::insecure_open_stream(stream, ConnectionOptions::<Auth>::default(), tuning)
+            .unwrap();
+    assert!(socket.nodelay().unwrap());
+    assert!(SockRef::from(&socket).keepalive().unwrap());
+    // Release our handle on the socket so the server sees the client hang up.
+    drop(socket);
+    connection.close().unwrap();
+    server.join();
+}
+
+// A stream whose connection never completes: it never becomes readable or writable.
+struct NeverConnects;
+
+impl Read for NeverConnects {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Write for NeverConnects {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Registers nothing, so mio never reports it ready.
+impl Source for NeverConnects {
+    fn register(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&mut self, _: &Registry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl IoStream for NeverConnects {}
+
+#[test]
+fn connect_timeout_expires() {
+    let stream = NeverConnects;
+    let options = ConnectionOptions::<Auth>::default().connect_timeout(Duration::from_millis(100));
+    match Connection::insecure_open_stream(stream, options, ConnectionTuning::default()) {
+        Err(Error::ConnectTimeout) => (),
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn handshake_timeout_expires() {
+    let server = MockServer::start(|mut conn| {
+        // Accept the connection but never start the handshake.
+        conn.expect_protocol_header();
+        conn.wait_for_client_eof();
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default()
+        .connect_timeout(Duration::from_secs(5))
+        .handshake_timeout(Duration::from_millis(200));
+    match Connection::insecure_open_stream(stream, options, ConnectionTuning::default()) {
+        Err(Error::HandshakeTimeout) => (),
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+    server.join();
+}
+
+#[test]
+fn connection_timeout_stops_a_stalled_handshake() {
+    let server = MockServer::start(|mut conn| {
+        conn.expect_protocol_header();
+        conn.wait_for_client_eof();
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options =
+        ConnectionOptions::<Auth>::default().connection_timeout(Some(Duration::from_millis(100)));
+    match Connection::insecure_open_stream(stream, options, ConnectionTuning::default()) {
+        Err(Error::ConnectionTimeout) => (),
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+    server.join();
+}
+
+#[test]
+fn idle_connection_outlives_connection_timeout() {
+    let server = MockServer::start(|mut conn| {
+        // DEFAULT_TUNE disables heartbeats, so nothing is sent while the client sits idle.
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options =
+        ConnectionOptions::<Auth>::default().connection_timeout(Some(Duration::from_millis(100)));
+    let mut connection =
+        Connection::insecure_open_stream(stream, options, ConnectionTuning::default()).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let channel = connection.open_channel(None).unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn stalled_write_fails_with_connection_timeout() {
+    let (gave_up_tx, gave_up_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        conn.accept_channel();
+        // Stop reading until the client gives up, then skip whatever it managed to send.
+        gave_up_rx.recv().unwrap();
+        while conn.try_recv_frame().is_some() {}
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options =
+        ConnectionOptions::<Auth>::default().connection_timeout(Some(Duration::from_millis(200)));
+    let tuning = ConnectionTuning::default()
+        .tcp_options(TcpOptions::default().send_buffer_size(Some(4096)))
+        .buffered_writes_high_water(1 << 20);
+    let mut connection = Connection::insecure_open_stream(stream, options, tuning).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+
+    // Publish until the socket stops taking our writes and the connection gives up.
+    let body = vec![0; 64 << 10];
+    while channel.basic_publish("", Publish::new(&body, "q")).is_ok() {}
+    match connection.close() {
+        Err(Error::ConnectionTimeout) => (),
+        other => panic!("unexpected close result {:?}", other),
+    }
+    gave_up_tx.send(()).unwrap();
+    server.join();
+}
+
+#[test]
+fn unwritable_stream_fails_with_write_stalled() {
+    let stream = NeverConnects;
+    let tuning = ConnectionTuning::default().write_stall_timeout(Some(Duration::from_millis(100)));
+    match Connection::insecure_open_stream(stream, ConnectionOptions::<Auth>::default(), tuning) {
+        Err(Error::WriteStalled { stalled_for }) => {
+            assert!(stalled_for >= Duration::from_millis(100))
+        }
+        other => panic!("unexpected open result {:?}", other.map(|_| ())),
+    }
+}
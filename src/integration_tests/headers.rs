@@ -0,0 +1,103 @@
+use super::mock_server::MockServer;
+use super::with_chan;
+use crate::{
+    AmqpProperties, AmqpValue, Connection, Exchange, ExchangeDeclareOptions, ExchangeType,
+    FieldTable, HeaderMatch, Publish, QueueDeclareOptions,
+};
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::queue::BindOk;
+use amq_protocol::protocol::AMQPClass;
+
+#[test]
+fn header_match_binding_arguments_keep_their_types() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Bind(bind))) if ch == n => {
+                let arg = |name: &str| bind.arguments.get(name).cloned();
+                assert_eq!(
+                    arg("x-match"),
+                    Some(AmqpValue::LongString("any".to_string()))
+                );
+                assert_eq!(
+                    arg("format"),
+                    Some(AmqpValue::LongString("pdf".to_string()))
+                );
+                assert_eq!(arg("size"), Some(AmqpValue::LongInt(42)));
+                assert_eq!(arg("pages"), Some(AmqpValue::LongLongInt(7)));
+            }
+            other => panic!("expected queue.bind, got {:?}", other),
+        }
+        conn.send_method(n, AmqpQueue::BindOk(BindOk {}));
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let matcher = HeaderMatch::any()
+        .header("format", "pdf")
+        .header("size", 42i32)
+        .header("pages", 7i64);
+    channel
+        .queue_bind("q", "amq.headers", "", matcher.arguments())
+        .unwrap();
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_header_match_routing() {
+    with_chan(|chan| {
+        let exchange = chan
+            .exchange_declare(
+                ExchangeType::Headers,
+                "amiquip-test-headers",
+                ExchangeDeclareOptions {
+                    auto_delete: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..QueueDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let matcher = HeaderMatch::all()
+            .header("format", "pdf")
+            .header("size", 42i32);
+        queue.bind(&exchange, "", matcher.arguments()).unwrap();
+
+        let routed = |exchange: &Exchange, properties: AmqpProperties| {
+            exchange
+                .publish(Publish::with_properties(b"hello", "", properties))
+                .unwrap();
+            queue.get(true).unwrap().is_some()
+        };
+
+        assert!(routed(
+            &exchange,
+            matcher.apply_to(AmqpProperties::default())
+        ));
+
+        // The same value encoded as a string doesn't match the integer binding.
+        let mut headers = FieldTable::new();
+        headers.insert(
+            "format".to_string(),
+            AmqpValue::LongString("pdf".to_string()),
+        );
+        headers.insert("size".to_string(), AmqpValue::LongString("42".to_string()));
+        assert!(!routed(
+            &exchange,
+            AmqpProperties::default().with_headers(headers)
+        ));
+    })
+}
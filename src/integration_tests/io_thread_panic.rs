@@ -0,0 +1,37 @@
+use super::mock_server::MockServer;
+use crate::{install_panic_hook, Connection, Error};
+
+#[test]
+fn panic_is_reported_to_callers_and_close() {
+    install_panic_hook();
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_channel();
+        // The client's I/O thread panics and drops the socket.
+        assert!(conn.try_recv_frame().is_none());
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    channel.inject_io_thread_panic("injected panic").unwrap();
+
+    match channel.tx_select().unwrap_err() {
+        Error::EventLoopPanicked { message, stack } => {
+            assert!(message.starts_with("injected panic at "), "{}", message);
+            assert!(!stack.is_empty());
+        }
+        err => panic!("unexpected error {}", err),
+    }
+    match connection.open_channel(None) {
+        Err(Error::EventLoopPanicked { .. }) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("opened a channel after the I/O thread panicked"),
+    }
+    match connection.close().unwrap_err() {
+        Error::EventLoopPanicked { message, .. } => {
+            assert!(message.starts_with("injected panic"), "{}", message)
+        }
+        err => panic!("unexpected error {}", err),
+    }
+    server.join();
+}
@@ -0,0 +1,82 @@
+use super::mock_server::MockServer;
+use super::with_chan;
+use crate::{AmqpProperties, Connection, Error, Exchange, Publish, QueueDeclareOptions};
+use serde_crate::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "serde_crate")]
+struct Order {
+    id: u32,
+    items: Vec<String>,
+}
+
+fn order() -> Order {
+    Order {
+        id: 7,
+        items: vec!["tea".to_string(), "cake".to_string()],
+    }
+}
+
+#[test]
+fn publish_json_sets_content_type() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        let (publish, properties, body) = conn.recv_publish_with_properties(n);
+        assert_eq!(publish.routing_key, "orders");
+        assert_eq!(body, br#"{"id":7,"items":["tea","cake"]}"#);
+        assert_eq!(
+            properties.content_type(),
+            &Some("application/json".to_string())
+        );
+        assert_eq!(properties.correlation_id(), &Some("corr".to_string()));
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let properties = AmqpProperties::default()
+        .with_content_type("text/plain".to_string())
+        .with_correlation_id("corr".to_string());
+    Exchange::direct(&channel)
+        .publish_json("orders", &order(), properties)
+        .unwrap();
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_json_round_trip() {
+    with_chan(|chan| {
+        let queue = chan
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    ..QueueDeclareOptions::default()
+                },
+            )
+            .unwrap();
+        let exchange = Exchange::direct(chan);
+
+        exchange
+            .publish_json(queue.name(), &order(), AmqpProperties::default())
+            .unwrap();
+        let get = queue.get(true).unwrap().unwrap();
+        assert_eq!(get.delivery.decode_json::<Order>().unwrap(), order());
+
+        exchange
+            .publish(Publish::new(br#"{"id":7}"#, queue.name()))
+            .unwrap();
+        let get = queue.get(true).unwrap().unwrap();
+        match get.delivery.decode_json::<Order>() {
+            Err(Error::UnexpectedContentType { content_type, .. }) => {
+                assert_eq!(content_type, None)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    })
+}
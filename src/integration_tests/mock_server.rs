@@ -0,0 +1,512 @@
+// A scripted, single-connection AMQP server for tests that need to control exactly what the
+// client sees on the wire. Unlike the rest of the integration tests, these do not need a real
+// server. Not every helper is used by every test.
+#![allow(dead_code)]
+
+use crate::serialize::{IntoAmqpClass, OutputBuffer};
+use crate::{AmqpProperties, AmqpValue, FieldTable};
+use amq_protocol::frame::{parse_frame, AMQPFrame};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Ack, CancelOk, ConsumeOk, Deliver, Publish};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::{CloseOk as ChannelCloseOk, OpenOk as ChannelOpenOk};
+use amq_protocol::protocol::confirm::AMQPMethod as AmqpConfirm;
+use amq_protocol::protocol::confirm::SelectOk;
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::{Close, CloseOk, OpenOk, Secure, Start, StartOk, Tune};
+use amq_protocol::protocol::tx::AMQPMethod as AmqpTx;
+use amq_protocol::protocol::tx::{
+    CommitOk as TxCommitOk, RollbackOk as TxRollbackOk, SelectOk as TxSelectOk,
+};
+use amq_protocol::protocol::AMQPClass;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[cfg(feature = "consume")]
+use crate::{ConsumerMessage, Delivery};
+#[cfg(feature = "consume")]
+use crossbeam_channel::Receiver;
+
+#[cfg(feature = "rustls")]
+use rustls_crate::{ServerConfig, ServerConnection};
+#[cfg(feature = "rustls")]
+use std::sync::Arc;
+
+pub(super) const DEFAULT_TUNE: Tune = Tune {
+    channel_max: 2047,
+    frame_max: 1 << 17,
+    heartbeat: 0,
+};
+
+pub(super) struct MockServer {
+    addr: SocketAddr,
+    join_handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    // Listen on an ephemeral port and run `script` against the first client that connects.
+    pub(super) fn start<F: FnOnce(ServerConn) + Send + 'static>(script: F) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let join_handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            script(ServerConn {
+                stream,
+                #[cfg(feature = "rustls")]
+                tls: None,
+                buf: Vec::new(),
+            });
+        });
+        MockServer { addr, join_handle }
+    }
+
+    // Like `start`, but the client has to connect with TLS; the script sees plaintext.
+    #[cfg(feature = "rustls")]
+    pub(super) fn start_tls<F: FnOnce(ServerConn) + Send + 'static>(
+        config: Arc<ServerConfig>,
+        script: F,
+    ) -> MockServer {
+        MockServer::start(move |mut conn| {
+            conn.tls = Some(ServerConnection::new(config).unwrap());
+            script(conn)
+        })
+    }
+
+    pub(super) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub(super) fn url(&self) -> String {
+        format!("amqp://{}", self.addr)
+    }
+
+    // Wait for the script to finish, propagating any assertion failures it hit.
+    pub(super) fn join(self) {
+        if let Err(err) = self.join_handle.join() {
+            std::panic::resume_unwind(err);
+        }
+    }
+}
+
+pub(super) struct ServerConn {
+    stream: TcpStream,
+    #[cfg(feature = "rustls")]
+    tls: Option<ServerConnection>,
+    buf: Vec<u8>,
+}
+
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+impl ServerConn {
+    // The stream to exchange AMQP data over, which is the socket unless we're speaking TLS.
+    fn io(&mut self) -> Box<dyn ReadWrite + '_> {
+        #[cfg(feature = "rustls")]
+        {
+            if let Some(tls) = &mut self.tls {
+                return Box::new(rustls_crate::Stream::new(tls, &mut self.stream));
+            }
+        }
+        Box::new(&mut self.stream)
+    }
+
+    pub(super) fn stream(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+
+    // The DER-encoded certificate the client presented, if we're speaking TLS and it sent one.
+    #[cfg(feature = "rustls")]
+    pub(super) fn client_certificate(&self) -> Option<Vec<u8>> {
+        let certs = self.tls.as_ref()?.peer_certificates()?;
+        certs.first().map(|cert| cert.0.clone())
+    }
+
+    pub(super) fn expect_protocol_header(&mut self) {
+        let mut header = [0; 8];
+        self.io().read_exact(&mut header).unwrap();
+        assert_eq!(&header, b"AMQP\x00\x00\x09\x01");
+    }
+
+    // Returns None if the client closes the socket.
+    pub(super) fn try_recv_frame(&mut self) -> Option<AMQPFrame> {
+        loop {
+            if let Ok((rest, frame)) = parse_frame(&self.buf) {
+                let consumed = self.buf.len() - rest.len();
+                self.buf.drain(..consumed);
+                return Some(frame);
+            }
+            let mut chunk = [0; 4096];
+            let result = self.io().read(&mut chunk);
+            match result {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    pub(super) fn recv_frame(&mut self) -> AMQPFrame {
+        self.try_recv_frame()
+            .expect("client closed socket unexpectedly")
+    }
+
+    // Receive the next non-heartbeat frame, which must be a method.
+    pub(super) fn recv_method(&mut self) -> (u16, AMQPClass) {
+        loop {
+            match self.recv_frame() {
+                AMQPFrame::Heartbeat(_) => continue,
+                AMQPFrame::Method(channel_id, method) => return (channel_id, method),
+                frame => panic!("expected method, got {:?}", frame),
+            }
+        }
+    }
+
+    pub(super) fn send_raw(&mut self, bytes: &[u8]) {
+        self.io().write_all(bytes).unwrap();
+    }
+
+    pub(super) fn send_method<M: IntoAmqpClass>(&mut self, channel_id: u16, method: M) {
+        let mut buf = OutputBuffer::empty();
+        buf.push_method(channel_id, method);
+        self.send_raw(&buf[0..]);
+    }
+
+    pub(super) fn send_heartbeat(&mut self) {
+        let mut buf = OutputBuffer::empty();
+        buf.push_heartbeat();
+        self.send_raw(&buf[0..]);
+    }
+
+    // Send a content header followed by body frames (none if `body` is empty).
+    pub(super) fn send_content(
+        &mut self,
+        channel_id: u16,
+        body: &[u8],
+        properties: &AmqpProperties,
+    ) {
+        let mut buf = OutputBuffer::empty();
+        buf.push_content_header(channel_id, 60, body.len() as u64, properties);
+        if !body.is_empty() {
+            buf.push_content_body(channel_id, body);
+        }
+        self.send_raw(&buf[0..]);
+    }
+
+    // Send just a content header announcing `body_size` bytes, leaving the body frames to
+    // `send_content_body`.
+    pub(super) fn send_content_header(
+        &mut self,
+        channel_id: u16,
+        body_size: u64,
+        properties: &AmqpProperties,
+    ) {
+        let mut buf = OutputBuffer::empty();
+        buf.push_content_header(channel_id, 60, body_size, properties);
+        self.send_raw(&buf[0..]);
+    }
+
+    pub(super) fn send_content_body(&mut self, channel_id: u16, body: &[u8]) {
+        let mut buf = OutputBuffer::empty();
+        buf.push_content_body(channel_id, body);
+        self.send_raw(&buf[0..]);
+    }
+
+    pub(super) fn start_method() -> Start {
+        let mut capabilities = FieldTable::new();
+        for cap in &[
+            "basic.nack",
+            "connection.blocked",
+            "consumer_cancel_notify",
+            "exchange_exchange_bindings",
+            "publisher_confirms",
+        ] {
+            capabilities.insert(cap.to_string(), AmqpValue::Boolean(true));
+        }
+        let mut server_properties = FieldTable::new();
+        server_properties.insert(
+            "product".to_string(),
+            AmqpValue::LongString("amiquip-mock".to_string()),
+        );
+        server_properties.insert(
+            "capabilities".to_string(),
+            AmqpValue::FieldTable(capabilities),
+        );
+        Start {
+            version_major: 0,
+            version_minor: 9,
+            server_properties,
+            mechanisms: "PLAIN AMQPLAIN".to_string(),
+            locales: "en_US".to_string(),
+        }
+    }
+
+    pub(super) fn handshake(&mut self) {
+        self.handshake_with_tune(DEFAULT_TUNE);
+    }
+
+    pub(super) fn handshake_with_tune(&mut self, tune: Tune) {
+        self.start_handshake(Self::start_method());
+        self.finish_handshake(tune);
+    }
+
+    // Send start and return the client's start-ok, leaving room for secure challenges.
+    pub(super) fn start_handshake(&mut self, start: Start) -> StartOk {
+        self.expect_protocol_header();
+        self.send_method(0, AmqpConnection::Start(start));
+        match self.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::StartOk(start_ok))) => start_ok,
+            other => panic!("expected start-ok, got {:?}", other),
+        }
+    }
+
+    // Send a secure challenge and return the client's response.
+    pub(super) fn challenge(&mut self, challenge: &str) -> String {
+        self.send_method(
+            0,
+            AmqpConnection::Secure(Secure {
+                challenge: challenge.to_string(),
+            }),
+        );
+        match self.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::SecureOk(secure_ok))) => secure_ok.response,
+            other => panic!("expected secure-ok, got {:?}", other),
+        }
+    }
+
+    pub(super) fn finish_handshake(&mut self, tune: Tune) {
+        self.send_method(0, AmqpConnection::Tune(tune));
+        match self.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::TuneOk(_))) => (),
+            other => panic!("expected tune-ok, got {:?}", other),
+        }
+        match self.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::Open(_))) => (),
+            other => panic!("expected open, got {:?}", other),
+        }
+        self.send_method(
+            0,
+            AmqpConnection::OpenOk(OpenOk {
+                known_hosts: String::new(),
+            }),
+        );
+    }
+
+    // Expect a channel.open and accept it, returning the channel id.
+    pub(super) fn accept_channel(&mut self) -> u16 {
+        match self.recv_method() {
+            (n, AMQPClass::Channel(AmqpChannel::Open(_))) => {
+                self.send_method(
+                    n,
+                    AmqpChannel::OpenOk(ChannelOpenOk {
+                        channel_id: String::new(),
+                    }),
+                );
+                n
+            }
+            other => panic!("expected channel open, got {:?}", other),
+        }
+    }
+
+    pub(super) fn accept_channel_close(&mut self, channel_id: u16) {
+        match self.recv_method() {
+            (n, AMQPClass::Channel(AmqpChannel::Close(_))) if n == channel_id => {
+                self.send_method(n, AmqpChannel::CloseOk(ChannelCloseOk {}));
+            }
+            other => panic!("expected channel close, got {:?}", other),
+        }
+    }
+
+    pub(super) fn accept_confirm_select(&mut self, channel_id: u16) {
+        match self.recv_method() {
+            (n, AMQPClass::Confirm(AmqpConfirm::Select(select))) if n == channel_id => {
+                if !select.nowait {
+                    self.send_method(n, AmqpConfirm::SelectOk(SelectOk {}));
+                }
+            }
+            other => panic!("expected confirm select, got {:?}", other),
+        }
+    }
+
+    // Expect a tx.select, tx.commit or tx.rollback and reply to it, returning the method.
+    pub(super) fn accept_tx(&mut self, channel_id: u16) -> AmqpTx {
+        let method = match self.recv_method() {
+            (n, AMQPClass::Tx(method)) if n == channel_id => method,
+            other => panic!("expected tx method, got {:?}", other),
+        };
+        let reply = match method {
+            AmqpTx::Select(_) => AmqpTx::SelectOk(TxSelectOk {}),
+            AmqpTx::Commit(_) => AmqpTx::CommitOk(TxCommitOk {}),
+            AmqpTx::Rollback(_) => AmqpTx::RollbackOk(TxRollbackOk {}),
+            ref other => panic!("unexpected tx method {:?}", other),
+        };
+        self.send_method(channel_id, reply);
+        method
+    }
+
+    // Expect a basic.publish and its content, returning the method and body.
+    pub(super) fn recv_publish(&mut self, channel_id: u16) -> (Publish, Vec<u8>) {
+        let (publish, _, body) = self.recv_publish_with_properties(channel_id);
+        (publish, body)
+    }
+
+    // Expect a basic.publish and its content, returning the method, properties, and body.
+    pub(super) fn recv_publish_with_properties(
+        &mut self,
+        channel_id: u16,
+    ) -> (Publish, AmqpProperties, Vec<u8>) {
+        let publish = match self.recv_method() {
+            (n, AMQPClass::Basic(AmqpBasic::Publish(publish))) if n == channel_id => publish,
+            other => panic!("expected publish, got {:?}", other),
+        };
+        let (body_size, properties) = match self.recv_frame() {
+            AMQPFrame::Header(n, _, header) if n == channel_id => {
+                (header.body_size as usize, header.properties)
+            }
+            other => panic!("expected content header, got {:?}", other),
+        };
+        let mut body = Vec::with_capacity(body_size);
+        while body.len() < body_size {
+            match self.recv_frame() {
+                AMQPFrame::Body(n, chunk) if n == channel_id => body.extend(chunk),
+                other => panic!("expected content body, got {:?}", other),
+            }
+        }
+        (publish, properties, body)
+    }
+
+    // Expect a basic.consume and accept it as consumer "ctag".
+    pub(super) fn accept_consume(&mut self, channel_id: u16) {
+        self.accept_consume_as(channel_id, "ctag");
+    }
+
+    pub(super) fn accept_consume_as(&mut self, channel_id: u16, consumer_tag: &str) {
+        match self.recv_method() {
+            (n, AMQPClass::Basic(AmqpBasic::Consume(_))) if n == channel_id => (),
+            other => panic!("expected consume, got {:?}", other),
+        }
+        self.send_method(
+            channel_id,
+            AmqpBasic::ConsumeOk(ConsumeOk {
+                consumer_tag: consumer_tag.to_string(),
+            }),
+        );
+    }
+
+    // Expect a basic.cancel and accept it, returning the cancelled consumer's tag.
+    pub(super) fn accept_cancel(&mut self, channel_id: u16) -> String {
+        match self.recv_method() {
+            (n, AMQPClass::Basic(AmqpBasic::Cancel(cancel))) if n == channel_id => {
+                self.send_method(
+                    n,
+                    AmqpBasic::CancelOk(CancelOk {
+                        consumer_tag: cancel.consumer_tag.clone(),
+                    }),
+                );
+                cancel.consumer_tag
+            }
+            other => panic!("expected cancel, got {:?}", other),
+        }
+    }
+
+    // A basic.deliver to consumer "ctag" from the default exchange with routing key "q". Tests
+    // that care about the other fields override them.
+    pub(super) fn deliver_method(delivery_tag: u64) -> Deliver {
+        Deliver {
+            consumer_tag: "ctag".to_string(),
+            delivery_tag,
+            redelivered: false,
+            exchange: String::new(),
+            routing_key: "q".to_string(),
+        }
+    }
+
+    // Deliver `body` with default properties to consumer "ctag".
+    pub(super) fn deliver(&mut self, channel_id: u16, delivery_tag: u64, body: &[u8]) {
+        self.deliver_as(channel_id, "ctag", delivery_tag, body);
+    }
+
+    pub(super) fn deliver_with_properties(
+        &mut self,
+        channel_id: u16,
+        delivery_tag: u64,
+        body: &[u8],
+        properties: &AmqpProperties,
+    ) {
+        let deliver = Self::deliver_method(delivery_tag);
+        self.send_delivery(channel_id, deliver, body, properties);
+    }
+
+    pub(super) fn deliver_as(
+        &mut self,
+        channel_id: u16,
+        consumer_tag: &str,
+        delivery_tag: u64,
+        body: &[u8],
+    ) {
+        let deliver = Deliver {
+            consumer_tag: consumer_tag.to_string(),
+            ..Self::deliver_method(delivery_tag)
+        };
+        self.send_delivery(channel_id, deliver, body, &AmqpProperties::default());
+    }
+
+    // Send `deliver` followed by its content.
+    pub(super) fn send_delivery(
+        &mut self,
+        channel_id: u16,
+        deliver: Deliver,
+        body: &[u8],
+        properties: &AmqpProperties,
+    ) {
+        self.send_method(channel_id, AmqpBasic::Deliver(deliver));
+        self.send_content(channel_id, body, properties);
+    }
+
+    pub(super) fn recv_ack(&mut self, channel_id: u16) -> Ack {
+        match self.recv_method() {
+            (n, AMQPClass::Basic(AmqpBasic::Ack(ack))) if n == channel_id => ack,
+            other => panic!("expected ack, got {:?}", other),
+        }
+    }
+
+    // Expect a basic.ack of a single delivery, returning its tag.
+    pub(super) fn recv_single_ack(&mut self, channel_id: u16) -> u64 {
+        let ack = self.recv_ack(channel_id);
+        assert!(!ack.multiple, "expected single ack, got {:?}", ack);
+        ack.delivery_tag
+    }
+
+    pub(super) fn accept_connection_close(&mut self) {
+        self.expect_connection_close();
+        self.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+        self.wait_for_client_eof();
+    }
+
+    pub(super) fn expect_connection_close(&mut self) -> Close {
+        match self.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::Close(close))) => close,
+            other => panic!("expected connection close, got {:?}", other),
+        }
+    }
+
+    // Let the client hang up first so its I/O thread never sees an unexpected EOF.
+    pub(super) fn wait_for_client_eof(&mut self) {
+        if let Some(frame) = self.try_recv_frame() {
+            panic!("unexpected frame after close: {:?}", frame);
+        }
+    }
+}
+
+// Wait for the next consumer message, which must be a delivery.
+#[cfg(feature = "consume")]
+pub(super) fn recv_delivery(rx: &Receiver<ConsumerMessage>) -> Delivery {
+    match rx.recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => delivery,
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+}
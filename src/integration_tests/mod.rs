@@ -2,7 +2,98 @@ use crate::{Channel, Connection};
 use std::env;
 use std::sync::Once;
 
+#[cfg(feature = "consume")]
+mod ack_policy;
+#[cfg(feature = "consume")]
+mod bindings;
+mod blocking_stream;
+mod channel_drop;
+mod channel_ids;
+mod channel_pool;
+mod coalesce;
+#[cfg(all(feature = "chaos", feature = "consume"))]
+mod chaos;
+#[cfg(all(feature = "compression", feature = "consume"))]
+mod compression;
+mod confirms;
+mod connection_manager;
+#[cfg(feature = "consume")]
+mod consumer_buffer;
+#[cfg(feature = "consume")]
+mod consumer_cancel;
+#[cfg(feature = "consume")]
+mod consumer_iter;
+#[cfg(feature = "consume")]
+mod consumer_options;
+#[cfg(feature = "consume")]
+mod consumer_pause;
+#[cfg(feature = "consume")]
+mod consumer_group;
+#[cfg(feature = "consume")]
+mod dead_letter;
+mod deadline;
+mod delayed_exchange;
+#[cfg(feature = "consume")]
+mod delivery_batch;
+#[cfg(feature = "consume")]
+mod delivery_stream;
+#[cfg(feature = "consume")]
+mod dispatch;
+#[cfg(feature = "consume")]
+mod empty_body;
+mod endpoints;
 mod exchange;
+mod expiration;
+mod exists;
+mod frame_tap;
+#[cfg(feature = "consume")]
+mod get;
+mod graceful_shutdown;
+mod handshake;
+#[cfg(all(feature = "serde", feature = "consume"))]
+mod json;
+#[cfg(feature = "consume")]
+mod headers;
+mod io_thread_panic;
+mod mock_server;
+mod nowait;
+mod observer;
+mod on_close;
+#[cfg(not(feature = "consume"))]
+mod publish_only;
+mod publish_defaults;
+mod publish_stream;
+mod publisher;
+#[cfg(feature = "consume")]
+mod qos;
+mod queue;
+#[cfg(feature = "consume")]
+mod redelivery_policy;
+mod reply_code;
+#[cfg(feature = "consume")]
+mod republish;
+mod returns;
+#[cfg(feature = "consume")]
+mod rpc_server;
+#[cfg(feature = "rustls")]
+mod rustls;
+mod shared_body;
+#[cfg(feature = "consume")]
+mod shovel;
+#[cfg(feature = "consume")]
+mod shutdown;
+mod socks5;
+#[cfg(feature = "tracing")]
+mod spans;
+#[cfg(feature = "consume")]
+mod stats;
+#[cfg(feature = "consume")]
+mod topology;
+mod topology_apply;
+mod transactions;
+mod wakeup;
+#[cfg(feature = "consume")]
+mod worker_pool;
 
 static PRINT_WARNING: Once = Once::new();
 
@@ -0,0 +1,154 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{AmqpReplyCode, Connection, Error, ExchangeDeclareOptions, ExchangeType};
+use crate::{FieldTable, QueueDeclareOptions};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::exchange::AMQPMethod as AmqpExchange;
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::AMQPClass;
+
+#[cfg(feature = "consume")]
+use crate::{AmqpProperties, ConsumerMessage, ConsumerOptions};
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::Deliver;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::exchange::DeclareOk as ExchangeDeclareOk;
+#[cfg(feature = "consume")]
+use std::time::Duration;
+
+// Expect the passive declare of amq.direct that `Channel::sync` sends, without replying.
+fn recv_sync(conn: &mut ServerConn, channel_id: u16) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == channel_id => {
+            assert_eq!(declare.exchange, "amq.direct");
+            assert!(declare.passive);
+            assert!(!declare.nowait);
+        }
+        other => panic!("expected exchange.declare, got {:?}", other),
+    }
+}
+
+#[test]
+fn sync_surfaces_failed_nowait_method() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == n => {
+                assert_eq!(declare.exchange, "x");
+                assert!(declare.nowait);
+            }
+            other => panic!("expected exchange.declare, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                assert_eq!(declare.queue, "q");
+                assert!(declare.nowait);
+            }
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Bind(bind))) if ch == n => {
+                assert_eq!(bind.exchange, "missing");
+                assert!(bind.nowait);
+            }
+            other => panic!("expected queue.bind, got {:?}", other),
+        }
+        recv_sync(&mut conn, n);
+        // The bind failed before the server got to the passive declare.
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 404,
+                reply_text: "NOT_FOUND - no exchange 'missing' in vhost '/'".to_string(),
+                class_id: 50,
+                method_id: 20,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel.close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    channel
+        .exchange_declare_nowait(ExchangeType::Topic, "x", ExchangeDeclareOptions::default())
+        .unwrap();
+    channel
+        .queue_declare_nowait("q", QueueDeclareOptions::default())
+        .unwrap();
+    channel
+        .queue_bind_nowait("q", "missing", "", FieldTable::new())
+        .unwrap();
+    match channel.sync() {
+        Err(Error::ServerClosedChannel {
+            reply_code: AmqpReplyCode::NotFound,
+            method: Some("queue.bind"),
+            ..
+        }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[cfg(feature = "consume")]
+#[test]
+fn nowait_consumer_receives_deliveries_without_consume_ok() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Consume(consume))) if ch == n => {
+                assert_eq!(consume.queue, "q");
+                assert_eq!(consume.consumer_tag, "my-tag");
+                assert!(consume.nowait);
+            }
+            other => panic!("expected basic.consume, got {:?}", other),
+        }
+        conn.send_method(
+            n,
+            AmqpBasic::Deliver(Deliver {
+                consumer_tag: "my-tag".to_string(),
+                delivery_tag: 1,
+                redelivered: false,
+                exchange: String::new(),
+                routing_key: "q".to_string(),
+            }),
+        );
+        conn.send_content(n, b"hello", &AmqpProperties::default());
+        recv_sync(&mut conn, n);
+        conn.send_method(n, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        no_ack: true,
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel
+        .basic_consume_nowait("q", "my-tag", options)
+        .unwrap();
+    assert_eq!(consumer.consumer_tag(), "my-tag");
+    channel.sync().unwrap();
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => assert_eq!(delivery.body, b"hello"),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+
+    // Leak the consumer so only the channel close ends it.
+    std::mem::forget(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
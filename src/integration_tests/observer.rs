@@ -0,0 +1,187 @@
+use super::mock_server::{MockServer, DEFAULT_TUNE};
+use crate::{
+    AmqpTuneOk, Auth, Connection, ConnectionObserver, ConnectionOptions, ConnectionTuning,
+    Exchange, Publish,
+};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::connection::Tune;
+use amq_protocol::protocol::AMQPClass;
+use mio::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Event {
+    HandshakeComplete(u32),
+    FrameRead(u16, u16, u16),
+    FrameWritten(u16, u16, u16),
+    HeartbeatReceived,
+    HeartbeatWarning(Duration),
+    ConnectionClose(u16, String),
+}
+
+struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+impl ConnectionObserver for Recorder {
+    fn on_handshake_complete(&self, tune_ok: &AmqpTuneOk) {
+        let event = Event::HandshakeComplete(tune_ok.frame_max);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn on_frame_read(&self, channel_id: u16, class_id: u16, method_id: u16) {
+        let event = Event::FrameRead(channel_id, class_id, method_id);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn on_frame_written(&self, channel_id: u16, class_id: u16, method_id: u16) {
+        let event = Event::FrameWritten(channel_id, class_id, method_id);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn on_heartbeat_received(&self) {
+        self.0.lock().unwrap().push(Event::HeartbeatReceived);
+    }
+
+    fn on_heartbeat_warning(&self, silent_for: Duration) {
+        let event = Event::HeartbeatWarning(silent_for);
+        self.0.lock().unwrap().push(event);
+    }
+
+    fn on_connection_close(&self, reply_code: u16, reply_text: &str) {
+        let event = Event::ConnectionClose(reply_code, reply_text.to_string());
+        self.0.lock().unwrap().push(event);
+    }
+}
+
+struct Panicker;
+
+impl ConnectionObserver for Panicker {
+    fn on_frame_read(&self, _channel_id: u16, _class_id: u16, _method_id: u16) {
+        panic!("observer bug");
+    }
+
+    fn on_frame_written(&self, _channel_id: u16, _class_id: u16, _method_id: u16) {
+        panic!("observer bug");
+    }
+}
+
+fn open_observed(server: &MockServer, observer: Box<dyn ConnectionObserver>) -> Connection {
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let options = ConnectionOptions::<Auth>::default().observer(observer);
+    Connection::insecure_open_stream(stream, options, ConnectionTuning::default()).unwrap()
+}
+
+#[test]
+fn observer_sees_handshake_frames_and_close() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.send_heartbeat();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == n => (),
+            other => panic!("expected publish, got {:?}", other),
+        }
+        match conn.recv_frame() {
+            AMQPFrame::Header(ch, _, _) if ch == n => (),
+            other => panic!("expected content header, got {:?}", other),
+        }
+        match conn.recv_frame() {
+            AMQPFrame::Body(ch, _) if ch == n => (),
+            other => panic!("expected content body, got {:?}", other),
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut connection = open_observed(&server, Box::new(Recorder(Arc::clone(&events))));
+    let channel = connection.open_channel(None).unwrap();
+    let n = channel.channel_id();
+    Exchange::direct(&channel)
+        .publish(Publish::new(b"hello", "q"))
+        .unwrap();
+    channel.close().unwrap();
+    connection.close_with(200, "see you").unwrap();
+    server.join();
+
+    let events = events.lock().unwrap().clone();
+    let position = |event: Event| match events.iter().position(|e| *e == event) {
+        Some(i) => i,
+        None => panic!("{:?} not observed in {:?}", event, events),
+    };
+
+    // connection.start, start-ok, tune, tune-ok, open, and open-ok.
+    let start = position(Event::FrameRead(0, 10, 10));
+    let start_ok = position(Event::FrameWritten(0, 10, 11));
+    let tune = position(Event::FrameRead(0, 10, 30));
+    let tune_ok = position(Event::FrameWritten(0, 10, 31));
+    let open = position(Event::FrameWritten(0, 10, 40));
+    let open_ok = position(Event::FrameRead(0, 10, 41));
+    let handshake_complete = position(Event::HandshakeComplete(DEFAULT_TUNE.frame_max));
+    assert!(start < start_ok && start_ok < tune && tune < tune_ok && tune_ok < open);
+    assert!(open < open_ok && open_ok < handshake_complete);
+
+    position(Event::HeartbeatReceived);
+    // Only the publish method frame is reported, not its content header or body.
+    let publish = position(Event::FrameWritten(n, 60, 40));
+    assert!(publish < position(Event::FrameWritten(n, 20, 40)));
+    let close = position(Event::ConnectionClose(200, "see you".to_string()));
+    assert!(close < position(Event::FrameWritten(0, 10, 50)));
+    position(Event::FrameRead(0, 10, 51));
+    assert_eq!(
+        events
+            .iter()
+            .filter(|e| matches!(e, Event::FrameWritten(_, 60, _)))
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn panicking_observer_does_not_break_connection() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = open_observed(&server, Box::new(Panicker));
+    let channel = connection.open_channel(None).unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn observer_is_warned_before_heartbeats_are_missed() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            heartbeat: 1,
+            ..DEFAULT_TUNE
+        });
+        // Never send a heartbeat; wait for the client to give up on us.
+        while conn.try_recv_frame().is_some() {}
+    });
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let connection = open_observed(&server, Box::new(Recorder(Arc::clone(&events))));
+    let rx = connection.on_close();
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    server.join();
+
+    // With the default limit of 2, one warning after the first silent interval.
+    let warnings = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|e| match e {
+            Event::HeartbeatWarning(silent_for) => Some(*silent_for),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0] >= Duration::from_millis(900));
+    assert!(warnings[0] < Duration::from_millis(1900));
+}
@@ -0,0 +1,138 @@
+use super::mock_server::{MockServer, DEFAULT_TUNE};
+use crate::{ArcError, Connection, Error};
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::{Close, Tune};
+use amq_protocol::protocol::AMQPClass;
+use crossbeam_channel::Receiver;
+use std::time::Duration;
+
+fn recv_once(rx: &Receiver<ArcError>) -> ArcError {
+    let err = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("no close notification");
+    // Exactly one notification; the I/O thread has dropped its end after sending it.
+    assert!(rx.recv().is_err());
+    err
+}
+
+#[test]
+fn client_close() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    let rx = connection.on_close();
+    let rx2 = rx.clone();
+    let other = connection.on_close();
+    connection.close().unwrap();
+
+    assert!(matches!(*recv_once(&rx), Error::ClientClosedConnection));
+    assert!(rx2.try_recv().is_err());
+    assert!(matches!(*recv_once(&other), Error::ClientClosedConnection));
+    server.join();
+}
+
+#[test]
+fn server_close() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.send_method(
+            0,
+            AmqpConnection::Close(Close {
+                reply_code: 320,
+                reply_text: "CONNECTION_FORCED - shutdown".to_string(),
+                class_id: 0,
+                method_id: 0,
+            }),
+        );
+        match conn.recv_method() {
+            (0, AMQPClass::Connection(AmqpConnection::CloseOk(_))) => (),
+            other => panic!("expected close-ok, got {:?}", other),
+        }
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    let rx = connection.on_close();
+    match &*recv_once(&rx) {
+        Error::ServerClosedConnection { code: 320, .. } => (),
+        err => panic!("unexpected error {}", err),
+    }
+
+    // Listeners registered after the connection died hear about it immediately.
+    match &*recv_once(&connection.on_close()) {
+        Error::ServerClosedConnection { code: 320, .. } => (),
+        err => panic!("unexpected error {}", err),
+    }
+    server.join();
+}
+
+#[test]
+fn socket_closed() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        // Hang up once the client shows it has finished opening the connection.
+        conn.accept_channel();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let rx = connection.on_close();
+    let _channel = connection.open_channel(None).unwrap();
+    assert!(matches!(*recv_once(&rx), Error::UnexpectedSocketClose));
+    server.join();
+}
+
+#[test]
+fn missed_heartbeats() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            heartbeat: 1,
+            ..DEFAULT_TUNE
+        });
+        // Never send a heartbeat; wait for the client to give up on us.
+        while conn.try_recv_frame().is_some() {}
+    });
+
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    let rx = connection.on_close();
+    let err = recv_once(&rx);
+    match &*err {
+        // The default limit is two missed heartbeats.
+        Error::MissedServerHeartbeats { since_last_rx } => {
+            assert!(*since_last_rx >= Duration::from_millis(1900));
+        }
+        err => panic!("unexpected error {}", err),
+    }
+    assert!(err
+        .to_string()
+        .starts_with("missed heartbeats from server (last data received "));
+
+    let diagnostics = connection.heartbeat_diagnostics();
+    assert!(diagnostics.last_rx.is_some());
+    assert!(diagnostics.last_tx.is_some());
+    assert_eq!(diagnostics.last_heartbeat_received, None);
+    assert!(diagnostics.last_heartbeat_sent.unwrap() > diagnostics.last_rx.unwrap());
+    server.join();
+}
+
+#[test]
+fn io_thread_panic() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_channel();
+        assert!(conn.try_recv_frame().is_none());
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let rx = connection.on_close();
+    let channel = connection.open_channel(None).unwrap();
+    channel.inject_io_thread_panic("injected panic").unwrap();
+    match &*recv_once(&rx) {
+        Error::EventLoopPanicked { message, .. } => {
+            assert!(message.starts_with("injected panic"), "{}", message)
+        }
+        err => panic!("unexpected error {}", err),
+    }
+    server.join();
+}
@@ -0,0 +1,84 @@
+use super::mock_server::MockServer;
+use crate::{AmqpProperties, Connection, MessageIdSource, Publish, PublishDefaults};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[test]
+fn defaults_fill_in_unset_properties_only() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Defaults only.
+        let (_, properties, _) = conn.recv_publish_with_properties(n);
+        assert_eq!(properties.message_id(), &Some("id-0".to_string()));
+        assert_eq!(properties.app_id(), &Some("auditor".to_string()));
+        let timestamp = properties.timestamp().unwrap();
+        assert!(timestamp + 60 >= now && timestamp <= now + 60);
+
+        // Explicit values win, and no message ID is generated for them.
+        let (_, properties, _) = conn.recv_publish_with_properties(n);
+        assert_eq!(properties.message_id(), &Some("explicit".to_string()));
+        assert_eq!(properties.app_id(), &Some("other-app".to_string()));
+        assert_eq!(properties.timestamp(), &Some(42));
+
+        // A publisher created before the defaults were set still applies them.
+        let (_, properties, _) = conn.recv_publish_with_properties(n);
+        assert_eq!(properties.message_id(), &Some("id-1".to_string()));
+        assert_eq!(properties.app_id(), &Some("auditor".to_string()));
+        assert!(properties.timestamp().is_some());
+
+        // Resetting the defaults stops filling them in.
+        let (_, properties, _) = conn.recv_publish_with_properties(n);
+        assert_eq!(properties, AmqpProperties::default());
+
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let publisher = channel.publisher();
+
+    let next_id = AtomicUsize::new(0);
+    channel.set_publish_defaults(PublishDefaults {
+        auto_timestamp: true,
+        message_id: MessageIdSource::custom(move || {
+            format!("id-{}", next_id.fetch_add(1, Ordering::SeqCst))
+        }),
+        app_id: Some("auditor".to_string()),
+    });
+
+    channel
+        .basic_publish("", Publish::new(b"defaults", "q"))
+        .unwrap();
+    channel
+        .basic_publish(
+            "",
+            Publish::with_properties(
+                b"explicit",
+                "q",
+                AmqpProperties::default()
+                    .with_message_id("explicit".to_string())
+                    .with_app_id("other-app".to_string())
+                    .with_timestamp(42),
+            ),
+        )
+        .unwrap();
+    publisher
+        .publish("", Publish::new(b"publisher", "q"))
+        .unwrap();
+
+    channel.set_publish_defaults(PublishDefaults::default());
+    channel
+        .basic_publish("", Publish::new(b"plain", "q"))
+        .unwrap();
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
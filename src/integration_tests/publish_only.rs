@@ -0,0 +1,35 @@
+use super::mock_server::MockServer;
+use crate::{Connection, Error, QueueDeclareOptions};
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::AMQPClass;
+
+#[test]
+fn unsolicited_delivery_fails_connection() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(_))) if ch == n => (),
+            other => panic!("expected queue declare, got {:?}", other),
+        }
+        conn.deliver(n, 1, b"hello");
+        conn.wait_for_client_eof();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    assert!(channel
+        .queue_declare("q", QueueDeclareOptions::default())
+        .is_err());
+    match connection.close() {
+        Err(Error::UnknownConsumerTag {
+            channel_id,
+            consumer_tag,
+        }) => {
+            assert_eq!(channel_id, channel.channel_id());
+            assert_eq!(consumer_tag, "ctag");
+        }
+        other => panic!("unexpected close result {:?}", other),
+    }
+    server.join();
+}
@@ -0,0 +1,137 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{AmqpProperties, AmqpReplyCode, Connection, Error, Exchange};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::CloseOk;
+use amq_protocol::protocol::AMQPClass;
+use std::io::{self, Read};
+
+// The mock server's frame_max less 8 bytes of frame overhead.
+const MAX_BODY_FRAME: usize = (1 << 17) - 8;
+
+fn body(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// Yields `body`, failing with an error instead once `fail_at` bytes have been read, and records
+// the largest read requested.
+struct TestReader {
+    body: io::Cursor<Vec<u8>>,
+    fail_at: Option<u64>,
+    largest_read: usize,
+}
+
+impl Read for TestReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.largest_read = self.largest_read.max(buf.len());
+        if Some(self.body.position()) == self.fail_at {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "disk on fire"));
+        }
+        self.body.read(buf)
+    }
+}
+
+// Receive a publish on channel `n` and return the sizes of its body frames, checking that the
+// header announced `len` bytes and that the frames hold `expected`.
+fn recv_streamed_publish(conn: &mut ServerConn, n: u16, len: u64, expected: &[u8]) -> Vec<usize> {
+    match conn.recv_method() {
+        (ch, AMQPClass::Basic(AmqpBasic::Publish(publish))) if ch == n => {
+            assert_eq!(publish.routing_key, "big");
+        }
+        other => panic!("expected publish, got {:?}", other),
+    }
+    match conn.recv_frame() {
+        AMQPFrame::Header(ch, _, header) if ch == n => assert_eq!(header.body_size, len),
+        other => panic!("expected content header, got {:?}", other),
+    }
+    let mut frames = Vec::new();
+    let mut received = Vec::new();
+    while received.len() < expected.len() {
+        match conn.recv_frame() {
+            AMQPFrame::Body(ch, chunk) if ch == n => {
+                frames.push(chunk.len());
+                received.extend(chunk);
+            }
+            other => panic!("expected content body, got {:?}", other),
+        }
+    }
+    assert_eq!(received, expected);
+    frames
+}
+
+#[test]
+fn publish_from_reader_streams_frame_sized_chunks() {
+    let len = 2 * MAX_BODY_FRAME + 1000;
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        let frames = recv_streamed_publish(&mut conn, n, len as u64, &body(len));
+        assert_eq!(frames, vec![MAX_BODY_FRAME, MAX_BODY_FRAME, 1000]);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let mut reader = TestReader {
+        body: io::Cursor::new(body(len)),
+        fail_at: None,
+        largest_read: 0,
+    };
+    Exchange::direct(&channel)
+        .publish_from_reader("big", &mut reader, len as u64, AmqpProperties::default())
+        .unwrap();
+    assert_eq!(reader.largest_read, MAX_BODY_FRAME);
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn reader_failure_mid_body_closes_channel() {
+    let len = 3 * MAX_BODY_FRAME;
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        recv_streamed_publish(&mut conn, n, len as u64, &body(MAX_BODY_FRAME));
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::Close(close))) if ch == n => {
+                assert_eq!(close.reply_code, AmqpReplyCode::InternalError.code());
+                assert!(close.reply_text.contains("131064 of 393192"));
+            }
+            other => panic!("expected channel close, got {:?}", other),
+        }
+        conn.send_method(n, AmqpChannel::CloseOk(CloseOk {}));
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let reader = TestReader {
+        body: io::Cursor::new(body(len)),
+        fail_at: Some(MAX_BODY_FRAME as u64),
+        largest_read: 0,
+    };
+    match channel.basic_publish_from_reader(
+        "",
+        "big",
+        reader,
+        len as u64,
+        AmqpProperties::default(),
+    ) {
+        Err(Error::PublishSourceFailed {
+            sent, len: total, ..
+        }) => {
+            assert_eq!(sent, MAX_BODY_FRAME as u64);
+            assert_eq!(total, len as u64);
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    // The channel is gone; dropping it must not try to close it again.
+    drop(channel);
+
+    connection.close().unwrap();
+    server.join();
+}
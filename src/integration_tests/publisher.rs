@@ -0,0 +1,68 @@
+use super::mock_server::{MockServer, DEFAULT_TUNE};
+use crate::{Connection, Publish};
+use amq_protocol::protocol::connection::Tune;
+use std::collections::HashMap;
+use std::thread;
+
+const THREADS: usize = 4;
+const PUBLISHES: usize = 50;
+
+// Spans several body frames with the small frame_max below, so frames from publishes made on
+// different threads would interleave if a publish were not sent as a whole.
+fn body(thread: usize, i: usize) -> Vec<u8> {
+    let mut body = format!("{}:{}:", thread, i).into_bytes();
+    body.resize(10_000, thread as u8);
+    body
+}
+
+#[test]
+fn clones_publish_concurrently_in_order() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake_with_tune(Tune {
+            frame_max: 4096,
+            ..DEFAULT_TUNE
+        });
+        let n = conn.accept_channel();
+        let mut next = HashMap::new();
+        for _ in 0..THREADS * PUBLISHES {
+            let (publish, received) = conn.recv_publish(n);
+            let thread: usize = publish.routing_key.parse().unwrap();
+            let i = next.entry(thread).or_insert(0);
+            assert!(
+                received == body(thread, *i),
+                "publish {} from {}",
+                i,
+                thread
+            );
+            *i += 1;
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let publisher = channel.publisher();
+    assert_eq!(publisher.channel_id(), channel.channel_id());
+    let threads = (0..THREADS)
+        .map(|thread| {
+            let publisher = publisher.clone();
+            thread::spawn(move || {
+                for i in 0..PUBLISHES {
+                    let body = body(thread, i);
+                    publisher
+                        .publish("", Publish::new(&body, thread.to_string()))
+                        .unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    channel.close().unwrap();
+    assert!(publisher.publish("", Publish::new(b"late", "0")).is_err());
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,118 @@
+use super::mock_server::{recv_delivery, MockServer, ServerConn};
+use super::with_chan;
+use crate::{
+    Connection, ConsumerMessage, ConsumerOptions, Publish, QueueDeclareOptions, QueueDeleteOptions,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Qos, QosOk};
+use amq_protocol::protocol::AMQPClass;
+use crossbeam_channel::Receiver;
+use std::time::Duration;
+
+fn accept_qos(conn: &mut ServerConn, channel_id: u16) -> Qos {
+    match conn.recv_method() {
+        (ch, AMQPClass::Basic(AmqpBasic::Qos(qos))) if ch == channel_id => {
+            conn.send_method(channel_id, AmqpBasic::QosOk(QosOk {}));
+            qos
+        }
+        other => panic!("expected qos, got {:?}", other),
+    }
+}
+
+#[test]
+fn set_prefetch_while_deliveries_are_in_flight() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        let qos = accept_qos(&mut conn, n);
+        assert_eq!((qos.prefetch_count, qos.global), (1, true));
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"body");
+
+        // The client raises the limit without acking; a delivery sent before qos-ok must still
+        // reach it.
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Qos(qos))) if ch == n => {
+                assert_eq!((qos.prefetch_count, qos.global), (3, true));
+            }
+            other => panic!("expected qos, got {:?}", other),
+        }
+        conn.deliver(n, 2, b"body");
+        conn.send_method(n, AmqpBasic::QosOk(QosOk {}));
+        conn.deliver(n, 3, b"body");
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                assert_eq!((ack.delivery_tag, ack.multiple), (3, true));
+            }
+            other => panic!("expected ack, got {:?}", other),
+        }
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    channel.qos(0, 1, true).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let first = recv_delivery(consumer.receiver());
+    assert_eq!(first.delivery_tag(), 1);
+
+    consumer.set_prefetch(3).unwrap();
+    assert_eq!(recv_delivery(consumer.receiver()).delivery_tag(), 2);
+    let third = recv_delivery(consumer.receiver());
+    assert_eq!(third.delivery_tag(), 3);
+    consumer.ack_multiple(third).unwrap();
+
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_adjust_prefetch_while_consuming() {
+    with_chan(|chan| {
+        let queue = chan
+            .queue_declare("", QueueDeclareOptions::default())
+            .unwrap();
+        for _ in 0..10 {
+            chan.basic_publish("", Publish::new(b"message", queue.name()))
+                .unwrap();
+        }
+
+        // Count deliveries that arrive without acking; the broker stops at the prefetch limit.
+        let drain = |rx: &Receiver<ConsumerMessage>| {
+            let mut deliveries = Vec::new();
+            while let Ok(ConsumerMessage::Delivery(delivery)) =
+                rx.recv_timeout(Duration::from_millis(500))
+            {
+                deliveries.push(delivery);
+            }
+            deliveries
+        };
+
+        chan.qos(0, 2, true).unwrap();
+        let consumer = queue.consume(ConsumerOptions::default()).unwrap();
+        let batch = drain(consumer.receiver());
+        assert_eq!(batch.len(), 2);
+
+        consumer.set_prefetch(5).unwrap();
+        let batch = drain(consumer.receiver());
+        assert_eq!(batch.len(), 3);
+
+        // Lowering the limit holds back further deliveries until enough have been acked.
+        consumer.set_prefetch(1).unwrap();
+        consumer.ack_multiple(batch.into_iter().last().unwrap()).unwrap();
+        let batch = drain(consumer.receiver());
+        assert_eq!(batch.len(), 1);
+
+        consumer.cancel().unwrap();
+        drop(consumer);
+        queue.delete(QueueDeleteOptions::default()).unwrap();
+    })
+}
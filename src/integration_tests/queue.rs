@@ -0,0 +1,388 @@
+use super::mock_server::{MockServer, ServerConn};
+use super::with_chan;
+use crate::{
+    AmqpReplyCode, AmqpValue, Connection, Error, PriorityValidation, Publish, QueueArguments,
+    QueueDeclareOptions, QueueDeleteOptions, QueueInfo, QueueMode, QueueType,
+};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::queue::{DeclareOk, PurgeOk};
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+fn accept_declare(conn: &mut ServerConn, channel_id: u16, message_count: u32) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == channel_id => {
+            conn.send_method(
+                channel_id,
+                AmqpQueue::DeclareOk(DeclareOk {
+                    queue: declare.queue,
+                    message_count,
+                    consumer_count: 0,
+                }),
+            );
+        }
+        other => panic!("expected queue.declare, got {:?}", other),
+    }
+}
+
+#[test]
+fn purge_returns_count_and_failed_delete_closes_channel() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        accept_declare(&mut conn, n, 3);
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Purge(purge))) if ch == n => {
+                assert_eq!(purge.queue, "q");
+            }
+            other => panic!("expected queue.purge, got {:?}", other),
+        }
+        conn.send_method(n, AmqpQueue::PurgeOk(PurgeOk { message_count: 3 }));
+
+        match conn.recv_method() {
+            // amq-protocol's parser drops multi-word flags like `if-empty`, so we can't check
+            // them here.
+            (ch, AMQPClass::Queue(AmqpQueue::Delete(delete))) if ch == n => {
+                assert_eq!(delete.queue, "q");
+            }
+            other => panic!("expected queue.delete, got {:?}", other),
+        }
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 406,
+                reply_text: "PRECONDITION_FAILED - queue 'q' in vhost '/' not empty".to_string(),
+                class_id: 50,
+                method_id: 40,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel.close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let queue = channel
+        .queue_declare("q", QueueDeclareOptions::default())
+        .unwrap();
+    assert_eq!(queue.purge().unwrap(), 3);
+
+    let options = QueueDeleteOptions {
+        if_empty: true,
+        ..QueueDeleteOptions::default()
+    };
+    match queue.delete(options) {
+        Err(Error::ServerClosedChannel {
+            reply_code: AmqpReplyCode::PreconditionFailed,
+            method: Some("queue.delete"),
+            ..
+        }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn redeclare_requeries_counts_of_server_named_queue() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                assert_eq!(declare.queue, "");
+                assert!(!declare.passive);
+            }
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+        conn.send_method(
+            n,
+            AmqpQueue::DeclareOk(DeclareOk {
+                queue: "amq.gen-abc".to_string(),
+                message_count: 0,
+                consumer_count: 0,
+            }),
+        );
+        for (message_count, consumer_count) in &[(5, 1), (2, 2)] {
+            match conn.recv_method() {
+                (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                    assert_eq!(declare.queue, "amq.gen-abc");
+                    assert!(declare.passive);
+                }
+                other => panic!("expected queue.declare, got {:?}", other),
+            }
+            conn.send_method(
+                n,
+                AmqpQueue::DeclareOk(DeclareOk {
+                    queue: "amq.gen-abc".to_string(),
+                    message_count: *message_count,
+                    consumer_count: *consumer_count,
+                }),
+            );
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let queue = channel
+        .queue_declare("", QueueDeclareOptions::default())
+        .unwrap();
+    assert_eq!(queue.name(), "amq.gen-abc");
+    assert_eq!(queue.declared_message_count(), Some(0));
+    assert_eq!(queue.declared_consumer_count(), Some(0));
+
+    for &(message_count, consumer_count) in &[(5, 1), (2, 2)] {
+        assert_eq!(
+            queue.redeclare().unwrap(),
+            QueueInfo {
+                name: "amq.gen-abc".to_string(),
+                message_count,
+                consumer_count,
+            }
+        );
+    }
+    assert_eq!(queue.declared_message_count(), Some(0));
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_purge_and_delete_counts() {
+    with_chan(|chan| {
+        let queue = chan
+            .queue_declare("", QueueDeclareOptions::default())
+            .unwrap();
+        let publish = |n| {
+            for _ in 0..n {
+                chan.basic_publish("", Publish::new(b"message", queue.name()))
+                    .unwrap();
+            }
+        };
+
+        publish(3);
+        assert_eq!(queue.purge().unwrap(), 3);
+        assert_eq!(queue.purge().unwrap(), 0);
+
+        publish(2);
+        assert_eq!(queue.delete(QueueDeleteOptions::default()).unwrap(), 2);
+    })
+}
+
+#[test]
+fn test_delete_if_empty_fails_on_nonempty_queue() {
+    with_chan(|chan| {
+        let queue = chan
+            .queue_declare("", QueueDeclareOptions::default())
+            .unwrap();
+        chan.basic_publish("", Publish::new(b"message", queue.name()))
+            .unwrap();
+        let options = QueueDeleteOptions {
+            if_empty: true,
+            ..QueueDeleteOptions::default()
+        };
+        let err = queue.delete(options).unwrap_err();
+        assert_eq!(err.reply_code(), Some(AmqpReplyCode::PreconditionFailed));
+    })
+}
+
+#[test]
+fn test_declare_with_typed_arguments() {
+    let cases = vec![
+        QueueArguments::new().message_ttl(Duration::from_secs(60)),
+        QueueArguments::new().expires(Duration::from_secs(60)),
+        QueueArguments::new().max_length(10),
+        QueueArguments::new().max_length_bytes(1 << 20),
+        QueueArguments::new().dead_letter_exchange("amiquip-test-dlx"),
+        QueueArguments::new()
+            .dead_letter_exchange("")
+            .dead_letter_routing_key("dead"),
+        QueueArguments::new().max_priority(10),
+        QueueArguments::new().queue_mode(QueueMode::Lazy),
+        QueueArguments::new().queue_type(QueueType::Classic),
+        QueueArguments::new().queue_type(QueueType::Quorum),
+        QueueArguments::new().queue_type(QueueType::Stream),
+    ];
+
+    with_chan(|chan| {
+        for (i, arguments) in cases.into_iter().enumerate() {
+            // Quorum queues and streams must be durable; declare everything that way so they
+            // share one code path, and delete each queue when we're done with it.
+            let options = QueueDeclareOptions {
+                durable: true,
+                arguments: arguments.build().unwrap(),
+                ..QueueDeclareOptions::default()
+            };
+            let queue = chan
+                .queue_declare(format!("amiquip-test-arguments-{}", i), options)
+                .unwrap();
+            queue.delete(QueueDeleteOptions::default()).unwrap();
+        }
+    })
+}
+
+#[test]
+fn quorum_declare_is_validated_and_type_mismatch_is_described() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        // The invalid declarations are never sent.
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                assert_eq!(declare.queue, "jobs");
+                assert!(declare.durable);
+                assert_eq!(
+                    declare.arguments.get("x-queue-type"),
+                    Some(&AmqpValue::LongString("quorum".to_string()))
+                );
+            }
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 406,
+                reply_text: "PRECONDITION_FAILED - inequivalent arg 'x-queue-type' for queue \
+                             'jobs' in vhost '/': received the value 'quorum' of type 'longstr' \
+                             but current is none"
+                    .to_string(),
+                class_id: 50,
+                method_id: 10,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel.close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+
+    let invalid = |options: QueueDeclareOptions| match channel.queue_declare("jobs", options) {
+        Err(Error::InvalidQueueDeclaration { message }) => message,
+        other => panic!(
+            "unexpected result {:?}",
+            other.map(|q| q.name().to_string())
+        ),
+    };
+    assert_eq!(
+        invalid(QueueDeclareOptions {
+            auto_delete: true,
+            ..QueueDeclareOptions::quorum()
+        }),
+        "quorum queues cannot be auto_delete"
+    );
+    assert_eq!(
+        invalid(QueueDeclareOptions {
+            durable: false,
+            ..QueueDeclareOptions::stream()
+        }),
+        "streams must be durable"
+    );
+    let mut options = QueueDeclareOptions::stream();
+    options
+        .arguments
+        .insert("x-message-ttl".to_string(), AmqpValue::LongLongInt(1000));
+    assert_eq!(
+        invalid(options),
+        "streams do not support the x-message-ttl argument"
+    );
+
+    let err = match channel.queue_declare("jobs", QueueDeclareOptions::quorum()) {
+        Err(err) => err,
+        Ok(_) => panic!("declare of mismatched queue succeeded"),
+    };
+    assert_eq!(err.reply_code(), Some(AmqpReplyCode::PreconditionFailed));
+    match err {
+        Error::InequivalentQueueArgument {
+            queue, argument, ..
+        } => assert_eq!(
+            (queue.as_str(), argument.as_str()),
+            ("jobs", "x-queue-type")
+        ),
+        other => panic!("unexpected error {:?}", other),
+    }
+
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn check_priority_against_declared_max_priority() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        accept_declare(&mut conn, n, 0);
+        accept_declare(&mut conn, n, 0);
+        accept_declare(&mut conn, n, 0);
+        let (_, properties, _) = conn.recv_publish_with_properties(n);
+        assert_eq!(properties.priority(), &Some(3));
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = QueueDeclareOptions {
+        arguments: QueueArguments::new().max_priority(5).build().unwrap(),
+        ..QueueDeclareOptions::default()
+    };
+    let priority_queue = channel.queue_declare("urgent", options).unwrap();
+    let plain_queue = channel
+        .queue_declare("plain", QueueDeclareOptions::default())
+        .unwrap();
+    let passive_queue = channel.queue_declare_passive("unknown").unwrap();
+    assert_eq!(priority_queue.max_priority(), Some(5));
+    assert_eq!(plain_queue.max_priority(), None);
+    assert_eq!(passive_queue.max_priority(), None);
+
+    let low = Publish::new(b"", "urgent").with_priority(3);
+    let high = Publish::new(b"", "urgent").with_priority(9);
+    let unset = Publish::new(b"", "urgent");
+    assert_eq!(low.priority(), Some(3));
+    for publish in &[&low, &unset] {
+        priority_queue
+            .check_priority(publish, PriorityValidation::Error)
+            .unwrap();
+    }
+    match priority_queue.check_priority(&high, PriorityValidation::Error) {
+        Err(Error::UnsupportedMessagePriority {
+            priority: 9,
+            max_priority: Some(5),
+            ..
+        }) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    priority_queue
+        .check_priority(&high, PriorityValidation::Warn)
+        .unwrap();
+    match plain_queue.check_priority(&low, PriorityValidation::Error) {
+        Err(
+            err @ Error::UnsupportedMessagePriority {
+                max_priority: None, ..
+            },
+        ) => assert!(err.to_string().contains("not a priority queue")),
+        other => panic!("unexpected result {:?}", other),
+    }
+    passive_queue
+        .check_priority(&high, PriorityValidation::Error)
+        .unwrap();
+
+    channel.basic_publish("", low).unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,275 @@
+use super::mock_server::{recv_delivery, MockServer, ServerConn};
+use crate::{
+    AmqpProperties, AmqpValue, Confirm, Connection, ConsumerOptions, FieldTable, Publish,
+    RedeliveryPolicy,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Ack, Deliver, Return};
+use amq_protocol::protocol::AMQPClass;
+use std::collections::HashSet;
+use std::time::Duration;
+
+fn with_delivery_count(count: i64) -> AmqpProperties {
+    let mut headers = FieldTable::new();
+    headers.insert(
+        "x-delivery-count".to_string(),
+        AmqpValue::LongLongInt(count),
+    );
+    headers.insert(
+        "trace-id".to_string(),
+        AmqpValue::LongString("t1".to_string()),
+    );
+    AmqpProperties::default().with_headers(headers)
+}
+
+// A delivery from the "jobs" exchange, whose exchange and routing key parked messages record.
+fn job(delivery_tag: u64, redelivered: bool) -> Deliver {
+    Deliver {
+        redelivered,
+        exchange: "jobs".to_string(),
+        routing_key: "jobs.resize".to_string(),
+        ..ServerConn::deliver_method(delivery_tag)
+    }
+}
+
+fn header<'a>(properties: &'a AmqpProperties, name: &str) -> Option<&'a AmqpValue> {
+    properties.headers().as_ref().and_then(|h| h.get(name))
+}
+
+fn confirm(conn: &mut ServerConn, channel_id: u16, delivery_tag: u64) {
+    conn.send_method(
+        channel_id,
+        AmqpBasic::Ack(Ack {
+            delivery_tag,
+            multiple: false,
+        }),
+    );
+}
+
+#[test]
+fn deliveries_over_delivery_count_are_parked_and_acked() {
+    let (acked_tx, acked_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.accept_consume(n);
+        conn.send_delivery(n, job(1, true), b"under", &with_delivery_count(2));
+        conn.send_delivery(n, job(2, true), b"over", &with_delivery_count(3));
+        conn.send_delivery(n, job(3, true), b"uncounted", &AmqpProperties::default());
+
+        let (publish, properties, body) = conn.recv_publish_with_properties(n);
+        assert_eq!(publish.exchange, "");
+        assert_eq!(publish.routing_key, "parking-lot");
+        assert!(publish.mandatory);
+        assert_eq!(body, b"over");
+        let string = |s: &str| Some(AmqpValue::LongString(s.to_string()));
+        assert_eq!(
+            header(&properties, "x-original-exchange").cloned(),
+            string("jobs")
+        );
+        assert_eq!(
+            header(&properties, "x-original-routing-key").cloned(),
+            string("jobs.resize")
+        );
+        assert_eq!(header(&properties, "trace-id").cloned(), string("t1"));
+
+        // The original is only acked once the republish is confirmed.
+        confirm(&mut conn, n, 1);
+        let acked = (0..3)
+            .map(|_| conn.recv_single_ack(n))
+            .collect::<HashSet<_>>();
+        assert_eq!(acked, vec![1, 2, 3].into_iter().collect());
+        acked_tx.send(()).unwrap();
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions::default()
+        .redelivery_policy(RedeliveryPolicy::dead_letter_after(3, "parking-lot"));
+    let consumer = channel.basic_consume("q", options).unwrap();
+
+    let first = recv_delivery(consumer.receiver());
+    let second = recv_delivery(consumer.receiver());
+    assert_eq!(first.body, b"under");
+    assert_eq!(second.body, b"uncounted");
+    consumer.ack(first).unwrap();
+    consumer.ack(second).unwrap();
+
+    // Don't cancel until the parked delivery's ack (which waits for the server) has been sent.
+    acked_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn redelivered_flag_counts_as_one_delivery_without_delivery_count() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.accept_consume(n);
+        conn.send_delivery(n, job(1, false), b"first", &AmqpProperties::default());
+        conn.send_delivery(n, job(2, true), b"again", &AmqpProperties::default());
+
+        let (publish, _, body) = conn.recv_publish_with_properties(n);
+        assert_eq!(publish.exchange, "dlx");
+        assert_eq!(publish.routing_key, "parked");
+        assert_eq!(body, b"again");
+        confirm(&mut conn, n, 1);
+        assert_eq!(conn.recv_single_ack(n), 2);
+
+        assert_eq!(conn.recv_single_ack(n), 1);
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions::default().redelivery_policy(
+        RedeliveryPolicy::dead_letter_to_exchange(1, "dlx", "parked"),
+    );
+    let consumer = channel.basic_consume("q", options).unwrap();
+
+    let delivery = recv_delivery(consumer.receiver());
+    assert_eq!(delivery.body, b"first");
+    // The parked delivery never reaches the consumer.
+    match consumer.recv_timeout(Duration::from_millis(100)) {
+        Ok(None) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    consumer.ack(delivery).unwrap();
+
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn returned_republish_requeues_original() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.accept_consume(n);
+        conn.send_delivery(n, job(1, true), b"over", &with_delivery_count(3));
+
+        let (publish, properties, body) = conn.recv_publish_with_properties(n);
+        conn.send_method(
+            n,
+            AmqpBasic::Return(Return {
+                reply_code: 312,
+                reply_text: "NO_ROUTE".to_string(),
+                exchange: publish.exchange,
+                routing_key: publish.routing_key,
+            }),
+        );
+        conn.send_content(n, &body, &properties);
+        confirm(&mut conn, n, 1);
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                assert_eq!(nack.delivery_tag, 1);
+                assert!(!nack.multiple);
+                assert!(nack.requeue);
+            }
+            other => panic!("expected nack, got {:?}", other),
+        }
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let returns = channel.listen_for_returns().unwrap();
+    let options = ConsumerOptions::default()
+        .redelivery_policy(RedeliveryPolicy::dead_letter_after(3, "parking-lot"));
+    let consumer = channel.basic_consume("q", options).unwrap();
+
+    // Neither the consumer nor the return listener see the parked delivery.
+    match consumer.recv_timeout(Duration::from_millis(100)) {
+        Ok(None) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    assert!(returns.try_recv().is_err());
+
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn confirms_skip_republishes_on_channel_with_confirms() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.accept_consume(n);
+
+        // Two of these are the channel's publishes and one is a republish, in whatever order the
+        // I/O thread sent them; confirming all three settles both of the channel's either way.
+        conn.recv_publish(n);
+        conn.send_delivery(n, job(1, true), b"over", &with_delivery_count(3));
+        let bodies = (0..2)
+            .map(|_| conn.recv_publish(n).1)
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            bodies,
+            vec![b"over".to_vec(), b"second".to_vec()]
+                .into_iter()
+                .collect()
+        );
+        conn.send_method(
+            n,
+            AmqpBasic::Ack(Ack {
+                delivery_tag: 3,
+                multiple: true,
+            }),
+        );
+        assert_eq!(conn.recv_single_ack(n), 1);
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let confirms = channel.listen_for_publisher_confirms().unwrap();
+    let options = ConsumerOptions::default()
+        .redelivery_policy(RedeliveryPolicy::dead_letter_after(3, "parking-lot"));
+    let consumer = channel.basic_consume("q", options).unwrap();
+    assert_eq!(channel.next_publish_seq_no(), 1);
+
+    channel
+        .basic_publish("", Publish::new(b"first", "q"))
+        .unwrap();
+    channel
+        .basic_publish("", Publish::new(b"second", "q"))
+        .unwrap();
+    match confirms.recv_timeout(Duration::from_secs(5)).unwrap() {
+        Confirm::Ack(payload) => {
+            assert_eq!(payload.delivery_tag, 2);
+            assert!(payload.multiple);
+        }
+        other => panic!("unexpected confirm {:?}", other),
+    }
+
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,53 @@
+use super::mock_server::MockServer;
+use crate::{AmqpReplyCode, Connection, Error, QueueDeclareOptions};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::AMQPClass;
+
+#[test]
+fn channel_close_carries_reply_code_and_method() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(_))) if ch == n => (),
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 406,
+                // Not an "inequivalent arg" reply, which would be an InequivalentQueueArgument.
+                reply_text: "PRECONDITION_FAILED - invalid arg 'x-max-length'".to_string(),
+                class_id: 50,
+                method_id: 10,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel.close-ok, got {:?}", other),
+        }
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let err = match channel.queue_declare("q", QueueDeclareOptions::default()) {
+        Ok(_) => panic!("queue.declare unexpectedly succeeded"),
+        Err(err) => err,
+    };
+    assert_eq!(err.reply_code(), Some(AmqpReplyCode::PreconditionFailed));
+    match err {
+        Error::ServerClosedChannel {
+            code: 406,
+            reply_code: AmqpReplyCode::PreconditionFailed,
+            method: Some("queue.declare"),
+            ..
+        } => (),
+        err => panic!("unexpected error {:?}", err),
+    }
+    drop(channel);
+    connection.close().unwrap();
+    server.join();
+}
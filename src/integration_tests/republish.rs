@@ -0,0 +1,86 @@
+use super::mock_server::MockServer;
+use crate::{
+    AmqpProperties, AmqpValue, Connection, ConsumerMessage, ConsumerOptions, Exchange, FieldTable,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::Deliver;
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+#[test]
+fn forward_delivery_with_modified_properties() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+
+        let mut headers = FieldTable::new();
+        headers.insert("keep".to_string(), AmqpValue::LongInt(1));
+        headers.insert("drop".to_string(), AmqpValue::LongInt(2));
+        let properties = AmqpProperties::default()
+            .with_correlation_id("corr".to_string())
+            .with_content_type("application/json".to_string())
+            .with_headers(headers);
+        conn.send_method(
+            n,
+            AmqpBasic::Deliver(Deliver {
+                consumer_tag: "ctag".to_string(),
+                delivery_tag: 1,
+                redelivered: false,
+                exchange: String::new(),
+                routing_key: "in".to_string(),
+            }),
+        );
+        conn.send_content(n, b"payload", &properties);
+
+        let (publish, properties, body) = conn.recv_publish_with_properties(n);
+        assert_eq!(publish.routing_key, "out");
+        assert_eq!(body, b"payload");
+        assert_eq!(properties.correlation_id(), &Some("corr".to_string()));
+        assert_eq!(
+            properties.content_type(),
+            &Some("application/json".to_string())
+        );
+        assert_eq!(properties.expiration(), &Some("5000".to_string()));
+        let headers = properties.headers().as_ref().unwrap();
+        assert_eq!(headers.get("keep"), Some(&AmqpValue::LongInt(1)));
+        assert_eq!(headers.get("forwarded"), Some(&AmqpValue::Boolean(true)));
+        assert!(!headers.contains_key("drop"));
+
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                assert_eq!(ack.delivery_tag, 1);
+            }
+            other => panic!("expected ack, got {:?}", other),
+        }
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("in", ConsumerOptions::default())
+        .unwrap();
+    let delivery = match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::Delivery(delivery)) => delivery,
+        other => panic!("unexpected consumer message {:?}", other),
+    };
+
+    Exchange::direct(&channel)
+        .publish(
+            delivery
+                .to_publish("out")
+                .with_header("forwarded", true)
+                .without_header("drop")
+                .with_expiration(Duration::from_secs(5)),
+        )
+        .unwrap();
+    consumer.ack(delivery).unwrap();
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,82 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{AmqpProperties, Channel, Connection, Publish};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::Return as AmqpReturn;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROUTING_KEYS: &[&str] = &["a", "b", "c"];
+
+fn return_publish(conn: &mut ServerConn, channel_id: u16) {
+    let (publish, body) = conn.recv_publish(channel_id);
+    conn.send_method(
+        channel_id,
+        AmqpBasic::Return(AmqpReturn {
+            reply_code: 312,
+            reply_text: "NO_ROUTE".to_string(),
+            exchange: publish.exchange,
+            routing_key: publish.routing_key,
+        }),
+    );
+    conn.send_content(channel_id, &body, &AmqpProperties::default());
+}
+
+// Wait for the I/O thread to have handled `returns` returns on `channel`.
+fn wait_for_returns(channel: &Channel, returns: u64) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while channel.stats().returns < returns {
+        assert!(Instant::now() < deadline, "never saw {} returns", returns);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn bounded_listener_gets_early_return_and_drops_oldest() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        return_publish(&mut conn, n);
+        for _ in ROUTING_KEYS {
+            return_publish(&mut conn, n);
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let publish = |routing_key| {
+        let publish = Publish {
+            mandatory: true,
+            ..Publish::new(b"hello", routing_key)
+        };
+        channel.basic_publish("", publish).unwrap();
+    };
+
+    // The return arrives before anyone is listening for it...
+    publish("early");
+    wait_for_returns(&channel, 1);
+    // ...and goes to the first listener.
+    let returns = channel.listen_for_returns_bounded(2).unwrap();
+    let early = returns.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(early.routing_key, "early");
+    assert_eq!(early.content, b"hello");
+
+    // Nobody reads the next three, so the oldest makes way for the last two.
+    for routing_key in ROUTING_KEYS {
+        publish(routing_key);
+    }
+    wait_for_returns(&channel, 4);
+    let routing_keys = returns
+        .try_iter()
+        .map(|return_| return_.routing_key)
+        .collect::<Vec<_>>();
+    assert_eq!(routing_keys, vec!["b", "c"]);
+    let stats = channel.stats();
+    assert_eq!(stats.returns, 4);
+    assert_eq!(stats.returns_dropped, 1);
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,156 @@
+use super::mock_server::MockServer;
+use super::with_conn;
+use crate::{
+    AmqpProperties, Connection, MissingReplyTo, Publish, QueueDeclareOptions, RpcError, RpcServer,
+    RpcServerOptions,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::AMQPClass;
+use std::collections::HashSet;
+use std::sync::Barrier;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn rpc_server_replies_rejects_and_nacks() {
+    let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        let request = |correlation_id: &str| {
+            AmqpProperties::default()
+                .with_reply_to("replies".to_string())
+                .with_correlation_id(correlation_id.to_string())
+        };
+
+        // Both requests must be in the handler at once to get past its barrier.
+        conn.deliver_with_properties(n, 1, b"a", &request("c1"));
+        conn.deliver_with_properties(n, 2, b"b", &request("c2"));
+        let mut replies = HashSet::new();
+        for _ in 0..2 {
+            let (publish, properties, body) = conn.recv_publish_with_properties(n);
+            assert_eq!(publish.exchange, "");
+            assert_eq!(publish.routing_key, "replies");
+            let delivery_tag = conn.recv_ack(n).delivery_tag;
+            replies.insert((
+                properties.correlation_id().clone().unwrap(),
+                body,
+                delivery_tag,
+            ));
+        }
+        let expected = vec![
+            ("c1".to_string(), b"A".to_vec(), 1),
+            ("c2".to_string(), b"B".to_vec(), 2),
+        ];
+        assert_eq!(replies, expected.into_iter().collect());
+
+        conn.deliver_with_properties(n, 3, b"c", &AmqpProperties::default());
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Reject(reject))) if ch == n => {
+                assert_eq!(reject.delivery_tag, 3);
+                assert!(!reject.requeue);
+            }
+            other => panic!("expected reject, got {:?}", other),
+        }
+
+        conn.deliver_with_properties(n, 4, b"fail", &request("c4"));
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                assert_eq!(nack.delivery_tag, 4);
+                assert!(!nack.requeue);
+            }
+            other => panic!("expected nack, got {:?}", other),
+        }
+        done_tx.send(()).unwrap();
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let barrier = Barrier::new(2);
+    let options = RpcServerOptions {
+        workers: 2,
+        missing_reply_to: MissingReplyTo::Reject,
+        ..RpcServerOptions::default()
+    };
+    let rpc = RpcServer::start(&mut connection, "rpc", options, move |delivery| {
+        if delivery.body == b"fail" {
+            return Err(RpcError::new("failed"));
+        }
+        barrier.wait();
+        Ok(delivery.body.to_ascii_uppercase())
+    })
+    .unwrap();
+
+    done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(rpc.is_running());
+    rpc.shutdown().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn test_rpc_round_trip() {
+    with_conn(|conn| {
+        let chan = conn.open_channel(None).unwrap();
+        let exclusive = QueueDeclareOptions {
+            exclusive: true,
+            ..QueueDeclareOptions::default()
+        };
+        let requests = chan.queue_declare("", exclusive.clone()).unwrap();
+        let replies = chan.queue_declare("", exclusive).unwrap();
+
+        let rpc = RpcServer::start(
+            conn,
+            requests.name(),
+            RpcServerOptions {
+                workers: 4,
+                ..RpcServerOptions::default()
+            },
+            move |delivery| {
+                let n: u64 = std::str::from_utf8(&delivery.body)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| RpcError::new("not a number"))?;
+                Ok((n * 2).to_string().into_bytes())
+            },
+        )
+        .unwrap();
+
+        for i in 0..10u64 {
+            let properties = AmqpProperties::default()
+                .with_reply_to(replies.name().to_string())
+                .with_correlation_id(i.to_string());
+            chan.basic_publish(
+                "",
+                Publish::with_properties(i.to_string().as_bytes(), requests.name(), properties),
+            )
+            .unwrap();
+        }
+
+        let mut answers = HashSet::new();
+        for _ in 0..200 {
+            if answers.len() == 10 {
+                break;
+            }
+            match replies.get(true).unwrap() {
+                Some(get) => {
+                    let id = get.delivery.properties.correlation_id().clone().unwrap();
+                    let body = String::from_utf8(get.delivery.body).unwrap();
+                    answers.insert((id, body));
+                }
+                None => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        let expected = (0..10u64)
+            .map(|i| (i.to_string(), (i * 2).to_string()))
+            .collect::<HashSet<_>>();
+        assert_eq!(answers, expected);
+
+        rpc.shutdown().unwrap();
+        chan.close().unwrap();
+    })
+}
@@ -0,0 +1,298 @@
+use super::mock_server::{MockServer, ServerConn, DEFAULT_TUNE};
+use crate::{
+    Auth, ClientIdentity, Connection, ConnectionOptions, ConnectionTuning, Error, Exchange,
+    Publish, TlsConnector,
+};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::connection::Start;
+use amq_protocol::protocol::AMQPClass;
+use mio::net::TcpStream;
+use rustls_crate::server::AllowAnyAuthenticatedClient;
+use rustls_crate::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use std::sync::Arc;
+
+// A test CA and certificates it issued for "localhost" and for a client (DER-encoded, ECDSA P-256
+// keys, valid until 2126), generated with openssl. The keys are PKCS#8.
+const CA_CERT: &[u8] = include_bytes!("testdata/ca.der");
+const SERVER_CERT: &[u8] = include_bytes!("testdata/server.der");
+const SERVER_KEY: &[u8] = include_bytes!("testdata/server.key.der");
+const CLIENT_CERT: &[u8] = include_bytes!("testdata/client.der");
+const CLIENT_KEY: &[u8] = include_bytes!("testdata/client.key.der");
+
+fn server_config() -> Arc<ServerConfig> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![Certificate(SERVER_CERT.to_vec())],
+            PrivateKey(SERVER_KEY.to_vec()),
+        )
+        .unwrap();
+    Arc::new(config)
+}
+
+// A server that only accepts clients with a certificate issued by the test CA.
+fn client_auth_server_config() -> Arc<ServerConfig> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(test_ca_roots()))
+        .with_single_cert(
+            vec![Certificate(SERVER_CERT.to_vec())],
+            PrivateKey(SERVER_KEY.to_vec()),
+        )
+        .unwrap();
+    Arc::new(config)
+}
+
+fn client_connector(roots: RootCertStore) -> TlsConnector {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth()
+        .into()
+}
+
+fn test_ca_roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add(&Certificate(CA_CERT.to_vec())).unwrap();
+    roots
+}
+
+fn open(server: &MockServer, connector: TlsConnector, domain: &str) -> crate::Result<Connection> {
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    Connection::open_tls_stream(
+        connector,
+        domain,
+        stream,
+        ConnectionOptions::<Auth>::default(),
+        ConnectionTuning::default(),
+    )
+}
+
+#[test]
+fn publish_over_rustls() {
+    // Larger than the socket buffers, so the client's writes back up while it is encrypting.
+    const BODY_LEN: usize = 4 << 20;
+
+    let server = MockServer::start_tls(server_config(), |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == n => (),
+            other => panic!("expected publish, got {:?}", other),
+        }
+        match conn.recv_frame() {
+            AMQPFrame::Header(ch, _, header) if ch == n => {
+                assert_eq!(header.body_size, BODY_LEN as u64)
+            }
+            other => panic!("expected content header, got {:?}", other),
+        }
+        let mut received = 0;
+        while received < BODY_LEN {
+            match conn.recv_frame() {
+                AMQPFrame::Body(ch, body) if ch == n => {
+                    assert!(body.iter().all(|&b| b == 0xa5));
+                    received += body.len();
+                }
+                other => panic!("expected content body, got {:?}", other),
+            }
+        }
+        assert_eq!(received, BODY_LEN);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = open(&server, client_connector(test_ca_roots()), "localhost").unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    Exchange::direct(&channel)
+        .publish(Publish::new(&vec![0xa5; BODY_LEN], "q"))
+        .unwrap();
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn untrusted_certificate_fails_handshake() {
+    let server = MockServer::start_tls(server_config(), |mut conn| {
+        assert!(conn.try_recv_frame().is_none());
+    });
+
+    let result = open(
+        &server,
+        TlsConnector::rustls_with_webpki_roots(),
+        "localhost",
+    );
+    server.join();
+    match result {
+        Err(Error::RustlsHandshake { .. }) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("connected with an untrusted certificate"),
+    }
+}
+
+#[test]
+fn certificate_is_checked_against_domain() {
+    let server = MockServer::start_tls(server_config(), |mut conn| {
+        assert!(conn.try_recv_frame().is_none());
+    });
+
+    let result = open(
+        &server,
+        client_connector(test_ca_roots()),
+        "broker.internal",
+    );
+    server.join();
+    match result {
+        Err(Error::RustlsHandshake { .. }) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("connected to a server with the wrong name"),
+    }
+}
+
+fn external_start_method() -> Start {
+    Start {
+        mechanisms: "PLAIN EXTERNAL".to_string(),
+        ..ServerConn::start_method()
+    }
+}
+
+#[test]
+fn external_auth_with_client_certificate() {
+    let server = MockServer::start_tls(client_auth_server_config(), |mut conn| {
+        let start_ok = conn.start_handshake(external_start_method());
+        assert_eq!(start_ok.mechanism, "EXTERNAL");
+        assert_eq!(conn.client_certificate().as_deref(), Some(CLIENT_CERT));
+        conn.finish_handshake(DEFAULT_TUNE);
+        conn.accept_connection_close();
+    });
+
+    let identity = ClientIdentity::from_der(vec![CLIENT_CERT.to_vec()], CLIENT_KEY.to_vec());
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let connection = Connection::open_tls_stream(
+        client_connector(test_ca_roots()),
+        "localhost",
+        stream,
+        ConnectionOptions::external_with_client_cert(identity),
+        ConnectionTuning::default(),
+    )
+    .unwrap();
+    assert_eq!(connection.peer_certificate().as_deref(), Some(SERVER_CERT));
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn rejected_client_certificate_is_invalid_credentials() {
+    let server = MockServer::start_tls(client_auth_server_config(), |mut conn| {
+        assert!(conn.try_recv_frame().is_none());
+    });
+
+    // Asking for EXTERNAL without presenting a certificate.
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let result = Connection::open_tls_stream(
+        client_connector(test_ca_roots()),
+        "localhost",
+        stream,
+        ConnectionOptions::<Auth>::default().auth(Auth::External),
+        ConnectionTuning::default(),
+    );
+    server.join();
+    match result {
+        Err(Error::InvalidCredentials) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("connected without a client certificate"),
+    }
+}
+
+#[test]
+fn insecure_connection_has_no_peer_certificate() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+    let connection = Connection::insecure_open(&server.url()).unwrap();
+    assert_eq!(connection.peer_certificate(), None);
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn tls_server_name_overrides_domain() {
+    let server = MockServer::start_tls(server_config(), |mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    // The certificate is for "localhost", but we connect (and pass the domain) by IP address.
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let connection = Connection::open_tls_stream(
+        client_connector(test_ca_roots()),
+        "127.0.0.1",
+        stream,
+        ConnectionOptions::<Auth>::default().tls_server_name("localhost"),
+        ConnectionTuning::default(),
+    )
+    .unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn danger_accept_invalid_hostnames() {
+    let server = MockServer::start_tls(server_config(), |mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let connection = Connection::open_tls_stream(
+        TlsConnector::rustls_with_roots(test_ca_roots()),
+        "broker.internal",
+        stream,
+        ConnectionOptions::<Auth>::default().danger_accept_invalid_hostnames(true),
+        ConnectionTuning::default(),
+    )
+    .unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn danger_accept_invalid_hostnames_still_verifies_chain() {
+    let server = MockServer::start_tls(server_config(), |mut conn| {
+        assert!(conn.try_recv_frame().is_none());
+    });
+
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    let result = Connection::open_tls_stream(
+        TlsConnector::rustls_with_webpki_roots(),
+        "127.0.0.1",
+        stream,
+        ConnectionOptions::<Auth>::default().danger_accept_invalid_hostnames(true),
+        ConnectionTuning::default(),
+    );
+    server.join();
+    match result {
+        Err(Error::RustlsHandshake { .. }) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("connected with an untrusted certificate"),
+    }
+}
+
+#[test]
+fn danger_accept_invalid_hostnames_needs_known_roots() {
+    let result = Connection::open_tls_stream(
+        client_connector(test_ca_roots()),
+        "localhost",
+        TcpStream::connect("127.0.0.1:1".parse().unwrap()).unwrap(),
+        ConnectionOptions::<Auth>::default().danger_accept_invalid_hostnames(true),
+        ConnectionTuning::default(),
+    );
+    match result {
+        Err(Error::CannotSkipHostnameVerification) => (),
+        Err(err) => panic!("unexpected error {}", err),
+        Ok(_) => panic!("connected"),
+    }
+}
@@ -0,0 +1,44 @@
+use super::mock_server::MockServer;
+use crate::{Connection, Exchange, Publish};
+use std::sync::Arc;
+
+// The mock server's frame_max less 8 bytes of frame overhead.
+const MAX_BODY_FRAME: usize = (1 << 17) - 8;
+
+#[test]
+fn shared_body_is_published_to_many_routing_keys() {
+    let body: Arc<[u8]> = (0..2 * MAX_BODY_FRAME + 5)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>()
+        .into();
+    let expected = body.to_vec();
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for key in &["a", "b", "c", "d"] {
+            let (publish, received) = conn.recv_publish(n);
+            assert_eq!(publish.routing_key, *key);
+            assert!(received == expected, "body for {} differs", key);
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let exchange = Exchange::direct(&channel);
+    for key in &["a", "b", "c"] {
+        exchange
+            .publish(Publish::shared(Arc::clone(&body), *key))
+            .unwrap();
+    }
+    assert!(channel
+        .try_publish("", Publish::shared(Arc::clone(&body), "d"))
+        .unwrap());
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+
+    // The I/O thread let go of the body once it was written.
+    assert_eq!(Arc::strong_count(&body), 1);
+}
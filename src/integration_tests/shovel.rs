@@ -0,0 +1,121 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{
+    AmqpProperties, Connection, Shovel, ShovelDestination, ShovelOptions, ShovelSource, ShovelStats,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Ack, ConsumeOk, Deliver, Nack, QosOk};
+use amq_protocol::protocol::AMQPClass;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn relays_and_settles_source_after_destination_confirms() {
+    let source = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Qos(qos))) if ch == n => {
+                assert_eq!(qos.prefetch_count, 10);
+                conn.send_method(n, AmqpBasic::QosOk(QosOk {}));
+            }
+            other => panic!("expected qos, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Consume(consume))) if ch == n => {
+                assert_eq!(consume.queue, "orders");
+                assert!(!consume.no_ack);
+            }
+            other => panic!("expected consume, got {:?}", other),
+        }
+        conn.send_method(
+            n,
+            AmqpBasic::ConsumeOk(ConsumeOk {
+                consumer_tag: "ctag".to_string(),
+            }),
+        );
+        let properties = AmqpProperties::default().with_content_type("text/plain".to_string());
+        for (delivery_tag, body) in &[(1, &b"first"[..]), (2, &b"second"[..])] {
+            let deliver = Deliver {
+                exchange: "orders".to_string(),
+                routing_key: "orders.eu".to_string(),
+                ..ServerConn::deliver_method(*delivery_tag)
+            };
+            conn.send_delivery(n, deliver, body, &properties);
+        }
+
+        assert_eq!(conn.recv_single_ack(n), 1);
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == n => {
+                assert_eq!((nack.delivery_tag, nack.requeue), (2, true));
+            }
+            other => panic!("expected nack, got {:?}", other),
+        }
+
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let destination = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        for expected in &[&b"first"[..], &b"second"[..]] {
+            let (publish, properties, body) = conn.recv_publish_with_properties(n);
+            assert_eq!(publish.exchange, "archive");
+            assert_eq!(publish.routing_key, "archived.orders.eu");
+            assert_eq!(properties.content_type().as_deref(), Some("text/plain"));
+            assert_eq!(body, *expected);
+        }
+        conn.send_method(
+            n,
+            AmqpBasic::Ack(Ack {
+                delivery_tag: 1,
+                multiple: false,
+            }),
+        );
+        conn.send_method(
+            n,
+            AmqpBasic::Nack(Nack {
+                delivery_tag: 2,
+                multiple: false,
+                requeue: false,
+            }),
+        );
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let source_connection = Connection::insecure_open(&source.url()).unwrap();
+    let destination_connection = Connection::insecure_open(&destination.url()).unwrap();
+    let options = ShovelOptions {
+        max_in_flight: 10,
+        ..ShovelOptions::default()
+    };
+    let shovel = Shovel::start(
+        ShovelSource::new(source_connection, "orders"),
+        ShovelDestination::new(destination_connection, "archive")
+            .map_routing_key(|delivery| format!("archived.{}", delivery.routing_key)),
+        options,
+    )
+    .unwrap();
+
+    let expected = ShovelStats {
+        relayed: 1,
+        pending: 0,
+        failed: 1,
+    };
+    let give_up = Instant::now() + Duration::from_secs(5);
+    while shovel.stats() != expected {
+        assert!(Instant::now() < give_up, "stats were {:?}", shovel.stats());
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(shovel.is_running());
+
+    let stats = shovel
+        .stop(Instant::now() + Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(stats, expected);
+    source.join();
+    destination.join();
+}
@@ -0,0 +1,148 @@
+use super::mock_server::MockServer;
+use crate::{Connection, ConnectionTuning, ConsumerMessage, ConsumerOptions, Error};
+use crate::{QueueDeclareOptions, Result};
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::CloseOk;
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::AMQPClass;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Generous upper bound on how long any of these drops should take; without bounded shutdown,
+// the "hanging" tests below would instead block until the mock server gives up (10 seconds).
+const QUICK: Duration = Duration::from_secs(2);
+
+fn timed<T, F: FnOnce() -> T>(f: F) -> T {
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    assert!(elapsed < QUICK, "took {:?}", elapsed);
+    result
+}
+
+#[test]
+fn drop_consumer_then_channel_then_connection() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    timed(|| drop(consumer));
+    timed(|| drop(channel));
+    timed(|| drop(connection));
+    server.join();
+}
+
+#[test]
+fn drop_connection_while_server_hangs_up_after_close_ok() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.accept_cancel(n);
+        conn.expect_connection_close();
+        // Reply and immediately close the socket, like a real server does.
+        conn.send_method(0, AmqpConnection::CloseOk(CloseOk {}));
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    timed(|| drop(consumer));
+    timed(|| connection.close()).unwrap();
+
+    // The channel outlived its connection; closing it fails, but promptly.
+    match timed(|| channel.close()) {
+        Err(Error::EventLoopDropped) | Err(Error::ClientClosedConnection) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    server.join();
+}
+
+#[test]
+fn drop_connection_before_consumer_and_channel() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    timed(|| drop(connection));
+
+    // The consumer gets exactly one terminal message, then its channel disconnects.
+    match consumer.receiver().recv_timeout(QUICK) {
+        Ok(ConsumerMessage::ClientClosedConnection) => (),
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+    assert!(consumer.receiver().recv_timeout(QUICK).is_err());
+
+    timed(|| drop(consumer));
+    timed(|| drop(channel));
+    server.join();
+}
+
+#[test]
+fn in_flight_rpc_fails_when_connection_is_dropped() {
+    let (declared_tx, declared_rx) = crossbeam_channel::bounded(1);
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(_))) if ch == n => (),
+            other => panic!("expected queue declare, got {:?}", other),
+        }
+        // Never answer the declare; the client gives up on it when the connection closes.
+        declared_tx.send(()).unwrap();
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let declaring = thread::spawn(move || -> Result<()> {
+        let result = channel
+            .queue_declare("q", QueueDeclareOptions::default())
+            .map(|_| ());
+        timed(|| drop(channel));
+        result
+    });
+
+    declared_rx.recv_timeout(QUICK).unwrap();
+    timed(|| drop(connection));
+    match declaring.join().unwrap() {
+        Err(Error::ClientClosedConnection) => (),
+        other => panic!("unexpected declare result {:?}", other),
+    }
+    server.join();
+}
+
+#[test]
+fn drop_does_not_wait_forever_for_unresponsive_server() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.expect_connection_close();
+        // Sit on the close without replying; the client should give up well before this.
+        thread::sleep(Duration::from_secs(3));
+    });
+
+    let tuning = ConnectionTuning::default().drop_timeout(Duration::from_millis(500));
+    let connection = Connection::insecure_open_tuned(&server.url(), tuning).unwrap();
+    timed(|| drop(connection));
+    server.join();
+}
@@ -0,0 +1,184 @@
+use super::mock_server::MockServer;
+use crate::{Auth, Connection, ConnectionOptions, ConnectionTuning, Endpoint, Error, Proxy};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread::{self, JoinHandle};
+
+// What a client asked the proxy for.
+#[derive(Debug, PartialEq)]
+struct Request {
+    auth: Option<(String, String)>,
+    target: Vec<u8>,
+}
+
+// A single-connection SOCKS5 proxy that relays to `upstream` whatever target is requested, or
+// refuses the CONNECT with `reply` if it is nonzero.
+struct Socks5Server {
+    addr: SocketAddr,
+    join_handle: JoinHandle<Request>,
+}
+
+impl Socks5Server {
+    fn start(upstream: SocketAddr, reply: u8) -> Socks5Server {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let join_handle = thread::spawn(move || {
+            let (mut client, _) = listener.accept().unwrap();
+            let request = handshake(&mut client, reply);
+            if reply == 0 {
+                relay(client, TcpStream::connect(upstream).unwrap());
+            }
+            request
+        });
+        Socks5Server { addr, join_handle }
+    }
+
+    fn join(self) -> Request {
+        self.join_handle.join().unwrap()
+    }
+}
+
+fn read_n(stream: &mut TcpStream, n: usize) -> Vec<u8> {
+    let mut buf = vec![0; n];
+    stream.read_exact(&mut buf).unwrap();
+    buf
+}
+
+fn handshake(client: &mut TcpStream, reply: u8) -> Request {
+    let greeting = read_n(client, 2);
+    assert_eq!(greeting[0], 5);
+    let methods = read_n(client, usize::from(greeting[1]));
+    let auth = if methods.contains(&2) {
+        client.write_all(&[5, 2]).unwrap();
+        assert_eq!(read_n(client, 1), [1]);
+        let len = read_n(client, 1)[0];
+        let username = String::from_utf8(read_n(client, usize::from(len))).unwrap();
+        let len = read_n(client, 1)[0];
+        let password = String::from_utf8(read_n(client, usize::from(len))).unwrap();
+        client.write_all(&[1, 0]).unwrap();
+        Some((username, password))
+    } else {
+        client.write_all(&[5, 0]).unwrap();
+        None
+    };
+
+    let header = read_n(client, 4);
+    assert_eq!(header[..3], [5, 1, 0]);
+    let mut target = vec![header[3]];
+    match header[3] {
+        1 => target.extend(read_n(client, 4)),
+        3 => {
+            let len = read_n(client, 1)[0];
+            target.push(len);
+            target.extend(read_n(client, usize::from(len)));
+        }
+        4 => target.extend(read_n(client, 16)),
+        atyp => panic!("bad address type {}", atyp),
+    }
+    target.extend(read_n(client, 2));
+    client
+        .write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0, 0])
+        .unwrap();
+    Request { auth, target }
+}
+
+fn relay(client: TcpStream, server: TcpStream) {
+    fn pump(mut from: TcpStream, mut to: TcpStream) {
+        let _ = io::copy(&mut from, &mut to);
+        let _ = to.shutdown(Shutdown::Write);
+    }
+    let (client2, server2) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+    let upstream = thread::spawn(move || pump(client2, server2));
+    pump(server, client);
+    upstream.join().unwrap();
+}
+
+fn mock_broker() -> MockServer {
+    MockServer::start(|mut conn| {
+        conn.handshake();
+        conn.accept_connection_close();
+    })
+}
+
+#[test]
+fn connect_by_host_name_with_auth() {
+    let broker = mock_broker();
+    let proxy = Socks5Server::start(broker.addr(), 0);
+
+    let options = ConnectionOptions::<Auth>::default()
+        .endpoints(vec![Endpoint::new("broker.internal", 5672)])
+        .proxy(Proxy::Socks5 {
+            addr: proxy.addr,
+            auth: Some(("user".to_string(), "secret".to_string())),
+        });
+    let connection =
+        Connection::insecure_open_endpoints(options, ConnectionTuning::default()).unwrap();
+    connection.close().unwrap();
+    broker.join();
+
+    let mut target = vec![3, 15];
+    target.extend_from_slice(b"broker.internal");
+    target.extend_from_slice(&[0x16, 0x28]);
+    assert_eq!(
+        proxy.join(),
+        Request {
+            auth: Some(("user".to_string(), "secret".to_string())),
+            target,
+        }
+    );
+}
+
+#[test]
+fn connect_by_ip_address() {
+    let broker = mock_broker();
+    let proxy = Socks5Server::start(broker.addr(), 0);
+
+    let options = ConnectionOptions::<Auth>::default()
+        .endpoints(vec!["10.1.2.3:5673".parse().unwrap()])
+        .proxy(Proxy::Socks5 {
+            addr: proxy.addr,
+            auth: None,
+        });
+    let connection =
+        Connection::insecure_open_endpoints(options, ConnectionTuning::default()).unwrap();
+    connection.close().unwrap();
+    broker.join();
+
+    assert_eq!(
+        proxy.join(),
+        Request {
+            auth: None,
+            target: vec![1, 10, 1, 2, 3, 0x16, 0x29],
+        }
+    );
+}
+
+#[test]
+fn refused_connect_is_a_proxy_error() {
+    let unused = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let proxy = Socks5Server::start(unused, 5);
+
+    let options = ConnectionOptions::<Auth>::default()
+        .endpoints(vec![Endpoint::new("broker.internal", 5672)])
+        .proxy(Proxy::Socks5 {
+            addr: proxy.addr,
+            auth: None,
+        });
+    let err = match Connection::insecure_open_endpoints(options, ConnectionTuning::default()) {
+        Ok(_) => panic!("proxy refused but connection opened"),
+        Err(err) => err,
+    };
+    proxy.join();
+    match err {
+        Error::AllEndpointsFailed { mut failures } => match failures.pop() {
+            Some((_, Error::ProxyError { message })) => {
+                assert!(message.contains("connection refused"), "{}", message)
+            }
+            failure => panic!("unexpected failure {:?}", failure),
+        },
+        err => panic!("unexpected error {:?}", err),
+    }
+}
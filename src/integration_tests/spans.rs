@@ -0,0 +1,111 @@
+use super::mock_server::MockServer;
+use crate::{Connection, Exchange, Publish};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::AMQPClass;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+thread_local! {
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Span name and parent, by span ID.
+type Spans = Arc<Mutex<HashMap<u64, (&'static str, Option<u64>)>>>;
+
+// Just enough of a subscriber to record every span's name and parent.
+struct SpanRecorder {
+    next_id: AtomicU64,
+    spans: Spans,
+}
+
+impl Subscriber for SpanRecorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let parent = match attrs.parent() {
+            Some(parent) => Some(parent.into_u64()),
+            None if attrs.is_contextual() => ENTERED.with(|e| e.borrow().last().copied()),
+            None => None,
+        };
+        let name = attrs.metadata().name();
+        self.spans.lock().unwrap().insert(id, (name, parent));
+        Id::from_u64(id)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, _event: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|e| e.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, _span: &Id) {
+        ENTERED.with(|e| e.borrow_mut().pop());
+    }
+}
+
+#[test]
+fn io_thread_runs_inside_callers_span() {
+    let spans = Spans::default();
+    let recorder = SpanRecorder {
+        next_id: AtomicU64::new(1),
+        spans: Arc::clone(&spans),
+    };
+
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == n => (),
+            other => panic!("expected publish, got {:?}", other),
+        }
+        match conn.recv_frame() {
+            AMQPFrame::Header(ch, _, _) if ch == n => (),
+            other => panic!("expected content header, got {:?}", other),
+        }
+        match conn.recv_frame() {
+            AMQPFrame::Body(ch, _) if ch == n => (),
+            other => panic!("expected content body, got {:?}", other),
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    // The recorder is only this thread's default; the I/O thread must pick it up from us.
+    let caller_id = tracing::subscriber::with_default(recorder, || {
+        let caller = tracing::info_span!("caller");
+        let _caller = caller.enter();
+        let mut connection = Connection::insecure_open(&server.url()).unwrap();
+        let channel = connection.open_channel(None).unwrap();
+        Exchange::direct(&channel)
+            .publish(Publish::new(b"hello", "q"))
+            .unwrap();
+        channel.close().unwrap();
+        connection.close().unwrap();
+        caller.id().unwrap().into_u64()
+    });
+    server.join();
+
+    let spans = spans.lock().unwrap();
+    let child_of = |name: &str, parent: u64| {
+        spans
+            .iter()
+            .find(|(_, span)| **span == (name, Some(parent)))
+            .map(|(id, _)| *id)
+            .unwrap_or_else(|| panic!("no {} span under {}", name, parent))
+    };
+    let connection = child_of("amiquip_connection", caller_id);
+    child_of("handshake", connection);
+    child_of("publish", caller_id);
+}
@@ -0,0 +1,273 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{
+    AmqpProperties, ChannelInfo, ChannelStats, Connection, ConnectionStats, ConsumerMessage,
+    ConsumerOptions, Exchange, Publish,
+};
+use amq_protocol::frame::AMQPFrame;
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::{Ack, Return};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::AMQPClass;
+use std::time::Duration;
+
+const MESSAGES: u64 = 3;
+
+fn expect_publish(conn: &mut ServerConn, channel_id: u16) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Basic(AmqpBasic::Publish(_))) if ch == channel_id => (),
+        other => panic!("expected publish, got {:?}", other),
+    }
+    match conn.recv_frame() {
+        AMQPFrame::Header(ch, _, _) if ch == channel_id => (),
+        other => panic!("expected content header, got {:?}", other),
+    }
+    match conn.recv_frame() {
+        AMQPFrame::Body(ch, _) if ch == channel_id => (),
+        other => panic!("expected content body, got {:?}", other),
+    }
+}
+
+fn assert_monotonic(earlier: &ConnectionStats, later: &ConnectionStats) {
+    assert!(earlier.bytes_read <= later.bytes_read);
+    assert!(earlier.bytes_written <= later.bytes_written);
+    assert!(earlier.frames_read <= later.frames_read);
+    assert!(earlier.frames_written <= later.frames_written);
+    assert!(earlier.heartbeats_sent <= later.heartbeats_sent);
+    assert!(earlier.heartbeats_received <= later.heartbeats_received);
+    assert!(earlier.publishes <= later.publishes);
+    assert!(earlier.deliveries <= later.deliveries);
+}
+
+#[test]
+fn stats_count_traffic() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for _ in 0..MESSAGES {
+            expect_publish(&mut conn, n);
+        }
+        conn.accept_consume(n);
+        conn.send_heartbeat();
+        for tag in 1..=MESSAGES {
+            conn.deliver(n, tag, b"hello");
+        }
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let opened = connection.stats();
+    // The handshake alone reads and writes a few frames.
+    assert!(opened.bytes_read > 0);
+    assert!(opened.bytes_written > 0);
+    assert!(opened.frames_read > 0);
+    assert!(opened.frames_written > 0);
+
+    let channel = connection.open_channel(None).unwrap();
+    let exchange = Exchange::direct(&channel);
+    for _ in 0..MESSAGES {
+        exchange.publish(Publish::new(b"hello", "q")).unwrap();
+    }
+    let published = connection.stats();
+    assert_monotonic(&opened, &published);
+
+    let consumer = channel
+        .basic_consume(
+            "q",
+            ConsumerOptions {
+                no_ack: true,
+                ..ConsumerOptions::default()
+            },
+        )
+        .unwrap();
+    for _ in 0..MESSAGES {
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(_)) => (),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+    let consumed = connection.stats();
+    assert_monotonic(&published, &consumed);
+    // The publishes were written before the consume, which the server answered before sending
+    // its heartbeat and deliveries.
+    assert_eq!(consumed.publishes, MESSAGES);
+    assert_eq!(consumed.deliveries, MESSAGES);
+    assert_eq!(consumed.heartbeats_received, 1);
+    assert_eq!(consumed.heartbeats_sent, 0);
+    // Each publish is a method, header and body frame.
+    assert!(consumed.frames_written >= opened.frames_written + 3 * MESSAGES);
+    assert!(consumed.frames_read > opened.frames_read + 3 * MESSAGES);
+
+    consumer.cancel().unwrap();
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn channel_stats_survive_server_close() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.recv_publish(n);
+        let (publish, body) = conn.recv_publish(n);
+        conn.send_method(
+            n,
+            AmqpBasic::Return(Return {
+                reply_code: 312,
+                reply_text: "NO_ROUTE".to_string(),
+                exchange: publish.exchange,
+                routing_key: publish.routing_key,
+            }),
+        );
+        conn.send_content(n, &body, &AmqpProperties::default());
+        conn.send_method(
+            n,
+            AmqpBasic::Ack(Ack {
+                delivery_tag: 2,
+                multiple: true,
+            }),
+        );
+
+        let tx = conn.accept_channel();
+        conn.accept_tx(tx);
+
+        conn.accept_consume(n);
+        for tag in 1..=2 {
+            conn.deliver(n, tag, b"hello");
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == n => {
+                assert_eq!(ack.delivery_tag, 1)
+            }
+            other => panic!("expected ack, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Basic(AmqpBasic::Reject(reject))) if ch == n => {
+                assert_eq!(reject.delivery_tag, 2)
+            }
+            other => panic!("expected reject, got {:?}", other),
+        }
+
+        conn.send_method(
+            n,
+            AmqpChannel::Close(ChannelClose {
+                reply_code: 406,
+                reply_text: "PRECONDITION_FAILED - unknown delivery tag 3".to_string(),
+                class_id: 60,
+                method_id: 80,
+            }),
+        );
+        match conn.recv_method() {
+            (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == n => (),
+            other => panic!("expected channel close-ok, got {:?}", other),
+        }
+        conn.accept_channel_close(tx);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    assert!(connection.channels().is_empty());
+
+    let channel = connection.open_channel(None).unwrap();
+    assert_eq!(channel.stats(), ChannelStats::default());
+    let returns = channel.listen_for_returns().unwrap();
+    channel.enable_publisher_confirms().unwrap();
+    for _ in 0..2 {
+        channel
+            .basic_publish("", Publish::new(b"hello", "q"))
+            .unwrap();
+    }
+    returns.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let tx = connection.open_channel(None).unwrap();
+    tx.tx_select().unwrap();
+    assert_eq!(
+        tx.stats(),
+        ChannelStats {
+            tx_mode: true,
+            ..ChannelStats::default()
+        }
+    );
+
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let published = ChannelStats {
+        publishes: 2,
+        returns: 1,
+        confirm_mode: true,
+        consumers: 1,
+        ..ChannelStats::default()
+    };
+    assert_eq!(
+        connection.channels(),
+        vec![
+            ChannelInfo {
+                channel_id: channel.channel_id(),
+                open: true,
+                consumer_tags: vec!["ctag".to_string()],
+                stats: published,
+            },
+            ChannelInfo {
+                channel_id: tx.channel_id(),
+                open: true,
+                consumer_tags: Vec::new(),
+                stats: tx.stats(),
+            },
+        ]
+    );
+
+    let mut deliveries = Vec::new();
+    for _ in 0..2 {
+        match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+            Ok(ConsumerMessage::Delivery(delivery)) => deliveries.push(delivery),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+    let second = deliveries.pop().unwrap();
+    deliveries.pop().unwrap().ack(&channel).unwrap();
+    second.reject(&channel, false).unwrap();
+    match consumer.receiver().recv_timeout(Duration::from_secs(5)) {
+        Ok(ConsumerMessage::ServerClosedChannel(_)) => (),
+        other => panic!("unexpected message {:?}", other),
+    }
+
+    // The channel is closed, but its handle still reports what happened on it.
+    let closed = ChannelStats {
+        deliveries: 2,
+        acks: 1,
+        nacks: 1,
+        consumers: 0,
+        ..published
+    };
+    assert_eq!(channel.stats(), closed);
+    let channels = connection.channels();
+    assert_eq!(channels.len(), 2);
+    assert_eq!(
+        channels[0],
+        ChannelInfo {
+            channel_id: channel.channel_id(),
+            open: false,
+            consumer_tags: Vec::new(),
+            stats: closed,
+        }
+    );
+    assert!(channels[1].open);
+
+    // Once its handle is gone, a closed channel is no longer listed.
+    drop(consumer);
+    drop(channel);
+    let channels = connection.channels();
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].channel_id, tx.channel_id());
+
+    tx.close().unwrap();
+    assert!(connection.channels().is_empty());
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,71 @@
+use super::with_chan;
+use crate::{
+    BindingDefinition, BindingDestination, ExchangeDeclareOptions, ExchangeDefinition,
+    ExchangeType, FieldTable, Publish, QueueDeclareOptions, QueueDefinition, QueueDeleteOptions,
+    Topology,
+};
+use std::time::Duration;
+
+#[test]
+fn test_apply_topology() {
+    let exchange = "amiquip-test-topology";
+    let queue = "amiquip-test-topology-queue";
+    let topology = Topology {
+        exchanges: vec![
+            ExchangeDefinition {
+                name: exchange.to_string(),
+                type_: ExchangeType::Topic,
+                options: ExchangeDeclareOptions {
+                    auto_delete: true,
+                    ..ExchangeDeclareOptions::default()
+                },
+            },
+            // predeclared; must be declared passively
+            ExchangeDefinition {
+                name: "amq.topic".to_string(),
+                type_: ExchangeType::Topic,
+                options: ExchangeDeclareOptions::default(),
+            },
+        ],
+        queues: vec![QueueDefinition {
+            name: queue.to_string(),
+            options: QueueDeclareOptions::default(),
+        }],
+        bindings: vec![
+            BindingDefinition {
+                source: "amq.topic".to_string(),
+                destination: exchange.to_string(),
+                destination_type: BindingDestination::Exchange,
+                routing_key: "topology.#".to_string(),
+                arguments: FieldTable::new(),
+            },
+            BindingDefinition {
+                source: exchange.to_string(),
+                destination: queue.to_string(),
+                destination_type: BindingDestination::Queue,
+                routing_key: "topology.*".to_string(),
+                arguments: FieldTable::new(),
+            },
+        ],
+    };
+
+    with_chan(|chan| {
+        chan.apply_topology(&topology).unwrap();
+        // applying twice is a no-op
+        chan.apply_topology(&topology).unwrap();
+
+        chan.basic_publish("amq.topic", Publish::new(b"routed", "topology.test"))
+            .unwrap();
+        let queue = chan.queue_declare_passive(queue).unwrap();
+        let mut get = None;
+        for _ in 0..50 {
+            get = queue.get(true).unwrap();
+            if get.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(get.unwrap().delivery.body, b"routed");
+        queue.delete(QueueDeleteOptions::default()).unwrap();
+    })
+}
@@ -0,0 +1,267 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{
+    Connection, ExchangeType, QueueDeclareOptions, QueueDefinition, Topology, TopologyMismatch,
+    TopologyReport,
+};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::exchange::AMQPMethod as AmqpExchange;
+use amq_protocol::protocol::exchange::DeclareOk as ExchangeDeclareOk;
+use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::queue::DeclareOk as QueueDeclareOk;
+use amq_protocol::protocol::AMQPClass;
+
+// Receive an exchange.declare, checking its name and flags.
+fn recv_exchange_declare(conn: &mut ServerConn, channel_id: u16, name: &str, passive: bool) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == channel_id => {
+            assert_eq!(declare.exchange, name);
+            assert_eq!(declare.passive, passive);
+        }
+        other => panic!("expected exchange.declare, got {:?}", other),
+    }
+}
+
+// Receive a queue.declare, checking its name and flags.
+fn recv_queue_declare(conn: &mut ServerConn, channel_id: u16, name: &str, passive: bool) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == channel_id => {
+            assert_eq!(declare.queue, name);
+            assert_eq!(declare.passive, passive);
+        }
+        other => panic!("expected queue.declare, got {:?}", other),
+    }
+}
+
+fn send_queue_declare_ok(conn: &mut ServerConn, channel_id: u16, name: &str) {
+    conn.send_method(
+        channel_id,
+        AmqpQueue::DeclareOk(QueueDeclareOk {
+            queue: name.to_string(),
+            message_count: 0,
+            consumer_count: 0,
+        }),
+    );
+}
+
+fn close_channel(conn: &mut ServerConn, channel_id: u16, reply_code: u16, reply_text: &str) {
+    conn.send_method(
+        channel_id,
+        AmqpChannel::Close(ChannelClose {
+            reply_code,
+            reply_text: reply_text.to_string(),
+            class_id: 0,
+            method_id: 0,
+        }),
+    );
+    match conn.recv_method() {
+        (ch, AMQPClass::Channel(AmqpChannel::CloseOk(_))) if ch == channel_id => (),
+        other => panic!("expected channel.close-ok, got {:?}", other),
+    }
+}
+
+#[test]
+fn apply_declares_everything_before_one_sync() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == n => {
+                assert_eq!(declare.exchange, "events");
+                assert_eq!(declare.type_, "topic");
+                assert!(declare.durable && declare.nowait && !declare.passive);
+            }
+            other => panic!("expected exchange.declare, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Exchange(AmqpExchange::Declare(declare))) if ch == n => {
+                assert_eq!(declare.exchange, "amq.topic");
+                assert!(declare.nowait && declare.passive);
+            }
+            other => panic!("expected exchange.declare, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                assert_eq!(declare.queue, "audit");
+                assert!(declare.durable && declare.nowait);
+            }
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Bind(bind))) if ch == n => {
+                assert_eq!(
+                    (bind.queue.as_str(), bind.exchange.as_str()),
+                    ("audit", "events")
+                );
+                assert_eq!(bind.routing_key, "user.*");
+                assert!(bind.nowait);
+            }
+            other => panic!("expected queue.bind, got {:?}", other),
+        }
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Bind(bind))) if ch == n => {
+                assert_eq!(
+                    (bind.queue.as_str(), bind.exchange.as_str()),
+                    ("audit", "amq.topic")
+                );
+                assert!(bind.nowait);
+            }
+            other => panic!("expected queue.bind, got {:?}", other),
+        }
+        recv_exchange_declare(&mut conn, n, "amq.direct", true);
+        conn.send_method(n, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let topology = Topology::builder()
+        .exchange("", ExchangeType::Direct)
+        .exchange("events", ExchangeType::Topic)
+        .durable()
+        .exchange("amq.topic", ExchangeType::Topic)
+        .queue("audit")
+        .durable()
+        .bind("events", "user.*")
+        .bind("amq.topic", "#")
+        .build();
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let report = channel.apply(&topology).unwrap();
+    assert_eq!(
+        report,
+        TopologyReport {
+            exchanges_declared: vec!["events".to_string()],
+            exchanges_verified: vec!["amq.topic".to_string()],
+            queues_declared: vec!["audit".to_string()],
+            bindings_created: 2,
+        }
+    );
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn apply_declares_unnamed_queue_synchronously() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                assert_eq!(declare.queue, "");
+                assert!(declare.exclusive && !declare.nowait);
+            }
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+        send_queue_declare_ok(&mut conn, n, "amq.gen-1");
+        match conn.recv_method() {
+            (ch, AMQPClass::Queue(AmqpQueue::Declare(declare))) if ch == n => {
+                assert_eq!(declare.queue, "audit");
+                assert!(declare.nowait);
+            }
+            other => panic!("expected queue.declare, got {:?}", other),
+        }
+        recv_exchange_declare(&mut conn, n, "amq.direct", true);
+        conn.send_method(n, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    // The builder insists on named queues, so add the unnamed one directly.
+    let mut topology = Topology::builder().queue("audit").build();
+    topology.queues.insert(
+        0,
+        QueueDefinition {
+            name: String::new(),
+            options: QueueDeclareOptions {
+                exclusive: true,
+                ..QueueDeclareOptions::default()
+            },
+        },
+    );
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let report = channel.apply(&topology).unwrap();
+    assert_eq!(
+        report.queues_declared,
+        vec!["amq.gen-1".to_string(), "audit".to_string()]
+    );
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn verify_reports_missing_and_differing_entities() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+
+        let m = conn.accept_channel();
+        recv_exchange_declare(&mut conn, m, "events", true);
+        conn.send_method(m, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+        recv_exchange_declare(&mut conn, m, "events", false);
+        conn.send_method(m, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+        recv_exchange_declare(&mut conn, m, "amq.fanout", true);
+        conn.send_method(m, AmqpExchange::DeclareOk(ExchangeDeclareOk {}));
+        recv_exchange_declare(&mut conn, m, "missing", true);
+        close_channel(
+            &mut conn,
+            m,
+            404,
+            "NOT_FOUND - no exchange 'missing' in vhost '/'",
+        );
+
+        let m = conn.accept_channel();
+        recv_queue_declare(&mut conn, m, "audit", true);
+        send_queue_declare_ok(&mut conn, m, "audit");
+        recv_queue_declare(&mut conn, m, "audit", false);
+        close_channel(
+            &mut conn,
+            m,
+            406,
+            "PRECONDITION_FAILED - inequivalent arg 'durable'",
+        );
+
+        let m = conn.accept_channel();
+        recv_queue_declare(&mut conn, m, "work", true);
+        send_queue_declare_ok(&mut conn, m, "work");
+        recv_queue_declare(&mut conn, m, "work", false);
+        send_queue_declare_ok(&mut conn, m, "work");
+        conn.accept_channel_close(m);
+
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let topology = Topology::builder()
+        .exchange("events", ExchangeType::Topic)
+        .exchange("amq.fanout", ExchangeType::Fanout)
+        .exchange("missing", ExchangeType::Direct)
+        .queue("audit")
+        .durable()
+        .queue("work")
+        .build();
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let mismatches = channel.verify(&topology).unwrap();
+    assert_eq!(
+        mismatches,
+        vec![
+            TopologyMismatch::MissingExchange("missing".to_string()),
+            TopologyMismatch::QueueDiffers {
+                name: "audit".to_string(),
+                message: "PRECONDITION_FAILED - inequivalent arg 'durable'".to_string(),
+            },
+        ]
+    );
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
@@ -0,0 +1,94 @@
+use super::mock_server::MockServer;
+use crate::{Connection, Error, Publish};
+use amq_protocol::protocol::tx::AMQPMethod as AmqpTx;
+use std::panic::{self, AssertUnwindSafe};
+
+#[test]
+fn with_transaction_commits_or_rolls_back() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        assert!(matches!(conn.accept_tx(n), AmqpTx::Select(_)));
+        let (_, body) = conn.recv_publish(n);
+        assert_eq!(body, b"kept");
+        assert!(matches!(conn.accept_tx(n), AmqpTx::Commit(_)));
+
+        // The channel is already transactional; no second select.
+        let (_, body) = conn.recv_publish(n);
+        assert_eq!(body, b"discarded");
+        assert!(matches!(conn.accept_tx(n), AmqpTx::Rollback(_)));
+
+        let (_, body) = conn.recv_publish(n);
+        assert_eq!(body, b"panicked");
+        assert!(matches!(conn.accept_tx(n), AmqpTx::Rollback(_)));
+
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+
+    let value = channel
+        .with_transaction(|ch| {
+            ch.basic_publish("", Publish::new(b"kept", "q"))?;
+            Ok::<_, Error>(5)
+        })
+        .unwrap();
+    assert_eq!(value, 5);
+
+    let result = channel.with_transaction(|ch| {
+        ch.basic_publish("", Publish::new(b"discarded", "q"))?;
+        Err::<(), _>(Error::DeadlineExceeded)
+    });
+    assert!(matches!(result, Err(Error::DeadlineExceeded)));
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        channel.with_transaction(|ch| -> Result<(), Error> {
+            ch.basic_publish("", Publish::new(b"panicked", "q"))?;
+            panic!("processing failed");
+        })
+    }));
+    assert!(result.is_err());
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn confirms_and_transactions_are_exclusive() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        assert!(matches!(conn.accept_tx(n), AmqpTx::Select(_)));
+        conn.accept_channel_close(n);
+        let n = conn.accept_channel();
+        conn.accept_confirm_select(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    channel.tx_select().unwrap();
+    match channel.enable_publisher_confirms() {
+        Err(Error::ConfirmsWithTransactions) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    match channel.publish_confirmed("", Publish::new(b"body", "q"), None) {
+        Err(Error::ConfirmsWithTransactions) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    channel.close().unwrap();
+
+    let channel = connection.open_channel(None).unwrap();
+    channel.enable_publisher_confirms().unwrap();
+    match channel.tx_select() {
+        Err(Error::ConfirmsWithTransactions) => (),
+        other => panic!("unexpected result {:?}", other),
+    }
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
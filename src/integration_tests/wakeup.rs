@@ -0,0 +1,49 @@
+use super::mock_server::MockServer;
+use crate::{Connection, Publish};
+use crossbeam_channel::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROUNDS: usize = 3;
+
+// Heartbeats are disabled, so an idle connection's I/O thread polls without a timeout; only the
+// wakeup sent along with the publish gets it out to the server.
+#[test]
+fn publish_on_idle_connection_is_sent_immediately() {
+    let (received_tx, received_rx) = crossbeam_channel::unbounded();
+    let server = MockServer::start(move |mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        for round in 0..ROUNDS {
+            let (publish, body) = conn.recv_publish(n);
+            assert_eq!(publish.routing_key, round.to_string());
+            assert_eq!(body, b"ping");
+            received_tx.send(Instant::now()).unwrap();
+        }
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    for round in 0..ROUNDS {
+        // Let the I/O thread go back to waiting on an idle socket.
+        thread::sleep(Duration::from_millis(100));
+        let sent = Instant::now();
+        channel
+            .basic_publish("", Publish::new(b"ping", round.to_string()))
+            .unwrap();
+        match received_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(received) => {
+                let latency = received.saturating_duration_since(sent);
+                assert!(latency < Duration::from_millis(500), "took {:?}", latency);
+            }
+            Err(RecvTimeoutError::Timeout) => panic!("publish {} never reached the server", round),
+            Err(RecvTimeoutError::Disconnected) => panic!("server exited early"),
+        }
+    }
+
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
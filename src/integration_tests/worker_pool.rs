@@ -0,0 +1,267 @@
+use super::mock_server::{MockServer, ServerConn};
+use crate::{
+    AckPolicy, Connection, ConsumerMessage, ConsumerOptions, HandlerResult, WorkerPoolOptions,
+    WorkerStats,
+};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::AMQPClass;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Receive an ack or nack, returning its delivery tag and (for nacks) requeue flag.
+fn recv_settle(conn: &mut ServerConn, channel_id: u16) -> (u64, Option<bool>) {
+    match conn.recv_method() {
+        (ch, AMQPClass::Basic(AmqpBasic::Ack(ack))) if ch == channel_id => {
+            assert!(!ack.multiple);
+            (ack.delivery_tag, None)
+        }
+        (ch, AMQPClass::Basic(AmqpBasic::Nack(nack))) if ch == channel_id => {
+            assert!(!nack.multiple);
+            (nack.delivery_tag, Some(nack.requeue))
+        }
+        other => panic!("expected ack or nack, got {:?}", other),
+    }
+}
+
+fn totals(stats: &[WorkerStats]) -> (u64, u64) {
+    stats
+        .iter()
+        .fold((0, 0), |(p, f), s| (p + s.processed, f + s.failed))
+}
+
+#[test]
+fn workers_settle_deliveries_as_handler_directs() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"ok");
+        conn.deliver(n, 2, b"retry");
+        conn.deliver(n, 3, b"drop");
+        conn.deliver(n, 4, b"panic");
+        conn.deliver(n, 5, b"ok");
+        let settled = (0..5)
+            .map(|_| recv_settle(&mut conn, n))
+            .collect::<HashSet<_>>();
+        let expected = vec![
+            (1, None),
+            (2, Some(true)),
+            (3, Some(false)),
+            (4, Some(false)),
+            (5, None),
+        ];
+        assert_eq!(settled, expected.into_iter().collect());
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let options = WorkerPoolOptions {
+        workers: 3,
+        requeue_on_panic: false,
+    };
+    let pool = consumer
+        .run_workers_with_options(options, |delivery| match &delivery.body[..] {
+            b"ok" => HandlerResult::Ack,
+            b"retry" => HandlerResult::Nack { requeue: true },
+            b"drop" => HandlerResult::Nack { requeue: false },
+            _ => panic!("handler failed"),
+        })
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while totals(&pool.stats()) != (2, 3) {
+        assert!(Instant::now() < deadline, "stats {:?}", pool.stats());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(pool.is_running());
+
+    // Cancelling the consumer ends the workers.
+    consumer.cancel().unwrap();
+    let stats = pool.join();
+    assert_eq!(stats.len(), 3);
+    assert!(stats.iter().all(|s| !s.running));
+    assert_eq!(totals(&stats), (2, 3));
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn stop_leaves_untaken_deliveries_in_receiver() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"slow");
+        conn.deliver(n, 2, b"left");
+        let settled = (0..2)
+            .map(|_| recv_settle(&mut conn, n))
+            .collect::<HashSet<_>>();
+        assert_eq!(settled, vec![(1, None), (2, None)].into_iter().collect());
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let (started_tx, started_rx) = crossbeam_channel::bounded(1);
+    let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+    let pool = consumer
+        .run_workers(1, move |delivery| {
+            assert_eq!(delivery.body, b"slow");
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+            HandlerResult::Ack
+        })
+        .unwrap();
+
+    started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let stats = pool.stop(Instant::now() + Duration::from_millis(50));
+    assert_eq!(
+        stats,
+        vec![WorkerStats {
+            processed: 0,
+            failed: 0,
+            running: true,
+        }]
+    );
+
+    // The detached worker still acks the delivery it was handling, then exits without taking
+    // the next one.
+    drop(release_tx);
+    match consumer.recv_timeout(Duration::from_secs(5)) {
+        Ok(Some(ConsumerMessage::Delivery(delivery))) => {
+            assert_eq!(delivery.body, b"left");
+            consumer.ack(delivery).unwrap();
+        }
+        other => panic!("unexpected consumer message {:?}", other),
+    }
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn pool_of_several_workers_acks_individually_under_batched_policy() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"slow");
+        conn.deliver(n, 2, b"fast");
+        // A batched ack of 2 would also settle 1, which is still being handled.
+        let settled = (0..2)
+            .map(|_| recv_settle(&mut conn, n))
+            .collect::<HashSet<_>>();
+        assert_eq!(settled, vec![(1, None), (2, None)].into_iter().collect());
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let options = ConsumerOptions {
+        ack_policy: AckPolicy::Batched {
+            max_count: 1,
+            max_delay: Duration::from_secs(60),
+        },
+        ..ConsumerOptions::default()
+    };
+    let consumer = channel.basic_consume("q", options).unwrap();
+    let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+    let pool = consumer
+        .run_workers(2, move |delivery| {
+            if delivery.body == b"slow" {
+                let _ = release_rx.recv();
+            }
+            HandlerResult::Ack
+        })
+        .unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while totals(&pool.stats()) != (1, 0) {
+        assert!(Instant::now() < deadline, "stats {:?}", pool.stats());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    drop(release_tx);
+    while totals(&pool.stats()) != (2, 0) {
+        assert!(Instant::now() < deadline, "stats {:?}", pool.stats());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    consumer.cancel().unwrap();
+    assert_eq!(totals(&pool.join()), (2, 0));
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
+
+#[test]
+fn scoped_workers_borrow_from_enclosing_function() {
+    let server = MockServer::start(|mut conn| {
+        conn.handshake();
+        let n = conn.accept_channel();
+        conn.accept_consume(n);
+        conn.deliver(n, 1, b"a");
+        conn.deliver(n, 2, b"b");
+        let settled = (0..2)
+            .map(|_| recv_settle(&mut conn, n))
+            .collect::<HashSet<_>>();
+        assert_eq!(settled, vec![(1, None), (2, None)].into_iter().collect());
+        conn.accept_cancel(n);
+        conn.accept_channel_close(n);
+        conn.accept_connection_close();
+    });
+
+    let mut connection = Connection::insecure_open(&server.url()).unwrap();
+    let channel = connection.open_channel(None).unwrap();
+    let consumer = channel
+        .basic_consume("q", ConsumerOptions::default())
+        .unwrap();
+    let bodies = Mutex::new(Vec::new());
+    let stats = std::thread::scope(|scope| {
+        let options = WorkerPoolOptions {
+            workers: 2,
+            ..WorkerPoolOptions::default()
+        };
+        let pool = consumer
+            .run_workers_scoped(scope, options, |delivery| {
+                bodies.lock().unwrap().push(delivery.body);
+                HandlerResult::Ack
+            })
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while totals(&pool.stats()) != (2, 0) {
+            assert!(Instant::now() < deadline, "stats {:?}", pool.stats());
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        consumer.cancel().unwrap();
+        pool.join()
+    });
+    assert_eq!(totals(&stats), (2, 0));
+    let mut bodies = bodies.into_inner().unwrap();
+    bodies.sort();
+    assert_eq!(bodies, vec![b"a".to_vec(), b"b".to_vec()]);
+
+    drop(consumer);
+    channel.close().unwrap();
+    connection.close().unwrap();
+    server.join();
+}
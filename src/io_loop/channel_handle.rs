@@ -1,26 +1,50 @@
 use super::{
-    ConnectionBlockedNotification, ConsumerMessage, CrossbeamReceiver, IoLoopHandle, IoLoopHandle0,
+    ChannelAllocator, ChannelStats, ConnectionBlockedNotification, IoLoopHandle, IoLoopHandle0,
+    PublishSender, ReturnHandler, ShutdownReport,
 };
+use crate::errors::*;
+use crate::logging::{debug, trace};
 use crate::serialize::{IntoAmqpClass, TryFromAmqpClass};
-use crate::{Confirm, Get, Result, Return};
-use amq_protocol::protocol::basic::Get as AmqpGet;
-use amq_protocol::protocol::basic::{AMQPProperties, Consume};
+use crate::tag::SeqNo;
+use crate::{AmqpReplyCode, Confirm, ConfirmOutcome, PublishBody, PublishResult};
+use amq_protocol::protocol::basic::AMQPProperties;
 use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
 use amq_protocol::protocol::channel::Close as ChannelClose;
-use amq_protocol::protocol::channel::CloseOk as ChannelCloseOk;
 use amq_protocol::protocol::channel::Open as ChannelOpen;
 use amq_protocol::protocol::channel::OpenOk as ChannelOpenOk;
 use amq_protocol::protocol::connection::Close as ConnectionClose;
-use amq_protocol::protocol::constants::REPLY_SUCCESS;
 use crossbeam_channel::Sender as CrossbeamSender;
-use log::{debug, trace};
+use snafu::IntoError;
+use std::cmp;
 use std::fmt::Debug;
+use std::io::Read;
+use std::time::Instant;
+
+#[cfg(feature = "consume")]
+use super::{ChannelSender, ConsumerMessage, CrossbeamReceiver};
+#[cfg(feature = "consume")]
+use crate::consumer::ConsumerBuffer;
+#[cfg(feature = "consume")]
+use crate::tag::ChannelEpoch;
+#[cfg(feature = "consume")]
+use crate::Get;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::{Consume, Get as AmqpGet};
 
 // Each frame has 8 bytes of overhead (7 byte header, 1 byte frame-end), so when
 // we break our frames up into frame_max pieces, we need to account for this many
 // bytes.
 const FRAME_OVERHEAD: usize = 8;
 
+// The most body that fits in one content body frame, given the negotiated `frame_max` (0 means
+// no limit).
+pub(super) fn body_frame_max(frame_max: usize) -> usize {
+    match frame_max {
+        0 => usize::MAX - FRAME_OVERHEAD,
+        n => n - FRAME_OVERHEAD,
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Channel0Handle {
     handle: IoLoopHandle0,
@@ -28,16 +52,15 @@ pub(crate) struct Channel0Handle {
 }
 
 impl Channel0Handle {
-    pub(super) fn new(handle: IoLoopHandle0, mut frame_max: usize) -> Channel0Handle {
+    pub(super) fn new(handle: IoLoopHandle0, frame_max: usize) -> Channel0Handle {
         assert!(
             handle.channel_id() == 0,
             "handle for Channel0 must be channel 0"
         );
-        if frame_max == 0 {
-            frame_max = usize::max_value();
+        Channel0Handle {
+            handle,
+            frame_max: body_frame_max(frame_max),
         }
-        frame_max -= FRAME_OVERHEAD;
-        Channel0Handle { handle, frame_max }
     }
 
     pub(crate) fn set_blocked_tx(
@@ -47,19 +70,67 @@ impl Channel0Handle {
         self.handle.set_blocked_tx(tx)
     }
 
-    pub(crate) fn close_connection(&mut self) -> Result<()> {
+    pub(crate) fn close_connection(
+        &mut self,
+        reply_code: u16,
+        reply_text: &str,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
         let close = ConnectionClose {
-            reply_code: u16::from(REPLY_SUCCESS),
-            reply_text: "goodbye".to_string(),
+            reply_code,
+            reply_text: reply_text.to_string(),
             class_id: 0,
             method_id: 0,
         };
-        self.handle.call_connection_close(close).map(|_| ())
+        self.handle
+            .call_connection_close(close, deadline)
+            .map(|_| ())
+    }
+
+    pub(crate) fn shutdown(&mut self, deadline: Instant) -> Result<ShutdownReport> {
+        self.handle.shutdown(deadline)
     }
 
     pub(crate) fn open_channel(&mut self, channel_id: Option<u16>) -> Result<ChannelHandle> {
-        let mut handle = self.handle.allocate_channel(channel_id)?;
+        let handle = self.handle.allocate_channel(channel_id)?;
+        ChannelHandle::open(handle, self.handle.allocator().clone(), self.frame_max)
+    }
 
+    pub(crate) fn channel_opener(&self) -> ChannelOpener {
+        ChannelOpener {
+            allocator: self.handle.allocator().clone(),
+            frame_max: self.frame_max,
+        }
+    }
+}
+
+// A cloneable, sendable handle that opens channels (with the lowest free channel ID) from any
+// thread.
+#[derive(Clone)]
+pub(crate) struct ChannelOpener {
+    allocator: ChannelAllocator,
+    frame_max: usize,
+}
+
+impl ChannelOpener {
+    pub(crate) fn open(&self) -> Result<ChannelHandle> {
+        let handle = self.allocator.allocate(None)?;
+        ChannelHandle::open(handle, self.allocator.clone(), self.frame_max)
+    }
+}
+
+pub(crate) struct ChannelHandle {
+    handle: IoLoopHandle,
+    allocator: ChannelAllocator,
+    frame_max: usize,
+}
+
+impl ChannelHandle {
+    fn open(
+        mut handle: IoLoopHandle,
+        allocator: ChannelAllocator,
+        frame_max: usize,
+    ) -> Result<ChannelHandle> {
         debug!("opening channel {}", handle.channel_id());
         let out_of_band = String::new();
         let open = AmqpChannel::Open(ChannelOpen { out_of_band });
@@ -68,40 +139,85 @@ impl Channel0Handle {
         trace!("got open-ok: {:?}", open_ok);
         Ok(ChannelHandle {
             handle,
-            frame_max: self.frame_max,
+            allocator,
+            frame_max,
         })
     }
-}
 
-pub(crate) struct ChannelHandle {
-    handle: IoLoopHandle,
-    frame_max: usize,
-}
+    // Opens another channel on the same connection, with the lowest free channel ID.
+    pub(crate) fn open_sibling(&self) -> Result<ChannelHandle> {
+        let handle = self.allocator.allocate(None)?;
+        ChannelHandle::open(handle, self.allocator.clone(), self.frame_max)
+    }
 
-impl ChannelHandle {
     pub(crate) fn close(&mut self) -> Result<()> {
-        let close = AmqpChannel::Close(ChannelClose {
+        let close = ChannelClose {
             reply_code: 0,
             reply_text: String::new(),
             class_id: 0,
             method_id: 0,
-        });
+        };
         debug!("closing channel {}", self.channel_id());
-        let close_ok = self.handle.call::<_, ChannelCloseOk>(close)?;
+        let close_ok = self.handle.close_channel(close)?;
         trace!("got close-ok: {:?}", close_ok);
         Ok(())
     }
 
+    // Used in place of `close` when the channel is dropped; see `IoLoopHandle::close_on_drop`.
+    pub(crate) fn close_on_drop(&mut self) {
+        debug!("channel {} dropped; closing it", self.channel_id());
+        self.handle.close_on_drop();
+    }
+
     #[inline]
     pub(crate) fn channel_id(&self) -> u16 {
         self.handle.channel_id()
     }
 
     #[inline]
-    pub(crate) fn set_return_handler(
-        &mut self,
-        handler: Option<CrossbeamSender<Return>>,
-    ) -> Result<()> {
+    pub(crate) fn stats(&self) -> ChannelStats {
+        self.handle.stats()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn inject_io_thread_panic(&mut self, message: &'static str) -> Result<()> {
+        self.handle.inject_panic(message)
+    }
+
+    // The error for a caller that found the I/O thread gone.
+    pub(crate) fn event_loop_dropped(&self) -> Error {
+        self.handle.event_loop_dropped()
+    }
+
+    #[cfg(feature = "consume")]
+    #[inline]
+    pub(crate) fn epoch(&self) -> ChannelEpoch {
+        self.handle.epoch()
+    }
+
+    #[cfg(feature = "consume")]
+    #[inline]
+    pub(crate) fn sender(&self) -> ChannelSender {
+        self.handle.sender()
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn set_consumer_paused(&mut self, consumer_tag: &str, paused: bool) -> Result<()> {
+        self.handle.set_consumer_paused(consumer_tag, paused)
+    }
+
+    #[inline]
+    pub(crate) fn is_open(&mut self) -> bool {
+        self.handle.is_open()
+    }
+
+    #[inline]
+    pub(crate) fn publish_sender(&self) -> PublishSender {
+        self.handle.publish_sender(self.frame_max)
+    }
+
+    #[inline]
+    pub(crate) fn set_return_handler(&mut self, handler: ReturnHandler) -> Result<()> {
         self.handle.set_return_handler(handler)
     }
 
@@ -113,20 +229,60 @@ impl ChannelHandle {
         self.handle.set_pub_confirm_handler(handler)
     }
 
+    // Asks the I/O thread to report on `tx` how the server settles the publish with `seq_no`,
+    // which must not have been sent yet.
+    #[inline]
+    pub(crate) fn await_confirm(
+        &mut self,
+        seq_no: SeqNo,
+        tx: CrossbeamSender<Result<ConfirmOutcome>>,
+    ) -> Result<()> {
+        self.handle.await_confirm(seq_no, tx)
+    }
+
+    #[inline]
+    pub(crate) fn set_publish_result_handler(
+        &mut self,
+        handler: CrossbeamSender<PublishResult>,
+        first_seq_no: SeqNo,
+    ) -> Result<()> {
+        self.handle
+            .set_publish_result_handler(handler, first_seq_no)
+    }
+
+    #[cfg(feature = "consume")]
     pub(crate) fn get(&mut self, get: AmqpGet) -> Result<Option<Get>> {
         self.handle.get(get)
     }
 
+    #[cfg(feature = "consume")]
     pub(crate) fn consume(
         &mut self,
         consume: Consume,
+        buffer: ConsumerBuffer,
     ) -> Result<(String, CrossbeamReceiver<ConsumerMessage>)> {
         trace!(
-            "starting consumer on channel {}: {:?}",
+            "starting consumer on channel {}: {:?} ({:?})",
+            self.channel_id(),
+            consume,
+            buffer
+        );
+        self.handle.consume(consume, buffer)
+    }
+
+    #[cfg(feature = "consume")]
+    pub(crate) fn consume_nowait(
+        &mut self,
+        consume: Consume,
+        buffer: ConsumerBuffer,
+    ) -> Result<CrossbeamReceiver<ConsumerMessage>> {
+        trace!(
+            "starting consumer on channel {} without waiting for consume-ok: {:?} ({:?})",
             self.channel_id(),
-            consume
+            consume,
+            buffer
         );
-        self.handle.consume(consume)
+        self.handle.consume_nowait(consume, buffer)
     }
 
     pub(crate) fn call<M: IntoAmqpClass + Debug, T: TryFromAmqpClass>(
@@ -150,9 +306,42 @@ impl ChannelHandle {
         self.handle.call_nowait(method)
     }
 
+    #[cfg(feature = "consume")]
+    pub(crate) fn settle<M: IntoAmqpClass + Debug>(
+        &mut self,
+        method: M,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> Result<()> {
+        trace!("settling on channel {}: {:?}", self.channel_id(), method);
+        self.handle.settle(method, delivery_tag, multiple)
+    }
+
+    pub(crate) fn try_send_method_with_content<M: IntoAmqpClass + Debug>(
+        &mut self,
+        method: M,
+        content: &PublishBody,
+        class_id: u16,
+        properties: &AMQPProperties,
+    ) -> Result<bool> {
+        trace!(
+            "trying to send method on channel {} with content (len = {}): {:?}",
+            self.channel_id(),
+            content.len(),
+            method
+        );
+        self.handle.try_send_method_with_content(
+            method,
+            class_id,
+            content,
+            properties,
+            self.frame_max,
+        )
+    }
+
     pub(crate) fn send_content(
         &mut self,
-        mut content: &[u8],
+        content: &PublishBody,
         class_id: u16,
         properties: &AMQPProperties,
     ) -> Result<()> {
@@ -163,25 +352,81 @@ impl ChannelHandle {
             content.len()
         );
         self.handle
-            .send_content_header(class_id, content.len(), properties)?;
+            .send_content_header(class_id, content.len() as u64, properties)?;
 
-        while content.len() > self.frame_max {
+        let mut start = 0;
+        while start < content.len() {
+            let end = start + cmp::min(content.len() - start, self.frame_max);
             trace!(
-                "sending partial content body frame on channel {} (len = {})",
+                "sending content body frame on channel {} (len = {})",
                 self.channel_id(),
-                self.frame_max
+                end - start
             );
-            self.handle.send_content_body(&content[..self.frame_max])?;
-            content = &content[self.frame_max..];
+            match content {
+                PublishBody::Shared(body) => {
+                    self.handle.send_shared_content_body(body, start..end)?
+                }
+                _ => self.handle.send_content_body(&content[start..end])?,
+            }
+            start = end;
         }
-        if !content.is_empty() {
+        Ok(())
+    }
+
+    // Sends a content header announcing `len` bytes, then body frames read from `reader` one
+    // frame at a time. Each frame waits for room in the I/O thread's mailbox, so at most a few
+    // frames are held in memory. A body can't be cut short once its header is sent, so if
+    // `reader` fails we close the channel.
+    pub(crate) fn send_content_from_reader<R: Read>(
+        &mut self,
+        mut reader: R,
+        len: u64,
+        class_id: u16,
+        properties: &AMQPProperties,
+    ) -> Result<()> {
+        trace!(
+            "sending content header on channel {} (class_id = {}, len = {}, streaming)",
+            self.channel_id(),
+            class_id,
+            len
+        );
+        self.handle.send_content_header(class_id, len, properties)?;
+
+        let mut chunk = vec![0; cmp::min(len, self.frame_max as u64) as usize];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = cmp::min(remaining, self.frame_max as u64) as usize;
+            if let Err(err) = reader.read_exact(&mut chunk[..n]) {
+                let sent = len - remaining;
+                self.abort(
+                    class_id,
+                    format!("publish body source failed after {} of {} bytes", sent, len),
+                );
+                return Err(PublishSourceFailedSnafu { sent, len }.into_error(err));
+            }
             trace!(
-                "sending final content body frame on channel {} (len = {})",
+                "sending streamed content body frame on channel {} (len = {})",
                 self.channel_id(),
-                content.len()
+                n
             );
-            self.handle.send_content_body(content)?;
+            self.handle.send_content_body(&chunk[..n])?;
+            remaining -= n as u64;
         }
         Ok(())
     }
+
+    // Close this channel because of a client-side error. The server may escalate this to a
+    // connection error if it arrives in the middle of a message.
+    fn abort(&mut self, class_id: u16, reply_text: String) {
+        debug!("aborting channel {}: {}", self.channel_id(), reply_text);
+        let close = ChannelClose {
+            reply_code: AmqpReplyCode::InternalError.code(),
+            reply_text,
+            class_id,
+            method_id: 0,
+        };
+        if let Err(err) = self.handle.close_channel(close) {
+            debug!("failed to abort channel {}: {}", self.channel_id(), err);
+        }
+    }
 }
@@ -20,7 +20,7 @@ impl<T> ChannelSlots<T> {
         }
     }
 
-    pub(crate) fn drain(&mut self) -> Drain<u16, T> {
+    pub(crate) fn drain(&mut self) -> Drain<'_, u16, T> {
         for (id, _) in self.slots.iter() {
             self.freed_channel_ids.insert(*id);
         }
@@ -55,14 +55,22 @@ impl<T> ChannelSlots<T> {
             Some(id) => id,
             None => return self.insert_unused_channel_id(make_entry),
         };
+        if channel_id == 0 {
+            return ChannelIdInUseSnafu { channel_id }.fail();
+        }
         if channel_id > self.channel_max {
-            return UnavailableChannelIdSnafu { channel_id }.fail();
+            return ChannelMaxReachedSnafu {
+                channel_max: self.channel_max,
+            }
+            .fail();
         }
         match self.slots.entry(channel_id) {
-            Entry::Occupied(_) => UnavailableChannelIdSnafu { channel_id }.fail(),
+            Entry::Occupied(_) => ChannelIdInUseSnafu { channel_id }.fail(),
             Entry::Vacant(entry) => {
                 let (t, u) = make_entry(channel_id)?;
                 entry.insert(t);
+                // A freed ID that has been taken explicitly is no longer free.
+                self.freed_channel_ids.swap_remove(&channel_id);
                 Ok(u)
             }
         }
@@ -86,24 +94,41 @@ impl<T> ChannelSlots<T> {
             self.next_channel_id += 1;
             match self.slots.entry(channel_id) {
                 Entry::Occupied(_) => continue,
-                Entry::Vacant(entry) => {
-                    let (t, u) = make_entry(channel_id)?;
-                    entry.insert(t);
-                    return Ok(u);
-                }
+                Entry::Vacant(entry) => match make_entry(channel_id) {
+                    Ok((t, u)) => {
+                        entry.insert(t);
+                        return Ok(u);
+                    }
+                    Err(err) => {
+                        // Never used, but we've moved past it; remember it for later.
+                        self.freed_channel_ids.insert(channel_id);
+                        return Err(err);
+                    }
+                },
             }
         }
 
         // At the end of our rope for simple channel allocation; fall back to finding
         // one that has been previously freed.
-        let channel_id = self.freed_channel_ids.pop().context(ExhaustedChannelIdsSnafu)?;
+        let channel_id = self
+            .freed_channel_ids
+            .pop()
+            .context(ChannelMaxReachedSnafu {
+                channel_max: self.channel_max,
+            })?;
         match self.slots.entry(channel_id) {
             Entry::Occupied(_) => unreachable!("free channel id cannot be occupied"),
-            Entry::Vacant(entry) => {
-                let (t, u) = make_entry(channel_id)?;
-                entry.insert(t);
-                Ok(u)
-            }
+            Entry::Vacant(entry) => match make_entry(channel_id) {
+                Ok((t, u)) => {
+                    entry.insert(t);
+                    Ok(u)
+                }
+                Err(err) => {
+                    // Still free; don't lose track of it.
+                    self.freed_channel_ids.insert(channel_id);
+                    Err(err)
+                }
+            },
         }
     }
 }
@@ -150,7 +175,7 @@ mod tests {
         let mut cs = with_channel_max(4);
         let res = cs.insert(Some(5), id);
         match res.unwrap_err() {
-            Error::UnavailableChannelId { channel_id } if channel_id == 5 => (),
+            Error::ChannelMaxReached { channel_max: 4 } => (),
             err => panic!("unexpected error {}", err),
         }
     }
@@ -161,7 +186,7 @@ mod tests {
         cs.insert(Some(1), id).unwrap();
         let res = cs.insert(Some(1), id);
         match res.unwrap_err() {
-            Error::UnavailableChannelId { channel_id } if channel_id == 1 => (),
+            Error::ChannelIdInUse { channel_id: 1 } => (),
             err => panic!("unexpected error {}", err),
         }
     }
@@ -198,8 +223,49 @@ mod tests {
             cs.insert(Some(i), id).unwrap();
         }
         match cs.insert(None, id).unwrap_err() {
-            Error::ExhaustedChannelIds => (),
+            Error::ChannelMaxReached { channel_max: 4 } => (),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn insert_channel_zero_fails() {
+        let mut cs = with_channel_max(4);
+        match cs.insert(Some(0), id).unwrap_err() {
+            Error::ChannelIdInUse { channel_id: 0 } => (),
             err => panic!("unexpected error {}", err),
         }
     }
+
+    #[test]
+    fn explicitly_taking_freed_id_removes_it_from_free_list() {
+        let mut cs = with_channel_max(2);
+        cs.insert(None, id).unwrap();
+        cs.insert(None, id).unwrap();
+        assert!(cs.remove(1).is_some());
+        cs.insert(Some(1), id).unwrap();
+        match cs.insert(None, id).unwrap_err() {
+            Error::ChannelMaxReached { channel_max: 2 } => (),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn ids_are_recycled_indefinitely() {
+        let mut cs = with_channel_max(3);
+        for _ in 0..100 {
+            let channel_id = cs.insert(None, |n| Ok((n, n))).unwrap();
+            assert!((1..=3).contains(&channel_id));
+            assert!(cs.remove(channel_id).is_some());
+        }
+    }
+
+    #[test]
+    fn failed_insert_does_not_leak_id() {
+        let mut cs = with_channel_max(1);
+        let res: Result<()> = cs.insert(None, |_| ClientExceptionSnafu.fail());
+        assert!(res.is_err());
+        cs.insert(None, id).unwrap();
+        assert!(cs.get(1).is_some());
+    }
 }
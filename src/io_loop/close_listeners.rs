@@ -0,0 +1,95 @@
+use crate::errors::*;
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+// Receivers registered with `Connection::on_close`. The I/O thread reports how it ended to each
+// of them exactly once; listeners that register after that are told immediately.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CloseListeners(Arc<Mutex<State>>);
+
+#[derive(Debug)]
+enum State {
+    Running(Vec<Sender<ArcError>>),
+    Closed(ArcError),
+}
+
+impl Default for State {
+    fn default() -> State {
+        State::Running(Vec::new())
+    }
+}
+
+impl CloseListeners {
+    pub(crate) fn listen(&self) -> Receiver<ArcError> {
+        // Each listener is sent exactly one error, so a bound of 1 never blocks.
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        match &mut *self.0.lock().unwrap_or_else(|err| err.into_inner()) {
+            State::Running(listeners) => listeners.push(tx),
+            State::Closed(err) => {
+                let _ = tx.send(Arc::clone(err));
+            }
+        }
+        rx
+    }
+
+    // Called with the I/O thread's final result. A clean exit means the client closed the
+    // connection.
+    pub(super) fn close(&self, result: &Result<()>) {
+        let err = Arc::new(match result {
+            Ok(()) => Error::ClientClosedConnection,
+            Err(err) => err.duplicate(),
+        });
+        let mut state = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        if let State::Running(listeners) = &*state {
+            for tx in listeners {
+                // Ignore failure; the listener may already have been dropped.
+                let _ = tx.send(Arc::clone(&err));
+            }
+            *state = State::Closed(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn listeners_are_told_once() {
+        let listeners = CloseListeners::default();
+        let before = listeners.listen();
+        listeners.close(
+            &MissedServerHeartbeatsSnafu {
+                since_last_rx: Duration::from_secs(30),
+            }
+            .fail(),
+        );
+        let after = listeners.listen();
+
+        for rx in &[before, after] {
+            match &*rx.try_recv().unwrap() {
+                Error::MissedServerHeartbeats { .. } => (),
+                err => panic!("unexpected error {}", err),
+            }
+            assert!(rx.try_recv().is_err());
+        }
+
+        // Only the first result counts.
+        let rx = listeners.listen();
+        listeners.close(&Ok(()));
+        assert!(matches!(
+            *rx.recv().unwrap(),
+            Error::MissedServerHeartbeats { .. }
+        ));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn clean_exit_is_client_close() {
+        let listeners = CloseListeners::default();
+        let rx = listeners.listen();
+        listeners.close(&Ok(()));
+        assert!(matches!(*rx.recv().unwrap(), Error::ClientClosedConnection));
+    }
+}
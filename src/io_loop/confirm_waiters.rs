@@ -0,0 +1,169 @@
+use crate::errors::*;
+use crate::tag::SeqNo;
+use crate::{Confirm, ConfirmOutcome, Return};
+use crossbeam_channel::Sender;
+use std::collections::BTreeMap;
+
+// Callers of `Channel::publish_confirmed` on one channel, keyed by the sequence number of the
+// message each is waiting on.
+//
+// The server sends a mandatory message's basic.return before the basic.ack for it, and acks
+// unroutable messages right away. While anyone is waiting, a return is therefore held until the
+// next confirm and given to the waiter that confirm settles at its delivery tag; if there is no
+// such waiter, it goes to the channel's return listener as usual.
+#[derive(Default)]
+pub(super) struct ConfirmWaiters {
+    waiters: BTreeMap<SeqNo, Sender<Result<ConfirmOutcome>>>,
+    held_return: Option<Return>,
+}
+
+impl ConfirmWaiters {
+    pub(super) fn insert(&mut self, seq_no: SeqNo, tx: Sender<Result<ConfirmOutcome>>) {
+        self.waiters.insert(seq_no, tx);
+    }
+
+    // Returns `return_` back if nobody could be waiting for it.
+    pub(super) fn hold_return(&mut self, return_: Return) -> Option<Return> {
+        if self.waiters.is_empty() {
+            return Some(return_);
+        }
+        self.held_return.replace(return_)
+    }
+
+    // Wakes every waiter settled by `confirm`, returning the held return if none of them claimed
+    // it.
+    pub(super) fn settle(&mut self, confirm: &Confirm) -> Option<Return> {
+        let (payload, acked) = match confirm {
+            Confirm::Ack(payload) => (payload, true),
+            Confirm::Nack(payload) => (payload, false),
+        };
+        let tag = SeqNo::new(payload.delivery_tag);
+        let mut held_return = self.held_return.take();
+
+        let settled = if payload.multiple {
+            let rest = self.waiters.split_off(&tag.next());
+            std::mem::replace(&mut self.waiters, rest)
+        } else {
+            self.waiters.remove_entry(&tag).into_iter().collect()
+        };
+        for (seq_no, tx) in settled {
+            let outcome = match held_return.take() {
+                Some(return_) if acked && seq_no == tag => ConfirmOutcome::Returned(return_),
+                other => {
+                    held_return = other;
+                    if acked {
+                        ConfirmOutcome::Acked
+                    } else {
+                        ConfirmOutcome::Nacked
+                    }
+                }
+            };
+            // The caller may have given up waiting; that's fine.
+            let _ = tx.try_send(Ok(outcome));
+        }
+        held_return
+    }
+
+    // Fails every waiter (e.g., because the channel closed).
+    pub(super) fn fail_all<F: Fn() -> Error>(&mut self, make_err: F) {
+        for (_, tx) in std::mem::take(&mut self.waiters) {
+            let _ = tx.try_send(Err(make_err()));
+        }
+        self.held_return = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AmqpProperties, ConfirmPayload};
+    use amq_protocol::protocol::basic::Return as AmqpReturn;
+    use crossbeam_channel::Receiver;
+
+    fn wait(waiters: &mut ConfirmWaiters, seq_no: u64) -> Receiver<Result<ConfirmOutcome>> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        waiters.insert(SeqNo::new(seq_no), tx);
+        rx
+    }
+
+    fn ack(delivery_tag: u64, multiple: bool) -> Confirm {
+        Confirm::Ack(ConfirmPayload {
+            delivery_tag,
+            multiple,
+        })
+    }
+
+    fn return_() -> Return {
+        Return::new(
+            AmqpReturn {
+                reply_code: 312,
+                reply_text: "NO_ROUTE".to_string(),
+                exchange: String::new(),
+                routing_key: "nowhere".to_string(),
+            },
+            b"body".to_vec(),
+            AmqpProperties::default(),
+        )
+    }
+
+    fn outcome(rx: &Receiver<Result<ConfirmOutcome>>) -> Option<ConfirmOutcome> {
+        rx.try_recv().ok().map(Result::unwrap)
+    }
+
+    #[test]
+    fn multiple_ack_settles_everything_up_to_its_tag() {
+        let mut waiters = ConfirmWaiters::default();
+        let rxs = (1..=4).map(|n| wait(&mut waiters, n)).collect::<Vec<_>>();
+
+        assert!(waiters.settle(&ack(3, true)).is_none());
+        for rx in &rxs[..3] {
+            assert!(matches!(outcome(rx), Some(ConfirmOutcome::Acked)));
+        }
+        assert!(outcome(&rxs[3]).is_none());
+
+        let nack = Confirm::Nack(ConfirmPayload {
+            delivery_tag: 4,
+            multiple: false,
+        });
+        waiters.settle(&nack);
+        assert!(matches!(outcome(&rxs[3]), Some(ConfirmOutcome::Nacked)));
+    }
+
+    #[test]
+    fn return_goes_to_the_publish_acked_next() {
+        let mut waiters = ConfirmWaiters::default();
+        let first = wait(&mut waiters, 1);
+        let second = wait(&mut waiters, 2);
+
+        assert!(waiters.hold_return(return_()).is_none());
+        assert!(waiters.settle(&ack(2, false)).is_none());
+        match outcome(&second) {
+            Some(ConfirmOutcome::Returned(ret)) => assert_eq!(ret.routing_key, "nowhere"),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert!(outcome(&first).is_none());
+    }
+
+    #[test]
+    fn unclaimed_return_is_handed_back() {
+        let mut waiters = ConfirmWaiters::default();
+        assert!(waiters.hold_return(return_()).is_some());
+
+        // A return for a publish nobody is waiting on (here, tag 1).
+        let rx = wait(&mut waiters, 2);
+        assert!(waiters.hold_return(return_()).is_none());
+        assert!(waiters.settle(&ack(1, false)).is_some());
+        assert!(outcome(&rx).is_none());
+    }
+
+    #[test]
+    fn fail_all_wakes_waiters_with_errors() {
+        let mut waiters = ConfirmWaiters::default();
+        let rx = wait(&mut waiters, 1);
+        waiters.fail_all(|| Error::ClientClosedConnection);
+        match rx.try_recv() {
+            Ok(Err(Error::ClientClosedConnection)) => (),
+            other => panic!("unexpected outcome {:?}", other),
+        }
+    }
+}
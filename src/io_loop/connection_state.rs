@@ -1,9 +1,11 @@
 use crate::errors::*;
-use crate::{Confirm, ConfirmPayload, Return};
+use crate::logging::{debug, error, trace, warn};
+use crate::reply_code::method_name;
+use crate::{AmqpReplyCode, Confirm, ConfirmPayload, Return};
 use amq_protocol::frame::AMQPFrame;
 use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
-use amq_protocol::protocol::basic::CancelOk;
 use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
 use amq_protocol::protocol::channel::CloseOk as ChannelCloseOk;
 use amq_protocol::protocol::confirm::AMQPMethod as AmqpConfirm;
 use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
@@ -11,18 +13,38 @@ use amq_protocol::protocol::connection::Close as ConnectionClose;
 use amq_protocol::protocol::connection::CloseOk as ConnectionCloseOk;
 use amq_protocol::protocol::exchange::AMQPMethod as AmqpExchange;
 use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
+use amq_protocol::protocol::tx::AMQPMethod as AmqpTx;
 use amq_protocol::protocol::{AMQPClass, AMQPHardError};
 use crossbeam_channel::{Sender, TrySendError};
-use log::{debug, error, trace, warn};
 use snafu::OptionExt;
-use std::collections::hash_map::Entry;
 
 use super::content_collector::CollectorResult;
 use super::{
-    Channel0Slot, ChannelMessage, ChannelSlot, ConnectionBlockedNotification, ConsumerMessage,
-    Inner,
+    Channel0Slot, ChannelMessage, ChannelSlot, ConnectionBlockedNotification, Inner,
+    UnconfirmedPublishes,
 };
 
+#[cfg(feature = "consume")]
+use super::parked_publishes::Original;
+#[cfg(feature = "consume")]
+use super::{ConsumerMessage, ConsumerSlot, StalledDelivery};
+#[cfg(feature = "consume")]
+use crate::{DeliveryStream, OverflowPolicy};
+#[cfg(feature = "consume")]
+use amq_protocol::frame::AMQPContentHeader;
+#[cfg(feature = "consume")]
+use super::io_loop_handle::push_method_with_content;
+#[cfg(feature = "consume")]
+use crate::serialize::OutputBuffer;
+#[cfg(feature = "consume")]
+use crate::Delivery;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::Publish as AmqpPublish;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::{Ack, CancelOk, Nack};
+#[cfg(feature = "consume")]
+use std::collections::hash_map::Entry;
+
 // Clippy warns about ConnectionState::Steady being much larger than the other variants, but we
 // expect ConnectionState to be in the Steady case almost all the time.
 #[allow(clippy::large_enum_variant)]
@@ -34,10 +56,14 @@ pub(super) enum ConnectionState {
 }
 
 fn slot_remove(inner: &mut Inner, channel_id: u16) -> Result<ChannelSlot> {
-    inner
+    let slot = inner
         .chan_slots
         .remove(channel_id)
-        .context(ReceivedFrameWithBogusChannelIdSnafu { channel_id })
+        .context(ReceivedFrameWithBogusChannelIdSnafu { channel_id })?;
+    // Before our callers tell the channel's handle and consumers it is gone, so they see it
+    // closed in its stats.
+    slot.counters.close();
+    Ok(slot)
 }
 
 fn slot_get(inner: &mut Inner, channel_id: u16) -> Result<&ChannelSlot> {
@@ -56,8 +82,7 @@ fn slot_get_mut(inner: &mut Inner, channel_id: u16) -> Result<&mut ChannelSlot>
 
 fn send<T: Send + Sync + 'static>(tx: &Sender<T>, item: T) -> Result<()> {
     // See comment in ChannelSlot::new() about the bound size of the control
-    // channel. If we're sending to a consumer channel, they are not bounded
-    // and will not return Full.
+    // channel.
     match tx.try_send(item) {
         Ok(()) => Ok(()),
         Err(TrySendError::Full(_)) => {
@@ -71,21 +96,134 @@ fn send<T: Send + Sync + 'static>(tx: &Sender<T>, item: T) -> Result<()> {
     }
 }
 
-// When we set up a return listener, it's just a crossbeam channel. If it gets dropped,
-// we don't want to error; just start discarding returned messages.
+// We only send deliveries to consumer channels with room for them (see `dispatch_delivery`), and
+// bounded ones keep room for one more message, so the only possible failure is that the
+// consumer's receiver has been dropped (e.g., the `Consumer` was dropped after its connection
+// started closing, or a callback consumer panicked). That consumer is gone either way; discard
+// the message rather than failing the whole connection.
+#[cfg(feature = "consume")]
+fn send_consumer(tx: &Sender<ConsumerMessage>, message: ConsumerMessage) {
+    if let Err(err) = tx.try_send(message) {
+        debug!(
+            "discarding message for dropped consumer: {:?}",
+            err.into_inner()
+        );
+    }
+}
+
 fn try_send_return(slot: &mut ChannelSlot, return_: Return) {
-    let return_ = if let Some(tx) = &slot.return_handler {
-        match tx.try_send(return_) {
-            Ok(()) => return,
-            Err(TrySendError::Full(return_)) | Err(TrySendError::Disconnected(return_)) => {
-                slot.return_handler = None;
-                return_
-            }
+    slot.return_listener.send(return_, &slot.counters);
+}
+
+// A return may be for a delivery we republished to a parking lot, in which case it is held until
+// the following ack (see `ParkedPublishes`).
+fn hold_or_send_return(slot: &mut ChannelSlot, return_: Return) {
+    #[cfg(feature = "consume")]
+    let return_ = match slot.parked.hold_return(return_) {
+        Some(return_) => return_,
+        None => return,
+    };
+    hold_or_send_unparked_return(slot, return_);
+}
+
+// Returns are held until the following ack for `Channel::publish_confirmed` callers and the
+// publish result listener, and only go to the return listener if neither wants them.
+fn hold_or_send_unparked_return(slot: &mut ChannelSlot, return_: Return) {
+    slot.publish_results.hold_return(&return_);
+    if let Some(return_) = slot.confirm_waiters.hold_return(return_) {
+        if !slot.publish_results.is_listening() {
+            try_send_return(slot, return_);
+        }
+    }
+}
+
+fn settle_confirm(inner: &mut Inner, n: u16, confirm: Confirm) -> Result<()> {
+    let slot = slot_get_mut(inner, n)?;
+    if let Some(unconfirmed) = &mut slot.unconfirmed {
+        let payload = match &confirm {
+            Confirm::Ack(payload) | Confirm::Nack(payload) => payload,
+        };
+        unconfirmed.settle(payload.delivery_tag, payload.multiple);
+    }
+    #[cfg(feature = "consume")]
+    let confirm = {
+        let settled_through = slot
+            .unconfirmed
+            .as_ref()
+            .map_or(0, UnconfirmedPublishes::settled_through);
+        let settled = slot.parked.settle(confirm, settled_through);
+        if let Some(return_) = settled.return_ {
+            hold_or_send_unparked_return(slot, return_);
+        }
+        settle_parked_originals(inner, n, settled.originals)?;
+        match settled.confirm {
+            Some(confirm) => confirm,
+            // It only settled deliveries we republished.
+            None => return Ok(()),
         }
-    } else {
-        return_
     };
-    warn!("discarding returned data {:?}", return_);
+    let slot = slot_get_mut(inner, n)?;
+    slot.publish_results.settle(&confirm);
+    if let Some(return_) = slot.confirm_waiters.settle(&confirm) {
+        if !slot.publish_results.is_listening() {
+            try_send_return(slot, return_);
+        }
+    }
+    try_send_confirm(slot, confirm);
+    Ok(())
+}
+
+// Ack the originals of deliveries whose republish to a parking lot the server has accepted, and
+// nack (requeueing) the rest.
+#[cfg(feature = "consume")]
+fn settle_parked_originals(inner: &mut Inner, n: u16, originals: Vec<Original>) -> Result<()> {
+    if originals.is_empty() {
+        return Ok(());
+    }
+    let writes_sealed = inner.are_writes_sealed();
+    let slot = slot_get_mut(inner, n)?;
+    if slot.closing || writes_sealed {
+        // The server will requeue them when the channel closes.
+        return Ok(());
+    }
+    let mut buf = OutputBuffer::empty();
+    for original in originals {
+        let (delivery_tag, method) = match original {
+            Original::Ack(delivery_tag) => (
+                delivery_tag,
+                AmqpBasic::Ack(Ack {
+                    delivery_tag,
+                    multiple: false,
+                }),
+            ),
+            Original::Nack(delivery_tag) => {
+                warn!(
+                    "server did not accept parked delivery {} on channel {}; requeueing it",
+                    delivery_tag, n
+                );
+                (
+                    delivery_tag,
+                    AmqpBasic::Nack(Nack {
+                        delivery_tag,
+                        multiple: false,
+                        requeue: true,
+                    }),
+                )
+            }
+        };
+        // A `multiple` ack or nack of a later delivery may have settled it already.
+        if !slot.unacked.contains(delivery_tag) {
+            debug!(
+                "parked delivery {} on channel {} was already settled",
+                delivery_tag, n
+            );
+            continue;
+        }
+        slot.unacked.settle(delivery_tag, false);
+        buf.push_method(n, method);
+    }
+    inner.append(n, buf);
+    Ok(())
 }
 
 // When we set up a pub confirm listener, it's just a crossbeam channel. If it gets dropped,
@@ -118,6 +256,296 @@ fn try_send_blocked(slot: &mut Channel0Slot, note: ConnectionBlockedNotification
     }
 }
 
+// Hand off content the collector has finished assembling on channel `n`.
+fn dispatch_content(inner: &mut Inner, n: u16, collected: CollectorResult) -> Result<()> {
+    match collected {
+        #[cfg(feature = "consume")]
+        CollectorResult::Delivery((consumer_tag, delivery)) => {
+            if let Some(delivery) = park_if_redelivered(inner, n, &consumer_tag, delivery)? {
+                dispatch_delivery(inner, n, consumer_tag, ConsumerMessage::Delivery(delivery))?;
+            }
+        }
+        CollectorResult::Return(return_) => {
+            let slot = slot_get_mut(inner, n)?;
+            slot.counters.record_return();
+            hold_or_send_return(slot, return_);
+        }
+        #[cfg(feature = "consume")]
+        CollectorResult::Get(get) => {
+            let slot = slot_get(inner, n)?;
+            send(&slot.tx, Ok(ChannelMessage::GetOk(Box::new(Some(get)))))?;
+        }
+    }
+    Ok(())
+}
+
+// If the consumer's `RedeliveryPolicy` says `delivery` has been delivered too many times,
+// republish it to the parking lot, leaving it unacked until the server confirms the republish
+// (see `ParkedPublishes`). Returns the delivery if it should go to the consumer instead.
+#[cfg(feature = "consume")]
+fn park_if_redelivered(
+    inner: &mut Inner,
+    n: u16,
+    consumer_tag: &str,
+    delivery: Delivery,
+) -> Result<Option<Delivery>> {
+    let writes_sealed = inner.are_writes_sealed();
+    let body_frame_max = inner.body_frame_max;
+    let slot = slot_get_mut(inner, n)?;
+    let policy = match slot
+        .consumers
+        .get(consumer_tag)
+        .and_then(|consumer| consumer.redelivery_policy.as_ref())
+    {
+        Some(policy) if policy.applies_to(&delivery) => policy,
+        _ => return Ok(Some(delivery)),
+    };
+    let delivery_tag = delivery.delivery_tag();
+    // `Channel` selects confirm mode before starting a consumer with a redelivery policy.
+    let unconfirmed = match &mut slot.unconfirmed {
+        Some(unconfirmed) => unconfirmed,
+        None => {
+            warn!(
+                "not parking delivery {} on channel {}, which is not in confirm mode",
+                delivery_tag, n
+            );
+            return Ok(Some(delivery));
+        }
+    };
+    if slot.closing || writes_sealed {
+        // We can't send anything; the server will requeue the delivery when the channel closes.
+        debug!(
+            "discarding delivery {} to be parked on closing channel {}",
+            delivery_tag, n
+        );
+        return Ok(None);
+    }
+    debug!(
+        "parking delivery {} on channel {} in exchange {:?} with routing key {:?}",
+        delivery_tag,
+        n,
+        policy.exchange(),
+        policy.routing_key()
+    );
+    let publish = policy.park(&delivery);
+    let mut buf = OutputBuffer::empty();
+    push_method_with_content(
+        &mut buf,
+        n,
+        AmqpBasic::Publish(AmqpPublish {
+            ticket: 0,
+            exchange: policy.exchange().to_string(),
+            routing_key: publish.routing_key,
+            mandatory: true,
+            immediate: false,
+        }),
+        AmqpPublish::get_class_id(),
+        &publish.body,
+        &publish.properties,
+        body_frame_max,
+    );
+    slot.parked.park(unconfirmed.next_seq_no(), delivery_tag);
+    unconfirmed.record_publishes(1);
+    inner.append(n, buf);
+    Ok(None)
+}
+
+#[cfg(feature = "consume")]
+fn dispatch_delivery(
+    inner: &mut Inner,
+    n: u16,
+    consumer_tag: String,
+    message: ConsumerMessage,
+) -> Result<()> {
+    // Once we've sent (or received) a connection close, nothing will make room in a full buffer
+    // that matters; don't stop reading the close-ok.
+    let closing = inner.are_writes_sealed();
+    let slot = slot_get_mut(inner, n)?;
+    let consumer = slot
+        .consumers
+        .get_mut(&consumer_tag)
+        .context(UnknownConsumerTagSnafu {
+            channel_id: n,
+            consumer_tag: consumer_tag.clone(),
+        })?;
+    // Held deliveries go first; see `Consumer::pause`.
+    if consumer.paused || !consumer.held.is_empty() {
+        consumer.held.push_back(message);
+        return Ok(());
+    }
+    if !consumer.is_full() {
+        send_consumer(&consumer.tx, message);
+        return Ok(());
+    }
+    match consumer.overflow_policy {
+        OverflowPolicy::Block if closing => {
+            debug!(
+                "discarding delivery for full consumer {} on channel {} while closing",
+                consumer_tag, n
+            );
+        }
+        OverflowPolicy::Block => {
+            debug!(
+                "consumer {} on channel {} is full; pausing reads",
+                consumer_tag, n
+            );
+            inner.stalled_delivery = Some(StalledDelivery {
+                channel_id: n,
+                consumer_tag,
+                message,
+            });
+        }
+        OverflowPolicy::Error => close_overflowed_channel(inner, n, consumer_tag),
+    }
+    Ok(())
+}
+
+// A consumer that was paused may still hold deliveries when it ends. Hand over the ones that fit
+// ahead of its final message, and return the rest to the server to be redelivered (unless the
+// server considers them settled already).
+#[cfg(feature = "consume")]
+fn release_held(inner: &mut Inner, n: u16, consumer: &mut ConsumerSlot) {
+    for message in consumer.release_held() {
+        let delivery_tag = match &message {
+            ConsumerMessage::Delivery(delivery) => delivery.delivery_tag(),
+            ConsumerMessage::DeliveryStream(stream) => stream.delivery_tag(),
+            _ => continue,
+        };
+        if consumer.no_ack {
+            warn!(
+                "discarding held delivery {} on channel {} for ended no_ack consumer",
+                delivery_tag, n
+            );
+            continue;
+        }
+        debug!(
+            "requeueing held delivery {} on channel {} for ended consumer",
+            delivery_tag, n
+        );
+        inner.push_method(
+            n,
+            AmqpBasic::Nack(Nack {
+                delivery_tag,
+                multiple: false,
+                requeue: true,
+            }),
+        );
+        if let Some(slot) = inner.chan_slots.get_mut(n) {
+            slot.unacked.settle(delivery_tag, false);
+        }
+    }
+}
+
+// If `header` belongs to a delivery for a consumer that streams bodies larger than this one,
+// start streaming it.
+#[cfg(feature = "consume")]
+fn stream_delivery(
+    inner: &mut Inner,
+    n: u16,
+    header: &AMQPContentHeader,
+) -> Result<Option<(String, DeliveryStream)>> {
+    let slot = slot_get_mut(inner, n)?;
+    let stream_above = slot
+        .collector
+        .pending_delivery_consumer_tag()
+        .and_then(|consumer_tag| slot.consumers.get(consumer_tag))
+        .and_then(|consumer| consumer.stream_above);
+    match stream_above {
+        Some(threshold) if header.body_size > threshold => {
+            slot.collector.stream_delivery(header.clone()).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+// Close channel `n` because a consumer using `OverflowPolicy::Error` overflowed its buffer.
+#[cfg(feature = "consume")]
+fn close_overflowed_channel(inner: &mut Inner, n: u16, consumer_tag: String) {
+    error!(
+        "consumer {} on channel {} overflowed its buffer - closing channel",
+        consumer_tag, n
+    );
+    let close = ChannelClose {
+        reply_code: AmqpReplyCode::PreconditionFailed.code(),
+        reply_text: format!(
+            "PRECONDITION_FAILED - consumer {} overflowed its buffer",
+            consumer_tag
+        ),
+        class_id: 60,
+        method_id: 60,
+    };
+    start_closing_channel(inner, n, close, || Error::ConsumerBufferOverflow {
+        channel_id: n,
+        consumer_tag: consumer_tag.clone(),
+    });
+}
+
+// Close channel `n` because its handle was dropped without closing it.
+pub(super) fn close_dropped_channel(inner: &mut Inner, n: u16) {
+    debug!("channel {} dropped without being closed - closing it", n);
+    let close = ChannelClose {
+        reply_code: AmqpReplyCode::ReplySuccess.code(),
+        reply_text: "channel handle dropped".to_string(),
+        class_id: 0,
+        method_id: 0,
+    };
+    start_closing_channel(inner, n, close, || Error::ClientClosedChannel);
+}
+
+// Send `close` on channel `n` on behalf of a handle that cannot (or can no longer) wait for the
+// reply. The slot stays until the server acknowledges the close (so its ID is not reused before
+// then), but everyone waiting on it is told now.
+fn start_closing_channel<F: Fn() -> Error>(
+    inner: &mut Inner,
+    n: u16,
+    close: ChannelClose,
+    make_err: F,
+) {
+    inner.push_method(n, AmqpChannel::Close(close));
+
+    // unwrap is safe; our callers only close channels that have a slot.
+    let slot = inner.chan_slots.get_mut(n).unwrap();
+    let _ = slot.tx.try_send(Err(make_err()));
+    // Replace the handle's reply channel with a disconnected one: once it has seen the error
+    // above, any further call on the channel fails instead of waiting for a reply that will
+    // never come.
+    slot.tx = crossbeam_channel::bounded(0).0;
+    slot.closing = true;
+    slot.confirm_waiters.fail_all(make_err);
+    #[cfg(feature = "consume")]
+    {
+        for (_, consumer) in slot.consumers.drain() {
+            send_consumer(&consumer.tx, ConsumerMessage::ClientClosedChannel);
+        }
+        slot.consumers_changed();
+    }
+}
+
+// After closing a channel for a handle (see `start_closing_channel`), we must discard everything
+// the server sends on it other than its close-ok (or a close of its own, if it crossed ours).
+// Returns false if `frame` is not on such a channel.
+fn discard_on_closing_channel(inner: &mut Inner, frame: &AMQPFrame) -> bool {
+    let n = match frame {
+        AMQPFrame::Method(n, _) | AMQPFrame::Header(n, _, _) | AMQPFrame::Body(n, _) => *n,
+        AMQPFrame::ProtocolHeader | AMQPFrame::Heartbeat(_) => return false,
+    };
+    match inner.chan_slots.get(n) {
+        Some(slot) if slot.closing => (),
+        _ => return false,
+    }
+    match frame {
+        AMQPFrame::Method(_, AMQPClass::Channel(AmqpChannel::Close(_))) => {
+            inner.chan_slots.remove(n);
+            inner.push_method(n, AmqpChannel::CloseOk(ChannelCloseOk {}));
+        }
+        AMQPFrame::Method(_, AMQPClass::Channel(AmqpChannel::CloseOk(_))) => {
+            inner.chan_slots.remove(n);
+        }
+        frame => trace!("discarding frame for closing channel {}: {:?}", n, frame),
+    }
+    true
+}
+
 impl ConnectionState {
     fn client_exception(
         &mut self,
@@ -148,6 +576,10 @@ impl ConnectionState {
             }
         };
 
+        if discard_on_closing_channel(inner, &frame) {
+            return Ok(());
+        }
+
         match frame {
             // Server-sent heartbeat
             AMQPFrame::Heartbeat(0) => {
@@ -164,16 +596,26 @@ impl ConnectionState {
                 inner.seal_writes();
                 let reply_code = close.reply_code;
                 let message = close.reply_text.clone();
+                let method = method_name(close.class_id, close.method_id);
                 let make_err = || Error::ServerClosedConnection {
                     code: reply_code,
                     message: message.clone(),
+                    reply_code: AmqpReplyCode::from_code(reply_code),
+                    method,
                 };
                 *self = ConnectionState::ServerClosing(close);
 
+                // Channels whose handles are already gone have nothing to notify; ignore
+                // send failures so the rest of the channels still get their errors.
                 for (_, mut slot) in inner.chan_slots.drain() {
-                    send(&slot.tx, Err(make_err()))?;
-                    for (_, tx) in slot.consumers.drain() {
-                        send(&tx, ConsumerMessage::ServerClosedConnection(make_err()))?;
+                    let _ = slot.tx.try_send(Err(make_err()));
+                    slot.confirm_waiters.fail_all(make_err);
+                    #[cfg(feature = "consume")]
+                    for (_, consumer) in slot.consumers {
+                        send_consumer(
+                            &consumer.tx,
+                            ConsumerMessage::ServerClosedConnection(make_err()),
+                        );
                     }
                 }
             }
@@ -188,22 +630,28 @@ impl ConnectionState {
                     .map_err(|_| Error::EventLoopClientDropped)?;
                 *self = ConnectionState::ClientClosed;
 
+                // See the comment on server-initiated close above.
                 for (_, mut slot) in inner.chan_slots.drain() {
-                    send(&slot.tx, Err(Error::ClientClosedConnection))?;
-                    for (_, tx) in slot.consumers.drain() {
-                        send(&tx, ConsumerMessage::ClientClosedConnection)?;
+                    let _ = slot.tx.try_send(Err(Error::ClientClosedConnection));
+                    slot.confirm_waiters
+                        .fail_all(|| Error::ClientClosedConnection);
+                    #[cfg(feature = "consume")]
+                    for (_, consumer) in slot.consumers {
+                        send_consumer(&consumer.tx, ConsumerMessage::ClientClosedConnection);
                     }
                 }
             }
             // Server is blocking publishes due to an alarm on its side (e.g., low mem)
             AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Blocked(blocked))) => {
                 warn!("server has blocked connection; reason = {}", blocked.reason);
+                ch0_slot.blocked = Some(blocked.reason.clone());
                 let note = ConnectionBlockedNotification::Blocked(blocked.reason);
                 try_send_blocked(ch0_slot, note);
             }
             // Server has unblocked publishes
             AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Unblocked(_))) => {
                 warn!("server has unblocked connection");
+                ch0_slot.blocked = None;
                 let note = ConnectionBlockedNotification::Unblocked;
                 try_send_blocked(ch0_slot, note);
             }
@@ -225,10 +673,18 @@ impl ConnectionState {
                     channel_id: n,
                     code: close.reply_code,
                     message: close.reply_text.clone(),
+                    reply_code: AmqpReplyCode::from_code(close.reply_code),
+                    method: method_name(close.class_id, close.method_id),
                 };
-                send(&slot.tx, Err(make_err()))?;
-                for (_, tx) in slot.consumers.drain() {
-                    send(&tx, ConsumerMessage::ServerClosedChannel(make_err()))?;
+                // The handle may have been dropped before we saw its request to close.
+                let _ = slot.tx.try_send(Err(make_err()));
+                slot.confirm_waiters.fail_all(make_err);
+                #[cfg(feature = "consume")]
+                for (_, consumer) in slot.consumers {
+                    send_consumer(
+                        &consumer.tx,
+                        ConsumerMessage::ServerClosedChannel(make_err()),
+                    );
                 }
                 inner.push_method(n, AmqpChannel::CloseOk(ChannelCloseOk {}));
             }
@@ -239,22 +695,31 @@ impl ConnectionState {
                 // we will have removed the slot when we got the close. It is therefore not
                 // an error to get a CloseOk for a nonexistent slot, since the server is
                 // confirming that a channel is gone (and we don't have it anymore anyway).
-                if let Ok(mut slot) = slot_remove(inner, n) {
+                if let Ok(slot) = slot_remove(inner, n) {
+                    // Stop listing the channel (see `Connection::channels`) before its handle
+                    // hears that it is closed.
+                    drop(slot.counters);
                     send(
                         &slot.tx,
                         Ok(ChannelMessage::Method(AMQPClass::Channel(
                             AmqpChannel::CloseOk(close_ok),
                         ))),
                     )?;
-                    for (_, tx) in slot.consumers.drain() {
-                        send(&tx, ConsumerMessage::ClientClosedChannel)?;
+                    #[cfg(feature = "consume")]
+                    for (_, consumer) in slot.consumers {
+                        send_consumer(&consumer.tx, ConsumerMessage::ClientClosedChannel);
                     }
                 }
             }
             // Server ack for consume request.
+            #[cfg(feature = "consume")]
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::ConsumeOk(consume_ok))) => {
                 let consumer_tag = consume_ok.consumer_tag;
                 let slot = slot_get_mut(inner, n)?;
+                let buffer = slot
+                    .pending_consumers
+                    .pop_front()
+                    .context(FrameUnexpectedSnafu)?;
                 match slot.consumers.entry(consumer_tag.clone()) {
                     Entry::Occupied(_) => {
                         return DuplicateConsumerTagSnafu {
@@ -264,40 +729,70 @@ impl ConnectionState {
                         .fail();
                     }
                     Entry::Vacant(entry) => {
-                        let (tx, rx) = crossbeam_channel::unbounded();
-                        entry.insert(tx);
+                        let (consumer, rx) = ConsumerSlot::new(buffer);
+                        entry.insert(consumer);
+                        slot.consumers_changed();
                         send(&slot.tx, Ok(ChannelMessage::ConsumeOk(consumer_tag, rx)))?;
                     }
                 }
             }
             // Server-initiated consumer cancel.
+            #[cfg(feature = "consume")]
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::Cancel(cancel))) => {
                 let consumer_tag = cancel.consumer_tag;
                 let slot = slot_get_mut(inner, n)?;
-                if let Some(tx) = slot.consumers.remove(&consumer_tag) {
-                    send(&tx, ConsumerMessage::ServerCancelled)?;
+                let consumer = slot.consumers.remove(&consumer_tag);
+                slot.consumers_changed();
+                // Dropping `tx` closes the consumer's receiver after this message.
+                if let Some(mut consumer) = consumer {
+                    release_held(inner, n, &mut consumer);
+                    send_consumer(
+                        &consumer.tx,
+                        ConsumerMessage::ServerCancelled(consumer_tag.clone()),
+                    );
                 }
                 if !cancel.nowait {
                     inner.push_method(n, AmqpBasic::CancelOk(CancelOk { consumer_tag }));
                 }
             }
             // Server ack for client-initiated consumer cancel.
+            #[cfg(feature = "consume")]
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::CancelOk(cancel_ok))) => {
+                // Nobody is waiting for the cancel-ok of a consumer we cancelled for a graceful
+                // shutdown, or because its callback panicked.
+                let key = (n, cancel_ok.consumer_tag.clone());
+                let for_shutdown = inner.shutdown_cancels.remove(&key);
+                let for_panic = inner.panicked_cancels.remove(&key);
                 let slot = slot_get_mut(inner, n)?;
                 let consumer = slot.consumers.remove(&cancel_ok.consumer_tag);
-                send(
-                    &slot.tx,
-                    Ok(ChannelMessage::Method(AMQPClass::Basic(
-                        AmqpBasic::CancelOk(cancel_ok),
-                    ))),
-                )?;
-                if let Some(tx) = consumer {
-                    send(&tx, ConsumerMessage::ClientCancelled)?;
+                slot.consumers_changed();
+                // Finish the consumer before replying, so by the time `basic_cancel` returns its
+                // receiver holds every delivery that preceded cancel-ok and the terminal message.
+                if let Some(mut consumer) = consumer {
+                    release_held(inner, n, &mut consumer);
+                    send_consumer(&consumer.tx, ConsumerMessage::ClientCancelled);
+                }
+                let slot = slot_get(inner, n)?;
+                if !for_shutdown && !for_panic {
+                    send(
+                        &slot.tx,
+                        Ok(ChannelMessage::Method(AMQPClass::Basic(
+                            AmqpBasic::CancelOk(cancel_ok),
+                        ))),
+                    )?;
                 }
             }
             // Server beginning delivery of content to a consumer.
+            #[cfg(feature = "consume")]
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::Deliver(deliver))) => {
                 let slot = slot_get_mut(inner, n)?;
+                if slot
+                    .consumers
+                    .get(&deliver.consumer_tag)
+                    .is_some_and(|consumer| !consumer.no_ack)
+                {
+                    slot.unacked.record(deliver.delivery_tag);
+                }
                 slot.collector.collect_deliver(deliver)?;
             }
             // Server beginning return of undeliverable content.
@@ -306,38 +801,58 @@ impl ConnectionState {
                 slot.collector.collect_return(return_)?;
             }
             // Server ack for get (message incoming).
+            #[cfg(feature = "consume")]
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::GetOk(get_ok))) => {
                 let slot = slot_get_mut(inner, n)?;
                 slot.collector.collect_get(get_ok)?;
             }
             // Server ack for get (no message).
+            #[cfg(feature = "consume")]
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::GetEmpty(_))) => {
                 let slot = slot_get(inner, n)?;
                 send(&slot.tx, Ok(ChannelMessage::GetOk(Box::new(None))))?;
             }
+            // Without the consume feature we never start consumers or gets, so a delivery can
+            // only be for a consumer tag we do not know about.
+            #[cfg(not(feature = "consume"))]
+            AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::Deliver(deliver))) => {
+                return UnknownConsumerTagSnafu {
+                    channel_id: n,
+                    consumer_tag: deliver.consumer_tag,
+                }
+                .fail();
+            }
+            // ... and any other consumer or get reply is unsolicited.
+            #[cfg(not(feature = "consume"))]
+            AMQPFrame::Method(_, AMQPClass::Basic(AmqpBasic::ConsumeOk(_)))
+            | AMQPFrame::Method(_, AMQPClass::Basic(AmqpBasic::Cancel(_)))
+            | AMQPFrame::Method(_, AMQPClass::Basic(AmqpBasic::CancelOk(_)))
+            | AMQPFrame::Method(_, AMQPClass::Basic(AmqpBasic::GetOk(_)))
+            | AMQPFrame::Method(_, AMQPClass::Basic(AmqpBasic::GetEmpty(_))) => {
+                return FrameUnexpectedSnafu.fail();
+            }
             // Server ack for publish (publisher confirmation)
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::Ack(ack))) => {
-                let slot = slot_get_mut(inner, n)?;
                 let confirm = ConfirmPayload {
                     delivery_tag: ack.delivery_tag,
                     multiple: ack.multiple,
                 };
-                try_send_confirm(slot, Confirm::Ack(confirm));
+                let confirm = Confirm::Ack(confirm);
+                settle_confirm(inner, n, confirm)?;
             }
             // Server nack for publish (publisher confirmation)
             AMQPFrame::Method(n, AMQPClass::Basic(AmqpBasic::Nack(nack))) => {
-                let slot = slot_get_mut(inner, n)?;
                 let confirm = ConfirmPayload {
                     delivery_tag: nack.delivery_tag,
                     multiple: nack.multiple,
                 };
-                try_send_confirm(slot, Confirm::Nack(confirm));
+                let confirm = Confirm::Nack(confirm);
+                settle_confirm(inner, n, confirm)?;
             }
             // Generic ack messages we send back to the caller.
             AMQPFrame::Method(n, method @ AMQPClass::Basic(AmqpBasic::QosOk(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Basic(AmqpBasic::RecoverOk(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Channel(AmqpChannel::OpenOk(_)))
-            | AMQPFrame::Method(n, method @ AMQPClass::Confirm(AmqpConfirm::SelectOk(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Exchange(AmqpExchange::DeclareOk(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Exchange(AmqpExchange::DeleteOk(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Exchange(AmqpExchange::BindOk(_)))
@@ -346,7 +861,9 @@ impl ConnectionState {
             | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::DeleteOk(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::BindOk(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::PurgeOk(_)))
-            | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::UnbindOk(_))) => {
+            | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::UnbindOk(_)))
+            | AMQPFrame::Method(n, method @ AMQPClass::Tx(AmqpTx::CommitOk(_)))
+            | AMQPFrame::Method(n, method @ AMQPClass::Tx(AmqpTx::RollbackOk(_))) => {
                 let slot = slot_get(inner, n)?;
                 trace!(
                     "trying to send method to client for channel {}: {:?}",
@@ -355,11 +872,24 @@ impl ConnectionState {
                 );
                 send(&slot.tx, Ok(ChannelMessage::Method(method)))?;
             }
+            // Server ack for confirm select; we count publishes from here on (see
+            // `Connection::shutdown`).
+            AMQPFrame::Method(n, method @ AMQPClass::Confirm(AmqpConfirm::SelectOk(_))) => {
+                let slot = slot_get_mut(inner, n)?;
+                slot.unconfirmed
+                    .get_or_insert_with(UnconfirmedPublishes::default);
+                slot.counters.set_confirm_mode();
+                send(&slot.tx, Ok(ChannelMessage::Method(method)))?;
+            }
+            AMQPFrame::Method(n, method @ AMQPClass::Tx(AmqpTx::SelectOk(_))) => {
+                let slot = slot_get(inner, n)?;
+                slot.counters.set_tx_mode();
+                send(&slot.tx, Ok(ChannelMessage::Method(method)))?;
+            }
             // Methods we do not handle
             AMQPFrame::Method(n, method @ AMQPClass::Access(_))
             | AMQPFrame::Method(n, method @ AMQPClass::Channel(AmqpChannel::Flow(_)))
-            | AMQPFrame::Method(n, method @ AMQPClass::Channel(AmqpChannel::FlowOk(_)))
-            | AMQPFrame::Method(n, method @ AMQPClass::Tx(_)) => {
+            | AMQPFrame::Method(n, method @ AMQPClass::Channel(AmqpChannel::FlowOk(_))) => {
                 let text = format!(
                     "do not know how to handle channel {} method {:?}",
                     n, method
@@ -385,56 +915,32 @@ impl ConnectionState {
             | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::Delete(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::Bind(_)))
             | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::Purge(_)))
-            | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::Unbind(_))) => {
+            | AMQPFrame::Method(n, method @ AMQPClass::Queue(AmqpQueue::Unbind(_)))
+            | AMQPFrame::Method(n, method @ AMQPClass::Tx(AmqpTx::Select(_)))
+            | AMQPFrame::Method(n, method @ AMQPClass::Tx(AmqpTx::Commit(_)))
+            | AMQPFrame::Method(n, method @ AMQPClass::Tx(AmqpTx::Rollback(_))) => {
                 let text = format!("illegal channel {} method {:?}", n, method);
                 self.client_exception(inner, AMQPHardError::NOTALLOWED, text)?;
             }
             // Server sending content header as part of a deliver.
             AMQPFrame::Header(n, _, header) => {
+                #[cfg(feature = "consume")]
+                {
+                    if let Some((consumer_tag, stream)) = stream_delivery(inner, n, &header)? {
+                        let message = ConsumerMessage::DeliveryStream(stream);
+                        return dispatch_delivery(inner, n, consumer_tag, message);
+                    }
+                }
                 let slot = slot_get_mut(inner, n)?;
                 if let Some(collected) = slot.collector.collect_header(*header)? {
-                    match collected {
-                        CollectorResult::Delivery((consumer_tag, delivery)) => {
-                            let tx =
-                                slot.consumers
-                                    .get(&consumer_tag)
-                                    .context(UnknownConsumerTagSnafu {
-                                        channel_id: n,
-                                        consumer_tag,
-                                    })?;
-                            send(tx, ConsumerMessage::Delivery(delivery))?;
-                        }
-                        CollectorResult::Return(return_) => {
-                            try_send_return(slot, return_);
-                        }
-                        CollectorResult::Get(get) => {
-                            send(&slot.tx, Ok(ChannelMessage::GetOk(Box::new(Some(get)))))?;
-                        }
-                    }
+                    dispatch_content(inner, n, collected)?;
                 }
             }
             // Server sending content body as part of a deliver.
             AMQPFrame::Body(n, body) => {
                 let slot = slot_get_mut(inner, n)?;
                 if let Some(collected) = slot.collector.collect_body(body)? {
-                    match collected {
-                        CollectorResult::Delivery((consumer_tag, delivery)) => {
-                            let tx =
-                                slot.consumers
-                                    .get(&consumer_tag)
-                                    .context(UnknownConsumerTagSnafu {
-                                        channel_id: n,
-                                        consumer_tag,
-                                    })?;
-                            send(tx, ConsumerMessage::Delivery(delivery))?;
-                        }
-                        CollectorResult::Return(return_) => {
-                            try_send_return(slot, return_);
-                        }
-                        CollectorResult::Get(get) => {
-                            send(&slot.tx, Ok(ChannelMessage::GetOk(Box::new(Some(get)))))?;
-                        }
-                    }
+                    dispatch_content(inner, n, collected)?;
                 }
             }
         }
@@ -1,30 +1,59 @@
 use crate::errors::*;
-use crate::{AmqpProperties, Delivery, Get, Return};
+use crate::{AmqpProperties, Return};
 use amq_protocol::frame::AMQPContentHeader;
-use amq_protocol::protocol::basic::Deliver;
-use amq_protocol::protocol::basic::GetOk as AmqpGetOk;
 use amq_protocol::protocol::basic::Return as AmqpReturn;
 use std::cmp::Ordering;
 
+#[cfg(feature = "consume")]
+use crate::tag::ChannelEpoch;
+#[cfg(feature = "consume")]
+use crate::{Delivery, DeliveryStream, Get};
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::Deliver;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::GetOk as AmqpGetOk;
+#[cfg(feature = "consume")]
+use crossbeam_channel::Sender as CrossbeamSender;
+
 pub(super) struct ContentCollector {
-    channel_id: u16,
+    origin: Origin,
     kind: Option<Kind>,
 }
 
+// The channel incarnation content is being collected for, passed along to whatever is built from
+// the content. Returned messages do not need it.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(feature = "consume"), allow(dead_code))]
+struct Origin {
+    channel_id: u16,
+    #[cfg(feature = "consume")]
+    epoch: ChannelEpoch,
+}
+
 pub(super) enum CollectorResult {
+    #[cfg(feature = "consume")]
     Delivery((String, Delivery)),
     Return(Return),
+    #[cfg(feature = "consume")]
     Get(Get),
 }
 
 impl ContentCollector {
-    pub(super) fn new(channel_id: u16) -> ContentCollector {
+    pub(super) fn new(
+        channel_id: u16,
+        #[cfg(feature = "consume")] epoch: ChannelEpoch,
+    ) -> ContentCollector {
         ContentCollector {
-            channel_id,
+            origin: Origin {
+                channel_id,
+                #[cfg(feature = "consume")]
+                epoch,
+            },
             kind: None,
         }
     }
 
+    #[cfg(feature = "consume")]
     pub(super) fn collect_deliver(&mut self, deliver: Deliver) -> Result<()> {
         match self.kind.take() {
             None => {
@@ -45,6 +74,7 @@ impl ContentCollector {
         }
     }
 
+    #[cfg(feature = "consume")]
     pub(super) fn collect_get(&mut self, get_ok: AmqpGetOk) -> Result<()> {
         match self.kind.take() {
             None => {
@@ -55,12 +85,51 @@ impl ContentCollector {
         }
     }
 
+    // The consumer tag of a delivery whose content header has not arrived yet.
+    #[cfg(feature = "consume")]
+    pub(super) fn pending_delivery_consumer_tag(&self) -> Option<&str> {
+        match &self.kind {
+            Some(Kind::Delivery(State::Start(deliver))) => Some(&deliver.consumer_tag),
+            _ => None,
+        }
+    }
+
+    // Like `collect_header` for a pending delivery, but hand the body to a `DeliveryStream` as it
+    // arrives instead of collecting it.
+    #[cfg(feature = "consume")]
+    pub(super) fn stream_delivery(
+        &mut self,
+        header: AMQPContentHeader,
+    ) -> Result<(String, DeliveryStream)> {
+        match self.kind.take() {
+            Some(Kind::Delivery(State::Start(deliver))) => {
+                let (consumer_tag, delivery) = Delivery::new(
+                    self.origin.channel_id,
+                    self.origin.epoch,
+                    deliver,
+                    Vec::new(),
+                    header.properties,
+                );
+                // Unbounded, so the I/O thread never waits on a slow reader: blocking here (or
+                // pausing socket reads) would stall heartbeats and every other channel. The cost
+                // is that frames pile up behind a reader that falls behind, as `DeliveryStream`
+                // documents.
+                let (tx, rx) = crossbeam_channel::unbounded();
+                self.kind = Some(Kind::Stream(tx, header.body_size));
+                let stream = DeliveryStream::new(delivery, header.body_size, rx);
+                Ok((consumer_tag, stream))
+            }
+            _ => FrameUnexpectedSnafu.fail(),
+        }
+    }
+
     pub(super) fn collect_header(
         &mut self,
         header: AMQPContentHeader,
     ) -> Result<Option<CollectorResult>> {
         match self.kind.take() {
-            Some(Kind::Delivery(state)) => match state.collect_header(self.channel_id, header)? {
+            #[cfg(feature = "consume")]
+            Some(Kind::Delivery(state)) => match state.collect_header(self.origin, header)? {
                 Content::Done((tag, delivery)) => {
                     self.kind = None;
                     Ok(Some(CollectorResult::Delivery((tag, delivery))))
@@ -70,7 +139,7 @@ impl ContentCollector {
                     Ok(None)
                 }
             },
-            Some(Kind::Return(state)) => match state.collect_header(self.channel_id, header)? {
+            Some(Kind::Return(state)) => match state.collect_header(self.origin, header)? {
                 Content::Done(return_) => {
                     self.kind = None;
                     Ok(Some(CollectorResult::Return(return_)))
@@ -80,7 +149,8 @@ impl ContentCollector {
                     Ok(None)
                 }
             },
-            Some(Kind::Get(state)) => match state.collect_header(self.channel_id, header)? {
+            #[cfg(feature = "consume")]
+            Some(Kind::Get(state)) => match state.collect_header(self.origin, header)? {
                 Content::Done(get) => {
                     self.kind = None;
                     Ok(Some(CollectorResult::Get(get)))
@@ -90,13 +160,16 @@ impl ContentCollector {
                     Ok(None)
                 }
             },
+            #[cfg(feature = "consume")]
+            Some(Kind::Stream(..)) => FrameUnexpectedSnafu.fail(),
             None => FrameUnexpectedSnafu.fail(),
         }
     }
 
     pub(super) fn collect_body(&mut self, body: Vec<u8>) -> Result<Option<CollectorResult>> {
         match self.kind.take() {
-            Some(Kind::Delivery(state)) => match state.collect_body(self.channel_id, body)? {
+            #[cfg(feature = "consume")]
+            Some(Kind::Delivery(state)) => match state.collect_body(self.origin, body)? {
                 Content::Done((tag, delivery)) => {
                     self.kind = None;
                     Ok(Some(CollectorResult::Delivery((tag, delivery))))
@@ -106,7 +179,7 @@ impl ContentCollector {
                     Ok(None)
                 }
             },
-            Some(Kind::Return(state)) => match state.collect_body(self.channel_id, body)? {
+            Some(Kind::Return(state)) => match state.collect_body(self.origin, body)? {
                 Content::Done(return_) => {
                     self.kind = None;
                     Ok(Some(CollectorResult::Return(return_)))
@@ -116,7 +189,8 @@ impl ContentCollector {
                     Ok(None)
                 }
             },
-            Some(Kind::Get(state)) => match state.collect_body(self.channel_id, body)? {
+            #[cfg(feature = "consume")]
+            Some(Kind::Get(state)) => match state.collect_body(self.origin, body)? {
                 Content::Done(get) => {
                     self.kind = None;
                     Ok(Some(CollectorResult::Get(get)))
@@ -126,15 +200,33 @@ impl ContentCollector {
                     Ok(None)
                 }
             },
+            #[cfg(feature = "consume")]
+            Some(Kind::Stream(tx, remaining)) => {
+                let len = body.len() as u64;
+                if len > remaining {
+                    return FrameUnexpectedSnafu.fail();
+                }
+                // If the stream was abandoned or dropped, discard the rest of the body.
+                let _ = tx.send(body);
+                if len < remaining {
+                    self.kind = Some(Kind::Stream(tx, remaining - len));
+                }
+                Ok(None)
+            }
             None => FrameUnexpectedSnafu.fail(),
         }
     }
 }
 
 enum Kind {
+    #[cfg(feature = "consume")]
     Delivery(State<Delivery>),
     Return(State<Return>),
+    #[cfg(feature = "consume")]
     Get(State<Get>),
+    // A streamed delivery's body, and how many bytes of it are still to come.
+    #[cfg(feature = "consume")]
+    Stream(CrossbeamSender<Vec<u8>>, u64),
 }
 
 trait ContentType {
@@ -142,24 +234,25 @@ trait ContentType {
     type Finish;
 
     fn new(
-        channel_id: u16,
+        origin: Origin,
         start: Self::Start,
         buf: Vec<u8>,
         properties: AmqpProperties,
     ) -> Self::Finish;
 }
 
+#[cfg(feature = "consume")]
 impl ContentType for Delivery {
     type Start = Deliver;
     type Finish = (String, Delivery);
 
     fn new(
-        channel_id: u16,
+        origin: Origin,
         start: Self::Start,
         buf: Vec<u8>,
         properties: AmqpProperties,
     ) -> Self::Finish {
-        Delivery::new(channel_id, start, buf, properties)
+        Delivery::new(origin.channel_id, origin.epoch, start, buf, properties)
     }
 }
 
@@ -168,7 +261,7 @@ impl ContentType for Return {
     type Finish = Return;
 
     fn new(
-        _channel_id: u16,
+        _origin: Origin,
         start: Self::Start,
         buf: Vec<u8>,
         properties: AmqpProperties,
@@ -177,18 +270,20 @@ impl ContentType for Return {
     }
 }
 
+#[cfg(feature = "consume")]
 impl ContentType for Get {
     type Start = AmqpGetOk;
     type Finish = Get;
 
     fn new(
-        channel_id: u16,
+        origin: Origin,
         get_ok: AmqpGetOk,
         buf: Vec<u8>,
         properties: AmqpProperties,
     ) -> Self::Finish {
         let message_count = get_ok.message_count;
-        let delivery = Delivery::new_get_ok(channel_id, get_ok, buf, properties);
+        let delivery =
+            Delivery::new_get_ok(origin.channel_id, origin.epoch, get_ok, buf, properties);
         Get {
             delivery,
             message_count,
@@ -196,6 +291,7 @@ impl ContentType for Get {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 enum Content<T: ContentType> {
     Done(T::Finish),
     NeedMore(State<T>),
@@ -210,12 +306,12 @@ enum State<T: ContentType> {
 }
 
 impl<T: ContentType> State<T> {
-    fn collect_header(self, channel_id: u16, header: AMQPContentHeader) -> Result<Content<T>> {
+    fn collect_header(self, origin: Origin, header: AMQPContentHeader) -> Result<Content<T>> {
         match self {
             State::Start(start) => {
                 if header.body_size == 0 {
                     Ok(Content::Done(T::new(
-                        channel_id,
+                        origin,
                         start,
                         Vec::new(),
                         header.properties,
@@ -229,7 +325,7 @@ impl<T: ContentType> State<T> {
         }
     }
 
-    fn collect_body(self, channel_id: u16, mut body: Vec<u8>) -> Result<Content<T>> {
+    fn collect_body(self, origin: Origin, mut body: Vec<u8>) -> Result<Content<T>> {
         match self {
             State::Body(start, header, mut buf) => {
                 let body_size = header.body_size as usize;
@@ -237,7 +333,7 @@ impl<T: ContentType> State<T> {
                 match buf.len().cmp(&body_size) {
                     Ordering::Equal => {
                         Ok(Content::Done(T::new(
-                            channel_id,
+                            origin,
                             start,
                             buf,
                             header.properties,
@@ -1,21 +1,26 @@
 use super::Inner;
 use crate::connection_options::ConnectionOptions;
 use crate::errors::*;
+use crate::logging::{debug, warn};
 use crate::serialize::TryFromAmqpFrame;
 use crate::{FieldTable, Sasl};
 use amq_protocol::frame::AMQPFrame;
 use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
-use amq_protocol::protocol::connection::{Close, CloseOk, OpenOk, Secure, Start, Tune, TuneOk};
-use log::{debug, error};
+use amq_protocol::protocol::connection::{
+    Close, CloseOk, OpenOk, Secure, SecureOk, Start, Tune, TuneOk,
+};
+use amq_protocol::protocol::AMQPClass;
 
 #[derive(Debug)]
 pub(super) enum HandshakeState<Auth: Sasl> {
     Start(ConnectionOptions<Auth>),
     Secure(ConnectionOptions<Auth>, FieldTable),
     Tune(ConnectionOptions<Auth>, FieldTable),
-    Open(TuneOk, FieldTable),
+    // The last element of `Open` and `Done` is the reason the server has blocked the connection,
+    // if it sent `connection.blocked` before the handshake finished.
+    Open(TuneOk, FieldTable, Option<String>),
     ServerClosing(Close),
-    Done(TuneOk, FieldTable),
+    Done(TuneOk, FieldTable, Option<String>),
 }
 
 impl<Auth: Sasl> HandshakeState<Auth> {
@@ -38,11 +43,18 @@ impl<Auth: Sasl> HandshakeState<Auth> {
                 *self = HandshakeState::Secure(options.clone(), server_properties);
             }
             HandshakeState::Secure(options, server_properties) => {
-                // We currently only support PLAIN and EXTERNAL, neither of which
-                // need a secure/secure-ok
+                // The server may send any number of challenges before tune.
                 if let Ok(secure) = Secure::try_from(0, frame.clone()) {
-                    error!("received unsupported handshake {:?}", secure);
-                    return SaslSecureNotSupportedSnafu.fail();
+                    debug!("received handshake {:?}", secure);
+                    let response = options
+                        .auth
+                        .respond_to_challenge(secure.challenge.as_bytes())?;
+                    let response = String::from_utf8(response)
+                        .map_err(|_| SaslResponseNotUtf8Snafu.build())?;
+                    // Don't log the response; it may contain credentials.
+                    debug!("sending handshake secure-ok");
+                    inner.push_method(0, AmqpConnection::SecureOk(SecureOk { response }));
+                    return Ok(());
                 }
                 *self = HandshakeState::Tune(options.clone(), server_properties.clone());
                 return self.process(inner, frame);
@@ -50,6 +62,7 @@ impl<Auth: Sasl> HandshakeState<Auth> {
             HandshakeState::Tune(options, server_properties) => {
                 let tune = Tune::try_from(0, frame)?;
                 debug!("received handshake {:?}", tune);
+                options.auth.verify_server()?;
 
                 let tune_ok = options.make_tune_ok(tune)?;
                 inner.start_heartbeats(tune_ok.heartbeat);
@@ -61,9 +74,15 @@ impl<Auth: Sasl> HandshakeState<Auth> {
                 debug!("sending handshake {:?}", open);
                 inner.push_method(0, AmqpConnection::Open(open));
 
-                *self = HandshakeState::Open(tune_ok, server_properties.clone());
+                *self = HandshakeState::Open(tune_ok, server_properties.clone(), None);
             }
-            HandshakeState::Open(tune_ok, server_properties) => {
+            HandshakeState::Open(tune_ok, server_properties, blocked) => {
+                // The server may block us as soon as we've sent open (e.g., if it is already in an
+                // alarm state); that is not a handshake failure.
+                if update_blocked(blocked, &frame) {
+                    return Ok(());
+                }
+
                 // If we sent bad tune params, server might send us a Close.
                 if let Ok(close) = Close::try_from(0, frame.clone()) {
                     inner.push_method(0, AmqpConnection::CloseOk(CloseOk {}));
@@ -75,12 +94,39 @@ impl<Auth: Sasl> HandshakeState<Auth> {
                 let open_ok = OpenOk::try_from(0, frame)?;
                 debug!("received handshake {:?}", open_ok);
 
-                *self = HandshakeState::Done(tune_ok.clone(), server_properties.clone());
+                *self = HandshakeState::Done(
+                    tune_ok.clone(),
+                    server_properties.clone(),
+                    blocked.take(),
+                );
+            }
+            HandshakeState::Done(_, _, blocked) => {
+                // We may read a blocked notification in the same buffer as open-ok.
+                if !update_blocked(blocked, &frame) {
+                    return FrameUnexpectedSnafu.fail();
+                }
             }
-            HandshakeState::ServerClosing(_) | HandshakeState::Done(_, _) => {
+            HandshakeState::ServerClosing(_) => {
                 return FrameUnexpectedSnafu.fail();
             }
         }
         Ok(())
     }
 }
+
+// If `frame` is a blocked or unblocked notification, record it in `blocked` and return true.
+fn update_blocked(blocked: &mut Option<String>, frame: &AMQPFrame) -> bool {
+    match frame {
+        AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Blocked(b))) => {
+            warn!("server has blocked connection; reason = {}", b.reason);
+            *blocked = Some(b.reason.clone());
+            true
+        }
+        AMQPFrame::Method(0, AMQPClass::Connection(AmqpConnection::Unblocked(_))) => {
+            warn!("server has unblocked connection");
+            *blocked = None;
+            true
+        }
+        _ => false,
+    }
+}
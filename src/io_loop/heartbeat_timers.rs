@@ -1,11 +1,37 @@
 use crate::heartbeats::Heartbeat;
-use log::trace;
-use mio_extras::timer::Timer;
-use std::time::Duration;
+use crate::logging::trace;
+use crate::timer::Timer;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub(super) use crate::heartbeats::HeartbeatState;
 
-const MAX_MISSED_SERVER_HEARTBEATS: u32 = 2;
+const MIN_TX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When a connection last exchanged data and heartbeat frames with the server, for diagnosing
+/// missed heartbeats. Returned by
+/// [`Connection::heartbeat_diagnostics`](struct.Connection.html#method.heartbeat_diagnostics).
+///
+/// Each field is `None` until the first time it happens. Activity is recorded whether or not
+/// heartbeats were negotiated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeartbeatDiagnostics {
+    /// When data was last read from the server.
+    pub last_rx: Option<Instant>,
+
+    /// When data was last written to the server.
+    pub last_tx: Option<Instant>,
+
+    /// When a heartbeat frame was last received from the server.
+    pub last_heartbeat_received: Option<Instant>,
+
+    /// When a heartbeat frame was last queued to be sent to the server. (It is only queued when
+    /// nothing else is waiting to be written.)
+    pub last_heartbeat_sent: Option<Instant>,
+}
+
+// Updated by the I/O thread and read by `Connection::heartbeat_diagnostics`.
+pub(crate) type SharedHeartbeatDiagnostics = Arc<Mutex<HeartbeatDiagnostics>>;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub(super) enum HeartbeatKind {
@@ -19,13 +45,16 @@ struct RxTxHeartbeat {
 }
 
 impl RxTxHeartbeat {
-    fn new(timer: &mut Timer<HeartbeatKind>, interval: Duration) -> RxTxHeartbeat {
-        let rx = Heartbeat::start(
-            HeartbeatKind::Rx,
-            MAX_MISSED_SERVER_HEARTBEATS * interval,
-            timer,
-        );
-        let tx = Heartbeat::start(HeartbeatKind::Tx, interval, timer);
+    fn new(
+        timer: &mut Timer<HeartbeatKind>,
+        interval: Duration,
+        missed_limit: u32,
+    ) -> RxTxHeartbeat {
+        let rx = Heartbeat::start_with_limit(HeartbeatKind::Rx, interval, missed_limit, timer);
+        // Send at twice the negotiated rate so a single late send can't look like a missed
+        // heartbeat to the server.
+        let tx_interval = Duration::max(interval / 2, MIN_TX_INTERVAL);
+        let tx = Heartbeat::start(HeartbeatKind::Tx, tx_interval, timer);
         RxTxHeartbeat { rx, tx }
     }
 }
@@ -34,10 +63,26 @@ impl RxTxHeartbeat {
 pub(super) struct HeartbeatTimers {
     pub(super) timer: Timer<HeartbeatKind>,
     heartbeats: Option<RxTxHeartbeat>,
+    diagnostics: SharedHeartbeatDiagnostics,
 }
 
 impl HeartbeatTimers {
+    fn update_diagnostics<F: FnOnce(&mut HeartbeatDiagnostics)>(&self, f: F) {
+        let mut diagnostics = self
+            .diagnostics
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        f(&mut diagnostics);
+    }
+
     pub(super) fn record_rx_activity(&mut self) {
+        self.update_diagnostics(|d| d.last_rx = Some(Instant::now()));
+        self.defer_rx_deadline();
+    }
+
+    // Restart the rx heartbeat without recording that anything was received, for when the
+    // server's silence is our doing.
+    pub(super) fn defer_rx_deadline(&mut self) {
         if let Some(hb) = &mut self.heartbeats {
             trace!("recording activity for rx heartbeat");
             hb.rx.record_activity();
@@ -45,18 +90,41 @@ impl HeartbeatTimers {
     }
 
     pub(super) fn record_tx_activity(&mut self) {
+        self.update_diagnostics(|d| d.last_tx = Some(Instant::now()));
         if let Some(hb) = &mut self.heartbeats {
             trace!("recording activity for tx heartbeat");
             hb.tx.record_activity();
         }
     }
 
-    pub(super) fn start(&mut self, interval: Duration) {
+    pub(super) fn record_heartbeat_received(&self) {
+        self.update_diagnostics(|d| d.last_heartbeat_received = Some(Instant::now()));
+    }
+
+    pub(super) fn record_heartbeat_sent(&self) {
+        self.update_diagnostics(|d| d.last_heartbeat_sent = Some(Instant::now()));
+    }
+
+    pub(super) fn diagnostics(&self) -> SharedHeartbeatDiagnostics {
+        Arc::clone(&self.diagnostics)
+    }
+
+    // How long the server has been silent, as reported when its heartbeats are missed.
+    pub(super) fn since_last_rx(&self) -> Duration {
+        self.diagnostics
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .last_rx
+            .map_or(Duration::from_secs(0), |last_rx| last_rx.elapsed())
+    }
+
+    // The rx timer expires once the server has been silent for `missed_limit` intervals.
+    pub(super) fn start(&mut self, interval: Duration, missed_limit: u32) {
         assert!(
             self.heartbeats.is_none(),
             "heartbeat timer started multiple times"
         );
-        self.heartbeats = Some(RxTxHeartbeat::new(&mut self.timer, interval));
+        self.heartbeats = Some(RxTxHeartbeat::new(&mut self.timer, interval, missed_limit));
     }
 
     pub(super) fn fire_rx(&mut self) -> HeartbeatState {
@@ -75,3 +143,101 @@ impl HeartbeatTimers {
             .fire(&mut self.timer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    struct Harness {
+        timers: HeartbeatTimers,
+    }
+
+    impl Harness {
+        fn start(interval: Duration, missed_limit: u32) -> Harness {
+            let mut timers = HeartbeatTimers::default();
+            timers.start(interval, missed_limit);
+            Harness { timers }
+        }
+
+        // Wait (up to `timeout`) for the next timer to fire, returning it if it expired (i.e., a
+        // heartbeat would be sent or the server considered dead), as if there were no traffic.
+        fn next_expired(&mut self, timeout: Duration) -> Option<HeartbeatKind> {
+            let until = Instant::now() + timeout;
+            let deadline = self
+                .timers
+                .timer
+                .next_deadline()
+                .map_or(until, |deadline| deadline.min(until));
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            let mut expired = None;
+            while let Some(kind) = self.timers.timer.poll() {
+                let state = match kind {
+                    HeartbeatKind::Rx => self.timers.fire_rx(),
+                    HeartbeatKind::Tx => self.timers.fire_tx(),
+                };
+                if state == HeartbeatState::Expired {
+                    expired = Some(kind);
+                }
+            }
+            expired
+        }
+    }
+
+    fn time_until_rx_expires(interval: Duration, missed_limit: u32) -> Duration {
+        let mut harness = Harness::start(interval, missed_limit);
+        let start = Instant::now();
+        while harness.next_expired(interval * (missed_limit + 1)) != Some(HeartbeatKind::Rx) {}
+        start.elapsed()
+    }
+
+    #[test]
+    fn rx_expires_after_missed_limit_intervals() {
+        let interval = Duration::from_millis(100);
+        let elapsed = time_until_rx_expires(interval, 4);
+        assert!(elapsed >= interval * 4 - Duration::from_millis(10));
+        assert!(elapsed < interval * 5 + interval / 2);
+    }
+
+    #[test]
+    fn diagnostics_record_activity_without_heartbeats() {
+        let mut timers = HeartbeatTimers::default();
+        let diagnostics = timers.diagnostics();
+        assert_eq!(
+            *diagnostics.lock().unwrap(),
+            HeartbeatDiagnostics::default()
+        );
+
+        let before = Instant::now();
+        timers.record_rx_activity();
+        timers.record_heartbeat_received();
+        timers.defer_rx_deadline();
+        let snapshot = *diagnostics.lock().unwrap();
+        assert!(snapshot.last_rx.unwrap() >= before);
+        assert!(snapshot.last_heartbeat_received.unwrap() >= before);
+        assert_eq!(snapshot.last_tx, None);
+        assert_eq!(snapshot.last_heartbeat_sent, None);
+        assert!(timers.since_last_rx() < Duration::from_secs(1));
+
+        timers.record_tx_activity();
+        timers.record_heartbeat_sent();
+        let snapshot = *diagnostics.lock().unwrap();
+        assert!(snapshot.last_tx.unwrap() >= snapshot.last_rx.unwrap());
+        assert!(snapshot.last_heartbeat_sent.is_some());
+    }
+
+    #[test]
+    fn tx_fires_twice_per_interval() {
+        let interval = Duration::from_secs(2);
+        let mut harness = Harness::start(interval, 2);
+        let deadline = Instant::now() + interval + Duration::from_millis(200);
+        let mut sent = 0;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            if harness.next_expired(remaining) == Some(HeartbeatKind::Tx) {
+                sent += 1;
+            }
+        }
+        assert_eq!(sent, 2);
+    }
+}
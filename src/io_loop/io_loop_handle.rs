@@ -1,26 +1,217 @@
-use super::{ChannelMessage, ConnectionBlockedNotification, ConsumerMessage, IoLoopMessage};
+use super::mailbox::{Mailbox, MailboxSender, MailboxSenders};
+use super::waker::SyncSender;
+use super::{
+    ChannelCounters, ChannelMessage, ChannelStats, ConnectionBlockedNotification, IoLoopMessage,
+    PanicSlot, ReturnHandler, ShutdownReport,
+};
+use crate::deadline::Deadline;
 use crate::errors::*;
+use crate::logging::{error, trace};
 use crate::serialize::{IntoAmqpClass, OutputBuffer, TryFromAmqpClass};
-use crate::{AmqpProperties, Confirm, Error, Get, Return};
-use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
-use amq_protocol::protocol::basic::Consume;
-use amq_protocol::protocol::basic::Get as AmqpGet;
+use crate::tag::SeqNo;
+use crate::{AmqpProperties, Confirm, ConfirmOutcome, Error, PublishBody, PublishResult};
+use amq_protocol::protocol::channel::AMQPMethod as AmqpChannel;
+use amq_protocol::protocol::channel::Close as ChannelClose;
+use amq_protocol::protocol::channel::CloseOk as ChannelCloseOk;
 use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
 use amq_protocol::protocol::connection::Close as ConnectionClose;
 use amq_protocol::protocol::connection::CloseOk as ConnectionCloseOk;
 use crossbeam_channel::Receiver as CrossbeamReceiver;
+use crossbeam_channel::RecvTimeoutError;
+use crossbeam_channel::TryRecvError;
 use crossbeam_channel::Sender as CrossbeamSender;
-use log::error;
-use mio_extras::channel::SyncSender as MioSyncSender;
+use crossbeam_channel::TrySendError;
 use std::fmt;
-use std::ops::{Deref, DerefMut};
+use std::ops::{Deref, DerefMut, Range};
 use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "consume")]
+use super::{ConsumerMessage, ConsumerSlot};
+#[cfg(feature = "consume")]
+use crate::consumer::ConsumerBuffer;
+#[cfg(feature = "consume")]
+use crate::tag::ChannelEpoch;
+#[cfg(feature = "consume")]
+use crate::Get;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::{Consume, Get as AmqpGet};
+
+// How often to retry handing a message to a busy I/O thread while a deadline is in effect.
+const SEND_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+// A cloneable, sendable handle that can settle deliveries on a channel from any thread. It has no
+// access to the channel's responses, so it can only be used for methods that do not expect a reply
+// from the server.
+#[cfg(feature = "consume")]
+#[derive(Clone)]
+pub(crate) struct ChannelSender {
+    channel_id: u16,
+    epoch: ChannelEpoch,
+    // The channel's urgent mailbox; see `IoLoopHandle::settle`.
+    urgent_tx: MailboxSender<IoLoopMessage>,
+    panic_slot: PanicSlot,
+}
+
+#[cfg(feature = "consume")]
+impl fmt::Debug for ChannelSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "ChannelSender {{ channel_id: {}, .. }}", self.channel_id)
+    }
+}
+
+#[cfg(feature = "consume")]
+impl ChannelSender {
+    #[inline]
+    pub(crate) fn channel_id(&self) -> u16 {
+        self.channel_id
+    }
+
+    #[inline]
+    pub(crate) fn epoch(&self) -> ChannelEpoch {
+        self.epoch
+    }
+
+    // Send an ack, nack or reject; see `IoLoopHandle::settle`.
+    pub(crate) fn settle<M: IntoAmqpClass>(
+        &self,
+        method: M,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> Result<()> {
+        Deadline::check_current()?;
+        let mut buf = OutputBuffer::empty();
+        buf.push_method(self.channel_id, method);
+        self.urgent_tx
+            .send(IoLoopMessage::Settle(buf, delivery_tag, multiple))
+            .map_err(|_| self.panic_slot.dropped_error())
+    }
+
+    pub(crate) fn batched_ack(
+        &self,
+        delivery_tag: u64,
+        max_count: usize,
+        max_delay: Duration,
+    ) -> Result<()> {
+        Deadline::check_current()?;
+        self.urgent_tx
+            .send(IoLoopMessage::BatchedAck(
+                delivery_tag,
+                max_count,
+                max_delay,
+            ))
+            .map_err(|_| self.panic_slot.dropped_error())
+    }
+
+    // Requeue the delivery (if any) a consumer's callback was handling when it panicked, and
+    // cancel the consumer; see `IoLoopMessage::CancelPanicked`.
+    pub(crate) fn cancel_panicked(
+        &self,
+        consumer_tag: String,
+        in_flight: Option<u64>,
+    ) -> Result<()> {
+        self.urgent_tx
+            .send(IoLoopMessage::CancelPanicked(consumer_tag, in_flight))
+            .map_err(|_| self.panic_slot.dropped_error())
+    }
+}
+
+// A cloneable, sendable handle that publishes on a channel from any thread (see `Publisher`).
+// Each publish is serialized on the caller's thread into a single message, so publishes from
+// different clones cannot interleave their frames.
+#[derive(Clone)]
+pub(crate) struct PublishSender {
+    channel_id: u16,
+    frame_max: usize,
+    tx: MailboxSender<IoLoopMessage>,
+    panic_slot: PanicSlot,
+}
+
+impl fmt::Debug for PublishSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
+        write!(f, "PublishSender {{ channel_id: {}, .. }}", self.channel_id)
+    }
+}
+
+impl PublishSender {
+    #[inline]
+    pub(crate) fn channel_id(&self) -> u16 {
+        self.channel_id
+    }
+
+    pub(crate) fn send_method_with_content<M: IntoAmqpClass>(
+        &self,
+        method: M,
+        class_id: u16,
+        content: &PublishBody,
+        properties: &AmqpProperties,
+    ) -> Result<()> {
+        Deadline::check_current()?;
+        let mut buf = OutputBuffer::empty();
+        push_method_with_content(
+            &mut buf,
+            self.channel_id,
+            method,
+            class_id,
+            content,
+            properties,
+            self.frame_max,
+        );
+        self.tx
+            .send(IoLoopMessage::Send(buf))
+            .map_err(|_| self.panic_slot.dropped_error())
+    }
+}
+
+// Serializes a method followed by its content header and body frames, splitting the body into
+// `frame_max` pieces.
+pub(super) fn push_method_with_content<M: IntoAmqpClass>(
+    buf: &mut OutputBuffer,
+    channel_id: u16,
+    method: M,
+    class_id: u16,
+    content: &PublishBody,
+    properties: &AmqpProperties,
+    frame_max: usize,
+) {
+    buf.push_method(channel_id, method);
+    buf.push_content_header(channel_id, class_id, content.len() as u64, properties);
+    match content {
+        PublishBody::Shared(body) => {
+            for start in (0..body.len()).step_by(frame_max) {
+                let end = start + usize::min(body.len() - start, frame_max);
+                buf.push_shared_content_body(channel_id, body, start..end);
+            }
+        }
+        _ => {
+            for chunk in content.chunks(frame_max) {
+                buf.push_content_body(channel_id, chunk);
+            }
+        }
+    }
+}
 
 pub(super) struct IoLoopHandle {
     channel_id: u16,
+    #[cfg(feature = "consume")]
+    epoch: ChannelEpoch,
     buf: OutputBuffer,
-    tx: MioSyncSender<IoLoopMessage>,
+    tx: MailboxSender<IoLoopMessage>,
+    urgent_tx: MailboxSender<IoLoopMessage>,
     rx: CrossbeamReceiver<Result<ChannelMessage>>,
+    // Number of replies still owed to calls we stopped waiting on because their deadline
+    // passed; they must be discarded before the next call's reply can be read.
+    stale_replies: usize,
+    // Set by the I/O thread while it has stopped reading from channels because too much data is
+    // waiting to be written.
+    backpressure: Arc<AtomicBool>,
+    panic_slot: PanicSlot,
+    counters: Arc<ChannelCounters>,
 }
 
 impl fmt::Debug for IoLoopHandle {
@@ -32,14 +223,25 @@ impl fmt::Debug for IoLoopHandle {
 impl IoLoopHandle {
     pub(super) fn new(
         channel_id: u16,
-        tx: MioSyncSender<IoLoopMessage>,
+        #[cfg(feature = "consume")] epoch: ChannelEpoch,
+        mailboxes: MailboxSenders<IoLoopMessage>,
         rx: CrossbeamReceiver<Result<ChannelMessage>>,
+        backpressure: Arc<AtomicBool>,
+        panic_slot: PanicSlot,
+        counters: Arc<ChannelCounters>,
     ) -> IoLoopHandle {
         IoLoopHandle {
             channel_id,
+            #[cfg(feature = "consume")]
+            epoch,
             buf: OutputBuffer::empty(),
-            tx,
+            tx: mailboxes.regular,
+            urgent_tx: mailboxes.urgent,
             rx,
+            stale_replies: 0,
+            backpressure,
+            panic_slot,
+            counters,
         }
     }
 
@@ -48,6 +250,35 @@ impl IoLoopHandle {
         self.channel_id
     }
 
+    pub(super) fn stats(&self) -> ChannelStats {
+        self.counters.snapshot()
+    }
+
+    #[cfg(feature = "consume")]
+    #[inline]
+    pub(super) fn epoch(&self) -> ChannelEpoch {
+        self.epoch
+    }
+
+    #[cfg(feature = "consume")]
+    pub(super) fn sender(&self) -> ChannelSender {
+        ChannelSender {
+            channel_id: self.channel_id,
+            epoch: self.epoch,
+            urgent_tx: self.urgent_tx.clone(),
+            panic_slot: self.panic_slot.clone(),
+        }
+    }
+
+    pub(super) fn publish_sender(&self, frame_max: usize) -> PublishSender {
+        PublishSender {
+            channel_id: self.channel_id,
+            frame_max,
+            tx: self.tx.clone(),
+            panic_slot: self.panic_slot.clone(),
+        }
+    }
+
     fn make_buf<M: IntoAmqpClass>(&mut self, method: M) -> OutputBuffer {
         debug_assert!(self.buf.is_empty());
         self.buf.push_method(self.channel_id, method);
@@ -56,7 +287,7 @@ impl IoLoopHandle {
 
     pub(super) fn set_return_handler(
         &mut self,
-        handler: Option<CrossbeamSender<Return>>,
+        handler: ReturnHandler,
     ) -> Result<()> {
         self.send(IoLoopMessage::SetReturnHandler(handler))
     }
@@ -68,57 +299,241 @@ impl IoLoopHandle {
         self.send(IoLoopMessage::SetPubConfirmHandler(handler))
     }
 
+    pub(super) fn await_confirm(
+        &mut self,
+        seq_no: SeqNo,
+        tx: CrossbeamSender<Result<ConfirmOutcome>>,
+    ) -> Result<()> {
+        self.send(IoLoopMessage::AwaitConfirm(seq_no, tx))
+    }
+
+    pub(super) fn set_publish_result_handler(
+        &mut self,
+        handler: CrossbeamSender<PublishResult>,
+        first_seq_no: SeqNo,
+    ) -> Result<()> {
+        self.send(IoLoopMessage::SetPublishResultHandler(
+            handler,
+            first_seq_no,
+        ))
+    }
+
+    #[cfg(feature = "consume")]
     pub(super) fn get(&mut self, get: AmqpGet) -> Result<Option<Get>> {
-        let buf = self.make_buf(AmqpBasic::Get(get));
-        self.send(IoLoopMessage::Send(buf))?;
-        match self.recv()? {
+        match self.rpc(AmqpBasic::Get(get))? {
             ChannelMessage::GetOk(get) => Ok(*get),
             ChannelMessage::Method(_) | ChannelMessage::ConsumeOk(_, _) => FrameUnexpectedSnafu.fail(),
         }
     }
 
+    #[cfg(feature = "consume")]
     pub(super) fn consume(
         &mut self,
         consume: Consume,
+        buffer: ConsumerBuffer,
     ) -> Result<(String, CrossbeamReceiver<ConsumerMessage>)> {
-        let buf = self.make_buf(AmqpBasic::Consume(consume));
-        self.send(IoLoopMessage::Send(buf))?;
-        match self.recv()? {
+        let reply = self.rpc_with(AmqpBasic::Consume(consume), |buf, deadline| {
+            IoLoopMessage::Consume(buf, deadline, buffer)
+        })?;
+        match reply {
             ChannelMessage::ConsumeOk(tag, rx) => Ok((tag, rx)),
             ChannelMessage::Method(_) | ChannelMessage::GetOk(_) => FrameUnexpectedSnafu.fail(),
         }
     }
 
+    // Start a consumer without waiting for consume-ok; `consume` must have its nowait flag set
+    // and a client-chosen tag.
+    #[cfg(feature = "consume")]
+    pub(super) fn consume_nowait(
+        &mut self,
+        consume: Consume,
+        buffer: ConsumerBuffer,
+    ) -> Result<CrossbeamReceiver<ConsumerMessage>> {
+        let deadline = Deadline::current();
+        Deadline::check_current()?;
+        let consumer_tag = consume.consumer_tag.clone();
+        let (consumer, rx) = ConsumerSlot::new(buffer);
+        let buf = self.make_buf(AmqpBasic::Consume(consume));
+        let message = IoLoopMessage::ConsumeNowait(buf, consumer_tag, consumer);
+        match deadline {
+            Some(deadline) => self.send_to_before(Mailbox::Regular, message, deadline)?,
+            None => self.send(message)?,
+        }
+        Ok(rx)
+    }
+
+    // Pause or resume dispatching to the consumer with `consumer_tag`, returning once the I/O
+    // thread has done so.
+    #[cfg(feature = "consume")]
+    pub(super) fn set_consumer_paused(&mut self, consumer_tag: &str, paused: bool) -> Result<()> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.send(IoLoopMessage::PauseConsumer(
+            consumer_tag.to_string(),
+            paused,
+            tx,
+        ))?;
+        // The I/O thread drops `tx` without replying if the channel is closing.
+        rx.recv().map_err(|_| self.check_recv_for_error())
+    }
+
+    // Unlike other calls, a connection close may be bounded by a deadline; if it passes before
+    // the server replies, returns `ShutdownTimeout`.
     pub(super) fn call_connection_close(
         &mut self,
         close: ConnectionClose,
+        deadline: Option<Instant>,
     ) -> Result<ConnectionCloseOk> {
         let buf = self.make_buf(AmqpConnection::Close(close));
-        self.call_message(IoLoopMessage::ConnectionClose(buf))
+        self.send(IoLoopMessage::ConnectionClose(buf))?;
+        let message = match deadline {
+            Some(deadline) => match self.rx.recv_deadline(deadline) {
+                Ok(message) => message?,
+                Err(RecvTimeoutError::Timeout) => return ShutdownTimeoutSnafu.fail(),
+                Err(RecvTimeoutError::Disconnected) => return Err(self.event_loop_dropped()),
+            },
+            None => self.recv()?,
+        };
+        match message {
+            ChannelMessage::Method(method) => ConnectionCloseOk::try_from(method),
+            #[cfg(feature = "consume")]
+            ChannelMessage::ConsumeOk(_, _) | ChannelMessage::GetOk(_) => FrameUnexpectedSnafu.fail(),
+        }
     }
 
     pub(super) fn call<M: IntoAmqpClass, T: TryFromAmqpClass>(&mut self, method: M) -> Result<T> {
-        let buf = self.make_buf(method);
-        self.call_message(IoLoopMessage::Send(buf))
+        match self.rpc(method)? {
+            ChannelMessage::Method(method) => T::try_from(method),
+            #[cfg(feature = "consume")]
+            ChannelMessage::ConsumeOk(_, _) | ChannelMessage::GetOk(_) => FrameUnexpectedSnafu.fail(),
+        }
     }
 
-    fn call_message<T: TryFromAmqpClass>(&mut self, message: IoLoopMessage) -> Result<T> {
-        self.send(message)?;
-        match self.recv()? {
-            ChannelMessage::Method(method) => T::try_from(method),
+    // Close the channel and wait for the close-ok. Like acks, the close goes to the channel's
+    // urgent mailbox; see `settle`.
+    pub(super) fn close_channel(&mut self, close: ChannelClose) -> Result<ChannelCloseOk> {
+        let reply = self.rpc_to(
+            Mailbox::Urgent,
+            AmqpChannel::Close(close),
+            IoLoopMessage::ChannelClose,
+        )?;
+        match reply {
+            ChannelMessage::Method(method) => ChannelCloseOk::try_from(method),
+            #[cfg(feature = "consume")]
             ChannelMessage::ConsumeOk(_, _) | ChannelMessage::GetOk(_) => FrameUnexpectedSnafu.fail(),
         }
     }
 
-    pub(super) fn call_nowait<M: IntoAmqpClass>(&mut self, method: M) -> Result<()> {
+    // Send a method and wait for its reply, honoring the deadline in effect on this thread.
+    fn rpc<M: IntoAmqpClass>(&mut self, method: M) -> Result<ChannelMessage> {
+        self.rpc_with(method, |buf, deadline| match deadline {
+            Some(deadline) => IoLoopMessage::Call(buf, deadline),
+            None => IoLoopMessage::Send(buf),
+        })
+    }
+
+    // Like `rpc`, but `make_message` wraps the serialized method (and the deadline the I/O
+    // thread should hold it to, if any) for sending.
+    fn rpc_with<M, F>(&mut self, method: M, make_message: F) -> Result<ChannelMessage>
+    where
+        M: IntoAmqpClass,
+        F: FnOnce(OutputBuffer, Option<Deadline>) -> IoLoopMessage,
+    {
+        self.rpc_to(Mailbox::Regular, method, make_message)
+    }
+
+    fn rpc_to<M, F>(
+        &mut self,
+        mailbox: Mailbox,
+        method: M,
+        make_message: F,
+    ) -> Result<ChannelMessage>
+    where
+        M: IntoAmqpClass,
+        F: FnOnce(OutputBuffer, Option<Deadline>) -> IoLoopMessage,
+    {
+        let deadline = Deadline::current();
+        Deadline::check_current()?;
+        self.discard_stale_replies(deadline)?;
+
         let buf = self.make_buf(method);
-        self.send(IoLoopMessage::Send(buf))
+        match deadline {
+            // Channel 0 has no slot for the I/O thread to answer on, so its calls (which are
+            // all local bookkeeping or connection-level) only have their reply bounded.
+            Some(deadline) if self.channel_id != 0 => {
+                self.send_to_before(mailbox, make_message(buf, Some(deadline)), deadline)?
+            }
+            _ => self.send_to(mailbox, make_message(buf, None))?,
+        }
+        match self.recv_before(deadline) {
+            Ok(message) => message,
+            Err(Error::DeadlineExceeded) => {
+                self.stale_replies += 1;
+                DeadlineExceededSnafu.fail()
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    // True unless the channel has been closed (by either side) or its connection has gone away,
+    // as far as we can tell without asking the server. A handle still owed a reply to an
+    // abandoned call doesn't count as open, since it can't be told apart from a close.
+    pub(super) fn is_open(&mut self) -> bool {
+        self.stale_replies == 0 && matches!(self.rx.try_recv(), Err(TryRecvError::Empty))
+    }
+
+    fn discard_stale_replies(&mut self, deadline: Option<Deadline>) -> Result<()> {
+        while self.stale_replies > 0 {
+            let message = self.recv_before(deadline)?;
+            self.stale_replies -= 1;
+            match message {
+                Ok(_) | Err(Error::DeadlineExceeded) => {
+                    trace!("discarded stale reply on channel {}", self.channel_id)
+                }
+                // Anything else (e.g., the server closing the channel) is not a reply to the
+                // abandoned call, and it concerns this one too.
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn call_nowait<M: IntoAmqpClass>(&mut self, method: M) -> Result<()> {
+        self.send_nowait(Mailbox::Regular, method, IoLoopMessage::Send)
+    }
+
+    // Send an ack, nack or reject of `delivery_tag` (and, if `multiple`, every delivery before
+    // it), which `method` must be. These go to the channel's urgent mailbox, so they do not wait
+    // for the output buffer to drain (see `channel_mailbox`).
+    #[cfg(feature = "consume")]
+    pub(super) fn settle<M: IntoAmqpClass>(
+        &mut self,
+        method: M,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> Result<()> {
+        self.send_nowait(Mailbox::Urgent, method, |buf| {
+            IoLoopMessage::Settle(buf, delivery_tag, multiple)
+        })
+    }
+
+    fn send_nowait<M, F>(&mut self, mailbox: Mailbox, method: M, make_message: F) -> Result<()>
+    where
+        M: IntoAmqpClass,
+        F: FnOnce(OutputBuffer) -> IoLoopMessage,
+    {
+        let deadline = Deadline::current();
+        Deadline::check_current()?;
+        let message = make_message(self.make_buf(method));
+        match deadline {
+            Some(deadline) => self.send_to_before(mailbox, message, deadline),
+            None => self.send_to(mailbox, message),
+        }
     }
 
     pub(super) fn send_content_header(
         &mut self,
         class_id: u16,
-        len: usize,
+        len: u64,
         properties: &AmqpProperties,
     ) -> Result<()> {
         debug_assert!(self.buf.is_empty());
@@ -135,14 +550,126 @@ impl IoLoopHandle {
         self.send(IoLoopMessage::Send(buf))
     }
 
+    // Like `send_content_body` with `&body[range]`, but the I/O thread writes straight from
+    // `body` instead of a copy.
+    pub(super) fn send_shared_content_body(
+        &mut self,
+        body: &Arc<[u8]>,
+        range: Range<usize>,
+    ) -> Result<()> {
+        debug_assert!(self.buf.is_empty());
+        self.buf
+            .push_shared_content_body(self.channel_id, body, range);
+        let buf = self.buf.drain_into_new_buf();
+        self.send(IoLoopMessage::Send(buf))
+    }
+
+    // Send a method followed by its content as a single message, but only if the I/O thread can
+    // take it without blocking. Returns false (having sent nothing) if it can't.
+    pub(super) fn try_send_method_with_content<M: IntoAmqpClass>(
+        &mut self,
+        method: M,
+        class_id: u16,
+        content: &PublishBody,
+        properties: &AmqpProperties,
+        frame_max: usize,
+    ) -> Result<bool> {
+        Deadline::check_current()?;
+        if self.backpressure.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        debug_assert!(self.buf.is_empty());
+        push_method_with_content(
+            &mut self.buf,
+            self.channel_id,
+            method,
+            class_id,
+            content,
+            properties,
+            frame_max,
+        );
+        let buf = self.buf.drain_into_new_buf();
+        match self.tx.try_send(IoLoopMessage::Send(buf)) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => Ok(false),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(self.check_recv_for_error())
+            }
+        }
+    }
+
     fn send(&mut self, message: IoLoopMessage) -> Result<()> {
-        self.tx
+        self.send_to(Mailbox::Regular, message)
+    }
+
+    fn mailbox(&self, mailbox: Mailbox) -> &MailboxSender<IoLoopMessage> {
+        match mailbox {
+            Mailbox::Regular => &self.tx,
+            Mailbox::Urgent => &self.urgent_tx,
+        }
+    }
+
+    fn send_to(&mut self, mailbox: Mailbox, message: IoLoopMessage) -> Result<()> {
+        self.mailbox(mailbox)
             .send(message)
             .map_err(|_| self.check_recv_for_error())
     }
 
+    // Like `send_to`, but gives up if the I/O thread is applying backpressure past `deadline`.
+    fn send_to_before(
+        &mut self,
+        mailbox: Mailbox,
+        mut message: IoLoopMessage,
+        deadline: Deadline,
+    ) -> Result<()> {
+        loop {
+            message = match self.mailbox(mailbox).try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(message)) => message,
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(self.check_recv_for_error())
+                }
+            };
+            if deadline.has_passed() {
+                return DeadlineExceededSnafu.fail();
+            }
+            thread::sleep(SEND_RETRY_INTERVAL);
+        }
+    }
+
+    // Asks the I/O thread to close this channel without waiting for it (through our urgent
+    // mailbox, as with `close_channel`). Never blocks: if that mailbox is full, the I/O thread
+    // closes the channel when it sees this handle is gone.
+    pub(super) fn close_on_drop(&mut self) {
+        let _ = self.urgent_tx.try_send(IoLoopMessage::CloseDropped);
+    }
+
+    #[cfg(test)]
+    pub(super) fn inject_panic(&mut self, message: &'static str) -> Result<()> {
+        self.send(IoLoopMessage::Panic(message))
+    }
+
     fn recv(&mut self) -> Result<ChannelMessage> {
-        self.rx.recv().map_err(|_| Error::EventLoopDropped)?
+        self.rx.recv().map_err(|_| self.event_loop_dropped())?
+    }
+
+    // The error for a call that found the I/O thread gone: `EventLoopPanicked` if it panicked,
+    // `EventLoopDropped` otherwise.
+    pub(super) fn event_loop_dropped(&self) -> Error {
+        self.panic_slot.dropped_error()
+    }
+
+    // Receive the next reply. The outer result fails if `deadline` passes first or the I/O
+    // thread is gone; the inner result is the reply itself.
+    fn recv_before(&mut self, deadline: Option<Deadline>) -> Result<Result<ChannelMessage>> {
+        match deadline {
+            Some(deadline) => match self.rx.recv_deadline(deadline.instant()) {
+                Ok(message) => Ok(message),
+                Err(RecvTimeoutError::Timeout) => DeadlineExceededSnafu.fail(),
+                Err(RecvTimeoutError::Disconnected) => Err(self.event_loop_dropped()),
+            },
+            None => self.rx.recv().map_err(|_| self.event_loop_dropped()),
+        }
     }
 
     fn check_recv_for_error(&mut self) -> Error {
@@ -163,11 +690,47 @@ impl IoLoopHandle {
     }
 }
 
+// A request for the I/O thread to allocate a channel (with the given ID, or the lowest free ID if
+// none), along with where to send the handle for it.
+pub(super) type AllocChannelRequest = (Option<u16>, CrossbeamSender<Result<IoLoopHandle>>);
+
+// A cloneable handle that can ask the I/O thread to allocate channels. Each request carries its
+// own reply channel, so it may be used from several threads at once.
+#[derive(Clone)]
+pub(super) struct ChannelAllocator {
+    tx: SyncSender<AllocChannelRequest>,
+    panic_slot: PanicSlot,
+}
+
+impl ChannelAllocator {
+    pub(super) fn new(
+        tx: SyncSender<AllocChannelRequest>,
+        panic_slot: PanicSlot,
+    ) -> ChannelAllocator {
+        ChannelAllocator { tx, panic_slot }
+    }
+
+    fn request(
+        &self,
+        channel_id: Option<u16>,
+    ) -> StdResult<CrossbeamReceiver<Result<IoLoopHandle>>, ()> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.tx.send((channel_id, tx)).map_err(|_| ())?;
+        Ok(rx)
+    }
+
+    pub(super) fn allocate(&self, channel_id: Option<u16>) -> Result<IoLoopHandle> {
+        let rx = self
+            .request(channel_id)
+            .map_err(|()| self.panic_slot.dropped_error())?;
+        rx.recv().map_err(|_| self.panic_slot.dropped_error())?
+    }
+}
+
 pub(super) struct IoLoopHandle0 {
     common: IoLoopHandle,
-    set_blocked_tx: MioSyncSender<CrossbeamSender<ConnectionBlockedNotification>>,
-    alloc_chan_req_tx: MioSyncSender<Option<u16>>,
-    alloc_chan_rep_rx: CrossbeamReceiver<Result<IoLoopHandle>>,
+    set_blocked_tx: SyncSender<CrossbeamSender<ConnectionBlockedNotification>>,
+    allocator: ChannelAllocator,
 }
 
 impl fmt::Debug for IoLoopHandle0 {
@@ -179,25 +742,26 @@ impl fmt::Debug for IoLoopHandle0 {
 impl IoLoopHandle0 {
     pub(super) fn new(
         common: IoLoopHandle,
-        set_blocked_tx: MioSyncSender<CrossbeamSender<ConnectionBlockedNotification>>,
-        alloc_chan_req_tx: MioSyncSender<Option<u16>>,
-        alloc_chan_rep_rx: CrossbeamReceiver<Result<IoLoopHandle>>,
+        set_blocked_tx: SyncSender<CrossbeamSender<ConnectionBlockedNotification>>,
+        allocator: ChannelAllocator,
     ) -> IoLoopHandle0 {
         IoLoopHandle0 {
             common,
             set_blocked_tx,
-            alloc_chan_req_tx,
-            alloc_chan_rep_rx,
+            allocator,
         }
     }
 
+    pub(super) fn allocator(&self) -> &ChannelAllocator {
+        &self.allocator
+    }
+
     pub(super) fn allocate_channel(&mut self, channel_id: Option<u16>) -> Result<IoLoopHandle> {
-        self.alloc_chan_req_tx
-            .send(channel_id)
-            .map_err(|_| self.common.check_recv_for_error())?;
-        self.alloc_chan_rep_rx
-            .recv()
-            .map_err(|_| Error::EventLoopDropped)?
+        let rx = self
+            .allocator
+            .request(channel_id)
+            .map_err(|()| self.common.check_recv_for_error())?;
+        rx.recv().map_err(|_| self.common.event_loop_dropped())?
     }
 
     pub(super) fn set_blocked_tx(
@@ -208,6 +772,14 @@ impl IoLoopHandle0 {
             .send(tx)
             .map_err(|_| self.common.check_recv_for_error())
     }
+
+    // Start a graceful shutdown and wait for its report, which the I/O thread sends by
+    // `deadline`.
+    pub(super) fn shutdown(&mut self, deadline: Instant) -> Result<ShutdownReport> {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        self.common.send(IoLoopMessage::Shutdown(deadline, tx))?;
+        rx.recv().map_err(|_| self.common.event_loop_dropped())
+    }
 }
 
 impl Deref for IoLoopHandle0 {
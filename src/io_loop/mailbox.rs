@@ -0,0 +1,192 @@
+use super::waker::{sync_channel, LoopWaker, SyncSender};
+use crossbeam_channel::{Receiver, SendError, TryRecvError, TrySendError};
+use mio::Token;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Each channel sends to the I/O thread through two mailboxes: a regular one, which the I/O thread
+// stops reading while too much data is waiting to be written (see
+// `ConnectionTuning::buffered_writes_high_water`), and an urgent one for acks, nacks, rejects and
+// closes, which it reads regardless. Messages are numbered from a counter shared by all of the
+// channel's senders, so the I/O thread can still take them in the order they were sent.
+
+// Which of its channel's mailboxes a message is sent to.
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Mailbox {
+    Regular,
+    Urgent,
+}
+
+type Numbered<T> = (u64, T);
+
+// The sending half of one of a channel's mailboxes.
+pub(super) struct MailboxSender<T> {
+    tx: SyncSender<Numbered<T>>,
+    next_seq: Arc<AtomicU64>,
+}
+
+// Not derived, which would require `T: Clone`.
+impl<T> Clone for MailboxSender<T> {
+    fn clone(&self) -> Self {
+        MailboxSender {
+            tx: self.tx.clone(),
+            next_seq: Arc::clone(&self.next_seq),
+        }
+    }
+}
+
+impl<T> MailboxSender<T> {
+    pub(super) fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        self.tx
+            .send((seq, message))
+            .map_err(|SendError((_, message))| SendError(message))
+    }
+
+    pub(super) fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        self.tx.try_send((seq, message)).map_err(|err| match err {
+            TrySendError::Full((_, message)) => TrySendError::Full(message),
+            TrySendError::Disconnected((_, message)) => TrySendError::Disconnected(message),
+        })
+    }
+}
+
+// The sending halves of both of a channel's mailboxes.
+pub(super) struct MailboxSenders<T> {
+    pub(super) regular: MailboxSender<T>,
+    pub(super) urgent: MailboxSender<T>,
+}
+
+// What `ChannelMailbox::next` found.
+pub(super) enum Next<T> {
+    Message(T),
+    Empty,
+    // Every sender of the regular mailbox is gone and it has been emptied.
+    Disconnected,
+}
+
+// The receiving halves of both of a channel's mailboxes.
+pub(super) struct ChannelMailbox<T> {
+    regular: Receiver<Numbered<T>>,
+    urgent: Receiver<Numbered<T>>,
+    // The first message waiting in each mailbox, if we have had to take it out to compare it with
+    // the other's.
+    regular_head: Option<Numbered<T>>,
+    urgent_head: Option<Numbered<T>>,
+}
+
+// Both mailboxes hold up to `bound` messages and wake the I/O thread for `token`.
+pub(super) fn channel_mailbox<T>(
+    bound: usize,
+    token: Token,
+    waker: &Arc<LoopWaker>,
+) -> (MailboxSenders<T>, ChannelMailbox<T>) {
+    let next_seq = Arc::new(AtomicU64::new(0));
+    let (regular_tx, regular) = sync_channel(bound, token, waker);
+    let (urgent_tx, urgent) = sync_channel(bound, token, waker);
+    let senders = MailboxSenders {
+        regular: MailboxSender {
+            tx: regular_tx,
+            next_seq: Arc::clone(&next_seq),
+        },
+        urgent: MailboxSender {
+            tx: urgent_tx,
+            next_seq,
+        },
+    };
+    let mailbox = ChannelMailbox {
+        regular,
+        urgent,
+        regular_head: None,
+        urgent_head: None,
+    };
+    (senders, mailbox)
+}
+
+impl<T> ChannelMailbox<T> {
+    // Take the next message in the order they were sent. Unless `take_regular` is set, messages
+    // in the regular mailbox are only taken if they were sent before one waiting in the urgent
+    // mailbox.
+    pub(super) fn next(&mut self, take_regular: bool) -> Next<T> {
+        if self.urgent_head.is_none() {
+            self.urgent_head = self.urgent.try_recv().ok();
+        }
+        let mut disconnected = false;
+        if self.regular_head.is_none() && (take_regular || self.urgent_head.is_some()) {
+            match self.regular.try_recv() {
+                Ok(message) => self.regular_head = Some(message),
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => disconnected = true,
+            }
+        }
+        let head = match (&self.regular_head, &self.urgent_head) {
+            (Some((regular, _)), Some((urgent, _))) if regular < urgent => &mut self.regular_head,
+            (_, Some(_)) => &mut self.urgent_head,
+            (Some(_), None) if take_regular => &mut self.regular_head,
+            (Some(_), None) => return Next::Empty,
+            (None, None) if disconnected => return Next::Disconnected,
+            (None, None) => return Next::Empty,
+        };
+        match head.take() {
+            Some((_, message)) => Next::Message(message),
+            None => unreachable!("chose an empty mailbox"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::Poll;
+
+    fn mailbox() -> (Poll, MailboxSenders<u32>, ChannelMailbox<u32>) {
+        let poll = Poll::new().unwrap();
+        let waker = Arc::new(LoopWaker::new(poll.registry()).unwrap());
+        let (senders, mailbox) = channel_mailbox(4, Token(1), &waker);
+        (poll, senders, mailbox)
+    }
+
+    fn take(mailbox: &mut ChannelMailbox<u32>, take_regular: bool) -> Option<u32> {
+        match mailbox.next(take_regular) {
+            Next::Message(message) => Some(message),
+            Next::Empty => None,
+            Next::Disconnected => panic!("unexpected disconnect"),
+        }
+    }
+
+    #[test]
+    fn messages_are_taken_in_the_order_they_were_sent() {
+        let (_poll, senders, mut mailbox) = mailbox();
+        senders.regular.send(1).unwrap();
+        senders.urgent.send(2).unwrap();
+        senders.regular.send(3).unwrap();
+        senders.urgent.send(4).unwrap();
+        let taken: Vec<_> = (0..5).map(|_| take(&mut mailbox, true)).collect();
+        assert_eq!(taken, [Some(1), Some(2), Some(3), Some(4), None]);
+    }
+
+    #[test]
+    fn urgent_messages_bring_along_only_what_was_sent_before_them() {
+        let (_poll, senders, mut mailbox) = mailbox();
+        senders.regular.send(1).unwrap();
+        senders.regular.send(2).unwrap();
+        senders.urgent.send(3).unwrap();
+        senders.regular.send(4).unwrap();
+        let taken: Vec<_> = (0..4).map(|_| take(&mut mailbox, false)).collect();
+        assert_eq!(taken, [Some(1), Some(2), Some(3), None]);
+        assert_eq!(take(&mut mailbox, true), Some(4));
+    }
+
+    #[test]
+    fn disconnected_once_regular_senders_are_gone_and_drained() {
+        let (_poll, senders, mut mailbox) = mailbox();
+        let MailboxSenders { regular, urgent } = senders;
+        regular.send(1).unwrap();
+        drop(regular);
+        assert_eq!(take(&mut mailbox, false), None);
+        assert_eq!(take(&mut mailbox, true), Some(1));
+        assert!(matches!(mailbox.next(true), Next::Disconnected));
+        drop(urgent);
+    }
+}
@@ -1,142 +1,551 @@
 use crate::connection_options::ConnectionOptions;
+use crate::deadline::Deadline;
 use crate::errors::*;
-use crate::frame_buffer::FrameBuffer;
-use crate::serialize::{IntoAmqpClass, OutputBuffer, SealableOutputBuffer};
+use crate::frame_buffer::{FrameBuffer, FrameCounters};
+use crate::frame_tap::{FrameDirection, FrameTapHandle};
+use crate::logging::{debug, enter_span, error, trace, warn};
+use crate::observer::ObserverHandle;
+use crate::reply_code::method_name;
+use crate::serialize::{
+    for_each_frame, for_each_method_frame, method_ids, FrameCounts, IntoAmqpClass, OutputBuffer,
+    SealableOutputBuffer,
+};
+use crate::tag::SeqNo;
 use crate::{
-    Confirm, ConnectionBlockedNotification, ConnectionTuning, ConsumerMessage, FieldTable, Get,
-    IoStream, Return, Sasl,
+    AmqpReplyCode, Confirm, ConfirmOutcome, ConnectionBlockedNotification, ConnectionTuning,
+    FieldTable, IoStream, PublishResult, Sasl,
 };
-use amq_protocol::frame::AMQPFrame;
+use amq_protocol::frame::{parse_frame, AMQPFrame};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::Close as ConnectionClose;
 use amq_protocol::protocol::connection::TuneOk;
 use amq_protocol::protocol::AMQPClass;
 use crossbeam_channel::Receiver as CrossbeamReceiver;
 use crossbeam_channel::SendError;
 use crossbeam_channel::Sender as CrossbeamSender;
-use log::{debug, error, trace, warn};
-use mio::{Event, Evented, Events, Poll, PollOpt, Ready, Token};
-use mio_extras::channel::sync_channel as mio_sync_channel;
-use mio_extras::channel::Receiver as MioReceiver;
+use crossbeam_channel::TryRecvError;
+use mio::event::{Event, Source};
+use mio::{Events, Interest, Poll, Token};
 use snafu::ResultExt;
-use std::collections::hash_map::HashMap;
+use std::cell::Cell;
 use std::io;
-use std::sync::mpsc::TryRecvError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread::{Builder, JoinHandle};
 use std::time::{Duration, Instant};
 
-#[cfg(feature = "native-tls")]
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
 use crate::stream::HandshakeStream;
 
+#[cfg(feature = "chaos")]
+use crate::chaos::FaultInjectorHandle;
+
+#[cfg(feature = "consume")]
+use crate::consumer::ConsumerBuffer;
+#[cfg(feature = "consume")]
+use crate::tag::ChannelEpoch;
+#[cfg(feature = "consume")]
+use crate::{ConsumerMessage, Get, OverflowPolicy, RedeliveryPolicy};
+#[cfg(feature = "consume")]
+use amq_protocol::protocol::basic::{Ack, Cancel, Nack};
+#[cfg(feature = "consume")]
+use crate::timer::{Timeout, Timer};
+#[cfg(feature = "consume")]
+use std::collections::hash_map::{Entry, HashMap};
+#[cfg(feature = "consume")]
+use std::collections::HashSet;
+#[cfg(feature = "consume")]
+use std::collections::VecDeque;
+#[cfg(feature = "consume")]
+use std::mem;
+
 mod channel_handle;
 mod channel_slots;
+mod close_listeners;
+mod confirm_waiters;
 mod connection_state;
 mod content_collector;
 mod handshake_state;
 mod heartbeat_timers;
 mod io_loop_handle;
-
-pub(crate) use channel_handle::{Channel0Handle, ChannelHandle};
+mod mailbox;
+mod panic_slot;
+#[cfg(feature = "consume")]
+mod parked_publishes;
+mod publish_results;
+mod return_listener;
+mod shutdown;
+mod stats;
+mod waker;
+
+pub(crate) use channel_handle::{Channel0Handle, ChannelHandle, ChannelOpener};
 use channel_slots::ChannelSlots;
+pub(crate) use close_listeners::CloseListeners;
+use confirm_waiters::ConfirmWaiters;
 use connection_state::ConnectionState;
 use content_collector::ContentCollector;
 use handshake_state::HandshakeState;
 use heartbeat_timers::{HeartbeatKind, HeartbeatState, HeartbeatTimers};
-use io_loop_handle::{IoLoopHandle, IoLoopHandle0};
+pub use heartbeat_timers::HeartbeatDiagnostics;
+pub(crate) use heartbeat_timers::SharedHeartbeatDiagnostics;
+#[cfg(feature = "consume")]
+pub(crate) use io_loop_handle::ChannelSender;
+pub(crate) use io_loop_handle::PublishSender;
+use io_loop_handle::{AllocChannelRequest, ChannelAllocator, IoLoopHandle, IoLoopHandle0};
+pub use panic_slot::install_panic_hook;
+use panic_slot::PanicSlot;
+#[cfg(feature = "consume")]
+use parked_publishes::ParkedPublishes;
+use publish_results::PublishResults;
+pub(crate) use return_listener::ReturnHandler;
+use return_listener::ReturnListener;
+pub use shutdown::ShutdownReport;
+use shutdown::{Shutdown, UnconfirmedPublishes};
+#[cfg(feature = "consume")]
+use shutdown::UnackedDeliveries;
+pub(crate) use stats::{ChannelCounters, ChannelRegistry, ConnectionCounters};
+pub use stats::{ChannelInfo, ChannelStats, ConnectionStats};
+use stats::OpenChannelCounters;
+use mailbox::{channel_mailbox, ChannelMailbox, Next};
+use waker::{sync_channel, LoopWaker};
+pub use waker::StreamWaker;
+
+const STREAM: Token = Token(u16::MAX as usize + 1);
+const HEARTBEAT: Token = Token(u16::MAX as usize + 2);
+const ALLOC_CHANNEL: Token = Token(u16::MAX as usize + 3);
+const SET_BLOCKED_TX: Token = Token(u16::MAX as usize + 4);
+#[cfg(feature = "consume")]
+const ACK_FLUSH: Token = Token(u16::MAX as usize + 5);
+// Registered with mio for the `LoopWaker`; never handled as such (see `IoEvent`).
+const WAKER: Token = Token(u16::MAX as usize + 6);
+
+// How often to check whether a consumer has made room for a delivery we are holding (because its
+// buffer was full, or because it was paused and has been resumed); nothing wakes us when it does.
+#[cfg(feature = "consume")]
+const STALLED_DELIVERY_RETRY: Duration = Duration::from_millis(10);
+
+// How often to check on a graceful shutdown; some of what it waits for (e.g., the channels
+// being reregistered once the output buffer drains) happens without an event to wake us.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+// Something for the I/O loop to handle: readiness mio reported for the stream, a token woken
+// through the `LoopWaker` (a channel's mailbox, or a stream mio does not poll), or a timer that has
+// come due. Only the stream distinguishes reading from writing; anything woken may be both.
+#[derive(Debug, Clone, Copy)]
+struct IoEvent {
+    token: Token,
+    readable: bool,
+    writable: bool,
+}
+
+impl IoEvent {
+    fn woken(token: Token) -> IoEvent {
+        IoEvent {
+            token,
+            readable: true,
+            writable: true,
+        }
+    }
 
-const STREAM: Token = Token(u16::max_value() as usize + 1);
-const HEARTBEAT: Token = Token(u16::max_value() as usize + 2);
-const ALLOC_CHANNEL: Token = Token(u16::max_value() as usize + 3);
-const SET_BLOCKED_TX: Token = Token(u16::max_value() as usize + 4);
+    // Closed and failed sockets are reported as readable and writable, so the error surfaces from
+    // whichever we try.
+    fn from_mio(event: &Event) -> IoEvent {
+        IoEvent {
+            token: event.token(),
+            readable: event.is_readable() || event.is_read_closed() || event.is_error(),
+            writable: event.is_writable() || event.is_write_closed() || event.is_error(),
+        }
+    }
+}
 
 enum IoLoopMessage {
     Send(OutputBuffer),
+    // A method the caller is waiting on a reply for, sent while a deadline was in effect.
+    Call(OutputBuffer, Deadline),
     ConnectionClose(OutputBuffer),
-    SetReturnHandler(Option<CrossbeamSender<Return>>),
+    // A channel.close, with the deadline its caller is waiting under (see `Call`), if any. Sent
+    // to the channel's urgent mailbox (see `channel_mailbox`).
+    ChannelClose(OutputBuffer, Option<Deadline>),
+    SetReturnHandler(ReturnHandler),
     SetPubConfirmHandler(Option<CrossbeamSender<Confirm>>),
+    // Sent just ahead of the publish with this sequence number.
+    AwaitConfirm(SeqNo, CrossbeamSender<Result<ConfirmOutcome>>),
+    // Results are wanted for publishes starting with this sequence number.
+    SetPublishResultHandler(CrossbeamSender<PublishResult>, SeqNo),
+    // A basic.consume (sent like `Send` or `Call`, depending on the deadline) and how to buffer
+    // deliveries for the consumer it starts.
+    #[cfg(feature = "consume")]
+    Consume(OutputBuffer, Option<Deadline>, ConsumerBuffer),
+    // A basic.consume with its nowait flag set, the client-chosen consumer tag, and the consumer
+    // to start dispatching to immediately (there will be no consume-ok to wait for).
+    #[cfg(feature = "consume")]
+    ConsumeNowait(OutputBuffer, String, ConsumerSlot),
+    // An ack to coalesce with others (see `AckPolicy::Batched`): the delivery tag, and how many
+    // acks may be held or for how long before they must be sent.
+    #[cfg(feature = "consume")]
+    BatchedAck(u64, usize, Duration),
+    // An ack, nack or reject (sent like `Send`) of the delivery tag, and of every delivery before
+    // it if the flag is set.
+    #[cfg(feature = "consume")]
+    Settle(OutputBuffer, u64, bool),
+    // The channel's handle was dropped without closing it; close it without anyone waiting for
+    // the close-ok. Sent to the channel's urgent mailbox (see `channel_mailbox`).
+    CloseDropped,
+    // Begin a graceful shutdown (see `Connection::shutdown`), sending the report once it is done
+    // or the deadline passes. Only sent on channel 0.
+    Shutdown(Instant, CrossbeamSender<ShutdownReport>),
+    // A callback consumer whose callback panicked (see `Registration::dispatch`): nack with
+    // requeue the delivery it was handling, if any and if it is still unsettled, and cancel the
+    // consumer without anyone waiting for the cancel-ok. Sent to the channel's urgent mailbox, so
+    // it follows any ack or nack the callback sent first.
+    #[cfg(feature = "consume")]
+    CancelPanicked(String, Option<u64>),
+    // Pause (if true) or resume a consumer (see `Consumer::pause`), replying once done.
+    #[cfg(feature = "consume")]
+    PauseConsumer(String, bool, CrossbeamSender<()>),
+    // Makes the I/O thread panic with the given message, so tests can exercise panic handling.
+    #[cfg(test)]
+    Panic(&'static str),
 }
 
 enum ChannelMessage {
     Method(AMQPClass),
+    #[cfg(feature = "consume")]
     ConsumeOk(String, CrossbeamReceiver<ConsumerMessage>),
+    #[cfg(feature = "consume")]
     GetOk(Box<Option<Get>>),
 }
 
 struct ChannelSlot {
-    rx: MioReceiver<IoLoopMessage>,
+    rx: ChannelMailbox<IoLoopMessage>,
     tx: CrossbeamSender<Result<ChannelMessage>>,
     collector: ContentCollector,
-    consumers: HashMap<String, CrossbeamSender<ConsumerMessage>>,
-    return_handler: Option<CrossbeamSender<Return>>,
+    #[cfg(feature = "consume")]
+    consumers: HashMap<String, ConsumerSlot>,
+    // How to buffer deliveries for consumers we have asked the server to start, in order.
+    #[cfg(feature = "consume")]
+    pending_consumers: VecDeque<ConsumerBuffer>,
+    // Set once we have sent a close for this channel on behalf of a handle that will not wait
+    // for the close-ok: because the handle was dropped, or because a consumer overflowed its
+    // buffer (see `OverflowPolicy::Error`).
+    closing: bool,
+    // Acks we are holding under `AckPolicy::Batched`.
+    #[cfg(feature = "consume")]
+    pending_ack: Option<PendingAck>,
+    return_listener: ReturnListener,
     pub_confirm_handler: Option<CrossbeamSender<Confirm>>,
+    confirm_waiters: ConfirmWaiters,
+    publish_results: PublishResults,
+    // Set once the server confirms the channel is in confirm mode.
+    unconfirmed: Option<UnconfirmedPublishes>,
+    #[cfg(feature = "consume")]
+    unacked: UnackedDeliveries,
+    // Deliveries republished to their consumer's parking lot (see `RedeliveryPolicy`).
+    #[cfg(feature = "consume")]
+    parked: ParkedPublishes,
+    // Shared with the channel's handle (see `Channel::stats`).
+    counters: OpenChannelCounters,
 }
 
 impl ChannelSlot {
-    fn new(mio_channel_bound: usize, channel_id: u16) -> (ChannelSlot, IoLoopHandle) {
-        let (mio_tx, mio_rx) = mio_sync_channel(mio_channel_bound);
-
-        // Bound of 2 is intentional here. The normal case for this channel is that it
+    fn new(
+        mem_channel_bound: usize,
+        channel_id: u16,
+        backpressure: Arc<AtomicBool>,
+        panic_slot: PanicSlot,
+        waker: &Arc<LoopWaker>,
+    ) -> (ChannelSlot, IoLoopHandle) {
+        let (mio_tx, mio_rx) =
+            channel_mailbox(mem_channel_bound, Token(channel_id as usize), waker);
+
+        // Bound of 3 is intentional here. The normal case for this channel is that it
         // will have at most 1 message in it (the response to a synchronous RPC call).
-        // However, we might asynchronously receive a channel-close message from the
-        // server, and there should be room to push that into this channel as well. If
-        // we try to send to this channel and get blocked, we will exit the I/O loop
-        // quickly as something has gone wrong internally; either a channel client has
-        // tried to send 2 RPC synchronous calls without waiting for the answer to the
-        // first, or the server has sent us multiple messages unrelated to RPC requests.
-        // Either way, the connection is in a bad state - bail out.
-        let (tx, rx) = crossbeam_channel::bounded(2);
+        // If a caller gave up on a call because of its deadline, it will discard that
+        // call's (stale) response before making its next call, but the stale response
+        // and the next response may both be waiting here. We might also asynchronously
+        // receive a channel-close message from the server, and there should be room to
+        // push that into this channel as well. If we try to send to this channel and
+        // get blocked, we will exit the I/O loop quickly as something has gone wrong
+        // internally; either a channel client has tried to send multiple RPC
+        // synchronous calls without waiting for the answers, or the server has sent us
+        // multiple messages unrelated to RPC requests. Either way, the connection is in
+        // a bad state - bail out.
+        let (tx, rx) = crossbeam_channel::bounded(3);
+
+        #[cfg(feature = "consume")]
+        let epoch = ChannelEpoch::next();
+        let counters = OpenChannelCounters::new(channel_id);
 
         let channel_slot = ChannelSlot {
             rx: mio_rx,
             tx,
-            collector: ContentCollector::new(channel_id),
+            collector: ContentCollector::new(
+                channel_id,
+                #[cfg(feature = "consume")]
+                epoch,
+            ),
+            #[cfg(feature = "consume")]
             consumers: HashMap::new(),
-            return_handler: None,
+            #[cfg(feature = "consume")]
+            pending_consumers: VecDeque::new(),
+            closing: false,
+            #[cfg(feature = "consume")]
+            pending_ack: None,
+            return_listener: ReturnListener::default(),
             pub_confirm_handler: None,
+            confirm_waiters: ConfirmWaiters::default(),
+            publish_results: PublishResults::default(),
+            unconfirmed: None,
+            #[cfg(feature = "consume")]
+            unacked: UnackedDeliveries::default(),
+            #[cfg(feature = "consume")]
+            parked: ParkedPublishes::default(),
+            counters,
         };
 
-        let loop_handle = IoLoopHandle::new(channel_id, mio_tx, rx);
+        let loop_handle = IoLoopHandle::new(
+            channel_id,
+            #[cfg(feature = "consume")]
+            epoch,
+            mio_tx,
+            rx,
+            backpressure,
+            panic_slot,
+            channel_slot.counters.shared(),
+        );
 
         (channel_slot, loop_handle)
     }
+
+    // Must be called after changing `consumers`, to keep the channel's stats up to date.
+    #[cfg(feature = "consume")]
+    fn consumers_changed(&self) {
+        self.counters.set_consumer_tags(self.consumers.keys());
+    }
+}
+
+#[cfg(feature = "consume")]
+struct ConsumerSlot {
+    tx: CrossbeamSender<ConsumerMessage>,
+    // The number of deliveries `tx` may hold, if it is bounded. Its capacity is one more than
+    // this, so the consumer's final (non-delivery) message always fits.
+    bound: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    // Deliveries with a larger body are streamed (see `ConsumerOptions::stream_bodies_above`).
+    stream_above: Option<u64>,
+    // If false, we track which deliveries the consumer has yet to settle.
+    no_ack: bool,
+    // See `Consumer::pause`. Deliveries that arrive while paused are held, as are later ones
+    // until the held deliveries have all been handed over.
+    paused: bool,
+    held: VecDeque<ConsumerMessage>,
+    // See `ConsumerOptions::redelivery_policy`.
+    redelivery_policy: Option<RedeliveryPolicy>,
+}
+
+#[cfg(feature = "consume")]
+impl ConsumerSlot {
+    fn new(buffer: ConsumerBuffer) -> (ConsumerSlot, CrossbeamReceiver<ConsumerMessage>) {
+        let (tx, rx) = match buffer.bound {
+            Some(bound) => crossbeam_channel::bounded(bound + 1),
+            None => crossbeam_channel::unbounded(),
+        };
+        let slot = ConsumerSlot {
+            tx,
+            bound: buffer.bound,
+            overflow_policy: buffer.overflow_policy,
+            stream_above: buffer.stream_above,
+            no_ack: buffer.no_ack,
+            paused: false,
+            held: VecDeque::new(),
+            redelivery_policy: buffer.redelivery_policy,
+        };
+        (slot, rx)
+    }
+
+    fn is_full(&self) -> bool {
+        match self.bound {
+            Some(bound) => self.tx.len() >= bound,
+            None => false,
+        }
+    }
+
+    // Hand over held deliveries while the consumer has room for them. Returns true if there is
+    // nothing more to hand over until the consumer is next resumed.
+    fn drain_held(&mut self) -> bool {
+        while !self.paused && !self.is_full() {
+            let message = match self.held.pop_front() {
+                Some(message) => message,
+                None => break,
+            };
+            if let Err(err) = self.tx.try_send(message) {
+                debug!(
+                    "discarding held message for dropped consumer: {:?}",
+                    err.into_inner()
+                );
+            }
+        }
+        self.paused || self.held.is_empty()
+    }
+
+    // Hand over as many held deliveries as fit (even if paused, since the consumer is ending),
+    // returning the rest.
+    fn release_held(&mut self) -> VecDeque<ConsumerMessage> {
+        self.paused = false;
+        self.drain_held();
+        mem::take(&mut self.held)
+    }
+}
+
+// Acks held for a channel; sent as a single ack of `delivery_tag` with `multiple` set once there
+// are enough of them or `timeout` fires.
+#[cfg(feature = "consume")]
+struct PendingAck {
+    delivery_tag: u64,
+    count: usize,
+    timeout: Timeout,
+}
+
+// A delivery for a consumer whose buffer was full (with `OverflowPolicy::Block`). We stop
+// reading from the socket until we have handed it over.
+#[cfg(feature = "consume")]
+struct StalledDelivery {
+    channel_id: u16,
+    consumer_tag: String,
+    message: ConsumerMessage,
 }
 
 struct Channel0Slot {
     common: ChannelSlot,
-    set_blocked_rx: MioReceiver<CrossbeamSender<ConnectionBlockedNotification>>,
+    set_blocked_rx: CrossbeamReceiver<CrossbeamSender<ConnectionBlockedNotification>>,
     blocked_tx: Option<CrossbeamSender<ConnectionBlockedNotification>>,
-    alloc_chan_req_rx: MioReceiver<Option<u16>>,
-    alloc_chan_rep_tx: CrossbeamSender<Result<IoLoopHandle>>,
+    // Why the server has blocked the connection, if it currently has; sent to new listeners.
+    blocked: Option<String>,
+    alloc_chan_req_rx: CrossbeamReceiver<AllocChannelRequest>,
 }
 
 impl Channel0Slot {
-    fn new(mio_channel_bound: usize) -> (Channel0Slot, IoLoopHandle0) {
-        let (common_slot, common_handle) = ChannelSlot::new(mio_channel_bound, 0);
-        let (alloc_chan_req_tx, alloc_chan_req_rx) = mio_sync_channel(1);
-        let (set_blocked_tx, set_blocked_rx) = mio_sync_channel(1);
-        let (alloc_chan_rep_tx, alloc_chan_rep_rx) = crossbeam_channel::bounded(1);
+    fn new(
+        mem_channel_bound: usize,
+        backpressure: Arc<AtomicBool>,
+        panic_slot: PanicSlot,
+        waker: &Arc<LoopWaker>,
+    ) -> (Channel0Slot, IoLoopHandle0) {
+        let (common_slot, common_handle) =
+            ChannelSlot::new(mem_channel_bound, 0, backpressure, panic_slot.clone(), waker);
+        let (alloc_chan_req_tx, alloc_chan_req_rx) = sync_channel(1, ALLOC_CHANNEL, waker);
+        let (set_blocked_tx, set_blocked_rx) = sync_channel(1, SET_BLOCKED_TX, waker);
 
         let slot = Channel0Slot {
             common: common_slot,
             set_blocked_rx,
             blocked_tx: None,
+            blocked: None,
             alloc_chan_req_rx,
-            alloc_chan_rep_tx,
         };
         let handle = IoLoopHandle0::new(
             common_handle,
             set_blocked_tx,
-            alloc_chan_req_tx,
-            alloc_chan_rep_rx,
+            ChannelAllocator::new(alloc_chan_req_tx, panic_slot),
         );
 
         (slot, handle)
     }
 }
 
+// Handle to a running I/O thread. The thread holds the sending half of `done` and never sends on
+// it, so `done` disconnects when the thread exits (whether it returns or panics); this lets us
+// wait for the thread with a timeout before committing to a join. A panic in the thread is
+// caught and returned from `join` as `EventLoopPanicked`.
+#[derive(Debug)]
+pub(crate) struct IoThread {
+    join_handle: JoinHandle<Result<()>>,
+    done: CrossbeamReceiver<()>,
+}
+
+impl IoThread {
+    fn spawn<F: FnOnce() -> Result<()> + Send + 'static>(
+        panic_slot: PanicSlot,
+        close_listeners: CloseListeners,
+        f: F,
+    ) -> Result<IoThread> {
+        let (done_tx, done) = crossbeam_channel::bounded(0);
+        // We're called from Connection::open on the caller's thread; the I/O thread's span is a
+        // child of whatever span the caller is in, and its spans and events go to the caller's
+        // subscriber even if that was only set for the caller's thread.
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!("amiquip_connection");
+        #[cfg(feature = "tracing")]
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        let join_handle = Builder::new()
+            .name("amiquip-io".to_string())
+            .spawn(move || {
+                #[cfg(feature = "tracing")]
+                let _dispatch = tracing::dispatcher::set_default(&dispatch);
+                #[cfg(feature = "tracing")]
+                let _span = span.entered();
+                let _done_tx: CrossbeamSender<()> = done_tx;
+                let result = panic_slot.run(f);
+                close_listeners.close(&result);
+                result
+            })
+            .context(ForkFailedSnafu)?;
+        Ok(IoThread { join_handle, done })
+    }
+
+    // Wait for the I/O thread to exit and return its result.
+    pub(crate) fn join(self) -> Result<()> {
+        // `PanicSlot::run` catches panics in the thread's body, so this only sees one from
+        // reporting its result.
+        self.join_handle
+            .join()
+            .map_err(|payload| Error::EventLoopPanicked {
+                message: panic_slot::payload_message(&*payload),
+                stack: String::new(),
+            })?
+    }
+
+    // True until the I/O thread exits for any reason.
+    pub(crate) fn is_running(&self) -> bool {
+        matches!(self.done.try_recv(), Err(crossbeam_channel::TryRecvError::Empty))
+    }
+
+    // Wait until `deadline` for the I/O thread to exit. If it does not, the thread is left
+    // running (detached) and we return `ShutdownTimeout`.
+    pub(crate) fn join_deadline(self, deadline: Instant) -> Result<()> {
+        match self.done.recv_deadline(deadline) {
+            Ok(()) => unreachable!("nothing is ever sent on the done channel"),
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => self.join(),
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                warn!("timed out waiting for I/O thread to exit; detaching it");
+                ShutdownTimeoutSnafu.fail()
+            }
+        }
+    }
+}
+
 pub(crate) struct IoLoop {
     poll: Poll,
     connection_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    // Set once the TCP connection is established if there is a handshake timeout; cleared when
+    // the handshake finishes.
+    handshake_deadline: Option<Instant>,
+    // Set when the handshake finishes, after which the connection timeout only applies while we
+    // have data waiting to be written.
+    handshake_done: bool,
     frame_buffer: FrameBuffer,
     inner: Inner,
+    close_listeners: CloseListeners,
+
+    // What the stream is registered with mio for, so we only reregister it when that changes.
+    stream_interest: Interest,
+    // Whether the stream has reported writable since a write last left data unwritten. While it
+    // has, we write everything queued at the end of each pass through the I/O loop without
+    // waiting for another event.
+    stream_writable: bool,
 
     // Bound for buffered outgoing writes. If we have more than this much data enqueued,
     // we will stop polling non-0 channels' requests for us to send more data.
@@ -146,114 +555,253 @@ pub(crate) struct IoLoop {
 
 impl IoLoop {
     pub(crate) fn new(tuning: ConnectionTuning) -> Result<Self> {
+        if tuning.missed_heartbeat_limit == 0 {
+            return InvalidMissedHeartbeatLimitSnafu.fail();
+        }
         let heartbeats = HeartbeatTimers::default();
 
         let poll = Poll::new().context(CreatePollHandleSnafu)?;
-        poll.register(
-            &heartbeats.timer,
-            HEARTBEAT,
-            Ready::readable(),
-            PollOpt::edge(),
-        )
-        .context(RegisterWithPollHandleSnafu)?;
+        let waker = LoopWaker::new(poll.registry()).context(RegisterWithPollHandleSnafu)?;
+
+        let frame_buffer = FrameBuffer::new(
+            if tuning.dump_malformed_frames {
+                Some(tuning.malformed_frame_window)
+            } else {
+                None
+            },
+            tuning.read_buffer_size,
+            tuning.shrink_buffer_above,
+        );
+        let counters = Arc::new(ConnectionCounters::new(frame_buffer.counters()));
 
         Ok(IoLoop {
             poll,
-            frame_buffer: FrameBuffer::new(),
-            inner: Inner::new(heartbeats, tuning.mem_channel_bound),
+            frame_buffer,
+            inner: Inner::new(
+                Arc::new(waker),
+                heartbeats,
+                tuning.missed_heartbeat_limit,
+                tuning.write_stall_timeout,
+                tuning.mem_channel_bound,
+                counters,
+            ),
+            close_listeners: CloseListeners::default(),
             buffered_writes_high_water: tuning.buffered_writes_high_water,
             buffered_writes_low_water: tuning.buffered_writes_low_water,
             connection_timeout: None,
+            connect_timeout: None,
+            handshake_timeout: None,
+            handshake_deadline: None,
+            handshake_done: false,
+            stream_interest: Interest::WRITABLE,
+            stream_writable: false,
         })
     }
 
+    pub(crate) fn frame_counters(&self) -> Arc<FrameCounters> {
+        self.frame_buffer.counters()
+    }
+
+    pub(crate) fn connection_counters(&self) -> Arc<ConnectionCounters> {
+        Arc::clone(&self.inner.counters)
+    }
+
+    pub(crate) fn channel_registry(&self) -> ChannelRegistry {
+        self.inner.channels.clone()
+    }
+
+    pub(crate) fn heartbeat_diagnostics(&self) -> SharedHeartbeatDiagnostics {
+        self.inner.heartbeats.diagnostics()
+    }
+
+    pub(crate) fn close_listeners(&self) -> CloseListeners {
+        self.close_listeners.clone()
+    }
+
+    pub(crate) fn stream_waker(&self) -> StreamWaker {
+        StreamWaker::new(&self.inner.waker)
+    }
+
     pub(crate) fn start<Auth: Sasl, S: IoStream>(
         mut self,
-        stream: S,
+        mut stream: S,
         mut options: ConnectionOptions<Auth>,
-    ) -> Result<(JoinHandle<Result<()>>, FieldTable, Channel0Handle)> {
+    ) -> Result<(IoThread, TuneOk, FieldTable, Channel0Handle)> {
         self.poll
-            .register(&stream, STREAM, Ready::writable(), PollOpt::edge())
+            .registry()
+            .register(&mut stream, STREAM, Interest::WRITABLE)
             .context(RegisterWithPollHandleSnafu)?;
+        self.stream_interest = Interest::WRITABLE;
 
         self.connection_timeout = options.connection_timeout.take();
+        self.connect_timeout = options.connect_timeout.take();
+        self.handshake_timeout = options.handshake_timeout.take();
+        self.inner.observer = options.observer.take();
+        self.frame_buffer.set_tap(options.frame_tap.clone());
+        self.inner.frame_tap = options.frame_tap.take();
+        #[cfg(feature = "chaos")]
+        {
+            self.inner.fault_injector = options.fault_injector.take();
+        }
         let (handshake_done_tx, handshake_done_rx) = crossbeam_channel::bounded(1);
-        let (ch0_slot, ch0_handle) = Channel0Slot::new(self.inner.mio_channel_bound);
+        let (ch0_slot, ch0_handle) = Channel0Slot::new(
+            self.inner.mem_channel_bound,
+            Arc::clone(&self.inner.backpressure),
+            self.inner.panic_slot.clone(),
+            &self.inner.waker,
+        );
+        let panic_slot = self.inner.panic_slot.clone();
+        let close_listeners = self.close_listeners.clone();
 
-        let join_handle = Builder::new()
-            .name("amiquip-io".to_string())
-            .spawn(move || self.thread_main(stream, options, handshake_done_tx, ch0_slot, false))
-            .context(ForkFailedSnafu)?;
+        let io_thread = IoThread::spawn(panic_slot, close_listeners, move || {
+            self.wait_for_connect(&mut stream, Interest::WRITABLE)?;
+            self.thread_main(stream, options, handshake_done_tx, ch0_slot, false)
+        })?;
 
-        IoLoop::wait_for_amqp_handshake(ch0_handle, join_handle, handshake_done_rx)
+        IoLoop::wait_for_amqp_handshake(ch0_handle, io_thread, handshake_done_rx)
     }
 
-    #[cfg(feature = "native-tls")]
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    #[allow(clippy::type_complexity)]
     pub(crate) fn start_tls<Auth: Sasl, S: HandshakeStream>(
         mut self,
-        stream: S,
+        mut stream: S,
         mut options: ConnectionOptions<Auth>,
-    ) -> Result<(JoinHandle<Result<()>>, FieldTable, Channel0Handle)> {
+    ) -> Result<(
+        IoThread,
+        TuneOk,
+        FieldTable,
+        Channel0Handle,
+        Option<Vec<u8>>,
+    )> {
         self.poll
-            .register(
-                &stream,
-                STREAM,
-                Ready::readable() | Ready::writable(),
-                PollOpt::edge(),
-            )
+            .registry()
+            .register(&mut stream, STREAM, Interest::READABLE | Interest::WRITABLE)
             .context(RegisterWithPollHandleSnafu)?;
+        self.stream_interest = Interest::READABLE | Interest::WRITABLE;
 
         self.connection_timeout = options.connection_timeout.take();
+        self.connect_timeout = options.connect_timeout.take();
+        self.handshake_timeout = options.handshake_timeout.take();
+        self.inner.observer = options.observer.take();
+        self.frame_buffer.set_tap(options.frame_tap.clone());
+        self.inner.frame_tap = options.frame_tap.take();
+        #[cfg(feature = "chaos")]
+        {
+            self.inner.fault_injector = options.fault_injector.take();
+        }
         let (handshake_done_tx, handshake_done_rx) = crossbeam_channel::bounded(1);
-        let (ch0_slot, ch0_handle) = Channel0Slot::new(self.inner.mio_channel_bound);
+        let (ch0_slot, ch0_handle) = Channel0Slot::new(
+            self.inner.mem_channel_bound,
+            Arc::clone(&self.inner.backpressure),
+            self.inner.panic_slot.clone(),
+            &self.inner.waker,
+        );
+        let panic_slot = self.inner.panic_slot.clone();
+        let close_listeners = self.close_listeners.clone();
+
+        let (peer_certificate_tx, peer_certificate_rx) = crossbeam_channel::bounded(1);
+
+        let io_thread = IoThread::spawn(panic_slot, close_listeners, move || {
+            self.wait_for_connect(&mut stream, Interest::READABLE | Interest::WRITABLE)?;
+            self.thread_main_tls(
+                stream,
+                options,
+                peer_certificate_tx,
+                handshake_done_tx,
+                ch0_slot,
+            )
+        })?;
 
-        let join_handle = Builder::new()
-            .name("amiquip-io".to_string())
-            .spawn(move || self.thread_main_tls(stream, options, handshake_done_tx, ch0_slot))
-            .context(ForkFailedSnafu)?;
+        let (io_thread, tune_ok, server_properties, channel0) =
+            IoLoop::wait_for_amqp_handshake(ch0_handle, io_thread, handshake_done_rx)?;
+        // Sent before the AMQP handshake started, so it is here if the handshake succeeded.
+        let peer_certificate = peer_certificate_rx.try_recv().unwrap_or(None);
+        Ok((
+            io_thread,
+            tune_ok,
+            server_properties,
+            channel0,
+            peer_certificate,
+        ))
+    }
 
-        IoLoop::wait_for_amqp_handshake(ch0_handle, join_handle, handshake_done_rx)
+    // Wait (up to the connect timeout, if there is one) for the stream to report that it is
+    // connected, then start the handshake timeout. `interest` must match the stream's current
+    // registration; we reregister with it so the readiness we consumed here is reported again.
+    // (Streams that wake us through the `LoopWaker` instead are left to be collected by the loop.)
+    fn wait_for_connect<S: Source>(&mut self, stream: &mut S, interest: Interest) -> Result<()> {
+        if let Some(timeout) = self.connect_timeout {
+            let deadline = Instant::now() + timeout;
+            let mut events = Events::with_capacity(8);
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    return ConnectTimeoutSnafu.fail();
+                }
+                poll(&mut self.poll, &mut events, Some(deadline - now))?;
+                if events.iter().any(|event| event.token() == STREAM)
+                    || self.inner.waker.is_ready(STREAM)
+                {
+                    break;
+                }
+            }
+            self.poll
+                .registry()
+                .reregister(stream, STREAM, interest)
+                .context(RegisterWithPollHandleSnafu)?;
+        }
+        self.handshake_deadline = self
+            .handshake_timeout
+            .map(|timeout| Instant::now() + timeout);
+        Ok(())
     }
 
     fn wait_for_amqp_handshake(
         ch0_handle: IoLoopHandle0,
-        join_handle: JoinHandle<Result<()>>,
-        handshake_done_rx: CrossbeamReceiver<(usize, FieldTable)>,
-    ) -> Result<(JoinHandle<Result<()>>, FieldTable, Channel0Handle)> {
+        io_thread: IoThread,
+        handshake_done_rx: CrossbeamReceiver<(TuneOk, FieldTable)>,
+    ) -> Result<(IoThread, TuneOk, FieldTable, Channel0Handle)> {
         match handshake_done_rx.recv() {
-            Ok((frame_max, server_properties)) => Ok((
-                join_handle,
-                server_properties,
-                Channel0Handle::new(ch0_handle, frame_max),
-            )),
+            Ok((tune_ok, server_properties)) => {
+                let frame_max = tune_ok.frame_max as usize;
+                Ok((
+                    io_thread,
+                    tune_ok,
+                    server_properties,
+                    Channel0Handle::new(ch0_handle, frame_max),
+                ))
+            }
 
             // If sender was dropped without sending, the I/O thread has failed; peel out
             // its final error.
-            Err(_) => match join_handle.join() {
-                Ok(Ok(())) => {
+            Err(_) => match io_thread.join() {
+                Ok(()) => {
                     unreachable!("I/O thread ended successfully without completing handshake")
                 }
-                Ok(Err(err)) => Err(err),
-                Err(_) => IoThreadPanicSnafu.fail(),
+                Err(err) => Err(err),
             },
         }
     }
 
-    #[cfg(feature = "native-tls")]
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
     fn thread_main_tls<Auth: Sasl, S: HandshakeStream>(
         mut self,
         stream: S,
         options: ConnectionOptions<Auth>,
-        handshake_done_tx: crossbeam_channel::Sender<(usize, FieldTable)>,
+        peer_certificate_tx: crossbeam_channel::Sender<Option<Vec<u8>>>,
+        handshake_done_tx: crossbeam_channel::Sender<(TuneOk, FieldTable)>,
         ch0_slot: Channel0Slot,
     ) -> Result<()> {
         trace!("starting TLS handshake");
         let stream = self.run_tls_handshake(stream)?;
         trace!("finished TLS handshake");
+        // Can only fail if the connection has already been given up on.
+        let _ = peer_certificate_tx.send(S::peer_certificate(&stream));
         self.thread_main(stream, options, handshake_done_tx, ch0_slot, true)
     }
 
-    #[cfg(feature = "native-tls")]
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
     fn run_tls_handshake<S: HandshakeStream>(&mut self, mut stream: S) -> Result<S::Stream> {
         let mut state = None;
         self.run_io_loop(
@@ -275,38 +823,23 @@ impl IoLoop {
         mut self,
         mut stream: S,
         options: ConnectionOptions<Auth>,
-        handshake_done_tx: crossbeam_channel::Sender<(usize, FieldTable)>,
-        ch0_slot: Channel0Slot,
+        handshake_done_tx: crossbeam_channel::Sender<(TuneOk, FieldTable)>,
+        mut ch0_slot: Channel0Slot,
         have_written_to_socket: bool,
     ) -> Result<()> {
-        self.poll
-            .register(
-                &ch0_slot.common.rx,
-                Token(0),
-                Ready::readable(),
-                PollOpt::edge(),
-            )
-            .context(RegisterWithPollHandleSnafu)?;
-        self.poll
-            .register(
-                &ch0_slot.set_blocked_rx,
-                SET_BLOCKED_TX,
-                Ready::readable(),
-                PollOpt::edge(),
-            )
-            .context(RegisterWithPollHandleSnafu)?;
-        self.poll
-            .register(
-                &ch0_slot.alloc_chan_req_rx,
-                ALLOC_CHANNEL,
-                Ready::readable(),
-                PollOpt::edge(),
-            )
-            .context(RegisterWithPollHandleSnafu)?;
-        let (tune_ok, server_properties) =
+        let (tune_ok, server_properties, blocked) =
             self.run_amqp_handshake(&mut stream, options, have_written_to_socket)?;
+        if let Some(observer) = &self.inner.observer {
+            observer.on_handshake_complete(&tune_ok);
+        }
+        ch0_slot.blocked = blocked;
+        self.frame_buffer.set_frame_max(tune_ok.frame_max);
+        #[cfg(feature = "consume")]
+        {
+            self.inner.body_frame_max = channel_handle::body_frame_max(tune_ok.frame_max as usize);
+        }
         let channel_max = tune_ok.channel_max;
-        match handshake_done_tx.send((tune_ok.frame_max as usize, server_properties)) {
+        match handshake_done_tx.send((tune_ok, server_properties)) {
             Ok(_) => (),
             Err(_) => return Ok(()),
         }
@@ -319,7 +852,8 @@ impl IoLoop {
         stream: &mut S,
         options: ConnectionOptions<Auth>,
         have_written_to_socket: bool,
-    ) -> Result<(TuneOk, FieldTable)> {
+    ) -> Result<(TuneOk, FieldTable, Option<String>)> {
+        enter_span!(DEBUG, "handshake");
         let mut state = HandshakeState::Start(options);
         let result = self.run_io_loop(
             stream,
@@ -334,22 +868,38 @@ impl IoLoop {
                 // If our credentials are bad, the socket is dropped without a message,
                 // but we can detect that if we had gotten up to the Secure state before
                 // failing.
-                return match state {
-                    HandshakeState::Secure(_, _) => InvalidCredentialsSnafu.fail(),
-                    _ => Err(err),
+                return match (state, err) {
+                    (
+                        HandshakeState::Secure(_, _),
+                        Error::UnexpectedSocketClose
+                        | Error::IoErrorReadingSocket { .. }
+                        | Error::IoErrorWritingSocket { .. },
+                    ) => InvalidCredentialsSnafu.fail(),
+                    // TLS streams report the server rejecting our client certificate this way.
+                    (_, Error::IoErrorReadingSocket { source })
+                        if source.kind() == io::ErrorKind::PermissionDenied =>
+                    {
+                        InvalidCredentialsSnafu.fail()
+                    }
+                    (_, err) => Err(err),
                 };
             }
         }
-        self.connection_timeout = None;
+        self.handshake_done = true;
+        self.handshake_deadline = None;
         match state {
             HandshakeState::Start(_)
             | HandshakeState::Secure(_, _)
             | HandshakeState::Tune(_, _)
-            | HandshakeState::Open(_, _) => unreachable!(),
-            HandshakeState::Done(tune_ok, server_properties) => Ok((tune_ok, server_properties)),
+            | HandshakeState::Open(_, _, _) => unreachable!(),
+            HandshakeState::Done(tune_ok, server_properties, blocked) => {
+                Ok((tune_ok, server_properties, blocked))
+            }
             HandshakeState::ServerClosing(close) => ServerClosedConnectionSnafu {
                 code: close.reply_code,
                 message: close.reply_text,
+                reply_code: AmqpReplyCode::from_code(close.reply_code),
+                method: method_name(close.class_id, close.method_id),
             }
             .fail(),
         }
@@ -359,14 +909,14 @@ impl IoLoop {
         &mut self,
         stream: &mut S,
         state: &mut HandshakeState<Auth>,
-        event: Event,
+        event: IoEvent,
     ) -> Result<()> {
-        match event.token() {
+        match event.token {
             STREAM => {
-                if event.readiness().is_writable() {
+                if event.writable {
                     self.inner.write_to_stream(stream)?;
                 }
-                if event.readiness().is_readable() {
+                if event.readable {
                     self.inner.read_from_stream(
                         stream,
                         &mut self.frame_buffer,
@@ -385,8 +935,8 @@ impl IoLoop {
             HandshakeState::Start(_)
             | HandshakeState::Secure(_, _)
             | HandshakeState::Tune(_, _)
-            | HandshakeState::Open(_, _) => false,
-            HandshakeState::Done(_, _) => true,
+            | HandshakeState::Open(_, _, _) => false,
+            HandshakeState::Done(_, _, _) => true,
             HandshakeState::ServerClosing(_) => {
                 // server initiated a close (e.g., bad vhost). don't report that we're
                 // done until all our writes have gone out
@@ -417,6 +967,8 @@ impl IoLoop {
             ConnectionState::ServerClosing(close) => ServerClosedConnectionSnafu {
                 code: close.reply_code,
                 message: close.reply_text,
+                reply_code: AmqpReplyCode::from_code(close.reply_code),
+                method: method_name(close.class_id, close.method_id),
             }
             .fail(),
             ConnectionState::ClientException => ClientExceptionSnafu.fail(),
@@ -428,52 +980,61 @@ impl IoLoop {
         &mut self,
         stream: &mut S,
         state: &mut ConnectionState,
-        event: Event,
+        event: IoEvent,
     ) -> Result<()> {
-        match event.token() {
+        match event.token {
             STREAM => {
-                if event.readiness().is_writable() {
+                if event.writable {
                     self.inner.write_to_stream(stream)?;
                 }
-                if event.readiness().is_readable() {
-                    self.inner.read_from_stream(
+                if event.readable {
+                    let result = self.inner.read_from_stream(
                         stream,
                         &mut self.frame_buffer,
                         |inner, frame| state.process(inner, frame),
-                    )?;
+                    );
+                    match (result, &state) {
+                        // Servers typically close the socket as soon as they send close-ok;
+                        // once we've received it, EOF is expected.
+                        (Err(Error::UnexpectedSocketClose), ConnectionState::ClientClosed) => {
+                            debug!("server closed socket after close-ok")
+                        }
+                        (Err(err @ Error::ReceivedFrameTooLarge { .. }), _) => {
+                            self.inner.close_with_frame_error(stream, &err);
+                            return Err(err);
+                        }
+                        (result, _) => result?,
+                    }
                 }
             }
             HEARTBEAT => self.inner.process_heartbeat_timers()?,
+            #[cfg(feature = "consume")]
+            ACK_FLUSH => self.inner.process_ack_timer(),
+            // Once the ch0 slot is dropped, channel 0's handles may still wake us (e.g., as they
+            // are dropped); there is nothing left to read.
             SET_BLOCKED_TX => match state {
                 ConnectionState::Steady(ch0_slot) => self.handle_set_blocked_tx(ch0_slot)?,
                 ConnectionState::ServerClosing(_)
                 | ConnectionState::ClientException
-                | ConnectionState::ClientClosed => {
-                    unreachable!("ch0 slot cannot be readable after it is dropped")
-                }
+                | ConnectionState::ClientClosed => (),
             },
             ALLOC_CHANNEL => match &state {
-                ConnectionState::Steady(ch0_slot) => {
-                    self.inner.allocate_channel(ch0_slot, &self.poll)?
-                }
+                ConnectionState::Steady(ch0_slot) => self.inner.allocate_channel(ch0_slot)?,
                 ConnectionState::ServerClosing(_)
                 | ConnectionState::ClientException
-                | ConnectionState::ClientClosed => {
-                    unreachable!("ch0 slot cannot be readable after it is dropped")
-                }
+                | ConnectionState::ClientClosed => (),
             },
-            Token(0) => match &state {
+            Token(0) => match state {
                 ConnectionState::Steady(ch0_slot) => {
                     self.inner.handle_channel0_readable(ch0_slot)?
                 }
                 ConnectionState::ServerClosing(_)
                 | ConnectionState::ClientException
-                | ConnectionState::ClientClosed => {
-                    unreachable!("ch0 slot cannot be readable after it is dropped")
-                }
+                | ConnectionState::ClientClosed => (),
             },
-            Token(n) if n <= u16::max_value() as usize => {
-                self.inner.handle_channel_readable(n as u16)?
+            Token(n) if n <= u16::MAX as usize => {
+                let high_water = self.buffered_writes_high_water;
+                self.inner.handle_channel_readable(n as u16, high_water)?
             }
             _ => unreachable!(),
         }
@@ -487,6 +1048,10 @@ impl IoLoop {
                 Err(TryRecvError::Empty) => return Ok(()),
                 Err(TryRecvError::Disconnected) => return EventLoopClientDroppedSnafu.fail(),
             };
+            if let Some(reason) = &ch0_slot.blocked {
+                // Ignore failure; the listener may already have been dropped.
+                let _ = tx.send(ConnectionBlockedNotification::Blocked(reason.clone()));
+            }
             ch0_slot.blocked_tx = Some(tx);
         }
     }
@@ -506,6 +1071,56 @@ impl IoLoop {
         }
     }
 
+    // The connection timeout in force for the next poll. Once the handshake completes, it only
+    // applies while we have data waiting to be written, so an idle steady-state connection polls
+    // without a timeout; the heartbeat timers (if heartbeats are enabled) are what detect a dead
+    // peer then.
+    fn connection_timeout(&self) -> Option<Duration> {
+        if self.handshake_done && !self.inner.has_data_to_write() {
+            None
+        } else {
+            self.connection_timeout
+        }
+    }
+
+    // How long the next poll may wait, failing if the handshake or a write has already taken too
+    // long.
+    fn poll_timeout(&mut self, now: Instant) -> Result<Option<Duration>> {
+        self.inner.check_write_stall(now)?;
+        if let Some(deadline) = self.handshake_deadline {
+            if now >= deadline {
+                return HandshakeTimeoutSnafu.fail();
+            }
+        }
+        let deadline = [
+            self.handshake_deadline,
+            self.inner.write_stall_deadline(),
+            self.inner.heartbeats.timer.next_deadline(),
+            #[cfg(feature = "consume")]
+            self.inner.ack_timer.next_deadline(),
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied();
+        let mut remaining = deadline.map(|deadline| deadline.saturating_duration_since(now));
+        if let Some(shutdown) = &self.inner.shutdown {
+            let until_deadline = shutdown.deadline.saturating_duration_since(now);
+            let check = SHUTDOWN_CHECK_INTERVAL.min(until_deadline);
+            remaining = Some(remaining.map_or(check, |r| r.min(check)));
+        }
+        #[cfg(feature = "consume")]
+        let remaining = if self.inner.is_delivery_stalled() || self.inner.is_draining_held() {
+            Some(remaining.map_or(STALLED_DELIVERY_RETRY, |r| r.min(STALLED_DELIVERY_RETRY)))
+        } else {
+            remaining
+        };
+        Ok(match (self.connection_timeout(), remaining) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        })
+    }
+
     fn run_io_loop<State, S, F, G>(
         &mut self,
         stream: &mut S,
@@ -515,8 +1130,8 @@ impl IoLoop {
         is_done: G,
     ) -> Result<()>
     where
-        S: Evented,
-        F: FnMut(&mut Self, &mut S, &mut State, Event) -> Result<()>,
+        S: Source,
+        F: FnMut(&mut Self, &mut S, &mut State, IoEvent) -> Result<()>,
         G: Fn(&Self, &State) -> bool,
     {
         // Since we're called multiple times (to run TLS handshake, then AMQP handshake,
@@ -535,25 +1150,27 @@ impl IoLoop {
         if self.inner.has_data_to_write() && have_written_to_socket {
             trace!("reregistering socket for readable or writable");
             self.poll
-                .reregister(
-                    stream,
-                    STREAM,
-                    Ready::readable() | Ready::writable(),
-                    PollOpt::edge(),
-                )
+                .registry()
+                .reregister(stream, STREAM, Interest::READABLE | Interest::WRITABLE)
                 .context(RegisterWithPollHandleSnafu)?;
+            self.stream_interest = Interest::READABLE | Interest::WRITABLE;
         }
 
         let mut events = Events::with_capacity(128);
+        let mut ready = Vec::new();
         let mut listening_to_channels = true;
         loop {
             let start_poll = Instant::now();
-            self.poll
-                .poll(&mut events, self.connection_timeout)
-                .context(FailedToPollSnafu)?;
-            if events.is_empty() {
-                if let Some(timeout) = &self.connection_timeout {
-                    if start_poll.elapsed() > *timeout {
+            let timeout = self.poll_timeout(start_poll)?;
+            poll(&mut self.poll, &mut events, timeout)?;
+            self.collect_events(&events, &mut ready);
+            if ready.is_empty()
+                && !self.inner.is_delivery_stalled()
+                && !self.inner.is_draining_held()
+                && self.inner.shutdown.is_none()
+            {
+                if let Some(timeout) = self.connection_timeout() {
+                    if start_poll.elapsed() > timeout {
                         return ConnectionTimeoutSnafu.fail();
                     }
                 }
@@ -561,9 +1178,58 @@ impl IoLoop {
             }
 
             let had_data_to_write = self.inner.has_data_to_write();
+            enter_span!(
+                TRACE,
+                "poll",
+                events = ready.len(),
+                outbuf_len = self.inner.outbuf.len()
+            );
+
+            for mut event in ready.drain(..) {
+                // Writing waits until the end of the pass (below), so everything the events
+                // handled here queue goes out together.
+                if event.token == STREAM && event.writable {
+                    self.stream_writable = true;
+                    event.writable = false;
+                    if !event.readable {
+                        continue;
+                    }
+                }
+                handle_event(self, stream, state, event)?;
+            }
 
-            for event in events.iter() {
+            // If we stopped reading because a consumer's buffer was full, no event will tell us
+            // when to start again (and data the socket already reported may be waiting); try
+            // again every time around.
+            if self.inner.is_delivery_stalled() {
+                let event = IoEvent {
+                    token: STREAM,
+                    readable: true,
+                    writable: false,
+                };
+                handle_event(self, stream, state, event)?;
+            }
+            #[cfg(feature = "consume")]
+            self.inner.drain_held_deliveries();
+
+            self.inner
+                .counters
+                .set_outbuf_depth(self.inner.outbuf.len());
+
+            self.inner.check_shutdown(Instant::now());
+
+            // Write what every channel that woke us (up to the high water mark; see
+            // handle_channel_readable) and everything above queued with as few writes as the
+            // stream will take. As long as the last write took everything, the stream is still
+            // writable, and we don't wait for it to tell us so again.
+            if self.stream_writable && self.inner.has_data_to_write() {
+                let event = IoEvent {
+                    token: STREAM,
+                    readable: false,
+                    writable: true,
+                };
                 handle_event(self, stream, state, event)?;
+                self.stream_writable = !self.inner.has_data_to_write();
             }
 
             if is_done(self, state) {
@@ -576,77 +1242,541 @@ impl IoLoop {
             // buffered_writes_low_water.
             if listening_to_channels && self.inner.outbuf.len() > self.buffered_writes_high_water {
                 debug!("passed high water mark for buffered writes; blocking channels internally",);
-                self.inner.deregister_nonzero_channels(&self.poll)?;
+                self.inner.deregister_nonzero_channels();
                 listening_to_channels = false;
             } else if !listening_to_channels
                 && self.inner.outbuf.len() <= self.buffered_writes_low_water
             {
                 debug!("returned below low water mark for buffered writes; resuming channels",);
-                self.inner.reregister_nonzero_channels(&self.poll)?;
+                self.inner.reregister_nonzero_channels();
                 listening_to_channels = true;
             }
 
-            // If we have data to write, reregister for readable|writable. This may be a
-            // spurious reregistration, but also may not - if we wrote all the data we have
-            // but didn't get a WouldBlock, and then later in the processing loop added
-            // more data to write but didn't write it, mio won't wake us back up again next
-            // pass unless we reregister.
+            // If we still have data to write, the stream stopped taking it (or we have yet to
+            // hear that it is writable), so we need to be registered for readable|writable;
+            // mio will tell us when it can take more. Registering for writable when we already
+            // are is unnecessary: the last write hit WouldBlock, and mio reports the stream
+            // becoming writable again.
             //
             // If we don't have data to write, only reregister for readable (without
             // writable) if we had data to write after the last poll; otherwise we know
             // we were already registered as readable only and don't need to rereg.
-            if self.inner.has_data_to_write() && have_written_to_socket {
-                trace!("reregistering socket for readable or writable");
-                self.poll
-                    .reregister(
-                        stream,
-                        STREAM,
-                        Ready::readable() | Ready::writable(),
-                        PollOpt::edge(),
-                    )
-                    .context(RegisterWithPollHandleSnafu)?;
+            let interest = if self.inner.has_data_to_write() && have_written_to_socket {
+                Interest::READABLE | Interest::WRITABLE
             } else if had_data_to_write {
-                trace!("reregistering socket for readable only");
                 have_written_to_socket = true;
+                Interest::READABLE
+            } else {
+                self.stream_interest
+            };
+            if interest != self.stream_interest {
+                trace!("reregistering socket for {:?}", interest);
                 self.poll
-                    .reregister(stream, STREAM, Ready::readable(), PollOpt::edge())
+                    .registry()
+                    .reregister(stream, STREAM, interest)
                     .context(RegisterWithPollHandleSnafu)?;
+                self.stream_interest = interest;
+            }
+        }
+    }
+
+    // Gather what to handle this time around the loop into `ready`: what mio reported, what was
+    // woken through the `LoopWaker`, and timers that are due.
+    fn collect_events(&mut self, events: &Events, ready: &mut Vec<IoEvent>) {
+        ready.extend(
+            events
+                .iter()
+                .filter(|event| event.token() != WAKER)
+                .map(IoEvent::from_mio),
+        );
+        ready.extend(self.inner.waker.take_ready().into_iter().map(IoEvent::woken));
+        let now = Instant::now();
+        if is_due(self.inner.heartbeats.timer.next_deadline(), now) {
+            ready.push(IoEvent::woken(HEARTBEAT));
+        }
+        #[cfg(feature = "consume")]
+        {
+            if is_due(self.inner.ack_timer.next_deadline(), now) {
+                ready.push(IoEvent::woken(ACK_FLUSH));
             }
         }
     }
 }
 
+fn is_due(deadline: Option<Instant>, now: Instant) -> bool {
+    deadline.is_some_and(|deadline| deadline <= now)
+}
+
+// mio 0.6 retried polls interrupted by a signal itself; mio 0.8 reports them, as if nothing were
+// ready.
+fn poll(poll: &mut Poll, events: &mut Events, timeout: Option<Duration>) -> Result<()> {
+    match poll.poll(events, timeout) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+            events.clear();
+            Ok(())
+        }
+        Err(err) => Err(err).context(FailedToPollSnafu),
+    }
+}
+
 struct Inner {
     // Buffer of data waiting to be written. May contain multiple serialized frames.
     // Once we've appended a connection Close or CloseOk, it will be sealed (so any
     // future writes will be silently discarded).
     outbuf: SealableOutputBuffer,
 
+    // Wakes us for the channels client handles send on, and for streams mio does not poll.
+    waker: Arc<LoopWaker>,
+
     // Handle to I/O loop timers for tracking rx/tx heartbeats.
     heartbeats: HeartbeatTimers,
 
+    // Fires when a channel's batched acks have been held for as long as they may be; carries the
+    // channel id.
+    #[cfg(feature = "consume")]
+    ack_timer: Timer<u16>,
+
+    // Number of heartbeat intervals the server may miss before we give up on it.
+    missed_heartbeat_limit: u32,
+
+    // How long data may sit in `outbuf` without any of it being written before we give up on
+    // the socket, and when we last made progress (or first queued data) if `outbuf` is nonempty.
+    write_stall_timeout: Option<Duration>,
+    unflushed_since: Option<Instant>,
+
+    // Set if the stream is holding data of its own that it could not write yet (e.g., encrypted
+    // TLS records); we keep asking to hear about writability until it has been flushed.
+    stream_needs_flush: bool,
+
     // Slots for open channels. Channel 0 should be here once handshake is done.
     chan_slots: ChannelSlots<ChannelSlot>,
 
+    // The most body we may put in one content body frame, once the handshake is done; for
+    // messages we publish ourselves (see `RedeliveryPolicy`).
+    #[cfg(feature = "consume")]
+    body_frame_max: usize,
+
     // Bound for in-memory channels that send to our I/O thread. (Channels going _from_
     // the I/O thread are unbounded to prevent blocking the I/O thread on slow receviers.)
-    mio_channel_bound: usize,
+    mem_channel_bound: usize,
 
-    // If true, non-0 channels are registered with mio. (Channel 0 is always registered.)
+    // If true, we read from non-0 channels when they wake us. (Channel 0 is always read.)
     channels_are_registered: bool,
+
+    // The inverse of `channels_are_registered`, shared with every channel handle so it can tell
+    // whether a message would have to wait for the output buffer to drain (see `try_publish`).
+    backpressure: Arc<AtomicBool>,
+    // Shared with every handle; see `PanicSlot`.
+    panic_slot: PanicSlot,
+
+    // Traffic counters, shared with the connection handle (see `Connection::stats`).
+    counters: Arc<ConnectionCounters>,
+    // Every channel we have allocated, shared with the connection handle (see
+    // `Connection::channels`).
+    channels: ChannelRegistry,
+
+    // The user's observer, if they set one; see `ConnectionObserver`.
+    observer: Option<ObserverHandle>,
+    // The user's frame tap, if they set one, for frames we write. (The frame buffer taps frames
+    // we read.)
+    frame_tap: Option<FrameTapHandle>,
+
+    // Test hooks for simulating network faults, and the number of frames received so far (which
+    // is passed to them).
+    #[cfg(feature = "chaos")]
+    fault_injector: Option<FaultInjectorHandle>,
+    #[cfg(feature = "chaos")]
+    frames_received: u64,
+    #[cfg(feature = "chaos")]
+    wrote_to_socket: bool,
+
+    // A delivery we could not hand to its consumer yet; we don't read from the socket while we
+    // have one.
+    #[cfg(feature = "consume")]
+    stalled_delivery: Option<StalledDelivery>,
+    // Resumed consumers that still have deliveries held from while they were paused, to be handed
+    // over as they make room.
+    #[cfg(feature = "consume")]
+    draining_held: HashSet<(u16, String)>,
+
+    // Set while a graceful shutdown is waiting for outstanding work to finish.
+    shutdown: Option<Shutdown>,
+    // Consumers we cancelled for a graceful shutdown; their cancel-oks are not for the channel
+    // handle.
+    #[cfg(feature = "consume")]
+    shutdown_cancels: HashSet<(u16, String)>,
+    // Likewise for consumers we cancelled because their callback panicked.
+    #[cfg(feature = "consume")]
+    panicked_cancels: HashSet<(u16, String)>,
 }
 
 impl Inner {
-    fn new(heartbeats: HeartbeatTimers, mio_channel_bound: usize) -> Self {
+    fn new(
+        waker: Arc<LoopWaker>,
+        heartbeats: HeartbeatTimers,
+        missed_heartbeat_limit: u32,
+        write_stall_timeout: Option<Duration>,
+        mem_channel_bound: usize,
+        counters: Arc<ConnectionCounters>,
+    ) -> Self {
         Inner {
             outbuf: SealableOutputBuffer::new(OutputBuffer::with_protocol_header()),
+            waker,
             heartbeats,
+            #[cfg(feature = "consume")]
+            ack_timer: Timer::default(),
+            missed_heartbeat_limit,
+            write_stall_timeout,
+            unflushed_since: None,
+            stream_needs_flush: false,
             chan_slots: ChannelSlots::new(),
-            mio_channel_bound,
+            #[cfg(feature = "consume")]
+            body_frame_max: channel_handle::body_frame_max(0),
+            mem_channel_bound,
             channels_are_registered: true,
+            backpressure: Arc::new(AtomicBool::new(false)),
+            panic_slot: PanicSlot::default(),
+            counters,
+            channels: ChannelRegistry::default(),
+            observer: None,
+            frame_tap: None,
+            #[cfg(feature = "chaos")]
+            fault_injector: None,
+            #[cfg(feature = "chaos")]
+            frames_received: 0,
+            #[cfg(feature = "chaos")]
+            wrote_to_socket: false,
+            #[cfg(feature = "consume")]
+            stalled_delivery: None,
+            #[cfg(feature = "consume")]
+            draining_held: HashSet::new(),
+            shutdown: None,
+            #[cfg(feature = "consume")]
+            shutdown_cancels: HashSet::new(),
+            #[cfg(feature = "consume")]
+            panicked_cancels: HashSet::new(),
+        }
+    }
+
+    #[inline]
+    fn is_delivery_stalled(&self) -> bool {
+        #[cfg(feature = "consume")]
+        {
+            self.stalled_delivery.is_some()
+        }
+        #[cfg(not(feature = "consume"))]
+        {
+            false
         }
     }
 
+    #[inline]
+    fn is_draining_held(&self) -> bool {
+        #[cfg(feature = "consume")]
+        {
+            !self.draining_held.is_empty()
+        }
+        #[cfg(not(feature = "consume"))]
+        {
+            false
+        }
+    }
+
+    // Pause or resume dispatching deliveries to a consumer. Deliveries that arrive while it is
+    // paused are held; once resumed, they are handed over as the consumer makes room for them,
+    // before any that arrive later.
+    #[cfg(feature = "consume")]
+    fn set_consumer_paused(&mut self, channel_id: u16, consumer_tag: String, paused: bool) {
+        let consumer = match self
+            .chan_slots
+            .get_mut(channel_id)
+            .and_then(|slot| slot.consumers.get_mut(&consumer_tag))
+        {
+            Some(consumer) => consumer,
+            // The consumer has already ended; there is nothing to pause.
+            None => return,
+        };
+        debug!(
+            "{} consumer {} on channel {}",
+            if paused { "pausing" } else { "resuming" },
+            consumer_tag,
+            channel_id
+        );
+        consumer.paused = paused;
+        if !paused && !consumer.drain_held() {
+            self.draining_held.insert((channel_id, consumer_tag));
+        }
+    }
+
+    #[cfg(feature = "consume")]
+    fn cancel_panicked_consumer(
+        &mut self,
+        channel_id: u16,
+        consumer_tag: String,
+        in_flight: Option<u64>,
+    ) {
+        let slot = match self.chan_slots.get_mut(channel_id) {
+            Some(slot) => slot,
+            None => return,
+        };
+        if let Some(delivery_tag) = in_flight {
+            // The callback may have settled it (singly or along with a later one) before
+            // panicking.
+            if slot.unacked.contains(delivery_tag) {
+                debug!(
+                    "requeueing delivery {} on channel {} after its callback panicked",
+                    delivery_tag, channel_id
+                );
+                slot.unacked.settle(delivery_tag, false);
+                self.push_method(
+                    channel_id,
+                    AmqpBasic::Nack(Nack {
+                        delivery_tag,
+                        multiple: false,
+                        requeue: true,
+                    }),
+                );
+            }
+        }
+        // unwrap is safe: we found the slot above, and nothing since has removed it.
+        let slot = self.chan_slots.get(channel_id).unwrap();
+        // The server or the client may have cancelled it already.
+        if !slot.consumers.contains_key(&consumer_tag) {
+            return;
+        }
+        debug!(
+            "cancelling consumer {} on channel {} after its callback panicked",
+            consumer_tag, channel_id
+        );
+        self.push_method(
+            channel_id,
+            AmqpBasic::Cancel(Cancel {
+                consumer_tag: consumer_tag.clone(),
+                nowait: false,
+            }),
+        );
+        self.panicked_cancels.insert((channel_id, consumer_tag));
+    }
+
+    #[cfg(feature = "consume")]
+    fn drain_held_deliveries(&mut self) {
+        let chan_slots = &mut self.chan_slots;
+        self.draining_held.retain(|(channel_id, consumer_tag)| {
+            chan_slots
+                .get_mut(*channel_id)
+                .and_then(|slot| slot.consumers.get_mut(consumer_tag))
+                .is_some_and(|consumer| !consumer.drain_held())
+        });
+    }
+
+    // Hand over the stalled delivery if its consumer has made room for it. A consumer that has
+    // gone away (or been dropped along with its channel) no longer needs it.
+    #[cfg(feature = "consume")]
+    fn retry_stalled_delivery(&mut self) {
+        let stalled = match self.stalled_delivery.take() {
+            Some(stalled) => stalled,
+            None => return,
+        };
+        let consumer = self
+            .chan_slots
+            .get(stalled.channel_id)
+            .and_then(|slot| slot.consumers.get(&stalled.consumer_tag));
+        match consumer {
+            Some(consumer) if consumer.is_full() => self.stalled_delivery = Some(stalled),
+            Some(consumer) => {
+                trace!(
+                    "consumer {} on channel {} has room again; resuming reads",
+                    stalled.consumer_tag,
+                    stalled.channel_id
+                );
+                if let Err(err) = consumer.tx.try_send(stalled.message) {
+                    debug!(
+                        "discarding message for dropped consumer: {:?}",
+                        err.into_inner()
+                    );
+                }
+            }
+            None => debug!(
+                "discarding stalled delivery for consumer {} on channel {}, which is gone",
+                stalled.consumer_tag, stalled.channel_id
+            ),
+        }
+    }
+
+    // Add an ack to the ones we are holding for `channel_id`, sending them all if there are now
+    // `max_count` of them. The first ack held starts the clock on `max_delay`.
+    #[cfg(feature = "consume")]
+    fn hold_ack(
+        &mut self,
+        channel_id: u16,
+        delivery_tag: u64,
+        max_count: usize,
+        max_delay: Duration,
+    ) {
+        // unwrap is safe here, because we can only be called if we just
+        // received a message from this slot.
+        let slot = self.chan_slots.get_mut(channel_id).unwrap();
+        let ack_timer = &mut self.ack_timer;
+        let pending = slot.pending_ack.get_or_insert_with(|| PendingAck {
+            delivery_tag,
+            count: 0,
+            timeout: ack_timer.set_timeout(max_delay, channel_id),
+        });
+        pending.delivery_tag = u64::max(pending.delivery_tag, delivery_tag);
+        pending.count += 1;
+        if pending.count >= max_count {
+            self.flush_ack(channel_id);
+        }
+    }
+
+    #[cfg(feature = "consume")]
+    fn flush_ack(&mut self, channel_id: u16) {
+        let pending = match self
+            .chan_slots
+            .get_mut(channel_id)
+            .and_then(|slot| slot.pending_ack.take())
+        {
+            Some(pending) => pending,
+            None => return,
+        };
+        self.ack_timer.cancel_timeout(&pending.timeout);
+        if let Some(slot) = self.chan_slots.get_mut(channel_id) {
+            slot.unacked.settle(pending.delivery_tag, true);
+        }
+        trace!(
+            "sending {} batched acks on channel {} (through delivery tag {})",
+            pending.count,
+            channel_id,
+            pending.delivery_tag
+        );
+        self.push_method(
+            channel_id,
+            AmqpBasic::Ack(Ack {
+                delivery_tag: pending.delivery_tag,
+                multiple: true,
+            }),
+        );
+    }
+
+    #[cfg(feature = "consume")]
+    fn flush_all_acks(&mut self) {
+        let channel_ids = self
+            .chan_slots
+            .iter()
+            .filter(|(_, slot)| slot.pending_ack.is_some())
+            .map(|(channel_id, _)| *channel_id)
+            .collect::<Vec<_>>();
+        for channel_id in channel_ids {
+            self.flush_ack(channel_id);
+        }
+    }
+
+    // A timeout may outlive the acks it was set for if its channel has since been closed; the
+    // channel is then either gone or has nothing (or newer acks) pending, which can safely be
+    // sent early.
+    #[cfg(feature = "consume")]
+    fn process_ack_timer(&mut self) {
+        while let Some(channel_id) = self.ack_timer.poll() {
+            self.flush_ack(channel_id);
+        }
+    }
+
+    // Cancel every consumer and start waiting for the connection to go quiet; see
+    // `check_shutdown`. Acks we are holding are sent now rather than when they are due.
+    fn start_shutdown(&mut self, deadline: Instant, tx: CrossbeamSender<ShutdownReport>) {
+        debug!("starting graceful shutdown");
+        #[cfg(feature = "consume")]
+        {
+            self.flush_all_acks();
+            let mut cancels = Vec::new();
+            for (channel_id, slot) in self.chan_slots.iter() {
+                // We may not send anything else on a channel we are closing.
+                if !slot.closing {
+                    for consumer_tag in slot.consumers.keys() {
+                        cancels.push((*channel_id, consumer_tag.clone()));
+                    }
+                }
+            }
+            for (channel_id, consumer_tag) in cancels {
+                trace!(
+                    "cancelling consumer {} on channel {} for shutdown",
+                    consumer_tag,
+                    channel_id
+                );
+                self.push_method(
+                    channel_id,
+                    AmqpBasic::Cancel(Cancel {
+                        consumer_tag: consumer_tag.clone(),
+                        nowait: false,
+                    }),
+                );
+                self.shutdown_cancels.insert((channel_id, consumer_tag));
+            }
+        }
+        self.shutdown = Some(Shutdown { deadline, tx });
+    }
+
+    // Send the report for a graceful shutdown once there is nothing left to wait for: every
+    // consumer we cancelled is gone, every publish has been written (including any still in the
+    // channels' mailboxes, which we are not reading while the channels are deregistered), and
+    // every publish in confirm mode has been confirmed. Or, failing that, once the deadline
+    // passes.
+    fn check_shutdown(&mut self, now: Instant) {
+        let deadline = match &self.shutdown {
+            Some(shutdown) => shutdown.deadline,
+            None => return,
+        };
+        #[cfg(feature = "consume")]
+        self.flush_all_acks();
+        let report = self.shutdown_report();
+        let done =
+            report.is_complete() && !self.has_data_to_write() && self.channels_are_registered;
+        if !done && now < deadline {
+            return;
+        }
+        if done {
+            debug!("graceful shutdown finished");
+        } else {
+            warn!("graceful shutdown timed out: {:?}", report);
+        }
+        // unwrap is safe; we checked above.
+        let shutdown = self.shutdown.take().unwrap();
+        // Ignore failure; the caller will find out the I/O thread is gone some other way.
+        let _ = shutdown.tx.send(report);
+    }
+
+    fn shutdown_report(&self) -> ShutdownReport {
+        let mut report = ShutdownReport {
+            unflushed_publishes: self.outbuf.unwritten_publishes(),
+            ..ShutdownReport::default()
+        };
+        #[cfg(feature = "consume")]
+        {
+            // A consumer is gone once we have its cancel-ok, or if the server cancelled it or
+            // its channel closed in the meantime.
+            report.uncancelled_consumers = self
+                .shutdown_cancels
+                .iter()
+                .filter(|(channel_id, consumer_tag)| {
+                    self.chan_slots
+                        .get(*channel_id)
+                        .is_some_and(|slot| slot.consumers.contains_key(consumer_tag))
+                })
+                .count();
+        }
+        for (_, slot) in self.chan_slots.iter() {
+            if let Some(unconfirmed) = &slot.unconfirmed {
+                report.unconfirmed_publishes += unconfirmed.count();
+            }
+            #[cfg(feature = "consume")]
+            {
+                report.unacked_deliveries += slot.unacked.count();
+            }
+        }
+        report
+    }
+
     #[inline]
     fn are_writes_sealed(&self) -> bool {
         self.outbuf.is_sealed()
@@ -660,60 +1790,123 @@ impl Inner {
 
     #[inline]
     fn push_method<M: IntoAmqpClass>(&mut self, channel_id: u16, method: M) {
-        self.outbuf.push_method(channel_id, method)
+        let mut buf = OutputBuffer::empty();
+        buf.push_method(channel_id, method);
+        self.append(channel_id, buf);
+    }
+
+    // Queue frames for channel `channel_id`, counting them toward its stats.
+    fn append(&mut self, channel_id: u16, buf: OutputBuffer) {
+        if self.outbuf.is_sealed() {
+            return;
+        }
+        if let Some(slot) = self.chan_slots.get(channel_id) {
+            slot.counters.record_sent(buf.counts());
+        }
+        self.outbuf.append(buf);
     }
 
     #[inline]
     fn start_heartbeats(&mut self, interval: u16) {
         if interval > 0 {
             debug!("starting heartbeat timers ({} sec)", interval);
-            self.heartbeats
-                .start(Duration::from_secs(u64::from(interval)));
+            self.heartbeats.start(
+                Duration::from_secs(u64::from(interval)),
+                self.missed_heartbeat_limit,
+            );
         }
     }
 
     #[inline]
     fn has_data_to_write(&self) -> bool {
-        !self.outbuf.is_empty()
+        !self.outbuf.is_empty() || self.stream_needs_flush
     }
 
-    fn deregister_nonzero_channels(&mut self, poll: &Poll) -> Result<()> {
-        for (_, slot) in self.chan_slots.iter() {
-            poll.deregister(&slot.rx)
-                .context(DeregisterWithPollHandleSnafu)?;
+    // Fail if queued data has gone unwritten for longer than the write stall timeout.
+    fn check_write_stall(&mut self, now: Instant) -> Result<()> {
+        if self.outbuf.is_empty() {
+            self.unflushed_since = None;
+            return Ok(());
+        }
+        let since = *self.unflushed_since.get_or_insert(now);
+        if let Some(timeout) = self.write_stall_timeout {
+            let stalled_for = now.saturating_duration_since(since);
+            if stalled_for >= timeout {
+                error!(
+                    "unable to write to socket for {:?} - closing connection",
+                    stalled_for
+                );
+                return WriteStalledSnafu { stalled_for }.fail();
+            }
         }
-        self.channels_are_registered = false;
         Ok(())
     }
 
-    fn reregister_nonzero_channels(&mut self, poll: &Poll) -> Result<()> {
-        for (id, slot) in self.chan_slots.iter() {
-            poll.reregister(
-                &slot.rx,
-                Token(*id as usize),
-                Ready::readable(),
-                PollOpt::edge(),
-            )
-            .context(RegisterWithPollHandleSnafu)?;
+    // When `check_write_stall` will fail if no more data is written, if ever.
+    fn write_stall_deadline(&self) -> Option<Instant> {
+        match (self.unflushed_since, self.write_stall_timeout) {
+            (Some(since), Some(timeout)) => Some(since + timeout),
+            _ => None,
+        }
+    }
+
+    // Wakeups for non-0 channels are ignored until they are reregistered.
+    fn deregister_nonzero_channels(&mut self) {
+        self.channels_are_registered = false;
+        self.backpressure.store(true, Ordering::SeqCst);
+    }
+
+    // Wake every channel, since we may have ignored messages that arrived while they were
+    // deregistered.
+    fn reregister_nonzero_channels(&mut self) {
+        for (id, _) in self.chan_slots.iter() {
+            if *id != 0 {
+                self.waker.wake(Token(*id as usize));
+            }
         }
         self.channels_are_registered = true;
-        Ok(())
+        self.backpressure.store(false, Ordering::SeqCst);
     }
 
     fn process_heartbeat_timers(&mut self) -> Result<()> {
         while let Some(kind) = self.heartbeats.timer.poll() {
+            #[cfg(feature = "chaos")]
+            {
+                if let Some(injector) = &self.fault_injector {
+                    injector.on_heartbeat().context(IoErrorWritingSocketSnafu)?;
+                }
+            }
+            // We aren't reading while a delivery is stalled, so the server's silence is ours.
+            if kind == HeartbeatKind::Rx && self.is_delivery_stalled() {
+                self.heartbeats.defer_rx_deadline();
+            }
             match kind {
                 HeartbeatKind::Rx => match self.heartbeats.fire_rx() {
                     HeartbeatState::StillRunning => {
                         trace!("rx heartbeat timer fired, but have received data since last");
                     }
+                    HeartbeatState::Missed(missed) => {
+                        let silent_for = self.heartbeats.since_last_rx();
+                        warn!(
+                            "missed {} heartbeat(s) from server (last data received {:?} ago)",
+                            missed, silent_for
+                        );
+                        if let Some(observer) = &self.observer {
+                            observer.on_heartbeat_warning(silent_for);
+                        }
+                    }
                     HeartbeatState::Expired => {
-                        error!("missed heartbeats from server - closing connection");
-                        return MissedServerHeartbeatsSnafu.fail();
+                        let since_last_rx = self.heartbeats.since_last_rx();
+                        error!(
+                            "missed heartbeats from server (last data received {:?} ago) - closing connection",
+                            since_last_rx
+                        );
+                        return MissedServerHeartbeatsSnafu { since_last_rx }.fail();
                     }
                 },
                 HeartbeatKind::Tx => match self.heartbeats.fire_tx() {
-                    HeartbeatState::StillRunning => {
+                    // The tx heartbeat has a missed limit of 1, so it never reports a miss.
+                    HeartbeatState::StillRunning | HeartbeatState::Missed(_) => {
                         trace!("tx heartbeat timer fired, but have sent data since last");
                     }
                     HeartbeatState::Expired => {
@@ -722,6 +1915,11 @@ impl Inner {
                         if self.outbuf.is_empty() {
                             debug!("sending heartbeat");
                             self.outbuf.push_heartbeat();
+                            self.counters.record_heartbeat_sent();
+                            self.heartbeats.record_heartbeat_sent();
+                            if let Some(observer) = &self.observer {
+                                observer.on_heartbeat_sent();
+                            }
                         } else {
                             warn!("tx heartbeat fired, but already have queued data to write - possible socket problem");
                         }
@@ -732,19 +1930,26 @@ impl Inner {
         Ok(())
     }
 
-    fn handle_channel0_readable(&mut self, ch0_slot: &Channel0Slot) -> Result<()> {
+    fn handle_channel0_readable(&mut self, ch0_slot: &mut Channel0Slot) -> Result<()> {
         loop {
-            match ch0_slot.common.rx.try_recv() {
-                Ok(message) => self.process_channel_message(0, message)?,
-                Err(TryRecvError::Empty) => return Ok(()),
-                Err(TryRecvError::Disconnected) => return EventLoopClientDroppedSnafu.fail(),
+            match ch0_slot.common.rx.next(true) {
+                Next::Message(message) => self.process_channel_message(0, message)?,
+                Next::Empty => return Ok(()),
+                Next::Disconnected => return EventLoopClientDroppedSnafu.fail(),
             }
         }
     }
 
-    fn handle_channel_readable(&mut self, channel_id: u16) -> Result<()> {
+    // Stops taking messages from the channel's regular mailbox (leaving them there) once more
+    // than `high_water` bytes are waiting to be written; otherwise a publisher that refills the
+    // mailbox as fast as we empty it would keep us here well past the high water mark. The main
+    // loop deregisters the channels in that case, so we wake ourselves to come back once they are
+    // reregistered (or next time around, if the output buffer has already drained). Urgent
+    // messages are taken regardless (see `channel_mailbox`).
+    fn handle_channel_readable(&mut self, channel_id: u16, high_water: usize) -> Result<()> {
         loop {
-            let slot = match self.chan_slots.get(channel_id) {
+            let take_regular = self.channels_are_registered && self.outbuf.len() <= high_water;
+            let slot = match self.chan_slots.get_mut(channel_id) {
                 Some(slot) => slot,
                 None => {
                     // We've been asked to poll a receiver for a channel we dropped; this
@@ -757,29 +1962,100 @@ impl Inner {
                     return Ok(());
                 }
             };
-            match slot.rx.try_recv() {
-                Ok(message) => self.process_channel_message(channel_id, message)?,
-                Err(TryRecvError::Empty) => return Ok(()),
-                Err(TryRecvError::Disconnected) => return EventLoopClientDroppedSnafu.fail(),
+            match slot.rx.next(take_regular) {
+                Next::Message(message) => self.process_channel_message(channel_id, message)?,
+                Next::Empty => break,
+                // The handle of a channel we are closing may go away before the server
+                // acknowledges the close.
+                Next::Disconnected if slot.closing => return Ok(()),
+                // The handle was dropped without closing the channel (and without managing to
+                // ask us to, if its urgent mailbox was full); close it ourselves.
+                Next::Disconnected => {
+                    connection_state::close_dropped_channel(self, channel_id);
+                    return Ok(());
+                }
             }
         }
+        if self.channels_are_registered && self.outbuf.len() > high_water {
+            self.waker.wake(Token(channel_id as usize));
+        }
+        Ok(())
     }
 
     fn process_channel_message(&mut self, channel_id: u16, message: IoLoopMessage) -> Result<()> {
+        // We've already sent a close for a closing channel, after which we may not send anything
+        // else on it. The channel's handle has been given an error (or is gone) and cannot see
+        // replies anymore, so there is no one to answer.
+        if self
+            .chan_slots
+            .get(channel_id)
+            .is_some_and(|slot| slot.closing)
+        {
+            trace!("discarding message for closing channel {}", channel_id);
+            return Ok(());
+        }
+        #[cfg(feature = "consume")]
+        {
+            // Anything the client sends after acking may depend on the ack having been sent
+            // first (e.g., a channel close).
+            match message {
+                IoLoopMessage::BatchedAck(delivery_tag, max_count, max_delay) => {
+                    self.hold_ack(channel_id, delivery_tag, max_count, max_delay);
+                    return Ok(());
+                }
+                IoLoopMessage::ConnectionClose(_) => self.flush_all_acks(),
+                _ => self.flush_ack(channel_id),
+            }
+        }
         match message {
             IoLoopMessage::ConnectionClose(buf) => {
+                if let Some(observer) = &self.observer {
+                    if let Ok((_, AMQPFrame::Method(_, class))) = parse_frame(&buf[0..]) {
+                        observer.check_close(&class);
+                    }
+                }
                 self.outbuf.append(buf);
                 self.seal_writes();
             }
             IoLoopMessage::Send(buf) => {
-                self.outbuf.append(buf);
+                if let Some(slot) = self.chan_slots.get_mut(channel_id) {
+                    if let Some(unconfirmed) = &mut slot.unconfirmed {
+                        unconfirmed.record_publishes(buf.counts().publishes);
+                    }
+                }
+                self.append(channel_id, buf);
+            }
+            #[cfg(feature = "consume")]
+            IoLoopMessage::Settle(buf, delivery_tag, multiple) => {
+                if let Some(slot) = self.chan_slots.get_mut(channel_id) {
+                    slot.unacked.settle(delivery_tag, multiple);
+                }
+                self.append(channel_id, buf);
             }
+            IoLoopMessage::Call(buf, deadline)
+            | IoLoopMessage::ChannelClose(buf, Some(deadline)) => {
+                if deadline.has_passed() {
+                    // The caller may have stopped waiting, but it still counts on exactly one
+                    // reply per call; answer in place of the server.
+                    debug!(
+                        "channel {} call exceeded its deadline before being sent",
+                        channel_id
+                    );
+                    // unwrap is safe here, because we can only be called if we just
+                    // received a message from this slot (and channel 0 calls carry no deadline).
+                    let slot = self.chan_slots.get_mut(channel_id).unwrap();
+                    let _ = slot.tx.try_send(Err(Error::DeadlineExceeded));
+                } else {
+                    self.append(channel_id, buf);
+                }
+            }
+            IoLoopMessage::ChannelClose(buf, None) => self.append(channel_id, buf),
             IoLoopMessage::SetReturnHandler(handler) => {
                 assert!(channel_id != 0, "channel 0 cannot have a return handler");
                 // unwrap is safe here, because we can only be called if we just
                 // received a message from this slot.
                 let slot = self.chan_slots.get_mut(channel_id).unwrap();
-                slot.return_handler = handler;
+                slot.return_listener.register(handler, &slot.counters);
             }
             IoLoopMessage::SetPubConfirmHandler(handler) => {
                 assert!(channel_id != 0, "channel 0 cannot have a return handler");
@@ -788,43 +2064,112 @@ impl Inner {
                 let slot = self.chan_slots.get_mut(channel_id).unwrap();
                 slot.pub_confirm_handler = handler;
             }
+            IoLoopMessage::AwaitConfirm(seq_no, tx) => {
+                assert!(channel_id != 0, "channel 0 cannot publish");
+                // unwrap is safe here, because we can only be called if we just
+                // received a message from this slot.
+                let slot = self.chan_slots.get_mut(channel_id).unwrap();
+                slot.confirm_waiters.insert(seq_no, tx);
+            }
+            IoLoopMessage::SetPublishResultHandler(handler, first_seq_no) => {
+                assert!(channel_id != 0, "channel 0 cannot publish");
+                // unwrap is safe here, because we can only be called if we just
+                // received a message from this slot.
+                let slot = self.chan_slots.get_mut(channel_id).unwrap();
+                slot.publish_results.set_listener(handler, first_seq_no);
+            }
+            #[cfg(feature = "consume")]
+            IoLoopMessage::Consume(buf, deadline, buffer) => {
+                // unwrap is safe here, because we can only be called if we just
+                // received a message from this slot.
+                let slot = self.chan_slots.get_mut(channel_id).unwrap();
+                match deadline {
+                    Some(deadline) if deadline.has_passed() => {
+                        // See `Call` above.
+                        debug!(
+                            "channel {} call exceeded its deadline before being sent",
+                            channel_id
+                        );
+                        let _ = slot.tx.try_send(Err(Error::DeadlineExceeded));
+                    }
+                    _ => {
+                        slot.pending_consumers.push_back(buffer);
+                        self.append(channel_id, buf);
+                    }
+                }
+            }
+            #[cfg(feature = "consume")]
+            IoLoopMessage::ConsumeNowait(buf, consumer_tag, consumer) => {
+                // unwrap is safe here, because we can only be called if we just
+                // received a message from this slot.
+                let slot = self.chan_slots.get_mut(channel_id).unwrap();
+                match slot.consumers.entry(consumer_tag) {
+                    Entry::Occupied(entry) => {
+                        return DuplicateConsumerTagSnafu {
+                            channel_id,
+                            consumer_tag: entry.key().clone(),
+                        }
+                        .fail();
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(consumer);
+                        slot.consumers_changed();
+                        self.append(channel_id, buf);
+                    }
+                }
+            }
+            IoLoopMessage::CloseDropped => {
+                assert!(channel_id != 0, "channel 0 is not closed by dropping it");
+                connection_state::close_dropped_channel(self, channel_id);
+            }
+            #[cfg(feature = "consume")]
+            IoLoopMessage::CancelPanicked(consumer_tag, in_flight) => {
+                self.cancel_panicked_consumer(channel_id, consumer_tag, in_flight);
+            }
+            #[cfg(feature = "consume")]
+            IoLoopMessage::PauseConsumer(consumer_tag, paused, done) => {
+                self.set_consumer_paused(channel_id, consumer_tag, paused);
+                let _ = done.send(());
+            }
+            IoLoopMessage::Shutdown(deadline, tx) => {
+                assert!(channel_id == 0, "only channel 0 can shut down the connection");
+                self.start_shutdown(deadline, tx);
+            }
+            #[cfg(feature = "consume")]
+            IoLoopMessage::BatchedAck(..) => unreachable!("batched acks are held above"),
+            #[cfg(test)]
+            IoLoopMessage::Panic(message) => panic!("{}", message),
         }
         Ok(())
     }
 
-    fn allocate_channel(&mut self, ch0_slot: &Channel0Slot, poll: &Poll) -> Result<()> {
+    fn allocate_channel(&mut self, ch0_slot: &Channel0Slot) -> Result<()> {
         loop {
-            let new_channel_id = match ch0_slot.alloc_chan_req_rx.try_recv() {
-                Ok(new_channel_id) => new_channel_id,
+            let (new_channel_id, reply_tx) = match ch0_slot.alloc_chan_req_rx.try_recv() {
+                Ok(request) => request,
                 Err(TryRecvError::Empty) => return Ok(()),
                 Err(TryRecvError::Disconnected) => return EventLoopClientDroppedSnafu.fail(),
             };
 
-            let mio_channel_bound = self.mio_channel_bound;
-            let channels_are_registered = self.channels_are_registered;
+            let mem_channel_bound = self.mem_channel_bound;
+            let backpressure = &self.backpressure;
+            let panic_slot = &self.panic_slot;
+            let waker = &self.waker;
+            let channels = &self.channels;
             let result = self.chan_slots.insert(new_channel_id, |new_channel_id| {
-                let (slot, handle) = ChannelSlot::new(mio_channel_bound, new_channel_id);
-                poll.register(
-                    &slot.rx,
-                    Token(new_channel_id as usize),
-                    Ready::readable(),
-                    PollOpt::edge(),
-                )
-                .context(RegisterWithPollHandleSnafu)?;
-                if !channels_are_registered {
-                    // If we're currently in a deregistered state (i.e., too much data to
-                    // write), go ahead and deregister this new channel. We do the register+
-                    // deregister dance so we can call reregister on this new channel even
-                    // though it hadn't existed when we deregistered all the existing
-                    // channels.
-                    poll.deregister(&slot.rx)
-                        .context(DeregisterWithPollHandleSnafu)?;
-                }
+                let (slot, handle) = ChannelSlot::new(
+                    mem_channel_bound,
+                    new_channel_id,
+                    Arc::clone(backpressure),
+                    panic_slot.clone(),
+                    waker,
+                );
+                channels.register(&slot.counters);
                 Ok((slot, handle))
             });
             // safe to unwrap the get() here because we wouldn't be in this method
             // at all if we didn't have a slot that just received this message.
-            match ch0_slot.alloc_chan_rep_tx.send(result) {
+            match reply_tx.send(result) {
                 Ok(()) => (),
                 Err(SendError(Ok(handle))) => {
                     // send failed - clear the allocated channel
@@ -837,6 +2182,26 @@ impl Inner {
         }
     }
 
+    // The spec requires a peer that receives an oversized frame to close the connection with
+    // FRAME_ERROR. We can't keep reading (the rest of the frame is still on the socket), so send
+    // the close with a single best-effort write and give up on the connection.
+    fn close_with_frame_error<S: IoStream>(&mut self, stream: &mut S, err: &Error) {
+        error!("{} - closing connection", err);
+        if !self.are_writes_sealed() {
+            let close = ConnectionClose {
+                reply_code: AmqpReplyCode::FrameError.code(),
+                reply_text: format!("FRAME_ERROR - {}", err),
+                class_id: 0,
+                method_id: 0,
+            };
+            self.push_method(0, AmqpConnection::Close(close));
+            self.seal_writes();
+        }
+        if let Err(err) = self.write_to_stream(stream) {
+            debug!("failed to send connection close: {}", err);
+        }
+    }
+
     fn read_from_stream<S, F>(
         &mut self,
         stream: &mut S,
@@ -847,9 +2212,55 @@ impl Inner {
         S: IoStream,
         F: FnMut(&mut Inner, AMQPFrame) -> Result<()>,
     {
-        let n = frame_buffer.read_from(stream, |frame| {
+        #[cfg(feature = "consume")]
+        self.retry_stalled_delivery();
+        // Checked by the frame buffer before each frame; see `StalledDelivery`.
+        let paused = Cell::new(self.is_delivery_stalled());
+        let is_paused = || paused.get();
+        let n = frame_buffer.read_from(stream, is_paused, |frame| {
             trace!("read frame {:?}", frame);
-            handler(self, frame)
+            if let AMQPFrame::Method(
+                channel_id,
+                AMQPClass::Basic(AmqpBasic::Deliver(_)) | AMQPClass::Basic(AmqpBasic::GetOk(_)),
+            ) = &frame
+            {
+                self.counters.record_delivery();
+                if let Some(slot) = self.chan_slots.get(*channel_id) {
+                    slot.counters.record_delivery();
+                }
+            }
+            if let AMQPFrame::Heartbeat(_) = frame {
+                self.heartbeats.record_heartbeat_received();
+            }
+            let observed = match (&self.observer, &frame) {
+                (Some(observer), AMQPFrame::Method(channel_id, class)) => {
+                    observer.check_close(class);
+                    let (class_id, method_id) = method_ids(class);
+                    Some((*channel_id, class_id, method_id))
+                }
+                (Some(observer), AMQPFrame::Heartbeat(_)) => {
+                    observer.on_heartbeat_received();
+                    None
+                }
+                _ => None,
+            };
+            handler(self, frame)?;
+            paused.set(self.is_delivery_stalled());
+            if let (Some(observer), Some((channel_id, class_id, method_id))) =
+                (&self.observer, observed)
+            {
+                observer.on_frame_read(channel_id, class_id, method_id);
+            }
+            #[cfg(feature = "chaos")]
+            {
+                self.frames_received += 1;
+                if let Some(injector) = &self.fault_injector {
+                    injector
+                        .after_read(self.frames_received)
+                        .context(IoErrorReadingSocketSnafu)?;
+                }
+            }
+            Ok(())
         })?;
         if n > 0 {
             self.heartbeats.record_rx_activity();
@@ -858,31 +2269,178 @@ impl Inner {
     }
 
     fn write_to_stream<S: IoStream>(&mut self, stream: &mut S) -> Result<()> {
-        let len = self.outbuf.len();
-        let mut pos = 0;
-
-        // Keep writing until we've written all len bytes or we hit WouldBlock.
-        while pos < len {
-            trace!("trying to write {} bytes", len - pos);
-            let n = match stream.write(&self.outbuf[pos..]) {
+        enter_span!(TRACE, "write", outbuf_len = self.outbuf.len());
+        // Keep writing until we've written everything or we hit WouldBlock.
+        while !self.outbuf.is_empty() {
+            trace!("trying to write {} bytes", self.outbuf.len());
+            let n = match self.write_some(stream) {
+                // We only write with data to write, so the stream can take no more, ever.
+                Ok(0) => return UnexpectedSocketCloseSnafu.fail(),
                 Ok(n) => {
                     trace!("wrote {} bytes", n);
                     self.heartbeats.record_tx_activity();
+                    self.unflushed_since = Some(Instant::now());
                     n
                 }
                 Err(err) => match err.kind() {
-                    io::ErrorKind::WouldBlock => {
-                        self.outbuf.drain_written(pos);
-                        return Ok(());
-                    }
+                    io::ErrorKind::WouldBlock => return Ok(()),
+                    io::ErrorKind::Interrupted => continue,
                     _ => return Err(err).context(IoErrorWritingSocketSnafu),
                 },
             };
-            pos += n;
+            let observer = &self.observer;
+            let frame_tap = &self.frame_tap;
+            let mut counts = FrameCounts::default();
+            self.outbuf.drain_written(n, |chunk| {
+                counts.add(chunk.counts());
+                if let Some(frame_tap) = frame_tap {
+                    for_each_frame(chunk, |bytes| {
+                        frame_tap.tap_bytes(FrameDirection::Tx, bytes)
+                    });
+                }
+                if let Some(observer) = observer {
+                    for_each_method_frame(chunk, |channel_id, class_id, method_id| {
+                        observer.on_frame_written(channel_id, class_id, method_id)
+                    });
+                }
+            });
+            self.counters.record_write(n, counts);
+        }
+        // Everything we had has been accepted by the stream; make sure it reaches the socket.
+        // Streams that buffer internally report WouldBlock here until they have caught up.
+        let flushed = loop {
+            match stream.flush() {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result,
+            }
+        };
+        match flushed {
+            Ok(()) => self.stream_needs_flush = false,
+            Err(err) => match err.kind() {
+                io::ErrorKind::WouldBlock => self.stream_needs_flush = true,
+                _ => return Err(err).context(IoErrorWritingSocketSnafu),
+            },
         }
-
-        // Wrote everything we have - use clear instead of .drain_written().
-        self.outbuf.clear();
         Ok(())
     }
+
+    #[cfg(not(feature = "chaos"))]
+    #[inline]
+    fn write_some<S: IoStream>(&mut self, stream: &mut S) -> io::Result<usize> {
+        stream.write_bufs(&self.outbuf.io_slices(usize::MAX))
+    }
+
+    #[cfg(feature = "chaos")]
+    fn write_some<S: IoStream>(&mut self, stream: &mut S) -> io::Result<usize> {
+        let pending = self.outbuf.len();
+        let limit = match &self.fault_injector {
+            Some(injector) => match injector.before_write(pending) {
+                Ok(limit) => usize::min(limit, pending),
+                // Until we've written to the socket once, the I/O loop doesn't reregister it
+                // for writable (see run_io_loop), so a fake WouldBlock would stall it forever.
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::WouldBlock && !self.wrote_to_socket =>
+                {
+                    pending
+                }
+                // A socket reports writable again once it has room after a WouldBlock; ours
+                // never ran out of room, so nothing would tell us to try again. Wake ourselves
+                // instead, as the socket would.
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.waker.wake(STREAM);
+                    return Err(err);
+                }
+                Err(err) => return Err(err),
+            },
+            None => pending,
+        };
+        let n = stream.write_bufs(&self.outbuf.io_slices(limit))?;
+        self.wrote_to_socket = true;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio::Registry;
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+
+    // Returns scripted results from writes and flushes; other results are never expected.
+    #[derive(Default)]
+    struct ScriptedStream {
+        writes: VecDeque<io::Result<usize>>,
+        flushes: VecDeque<io::Result<()>>,
+    }
+
+    impl Read for ScriptedStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            unreachable!()
+        }
+    }
+
+    impl Write for ScriptedStream {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            self.writes.pop_front().expect("unexpected write")
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes.pop_front().unwrap_or(Ok(()))
+        }
+    }
+
+    impl Source for ScriptedStream {
+        fn register(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn deregister(&mut self, _: &Registry) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl IoStream for ScriptedStream {}
+
+    fn interrupted<T>() -> io::Result<T> {
+        Err(io::ErrorKind::Interrupted.into())
+    }
+
+    // A new I/O loop has the 8 byte protocol header waiting to be written.
+    fn inner() -> Inner {
+        let inner = IoLoop::new(ConnectionTuning::default()).unwrap().inner;
+        assert_eq!(inner.outbuf.len(), 8);
+        inner
+    }
+
+    #[test]
+    fn interrupted_writes_and_flushes_are_retried() {
+        let mut inner = inner();
+        let mut stream = ScriptedStream {
+            writes: vec![interrupted(), Ok(3), interrupted(), Ok(5)].into(),
+            flushes: vec![interrupted()].into(),
+        };
+        inner.write_to_stream(&mut stream).unwrap();
+        assert!(stream.writes.is_empty());
+        assert!(stream.flushes.is_empty());
+        assert!(!inner.has_data_to_write());
+    }
+
+    #[test]
+    fn zero_byte_write_is_unexpected_socket_close() {
+        let mut inner = inner();
+        let mut stream = ScriptedStream {
+            writes: vec![Ok(0)].into(),
+            ..ScriptedStream::default()
+        };
+        match inner.write_to_stream(&mut stream) {
+            Err(Error::UnexpectedSocketClose) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(inner.outbuf.len(), 8);
+    }
 }
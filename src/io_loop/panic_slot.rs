@@ -0,0 +1,146 @@
+use crate::errors::*;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Once};
+
+static INSTALL_HOOK: Once = Once::new();
+
+thread_local! {
+    // Set for the lifetime of an I/O thread; the panic hook records into it.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static CURRENT: RefCell<Option<PanicSlot>> = RefCell::new(None);
+}
+
+// Where an I/O thread records why it panicked. Every handle shares it, so a call that finds the
+// thread gone can report the panic instead of `EventLoopDropped`.
+//
+// Without `install_panic_hook`, the panic is recorded from `catch_unwind`, after unwinding has
+// dropped the thread's end of every channel; a caller woken by that may get there first and see
+// `EventLoopDropped`. The hook records it before unwinding starts (and is also the only place a
+// backtrace of the panic itself can be captured).
+#[derive(Debug, Clone, Default)]
+pub(super) struct PanicSlot(Arc<Mutex<Option<(String, String)>>>);
+
+impl PanicSlot {
+    // Run `f` (the body of an I/O thread), converting a panic into `EventLoopPanicked`.
+    pub(super) fn run<F: FnOnce() -> Result<()>>(&self, f: F) -> Result<()> {
+        CURRENT.with(|current| *current.borrow_mut() = Some(self.clone()));
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        match result {
+            Ok(result) => result,
+            Err(payload) => {
+                // Already set if our hook is installed; otherwise the message is all we have.
+                self.set(payload_message(&*payload), String::new());
+                Err(self.dropped_error())
+            }
+        }
+    }
+
+    // Keeps the first panic recorded (a panic during unwinding would otherwise replace it).
+    fn set(&self, message: String, stack: String) {
+        let mut slot = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        if slot.is_none() {
+            *slot = Some((message, stack));
+        }
+    }
+
+    // The error for a caller that found the I/O thread gone.
+    pub(super) fn dropped_error(&self) -> Error {
+        match &*self.0.lock().unwrap_or_else(|err| err.into_inner()) {
+            Some((message, stack)) => Error::EventLoopPanicked {
+                message: message.clone(),
+                stack: stack.clone(),
+            },
+            None => Error::EventLoopDropped,
+        }
+    }
+}
+
+/// Install a process-wide panic hook that records panics on amiquip's I/O threads.
+///
+/// By default, a panic on an I/O thread is reported as
+/// [`Error::EventLoopPanicked`](enum.Error.html#variant.EventLoopPanicked) with the panic message
+/// and an empty `stack`, and a call that is waiting on the I/O thread when it panics may fail
+/// with [`Error::EventLoopDropped`](enum.Error.html#variant.EventLoopDropped) instead. After this
+/// is called, the panic is recorded before the thread unwinds, so every such call reports it,
+/// and `message` includes the panic's location and `stack` a backtrace captured where it
+/// happened.
+///
+/// The hook wraps whatever hook is installed when this is called (see
+/// [`std::panic::set_hook`](https://doc.rust-lang.org/std/panic/fn.set_hook.html)) and calls it
+/// for every panic, on any thread. If you install your own hook, do so before calling this, or
+/// make it call the one it replaces. Calling this more than once has no further effect.
+pub fn install_panic_hook() {
+    INSTALL_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let _ = CURRENT.try_with(|current| {
+                if let Some(slot) = &*current.borrow() {
+                    let message = match info.location() {
+                        Some(location) => {
+                            format!("{} at {}", payload_message(info.payload()), location)
+                        }
+                        None => payload_message(info.payload()),
+                    };
+                    slot.set(message, Backtrace::force_capture().to_string());
+                }
+            });
+            previous(info);
+        }));
+    });
+}
+
+pub(super) fn payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "(non-string panic payload)".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn records_panic_message_and_backtrace() {
+        install_panic_hook();
+        let slot = PanicSlot::default();
+        let thread_slot = slot.clone();
+        let err = thread::spawn(move || thread_slot.run(|| panic!("boom {}", 1)))
+            .join()
+            .unwrap()
+            .unwrap_err();
+        match err {
+            Error::EventLoopPanicked { message, stack } => {
+                assert!(message.starts_with("boom 1 at "), "{}", message);
+                assert!(!stack.is_empty());
+            }
+            err => panic!("unexpected error {}", err),
+        }
+        match slot.dropped_error() {
+            Error::EventLoopPanicked { message, .. } => assert!(message.starts_with("boom 1")),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn passes_through_results() {
+        let slot = PanicSlot::default();
+        assert!(slot.run(|| Ok(())).is_ok());
+        match slot.run(|| ClientExceptionSnafu.fail()) {
+            Err(Error::ClientException) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        match slot.dropped_error() {
+            Error::EventLoopDropped => (),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{Confirm, ConfirmPayload, Return};
+use std::collections::{BTreeMap, VecDeque};
+
+// Deliveries the I/O thread has republished to a parking lot (see `RedeliveryPolicy`) on one
+// channel, which is always in confirm mode.
+//
+// The original of each republish stays unacked until the server settles the republish: it is
+// acked if the server acks the republish without returning it, and nacked with requeue otherwise.
+// As with `ConfirmWaiters`, a return is held until the next confirm and belongs to a republish
+// only if that confirm acks it at its own sequence number.
+//
+// The server numbers republishes along with the channel's own publishes, but `Channel` only counts
+// its own, so confirms are renumbered before anyone else sees them.
+#[derive(Default)]
+pub(super) struct ParkedPublishes {
+    // Sequence numbers of republishes the server has not settled, and the delivery tags of their
+    // originals.
+    pending: BTreeMap<u64, u64>,
+    // Sequence numbers of the republishes a future confirm might still be numbered after, in
+    // order...
+    recent: VecDeque<u64>,
+    // ...and how many republishes came before them.
+    forgotten: u64,
+    held_return: Option<Return>,
+}
+
+// What to do with the original of a republish the server has settled.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Original {
+    Ack(u64),
+    Nack(u64),
+}
+
+// The result of `ParkedPublishes::settle`.
+pub(super) struct Settled {
+    // The confirm renumbered as `Channel` numbers its publishes, if it settles any of them.
+    pub(super) confirm: Option<Confirm>,
+    pub(super) originals: Vec<Original>,
+    // A held return that did not belong to a republish.
+    pub(super) return_: Option<Return>,
+}
+
+impl ParkedPublishes {
+    pub(super) fn park(&mut self, seq_no: u64, delivery_tag: u64) {
+        self.pending.insert(seq_no, delivery_tag);
+        self.recent.push_back(seq_no);
+    }
+
+    // Returns `return_` back if it cannot be for a republish.
+    pub(super) fn hold_return(&mut self, return_: Return) -> Option<Return> {
+        if self.pending.is_empty() {
+            return Some(return_);
+        }
+        self.held_return.replace(return_)
+    }
+
+    // Settle the republishes `confirm` covers. Every publish up to `settled_through` has now been
+    // settled, so no later confirm can be numbered before it.
+    pub(super) fn settle(&mut self, confirm: Confirm, settled_through: u64) -> Settled {
+        let (payload, acked) = match confirm {
+            Confirm::Ack(payload) => (payload, true),
+            Confirm::Nack(payload) => (payload, false),
+        };
+        let tag = payload.delivery_tag;
+        let mut held_return = self.held_return.take();
+
+        let settled = if payload.multiple {
+            let rest = self.pending.split_off(&(tag + 1));
+            std::mem::replace(&mut self.pending, rest)
+        } else {
+            self.pending.remove_entry(&tag).into_iter().collect()
+        };
+        let originals = settled
+            .into_iter()
+            .map(|(seq_no, delivery_tag)| {
+                if acked && seq_no == tag && held_return.take().is_some() {
+                    Original::Nack(delivery_tag)
+                } else if acked {
+                    Original::Ack(delivery_tag)
+                } else {
+                    Original::Nack(delivery_tag)
+                }
+            })
+            .collect();
+
+        let republishes =
+            self.forgotten + self.recent.iter().take_while(|&&n| n <= tag).count() as u64;
+        let is_republish = self.recent.binary_search(&tag).is_ok();
+        let confirm = match tag - republishes {
+            0 => None,
+            _ if is_republish && !payload.multiple => None,
+            delivery_tag => {
+                let payload = ConfirmPayload {
+                    delivery_tag,
+                    multiple: payload.multiple,
+                };
+                Some(if acked {
+                    Confirm::Ack(payload)
+                } else {
+                    Confirm::Nack(payload)
+                })
+            }
+        };
+
+        while self.recent.front().is_some_and(|&n| n <= settled_through) {
+            self.recent.pop_front();
+            self.forgotten += 1;
+        }
+        Settled {
+            confirm,
+            originals,
+            return_: held_return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AmqpProperties;
+    use amq_protocol::protocol::basic::Return as AmqpReturn;
+
+    fn ack(delivery_tag: u64, multiple: bool) -> Confirm {
+        Confirm::Ack(ConfirmPayload {
+            delivery_tag,
+            multiple,
+        })
+    }
+
+    fn nack(delivery_tag: u64, multiple: bool) -> Confirm {
+        Confirm::Nack(ConfirmPayload {
+            delivery_tag,
+            multiple,
+        })
+    }
+
+    fn return_() -> Return {
+        Return::new(
+            AmqpReturn {
+                reply_code: 312,
+                reply_text: "NO_ROUTE".to_string(),
+                exchange: String::new(),
+                routing_key: "parking-lot".to_string(),
+            },
+            b"body".to_vec(),
+            AmqpProperties::default(),
+        )
+    }
+
+    #[test]
+    fn confirms_are_renumbered_around_republishes() {
+        // Publishes 1 and 4 are the channel's 1 and 2; 2 and 3 are republishes.
+        let mut parked = ParkedPublishes::default();
+        parked.park(2, 10);
+        parked.park(3, 11);
+
+        let settled = parked.settle(ack(1, false), 1);
+        assert_eq!(settled.confirm, Some(ack(1, false)));
+        assert!(settled.originals.is_empty());
+
+        let settled = parked.settle(ack(2, false), 2);
+        assert_eq!(settled.confirm, None);
+        assert_eq!(settled.originals, vec![Original::Ack(10)]);
+
+        let settled = parked.settle(nack(4, true), 4);
+        assert_eq!(settled.confirm, Some(nack(2, true)));
+        assert_eq!(settled.originals, vec![Original::Nack(11)]);
+    }
+
+    #[test]
+    fn multiple_confirm_of_republish_settles_earlier_publishes() {
+        let mut parked = ParkedPublishes::default();
+        parked.park(1, 10);
+        parked.park(3, 11);
+
+        let settled = parked.settle(ack(1, true), 1);
+        assert_eq!(settled.confirm, None);
+        assert_eq!(settled.originals, vec![Original::Ack(10)]);
+
+        let settled = parked.settle(ack(3, true), 3);
+        assert_eq!(settled.confirm, Some(ack(1, true)));
+        assert_eq!(settled.originals, vec![Original::Ack(11)]);
+    }
+
+    #[test]
+    fn returned_republish_nacks_original() {
+        let mut parked = ParkedPublishes::default();
+        assert!(parked.hold_return(return_()).is_some());
+
+        parked.park(2, 10);
+        assert!(parked.hold_return(return_()).is_none());
+        let settled = parked.settle(ack(2, false), 0);
+        assert_eq!(settled.originals, vec![Original::Nack(10)]);
+        assert!(settled.return_.is_none());
+    }
+
+    #[test]
+    fn return_for_channel_publish_is_passed_on() {
+        let mut parked = ParkedPublishes::default();
+        parked.park(2, 10);
+        assert!(parked.hold_return(return_()).is_none());
+
+        let settled = parked.settle(ack(1, false), 1);
+        assert_eq!(settled.confirm, Some(ack(1, false)));
+        assert!(settled.originals.is_empty());
+        assert!(settled.return_.is_some());
+
+        let settled = parked.settle(ack(2, false), 2);
+        assert_eq!(settled.originals, vec![Original::Ack(10)]);
+    }
+}
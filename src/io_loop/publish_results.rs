@@ -0,0 +1,216 @@
+use crate::logging::warn;
+use crate::tag::SeqNo;
+use crate::{Confirm, PublishOutcome, PublishResult, Return};
+use crossbeam_channel::{Sender, TrySendError};
+use std::collections::BTreeSet;
+
+// Turns a channel's confirms and returns into one `PublishResult` per publish for the listener
+// registered via `Channel::listen_for_publish_results`.
+//
+// The server sends a mandatory message's basic.return before the basic.ack for it, so a return
+// is held until the next ack and reported as the outcome of the publish at that ack's delivery
+// tag. Multiple confirms are expanded into a result for every publish they settle that has not
+// already been settled individually.
+#[derive(Default)]
+pub(super) struct PublishResults {
+    tx: Option<Sender<PublishResult>>,
+    held_return: Option<Return>,
+    // Every sequence number up to and including this one has been settled...
+    settled_through: u64,
+    // ...as have these, all of which are greater than `settled_through + 1`.
+    settled_above: BTreeSet<u64>,
+}
+
+impl PublishResults {
+    // Starts reporting results to `tx` for publishes from `first_seq_no` on, replacing any
+    // previous listener.
+    pub(super) fn set_listener(&mut self, tx: Sender<PublishResult>, first_seq_no: SeqNo) {
+        *self = PublishResults {
+            tx: Some(tx),
+            held_return: None,
+            settled_through: first_seq_no.get() - 1,
+            settled_above: BTreeSet::new(),
+        };
+    }
+
+    pub(super) fn is_listening(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    // Holds a copy of `return_` for the next ack, if anyone is listening.
+    pub(super) fn hold_return(&mut self, return_: &Return) {
+        if self.is_listening() {
+            if let Some(dropped) = self.held_return.replace(return_.clone()) {
+                warn!("return not followed by an ack: {:?}", dropped);
+            }
+        }
+    }
+
+    pub(super) fn settle(&mut self, confirm: &Confirm) {
+        let (payload, acked) = match confirm {
+            Confirm::Ack(payload) => (payload, true),
+            Confirm::Nack(payload) => (payload, false),
+        };
+        let tag = payload.delivery_tag;
+        let mut held_return = if acked { self.held_return.take() } else { None };
+        let mut outcome = |seq_no| {
+            if !acked {
+                return PublishOutcome::Nacked;
+            }
+            match held_return.take() {
+                Some(return_) if seq_no == tag => PublishOutcome::Unroutable(return_),
+                other => {
+                    held_return = other;
+                    PublishOutcome::Routed
+                }
+            }
+        };
+
+        let mut results = Vec::new();
+        if payload.multiple {
+            let mut seq_no = self.settled_through + 1;
+            while seq_no <= tag {
+                if !self.settled_above.remove(&seq_no) {
+                    results.push(PublishResult {
+                        seq_no,
+                        outcome: outcome(seq_no),
+                    });
+                }
+                seq_no += 1;
+            }
+            self.settled_through = self.settled_through.max(tag);
+        } else if tag > self.settled_through && self.settled_above.insert(tag) {
+            results.push(PublishResult {
+                seq_no: tag,
+                outcome: outcome(tag),
+            });
+        }
+        while self.settled_above.remove(&(self.settled_through + 1)) {
+            self.settled_through += 1;
+        }
+
+        for result in results {
+            self.send(result);
+        }
+    }
+
+    fn send(&mut self, result: PublishResult) {
+        if let Some(tx) = &self.tx {
+            match tx.try_send(result) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                    self.tx = None;
+                    self.held_return = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AmqpProperties, ConfirmPayload};
+    use amq_protocol::protocol::basic::Return as AmqpReturn;
+    use crossbeam_channel::Receiver;
+
+    fn listening() -> (PublishResults, Receiver<PublishResult>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut results = PublishResults::default();
+        results.set_listener(tx, SeqNo::new(1));
+        (results, rx)
+    }
+
+    fn ack(delivery_tag: u64, multiple: bool) -> Confirm {
+        Confirm::Ack(ConfirmPayload {
+            delivery_tag,
+            multiple,
+        })
+    }
+
+    fn nack(delivery_tag: u64, multiple: bool) -> Confirm {
+        Confirm::Nack(ConfirmPayload {
+            delivery_tag,
+            multiple,
+        })
+    }
+
+    fn return_() -> Return {
+        Return::new(
+            AmqpReturn {
+                reply_code: 312,
+                reply_text: "NO_ROUTE".to_string(),
+                exchange: String::new(),
+                routing_key: "nowhere".to_string(),
+            },
+            b"body".to_vec(),
+            AmqpProperties::default(),
+        )
+    }
+
+    fn summarize(rx: &Receiver<PublishResult>) -> Vec<(u64, &'static str)> {
+        rx.try_iter()
+            .map(|result| {
+                let outcome = match result.outcome {
+                    PublishOutcome::Routed => "routed",
+                    PublishOutcome::Unroutable(_) => "unroutable",
+                    PublishOutcome::Nacked => "nacked",
+                };
+                (result.seq_no, outcome)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn return_pairs_with_following_ack() {
+        let (mut results, rx) = listening();
+        results.settle(&ack(1, false));
+        results.hold_return(&return_());
+        results.settle(&ack(2, false));
+        results.settle(&ack(3, false));
+        assert_eq!(
+            summarize(&rx),
+            vec![(1, "routed"), (2, "unroutable"), (3, "routed")]
+        );
+    }
+
+    #[test]
+    fn multiple_confirms_skip_already_settled() {
+        let (mut results, rx) = listening();
+        results.settle(&ack(2, false));
+        results.settle(&nack(3, true));
+        results.settle(&ack(5, false));
+        results.hold_return(&return_());
+        results.settle(&ack(6, true));
+        assert_eq!(
+            summarize(&rx),
+            vec![
+                (2, "routed"),
+                (1, "nacked"),
+                (3, "nacked"),
+                (5, "routed"),
+                (4, "routed"),
+                (6, "unroutable"),
+            ]
+        );
+        assert_eq!(results.settled_through, 6);
+        assert!(results.settled_above.is_empty());
+    }
+
+    #[test]
+    fn earlier_publishes_are_not_reported() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut results = PublishResults::default();
+        results.set_listener(tx, SeqNo::new(4));
+        results.settle(&ack(2, false));
+        results.settle(&ack(4, true));
+        assert_eq!(summarize(&rx), vec![(4, "routed")]);
+    }
+
+    #[test]
+    fn nothing_held_without_listener() {
+        let mut results = PublishResults::default();
+        results.hold_return(&return_());
+        assert!(results.held_return.is_none());
+    }
+}
@@ -0,0 +1,200 @@
+use super::ChannelCounters;
+use crate::logging::warn;
+use crate::Return;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+
+// How many returns we hold for a channel's first return listener when they arrive before it is
+// registered (e.g., the server returned a message before the publisher got as far as
+// `Channel::listen_for_returns`).
+const UNREGISTERED_RETURNS: usize = 16;
+
+// The sending side of a return listener, registered with the I/O thread by
+// `Channel::listen_for_returns` and `Channel::listen_for_returns_bounded`.
+pub(crate) struct ReturnHandler {
+    tx: Sender<Return>,
+    // For a bounded listener, our own end of its channel, from which we drop the oldest return to
+    // make room for a new one. It also keeps the channel connected after the listener's receiver
+    // is dropped, so a bounded listener never stops taking returns.
+    oldest: Option<Receiver<Return>>,
+}
+
+impl fmt::Debug for ReturnHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReturnHandler")
+            .field("capacity", &self.tx.capacity())
+            .finish()
+    }
+}
+
+impl ReturnHandler {
+    pub(crate) fn unbounded() -> (ReturnHandler, Receiver<Return>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        (ReturnHandler { tx, oldest: None }, rx)
+    }
+
+    pub(crate) fn bounded(capacity: usize) -> (ReturnHandler, Receiver<Return>) {
+        debug_assert!(capacity > 0);
+        let (tx, rx) = crossbeam_channel::bounded(capacity);
+        let handler = ReturnHandler {
+            tx,
+            oldest: Some(rx.clone()),
+        };
+        (handler, rx)
+    }
+
+    // Gives `return_` back if the listener is gone.
+    fn send(&self, return_: Return, counters: &ChannelCounters) -> Option<Return> {
+        let return_ = match self.tx.try_send(return_) {
+            Ok(()) => return None,
+            Err(TrySendError::Full(return_)) => return_,
+            Err(TrySendError::Disconnected(return_)) => return Some(return_),
+        };
+        // Only a bounded listener can be full, and we hold a receiver for each of those.
+        if let Some(oldest) = &self.oldest {
+            if let Ok(dropped) = oldest.try_recv() {
+                discard(&dropped, "return listener is full", counters);
+            }
+        }
+        // The listener may have taken returns since we found it full, but can't have added any.
+        self.tx
+            .try_send(return_)
+            .err()
+            .map(TrySendError::into_inner)
+    }
+}
+
+// Where a channel's returned messages go: to its return listener if it has one, or else held
+// for the first one to be registered.
+pub(super) enum ReturnListener {
+    Unregistered(VecDeque<Return>),
+    Registered(ReturnHandler),
+    // The listener's receiver was dropped.
+    Gone,
+}
+
+impl Default for ReturnListener {
+    fn default() -> ReturnListener {
+        ReturnListener::Unregistered(VecDeque::new())
+    }
+}
+
+impl ReturnListener {
+    // Replaces any previous listener, handing `handler` whatever returns we were holding for it.
+    pub(super) fn register(&mut self, handler: ReturnHandler, counters: &ChannelCounters) {
+        let previous = mem::replace(self, ReturnListener::Registered(handler));
+        if let ReturnListener::Unregistered(held) = previous {
+            for return_ in held {
+                self.send(return_, counters);
+            }
+        }
+    }
+
+    pub(super) fn send(&mut self, return_: Return, counters: &ChannelCounters) {
+        match self {
+            ReturnListener::Unregistered(held) => {
+                warn!(
+                    "holding message returned from exchange {:?} with routing key {:?} until a \
+                     return listener is registered",
+                    return_.exchange, return_.routing_key
+                );
+                if held.len() == UNREGISTERED_RETURNS {
+                    // unwrap is safe; we just checked there is something to pop.
+                    let dropped = held.pop_front().unwrap();
+                    discard(
+                        &dropped,
+                        "too many returns held for return listener",
+                        counters,
+                    );
+                }
+                held.push_back(return_);
+            }
+            ReturnListener::Registered(handler) => {
+                if let Some(return_) = handler.send(return_, counters) {
+                    discard(&return_, "return listener was dropped", counters);
+                    *self = ReturnListener::Gone;
+                }
+            }
+            ReturnListener::Gone => discard(&return_, "return listener was dropped", counters),
+        }
+    }
+}
+
+fn discard(return_: &Return, reason: &str, counters: &ChannelCounters) {
+    warn!(
+        "discarding message returned from exchange {:?} with routing key {:?}: {}",
+        return_.exchange, return_.routing_key, reason
+    );
+    counters.record_return_dropped();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AmqpProperties;
+
+    fn make_return(routing_key: &str) -> Return {
+        Return {
+            reply_code: 312,
+            reply_text: "NO_ROUTE".to_string(),
+            exchange: String::new(),
+            routing_key: routing_key.to_string(),
+            content: Vec::new(),
+            properties: AmqpProperties::default(),
+        }
+    }
+
+    fn routing_keys(rx: &Receiver<Return>) -> Vec<String> {
+        rx.try_iter().map(|return_| return_.routing_key).collect()
+    }
+
+    #[test]
+    fn bounded_listener_drops_oldest() {
+        let counters = ChannelCounters::new(1);
+        let mut listener = ReturnListener::default();
+        let (handler, rx) = ReturnHandler::bounded(2);
+        listener.register(handler, &counters);
+
+        for key in &["a", "b", "c", "d"] {
+            listener.send(make_return(key), &counters);
+        }
+        assert_eq!(routing_keys(&rx), vec!["c", "d"]);
+        assert_eq!(counters.snapshot().returns_dropped, 2);
+
+        // Dropping the receiver does not stop a bounded listener taking (and dropping) returns.
+        drop(rx);
+        listener.send(make_return("e"), &counters);
+        listener.send(make_return("f"), &counters);
+        listener.send(make_return("g"), &counters);
+        assert!(matches!(listener, ReturnListener::Registered(_)));
+        assert_eq!(counters.snapshot().returns_dropped, 3);
+    }
+
+    #[test]
+    fn first_listener_gets_returns_held_before_it() {
+        let counters = ChannelCounters::new(1);
+        let mut listener = ReturnListener::default();
+        for i in 0..UNREGISTERED_RETURNS + 2 {
+            listener.send(make_return(&i.to_string()), &counters);
+        }
+        assert_eq!(counters.snapshot().returns_dropped, 2);
+
+        let (handler, rx) = ReturnHandler::unbounded();
+        listener.register(handler, &counters);
+        let expected = (2..UNREGISTERED_RETURNS + 2)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(routing_keys(&rx), expected);
+
+        // Returns are discarded once the listener is dropped, and not held for a later one.
+        drop(rx);
+        listener.send(make_return("late"), &counters);
+        assert!(matches!(listener, ReturnListener::Gone));
+        let (handler, rx) = ReturnHandler::unbounded();
+        listener.register(handler, &counters);
+        assert!(routing_keys(&rx).is_empty());
+        assert_eq!(counters.snapshot().returns_dropped, 3);
+    }
+}
@@ -0,0 +1,183 @@
+use crossbeam_channel::Sender;
+use std::collections::BTreeSet;
+use std::time::Instant;
+
+#[cfg(feature = "serde")]
+use serde_crate::Serialize;
+
+/// What a [graceful shutdown](struct.Connection.html#method.shutdown) left unfinished when its
+/// deadline passed.
+///
+/// A report with every count zero means the shutdown finished everything it waits for before
+/// closing the connection. With the `serde` feature enabled, `ShutdownReport` implements
+/// `serde::Serialize`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(crate = "serde_crate"))]
+pub struct ShutdownReport {
+    /// Number of consumers the server had not confirmed cancelling. They may have been sent
+    /// deliveries that the connection closed before they could see.
+    pub uncancelled_consumers: usize,
+
+    /// Number of publishes that had not been completely written to the socket.
+    pub unflushed_publishes: u64,
+
+    /// Number of deliveries to consumers (other than `no_ack` consumers) that had been neither
+    /// acked, nacked nor rejected. The server requeues them when the connection closes.
+    pub unacked_deliveries: u64,
+
+    /// Number of publishes on channels in [confirm
+    /// mode](struct.Channel.html#method.enable_publisher_confirms) that the server had neither
+    /// acked nor nacked.
+    pub unconfirmed_publishes: u64,
+
+    /// True if the server had not acknowledged closing the connection by the deadline either.
+    /// The connection's I/O thread is then left to finish closing it in the background.
+    pub close_abandoned: bool,
+}
+
+impl ShutdownReport {
+    /// True if the shutdown finished everything it waits for: every consumer was cancelled,
+    /// every publish was written, every publish in confirm mode was confirmed, and the server
+    /// acknowledged closing the connection. Unacked deliveries are not waited for, so they do not
+    /// count.
+    pub fn is_complete(&self) -> bool {
+        self.uncancelled_consumers == 0
+            && self.unflushed_publishes == 0
+            && self.unconfirmed_publishes == 0
+            && !self.close_abandoned
+    }
+}
+
+// A graceful shutdown in progress: when to give up on it, and where to send the report.
+pub(super) struct Shutdown {
+    pub(super) deadline: Instant,
+    pub(super) tx: Sender<ShutdownReport>,
+}
+
+// Publishes on a channel in confirm mode, numbered from 1 in the order we sent them, and which of
+// them the server has settled (acked or nacked).
+#[derive(Default)]
+pub(super) struct UnconfirmedPublishes {
+    published: u64,
+    // Every publish up to and including this one has been settled...
+    settled_through: u64,
+    // ...as have these, all of which are greater than `settled_through + 1`.
+    settled_above: BTreeSet<u64>,
+}
+
+impl UnconfirmedPublishes {
+    pub(super) fn record_publishes(&mut self, count: u64) {
+        self.published += count;
+    }
+
+    pub(super) fn settle(&mut self, delivery_tag: u64, multiple: bool) {
+        if delivery_tag <= self.settled_through {
+            return;
+        }
+        if multiple || delivery_tag == self.settled_through + 1 {
+            self.settled_through = delivery_tag;
+            self.settled_above = self.settled_above.split_off(&(delivery_tag + 1));
+            while self.settled_above.remove(&(self.settled_through + 1)) {
+                self.settled_through += 1;
+            }
+        } else {
+            self.settled_above.insert(delivery_tag);
+        }
+    }
+
+    pub(super) fn count(&self) -> u64 {
+        self.published
+            .saturating_sub(self.settled_through + self.settled_above.len() as u64)
+    }
+
+    // The sequence number the server will give the next publish.
+    #[cfg(feature = "consume")]
+    pub(super) fn next_seq_no(&self) -> u64 {
+        self.published + 1
+    }
+
+    #[cfg(feature = "consume")]
+    pub(super) fn settled_through(&self) -> u64 {
+        self.settled_through
+    }
+}
+
+// Delivery tags of deliveries to consumers that must be acked, and that we have not yet sent an
+// ack, nack or reject for.
+#[cfg(feature = "consume")]
+#[derive(Default)]
+pub(super) struct UnackedDeliveries(BTreeSet<u64>);
+
+#[cfg(feature = "consume")]
+impl UnackedDeliveries {
+    pub(super) fn record(&mut self, delivery_tag: u64) {
+        self.0.insert(delivery_tag);
+    }
+
+    // A `multiple` settlement of delivery tag 0 settles every delivery.
+    pub(super) fn settle(&mut self, delivery_tag: u64, multiple: bool) {
+        if multiple && delivery_tag == 0 {
+            self.0.clear();
+        } else if multiple {
+            self.0 = self.0.split_off(&(delivery_tag + 1));
+        } else {
+            self.0.remove(&delivery_tag);
+        }
+    }
+
+    pub(super) fn count(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    pub(super) fn contains(&self, delivery_tag: u64) -> bool {
+        self.0.contains(&delivery_tag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unconfirmed_publishes_settle_in_any_order() {
+        let mut unconfirmed = UnconfirmedPublishes::default();
+        unconfirmed.record_publishes(6);
+        assert_eq!(unconfirmed.count(), 6);
+
+        unconfirmed.settle(3, false);
+        unconfirmed.settle(5, false);
+        assert_eq!(unconfirmed.count(), 4);
+
+        unconfirmed.settle(2, true);
+        assert_eq!(unconfirmed.count(), 2);
+        assert_eq!(unconfirmed.settled_through, 3);
+
+        unconfirmed.settle(4, false);
+        assert_eq!(unconfirmed.settled_through, 5);
+        assert!(unconfirmed.settled_above.is_empty());
+
+        unconfirmed.settle(6, true);
+        unconfirmed.settle(6, false);
+        assert_eq!(unconfirmed.count(), 0);
+    }
+
+    #[cfg(feature = "consume")]
+    #[test]
+    fn unacked_deliveries_settle_singly_or_multiple() {
+        let mut unacked = UnackedDeliveries::default();
+        for tag in 1..=10 {
+            unacked.record(tag);
+        }
+
+        unacked.settle(3, true);
+        unacked.settle(5, false);
+        unacked.settle(7, false);
+        assert_eq!(
+            unacked.0.iter().copied().collect::<Vec<_>>(),
+            vec![4, 6, 8, 9, 10]
+        );
+
+        unacked.settle(0, true);
+        assert_eq!(unacked.count(), 0);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::frame_buffer::FrameCounters;
+use crate::serialize::FrameCounts;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+#[cfg(feature = "serde")]
+use serde_crate::Serialize;
+
+/// A snapshot of a connection's traffic counters.
+///
+/// Returned by [`Connection::stats`](struct.Connection.html#method.stats). All counts are totals
+/// since the connection was opened (including the handshake), so rates can be computed by
+/// comparing two snapshots. With the `serde` feature enabled, `ConnectionStats` implements
+/// `serde::Serialize`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(crate = "serde_crate"))]
+pub struct ConnectionStats {
+    /// Total number of bytes read from the underlying stream.
+    pub bytes_read: u64,
+
+    /// Total number of bytes written to the underlying stream.
+    pub bytes_written: u64,
+
+    /// Number of frames received from the server.
+    pub frames_read: u64,
+
+    /// Number of frames written to the underlying stream. A frame is counted once all of it has
+    /// been written.
+    pub frames_written: u64,
+
+    /// Number of heartbeat frames sent to the server.
+    pub heartbeats_sent: u64,
+
+    /// Number of heartbeat frames received from the server.
+    pub heartbeats_received: u64,
+
+    /// Number of messages published (i.e., `basic.publish` frames written) on any channel.
+    pub publishes: u64,
+
+    /// Number of messages received from the server by consumers or `basic.get`.
+    pub deliveries: u64,
+
+    /// Number of bytes queued by the I/O thread but not yet written to the underlying stream.
+    pub outbuf_depth: usize,
+}
+
+// Counters maintained by the I/O thread and read by `Connection::stats`. Frames read come from
+// the frame buffer's counters, which the I/O thread already keeps up to date.
+#[derive(Debug)]
+pub(crate) struct ConnectionCounters {
+    frames: Arc<FrameCounters>,
+    bytes_written: AtomicU64,
+    frames_written: AtomicU64,
+    heartbeats_sent: AtomicU64,
+    publishes: AtomicU64,
+    deliveries: AtomicU64,
+    outbuf_depth: AtomicUsize,
+}
+
+impl ConnectionCounters {
+    pub(super) fn new(frames: Arc<FrameCounters>) -> ConnectionCounters {
+        ConnectionCounters {
+            frames,
+            bytes_written: AtomicU64::new(0),
+            frames_written: AtomicU64::new(0),
+            heartbeats_sent: AtomicU64::new(0),
+            publishes: AtomicU64::new(0),
+            deliveries: AtomicU64::new(0),
+            outbuf_depth: AtomicUsize::new(0),
+        }
+    }
+
+    pub(super) fn record_write(&self, bytes: usize, counts: FrameCounts) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.frames_written
+            .fetch_add(counts.frames, Ordering::Relaxed);
+        self.publishes
+            .fetch_add(counts.publishes, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_heartbeat_sent(&self) {
+        self.heartbeats_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_delivery(&self) {
+        self.deliveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_outbuf_depth(&self, depth: usize) {
+        self.outbuf_depth.store(depth, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ConnectionStats {
+        let frames = self.frames.snapshot();
+        ConnectionStats {
+            bytes_read: frames.bytes,
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            frames_read: frames.method + frames.header + frames.body + frames.heartbeat,
+            frames_written: self.frames_written.load(Ordering::Relaxed),
+            heartbeats_sent: self.heartbeats_sent.load(Ordering::Relaxed),
+            heartbeats_received: frames.heartbeat,
+            publishes: self.publishes.load(Ordering::Relaxed),
+            deliveries: self.deliveries.load(Ordering::Relaxed),
+            outbuf_depth: self.outbuf_depth.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of a channel's counters.
+///
+/// Returned by [`Channel::stats`](struct.Channel.html#method.stats) and included in each
+/// [`ChannelInfo`](struct.ChannelInfo.html). Counts are totals since the channel was opened. Once
+/// the channel closes (including when the server closes it because of an error), its counters stop
+/// changing, and this reports their final values. With the `serde` feature enabled,
+/// `ChannelStats` implements `serde::Serialize`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(crate = "serde_crate"))]
+pub struct ChannelStats {
+    /// Number of messages published on this channel (i.e., `basic.publish` frames queued to be
+    /// sent to the server).
+    pub publishes: u64,
+
+    /// Number of messages received from the server by consumers or `basic.get` on this channel.
+    pub deliveries: u64,
+
+    /// Number of `basic.ack` frames sent. A single ack may settle several deliveries (e.g., with
+    /// `AckPolicy::Batched`).
+    pub acks: u64,
+
+    /// Number of `basic.nack` and `basic.reject` frames sent.
+    pub nacks: u64,
+
+    /// Number of messages the server returned as unroutable.
+    pub returns: u64,
+
+    /// Number of returned messages discarded without reaching a return listener: the oldest
+    /// return in a full [bounded
+    /// listener](struct.Channel.html#method.listen_for_returns_bounded), the oldest of those held
+    /// for the first listener when too many arrive before it is registered, and returns that
+    /// arrive after the listener's receiver is dropped.
+    pub returns_dropped: u64,
+
+    /// Whether the server has put the channel into publisher confirm mode.
+    pub confirm_mode: bool,
+
+    /// Whether the server has put the channel into transaction mode.
+    pub tx_mode: bool,
+
+    /// Number of consumers the server has started on this channel and not yet cancelled.
+    pub consumers: usize,
+}
+
+/// Information about a channel on a connection.
+///
+/// Returned by [`Connection::channels`](struct.Connection.html#method.channels). With the `serde`
+/// feature enabled, `ChannelInfo` implements `serde::Serialize`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize), serde(crate = "serde_crate"))]
+pub struct ChannelInfo {
+    /// The channel's ID. A closed channel's ID may already belong to a newer channel.
+    pub channel_id: u16,
+
+    /// False once the channel has closed, whether by the client or the server.
+    pub open: bool,
+
+    /// Tags of the consumers running on the channel, in sorted order. Empty once it has closed.
+    pub consumer_tags: Vec<String>,
+
+    /// The channel's counters.
+    pub stats: ChannelStats,
+}
+
+// Counters for one channel, maintained by the I/O thread and read by `Channel::stats` and
+// `Connection::channels`.
+#[derive(Debug)]
+pub(crate) struct ChannelCounters {
+    channel_id: u16,
+    open: AtomicBool,
+    publishes: AtomicU64,
+    deliveries: AtomicU64,
+    acks: AtomicU64,
+    nacks: AtomicU64,
+    returns: AtomicU64,
+    returns_dropped: AtomicU64,
+    confirm_mode: AtomicBool,
+    tx_mode: AtomicBool,
+    consumer_tags: Mutex<Vec<String>>,
+}
+
+impl ChannelCounters {
+    pub(super) fn new(channel_id: u16) -> ChannelCounters {
+        ChannelCounters {
+            channel_id,
+            open: AtomicBool::new(true),
+            publishes: AtomicU64::new(0),
+            deliveries: AtomicU64::new(0),
+            acks: AtomicU64::new(0),
+            nacks: AtomicU64::new(0),
+            returns: AtomicU64::new(0),
+            returns_dropped: AtomicU64::new(0),
+            confirm_mode: AtomicBool::new(false),
+            tx_mode: AtomicBool::new(false),
+            consumer_tags: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn record_sent(&self, counts: FrameCounts) {
+        self.publishes
+            .fetch_add(counts.publishes, Ordering::Relaxed);
+        self.acks.fetch_add(counts.acks, Ordering::Relaxed);
+        self.nacks.fetch_add(counts.nacks, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_delivery(&self) {
+        self.deliveries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_return(&self) {
+        self.returns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_return_dropped(&self) {
+        self.returns_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_confirm_mode(&self) {
+        self.confirm_mode.store(true, Ordering::Relaxed);
+    }
+
+    pub(super) fn set_tx_mode(&self) {
+        self.tx_mode.store(true, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "consume")]
+    pub(super) fn set_consumer_tags<'a, I: Iterator<Item = &'a String>>(&self, tags: I) {
+        let mut tags = tags.cloned().collect::<Vec<_>>();
+        tags.sort();
+        *self.lock_consumer_tags() = tags;
+    }
+
+    // Report the channel closed. Also done when the slot holding its `OpenChannelCounters` is
+    // dropped, but callers that notify the channel's users first should call this before.
+    pub(super) fn close(&self) {
+        self.open.store(false, Ordering::Relaxed);
+        self.lock_consumer_tags().clear();
+    }
+
+    fn lock_consumer_tags(&self) -> MutexGuard<'_, Vec<String>> {
+        self.consumer_tags
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn snapshot(&self) -> ChannelStats {
+        ChannelStats {
+            publishes: self.publishes.load(Ordering::Relaxed),
+            deliveries: self.deliveries.load(Ordering::Relaxed),
+            acks: self.acks.load(Ordering::Relaxed),
+            nacks: self.nacks.load(Ordering::Relaxed),
+            returns: self.returns.load(Ordering::Relaxed),
+            returns_dropped: self.returns_dropped.load(Ordering::Relaxed),
+            confirm_mode: self.confirm_mode.load(Ordering::Relaxed),
+            tx_mode: self.tx_mode.load(Ordering::Relaxed),
+            consumers: self.lock_consumer_tags().len(),
+        }
+    }
+
+    fn info(&self) -> ChannelInfo {
+        // Not inline in the struct below, where the lock would be held while `snapshot` takes it.
+        let consumer_tags = self.lock_consumer_tags().clone();
+        ChannelInfo {
+            channel_id: self.channel_id,
+            open: self.open.load(Ordering::Relaxed),
+            consumer_tags,
+            stats: self.snapshot(),
+        }
+    }
+}
+
+// The I/O thread's reference to a channel's counters, held in the channel's slot. Reports the
+// channel closed when dropped, i.e., once the slot is gone.
+#[derive(Debug)]
+pub(super) struct OpenChannelCounters(Arc<ChannelCounters>);
+
+impl OpenChannelCounters {
+    pub(super) fn new(channel_id: u16) -> OpenChannelCounters {
+        OpenChannelCounters(Arc::new(ChannelCounters::new(channel_id)))
+    }
+
+    pub(super) fn shared(&self) -> Arc<ChannelCounters> {
+        Arc::clone(&self.0)
+    }
+}
+
+impl Deref for OpenChannelCounters {
+    type Target = ChannelCounters;
+
+    fn deref(&self) -> &ChannelCounters {
+        &self.0
+    }
+}
+
+impl Drop for OpenChannelCounters {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+// Every channel allocated on a connection, for `Connection::channels`. Channels stay listed
+// while they are open or anything still has their counters (e.g., their `Channel` handle).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChannelRegistry(Arc<Mutex<Vec<Weak<ChannelCounters>>>>);
+
+impl ChannelRegistry {
+    pub(super) fn register(&self, counters: &OpenChannelCounters) {
+        let mut channels = self.lock();
+        channels.retain(|channel| channel.strong_count() > 0);
+        channels.push(Arc::downgrade(&counters.0));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Weak<ChannelCounters>>> {
+        self.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Sorted by channel ID; a closed channel comes before a newer one with the same ID.
+    pub(crate) fn snapshot(&self) -> Vec<ChannelInfo> {
+        let mut channels = self.lock();
+        channels.retain(|channel| channel.strong_count() > 0);
+        let mut infos = channels
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|channel| channel.info())
+            .collect::<Vec<_>>();
+        // Stable, so channels sharing an ID stay in the order they were opened.
+        infos.sort_by_key(|info| info.channel_id);
+        infos
+    }
+}
@@ -0,0 +1,238 @@
+use super::{STREAM, WAKER};
+use crate::logging::trace;
+use crossbeam_channel::{Receiver, SendError, Sender, TrySendError};
+use mio::{Registry, Token, Waker};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+// Wakes the I/O thread on behalf of anything that is not a socket: the channels client handles
+// send on, and streams mio cannot poll (see `StreamWaker`). mio allows one `Waker` per `Poll`, so
+// they all share it, and record which token they were woken for; the I/O thread collects those
+// with `take_ready` each time around its loop.
+pub(super) struct LoopWaker {
+    waker: Waker,
+    ready: Mutex<Ready>,
+}
+
+// Tokens in the order they were first woken for, since messages sent on different channels must
+// be handled in the order they were sent (e.g., a channel close before the connection close).
+#[derive(Default)]
+struct Ready {
+    order: Vec<Token>,
+    set: HashSet<Token>,
+}
+
+impl LoopWaker {
+    pub(super) fn new(registry: &Registry) -> io::Result<LoopWaker> {
+        Ok(LoopWaker {
+            waker: Waker::new(registry, WAKER)?,
+            ready: Mutex::new(Ready::default()),
+        })
+    }
+
+    pub(super) fn wake(&self, token: Token) {
+        let mut ready = self.ready.lock().unwrap_or_else(|err| err.into_inner());
+        // If there were already tokens waiting, the I/O thread has been woken and has yet to
+        // collect them; it will collect this one too.
+        let was_empty = ready.order.is_empty();
+        if ready.set.insert(token) {
+            ready.order.push(token);
+        }
+        if was_empty {
+            if let Err(err) = self.waker.wake() {
+                // Only fails if the poll is gone, in which case nobody is listening anyway.
+                trace!("failed to wake I/O thread: {}", err);
+            }
+        }
+    }
+
+    // Whether we have been woken for `token` since the last `take_ready`, leaving it to be taken.
+    pub(super) fn is_ready(&self, token: Token) -> bool {
+        let ready = self.ready.lock().unwrap_or_else(|err| err.into_inner());
+        ready.set.contains(&token)
+    }
+
+    pub(super) fn take_ready(&self) -> Vec<Token> {
+        let mut ready = self.ready.lock().unwrap_or_else(|err| err.into_inner());
+        ready.set.clear();
+        mem::take(&mut ready.order)
+    }
+}
+
+// Wakes the I/O thread for `token` when dropped. Outlives the sender it is stored with (see
+// `SyncSender`), so the I/O thread sees the channel disconnected when the last one is dropped.
+#[derive(Clone)]
+struct Notify {
+    waker: Arc<LoopWaker>,
+    token: Token,
+}
+
+impl Drop for Notify {
+    fn drop(&mut self) {
+        self.waker.wake(self.token);
+    }
+}
+
+// The sending half of a channel to the I/O thread, which wakes it for the channel's token on
+// every send.
+pub(super) struct SyncSender<T> {
+    // Must be declared (and so dropped) before `notify`.
+    tx: Sender<T>,
+    notify: Notify,
+}
+
+// Not derived, which would require `T: Clone`.
+impl<T> Clone for SyncSender<T> {
+    fn clone(&self) -> Self {
+        SyncSender {
+            tx: self.tx.clone(),
+            notify: self.notify.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for SyncSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SyncSender")
+            .field("token", &self.notify.token)
+            .finish()
+    }
+}
+
+impl<T> SyncSender<T> {
+    pub(super) fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.tx.send(value)?;
+        self.notify.waker.wake(self.notify.token);
+        Ok(())
+    }
+
+    pub(super) fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.tx.try_send(value)?;
+        self.notify.waker.wake(self.notify.token);
+        Ok(())
+    }
+}
+
+// A channel holding up to `bound` messages for the I/O thread, which is woken for `token` when
+// one is sent (or the last sender is dropped).
+pub(super) fn sync_channel<T>(
+    bound: usize,
+    token: Token,
+    waker: &Arc<LoopWaker>,
+) -> (SyncSender<T>, Receiver<T>) {
+    let (tx, rx) = crossbeam_channel::bounded(bound);
+    let notify = Notify {
+        waker: Arc::clone(waker),
+        token,
+    };
+    (SyncSender { tx, notify }, rx)
+}
+
+/// Wakes a connection's I/O thread to try reading from and writing to its stream again; see
+/// [`IoStream::set_waker`](trait.IoStream.html#method.set_waker).
+///
+/// For streams whose data is moved by other threads, which should wake the I/O thread whenever
+/// there is new data to read or room to write more. Spurious wakeups are harmless.
+#[derive(Clone)]
+pub struct StreamWaker(Arc<LoopWaker>);
+
+impl StreamWaker {
+    pub(super) fn new(waker: &Arc<LoopWaker>) -> StreamWaker {
+        StreamWaker(Arc::clone(waker))
+    }
+
+    /// Wake the I/O thread. Does nothing if the connection has been closed.
+    pub fn wake(&self) {
+        self.0.wake(STREAM);
+    }
+}
+
+impl fmt::Debug for StreamWaker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StreamWaker").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::TryRecvError;
+    use mio::{Events, Poll};
+    use std::thread;
+    use std::time::Duration;
+
+    struct Harness {
+        poll: Poll,
+        events: Events,
+        waker: Arc<LoopWaker>,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let poll = Poll::new().unwrap();
+            let waker = Arc::new(LoopWaker::new(poll.registry()).unwrap());
+            Harness {
+                poll,
+                events: Events::with_capacity(8),
+                waker,
+            }
+        }
+
+        // Wait up to `timeout` to be woken, returning the tokens we were woken for.
+        fn wait(&mut self, timeout: Duration) -> Vec<Token> {
+            self.poll.poll(&mut self.events, Some(timeout)).unwrap();
+            for event in &self.events {
+                assert_eq!(event.token(), WAKER);
+            }
+            self.waker.take_ready()
+        }
+    }
+
+    #[test]
+    fn sends_wake_for_their_token() {
+        let mut harness = Harness::new();
+        let (tx1, rx1) = sync_channel(4, Token(1), &harness.waker);
+        let (tx2, rx2) = sync_channel(4, Token(2), &harness.waker);
+
+        let sender = thread::spawn(move || {
+            tx2.send("a").unwrap();
+            tx2.send("b").unwrap();
+            tx1.try_send("c").unwrap();
+            (tx1, tx2)
+        });
+        let (tx1, tx2) = sender.join().unwrap();
+
+        // Each token is reported once, however many messages are waiting, in the order they were
+        // first sent on.
+        assert_eq!(
+            harness.wait(Duration::from_secs(1)),
+            vec![Token(2), Token(1)]
+        );
+        assert_eq!(rx1.try_recv(), Ok("c"));
+        assert_eq!(rx2.try_iter().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(harness.wait(Duration::from_millis(50)).is_empty());
+
+        // Dropping the last sender wakes us to see the disconnect.
+        let tx1_clone = tx1.clone();
+        drop(tx1);
+        assert_eq!(harness.wait(Duration::from_secs(1)), vec![Token(1)]);
+        assert_eq!(rx1.try_recv(), Err(TryRecvError::Empty));
+        drop(tx1_clone);
+        assert_eq!(harness.wait(Duration::from_secs(1)), vec![Token(1)]);
+        assert_eq!(rx1.try_recv(), Err(TryRecvError::Disconnected));
+
+        drop(rx2);
+        assert!(tx2.try_send("d").is_err());
+    }
+
+    #[test]
+    fn stream_waker_wakes_stream() {
+        let mut harness = Harness::new();
+        let stream_waker = StreamWaker::new(&harness.waker);
+        thread::spawn(move || stream_waker.wake()).join().unwrap();
+        assert_eq!(harness.wait(Duration::from_secs(1)), vec![STREAM]);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::errors::*;
+use serde_crate::Serialize;
+use snafu::ResultExt;
+
+#[cfg(feature = "consume")]
+use crate::AmqpProperties;
+#[cfg(feature = "consume")]
+use serde_crate::de::DeserializeOwned;
+
+pub(crate) const CONTENT_TYPE: &str = "application/json";
+
+pub(crate) fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).context(EncodeJsonSnafu)
+}
+
+#[cfg(feature = "consume")]
+pub(crate) fn decode<T: DeserializeOwned>(properties: &AmqpProperties, body: &[u8]) -> Result<T> {
+    match properties.content_type() {
+        Some(content_type) if is_json(content_type) => (),
+        content_type => {
+            return UnexpectedContentTypeSnafu {
+                expected: CONTENT_TYPE,
+                content_type: content_type.clone(),
+            }
+            .fail()
+        }
+    }
+    serde_json::from_slice(body).context(DecodeJsonSnafu)
+}
+
+// Compares the media type only, so parameters like `; charset=utf-8` are allowed.
+#[cfg(feature = "consume")]
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default();
+    media_type.trim().eq_ignore_ascii_case(CONTENT_TYPE)
+}
+
+#[cfg(all(test, feature = "consume"))]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    fn properties(content_type: &str) -> AmqpProperties {
+        AmqpProperties::default().with_content_type(content_type.to_string())
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let body = encode(&vec![1u32, 2, 3]).unwrap();
+        assert_eq!(body, b"[1,2,3]");
+        let decoded: Vec<u32> = decode(&properties(CONTENT_TYPE), &body).unwrap();
+        assert_eq!(decoded, vec![1, 2, 3]);
+        let decoded: Vec<u32> =
+            decode(&properties("Application/JSON; charset=utf-8"), &body).unwrap();
+        assert_eq!(decoded, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_other_content_types() {
+        match decode::<u32>(&properties("text/plain"), b"1") {
+            Err(Error::UnexpectedContentType { content_type, .. }) => {
+                assert_eq!(content_type, Some("text/plain".to_string()))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        match decode::<u32>(&AmqpProperties::default(), b"1") {
+            Err(Error::UnexpectedContentType { content_type, .. }) => {
+                assert_eq!(content_type, None)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_decode_failures() {
+        match decode::<u32>(&properties(CONTENT_TYPE), b"\"one\"") {
+            Err(err @ Error::DecodeJson { .. }) => {
+                assert!(err.to_string().contains("invalid type"), "{}", err)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
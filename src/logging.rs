@@ -0,0 +1,23 @@
+// Diagnostics go through `log` by default, or through `tracing` with the `tracing` feature. The
+// rest of the crate imports its logging macros from here so it does not care which.
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error, trace, warn};
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, trace, warn};
+
+// Enters a span (`level` is `TRACE`, `DEBUG`, etc.) until the end of the enclosing block. Takes
+// the same name and fields as `tracing::span!`. Without the `tracing` feature this expands to
+// nothing, so field expressions are not evaluated.
+#[cfg(feature = "tracing")]
+macro_rules! enter_span {
+    ($level:ident, $($args:tt)+) => {
+        let _span = tracing::span!(tracing::Level::$level, $($args)+).entered();
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! enter_span {
+    ($level:ident, $($args:tt)+) => {};
+}
+
+pub(crate) use enter_span;
@@ -0,0 +1,141 @@
+use crate::logging::error;
+use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
+use amq_protocol::protocol::connection::TuneOk;
+use amq_protocol::protocol::AMQPClass;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Callbacks invoked by the I/O thread as a connection makes progress, for wiring amiquip into
+/// tracing or metrics without parsing its logs. Set one with
+/// [`ConnectionOptions::observer`](struct.ConnectionOptions.html#method.observer).
+///
+/// Every callback defaults to doing nothing. Callbacks run on the I/O thread, so they should
+/// return quickly; nothing else happens on the connection (including heartbeats) while one is
+/// running. A callback that panics does not take the connection down with it: the panic is
+/// caught and logged, and the connection carries on.
+///
+/// Only method frames are reported by [`on_frame_read`](#method.on_frame_read) and
+/// [`on_frame_written`](#method.on_frame_written); content header and body frames, which carry
+/// the messages themselves, are not. Class and method IDs are as defined by the AMQP 0-9-1
+/// specification (e.g., `basic.publish` is class 60, method 40).
+pub trait ConnectionObserver: Send + Sync {
+    /// Called once the AMQP handshake has finished, with the tuning parameters the connection
+    /// will use (the [`AmqpTuneOk`](type.AmqpTuneOk.html) amiquip sent the server).
+    fn on_handshake_complete(&self, tune_ok: &TuneOk) {
+        let _ = tune_ok;
+    }
+
+    /// Called for each method frame received from the server, after it has been processed.
+    fn on_frame_read(&self, channel_id: u16, class_id: u16, method_id: u16) {
+        let _ = (channel_id, class_id, method_id);
+    }
+
+    /// Called for each method frame once it has been written to the socket.
+    fn on_frame_written(&self, channel_id: u16, class_id: u16, method_id: u16) {
+        let _ = (channel_id, class_id, method_id);
+    }
+
+    /// Called each time a heartbeat is queued to be sent to the server.
+    fn on_heartbeat_sent(&self) {}
+
+    /// Called each time a heartbeat is received from the server.
+    fn on_heartbeat_received(&self) {}
+
+    /// Called each time a heartbeat interval passes without anything received from the server,
+    /// as long as fewer than
+    /// [`missed_heartbeat_limit`](struct.ConnectionTuning.html#structfield.missed_heartbeat_limit)
+    /// intervals have been missed in a row (after which the connection fails with
+    /// [`Error::MissedServerHeartbeats`](enum.Error.html#variant.MissedServerHeartbeats)).
+    /// `silent_for` is how long it has been since data was last received.
+    ///
+    /// This is an early warning of a connection that may be about to drop; with the default
+    /// limit of 2, it is called once, one interval before the connection fails.
+    fn on_heartbeat_warning(&self, silent_for: Duration) {
+        let _ = silent_for;
+    }
+
+    /// Called when either side starts closing the connection, with the reply code and text it
+    /// gave: when the server sends `connection.close`, or when the client queues one to send
+    /// (including when a [`Connection`](struct.Connection.html) is closed or dropped).
+    fn on_connection_close(&self, reply_code: u16, reply_text: &str) {
+        let _ = (reply_code, reply_text);
+    }
+}
+
+// Shared, comparable handle to a user's observer, so ConnectionOptions can stay Clone + Debug +
+// PartialEq. Every call goes through `guard`, so a panicking observer cannot unwind into (and
+// kill) the I/O thread.
+#[derive(Clone)]
+pub(crate) struct ObserverHandle(Arc<dyn ConnectionObserver>);
+
+impl ObserverHandle {
+    pub(crate) fn new(observer: Box<dyn ConnectionObserver>) -> ObserverHandle {
+        ObserverHandle(Arc::from(observer))
+    }
+
+    pub(crate) fn on_handshake_complete(&self, tune_ok: &TuneOk) {
+        self.guard("on_handshake_complete", |o| {
+            o.on_handshake_complete(tune_ok)
+        });
+    }
+
+    pub(crate) fn on_frame_read(&self, channel_id: u16, class_id: u16, method_id: u16) {
+        self.guard("on_frame_read", |o| {
+            o.on_frame_read(channel_id, class_id, method_id)
+        });
+    }
+
+    pub(crate) fn on_frame_written(&self, channel_id: u16, class_id: u16, method_id: u16) {
+        self.guard("on_frame_written", |o| {
+            o.on_frame_written(channel_id, class_id, method_id)
+        });
+    }
+
+    pub(crate) fn on_heartbeat_sent(&self) {
+        self.guard("on_heartbeat_sent", |o| o.on_heartbeat_sent());
+    }
+
+    pub(crate) fn on_heartbeat_received(&self) {
+        self.guard("on_heartbeat_received", |o| o.on_heartbeat_received());
+    }
+
+    pub(crate) fn on_heartbeat_warning(&self, silent_for: Duration) {
+        self.guard("on_heartbeat_warning", |o| {
+            o.on_heartbeat_warning(silent_for)
+        });
+    }
+
+    pub(crate) fn on_connection_close(&self, reply_code: u16, reply_text: &str) {
+        self.guard("on_connection_close", |o| {
+            o.on_connection_close(reply_code, reply_text)
+        });
+    }
+
+    // Calls `on_connection_close` if `class` is a connection.close.
+    pub(crate) fn check_close(&self, class: &AMQPClass) {
+        if let AMQPClass::Connection(AmqpConnection::Close(close)) = class {
+            self.on_connection_close(close.reply_code, &close.reply_text);
+        }
+    }
+
+    fn guard<F: FnOnce(&dyn ConnectionObserver)>(&self, callback: &str, f: F) {
+        let observer = &*self.0;
+        if panic::catch_unwind(AssertUnwindSafe(|| f(observer))).is_err() {
+            error!("connection observer panicked in {}", callback);
+        }
+    }
+}
+
+impl fmt::Debug for ObserverHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConnectionObserver {{ .. }}")
+    }
+}
+
+impl PartialEq for ObserverHandle {
+    fn eq(&self, other: &ObserverHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
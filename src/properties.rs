@@ -0,0 +1,101 @@
+use crate::errors::*;
+use crate::AmqpProperties;
+use std::time::Duration;
+
+/// Helpers for [`AmqpProperties`](type.AmqpProperties.html) that take and return typed values
+/// instead of the strings and integers carried on the wire.
+pub trait AmqpPropertiesExt: Sized {
+    /// Set the per-message TTL (the `expiration` property). The server only honors it as a string
+    /// of whole milliseconds, which is what this sets; sub-millisecond precision is dropped.
+    ///
+    /// A TTL above `i64::MAX` milliseconds is rejected with
+    /// [`InvalidExpiration`](enum.Error.html#variant.InvalidExpiration) when the message is
+    /// published.
+    fn with_expiration_duration(self, ttl: Duration) -> Self;
+
+    /// The per-message TTL, if the `expiration` property is set to a value the server accepts.
+    fn expiration_duration(&self) -> Option<Duration>;
+}
+
+impl AmqpPropertiesExt for AmqpProperties {
+    fn with_expiration_duration(self, ttl: Duration) -> Self {
+        self.with_expiration(ttl.as_millis().to_string())
+    }
+
+    fn expiration_duration(&self) -> Option<Duration> {
+        self.expiration()
+            .as_ref()
+            .and_then(|expiration| parse_expiration(expiration))
+            .map(Duration::from_millis)
+    }
+}
+
+// The server parses `expiration` as a non-negative 64-bit signed integer and closes the channel
+// with PRECONDITION_FAILED if it cannot.
+fn parse_expiration(expiration: &str) -> Option<u64> {
+    if expiration.is_empty() || !expiration.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    expiration.parse::<i64>().ok().map(|millis| millis as u64)
+}
+
+/// Checks the `expiration` property of a message about to be published, so a value the server
+/// would reject fails the publish instead of closing the channel.
+pub(crate) fn check_expiration(properties: &AmqpProperties) -> Result<()> {
+    match properties.expiration() {
+        Some(expiration) if parse_expiration(expiration).is_none() => InvalidExpirationSnafu {
+            expiration: expiration.clone(),
+        }
+        .fail(),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Publish};
+
+    #[test]
+    fn expiration_is_formatted_as_milliseconds() {
+        let properties =
+            AmqpProperties::default().with_expiration_duration(Duration::from_secs(90));
+        assert_eq!(properties.expiration(), &Some("90000".to_string()));
+
+        let properties =
+            AmqpProperties::default().with_expiration_duration(Duration::from_micros(2_999));
+        assert_eq!(properties.expiration(), &Some("2".to_string()));
+    }
+
+    #[test]
+    fn expiration_round_trips() {
+        for &millis in &[0, 1, 60_000, i64::MAX as u64] {
+            let ttl = Duration::from_millis(millis);
+            let publish = Publish::new(b"", "").with_ttl(ttl);
+            check_expiration(&publish.properties).unwrap();
+            assert_eq!(publish.properties.expiration_duration(), Some(ttl));
+        }
+        assert_eq!(AmqpProperties::default().expiration_duration(), None);
+    }
+
+    #[test]
+    fn rejects_expirations_server_would_refuse() {
+        let too_long = Duration::from_millis(i64::MAX as u64 + 1);
+        for properties in &[
+            AmqpProperties::default().with_expiration_duration(too_long),
+            AmqpProperties::default().with_expiration_duration(Duration::MAX),
+            AmqpProperties::default().with_expiration("-1".to_string()),
+            AmqpProperties::default().with_expiration("+5".to_string()),
+            AmqpProperties::default().with_expiration("1.5".to_string()),
+            AmqpProperties::default().with_expiration(String::new()),
+        ] {
+            assert_eq!(properties.expiration_duration(), None);
+            match check_expiration(properties) {
+                Err(Error::InvalidExpiration { expiration }) => {
+                    assert_eq!(properties.expiration(), &Some(expiration))
+                }
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
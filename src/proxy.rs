@@ -0,0 +1,193 @@
+use crate::errors::*;
+use crate::Endpoint;
+use snafu::ResultExt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// A proxy to open connections through; see
+/// [`ConnectionOptions::proxy`](struct.ConnectionOptions.html#method.proxy).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Proxy {
+    /// A SOCKS5 proxy at `addr`, optionally requiring username/password authentication.
+    ///
+    /// Endpoints given by host name are passed to the proxy unresolved, so the proxy does the DNS
+    /// lookup; endpoints given by IP address are passed as addresses.
+    Socks5 {
+        addr: SocketAddr,
+        auth: Option<(String, String)>,
+    },
+}
+
+// SOCKS5 constants (RFC 1928 and RFC 1929).
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USER_PASS: u8 = 2;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const USER_PASS_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+impl Proxy {
+    /// Opens a TCP connection to `endpoint` through this proxy, ready to be passed to
+    /// [`Connection::insecure_open_stream`](struct.Connection.html#method.insecure_open_stream) or
+    /// [`Connection::open_tls_stream`](struct.Connection.html#method.open_tls_stream). If
+    /// `timeout` is given, it bounds connecting to the proxy and each step of the exchange with
+    /// it.
+    ///
+    /// Failing to reach the proxy returns an error with [kind
+    /// `ConnectEndpoint`](enum.Error.html#variant.ConnectEndpoint); the proxy failing or refusing
+    /// the request returns an error with [kind `ProxyError`](enum.Error.html#variant.ProxyError).
+    pub fn connect(
+        &self,
+        endpoint: &Endpoint,
+        timeout: Option<Duration>,
+    ) -> Result<mio::net::TcpStream> {
+        match self {
+            Proxy::Socks5 { addr, auth } => {
+                let connect = match timeout {
+                    Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                    None => TcpStream::connect(addr),
+                };
+                let mut stream = connect.context(ConnectEndpointSnafu {
+                    endpoint: addr.to_string(),
+                })?;
+                set_timeouts(&stream, timeout)?;
+                socks5_connect(&mut stream, auth.as_ref(), endpoint).map_err(|err| {
+                    Error::ProxyError {
+                        message: format!("SOCKS5 proxy {}: {}", addr, err),
+                    }
+                })?;
+                set_timeouts(&stream, None)?;
+                stream.set_nonblocking(true).context(ConnectEndpointSnafu {
+                    endpoint: endpoint.to_string(),
+                })?;
+                Ok(mio::net::TcpStream::from_std(stream))
+            }
+        }
+    }
+}
+
+fn set_timeouts(stream: &TcpStream, timeout: Option<Duration>) -> Result<()> {
+    stream
+        .set_read_timeout(timeout)
+        .and_then(|()| stream.set_write_timeout(timeout))
+        .context(SetTcpOptionSnafu {
+            option: "SO_RCVTIMEO/SO_SNDTIMEO",
+        })
+}
+
+fn proxy_error<T>(message: String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+// Runs the SOCKS5 greeting, authentication (if `auth` is given) and CONNECT exchange on `stream`.
+// Errors are turned into `ProxyError` by the caller.
+fn socks5_connect<S: Read + Write>(
+    stream: &mut S,
+    auth: Option<&(String, String)>,
+    endpoint: &Endpoint,
+) -> io::Result<()> {
+    let method = if auth.is_some() {
+        METHOD_USER_PASS
+    } else {
+        METHOD_NO_AUTH
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method])?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply)?;
+    match reply {
+        [SOCKS_VERSION, m] if m == method => (),
+        [SOCKS_VERSION, METHOD_NONE_ACCEPTABLE] => {
+            return proxy_error("no acceptable authentication method".to_string())
+        }
+        [version, m] => {
+            return proxy_error(format!(
+                "unexpected greeting reply (version {}, method {})",
+                version, m
+            ))
+        }
+    }
+
+    if let Some((username, password)) = auth {
+        if username.len() > 255 || password.len() > 255 {
+            return proxy_error("username and password must be at most 255 bytes".to_string());
+        }
+        let mut request = vec![USER_PASS_VERSION, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        stream.write_all(&request)?;
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            return proxy_error("authentication failed".to_string());
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    match endpoint.host().parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let host = endpoint.host().as_bytes();
+            if host.len() > 255 {
+                return proxy_error("host name must be at most 255 bytes".to_string());
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host);
+        }
+    }
+    request.extend_from_slice(&endpoint.port().to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return proxy_error(format!("unexpected CONNECT reply version {}", reply[0]));
+    }
+    if reply[1] != 0 {
+        return proxy_error(format!(
+            "CONNECT to {} failed: {}",
+            endpoint,
+            reply_message(reply[1])
+        ));
+    }
+    // Skip the address the proxy bound for us, which we have no use for.
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0; 1];
+            stream.read_exact(&mut len)?;
+            usize::from(len[0])
+        }
+        atyp => return proxy_error(format!("unexpected CONNECT reply address type {}", atyp)),
+    };
+    let mut bound = vec![0; addr_len + 2];
+    stream.read_exact(&mut bound)?;
+    Ok(())
+}
+
+fn reply_message(rep: u8) -> String {
+    let message = match rep {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        rep => return format!("unknown reply code {}", rep),
+    };
+    message.to_string()
+}
@@ -0,0 +1,169 @@
+use crate::AmqpProperties;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where [`PublishDefaults`](struct.PublishDefaults.html) gets the `message_id` of a message
+/// published without one.
+#[derive(Clone, Default)]
+pub enum MessageIdSource {
+    /// Leave `message_id` unset.
+    #[default]
+    None,
+
+    /// A random (version 4) UUID, in its hyphenated lowercase form.
+    ///
+    /// This variant is only available if amiquip is built with the `uuid` feature.
+    #[cfg(feature = "uuid")]
+    UuidV4,
+
+    /// The result of calling this function. It is called on the publishing thread, once per
+    /// message that needs an ID.
+    Custom(Arc<dyn Fn() -> String + Send + Sync>),
+}
+
+impl MessageIdSource {
+    /// Generate message IDs by calling `f`.
+    pub fn custom<F>(f: F) -> MessageIdSource
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        MessageIdSource::Custom(Arc::new(f))
+    }
+
+    fn next_id(&self) -> Option<String> {
+        match self {
+            MessageIdSource::None => None,
+            #[cfg(feature = "uuid")]
+            MessageIdSource::UuidV4 => Some(uuid::Uuid::new_v4().to_string()),
+            MessageIdSource::Custom(f) => Some(f()),
+        }
+    }
+}
+
+impl fmt::Debug for MessageIdSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageIdSource::None => f.write_str("None"),
+            #[cfg(feature = "uuid")]
+            MessageIdSource::UuidV4 => f.write_str("UuidV4"),
+            MessageIdSource::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Properties filled in on every message published on a channel, set with
+/// [`Channel::set_publish_defaults`](struct.Channel.html#method.set_publish_defaults). A message
+/// that already sets a property keeps its own value.
+///
+/// The default fills in nothing.
+///
+/// # Example
+///
+/// ```rust
+/// # use amiquip::{Channel, MessageIdSource, PublishDefaults};
+/// # use std::sync::atomic::{AtomicU64, Ordering};
+/// # fn configure(channel: &Channel) {
+/// let next_id = AtomicU64::new(0);
+/// channel.set_publish_defaults(PublishDefaults {
+///     auto_timestamp: true,
+///     message_id: MessageIdSource::custom(move || {
+///         format!("billing-{}", next_id.fetch_add(1, Ordering::Relaxed))
+///     }),
+///     app_id: Some("billing".to_string()),
+/// });
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PublishDefaults {
+    /// If true, set `timestamp` to the time of publishing (in seconds since the Unix epoch, as
+    /// AMQP timestamps are).
+    pub auto_timestamp: bool,
+
+    /// How to generate a `message_id`.
+    pub message_id: MessageIdSource,
+
+    /// The `app_id` to set.
+    pub app_id: Option<String>,
+}
+
+impl PublishDefaults {
+    fn apply(&self, mut properties: AmqpProperties) -> AmqpProperties {
+        if self.auto_timestamp && properties.timestamp().is_none() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            properties = properties.with_timestamp(now);
+        }
+        if properties.message_id().is_none() {
+            if let Some(id) = self.message_id.next_id() {
+                properties = properties.with_message_id(id);
+            }
+        }
+        if let (Some(app_id), None) = (&self.app_id, properties.app_id()) {
+            properties = properties.with_app_id(app_id.clone());
+        }
+        properties
+    }
+}
+
+/// A channel's publish defaults, shared with the [`Publisher`](struct.Publisher.html)s created
+/// from it so that changing them applies to every publish on the channel.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct SharedPublishDefaults(Arc<Mutex<Arc<PublishDefaults>>>);
+
+impl SharedPublishDefaults {
+    pub(crate) fn set(&self, defaults: PublishDefaults) {
+        *self.0.lock().unwrap_or_else(|err| err.into_inner()) = Arc::new(defaults);
+    }
+
+    pub(crate) fn apply(&self, properties: AmqpProperties) -> AmqpProperties {
+        // Don't hold the lock while calling a user's message ID function.
+        let defaults = Arc::clone(&self.0.lock().unwrap_or_else(|err| err.into_inner()));
+        defaults.apply(properties)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_fills_in_nothing() {
+        let properties = PublishDefaults::default().apply(AmqpProperties::default());
+        assert_eq!(properties, AmqpProperties::default());
+    }
+
+    #[test]
+    fn explicit_values_win() {
+        let defaults = PublishDefaults {
+            auto_timestamp: true,
+            message_id: MessageIdSource::custom(|| panic!("message ID should not be generated")),
+            app_id: Some("default-app".to_string()),
+        };
+        let explicit = AmqpProperties::default()
+            .with_timestamp(7)
+            .with_message_id("mine".to_string())
+            .with_app_id("my-app".to_string());
+        assert_eq!(defaults.apply(explicit.clone()), explicit);
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn uuid_message_ids_are_unique() {
+        let defaults = PublishDefaults {
+            message_id: MessageIdSource::UuidV4,
+            ..PublishDefaults::default()
+        };
+        let id = || {
+            defaults
+                .apply(AmqpProperties::default())
+                .message_id()
+                .clone()
+                .unwrap()
+        };
+        let (a, b) = (id(), id());
+        assert_eq!(a.len(), 36);
+        assert_ne!(a, b);
+    }
+}
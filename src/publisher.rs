@@ -0,0 +1,80 @@
+use crate::io_loop::PublishSender;
+use crate::logging::enter_span;
+use crate::properties::check_expiration;
+use crate::publish_defaults::SharedPublishDefaults;
+use crate::{Publish, Result};
+use amq_protocol::protocol::basic::AMQPMethod as AmqpBasic;
+use amq_protocol::protocol::basic::Publish as AmqpPublish;
+
+/// A handle for publishing on a channel from any thread.
+///
+/// [`Channel`](struct.Channel.html) is not `Sync`, so sharing it between threads requires a
+/// mutex. A `Publisher`, obtained from [`Channel::publisher`](struct.Channel.html#method.publisher),
+/// is `Send + Sync + Clone` instead: each publish is serialized on the calling thread and handed
+/// to the I/O thread as a whole, so clones may publish concurrently without taking turns.
+/// Publishes made through one clone reach the server in the order they were made; there is no
+/// ordering between clones (or between a publisher and its channel).
+///
+/// A publisher cannot be used for confirmed publishing. The channel numbers its publishes once
+/// [publisher confirms](struct.Channel.html#method.enable_publisher_confirms) are enabled, and
+/// publishes made through a publisher are not counted, so publish through the channel itself if
+/// you use confirms. Publishes made through a publisher on a
+/// [transactional](struct.Channel.html#method.tx_select) channel are part of the channel's
+/// current transaction.
+///
+/// Once the channel is closed (or dropped), publishing fails.
+#[derive(Clone, Debug)]
+pub struct Publisher {
+    sender: PublishSender,
+    defaults: SharedPublishDefaults,
+}
+
+impl Publisher {
+    pub(crate) fn new(sender: PublishSender, defaults: SharedPublishDefaults) -> Publisher {
+        Publisher { sender, defaults }
+    }
+
+    /// Return the ID of the channel this publisher publishes on.
+    pub fn channel_id(&self) -> u16 {
+        self.sender.channel_id()
+    }
+
+    /// Publish a message to `exchange`. See
+    /// [`Channel::basic_publish`](struct.Channel.html#method.basic_publish).
+    pub fn publish<S: Into<String>>(&self, exchange: S, mut publish: Publish) -> Result<()> {
+        let exchange = exchange.into();
+        enter_span!(
+            DEBUG,
+            "publish",
+            channel_id = self.sender.channel_id(),
+            exchange = %exchange,
+            routing_key = %publish.routing_key,
+            body_size = publish.body.len()
+        );
+        publish.properties = self.defaults.apply(publish.properties);
+        check_expiration(&publish.properties)?;
+        self.sender.send_method_with_content(
+            AmqpBasic::Publish(AmqpPublish {
+                ticket: 0,
+                exchange,
+                routing_key: publish.routing_key,
+                mandatory: publish.mandatory,
+                immediate: publish.immediate,
+            }),
+            AmqpPublish::get_class_id(),
+            &publish.body,
+            &publish.properties,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publisher_is_send_sync_clone() {
+        fn check<T: Send + Sync + Clone>() {}
+        check::<Publisher>();
+    }
+}
@@ -1,10 +1,23 @@
-use crate::{Channel, Consumer, ConsumerOptions, Exchange, FieldTable, Get, Result};
+use crate::errors::*;
+use crate::logging::warn;
+use crate::{AmqpReplyCode, AmqpValue, Channel, Exchange, FieldTable, Publish, QueueType};
 use amq_protocol::protocol::queue::{Declare, Delete};
+use std::convert::TryFrom;
+
+#[cfg(feature = "consume")]
+use crate::{Acker, CallbackConsumer, Consumer, ConsumerMessage, ConsumerOptions, Get};
 
 /// Options passed to the server when declaring a queue.
 ///
 /// The [`default`](#impl-Default) implementation sets all boolean fields to false and has an empty
-/// set of arguments.
+/// set of arguments. [`classic`](#method.classic), [`quorum`](#method.quorum), and
+/// [`stream`](#method.stream) also set the `x-queue-type` argument (and, for quorum queues and
+/// streams, `durable`, which they require).
+///
+/// Before declaring a quorum queue or stream, the options are checked for combinations the server
+/// would reject (e.g., an `auto_delete` quorum queue); declaring one fails with
+/// [`Error::InvalidQueueDeclaration`](enum.Error.html#variant.InvalidQueueDeclaration) without
+/// sending anything to the server.
 ///
 /// # Example
 ///
@@ -45,9 +58,83 @@ pub struct QueueDeclareOptions {
     pub arguments: FieldTable,
 }
 
+// Arguments quorum queues and streams do not support (beyond those the server ignores).
+const QUORUM_UNSUPPORTED_ARGUMENTS: &[&str] =
+    &["x-max-priority", "x-queue-mode", "x-queue-version"];
+const STREAM_UNSUPPORTED_ARGUMENTS: &[&str] = &[
+    "x-max-priority",
+    "x-queue-mode",
+    "x-queue-version",
+    "x-message-ttl",
+    "x-expires",
+    "x-max-length",
+    "x-overflow",
+    "x-dead-letter-exchange",
+    "x-dead-letter-routing-key",
+    "x-single-active-consumer",
+];
+
 impl QueueDeclareOptions {
-    pub(crate) fn into_declare(self, queue: String, passive: bool, nowait: bool) -> Declare {
-        Declare {
+    /// Options for a classic queue: the defaults, with `x-queue-type` set to `classic`.
+    pub fn classic() -> QueueDeclareOptions {
+        QueueDeclareOptions::of_type(QueueType::Classic, false)
+    }
+
+    /// Options for a durable [quorum queue](https://www.rabbitmq.com/quorum-queues.html), with
+    /// `x-queue-type` set to `quorum`.
+    pub fn quorum() -> QueueDeclareOptions {
+        QueueDeclareOptions::of_type(QueueType::Quorum, true)
+    }
+
+    /// Options for a durable [stream](https://www.rabbitmq.com/streams.html), with
+    /// `x-queue-type` set to `stream`.
+    pub fn stream() -> QueueDeclareOptions {
+        QueueDeclareOptions::of_type(QueueType::Stream, true)
+    }
+
+    // The `x-max-priority` these options declare, if any. The server caps it at 255, and treats 0
+    // (or a value it cannot use) as not declaring a priority queue.
+    pub(crate) fn max_priority(&self) -> Option<u8> {
+        let max_priority = match *self.arguments.get("x-max-priority")? {
+            AmqpValue::ShortShortInt(n) => i64::from(n),
+            AmqpValue::ShortShortUInt(n) => i64::from(n),
+            AmqpValue::ShortInt(n) => i64::from(n),
+            AmqpValue::ShortUInt(n) => i64::from(n),
+            AmqpValue::LongInt(n) => i64::from(n),
+            AmqpValue::LongUInt(n) => i64::from(n),
+            AmqpValue::LongLongInt(n) => n,
+            _ => return None,
+        };
+        match max_priority {
+            n if n <= 0 => None,
+            n => Some(u8::try_from(n).unwrap_or(u8::MAX)),
+        }
+    }
+
+    fn of_type(queue_type: QueueType, durable: bool) -> QueueDeclareOptions {
+        let mut arguments = FieldTable::new();
+        arguments.insert(
+            "x-queue-type".to_string(),
+            AmqpValue::LongString(queue_type.as_ref().to_string()),
+        );
+        QueueDeclareOptions {
+            durable,
+            arguments,
+            ..QueueDeclareOptions::default()
+        }
+    }
+
+    pub(crate) fn into_declare(
+        self,
+        queue: String,
+        passive: bool,
+        nowait: bool,
+    ) -> Result<Declare> {
+        // per spec, if passive is set all other fields are ignored
+        if !passive {
+            self.validate()?;
+        }
+        Ok(Declare {
             ticket: 0,
             queue,
             passive,
@@ -56,17 +143,80 @@ impl QueueDeclareOptions {
             auto_delete: self.auto_delete,
             nowait,
             arguments: self.arguments,
+        })
+    }
+
+    // Reject combinations of options the server refuses for the declared queue type.
+    fn validate(&self) -> Result<()> {
+        let (kind, unsupported) = match self.arguments.get("x-queue-type") {
+            Some(AmqpValue::LongString(t)) if t == QueueType::Quorum.as_ref() => {
+                ("quorum queues", QUORUM_UNSUPPORTED_ARGUMENTS)
+            }
+            Some(AmqpValue::LongString(t)) if t == QueueType::Stream.as_ref() => {
+                ("streams", STREAM_UNSUPPORTED_ARGUMENTS)
+            }
+            _ => return Ok(()),
+        };
+        let invalid = |message: String| InvalidQueueDeclarationSnafu { message }.fail();
+        if !self.durable {
+            return invalid(format!("{} must be durable", kind));
+        }
+        if self.exclusive {
+            return invalid(format!("{} cannot be exclusive", kind));
         }
+        if self.auto_delete {
+            return invalid(format!("{} cannot be auto_delete", kind));
+        }
+        // Check in a fixed order, so the error does not depend on the table's.
+        match unsupported
+            .iter()
+            .find(|argument| self.arguments.contains_key(**argument))
+        {
+            Some(argument) => invalid(format!("{} do not support the {} argument", kind, argument)),
+            None => Ok(()),
+        }
+    }
+}
+
+// If `err` is the server refusing to redeclare `queue` because it already exists with a different
+// argument or flag, describe it as such. RabbitMQ names the argument in its reply text, e.g.,
+// "PRECONDITION_FAILED - inequivalent arg 'x-queue-type' for queue 'q' in vhost '/': ...".
+pub(crate) fn describe_declare_error(err: Error, queue: &str) -> Error {
+    match err {
+        Error::ServerClosedChannel {
+            reply_code: AmqpReplyCode::PreconditionFailed,
+            ref message,
+            ..
+        } => match inequivalent_argument(message) {
+            Some(argument) => Error::InequivalentQueueArgument {
+                queue: queue.to_string(),
+                argument: argument.to_string(),
+                message: message.clone(),
+            },
+            None => err,
+        },
+        err => err,
     }
 }
 
+fn inequivalent_argument(message: &str) -> Option<&str> {
+    const PREFIX: &str = "inequivalent arg '";
+    let start = message.find(PREFIX)? + PREFIX.len();
+    let len = message[start..].find('\'')?;
+    Some(&message[start..start + len])
+}
+
 /// Options passed to the server when deleting a queue.
 ///
 /// The [`default`](#impl-Default) implementation sets all boolean fields to false.
+///
+/// If a condition set here does not hold, the server closes the channel and the delete fails with
+/// [`Error::ServerClosedChannel`](enum.Error.html#variant.ServerClosedChannel), whose
+/// [`reply_code`](enum.Error.html#method.reply_code) is
+/// [`PreconditionFailed`](enum.AmqpReplyCode.html#variant.PreconditionFailed).
 #[derive(Debug, Clone, Copy, Default)]
 pub struct QueueDeleteOptions {
-    /// If true, the server will only delete the queue if it has no consumers. If true and the
-    /// queue _does_ have consumers, the server will close the current channel with an error.
+    /// If true, the server will only delete the queue if it has no consumers.
     pub if_unused: bool,
 
     /// If true, the server will only delete the queue if it has no messages.
@@ -85,12 +235,42 @@ impl QueueDeleteOptions {
     }
 }
 
+/// Information about an existing queue, returned by
+/// [`Channel::queue_exists`](struct.Channel.html#method.queue_exists) and
+/// [`Queue::redeclare`](struct.Queue.html#method.redeclare).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueInfo {
+    /// The name of the queue.
+    pub name: String,
+
+    /// The number of messages ready in the queue when the server checked it.
+    pub message_count: u32,
+
+    /// The number of active consumers on the queue when the server checked it.
+    pub consumer_count: u32,
+}
+
+/// What [`Queue::check_priority`](struct.Queue.html#method.check_priority) does with a message
+/// whose priority the queue will not honor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityValidation {
+    /// Fail with
+    /// [`Error::UnsupportedMessagePriority`](enum.Error.html#variant.UnsupportedMessagePriority).
+    Error,
+
+    /// Log a warning and succeed.
+    Warn,
+}
+
 /// Handle for a declared AMQP queue.
 pub struct Queue<'a> {
     channel: &'a Channel,
     name: String,
     message_count: Option<u32>,
     consumer_count: Option<u32>,
+    // The `x-max-priority` the queue was declared with; `None` if we don't know the arguments it
+    // was declared with (i.e., it was declared passively).
+    max_priority: Option<Option<u8>>,
 }
 
 impl<'a> Queue<'a> {
@@ -99,12 +279,14 @@ impl<'a> Queue<'a> {
         name: String,
         message_count: Option<u32>,
         consumer_count: Option<u32>,
-    ) -> Queue {
+        max_priority: Option<Option<u8>>,
+    ) -> Queue<'_> {
         Queue {
             channel,
             name,
             message_count,
             consumer_count,
+            max_priority,
         }
     }
 
@@ -140,6 +322,74 @@ impl<'a> Queue<'a> {
         self.consumer_count
     }
 
+    /// The highest message priority this queue supports, from the `x-max-priority` argument it
+    /// was declared with. `None` if it is not a priority queue, or if it was declared with
+    /// [`Channel::queue_declare_passive`](struct.Channel.html#method.queue_declare_passive) (so
+    /// its arguments are unknown).
+    #[inline]
+    pub fn max_priority(&self) -> Option<u8> {
+        self.max_priority.flatten()
+    }
+
+    /// Check that this queue will honor the priority of `publish` (set with
+    /// [`Publish::with_priority`](struct.Publish.html#method.with_priority)) before publishing it
+    /// to an exchange this queue is bound to. The server does not reject such messages: it
+    /// treats priorities above the queue's `x-max-priority` as the maximum, and ignores them
+    /// entirely on queues that are not priority queues.
+    ///
+    /// A message without a priority always passes. Otherwise, if the priority is above
+    /// [`max_priority`](#method.max_priority), or this queue was declared without
+    /// `x-max-priority`, `validation` decides whether to fail or log a warning. Nothing is
+    /// checked for queues declared passively, whose arguments are unknown.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use amiquip::{Exchange, PriorityValidation, Publish, Queue, Result};
+    /// fn publish_urgent(exchange: &Exchange, queue: &Queue, body: &[u8]) -> Result<()> {
+    ///     let publish = Publish::new(body, queue.name()).with_priority(9);
+    ///     queue.check_priority(&publish, PriorityValidation::Error)?;
+    ///     exchange.publish(publish)
+    /// }
+    /// ```
+    pub fn check_priority(&self, publish: &Publish, validation: PriorityValidation) -> Result<()> {
+        let (priority, max_priority) = match (publish.priority(), self.max_priority) {
+            (Some(priority), Some(max_priority)) => (priority, max_priority),
+            _ => return Ok(()),
+        };
+        if max_priority.is_some_and(|max_priority| priority <= max_priority) {
+            return Ok(());
+        }
+        let err = Error::UnsupportedMessagePriority {
+            queue: self.name.clone(),
+            priority,
+            max_priority,
+        };
+        match validation {
+            PriorityValidation::Error => Err(err),
+            PriorityValidation::Warn => {
+                warn!("{}", err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Synchronously ask the server for this queue's current message and consumer counts, by
+    /// passively declaring it again on the same channel. This is cheap enough to poll (e.g., to
+    /// monitor a queue's backlog).
+    ///
+    /// The counts returned by [`declared_message_count`](#method.declared_message_count) and
+    /// [`declared_consumer_count`](#method.declared_consumer_count) are not updated. If the queue
+    /// no longer exists (e.g., because it was auto-deleted), the server will close the channel.
+    pub fn redeclare(&self) -> Result<QueueInfo> {
+        let queue = self.channel.queue_declare_passive(self.name.clone())?;
+        Ok(QueueInfo {
+            message_count: queue.message_count.unwrap_or(0),
+            consumer_count: queue.consumer_count.unwrap_or(0),
+            name: queue.name,
+        })
+    }
+
     /// Synchronously get a single message from the queue.
     ///
     /// On success, returns `Some(message)` if there was a message in the queue or `None` if there
@@ -148,17 +398,49 @@ impl<'a> Queue<'a> {
     ///
     /// Prefer using [`consume`](#method.consume) to allow the server to push messages to you on
     /// demand instead of polling with `get`.
+    #[cfg(feature = "consume")]
     #[inline]
     pub fn get(&self, no_ack: bool) -> Result<Option<Get>> {
         self.channel.basic_get(self.name.clone(), no_ack)
     }
 
     /// Synchronously start a consumer on this queue.
+    #[cfg(feature = "consume")]
     #[inline]
     pub fn consume(&self, options: ConsumerOptions) -> Result<Consumer<'a>> {
         self.channel.basic_consume(self.name.clone(), options)
     }
 
+    /// Asynchronously start a consumer on this queue with the given `consumer_tag`. See
+    /// [`Channel::basic_consume_nowait`](struct.Channel.html#method.basic_consume_nowait).
+    #[cfg(feature = "consume")]
+    #[inline]
+    pub fn consume_nowait<S: Into<String>>(
+        &self,
+        consumer_tag: S,
+        options: ConsumerOptions,
+    ) -> Result<Consumer<'a>> {
+        self.channel
+            .basic_consume_nowait(self.name.clone(), consumer_tag, options)
+    }
+
+    /// Synchronously start a consumer on this queue whose messages are handled by `callback` on
+    /// one of the connection's dispatch threads. See
+    /// [`Channel::basic_consume_with_callback`](struct.Channel.html#method.basic_consume_with_callback).
+    #[cfg(feature = "consume")]
+    #[inline]
+    pub fn consume_with_callback<F>(
+        &self,
+        options: ConsumerOptions,
+        callback: F,
+    ) -> Result<CallbackConsumer<'a>>
+    where
+        F: FnMut(ConsumerMessage, &Acker) + Send + 'static,
+    {
+        self.channel
+            .basic_consume_with_callback(self.name.clone(), options, callback)
+    }
+
     /// Synchronously bind this queue to an exchange with the given routing key. `arguments` are
     /// typically optional, and are plugin / server dependent.
     #[inline]
@@ -186,7 +468,8 @@ impl<'a> Queue<'a> {
     }
 
     /// Synchronously unbind this queue from an exchange with the given routing key. `arguments`
-    /// are typically optional, and are plugin / server dependent.
+    /// are typically optional, and are plugin / server dependent. Unbinding a binding that does
+    /// not exist is not an error.
     #[inline]
     pub fn unbind<S: Into<String>>(
         &self,
@@ -0,0 +1,284 @@
+use crate::errors::*;
+use crate::{AmqpValue, FieldTable};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+// The largest TTL or expiry (in milliseconds) RabbitMQ accepts.
+const MAX_EXPIRY_MILLIS: u128 = u32::MAX as u128;
+
+/// Types of queues (the `x-queue-type` argument).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueType {
+    /// A classic queue; the server's default.
+    Classic,
+
+    /// A [quorum queue](https://www.rabbitmq.com/quorum-queues.html); must be durable and not
+    /// exclusive.
+    Quorum,
+
+    /// A [stream](https://www.rabbitmq.com/streams.html); must be durable and not exclusive.
+    Stream,
+}
+
+impl AsRef<str> for QueueType {
+    fn as_ref(&self) -> &str {
+        match self {
+            QueueType::Classic => "classic",
+            QueueType::Quorum => "quorum",
+            QueueType::Stream => "stream",
+        }
+    }
+}
+
+/// Modes of classic queues (the `x-queue-mode` argument).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueMode {
+    /// Keep messages in memory where possible.
+    Default,
+
+    /// Move messages to disk as early as possible.
+    Lazy,
+}
+
+impl AsRef<str> for QueueMode {
+    fn as_ref(&self) -> &str {
+        match self {
+            QueueMode::Default => "default",
+            QueueMode::Lazy => "lazy",
+        }
+    }
+}
+
+/// Builder for the commonly-used `x-` arguments of a queue declaration, with each argument encoded
+/// as the field type the server expects.
+///
+/// Values the server would reject (e.g., a message TTL longer than it supports) are reported by
+/// [`build`](#method.build) rather than by the server closing the channel.
+///
+/// # Example
+///
+/// ```rust
+/// # use amiquip::{QueueArguments, QueueDeclareOptions, QueueType, Result};
+/// # use std::time::Duration;
+/// # fn options() -> Result<QueueDeclareOptions> {
+/// let options = QueueDeclareOptions {
+///     durable: true,
+///     arguments: QueueArguments::new()
+///         .queue_type(QueueType::Quorum)
+///         .message_ttl(Duration::from_secs(60))
+///         .dead_letter_exchange("dead-letters")
+///         .build()?,
+///     ..QueueDeclareOptions::default()
+/// };
+/// # Ok(options)
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct QueueArguments {
+    arguments: FieldTable,
+    // The first invalid argument we were given, and why it is invalid.
+    invalid: Option<(&'static str, String)>,
+}
+
+impl QueueArguments {
+    /// Create an empty set of arguments.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `x-message-ttl`: how long a message may stay in the queue before it is discarded (or
+    /// dead-lettered). Must be at most `u32::MAX` milliseconds.
+    pub fn message_ttl(self, ttl: Duration) -> Self {
+        self.expiry("x-message-ttl", ttl, false)
+    }
+
+    /// Set `x-expires`: how long the queue may go unused before the server deletes it. Must be
+    /// nonzero and at most `u32::MAX` milliseconds.
+    pub fn expires(self, expires: Duration) -> Self {
+        self.expiry("x-expires", expires, true)
+    }
+
+    /// Set `x-max-length`: the maximum number of ready messages the queue will hold.
+    pub fn max_length(self, max_length: u64) -> Self {
+        self.long_long("x-max-length", max_length)
+    }
+
+    /// Set `x-max-length-bytes`: the maximum total size of the bodies of the ready messages the
+    /// queue will hold.
+    pub fn max_length_bytes(self, max_length_bytes: u64) -> Self {
+        self.long_long("x-max-length-bytes", max_length_bytes)
+    }
+
+    /// Set `x-dead-letter-exchange`: the exchange messages are republished to when they are
+    /// rejected, expire, or overflow the queue.
+    pub fn dead_letter_exchange<S: Into<String>>(self, exchange: S) -> Self {
+        self.set(
+            "x-dead-letter-exchange",
+            AmqpValue::LongString(exchange.into()),
+        )
+    }
+
+    /// Set `x-dead-letter-routing-key`: the routing key dead-lettered messages are republished
+    /// with, instead of their original routing key.
+    pub fn dead_letter_routing_key<S: Into<String>>(self, routing_key: S) -> Self {
+        self.set(
+            "x-dead-letter-routing-key",
+            AmqpValue::LongString(routing_key.into()),
+        )
+    }
+
+    /// Set `x-max-priority`: the highest message priority the queue supports.
+    pub fn max_priority(self, max_priority: u8) -> Self {
+        self.set(
+            "x-max-priority",
+            AmqpValue::LongInt(i32::from(max_priority)),
+        )
+    }
+
+    /// Set `x-queue-mode`.
+    pub fn queue_mode(self, mode: QueueMode) -> Self {
+        self.set(
+            "x-queue-mode",
+            AmqpValue::LongString(mode.as_ref().to_string()),
+        )
+    }
+
+    /// Set `x-queue-type`.
+    pub fn queue_type(self, queue_type: QueueType) -> Self {
+        self.set(
+            "x-queue-type",
+            AmqpValue::LongString(queue_type.as_ref().to_string()),
+        )
+    }
+
+    /// Finish building, returning the arguments to use as
+    /// [`QueueDeclareOptions::arguments`](struct.QueueDeclareOptions.html#structfield.arguments).
+    ///
+    /// Fails with [`Error::InvalidQueueArgument`](enum.Error.html#variant.InvalidQueueArgument) if
+    /// any setter was given a value the server would reject.
+    pub fn build(self) -> Result<FieldTable> {
+        match self.invalid {
+            Some((argument, message)) => InvalidQueueArgumentSnafu { argument, message }.fail(),
+            None => Ok(self.arguments),
+        }
+    }
+
+    fn expiry(self, argument: &'static str, duration: Duration, nonzero: bool) -> Self {
+        let millis = duration.as_millis();
+        if nonzero && millis == 0 {
+            return self.invalid(argument, "must be at least 1ms".to_string());
+        }
+        if millis > MAX_EXPIRY_MILLIS {
+            return self.invalid(
+                argument,
+                format!("must be at most {}ms, got {}ms", MAX_EXPIRY_MILLIS, millis),
+            );
+        }
+        self.set(argument, AmqpValue::LongLongInt(millis as i64))
+    }
+
+    fn long_long(self, argument: &'static str, value: u64) -> Self {
+        match i64::try_from(value) {
+            Ok(value) => self.set(argument, AmqpValue::LongLongInt(value)),
+            Err(_) => self.invalid(argument, format!("must be at most {}", i64::MAX)),
+        }
+    }
+
+    fn set(mut self, argument: &'static str, value: AmqpValue) -> Self {
+        self.arguments.insert(argument.to_string(), value);
+        self
+    }
+
+    fn invalid(mut self, argument: &'static str, message: String) -> Self {
+        if self.invalid.is_none() {
+            self.invalid = Some((argument, message));
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn arguments_have_expected_types() {
+        let arguments = QueueArguments::new()
+            .message_ttl(Duration::from_secs(60))
+            .expires(Duration::from_millis(1500))
+            .max_length(10)
+            .max_length_bytes(1 << 40)
+            .dead_letter_exchange("dlx")
+            .dead_letter_routing_key("dead")
+            .max_priority(10)
+            .queue_mode(QueueMode::Lazy)
+            .queue_type(QueueType::Classic)
+            .build()
+            .unwrap();
+
+        let arg = |name: &str| arguments.get(name).cloned();
+        assert_eq!(arg("x-message-ttl"), Some(AmqpValue::LongLongInt(60000)));
+        assert_eq!(arg("x-expires"), Some(AmqpValue::LongLongInt(1500)));
+        assert_eq!(arg("x-max-length"), Some(AmqpValue::LongLongInt(10)));
+        assert_eq!(
+            arg("x-max-length-bytes"),
+            Some(AmqpValue::LongLongInt(1 << 40))
+        );
+        assert_eq!(
+            arg("x-dead-letter-exchange"),
+            Some(AmqpValue::LongString("dlx".to_string()))
+        );
+        assert_eq!(
+            arg("x-dead-letter-routing-key"),
+            Some(AmqpValue::LongString("dead".to_string()))
+        );
+        assert_eq!(arg("x-max-priority"), Some(AmqpValue::LongInt(10)));
+        assert_eq!(
+            arg("x-queue-mode"),
+            Some(AmqpValue::LongString("lazy".to_string()))
+        );
+        assert_eq!(
+            arg("x-queue-type"),
+            Some(AmqpValue::LongString("classic".to_string()))
+        );
+        assert_eq!(arguments.len(), 9);
+    }
+
+    #[test]
+    fn invalid_arguments_fail_build() {
+        let invalid = |arguments: QueueArguments| match arguments.build() {
+            Err(Error::InvalidQueueArgument { argument, .. }) => argument,
+            other => panic!("unexpected result {:?}", other),
+        };
+
+        let max = Duration::from_millis(u64::from(u32::MAX));
+        assert!(QueueArguments::new().message_ttl(max).build().is_ok());
+        assert!(QueueArguments::new()
+            .message_ttl(Duration::from_secs(0))
+            .build()
+            .is_ok());
+        assert_eq!(
+            invalid(QueueArguments::new().message_ttl(max + Duration::from_millis(1))),
+            "x-message-ttl"
+        );
+        assert_eq!(
+            invalid(QueueArguments::new().expires(Duration::from_secs(0))),
+            "x-expires"
+        );
+        assert_eq!(
+            invalid(QueueArguments::new().max_length_bytes(u64::MAX)),
+            "x-max-length-bytes"
+        );
+
+        // The first invalid argument is reported.
+        assert_eq!(
+            invalid(
+                QueueArguments::new()
+                    .max_length(u64::MAX)
+                    .expires(Duration::from_secs(0))
+            ),
+            "x-max-length"
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+
+/// An AMQP reply code, as sent by the server when it closes a channel or connection.
+///
+/// Returned by [`Error::reply_code`](enum.Error.html#method.reply_code) and carried by
+/// [`Error::ServerClosedChannel`](enum.Error.html#variant.ServerClosedChannel) and
+/// [`Error::ServerClosedConnection`](enum.Error.html#variant.ServerClosedConnection). Codes
+/// that are not defined by AMQP 0-9-1 are represented as `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AmqpReplyCode {
+    /// 200: the close was requested and completed normally.
+    ReplySuccess,
+
+    /// 311: the message was too large to deliver.
+    ContentTooLarge,
+
+    /// 312: a mandatory message could not be routed.
+    NoRoute,
+
+    /// 313: an immediate message had no consumer to deliver it to.
+    NoConsumers,
+
+    /// 320: an operator forced the connection closed (e.g., the broker is shutting down).
+    ConnectionForced,
+
+    /// 402: the client tried to use an invalid virtual host.
+    InvalidPath,
+
+    /// 403: the client is not permitted to access the requested resource.
+    AccessRefused,
+
+    /// 404: the requested exchange or queue does not exist.
+    NotFound,
+
+    /// 405: the requested resource is locked (e.g., by another connection's exclusive queue).
+    ResourceLocked,
+
+    /// 406: a precondition failed (e.g., redeclaring a queue with different arguments).
+    PreconditionFailed,
+
+    /// 501: the server received a malformed frame.
+    FrameError,
+
+    /// 502: the server received a frame with invalid field values.
+    SyntaxError,
+
+    /// 503: the client sent an invalid sequence of frames.
+    CommandInvalid,
+
+    /// 504: the client tried to use a channel that is not open.
+    ChannelError,
+
+    /// 505: the server received a frame it did not expect.
+    UnexpectedFrame,
+
+    /// 506: the server could not complete the request because it ran out of a resource.
+    ResourceError,
+
+    /// 530: the client tried to do something the server does not allow.
+    NotAllowed,
+
+    /// 540: the client tried to use functionality the server does not implement.
+    NotImplemented,
+
+    /// 541: the server hit an internal error.
+    InternalError,
+
+    /// Any reply code not listed above.
+    Other(u16),
+}
+
+impl AmqpReplyCode {
+    /// Maps a numeric reply code to an `AmqpReplyCode`.
+    pub fn from_code(code: u16) -> AmqpReplyCode {
+        use AmqpReplyCode::*;
+        match code {
+            200 => ReplySuccess,
+            311 => ContentTooLarge,
+            312 => NoRoute,
+            313 => NoConsumers,
+            320 => ConnectionForced,
+            402 => InvalidPath,
+            403 => AccessRefused,
+            404 => NotFound,
+            405 => ResourceLocked,
+            406 => PreconditionFailed,
+            501 => FrameError,
+            502 => SyntaxError,
+            503 => CommandInvalid,
+            504 => ChannelError,
+            505 => UnexpectedFrame,
+            506 => ResourceError,
+            530 => NotAllowed,
+            540 => NotImplemented,
+            541 => InternalError,
+            code => Other(code),
+        }
+    }
+
+    /// The numeric reply code.
+    pub fn code(self) -> u16 {
+        use AmqpReplyCode::*;
+        match self {
+            ReplySuccess => 200,
+            ContentTooLarge => 311,
+            NoRoute => 312,
+            NoConsumers => 313,
+            ConnectionForced => 320,
+            InvalidPath => 402,
+            AccessRefused => 403,
+            NotFound => 404,
+            ResourceLocked => 405,
+            PreconditionFailed => 406,
+            FrameError => 501,
+            SyntaxError => 502,
+            CommandInvalid => 503,
+            ChannelError => 504,
+            UnexpectedFrame => 505,
+            ResourceError => 506,
+            NotAllowed => 530,
+            NotImplemented => 540,
+            InternalError => 541,
+            Other(code) => code,
+        }
+    }
+}
+
+impl From<u16> for AmqpReplyCode {
+    fn from(code: u16) -> AmqpReplyCode {
+        AmqpReplyCode::from_code(code)
+    }
+}
+
+impl fmt::Display for AmqpReplyCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AmqpReplyCode::Other(code) => write!(f, "{}", code),
+            reply_code => write!(f, "{} ({:?})", reply_code.code(), reply_code),
+        }
+    }
+}
+
+// The AMQP name (e.g., "queue.declare") of the method with the given class and method IDs, as
+// reported in a server's channel.close or connection.close.
+pub(crate) fn method_name(class_id: u16, method_id: u16) -> Option<&'static str> {
+    let name = match (class_id, method_id) {
+        (10, 10) => "connection.start",
+        (10, 11) => "connection.start-ok",
+        (10, 20) => "connection.secure",
+        (10, 21) => "connection.secure-ok",
+        (10, 30) => "connection.tune",
+        (10, 31) => "connection.tune-ok",
+        (10, 40) => "connection.open",
+        (10, 41) => "connection.open-ok",
+        (10, 50) => "connection.close",
+        (10, 51) => "connection.close-ok",
+        (10, 60) => "connection.blocked",
+        (10, 61) => "connection.unblocked",
+        (20, 10) => "channel.open",
+        (20, 11) => "channel.open-ok",
+        (20, 20) => "channel.flow",
+        (20, 21) => "channel.flow-ok",
+        (20, 40) => "channel.close",
+        (20, 41) => "channel.close-ok",
+        (40, 10) => "exchange.declare",
+        (40, 11) => "exchange.declare-ok",
+        (40, 20) => "exchange.delete",
+        (40, 21) => "exchange.delete-ok",
+        (40, 30) => "exchange.bind",
+        (40, 31) => "exchange.bind-ok",
+        (40, 40) => "exchange.unbind",
+        (40, 51) => "exchange.unbind-ok",
+        (50, 10) => "queue.declare",
+        (50, 11) => "queue.declare-ok",
+        (50, 20) => "queue.bind",
+        (50, 21) => "queue.bind-ok",
+        (50, 30) => "queue.purge",
+        (50, 31) => "queue.purge-ok",
+        (50, 40) => "queue.delete",
+        (50, 41) => "queue.delete-ok",
+        (50, 50) => "queue.unbind",
+        (50, 51) => "queue.unbind-ok",
+        (60, 10) => "basic.qos",
+        (60, 11) => "basic.qos-ok",
+        (60, 20) => "basic.consume",
+        (60, 21) => "basic.consume-ok",
+        (60, 30) => "basic.cancel",
+        (60, 31) => "basic.cancel-ok",
+        (60, 40) => "basic.publish",
+        (60, 50) => "basic.return",
+        (60, 60) => "basic.deliver",
+        (60, 70) => "basic.get",
+        (60, 71) => "basic.get-ok",
+        (60, 72) => "basic.get-empty",
+        (60, 80) => "basic.ack",
+        (60, 90) => "basic.reject",
+        (60, 100) => "basic.recover-async",
+        (60, 110) => "basic.recover",
+        (60, 111) => "basic.recover-ok",
+        (60, 120) => "basic.nack",
+        (85, 10) => "confirm.select",
+        (85, 11) => "confirm.select-ok",
+        (90, 10) => "tx.select",
+        (90, 11) => "tx.select-ok",
+        (90, 20) => "tx.commit",
+        (90, 21) => "tx.commit-ok",
+        (90, 30) => "tx.rollback",
+        (90, 31) => "tx.rollback-ok",
+        _ => return None,
+    };
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_codes_round_trip() {
+        for code in 0..=1000 {
+            assert_eq!(AmqpReplyCode::from_code(code).code(), code);
+        }
+        assert_eq!(AmqpReplyCode::from(406), AmqpReplyCode::PreconditionFailed);
+        assert_eq!(AmqpReplyCode::from(999), AmqpReplyCode::Other(999));
+    }
+
+    #[test]
+    fn method_names() {
+        assert_eq!(method_name(50, 10), Some("queue.declare"));
+        assert_eq!(method_name(60, 40), Some("basic.publish"));
+        assert_eq!(method_name(0, 0), None);
+    }
+}
@@ -4,8 +4,10 @@ use amq_protocol::protocol::basic::Return as AmqpReturn;
 /// An unpublished message returned to the publishing channel.
 ///
 /// To receive returned messages, you must call
-/// [`Channel::listen_for_returns`](struct.Channel.html#method.listen_for_returns). If the server
-/// returns messages and that method has not been called, the returned message will be discarded.
+/// [`Channel::listen_for_returns`](struct.Channel.html#method.listen_for_returns) or
+/// [`Channel::listen_for_returns_bounded`](struct.Channel.html#method.listen_for_returns_bounded).
+/// Messages returned before either has been called are held for the first listener, up to a small
+/// limit; older ones are discarded.
 #[derive(Clone, Debug)]
 pub struct Return {
     /// AMQP code providing information about why the message was undeliverable.
@@ -0,0 +1,395 @@
+use crate::errors::*;
+use crate::logging::{debug, error, warn};
+use crate::{
+    AmqpProperties, Channel, Connection, ConsumerMessage, ConsumerOptions, Delivery, Publish,
+};
+use crossbeam_channel::{never, select, Receiver, Sender};
+use snafu::ResultExt;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// An error returned by an [`RpcServer`](struct.RpcServer.html) handler. The request is nacked
+/// (see [`RpcServerOptions::requeue_on_error`](struct.RpcServerOptions.html#structfield.requeue_on_error))
+/// and no reply is sent.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcError {
+    message: String,
+}
+
+impl RpcError {
+    /// Create an error with the given description, which is logged when the request is nacked.
+    pub fn new<S: Into<String>>(message: S) -> RpcError {
+        RpcError {
+            message: message.into(),
+        }
+    }
+
+    /// The description of this error.
+    #[inline]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+/// What an [`RpcServer`](struct.RpcServer.html) does with requests that have no `reply_to`
+/// property.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingReplyTo {
+    /// Run the handler as usual (acking or nacking based on its result) and discard its reply.
+    Process,
+
+    /// Reject the request without requeueing it, and without running the handler.
+    Reject,
+}
+
+/// Options for starting an [`RpcServer`](struct.RpcServer.html).
+///
+/// The [`default`](#impl-Default) implementation uses a single worker thread, no prefetch limit,
+/// the default [`ConsumerOptions`](struct.ConsumerOptions.html), does not requeue failed requests,
+/// and processes requests that have no `reply_to`.
+#[derive(Clone, Debug)]
+pub struct RpcServerOptions {
+    /// Number of threads running the handler. Must be at least 1.
+    pub workers: usize,
+
+    /// Options used for the server's consumer. `no_ack` must be false, since the server acks
+    /// each request after replying to it.
+    /// [`stream_bodies_above`](struct.ConsumerOptions.html#structfield.stream_bodies_above) is
+    /// ignored; handlers always receive whole requests.
+    pub consumer: ConsumerOptions,
+
+    /// If set, the server calls [`Channel::qos`](struct.Channel.html#method.qos) with this
+    /// prefetch count before starting its consumer. This bounds how many requests are queued up
+    /// waiting for a worker.
+    pub prefetch_count: Option<u16>,
+
+    /// Whether requests the handler fails (or panics) on are requeued when they are nacked.
+    pub requeue_on_error: bool,
+
+    /// What to do with requests that have no `reply_to` property.
+    pub missing_reply_to: MissingReplyTo,
+}
+
+impl Default for RpcServerOptions {
+    fn default() -> RpcServerOptions {
+        RpcServerOptions {
+            workers: 1,
+            consumer: ConsumerOptions::default(),
+            prefetch_count: None,
+            requeue_on_error: false,
+            missing_reply_to: MissingReplyTo::Process,
+        }
+    }
+}
+
+type Outcome = (Delivery, std::result::Result<Vec<u8>, RpcError>);
+
+/// A server answering RPC requests consumed from a queue.
+///
+/// The server owns a channel and a consumer on a dedicated thread, and hands each request to a
+/// pool of worker threads that run the handler. When the handler returns `Ok(body)`, `body` is
+/// published through the default exchange to the request's `reply_to` queue with the request's
+/// `correlation_id`, and the request is then acked. When the handler returns an error (or
+/// panics), the request is nacked and no reply is sent.
+///
+/// [`shutdown`](#method.shutdown) (or dropping the server) cancels the consumer, waits for
+/// every request already delivered to be handled and acknowledged, and then closes the channel.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use amiquip::{Connection, Result, RpcError, RpcServer, RpcServerOptions};
+///
+/// fn serve(connection: &mut Connection) -> Result<()> {
+///     let options = RpcServerOptions {
+///         workers: 4,
+///         prefetch_count: Some(8),
+///         ..RpcServerOptions::default()
+///     };
+///     let server = RpcServer::start(connection, "rpc_queue", options, |delivery| {
+///         let n: u64 = std::str::from_utf8(&delivery.body)
+///             .ok()
+///             .and_then(|s| s.parse().ok())
+///             .ok_or_else(|| RpcError::new("request is not a number"))?;
+///         Ok((n * 2).to_string().into_bytes())
+///     })?;
+///     // ... run until it's time to stop ...
+///     server.shutdown()
+/// }
+/// ```
+pub struct RpcServer {
+    shutdown_tx: Option<Sender<()>>,
+    running: Arc<AtomicBool>,
+    server: Option<JoinHandle<Result<()>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+impl RpcServer {
+    /// Start serving requests from `queue` on a new channel opened on `connection`, running
+    /// `handler` on `options.workers` threads.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `options.workers` is 0.
+    pub fn start<S, F>(
+        connection: &mut Connection,
+        queue: S,
+        options: RpcServerOptions,
+        handler: F,
+    ) -> Result<RpcServer>
+    where
+        S: Into<String>,
+        F: Fn(&Delivery) -> std::result::Result<Vec<u8>, RpcError> + Send + Sync + 'static,
+    {
+        assert!(
+            options.workers > 0,
+            "RPC server requires at least one worker"
+        );
+        let channel = connection.open_channel(None)?;
+        let queue = queue.into();
+
+        // Declared before the work channel so that if we bail out early, the work sender is
+        // dropped (letting the workers exit) before `server`'s Drop joins them.
+        let mut server = RpcServer {
+            shutdown_tx: None,
+            running: Arc::new(AtomicBool::new(true)),
+            server: None,
+            workers: Vec::with_capacity(options.workers),
+        };
+        let (work_tx, work_rx) = crossbeam_channel::unbounded::<Delivery>();
+        let (outcome_tx, outcome_rx) = crossbeam_channel::unbounded::<Outcome>();
+        let handler = Arc::new(handler);
+
+        for i in 0..options.workers {
+            let work_rx = work_rx.clone();
+            let outcome_tx = outcome_tx.clone();
+            let handler = Arc::clone(&handler);
+            let join_handle = thread::Builder::new()
+                .name(format!("amiquip-rpc-worker-{}", i))
+                .spawn(move || {
+                    for delivery in work_rx.iter() {
+                        let reply = panic::catch_unwind(AssertUnwindSafe(|| handler(&delivery)))
+                            .unwrap_or_else(|_| Err(RpcError::new("handler panicked")));
+                        if outcome_tx.send((delivery, reply)).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .context(ForkFailedSnafu)?;
+            server.workers.push(join_handle);
+        }
+        drop(outcome_tx);
+
+        let (shutdown_tx, shutdown_rx) = crossbeam_channel::bounded(0);
+        let (started_tx, started_rx) = crossbeam_channel::bounded(1);
+        let running = Arc::clone(&server.running);
+        let join_handle = thread::Builder::new()
+            .name("amiquip-rpc".to_string())
+            .spawn(move || {
+                let result = run_server(
+                    channel,
+                    queue,
+                    options,
+                    work_tx,
+                    outcome_rx,
+                    shutdown_rx,
+                    started_tx,
+                );
+                running.store(false, Ordering::SeqCst);
+                result
+            })
+            .context(ForkFailedSnafu)?;
+        server.shutdown_tx = Some(shutdown_tx);
+        server.server = Some(join_handle);
+
+        match started_rx.recv() {
+            Ok(Ok(())) => Ok(server),
+            Ok(Err(err)) => Err(err),
+            Err(_) => server.stop().and(RpcServerStoppedSnafu.fail()),
+        }
+    }
+
+    /// Returns true until the server stops, either because of a call to
+    /// [`shutdown`](#method.shutdown) or because its consumer ended or failed (e.g., because the
+    /// server closed its channel). The reason it stopped is returned by `shutdown`.
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Cancel the server's consumer, wait for all requests already delivered to it to be handled,
+    /// replied to, and acknowledged, then close its channel and stop its threads.
+    ///
+    /// Returns an error if the server had already stopped because of an error, or if replying to
+    /// or acknowledging a request failed.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        // Dropping the shutdown sender tells the server thread to cancel its consumer.
+        drop(self.shutdown_tx.take());
+        let result = match self.server.take() {
+            Some(join_handle) => join_handle
+                .join()
+                .unwrap_or_else(|_| RpcServerStoppedSnafu.fail()),
+            None => Ok(()),
+        };
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        result
+    }
+}
+
+fn run_server(
+    channel: Channel,
+    queue: String,
+    options: RpcServerOptions,
+    work_tx: Sender<Delivery>,
+    outcomes: Receiver<Outcome>,
+    shutdown: Receiver<()>,
+    started: Sender<Result<()>>,
+) -> Result<()> {
+    let consumer = match options
+        .prefetch_count
+        .map_or(Ok(()), |count| channel.qos(0, count, false))
+        .and_then(|()| {
+            let consumer_options = ConsumerOptions {
+                stream_bodies_above: None,
+                ..options.consumer.clone()
+            };
+            channel.basic_consume(queue, consumer_options)
+        }) {
+        Ok(consumer) => {
+            let _ = started.send(Ok(()));
+            consumer
+        }
+        Err(err) => {
+            let _ = started.send(Err(err));
+            return Ok(());
+        }
+    };
+    debug!(
+        "RPC server started on channel {} ({})",
+        channel.channel_id(),
+        consumer.consumer_tag()
+    );
+
+    let stopped_messages = never();
+    let stopped_shutdown = never();
+    let mut messages = consumer.receiver();
+    let mut shutdown = &shutdown;
+    let mut ended = None;
+    let mut in_flight = 0usize;
+
+    while ended.is_none() || in_flight > 0 {
+        select! {
+            recv(messages) -> message => match message {
+                Ok(ConsumerMessage::Delivery(delivery)) => {
+                    if delivery.properties.reply_to().is_none()
+                        && options.missing_reply_to == MissingReplyTo::Reject
+                    {
+                        warn!(
+                            "RPC server rejecting delivery {} with no reply_to",
+                            delivery.delivery_tag()
+                        );
+                        consumer.reject(delivery, false)?;
+                    } else {
+                        // The workers only exit after we drop work_tx, so this can't fail.
+                        let _ = work_tx.send(delivery);
+                        in_flight += 1;
+                    }
+                }
+                Ok(ConsumerMessage::ClientCancelled) => {
+                    ended = Some(Ok(()));
+                    messages = &stopped_messages;
+                }
+                Ok(ConsumerMessage::ServerClosedChannel(err))
+                | Ok(ConsumerMessage::ServerClosedConnection(err)) => {
+                    ended = Some(Err(err));
+                    messages = &stopped_messages;
+                }
+                Ok(reason) => {
+                    warn!("RPC server consumer ended: {:?}", reason);
+                    ended = Some(ConsumerEndedSnafu {
+                        consumer_tag: consumer.consumer_tag(),
+                    }
+                    .fail());
+                    messages = &stopped_messages;
+                }
+                Err(_) => {
+                    ended = Some(ConsumerEndedSnafu {
+                        consumer_tag: consumer.consumer_tag(),
+                    }
+                    .fail());
+                    messages = &stopped_messages;
+                }
+            },
+            recv(outcomes) -> outcome => {
+                // The workers only exit after we drop work_tx, so this can't fail.
+                let (delivery, reply) = match outcome {
+                    Ok(outcome) => outcome,
+                    Err(_) => return RpcServerStoppedSnafu.fail(),
+                };
+                in_flight -= 1;
+                match reply {
+                    Ok(body) => {
+                        if let Some(reply_to) = delivery.properties.reply_to() {
+                            let mut properties = AmqpProperties::default();
+                            if let Some(correlation_id) = delivery.properties.correlation_id() {
+                                properties = properties.with_correlation_id(correlation_id.clone());
+                            }
+                            channel.basic_publish(
+                                "",
+                                Publish::with_properties(&body, reply_to.clone(), properties),
+                            )?;
+                        }
+                        consumer.ack(delivery)?;
+                    }
+                    Err(err) => {
+                        error!(
+                            "RPC handler failed on delivery {}: {}",
+                            delivery.delivery_tag(),
+                            err
+                        );
+                        consumer.nack(delivery, options.requeue_on_error)?;
+                    }
+                }
+            },
+            recv(shutdown) -> _ => {
+                shutdown = &stopped_shutdown;
+                if ended.is_none() {
+                    // Deliveries that arrive before the server confirms the cancellation are
+                    // still received (and handled) before ClientCancelled.
+                    consumer.cancel()?;
+                }
+            },
+        }
+    }
+
+    debug!("RPC server on channel {} stopped", channel.channel_id());
+    drop(consumer);
+    let result = ended.unwrap_or(Ok(()));
+    if result.is_ok() {
+        channel.close()?;
+    }
+    result
+}
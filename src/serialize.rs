@@ -10,10 +10,18 @@ use amq_protocol::protocol::confirm::AMQPMethod as AmqpConfirm;
 use amq_protocol::protocol::connection::AMQPMethod as AmqpConnection;
 use amq_protocol::protocol::exchange::AMQPMethod as AmqpExchange;
 use amq_protocol::protocol::queue::AMQPMethod as AmqpQueue;
-use amq_protocol::protocol::AMQPClass;
+use amq_protocol::protocol::tx::AMQPMethod as AmqpTx;
+use amq_protocol::protocol::{constants, gen_class, AMQPClass};
 use cookie_factory::GenError;
-use std::ops::{Index, RangeFrom};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::iter::Peekable;
+use std::mem;
+use std::ops::{Index, Range, RangeFrom};
 use std::result::Result as StdResult;
+use std::slice;
+use std::sync::Arc;
 
 pub trait TryFromAmqpClass: Sized {
     fn try_from(class: AMQPClass) -> Result<Self>;
@@ -101,6 +109,22 @@ impl_try_from_class!(
     AmqpConfirm::SelectOk
 );
 
+impl_try_from_class!(
+    amq_protocol::protocol::tx::SelectOk,
+    AMQPClass::Tx,
+    AmqpTx::SelectOk
+);
+impl_try_from_class!(
+    amq_protocol::protocol::tx::CommitOk,
+    AMQPClass::Tx,
+    AmqpTx::CommitOk
+);
+impl_try_from_class!(
+    amq_protocol::protocol::tx::RollbackOk,
+    AMQPClass::Tx,
+    AmqpTx::RollbackOk
+);
+
 impl_try_from_class!(
     amq_protocol::protocol::queue::DeclareOk,
     AMQPClass::Queue,
@@ -195,6 +219,12 @@ impl IntoAmqpClass for AmqpConfirm {
     }
 }
 
+impl IntoAmqpClass for AmqpTx {
+    fn into_class(self) -> AMQPClass {
+        AMQPClass::Tx(self)
+    }
+}
+
 impl IntoAmqpClass for AmqpQueue {
     fn into_class(self) -> AMQPClass {
         AMQPClass::Queue(self)
@@ -207,27 +237,82 @@ impl IntoAmqpClass for AmqpExchange {
     }
 }
 
+// Numbers of frames (and, of those, publishes and acknowledgements) serialized into an
+// `OutputBuffer`. Rejects are counted as nacks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct FrameCounts {
+    pub(crate) frames: u64,
+    pub(crate) publishes: u64,
+    pub(crate) acks: u64,
+    pub(crate) nacks: u64,
+}
+
+impl FrameCounts {
+    pub(crate) fn add(&mut self, other: FrameCounts) {
+        self.frames += other.frames;
+        self.publishes += other.publishes;
+        self.acks += other.acks;
+        self.nacks += other.nacks;
+    }
+}
+
+// Serialized frames waiting to be written. Frames are serialized into `data`, except for the
+// bodies of shared content (see `push_shared_content_body`), which are referenced rather than
+// copied: each entry in `shared` is logically spliced into `data` at its offset.
+#[derive(Debug)]
+pub(crate) struct OutputBuffer {
+    data: Vec<u8>,
+    shared: Vec<SharedSegment>,
+    counts: FrameCounts,
+}
+
 #[derive(Debug)]
-pub(crate) struct OutputBuffer(Vec<u8>);
+struct SharedSegment {
+    // Offset into `data` this segment comes before.
+    at: usize,
+    body: Arc<[u8]>,
+    range: Range<usize>,
+}
+
+impl SharedSegment {
+    #[inline]
+    fn bytes(&self) -> &[u8] {
+        &self.body[self.range.clone()]
+    }
+}
 
 impl OutputBuffer {
     pub fn with_protocol_header() -> OutputBuffer {
-        OutputBuffer(b"AMQP\x00\x00\x09\x01".to_vec())
+        OutputBuffer {
+            data: b"AMQP\x00\x00\x09\x01".to_vec(),
+            shared: Vec::new(),
+            counts: FrameCounts::default(),
+        }
     }
 
     pub(crate) fn empty() -> OutputBuffer {
-        OutputBuffer(Vec::new())
+        OutputBuffer {
+            data: Vec::new(),
+            shared: Vec::new(),
+            counts: FrameCounts::default(),
+        }
     }
 
     pub(crate) fn drain_into_new_buf(&mut self) -> OutputBuffer {
-        let mut buf = OutputBuffer(Vec::with_capacity(self.len()));
-        buf.0.append(&mut self.0);
+        let mut buf = OutputBuffer {
+            data: Vec::with_capacity(self.data.len()),
+            shared: mem::take(&mut self.shared),
+            counts: self.counts,
+        };
+        buf.data.append(&mut self.data);
+        self.counts = FrameCounts::default();
         buf
     }
 
     pub fn push_heartbeat(&mut self) {
         // serializing heartbeat cannot fail; safe to unwrap.
-        serialize(&mut self.0, |buf, pos| gen_heartbeat_frame((buf, pos)))
+        serialize(&mut self.data, |buf, pos| gen_heartbeat_frame((buf, pos)));
+        self.counts.frames += 1;
     }
 
     // This can only fail if there is a bug in the serialization library; it is probably
@@ -237,73 +322,172 @@ impl OutputBuffer {
         M: IntoAmqpClass,
     {
         let class = method.into_class();
-        serialize(&mut self.0, |buf, pos| {
+        serialize(&mut self.data, |buf, pos| {
             gen_method_frame((buf, pos), channel_id, &class)
-        })
+        });
+        self.counts.frames += 1;
+        match class {
+            AMQPClass::Basic(AmqpBasic::Publish(_)) => self.counts.publishes += 1,
+            AMQPClass::Basic(AmqpBasic::Ack(_)) => self.counts.acks += 1,
+            AMQPClass::Basic(AmqpBasic::Nack(_)) | AMQPClass::Basic(AmqpBasic::Reject(_)) => {
+                self.counts.nacks += 1
+            }
+            _ => (),
+        }
     }
 
     pub(crate) fn push_content_header(
         &mut self,
         channel_id: u16,
         class_id: u16,
-        length: usize,
+        length: u64,
         properties: &AMQPProperties,
     ) {
-        let length = length as u64;
-        serialize(&mut self.0, |buf, pos| {
+        serialize(&mut self.data, |buf, pos| {
             gen_content_header_frame((buf, pos), channel_id, class_id, length, properties)
-        })
+        });
+        self.counts.frames += 1;
     }
 
     pub(crate) fn push_content_body(&mut self, channel_id: u16, content: &[u8]) {
-        serialize(&mut self.0, |buf, pos| {
+        serialize(&mut self.data, |buf, pos| {
             gen_content_body_frame((buf, pos), channel_id, content)
-        })
+        });
+        self.counts.frames += 1;
     }
 
-    #[inline]
-    pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+    // Like `push_content_body` with `&body[range]`, but the body is referenced until it has been
+    // written instead of being copied. Only the frame's 8 bytes of framing are serialized.
+    pub(crate) fn push_shared_content_body(
+        &mut self,
+        channel_id: u16,
+        body: &Arc<[u8]>,
+        range: Range<usize>,
+    ) {
+        self.data.push(constants::FRAME_BODY);
+        self.data.extend_from_slice(&channel_id.to_be_bytes());
+        self.data
+            .extend_from_slice(&(range.len() as u32).to_be_bytes());
+        if !range.is_empty() {
+            self.shared.push(SharedSegment {
+                at: self.data.len(),
+                body: Arc::clone(body),
+                range,
+            });
+        }
+        self.data.push(constants::FRAME_END);
+        self.counts.frames += 1;
     }
 
     #[inline]
-    pub fn len(&self) -> usize {
-        self.0.len()
+    pub(crate) fn counts(&self) -> FrameCounts {
+        self.counts
     }
 
     #[inline]
-    pub fn clear(&mut self) {
-        self.0.clear()
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
     }
 
     #[inline]
-    pub fn drain_written(&mut self, n: usize) {
-        self.0.drain(0..n);
+    pub fn len(&self) -> usize {
+        self.data.len() + self.shared.iter().map(|seg| seg.range.len()).sum::<usize>()
     }
 
-    #[inline]
-    pub fn append(&mut self, mut other: OutputBuffer) {
-        self.0.append(&mut other.0)
+    // The buffer's bytes, in order, as contiguous runs of `data` and shared bodies.
+    fn segments(&self) -> Segments<'_> {
+        Segments {
+            data: &self.data,
+            pos: 0,
+            shared: self.shared.iter().peekable(),
+        }
+    }
+
+    // A copy of the whole buffer as one slice; only needed to inspect frames, e.g. for a frame
+    // tap, and borrowed unless the buffer references shared bodies.
+    fn contiguous(&self) -> Cow<'_, [u8]> {
+        if self.shared.is_empty() {
+            return Cow::Borrowed(&self.data);
+        }
+        let mut bytes = Vec::with_capacity(self.len());
+        for segment in self.segments() {
+            bytes.extend_from_slice(segment);
+        }
+        Cow::Owned(bytes)
     }
 }
 
+// Only valid on buffers that do not reference shared bodies, which never hold anything but
+// content; e.g., a buffer holding just a method frame.
 impl Index<RangeFrom<usize>> for OutputBuffer {
     type Output = [u8];
 
     #[inline]
     fn index(&self, index: RangeFrom<usize>) -> &[u8] {
-        &self.0[index]
+        assert!(
+            self.shared.is_empty(),
+            "cannot index an output buffer holding shared content"
+        );
+        &self.data[index]
+    }
+}
+
+struct Segments<'a> {
+    data: &'a [u8],
+    pos: usize,
+    shared: Peekable<slice::Iter<'a, SharedSegment>>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        match self.shared.peek() {
+            Some(segment) if segment.at == self.pos => {
+                // unwrap is safe: we just peeked at it.
+                Some(self.shared.next().unwrap().bytes())
+            }
+            Some(segment) => {
+                let bytes = &self.data[self.pos..segment.at];
+                self.pos = segment.at;
+                Some(bytes)
+            }
+            None if self.pos < self.data.len() => {
+                let bytes = &self.data[self.pos..];
+                self.pos = self.data.len();
+                Some(bytes)
+            }
+            None => None,
+        }
     }
 }
 
+// Maximum number of buffers passed to a single vectored write; writev fails with more than
+// IOV_MAX (1024 on Linux and macOS).
+const MAX_IO_SLICES: usize = 1024;
+
+// The I/O thread's queue of data waiting to be written. Buffers handed to the I/O thread by
+// channels are queued as separate chunks rather than copied onto the end of one large buffer, and
+// written out with vectored writes. Chunks are written strictly in the order they were queued.
 pub(super) struct SealableOutputBuffer {
-    buf: OutputBuffer,
+    chunks: VecDeque<OutputBuffer>,
+    // Number of bytes at the start of the first chunk that have already been written.
+    written: usize,
+    // Number of unwritten bytes across all chunks.
+    len: usize,
     sealed: bool,
 }
 
 impl SealableOutputBuffer {
     pub(super) fn new(buf: OutputBuffer) -> SealableOutputBuffer {
-        SealableOutputBuffer { buf, sealed: false }
+        let mut this = SealableOutputBuffer {
+            chunks: VecDeque::new(),
+            written: 0,
+            len: 0,
+            sealed: false,
+        };
+        this.append(buf);
+        this
     }
 
     #[inline]
@@ -319,57 +503,123 @@ impl SealableOutputBuffer {
     #[inline]
     pub(super) fn push_heartbeat(&mut self) {
         if !self.sealed {
-            self.buf.push_heartbeat();
-        }
-    }
-
-    #[inline]
-    pub(super) fn push_method<M>(&mut self, channel_id: u16, method: M)
-    where
-        M: IntoAmqpClass,
-    {
-        if !self.sealed {
-            self.buf.push_method(channel_id, method)
+            let mut buf = OutputBuffer::empty();
+            buf.push_heartbeat();
+            self.append(buf);
         }
     }
 
     #[inline]
     pub(super) fn is_empty(&self) -> bool {
-        self.buf.is_empty()
+        self.len == 0
     }
 
     #[inline]
     pub(super) fn len(&self) -> usize {
-        self.buf.len()
+        self.len
     }
 
-    #[inline]
-    pub(super) fn clear(&mut self) {
-        self.buf.clear()
+    // The number of publishes in chunks that have not been completely written.
+    pub(super) fn unwritten_publishes(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.counts().publishes).sum()
     }
 
-    #[inline]
-    pub(super) fn drain_written(&mut self, n: usize) {
-        self.buf.drain_written(n)
+    // Discard the first `n` unwritten bytes, which have been written to the socket, passing each
+    // chunk that is now completely written to `written` (a chunk never ends mid-frame).
+    pub(super) fn drain_written<F: FnMut(&OutputBuffer)>(&mut self, mut n: usize, mut written: F) {
+        assert!(n <= self.len, "drained more than was buffered");
+        self.len -= n;
+        while n > 0 {
+            let front = self.chunks[0].len() - self.written;
+            if n < front {
+                self.written += n;
+                return;
+            }
+            n -= front;
+            // unwrap is safe: `n <= self.len` means there are chunks left to drain.
+            written(&self.chunks.pop_front().unwrap());
+            self.written = 0;
+        }
     }
 
-    #[inline]
     pub(super) fn append(&mut self, other: OutputBuffer) {
-        if !self.sealed {
-            self.buf.append(other)
+        if !self.sealed && !other.is_empty() {
+            self.len += other.len();
+            self.chunks.push_back(other);
+        }
+    }
+
+    // The unwritten data, covering at most `limit` bytes, as slices for a vectored write. Shared
+    // bodies get slices of their own, so they are written without being copied.
+    pub(super) fn io_slices(&self, limit: usize) -> Vec<IoSlice<'_>> {
+        let mut slices = Vec::with_capacity(usize::min(self.chunks.len(), MAX_IO_SLICES));
+        let mut remaining = limit;
+        let mut skip = self.written;
+        for segment in self.chunks.iter().flat_map(OutputBuffer::segments) {
+            if skip >= segment.len() {
+                skip -= segment.len();
+                continue;
+            }
+            let segment = &segment[skip..];
+            skip = 0;
+            if remaining <= segment.len() {
+                slices.push(IoSlice::new(&segment[..remaining]));
+                break;
+            }
+            remaining -= segment.len();
+            slices.push(IoSlice::new(segment));
+            if slices.len() == MAX_IO_SLICES {
+                break;
+            }
         }
+        slices
     }
 }
 
-impl Index<RangeFrom<usize>> for SealableOutputBuffer {
-    type Output = [u8];
+// The class and method IDs of `class`.
+pub(crate) fn method_ids(class: &AMQPClass) -> (u16, u16) {
+    let mut buf = Vec::new();
+    serialize(&mut buf, |buf, pos| gen_class((buf, pos), class));
+    (
+        u16::from_be_bytes([buf[0], buf[1]]),
+        u16::from_be_bytes([buf[2], buf[3]]),
+    )
+}
 
-    #[inline]
-    fn index(&self, index: RangeFrom<usize>) -> &[u8] {
-        &self.buf[index]
+// Calls `f` with the bytes of each frame in `buf`, which must hold whole serialized frames (and
+// possibly the protocol header, which is passed to `f` as if it were a frame).
+pub(crate) fn for_each_frame<F: FnMut(&[u8])>(buf: &OutputBuffer, mut f: F) {
+    let bytes = buf.contiguous();
+    let mut bytes = &bytes[..];
+    if bytes.starts_with(b"AMQP") {
+        f(&bytes[..8]);
+        bytes = &bytes[8..];
+    }
+    // Each frame is a 7 byte header (type, channel, payload size), the payload, and a frame-end
+    // octet.
+    while bytes.len() >= 8 {
+        let size = u32::from_be_bytes([bytes[3], bytes[4], bytes[5], bytes[6]]) as usize;
+        let end = usize::min(8 + size, bytes.len());
+        f(&bytes[..end]);
+        bytes = &bytes[end..];
     }
 }
 
+// Calls `f` with the channel, class and method IDs of each method frame in `buf`, which must hold
+// whole serialized frames (and possibly the protocol header).
+pub(crate) fn for_each_method_frame<F: FnMut(u16, u16, u16)>(buf: &OutputBuffer, mut f: F) {
+    // Method payloads start with the class and method IDs.
+    for_each_frame(buf, |bytes| {
+        if bytes[0] == constants::FRAME_METHOD && bytes.len() >= 12 {
+            f(
+                u16::from_be_bytes([bytes[1], bytes[2]]),
+                u16::from_be_bytes([bytes[7], bytes[8]]),
+                u16::from_be_bytes([bytes[9], bytes[10]]),
+            );
+        }
+    });
+}
+
 fn serialize<F: Fn(&mut [u8], usize) -> StdResult<(&mut [u8], usize), GenError>>(
     buf: &mut Vec<u8>,
     f: F,
@@ -384,3 +634,179 @@ fn serialize<F: Fn(&mut [u8], usize) -> StdResult<(&mut [u8], usize), GenError>>
         buf.resize(resize_to, 0);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amq_protocol::protocol::basic::Publish;
+    use std::io::Write;
+
+    fn frame(i: u32) -> OutputBuffer {
+        let mut buf = OutputBuffer::empty();
+        buf.push_content_body(1, &i.to_be_bytes());
+        buf
+    }
+
+    // Drain `outbuf` with vectored writes of at most `limit` bytes, returning the bytes written
+    // and the number of writes it took.
+    fn write_all(outbuf: &mut SealableOutputBuffer, limit: usize) -> (Vec<u8>, usize) {
+        let mut written = Vec::new();
+        let mut writes = 0;
+        while !outbuf.is_empty() {
+            let n = written.write_vectored(&outbuf.io_slices(limit)).unwrap();
+            outbuf.drain_written(n, |_| ());
+            writes += 1;
+        }
+        (written, writes)
+    }
+
+    #[test]
+    fn burst_is_written_in_few_vectored_writes() {
+        const FRAMES: u32 = 10_000;
+        let mut outbuf = SealableOutputBuffer::new(OutputBuffer::empty());
+        let mut expected = Vec::new();
+        for i in 0..FRAMES {
+            let buf = frame(i);
+            expected.extend_from_slice(&buf[0..]);
+            outbuf.append(buf);
+        }
+        assert_eq!(outbuf.len(), expected.len());
+
+        let (written, writes) = write_all(&mut outbuf, usize::MAX);
+        assert_eq!(written, expected);
+        // One write per MAX_IO_SLICES frames, rather than one per frame.
+        assert_eq!(writes, 10);
+    }
+
+    #[test]
+    fn short_writes_preserve_order() {
+        let mut outbuf = SealableOutputBuffer::new(OutputBuffer::with_protocol_header());
+        let mut expected = b"AMQP\x00\x00\x09\x01".to_vec();
+        for i in 0..10 {
+            let buf = frame(i);
+            expected.extend_from_slice(&buf[0..]);
+            outbuf.append(buf);
+            outbuf.push_heartbeat();
+            let mut heartbeat = OutputBuffer::empty();
+            heartbeat.push_heartbeat();
+            expected.extend_from_slice(&heartbeat[0..]);
+        }
+
+        // 7 bytes at a time splits frames at every possible offset.
+        let (written, _) = write_all(&mut outbuf, 7);
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn frames_are_counted_once_fully_written() {
+        let mut outbuf = SealableOutputBuffer::new(frame(0));
+        let mut publish = OutputBuffer::empty();
+        publish.push_method(
+            1,
+            AmqpBasic::Publish(Publish {
+                ticket: 0,
+                exchange: String::new(),
+                routing_key: "q".to_string(),
+                mandatory: false,
+                immediate: false,
+            }),
+        );
+        publish.push_content_body(1, b"hello");
+        let publish_len = publish.len();
+        outbuf.append(publish);
+
+        let mut drain = |n| {
+            let mut counts = FrameCounts::default();
+            let mut methods = Vec::new();
+            outbuf.drain_written(n, |chunk| {
+                counts.add(chunk.counts());
+                for_each_method_frame(chunk, |channel_id, class_id, method_id| {
+                    methods.push((channel_id, class_id, method_id))
+                });
+            });
+            (counts, methods)
+        };
+        let first = frame(0).len();
+        assert_eq!(drain(first - 1), (FrameCounts::default(), vec![]));
+        assert_eq!(
+            drain(2),
+            (
+                FrameCounts {
+                    frames: 1,
+                    ..FrameCounts::default()
+                },
+                vec![]
+            )
+        );
+        assert_eq!(
+            drain(publish_len - 1),
+            (
+                FrameCounts {
+                    frames: 2,
+                    publishes: 1,
+                    ..FrameCounts::default()
+                },
+                vec![(1, 60, 40)]
+            )
+        );
+    }
+
+    #[test]
+    fn shared_bodies_are_referenced_not_copied() {
+        let body: Arc<[u8]> = (0..=255u8).collect::<Vec<_>>().into();
+        let mut outbuf = SealableOutputBuffer::new(OutputBuffer::empty());
+        let mut expected = Vec::new();
+        for range in &[0..100, 100..256] {
+            let mut copied = OutputBuffer::empty();
+            copied.push_content_body(1, &body[range.clone()]);
+            expected.extend_from_slice(&copied[0..]);
+            let mut shared = OutputBuffer::empty();
+            shared.push_shared_content_body(1, &body, range.clone());
+            assert_eq!(shared.len(), copied.len());
+            outbuf.append(shared);
+        }
+        assert_eq!(outbuf.len(), expected.len());
+        assert_eq!(Arc::strong_count(&body), 3);
+
+        // Each body segment is written straight from the shared allocation.
+        let body_ptrs = outbuf
+            .io_slices(usize::MAX)
+            .iter()
+            .filter(|slice| slice.len() > 8)
+            .map(|slice| slice.as_ptr())
+            .collect::<Vec<_>>();
+        assert_eq!(body_ptrs, vec![&body[0] as *const u8, &body[100]]);
+
+        // Short writes split the shared segments at every possible offset.
+        let (written, _) = write_all(&mut outbuf, 7);
+        assert_eq!(written, expected);
+        assert_eq!(Arc::strong_count(&body), 1);
+    }
+
+    #[test]
+    fn frames_of_shared_content_can_be_inspected() {
+        let body: Arc<[u8]> = b"hello world".to_vec().into();
+        let mut buf = OutputBuffer::empty();
+        buf.push_content_header(1, 60, body.len() as u64, &AMQPProperties::default());
+        buf.push_shared_content_body(1, &body, 0..5);
+        buf.push_shared_content_body(1, &body, 5..body.len());
+        buf.push_heartbeat();
+        let mut frames = Vec::new();
+        for_each_frame(&buf, |bytes| frames.push(bytes.to_vec()));
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[1], b"\x03\x00\x01\x00\x00\x00\x05hello\xce");
+        assert_eq!(frames[2], b"\x03\x00\x01\x00\x00\x00\x06 world\xce");
+    }
+
+    #[test]
+    fn sealed_buffer_discards_new_data() {
+        let mut outbuf = SealableOutputBuffer::new(frame(0));
+        let len = outbuf.len();
+        outbuf.seal();
+        outbuf.append(frame(1));
+        outbuf.push_heartbeat();
+        assert_eq!(outbuf.len(), len);
+        let (written, _) = write_all(&mut outbuf, usize::MAX);
+        assert_eq!(written, &frame(0)[0..]);
+    }
+}
@@ -0,0 +1,591 @@
+use crate::errors::*;
+use crate::logging::{debug, warn};
+use crate::{Channel, Confirm, Connection, ConsumerMessage, ConsumerOptions, Delivery};
+use crossbeam_channel::{never, select, Receiver, Sender};
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+type Opener = Box<dyn FnMut() -> Result<Connection> + Send>;
+type RoutingKeyMapper = Box<dyn Fn(&Delivery) -> String + Send>;
+
+/// Where a [`Shovel`](struct.Shovel.html) consumes messages from: a queue on a connection.
+pub struct ShovelSource {
+    connection: Option<Connection>,
+    reconnect: Option<Opener>,
+    queue: String,
+}
+
+impl fmt::Debug for ShovelSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShovelSource")
+            .field("queue", &self.queue)
+            .field("reconnect", &self.reconnect.is_some())
+            .finish()
+    }
+}
+
+impl ShovelSource {
+    /// Consume from `queue` on `connection`.
+    pub fn new<S: Into<String>>(connection: Connection, queue: S) -> ShovelSource {
+        ShovelSource {
+            connection: Some(connection),
+            reconnect: None,
+            queue: queue.into(),
+        }
+    }
+
+    /// Call `opener` to open a new connection if this side's connection dies. Without this, the
+    /// shovel stops when the connection dies.
+    pub fn reconnect_with<F>(mut self, opener: F) -> Self
+    where
+        F: FnMut() -> Result<Connection> + Send + 'static,
+    {
+        self.reconnect = Some(Box::new(opener));
+        self
+    }
+}
+
+/// Where a [`Shovel`](struct.Shovel.html) publishes messages to: an exchange on a connection.
+///
+/// By default, messages are published with the routing key they were originally published with;
+/// use [`routing_key`](#method.routing_key) or [`map_routing_key`](#method.map_routing_key) to
+/// change that.
+pub struct ShovelDestination {
+    connection: Option<Connection>,
+    reconnect: Option<Opener>,
+    exchange: String,
+    routing_key: Option<RoutingKeyMapper>,
+}
+
+impl fmt::Debug for ShovelDestination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ShovelDestination")
+            .field("exchange", &self.exchange)
+            .field("reconnect", &self.reconnect.is_some())
+            .finish()
+    }
+}
+
+impl ShovelDestination {
+    /// Publish to `exchange` (which may be `""`, the default exchange) on `connection`.
+    pub fn new<S: Into<String>>(connection: Connection, exchange: S) -> ShovelDestination {
+        ShovelDestination {
+            connection: Some(connection),
+            reconnect: None,
+            exchange: exchange.into(),
+            routing_key: None,
+        }
+    }
+
+    /// Publish every message with `routing_key`.
+    pub fn routing_key<S: Into<String>>(self, routing_key: S) -> Self {
+        let routing_key = routing_key.into();
+        self.map_routing_key(move |_| routing_key.clone())
+    }
+
+    /// Publish each message with the routing key `mapper` returns for it.
+    pub fn map_routing_key<F>(mut self, mapper: F) -> Self
+    where
+        F: Fn(&Delivery) -> String + Send + 'static,
+    {
+        self.routing_key = Some(Box::new(mapper));
+        self
+    }
+
+    /// Call `opener` to open a new connection if this side's connection dies. Without this, the
+    /// shovel stops when the connection dies.
+    pub fn reconnect_with<F>(mut self, opener: F) -> Self
+    where
+        F: FnMut() -> Result<Connection> + Send + 'static,
+    {
+        self.reconnect = Some(Box::new(opener));
+        self
+    }
+
+    fn routing_key_for(&self, delivery: &Delivery) -> String {
+        match &self.routing_key {
+            Some(mapper) => mapper(delivery),
+            None => delivery.routing_key.clone(),
+        }
+    }
+}
+
+/// Options for starting a [`Shovel`](struct.Shovel.html).
+///
+/// The [`default`](#impl-Default) implementation allows 100 messages in flight and waits 5
+/// seconds between attempts to recover from a failure.
+#[derive(Clone, Debug)]
+pub struct ShovelOptions {
+    /// The most messages that may have been published to the destination without being
+    /// confirmed yet. Also used as the source channel's prefetch count. Must be at least 1.
+    pub max_in_flight: u16,
+
+    /// How long to wait after a failure before reopening channels (and, if they died,
+    /// connections).
+    pub retry_delay: Duration,
+}
+
+impl Default for ShovelOptions {
+    fn default() -> ShovelOptions {
+        ShovelOptions {
+            max_in_flight: 100,
+            retry_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Counts of messages a [`Shovel`](struct.Shovel.html) has handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShovelStats {
+    /// Messages the destination confirmed, which were then acked on the source.
+    pub relayed: u64,
+
+    /// Messages published to the destination and not yet confirmed. In the stats returned by
+    /// [`Shovel::stop`](struct.Shovel.html#method.stop), the messages still unconfirmed when
+    /// the shovel stopped; they are requeued on the source, and may also have reached the
+    /// destination.
+    pub pending: u64,
+
+    /// Messages returned to the source to be redelivered, because the destination nacked them or
+    /// a failure on either side interrupted them. They may also have reached the destination.
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    relayed: AtomicU64,
+    pending: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> ShovelStats {
+        ShovelStats {
+            relayed: self.relayed.load(Ordering::SeqCst),
+            pending: self.pending.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Relays messages from a queue on one connection to an exchange on another (e.g., on a
+/// different broker) with at-least-once semantics.
+///
+/// The shovel consumes from its [source](struct.ShovelSource.html) with manual
+/// acknowledgments, publishes each message (body and properties unchanged) to its
+/// [destination](struct.ShovelDestination.html) on a channel in [publisher
+/// confirms](struct.Channel.html#method.enable_publisher_confirms) mode, and acks the message on
+/// the source only once the destination has confirmed it. A message the destination nacks is
+/// nacked on the source, to be redelivered. At most
+/// [`max_in_flight`](struct.ShovelOptions.html#structfield.max_in_flight) messages are awaiting
+/// confirmation at a time.
+///
+/// The shovel runs on its own thread, which owns both connections. If either side fails (e.g.,
+/// a channel is closed by the server, or a connection dies), unconfirmed messages are left
+/// unacked for the source to redeliver, and after
+/// [`retry_delay`](struct.ShovelOptions.html#structfield.retry_delay) the shovel reopens its
+/// channels, reopening a dead connection with the side's
+/// [`reconnect_with`](struct.ShovelSource.html#method.reconnect_with) opener. If a side with no
+/// opener loses its connection, the shovel stops, and [`stop`](#method.stop) returns the error.
+/// Since a message may reach the destination and then be redelivered by the source, the
+/// destination may see duplicates.
+///
+/// Dropping a shovel stops it without waiting for unconfirmed messages.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use amiquip::{Connection, Result, Shovel, ShovelDestination, ShovelOptions, ShovelSource};
+/// use std::time::{Duration, Instant};
+///
+/// fn migrate() -> Result<()> {
+///     let source = ShovelSource::new(Connection::insecure_open("amqp://old-broker")?, "orders")
+///         .reconnect_with(|| Connection::insecure_open("amqp://old-broker"));
+///     let destination =
+///         ShovelDestination::new(Connection::insecure_open("amqp://new-broker")?, "orders")
+///             .reconnect_with(|| Connection::insecure_open("amqp://new-broker"));
+///     let shovel = Shovel::start(source, destination, ShovelOptions::default())?;
+///
+///     // ... wait until the old queue has drained ...
+///
+///     let stats = shovel.stop(Instant::now() + Duration::from_secs(30))?;
+///     println!("relayed {} messages, {} left unconfirmed", stats.relayed, stats.pending);
+///     Ok(())
+/// }
+/// ```
+pub struct Shovel {
+    stop_tx: Option<Sender<Instant>>,
+    running: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl fmt::Debug for Shovel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shovel")
+            .field("running", &self.is_running())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Drop for Shovel {
+    fn drop(&mut self) {
+        let _ = self.stop_at(Instant::now());
+    }
+}
+
+impl Shovel {
+    /// Start relaying messages from `source` to `destination`. Returns once the shovel's
+    /// channels are open and it is consuming from the source; if that fails, returns the error
+    /// (without retrying).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `options.max_in_flight` is 0.
+    pub fn start(
+        source: ShovelSource,
+        destination: ShovelDestination,
+        options: ShovelOptions,
+    ) -> Result<Shovel> {
+        assert!(
+            options.max_in_flight > 0,
+            "shovel requires max_in_flight of at least 1"
+        );
+        let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+        let (started_tx, started_rx) = crossbeam_channel::bounded(1);
+        let running = Arc::new(AtomicBool::new(true));
+        let counters = Arc::new(Counters::default());
+        let mut state = ShovelState {
+            source,
+            destination,
+            options,
+            counters: Arc::clone(&counters),
+            running: Arc::clone(&running),
+            stop: stop_rx,
+        };
+        let thread = thread::Builder::new()
+            .name("amiquip-shovel".to_string())
+            .spawn(move || state.run(started_tx))
+            .context(ForkFailedSnafu)?;
+        let mut shovel = Shovel {
+            stop_tx: Some(stop_tx),
+            running,
+            counters,
+            thread: Some(thread),
+        };
+
+        match started_rx.recv() {
+            Ok(Ok(())) => Ok(shovel),
+            Ok(Err(err)) => Err(err),
+            Err(_) => shovel
+                .stop_at(Instant::now())
+                .and(ShovelStoppedSnafu.fail()),
+        }
+    }
+
+    /// Returns true until the shovel stops, either because of a call to [`stop`](#method.stop)
+    /// or because of a failure it could not recover from. The failure is returned by `stop`.
+    #[inline]
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// The messages the shovel has handled so far.
+    pub fn stats(&self) -> ShovelStats {
+        self.counters.snapshot()
+    }
+
+    /// Stop consuming from the source, keep relaying messages it had already delivered, and wait
+    /// until every message in flight has been confirmed by the destination (and acked on the
+    /// source), or until `deadline` passes. Then close both connections.
+    ///
+    /// Returns the final counts; [`pending`](struct.ShovelStats.html#structfield.pending) is the
+    /// number of messages still unconfirmed at the deadline. Returns an error if the shovel had
+    /// already stopped because of a failure it could not recover from.
+    pub fn stop(mut self, deadline: Instant) -> Result<ShovelStats> {
+        self.stop_at(deadline)
+    }
+
+    fn stop_at(&mut self, deadline: Instant) -> Result<ShovelStats> {
+        if let Some(stop_tx) = self.stop_tx.take() {
+            // Fails if the shovel thread has already exited.
+            let _ = stop_tx.send(deadline);
+        }
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .unwrap_or_else(|_| ShovelStoppedSnafu.fail())?;
+        }
+        Ok(self.stats())
+    }
+}
+
+// One side of the shovel: a connection, and the means to replace it if it dies.
+trait Side {
+    fn connection(&mut self) -> &mut Option<Connection>;
+    fn reconnect(&mut self) -> &mut Option<Opener>;
+
+    // Open a channel, first replacing the connection if it has died and we can.
+    fn open_channel(&mut self) -> Result<Channel> {
+        if let Some(connection) = self.connection().as_mut() {
+            match connection.open_channel(None) {
+                Ok(channel) => return Ok(channel),
+                Err(err) if connection.is_running() && !err.is_recoverable() => return Err(err),
+                Err(err) if self.reconnect().is_none() => return Err(err),
+                Err(err) => debug!("replacing failed shovel connection: {}", err),
+            }
+            *self.connection() = None;
+        }
+        // unwrap is safe: we only give up a connection if we have an opener to replace it.
+        let mut connection = (self.reconnect().as_mut().unwrap())()?;
+        let channel = connection.open_channel(None)?;
+        *self.connection() = Some(connection);
+        Ok(channel)
+    }
+
+    // True if our connection has died and we cannot replace it.
+    fn is_lost(&mut self) -> bool {
+        let running = self
+            .connection()
+            .as_ref()
+            .is_some_and(Connection::is_running);
+        !running && self.reconnect().is_none()
+    }
+
+    fn close(&mut self) {
+        if let Some(connection) = self.connection().take() {
+            let _ = connection.close();
+        }
+    }
+}
+
+impl Side for ShovelSource {
+    fn connection(&mut self) -> &mut Option<Connection> {
+        &mut self.connection
+    }
+
+    fn reconnect(&mut self) -> &mut Option<Opener> {
+        &mut self.reconnect
+    }
+}
+
+impl Side for ShovelDestination {
+    fn connection(&mut self) -> &mut Option<Connection> {
+        &mut self.connection
+    }
+
+    fn reconnect(&mut self) -> &mut Option<Opener> {
+        &mut self.reconnect
+    }
+}
+
+// Why a session ended without an error.
+enum Stopped {
+    // Every message in flight was settled.
+    Drained,
+    // The stop deadline passed with messages in flight.
+    DeadlinePassed,
+}
+
+struct ShovelState {
+    source: ShovelSource,
+    destination: ShovelDestination,
+    options: ShovelOptions,
+    counters: Arc<Counters>,
+    running: Arc<AtomicBool>,
+    stop: Receiver<Instant>,
+}
+
+// Runs when the shovel thread exits, even if it panics.
+impl Drop for ShovelState {
+    fn drop(&mut self) {
+        self.source.close();
+        self.destination.close();
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl ShovelState {
+    fn run(&mut self, started: Sender<Result<()>>) -> Result<()> {
+        let mut started = Some(started);
+        let mut stop_deadline = None;
+        loop {
+            let mut in_flight = BTreeMap::new();
+            let result = self.run_session(&mut started, &mut stop_deadline, &mut in_flight);
+            let err = match result {
+                Ok(Stopped::Drained) => return Ok(()),
+                // Leave the unconfirmed messages counted as pending; the source will redeliver
+                // them once its channel closes.
+                Ok(Stopped::DeadlinePassed) => return Ok(()),
+                Err(err) => err,
+            };
+            if let Some(started) = started.take() {
+                let _ = started.send(Err(err));
+                return Ok(());
+            }
+            let lost = in_flight.len() as u64;
+            self.counters.pending.fetch_sub(lost, Ordering::SeqCst);
+            self.counters.failed.fetch_add(lost, Ordering::SeqCst);
+            if stop_deadline.is_some() {
+                return Err(err);
+            }
+            if self.source.is_lost() || self.destination.is_lost() {
+                return Err(err);
+            }
+            warn!(
+                "shovel failed ({}); retrying in {:?}",
+                err, self.options.retry_delay
+            );
+            select! {
+                recv(self.stop) -> _ => return Ok(()),
+                default(self.options.retry_delay) => (),
+            }
+        }
+    }
+
+    // Open channels on both sides and relay messages until stopped or something fails. Messages
+    // still in flight when this returns are left in `in_flight`.
+    fn run_session(
+        &mut self,
+        started: &mut Option<Sender<Result<()>>>,
+        stop_deadline: &mut Option<Instant>,
+        in_flight: &mut BTreeMap<u64, Delivery>,
+    ) -> Result<Stopped> {
+        let source_channel = self.source.open_channel()?;
+        let destination_channel = self.destination.open_channel()?;
+        let confirms = destination_channel.listen_for_publisher_confirms()?;
+        destination_channel.enable_publisher_confirms()?;
+        source_channel.qos(0, self.options.max_in_flight, false)?;
+        let consumer =
+            source_channel.basic_consume(self.source.queue.clone(), ConsumerOptions::default())?;
+        if let Some(started) = started.take() {
+            let _ = started.send(Ok(()));
+        }
+        debug!(
+            "shovel relaying from {:?} to {:?}",
+            self.source.queue, self.destination.exchange
+        );
+
+        let max_in_flight = usize::from(self.options.max_in_flight);
+        let stopped_messages = never();
+        let no_stop = never();
+        let no_deadline = never();
+        let mut messages = consumer.receiver();
+        let mut stop = &self.stop;
+        let mut deadline = no_deadline.clone();
+        let mut consumer_ended = false;
+
+        loop {
+            // The consumer only ends without an error after we cancel it, i.e., once stopped.
+            if consumer_ended && in_flight.is_empty() {
+                drop(consumer);
+                source_channel.close()?;
+                destination_channel.close()?;
+                return Ok(Stopped::Drained);
+            }
+            let next_message = match in_flight.len() < max_in_flight {
+                true => messages,
+                false => &stopped_messages,
+            };
+            select! {
+                recv(next_message) -> message => match message {
+                    Ok(ConsumerMessage::Delivery(mut delivery)) => {
+                        let routing_key = self.destination.routing_key_for(&delivery);
+                        let seq_no = destination_channel.next_publish_seq_no();
+                        destination_channel.basic_publish(
+                            self.destination.exchange.as_str(),
+                            delivery.to_publish(routing_key),
+                        )?;
+                        // Only the delivery tag is needed from here on.
+                        drop(mem::take(&mut delivery.body));
+                        in_flight.insert(seq_no, delivery);
+                        self.counters.pending.fetch_add(1, Ordering::SeqCst);
+                    }
+                    Ok(ConsumerMessage::ClientCancelled) if stop_deadline.is_some() => {
+                        consumer_ended = true;
+                        messages = &stopped_messages;
+                    }
+                    Ok(ConsumerMessage::ServerClosedChannel(err))
+                    | Ok(ConsumerMessage::ServerClosedConnection(err)) => return Err(err),
+                    Ok(_) | Err(_) => {
+                        return ConsumerEndedSnafu {
+                            consumer_tag: consumer.consumer_tag(),
+                        }
+                        .fail()
+                    }
+                },
+                recv(confirms) -> confirm => match confirm {
+                    Ok(confirm) => self.settle(&consumer, in_flight, confirm)?,
+                    // The destination channel has closed.
+                    Err(_) => {
+                        return UnconfirmedPublishesLostSnafu {
+                            seq_nos: in_flight.keys().copied().collect::<Vec<_>>(),
+                        }
+                        .fail()
+                    }
+                },
+                recv(stop) -> when => {
+                    // Receiving fails if the shovel handle was dropped; stop right away then.
+                    let when = when.unwrap_or_else(|_| Instant::now());
+                    *stop_deadline = Some(when);
+                    stop = &no_stop;
+                    deadline = crossbeam_channel::at(when);
+                    // Deliveries that arrive before the cancel-ok are still relayed.
+                    consumer.cancel()?;
+                },
+                recv(deadline) -> _ => {
+                    debug!(
+                        "shovel stop deadline passed with {} messages unconfirmed",
+                        in_flight.len()
+                    );
+                    return Ok(Stopped::DeadlinePassed);
+                },
+            }
+        }
+    }
+
+    // Ack or nack on the source the messages `confirm` settles.
+    fn settle(
+        &self,
+        consumer: &crate::Consumer,
+        in_flight: &mut BTreeMap<u64, Delivery>,
+        confirm: Confirm,
+    ) -> Result<()> {
+        let (payload, acked) = match confirm {
+            Confirm::Ack(payload) => (payload, true),
+            Confirm::Nack(payload) => (payload, false),
+        };
+        let settled = if payload.multiple {
+            let rest = in_flight.split_off(&(payload.delivery_tag + 1));
+            mem::replace(in_flight, rest)
+        } else {
+            in_flight
+                .remove(&payload.delivery_tag)
+                .map(|delivery| (payload.delivery_tag, delivery))
+                .into_iter()
+                .collect()
+        };
+        for (_, delivery) in settled {
+            self.counters.pending.fetch_sub(1, Ordering::SeqCst);
+            if acked {
+                consumer.ack(delivery)?;
+                self.counters.relayed.fetch_add(1, Ordering::SeqCst);
+            } else {
+                warn!(
+                    "shovel destination nacked delivery {}; requeueing it",
+                    delivery.delivery_tag()
+                );
+                consumer.nack(delivery, true)?;
+                self.counters.failed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use super::IoStream;
+use crate::StreamWaker;
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError};
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Size of each read from the transport.
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+
+// Number of chunks that may be queued in each direction before the transport catches up.
+const QUEUED_CHUNKS: usize = 16;
+
+/// Adapts a blocking transport (anything `Read`/`Write`, e.g., a socket handed to you by a tunnel
+/// or proxy library) into an [`IoStream`](trait.IoStream.html) the I/O thread can poll, for use
+/// with [`Connection::insecure_open_stream`](struct.Connection.html#method.insecure_open_stream)
+/// or [`Connection::open_tls_stream`](struct.Connection.html#method.open_tls_stream).
+///
+/// The reading and writing halves of the transport are each driven by their own thread, which
+/// hand data to and from the I/O thread through small queues and wake it when there is something
+/// to do; the connection's handshake, heartbeats and backpressure work as they do on a socket.
+/// Transports that cannot be split in two can often be cloned instead (e.g., with
+/// `std::net::TcpStream::try_clone`).
+///
+/// The writing thread exits (dropping `writer`) when the `BlockingStream` is dropped. The reading
+/// thread exits when the transport reports end of stream or an error, which normally follows the
+/// server closing its end after the connection is closed. Transports that have to be shut down
+/// explicitly can do so when the writer is dropped.
+///
+/// An already connected `std::net::TcpStream` does not need this adapter: convert it with
+/// `mio::net::TcpStream::from_std` instead.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use amiquip::{Auth, BlockingStream, Connection, ConnectionOptions, ConnectionTuning, Result};
+/// use std::net::TcpStream;
+///
+/// # fn open() -> Result<()> {
+/// // Stand-in for a transport set up by some other library.
+/// let socket = TcpStream::connect("localhost:5672").unwrap();
+/// let stream = BlockingStream::new(socket.try_clone().unwrap(), socket);
+/// let connection = Connection::insecure_open_stream(
+///     stream,
+///     ConnectionOptions::<Auth>::default(),
+///     ConnectionTuning::default(),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlockingStream {
+    waker: SharedWaker,
+    incoming: Receiver<io::Result<Vec<u8>>>,
+    outgoing: Option<Sender<Vec<u8>>>,
+    write_error: Arc<Mutex<Option<io::Error>>>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl BlockingStream {
+    /// Creates a stream that reads from `reader` and writes to `writer`, starting a thread for
+    /// each.
+    pub fn new<R, W>(reader: R, writer: W) -> BlockingStream
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let waker = SharedWaker::default();
+        let (incoming_tx, incoming) = crossbeam_channel::bounded(QUEUED_CHUNKS);
+        let (outgoing, outgoing_rx) = crossbeam_channel::bounded(QUEUED_CHUNKS);
+        let write_error = Arc::new(Mutex::new(None));
+
+        let wake_reader = waker.clone();
+        thread::spawn(move || run_reader(reader, incoming_tx, wake_reader));
+        let wake_writer = waker.clone();
+        let writer_error = Arc::clone(&write_error);
+        thread::spawn(move || run_writer(writer, outgoing_rx, writer_error, wake_writer));
+
+        BlockingStream {
+            waker,
+            incoming,
+            outgoing: Some(outgoing),
+            write_error,
+            pending: Vec::new(),
+            pending_pos: 0,
+        }
+    }
+}
+
+// The I/O thread's waker, once it has handed us one. Wakeups before then are not lost: the I/O
+// thread tries reading and writing as soon as it sets the waker.
+#[derive(Clone, Default)]
+struct SharedWaker(Arc<Mutex<Option<StreamWaker>>>);
+
+impl SharedWaker {
+    fn set(&self, waker: StreamWaker) {
+        // We can always try to read and write; reads and writes that cannot make progress return
+        // WouldBlock, and the threads below wake the I/O thread again when that changes.
+        let mut slot = self.0.lock().unwrap();
+        waker.wake();
+        *slot = Some(waker);
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = &*self.0.lock().unwrap() {
+            waker.wake();
+        }
+    }
+}
+
+fn run_reader<R: Read>(mut reader: R, incoming: Sender<io::Result<Vec<u8>>>, waker: SharedWaker) {
+    let mut buf = vec![0; READ_CHUNK_SIZE];
+    loop {
+        let chunk = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => Ok(buf[..n].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+        let failed = chunk.is_err();
+        if incoming.send(chunk).is_err() {
+            return;
+        }
+        waker.wake();
+        if failed {
+            return;
+        }
+    }
+    // Disconnecting the channel reports end of stream.
+    drop(incoming);
+    waker.wake();
+}
+
+fn run_writer<W: Write>(
+    mut writer: W,
+    outgoing: Receiver<Vec<u8>>,
+    write_error: Arc<Mutex<Option<io::Error>>>,
+    waker: SharedWaker,
+) {
+    for chunk in &outgoing {
+        if let Err(err) = writer.write_all(&chunk).and_then(|()| writer.flush()) {
+            *write_error.lock().unwrap() = Some(err);
+            // Dropping the receiver makes further writes fail.
+            drop(outgoing);
+            waker.wake();
+            return;
+        }
+        waker.wake();
+    }
+}
+
+impl Read for BlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_pos == self.pending.len() {
+            match self.incoming.try_recv() {
+                Ok(Ok(chunk)) => {
+                    self.pending = chunk;
+                    self.pending_pos = 0;
+                }
+                Ok(Err(err)) => return Err(err),
+                Err(TryRecvError::Empty) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = (&self.pending[self.pending_pos..]).read(buf)?;
+        self.pending_pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for BlockingStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let outgoing = match &self.outgoing {
+            Some(outgoing) => outgoing,
+            None => return Err(io::ErrorKind::BrokenPipe.into()),
+        };
+        match outgoing.try_send(buf.to_vec()) {
+            Ok(()) => Ok(buf.len()),
+            Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(TrySendError::Disconnected(_)) => {
+                self.outgoing = None;
+                Err(self
+                    .write_error
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .unwrap_or_else(|| io::ErrorKind::BrokenPipe.into()))
+            }
+        }
+    }
+
+    // Data is handed off to the writing thread as soon as it is written; there is no way to wait
+    // for it to reach the transport without blocking the I/O thread.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// There is nothing for mio to poll; the reading and writing threads wake the I/O thread through
+// the waker it sets instead. Like a socket's, our readiness is reported again when the I/O thread
+// reregisters (which it does when it has more to write).
+impl Source for BlockingStream {
+    fn register(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&mut self, _: &Registry, _: Token, _: Interest) -> io::Result<()> {
+        self.waker.wake();
+        Ok(())
+    }
+
+    fn deregister(&mut self, _: &Registry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl IoStream for BlockingStream {
+    fn set_waker(&mut self, waker: StreamWaker) {
+        self.waker.set(waker);
+    }
+}
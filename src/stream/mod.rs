@@ -1,21 +1,169 @@
-use crate::Result;
+use crate::errors::*;
+use crate::StreamWaker;
+use mio::event::Source;
 use mio::net::TcpStream;
-use mio::Evented;
-use std::io::{Read, Write};
+use snafu::ResultExt;
+use socket2::{SockRef, TcpKeepalive};
+use std::io::{self, IoSlice, Read, Write};
+use std::time::Duration;
 
-pub(crate) trait HandshakeStream: Evented + Send + 'static {
+// Only used by TLS backends.
+#[cfg_attr(not(any(feature = "native-tls", feature = "rustls")), allow(dead_code))]
+pub(crate) trait HandshakeStream: Source + Send + 'static {
     type Stream: IoStream;
 
     fn progress_handshake(&mut self) -> Result<Option<Self::Stream>>;
+
+    // The DER-encoded certificate the server presented, given the stream `progress_handshake`
+    // returned.
+    fn peer_certificate(stream: &Self::Stream) -> Option<Vec<u8>>;
 }
 
 /// Combination trait for readable, writable streams that can be polled by mio.
-pub trait IoStream: Read + Write + Evented + Send + 'static {}
+///
+/// Streams that are not backed by something mio can poll (e.g., data moved by other threads) may
+/// register nothing with mio, and instead wake the I/O thread with the
+/// [`StreamWaker`](struct.StreamWaker.html) passed to [`set_waker`](#method.set_waker).
+pub trait IoStream: Read + Write + Source + Send + 'static {
+    /// Write data from several buffers at once (e.g., with a single `writev` call), returning the
+    /// number of bytes written. The I/O thread uses this to send many queued frames without first
+    /// copying them into one buffer.
+    ///
+    /// The default implementation calls `Write::write_vectored`, which for many streams only
+    /// writes the first non-empty buffer.
+    fn write_bufs(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.write_vectored(bufs)
+    }
+
+    /// Apply [`TcpOptions`](struct.TcpOptions.html) to the underlying socket. This is called once,
+    /// before the stream is handed to the I/O thread.
+    ///
+    /// The default implementation does nothing, which is appropriate for streams that are not TCP
+    /// sockets.
+    fn apply_tcp_options(&self, options: &TcpOptions) -> Result<()> {
+        let _ = options;
+        Ok(())
+    }
 
-impl IoStream for TcpStream {}
+    /// Receive a handle that wakes the I/O thread to try reading from and writing to this stream
+    /// again. This is called once, before the stream is registered with mio.
+    ///
+    /// The default implementation drops the waker, which is appropriate for streams whose
+    /// readiness mio reports.
+    fn set_waker(&mut self, waker: StreamWaker) {
+        let _ = waker;
+    }
+}
+
+impl IoStream for TcpStream {
+    // mio only exposes TCP_NODELAY; the rest are set through socket2.
+    fn apply_tcp_options(&self, options: &TcpOptions) -> Result<()> {
+        if options.nodelay {
+            self.set_nodelay(true).context(SetTcpOptionSnafu {
+                option: "TCP_NODELAY",
+            })?;
+        }
+        let socket = SockRef::from(self);
+        if let Some(keepalive) = options.keepalive {
+            socket
+                .set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))
+                .context(SetTcpOptionSnafu {
+                    option: "SO_KEEPALIVE",
+                })?;
+        }
+        if let Some(size) = options.recv_buffer_size {
+            socket
+                .set_recv_buffer_size(size)
+                .context(SetTcpOptionSnafu {
+                    option: "SO_RCVBUF",
+                })?;
+        }
+        if let Some(size) = options.send_buffer_size {
+            socket
+                .set_send_buffer_size(size)
+                .context(SetTcpOptionSnafu {
+                    option: "SO_SNDBUF",
+                })?;
+        }
+        Ok(())
+    }
+}
 
+/// Socket options applied to a connection's TCP stream before the I/O thread takes ownership of
+/// it; see [`ConnectionTuning::tcp_options`](struct.ConnectionTuning.html#structfield.tcp_options).
+///
+/// Options left at their default values are not applied, so the operating system's defaults (or
+/// anything already configured on a stream passed to
+/// [`Connection::insecure_open_stream`](struct.Connection.html#method.insecure_open_stream)) are
+/// left alone. Streams other than `mio::net::TcpStream` ignore these options unless their
+/// [`IoStream`](trait.IoStream.html) implementation says otherwise.
+///
+/// `TcpOptions` uses the builder pattern:
+///
+/// ```rust
+/// use amiquip::TcpOptions;
+/// use std::time::Duration;
+///
+/// let options = TcpOptions::default()
+///     .nodelay(true)
+///     .keepalive(Some(Duration::from_secs(60)));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpOptions {
+    /// If true, set `TCP_NODELAY`, disabling Nagle's algorithm. This reduces latency for small
+    /// request/response exchanges at the cost of sending more packets. The default is false.
+    pub nodelay: bool,
+
+    /// If set, enable `SO_KEEPALIVE` with the given idle time before keepalive probes are sent.
+    /// Useful for long-lived, mostly idle connections through NATs or firewalls that drop quiet
+    /// flows. The default is `None`.
+    pub keepalive: Option<Duration>,
+
+    /// If set, the size in bytes of the socket's receive buffer (`SO_RCVBUF`). The default is
+    /// `None`.
+    pub recv_buffer_size: Option<usize>,
+
+    /// If set, the size in bytes of the socket's send buffer (`SO_SNDBUF`). The default is `None`.
+    pub send_buffer_size: Option<usize>,
+}
+
+impl TcpOptions {
+    /// Set [`nodelay`](#structfield.nodelay).
+    pub fn nodelay(self, nodelay: bool) -> Self {
+        TcpOptions { nodelay, ..self }
+    }
+
+    /// Set [`keepalive`](#structfield.keepalive).
+    pub fn keepalive(self, keepalive: Option<Duration>) -> Self {
+        TcpOptions { keepalive, ..self }
+    }
+
+    /// Set [`recv_buffer_size`](#structfield.recv_buffer_size).
+    pub fn recv_buffer_size(self, recv_buffer_size: Option<usize>) -> Self {
+        TcpOptions {
+            recv_buffer_size,
+            ..self
+        }
+    }
+
+    /// Set [`send_buffer_size`](#structfield.send_buffer_size).
+    pub fn send_buffer_size(self, send_buffer_size: Option<usize>) -> Self {
+        TcpOptions {
+            send_buffer_size,
+            ..self
+        }
+    }
+}
+
+mod blocking;
 #[cfg(feature = "native-tls")]
 mod native_tls;
+#[cfg(feature = "rustls")]
+mod rustls;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+mod tls;
 
-#[cfg(feature = "native-tls")]
-pub use self::native_tls::TlsConnector;
+pub use self::blocking::BlockingStream;
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub use self::tls::{ClientIdentity, TlsConnector};
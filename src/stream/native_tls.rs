@@ -1,34 +1,52 @@
 use super::{HandshakeStream, IoStream};
 use crate::errors::*;
-use mio::{Evented, Poll, PollOpt, Ready, Token};
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
 use native_tls::{HandshakeError, MidHandshakeTlsStream};
 use snafu::ResultExt;
 use std::io::{self, Read, Write};
 
-/// Newtype wrapper around a `native_tls::TlsConnector` to make it usable by amiquip's I/O loop.
-pub struct TlsConnector(native_tls::TlsConnector);
-
-impl TlsConnector {
-    pub(crate) fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsHandshakeStream<S>>
-    where
-        S: Read + Write,
-    {
-        let inner = Some(match self.0.connect(domain, stream) {
-            Ok(s) => InnerHandshake::Done(s),
-            Err(HandshakeError::WouldBlock(s)) => InnerHandshake::MidHandshake(s),
-            Err(HandshakeError::Failure(err)) => Err(err).context(TlsHandshakeSnafu)?,
-        });
-        Ok(TlsHandshakeStream { inner })
-    }
+pub(crate) fn connect<S>(
+    connector: &native_tls::TlsConnector,
+    domain: &str,
+    stream: S,
+) -> Result<NativeHandshakeStream<S>>
+where
+    S: Read + Write,
+{
+    let inner = Some(match connector.connect(domain, stream) {
+        Ok(s) => InnerHandshake::Done(s),
+        Err(HandshakeError::WouldBlock(s)) => InnerHandshake::MidHandshake(s),
+        Err(HandshakeError::Failure(err)) => return handshake_failed(err),
+    });
+    Ok(NativeHandshakeStream { inner })
 }
 
-impl From<native_tls::TlsConnector> for TlsConnector {
-    fn from(inner: native_tls::TlsConnector) -> TlsConnector {
-        TlsConnector(inner)
+fn handshake_failed<T>(err: native_tls::Error) -> Result<T> {
+    if is_certificate_rejection(&err.to_string()) {
+        return InvalidCredentialsSnafu.fail();
     }
+    Err(err).context(TlsHandshakeSnafu)
+}
+
+// Whether an error message describes the server refusing our client certificate (or the lack of
+// one). native-tls does not expose TLS alerts, so this recognizes OpenSSL's descriptions of them;
+// other platforms' failures are reported as plain TLS errors.
+fn is_certificate_rejection(message: &str) -> bool {
+    const ALERTS: &[&str] = &[
+        "alert bad certificate",
+        "alert unsupported certificate",
+        "alert certificate revoked",
+        "alert certificate expired",
+        "alert certificate unknown",
+        "alert unknown ca",
+        "alert access denied",
+        "alert certificate required",
+    ];
+    ALERTS.iter().any(|alert| message.contains(alert))
 }
 
-pub(crate) struct TlsHandshakeStream<S> {
+pub(crate) struct NativeHandshakeStream<S> {
     inner: Option<InnerHandshake<S>>,
 }
 
@@ -38,83 +56,97 @@ enum InnerHandshake<S> {
 }
 
 impl<S: Read + Write> InnerHandshake<S> {
-    fn get_ref(&self) -> &S {
+    fn get_mut(&mut self) -> &mut S {
         match self {
-            InnerHandshake::MidHandshake(s) => s.get_ref(),
-            InnerHandshake::Done(s) => s.get_ref(),
+            InnerHandshake::MidHandshake(s) => s.get_mut(),
+            InnerHandshake::Done(s) => s.get_mut(),
         }
     }
 }
 
-impl<S: Evented + Read + Write + Send + 'static> HandshakeStream for TlsHandshakeStream<S> {
-    type Stream = TlsStream<S>;
+impl<S: Source + Read + Write + Send + 'static> HandshakeStream for NativeHandshakeStream<S> {
+    type Stream = NativeTlsStream<S>;
+
+    fn peer_certificate(stream: &Self::Stream) -> Option<Vec<u8>> {
+        match stream.0.peer_certificate() {
+            Ok(Some(cert)) => cert.to_der().ok(),
+            Ok(None) | Err(_) => None,
+        }
+    }
 
     fn progress_handshake(&mut self) -> Result<Option<Self::Stream>> {
         let mid_hs = match self.inner.take().unwrap() {
             InnerHandshake::MidHandshake(mid_hs) => mid_hs,
-            InnerHandshake::Done(s) => return Ok(Some(TlsStream(s))),
+            InnerHandshake::Done(s) => return Ok(Some(NativeTlsStream(s))),
         };
 
         match mid_hs.handshake() {
-            Ok(s) => Ok(Some(TlsStream(s))),
+            Ok(s) => Ok(Some(NativeTlsStream(s))),
             Err(HandshakeError::WouldBlock(s)) => {
                 self.inner = Some(InnerHandshake::MidHandshake(s));
                 Ok(None)
             }
-            Err(HandshakeError::Failure(err)) => Err(err).context(TlsHandshakeSnafu)?,
+            Err(HandshakeError::Failure(err)) => handshake_failed(err),
         }
     }
 }
 
-impl<S: Evented + Read + Write> Evented for TlsHandshakeStream<S> {
+impl<S: Source + Read + Write> Source for NativeHandshakeStream<S> {
     #[inline]
     fn register(
-        &self,
-        poll: &Poll,
+        &mut self,
+        registry: &Registry,
         token: Token,
-        interest: Ready,
-        opts: PollOpt,
+        interests: Interest,
     ) -> io::Result<()> {
         self.inner
-            .as_ref()
+            .as_mut()
             .unwrap()
-            .get_ref()
-            .register(poll, token, interest, opts)
+            .get_mut()
+            .register(registry, token, interests)
     }
 
     #[inline]
     fn reregister(
-        &self,
-        poll: &Poll,
+        &mut self,
+        registry: &Registry,
         token: Token,
-        interest: Ready,
-        opts: PollOpt,
+        interests: Interest,
     ) -> io::Result<()> {
         self.inner
-            .as_ref()
+            .as_mut()
             .unwrap()
-            .get_ref()
-            .reregister(poll, token, interest, opts)
+            .get_mut()
+            .reregister(registry, token, interests)
     }
 
     #[inline]
-    fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        self.inner.as_ref().unwrap().get_ref().deregister(poll)
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner.as_mut().unwrap().get_mut().deregister(registry)
     }
 }
 
-pub(crate) struct TlsStream<S>(native_tls::TlsStream<S>);
+pub(crate) struct NativeTlsStream<S>(native_tls::TlsStream<S>);
 
-impl<S: Evented + Read + Write + Send + 'static> IoStream for TlsStream<S> {}
+impl<S: Source + Read + Write + Send + 'static> IoStream for NativeTlsStream<S> {}
 
-impl<S: Read + Write> Read for TlsStream<S> {
+impl<S: Read + Write> Read for NativeTlsStream<S> {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.0.read(buf)
+        self.0.read(buf).map_err(|err| {
+            // With TLS 1.3, the server rejects our client certificate after the handshake; the
+            // I/O loop reports PermissionDenied during the AMQP handshake as bad credentials.
+            if err.kind() != io::ErrorKind::WouldBlock && is_certificate_rejection(&err.to_string())
+            {
+                io::Error::new(io::ErrorKind::PermissionDenied, err)
+            } else {
+                err
+            }
+        })
     }
 }
 
-impl<S: Read + Write> Write for TlsStream<S> {
+impl<S: Read + Write> Write for NativeTlsStream<S> {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.0.write(buf)
@@ -126,31 +158,29 @@ impl<S: Read + Write> Write for TlsStream<S> {
     }
 }
 
-impl<S: Evented + Read + Write> Evented for TlsStream<S> {
+impl<S: Source + Read + Write> Source for NativeTlsStream<S> {
     #[inline]
     fn register(
-        &self,
-        poll: &Poll,
+        &mut self,
+        registry: &Registry,
         token: Token,
-        interest: Ready,
-        opts: PollOpt,
+        interests: Interest,
     ) -> io::Result<()> {
-        self.0.get_ref().register(poll, token, interest, opts)
+        self.0.get_mut().register(registry, token, interests)
     }
 
     #[inline]
     fn reregister(
-        &self,
-        poll: &Poll,
+        &mut self,
+        registry: &Registry,
         token: Token,
-        interest: Ready,
-        opts: PollOpt,
+        interests: Interest,
     ) -> io::Result<()> {
-        self.0.get_ref().reregister(poll, token, interest, opts)
+        self.0.get_mut().reregister(registry, token, interests)
     }
 
     #[inline]
-    fn deregister(&self, poll: &Poll) -> io::Result<()> {
-        self.0.get_ref().deregister(poll)
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.0.get_mut().deregister(registry)
     }
 }
@@ -0,0 +1,405 @@
+use super::{HandshakeStream, IoStream};
+use crate::errors::*;
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+use std::fmt;
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "native-tls")]
+use super::native_tls::{NativeHandshakeStream, NativeTlsStream};
+#[cfg(feature = "rustls")]
+use super::rustls::{RustlsHandshakeStream, RustlsStream};
+#[cfg(feature = "rustls")]
+use rustls_crate::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore};
+#[cfg(feature = "native-tls")]
+use snafu::ResultExt;
+use std::sync::Arc;
+
+/// TLS configuration for secure connections, backed by either
+/// [native-tls](https://crates.io/crates/native-tls) (with the `native-tls` feature) or
+/// [rustls](https://crates.io/crates/rustls) (with the `rustls` feature).
+///
+/// Create one by converting a `native_tls::TlsConnector` or a `rustls::ClientConfig` with
+/// `into()`, or with [`rustls_with_webpki_roots`](#method.rustls_with_webpki_roots). A rustls
+/// configuration with custom root certificates or a client certificate (e.g., for the `EXTERNAL`
+/// auth mechanism) can start from [`webpki_roots`](#method.webpki_roots):
+///
+/// ```rust,ignore
+/// use amiquip::TlsConnector;
+///
+/// let mut roots = TlsConnector::webpki_roots();
+/// roots.add(&rustls::Certificate(my_ca_der))?;
+/// let config = rustls::ClientConfig::builder()
+///     .with_safe_defaults()
+///     .with_root_certificates(roots)
+///     .with_single_cert(my_cert_chain, rustls::PrivateKey(my_key_der))?;
+/// let connector = TlsConnector::from(config);
+/// ```
+///
+/// The domain passed to
+/// [`Connection::open_tls_stream`](struct.Connection.html#method.open_tls_stream) is sent as the
+/// server name (SNI) and checked against the server's certificate by either backend, unless
+/// overridden with
+/// [`ConnectionOptions::tls_server_name`](struct.ConnectionOptions.html#method.tls_server_name).
+#[derive(Clone)]
+pub struct TlsConnector(Backend);
+
+#[derive(Clone)]
+enum Backend {
+    #[cfg(feature = "native-tls")]
+    Native(native_tls::TlsConnector),
+    // `roots` is what `config` trusts, if we know it.
+    #[cfg(feature = "rustls")]
+    Rustls {
+        config: Arc<ClientConfig>,
+        roots: Option<Arc<RootCertStore>>,
+    },
+}
+
+impl TlsConnector {
+    /// The root certificates of the [webpki-roots](https://crates.io/crates/webpki-roots) crate
+    /// (Mozilla's trusted roots), for building a `rustls::ClientConfig`.
+    #[cfg(feature = "rustls")]
+    pub fn webpki_roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+        roots
+    }
+
+    /// A rustls connector that trusts [`webpki_roots`](#method.webpki_roots) and does not
+    /// present a client certificate.
+    #[cfg(feature = "rustls")]
+    pub fn rustls_with_webpki_roots() -> TlsConnector {
+        Self::rustls_with_roots(Self::webpki_roots())
+    }
+
+    /// A rustls connector that trusts `roots` and does not present a client certificate.
+    ///
+    /// Unlike a connector converted from a `rustls::ClientConfig`, this one remembers its roots,
+    /// so it can be used with
+    /// [`ConnectionOptions::danger_accept_invalid_hostnames`](struct.ConnectionOptions.html#method.danger_accept_invalid_hostnames).
+    #[cfg(feature = "rustls")]
+    pub fn rustls_with_roots(roots: RootCertStore) -> TlsConnector {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots.clone())
+            .with_no_client_auth();
+        TlsConnector(Backend::Rustls {
+            config: Arc::new(config),
+            roots: Some(Arc::new(roots)),
+        })
+    }
+
+    // Returns a connector that presents `identity` (if given) to the server and, if
+    // `accept_invalid_hostnames` is set, does not check the server's certificate against the
+    // domain. rustls configurations are copied with these changes; native-tls connectors cannot be
+    // changed once built, so we build a new one with native-tls's default settings plus ours.
+    pub(crate) fn configure(
+        &self,
+        identity: Option<&ClientIdentity>,
+        accept_invalid_hostnames: bool,
+    ) -> Result<TlsConnector> {
+        if identity.is_none() && !accept_invalid_hostnames {
+            return Ok(self.clone());
+        }
+        match &self.0 {
+            #[cfg(feature = "native-tls")]
+            Backend::Native(_) => {
+                let mut builder = native_tls::TlsConnector::builder();
+                builder.danger_accept_invalid_hostnames(accept_invalid_hostnames);
+                match identity.map(|identity| &identity.0) {
+                    Some(IdentityInner::Native(identity)) => {
+                        builder.identity((**identity).clone());
+                    }
+                    #[cfg(feature = "rustls")]
+                    Some(IdentityInner::Rustls { .. }) => return backend_mismatch(),
+                    None => (),
+                }
+                Ok(builder.build().context(CreateTlsConnectorSnafu)?.into())
+            }
+            #[cfg(feature = "rustls")]
+            Backend::Rustls { config, roots } => {
+                let mut config = Arc::clone(config);
+                match identity.map(|identity| &identity.0) {
+                    Some(IdentityInner::Rustls { cert_chain, key }) => {
+                        config = Arc::new(super::rustls::with_client_identity(
+                            &config,
+                            cert_chain.clone(),
+                            key,
+                        )?);
+                    }
+                    #[cfg(feature = "native-tls")]
+                    Some(IdentityInner::Native(_)) => return backend_mismatch(),
+                    None => (),
+                }
+                if accept_invalid_hostnames {
+                    let roots = match roots {
+                        Some(roots) => roots,
+                        None => return CannotSkipHostnameVerificationSnafu.fail(),
+                    };
+                    config = Arc::new(super::rustls::accepting_invalid_hostnames(&config, roots));
+                }
+                Ok(TlsConnector(Backend::Rustls {
+                    config,
+                    roots: roots.clone(),
+                }))
+            }
+        }
+    }
+
+    pub(crate) fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsHandshakeStream<S>>
+    where
+        S: Read + Write,
+    {
+        Ok(match &self.0 {
+            #[cfg(feature = "native-tls")]
+            Backend::Native(connector) => {
+                TlsHandshakeStream::Native(super::native_tls::connect(connector, domain, stream)?)
+            }
+            #[cfg(feature = "rustls")]
+            Backend::Rustls { config, .. } => {
+                TlsHandshakeStream::Rustls(super::rustls::connect(config, domain, stream)?)
+            }
+        })
+    }
+}
+
+#[cfg(all(feature = "native-tls", feature = "rustls"))]
+fn backend_mismatch() -> Result<TlsConnector> {
+    InvalidClientIdentitySnafu {
+        message: "client identity is for a different TLS backend than the connector",
+    }
+    .fail()
+}
+
+#[cfg(feature = "native-tls")]
+impl From<native_tls::TlsConnector> for TlsConnector {
+    fn from(inner: native_tls::TlsConnector) -> TlsConnector {
+        TlsConnector(Backend::Native(inner))
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<ClientConfig> for TlsConnector {
+    fn from(config: ClientConfig) -> TlsConnector {
+        TlsConnector::from(Arc::new(config))
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<Arc<ClientConfig>> for TlsConnector {
+    fn from(config: Arc<ClientConfig>) -> TlsConnector {
+        TlsConnector(Backend::Rustls {
+            config,
+            roots: None,
+        })
+    }
+}
+
+/// A client certificate (with its private key) to present during the TLS handshake, e.g., to
+/// authenticate with the `EXTERNAL` mechanism; see
+/// [`ConnectionOptions::external_with_client_cert`](struct.ConnectionOptions.html#method.external_with_client_cert).
+///
+/// Identities are specific to a TLS backend: one created from a `native_tls::Identity` can only be
+/// used with a native-tls [`TlsConnector`](struct.TlsConnector.html), and one created with
+/// [`from_der`](#method.from_der) only with a rustls connector.
+#[derive(Clone)]
+pub struct ClientIdentity(IdentityInner);
+
+#[derive(Clone)]
+enum IdentityInner {
+    #[cfg(feature = "native-tls")]
+    Native(Arc<native_tls::Identity>),
+    #[cfg(feature = "rustls")]
+    Rustls {
+        cert_chain: Vec<Certificate>,
+        key: PrivateKey,
+    },
+}
+
+impl ClientIdentity {
+    /// Creates a native-tls identity from a DER-encoded PKCS #12 archive holding the certificate
+    /// chain and private key. An invalid archive or password returns an error with [kind
+    /// `InvalidClientIdentity`](enum.Error.html#variant.InvalidClientIdentity).
+    #[cfg(feature = "native-tls")]
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<ClientIdentity> {
+        native_tls::Identity::from_pkcs12(der, password)
+            .map(ClientIdentity::from)
+            .map_err(|err| Error::InvalidClientIdentity {
+                message: err.to_string(),
+            })
+    }
+
+    /// Creates a rustls identity from a DER-encoded certificate chain (the client's certificate
+    /// first) and a DER-encoded PKCS #8, PKCS #1 or SEC1 private key. The key is checked when a
+    /// connection is opened.
+    #[cfg(feature = "rustls")]
+    pub fn from_der(cert_chain: Vec<Vec<u8>>, private_key: Vec<u8>) -> ClientIdentity {
+        ClientIdentity(IdentityInner::Rustls {
+            cert_chain: cert_chain.into_iter().map(Certificate).collect(),
+            key: PrivateKey(private_key),
+        })
+    }
+}
+
+#[cfg(feature = "native-tls")]
+impl From<native_tls::Identity> for ClientIdentity {
+    fn from(identity: native_tls::Identity) -> ClientIdentity {
+        ClientIdentity(IdentityInner::Native(Arc::new(identity)))
+    }
+}
+
+// Identities hold private keys, which we never want to end up in logs.
+impl fmt::Debug for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ClientIdentity { .. }")
+    }
+}
+
+impl PartialEq for ClientIdentity {
+    fn eq(&self, other: &ClientIdentity) -> bool {
+        match (&self.0, &other.0) {
+            #[cfg(feature = "native-tls")]
+            (IdentityInner::Native(a), IdentityInner::Native(b)) => Arc::ptr_eq(a, b),
+            #[cfg(feature = "rustls")]
+            (
+                IdentityInner::Rustls { cert_chain, key },
+                IdentityInner::Rustls {
+                    cert_chain: other_chain,
+                    key: other_key,
+                },
+            ) => cert_chain == other_chain && key == other_key,
+            #[cfg(all(feature = "native-tls", feature = "rustls"))]
+            _ => false,
+        }
+    }
+}
+
+// Forwards a method call to whichever backend's stream `$self` holds.
+macro_rules! dispatch {
+    ($self:expr, $s:ident => $e:expr) => {
+        match $self {
+            #[cfg(feature = "native-tls")]
+            Self::Native($s) => $e,
+            #[cfg(feature = "rustls")]
+            Self::Rustls($s) => $e,
+        }
+    };
+}
+
+pub(crate) enum TlsHandshakeStream<S> {
+    #[cfg(feature = "native-tls")]
+    Native(NativeHandshakeStream<S>),
+    #[cfg(feature = "rustls")]
+    Rustls(RustlsHandshakeStream<S>),
+}
+
+impl<S: Source + Read + Write + Send + 'static> HandshakeStream for TlsHandshakeStream<S> {
+    type Stream = TlsStream<S>;
+
+    fn peer_certificate(stream: &Self::Stream) -> Option<Vec<u8>> {
+        match stream {
+            #[cfg(feature = "native-tls")]
+            TlsStream::Native(s) => NativeHandshakeStream::peer_certificate(s),
+            #[cfg(feature = "rustls")]
+            TlsStream::Rustls(s) => RustlsHandshakeStream::peer_certificate(s),
+        }
+    }
+
+    fn progress_handshake(&mut self) -> Result<Option<Self::Stream>> {
+        Ok(match self {
+            #[cfg(feature = "native-tls")]
+            Self::Native(s) => s.progress_handshake()?.map(TlsStream::Native),
+            #[cfg(feature = "rustls")]
+            Self::Rustls(s) => s.progress_handshake()?.map(TlsStream::Rustls),
+        })
+    }
+}
+
+impl<S: Source + Read + Write> Source for TlsHandshakeStream<S> {
+    #[inline]
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        dispatch!(self, s => s.register(registry, token, interests))
+    }
+
+    #[inline]
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        dispatch!(self, s => s.reregister(registry, token, interests))
+    }
+
+    #[inline]
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        dispatch!(self, s => s.deregister(registry))
+    }
+}
+
+pub(crate) enum TlsStream<S> {
+    #[cfg(feature = "native-tls")]
+    Native(NativeTlsStream<S>),
+    #[cfg(feature = "rustls")]
+    Rustls(RustlsStream<S>),
+}
+
+impl<S: Source + Read + Write + Send + 'static> IoStream for TlsStream<S> {}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(self, s => s.read(buf))
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        dispatch!(self, s => s.write(buf))
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        dispatch!(self, s => s.flush())
+    }
+}
+
+impl<S: Source + Read + Write> Source for TlsStream<S> {
+    #[inline]
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        dispatch!(self, s => s.register(registry, token, interests))
+    }
+
+    #[inline]
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        dispatch!(self, s => s.reregister(registry, token, interests))
+    }
+
+    #[inline]
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        dispatch!(self, s => s.deregister(registry))
+    }
+}
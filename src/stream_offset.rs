@@ -0,0 +1,148 @@
+use crate::errors::*;
+use crate::{AmqpProperties, AmqpValue};
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub(crate) const X_STREAM_OFFSET: &str = "x-stream-offset";
+
+/// Where in a [stream queue](https://www.rabbitmq.com/streams.html) a consumer starts reading,
+/// sent as the `x-stream-offset` consumer argument. Set it with
+/// [`ConsumerOptions::stream_offset`](struct.ConsumerOptions.html#method.stream_offset).
+///
+/// Stream queues also require consumers to acknowledge deliveries (i.e., not set
+/// [`no_ack`](struct.ConsumerOptions.html#structfield.no_ack)) and to have a prefetch limit set
+/// with [`Channel::qos`](struct.Channel.html#method.qos); the server delivers messages in chunks
+/// of up to that many at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamOffset {
+    /// The first message still in the stream.
+    First,
+
+    /// The last chunk of messages written to the stream.
+    Last,
+
+    /// Only messages written after the consumer starts. This is the server's default.
+    Next,
+
+    /// The message at this offset (or the first message, if it has been truncated away).
+    Offset(u64),
+
+    /// The first chunk of messages written at or after this time. The server only tracks
+    /// time to the second.
+    Timestamp(SystemTime),
+}
+
+impl StreamOffset {
+    /// The argument value the server expects: a string for `first`, `last` and `next`, a 64-bit
+    /// integer for offsets, and an AMQP timestamp (seconds since the Unix epoch) for times.
+    pub(crate) fn to_amqp_value(self) -> AmqpValue {
+        match self {
+            StreamOffset::First => AmqpValue::LongString("first".to_string()),
+            StreamOffset::Last => AmqpValue::LongString("last".to_string()),
+            StreamOffset::Next => AmqpValue::LongString("next".to_string()),
+            StreamOffset::Offset(offset) => {
+                AmqpValue::LongLongInt(i64::try_from(offset).unwrap_or(i64::MAX))
+            }
+            StreamOffset::Timestamp(time) => {
+                // Times before the epoch are clamped to it; the stream cannot be older.
+                let secs = time
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                AmqpValue::Timestamp(secs)
+            }
+        }
+    }
+}
+
+pub(crate) fn delivery_offset(properties: &AmqpProperties) -> Result<Option<u64>> {
+    let value = match properties
+        .headers()
+        .as_ref()
+        .and_then(|headers| headers.get(X_STREAM_OFFSET))
+    {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let offset = match *value {
+        AmqpValue::LongLongInt(n) => u64::try_from(n).ok(),
+        AmqpValue::LongInt(n) => u64::try_from(n).ok(),
+        AmqpValue::LongUInt(n) => Some(u64::from(n)),
+        _ => None,
+    };
+    match offset {
+        Some(offset) => Ok(Some(offset)),
+        None => MalformedHeaderSnafu {
+            header: X_STREAM_OFFSET,
+            message: format!("expected a non-negative offset, got {:?}", value),
+        }
+        .fail(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConsumerOptions, Error, FieldTable};
+    use std::time::Duration;
+
+    #[test]
+    fn offsets_use_types_server_expects() {
+        assert_eq!(
+            StreamOffset::First.to_amqp_value(),
+            AmqpValue::LongString("first".to_string())
+        );
+        assert_eq!(
+            StreamOffset::Next.to_amqp_value(),
+            AmqpValue::LongString("next".to_string())
+        );
+        assert_eq!(
+            StreamOffset::Offset(42).to_amqp_value(),
+            AmqpValue::LongLongInt(42)
+        );
+        let time = UNIX_EPOCH + Duration::from_millis(1_600_000_000_500);
+        assert_eq!(
+            StreamOffset::Timestamp(time).to_amqp_value(),
+            AmqpValue::Timestamp(1_600_000_000)
+        );
+    }
+
+    #[test]
+    fn consumer_options_send_offset_and_require_manual_ack() {
+        let options = ConsumerOptions::default().stream_offset(StreamOffset::Last);
+        let consume = options
+            .clone()
+            .into_consume("events".to_string(), String::new(), false)
+            .unwrap();
+        assert_eq!(
+            consume.arguments.get(X_STREAM_OFFSET),
+            Some(&AmqpValue::LongString("last".to_string()))
+        );
+
+        let options = ConsumerOptions {
+            no_ack: true,
+            ..options
+        };
+        match options.into_consume("events".to_string(), String::new(), false) {
+            Err(Error::StreamOffsetRequiresManualAck) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_delivery_offset_header() {
+        let with_header = |value| {
+            let mut headers = FieldTable::new();
+            headers.insert(X_STREAM_OFFSET.to_string(), value);
+            AmqpProperties::default().with_headers(headers)
+        };
+        assert_eq!(delivery_offset(&AmqpProperties::default()).unwrap(), None);
+        assert_eq!(
+            delivery_offset(&with_header(AmqpValue::LongLongInt(17))).unwrap(),
+            Some(17)
+        );
+        match delivery_offset(&with_header(AmqpValue::LongLongInt(-1))) {
+            Err(Error::MalformedHeader { header, .. }) => assert_eq!(header, X_STREAM_OFFSET),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+// Delivery tags and publisher confirm sequence numbers are both u64s assigned by the server,
+// starting at 1 on each new channel. The arithmetic on them (what a `multiple` acknowledgment
+// covers, what comes next) lives here so it is written, and tested, once.
+
+#[cfg(feature = "consume")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Whether an acknowledgment of `ack` (with the given `multiple` flag) settles `tag`.
+fn is_settled_by(tag: u64, ack: u64, multiple: bool) -> bool {
+    tag == ack || (multiple && tag < ack)
+}
+
+// The sequence number of a message published on a channel in confirm mode, as carried in the
+// delivery tag of the server's ack or nack for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct SeqNo(u64);
+
+impl SeqNo {
+    pub(crate) fn new(seq_no: u64) -> SeqNo {
+        SeqNo(seq_no)
+    }
+
+    pub(crate) fn get(self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn next(self) -> SeqNo {
+        SeqNo(
+            self.0
+                .checked_add(1)
+                .expect("publisher confirm sequence number overflowed"),
+        )
+    }
+
+    pub(crate) fn is_settled_by(self, confirm: SeqNo, multiple: bool) -> bool {
+        is_settled_by(self.0, confirm.0, multiple)
+    }
+}
+
+// Identifies one incarnation of a channel. Channel ids are reused once a channel is closed and
+// the server restarts delivery tags at 1 on every new channel, so a delivery from a closed
+// channel must not be acknowledged on a new channel that happens to have the same id.
+#[cfg(feature = "consume")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct ChannelEpoch(u64);
+
+#[cfg(feature = "consume")]
+impl ChannelEpoch {
+    pub(crate) fn next() -> ChannelEpoch {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        ChannelEpoch(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+// A server-assigned delivery tag, along with the channel incarnation it is valid on.
+#[cfg(feature = "consume")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DeliveryTag {
+    epoch: ChannelEpoch,
+    tag: u64,
+}
+
+#[cfg(feature = "consume")]
+impl DeliveryTag {
+    pub(crate) fn new(epoch: ChannelEpoch, tag: u64) -> DeliveryTag {
+        DeliveryTag { epoch, tag }
+    }
+
+    pub(crate) fn get(self) -> u64 {
+        self.tag
+    }
+
+    pub(crate) fn epoch(self) -> ChannelEpoch {
+        self.epoch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seq_no_settlement() {
+        let three = SeqNo::new(3);
+        assert!(three.is_settled_by(SeqNo::new(3), false));
+        assert!(three.is_settled_by(SeqNo::new(3), true));
+        assert!(three.is_settled_by(SeqNo::new(4), true));
+        assert!(!three.is_settled_by(SeqNo::new(4), false));
+        assert!(!three.is_settled_by(SeqNo::new(2), true));
+        assert_eq!(three.next(), SeqNo::new(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "overflowed")]
+    fn seq_no_does_not_wrap() {
+        let _ = SeqNo::new(u64::MAX).next();
+    }
+
+    #[cfg(feature = "consume")]
+    #[test]
+    fn channel_epochs_are_unique() {
+        let epochs = (0..100).map(|_| ChannelEpoch::next()).collect::<Vec<_>>();
+        for (i, epoch) in epochs.iter().enumerate() {
+            assert!(!epochs[i + 1..].contains(epoch));
+        }
+    }
+}
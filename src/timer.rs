@@ -0,0 +1,131 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+/// Handle to a timeout set on a [`Timer`], for cancelling it.
+#[derive(Debug)]
+pub(crate) struct Timeout(u64);
+
+/// A queue of deadlines, each carrying a value that is handed back once it passes. Nothing wakes
+/// anyone when a deadline passes; the I/O loop limits how long it polls to
+/// [`next_deadline`](#method.next_deadline) and then collects whatever expired with
+/// [`poll`](#method.poll).
+///
+/// The I/O loop only ever has a handful of timeouts set (two heartbeats, plus a batched ack
+/// flush per channel), so cancelled timeouts are simply left in the heap until they come due.
+#[derive(Debug)]
+pub(crate) struct Timer<T> {
+    deadlines: BinaryHeap<Reverse<(Instant, u64)>>,
+    values: HashMap<u64, T>,
+    next_id: u64,
+}
+
+impl<T> Default for Timer<T> {
+    fn default() -> Self {
+        Timer {
+            deadlines: BinaryHeap::new(),
+            values: HashMap::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<T> Timer<T> {
+    /// Arrange for `value` to be returned by `poll` once `delay` has passed.
+    pub(crate) fn set_timeout(&mut self, delay: Duration, value: T) -> Timeout {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.deadlines.push(Reverse((Instant::now() + delay, id)));
+        self.values.insert(id, value);
+        Timeout(id)
+    }
+
+    /// Cancel `timeout`, returning its value if it had not already expired (and been polled).
+    pub(crate) fn cancel_timeout(&mut self, timeout: &Timeout) -> Option<T> {
+        self.values.remove(&timeout.0)
+    }
+
+    /// Return the value of a timeout that has expired, if there is one. Timeouts that expire at
+    /// the same time are returned in the order they were set.
+    pub(crate) fn poll(&mut self) -> Option<T> {
+        self.poll_at(Instant::now())
+    }
+
+    fn poll_at(&mut self, now: Instant) -> Option<T> {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            if let Some(value) = self.values.remove(&id) {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    /// When the earliest timeout that is still set expires.
+    pub(crate) fn next_deadline(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if self.values.contains_key(&id) {
+                return Some(deadline);
+            }
+            self.deadlines.pop();
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(u: u64) -> Duration {
+        Duration::from_millis(u)
+    }
+
+    #[test]
+    fn expires_in_deadline_order() {
+        let mut timer = Timer::default();
+        let start = Instant::now();
+        timer.set_timeout(millis(300), "c");
+        timer.set_timeout(millis(100), "a");
+        timer.set_timeout(millis(200), "b");
+        let next = timer.next_deadline().unwrap();
+        assert!(next >= start + millis(100) && next < start + millis(200));
+
+        assert_eq!(timer.poll_at(start), None);
+        assert_eq!(timer.poll_at(start + millis(250)), Some("a"));
+        assert_eq!(timer.poll_at(start + millis(250)), Some("b"));
+        assert_eq!(timer.poll_at(start + millis(250)), None);
+        assert!(timer.next_deadline().unwrap() >= start + millis(300));
+        assert_eq!(timer.poll_at(start + millis(400)), Some("c"));
+        assert_eq!(timer.next_deadline(), None);
+    }
+
+    #[test]
+    fn cancelled_timeouts_do_not_fire() {
+        let mut timer = Timer::default();
+        let start = Instant::now();
+        let first = timer.set_timeout(millis(100), 1);
+        let second = timer.set_timeout(millis(200), 2);
+        assert_eq!(timer.cancel_timeout(&first), Some(1));
+        assert_eq!(timer.cancel_timeout(&first), None);
+        assert!(timer.next_deadline().unwrap() >= start + millis(200));
+
+        assert_eq!(timer.poll_at(start + millis(300)), Some(2));
+        // Already expired, so there is nothing left to cancel.
+        assert_eq!(timer.cancel_timeout(&second), None);
+        assert_eq!(timer.next_deadline(), None);
+    }
+
+    #[test]
+    fn same_deadline_keeps_insertion_order() {
+        let mut timer = Timer::default();
+        for value in 0..5 {
+            timer.set_timeout(Duration::from_secs(0), value);
+        }
+        let expired = std::iter::from_fn(|| timer.poll()).collect::<Vec<_>>();
+        assert_eq!(expired, vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,255 @@
+use super::{BindingDefinition, BindingDestination, ExchangeDefinition, QueueDefinition, Topology};
+use crate::{AmqpValue, ExchangeDeclareOptions, ExchangeType, FieldTable, QueueDeclareOptions};
+
+/// Builds a [`Topology`](struct.Topology.html) one exchange, queue, or binding at a time.
+///
+/// Create a builder with [`Topology::builder`](struct.Topology.html#method.builder). Options such
+/// as [`durable`](#method.durable) and bindings added with [`bind`](#method.bind) apply to the
+/// exchange or queue added most recently:
+///
+/// ```rust
+/// use amiquip::{ExchangeType, Topology};
+///
+/// let topology = Topology::builder()
+///     .exchange("events", ExchangeType::Topic)
+///     .durable()
+///     .queue("audit")
+///     .durable()
+///     .bind("events", "user.*")
+///     .build();
+/// assert_eq!(topology.bindings[0].destination, "audit");
+/// ```
+///
+/// # Panics
+///
+/// The methods that modify the most recent exchange or queue panic if there isn't one, or (for
+/// options that only apply to one of them, like [`exclusive`](#method.exclusive)) if it is the
+/// wrong kind.
+#[derive(Clone, Debug, Default)]
+pub struct TopologyBuilder {
+    topology: Topology,
+    last: Option<Last>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Last {
+    Exchange,
+    Queue,
+}
+
+impl Topology {
+    /// Start building a topology with a [`TopologyBuilder`](struct.TopologyBuilder.html).
+    pub fn builder() -> TopologyBuilder {
+        TopologyBuilder::default()
+    }
+}
+
+impl From<TopologyBuilder> for Topology {
+    fn from(builder: TopologyBuilder) -> Topology {
+        builder.build()
+    }
+}
+
+impl TopologyBuilder {
+    /// Add an exchange with the given name and type, and default options.
+    pub fn exchange<S: Into<String>>(mut self, name: S, type_: ExchangeType) -> Self {
+        self.topology.exchanges.push(ExchangeDefinition {
+            name: name.into(),
+            type_,
+            options: ExchangeDeclareOptions::default(),
+        });
+        self.last = Some(Last::Exchange);
+        self
+    }
+
+    /// Add a queue with the given name and default options. `name` must not be empty.
+    pub fn queue<S: Into<String>>(mut self, name: S) -> Self {
+        let name = name.into();
+        assert!(!name.is_empty(), "topology queues must be named");
+        self.topology.queues.push(QueueDefinition {
+            name,
+            options: QueueDeclareOptions::default(),
+        });
+        self.last = Some(Last::Queue);
+        self
+    }
+
+    /// Make the most recent exchange or queue durable.
+    pub fn durable(mut self) -> Self {
+        match self.last("durable") {
+            Last::Exchange => self.exchange_options("durable").durable = true,
+            Last::Queue => self.queue_options("durable").durable = true,
+        }
+        self
+    }
+
+    /// Make the most recent exchange or queue auto-delete.
+    pub fn auto_delete(mut self) -> Self {
+        match self.last("auto_delete") {
+            Last::Exchange => self.exchange_options("auto_delete").auto_delete = true,
+            Last::Queue => self.queue_options("auto_delete").auto_delete = true,
+        }
+        self
+    }
+
+    /// Make the most recent exchange internal. Panics if the most recent addition was a queue.
+    pub fn internal(mut self) -> Self {
+        self.exchange_options("internal").internal = true;
+        self
+    }
+
+    /// Make the most recent queue exclusive. Panics if the most recent addition was an exchange.
+    pub fn exclusive(mut self) -> Self {
+        self.queue_options("exclusive").exclusive = true;
+        self
+    }
+
+    /// Add an argument to the declaration of the most recent exchange or queue.
+    pub fn argument<S: Into<String>>(mut self, key: S, value: AmqpValue) -> Self {
+        let arguments = match self.last("argument") {
+            Last::Exchange => &mut self.exchange_options("argument").arguments,
+            Last::Queue => &mut self.queue_options("argument").arguments,
+        };
+        arguments.insert(key.into(), value);
+        self
+    }
+
+    /// Bind the most recent exchange or queue to the exchange `source` with the given routing
+    /// key. Binding an exchange creates a RabbitMQ exchange-to-exchange binding.
+    pub fn bind<S0: Into<String>, S1: Into<String>>(self, source: S0, routing_key: S1) -> Self {
+        self.bind_with_arguments(source, routing_key, FieldTable::new())
+    }
+
+    /// Like [`bind`](#method.bind), but with binding arguments (e.g., header matching rules for
+    /// headers exchanges).
+    pub fn bind_with_arguments<S0: Into<String>, S1: Into<String>>(
+        mut self,
+        source: S0,
+        routing_key: S1,
+        arguments: FieldTable,
+    ) -> Self {
+        let (destination, destination_type) = match self.last("bind") {
+            // unwraps are safe: `last` is only set after pushing onto the matching list.
+            Last::Exchange => (
+                self.topology.exchanges.last().unwrap().name.clone(),
+                BindingDestination::Exchange,
+            ),
+            Last::Queue => (
+                self.topology.queues.last().unwrap().name.clone(),
+                BindingDestination::Queue,
+            ),
+        };
+        self.topology.bindings.push(BindingDefinition {
+            source: source.into(),
+            destination,
+            destination_type,
+            routing_key: routing_key.into(),
+            arguments,
+        });
+        self
+    }
+
+    /// Finish building the topology.
+    pub fn build(self) -> Topology {
+        self.topology
+    }
+
+    fn last(&self, method: &str) -> Last {
+        match self.last {
+            Some(last) => last,
+            None => panic!("{}() requires an exchange or queue to apply to", method),
+        }
+    }
+
+    fn exchange_options(&mut self, method: &str) -> &mut ExchangeDeclareOptions {
+        match self.last {
+            Some(Last::Exchange) => &mut self.topology.exchanges.last_mut().unwrap().options,
+            _ => panic!("{}() requires an exchange to apply to", method),
+        }
+    }
+
+    fn queue_options(&mut self, method: &str) -> &mut QueueDeclareOptions {
+        match self.last {
+            Some(Last::Queue) => &mut self.topology.queues.last_mut().unwrap().options,
+            _ => panic!("{}() requires a queue to apply to", method),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_and_bindings_apply_to_most_recent_entity() {
+        let topology = Topology::builder()
+            .exchange("events", ExchangeType::Topic)
+            .durable()
+            .exchange("user-events", ExchangeType::Fanout)
+            .internal()
+            .bind("events", "user.*")
+            .queue("audit")
+            .durable()
+            .argument("x-max-priority", AmqpValue::LongInt(5))
+            .bind("user-events", "")
+            .queue("scratch")
+            .exclusive()
+            .auto_delete()
+            .build();
+
+        assert_eq!(topology.exchanges.len(), 2);
+        assert!(topology.exchanges[0].options.durable);
+        assert!(!topology.exchanges[0].options.internal);
+        assert!(!topology.exchanges[1].options.durable);
+        assert!(topology.exchanges[1].options.internal);
+
+        assert_eq!(topology.queues.len(), 2);
+        let audit = &topology.queues[0].options;
+        assert!(audit.durable && !audit.exclusive && !audit.auto_delete);
+        assert_eq!(
+            audit.arguments.get("x-max-priority"),
+            Some(&AmqpValue::LongInt(5))
+        );
+        let scratch = &topology.queues[1].options;
+        assert!(!scratch.durable && scratch.exclusive && scratch.auto_delete);
+
+        let bindings = topology
+            .bindings
+            .iter()
+            .map(|b| {
+                (
+                    b.source.as_str(),
+                    b.destination.as_str(),
+                    b.destination_type,
+                    b.routing_key.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bindings,
+            vec![
+                (
+                    "events",
+                    "user-events",
+                    BindingDestination::Exchange,
+                    "user.*"
+                ),
+                ("user-events", "audit", BindingDestination::Queue, ""),
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "exclusive() requires a queue")]
+    fn queue_option_on_exchange_panics() {
+        Topology::builder()
+            .exchange("events", ExchangeType::Topic)
+            .exclusive();
+    }
+
+    #[test]
+    #[should_panic(expected = "bind() requires an exchange or queue")]
+    fn bind_without_entity_panics() {
+        Topology::builder().bind("events", "");
+    }
+}
@@ -0,0 +1,536 @@
+use super::{BindingDefinition, BindingDestination, ExchangeDefinition, QueueDefinition, Topology};
+use crate::amqp_json::{plain_table_to_json, plain_value_from_json};
+use crate::errors::*;
+use crate::{AmqpValue, ExchangeDeclareOptions, ExchangeType, FieldTable, QueueDeclareOptions};
+use serde_json::{Map, Value};
+use snafu::ResultExt;
+use std::convert::TryFrom;
+
+// Top-level keys of a definitions file that describe the file itself rather than server state.
+const METADATA_KEYS: &[&str] = &[
+    "rabbit_version",
+    "rabbitmq_version",
+    "product_name",
+    "product_version",
+];
+
+// Arguments the server expects to be small integers (priorities). All other integer arguments are
+// encoded as 64-bit integers, which is also what the management plugin does when it declares
+// entities from a definitions file.
+const INT32_ARGUMENTS: &[&str] = &["x-max-priority", "x-priority"];
+
+/// The result of loading a topology with
+/// [`Topology::from_definitions`](struct.Topology.html#method.from_definitions).
+#[derive(Clone, Debug)]
+pub struct ImportedDefinitions {
+    /// The exchanges, queues, and bindings found in the definitions file.
+    pub topology: Topology,
+
+    /// Descriptions of everything in the definitions file that was not imported (users,
+    /// virtual hosts, policies, entities belonging to other virtual hosts, etc.).
+    pub warnings: Vec<String>,
+}
+
+impl Topology {
+    /// Load the exchanges, queues, and bindings from a RabbitMQ `definitions.json` file (as
+    /// exported by the management plugin or `rabbitmqctl export_definitions`).
+    ///
+    /// Only entities belonging to virtual host `vhost` are imported; entities without a `vhost`
+    /// field (as in a file exported for a single virtual host) are always imported. Users, virtual
+    /// hosts, permissions, policies, parameters, and entities in other virtual hosts are skipped,
+    /// and each skipped section is described in the returned
+    /// [`warnings`](struct.ImportedDefinitions.html#structfield.warnings).
+    ///
+    /// JSON strings, booleans, arrays, and objects in `arguments` are converted to the
+    /// corresponding [`AmqpValue`](enum.AmqpValue.html)s. Integers are converted to
+    /// `AmqpValue::LongLongInt`, except for `x-max-priority` and `x-priority`, which are converted
+    /// to `AmqpValue::LongInt`; other numbers are converted to `AmqpValue::Double`.
+    ///
+    /// This method is only available if amiquip is built with the `serde` feature.
+    pub fn from_definitions(json: &str, vhost: &str) -> Result<ImportedDefinitions> {
+        let root: Value = serde_json::from_str(json).context(ParseDefinitionsSnafu)?;
+        let root = match root {
+            Value::Object(root) => root,
+            _ => return invalid("expected a JSON object"),
+        };
+
+        let mut topology = Topology::new();
+        let mut warnings = Vec::new();
+        for (key, value) in &root {
+            let entries = match key.as_str() {
+                "exchanges" | "queues" | "bindings" => match value {
+                    Value::Array(entries) => entries,
+                    _ => return invalid(format!("\"{}\" is not an array", key)),
+                },
+                key if METADATA_KEYS.contains(&key) => continue,
+                key => {
+                    let count = match value {
+                        Value::Array(v) => v.len(),
+                        Value::Object(v) => v.len(),
+                        _ => 1,
+                    };
+                    if count > 0 {
+                        warnings.push(format!("ignored {} {}", count, key));
+                    }
+                    continue;
+                }
+            };
+
+            let mut skipped = 0;
+            for entry in entries {
+                let entry = Entry::new(key, entry)?;
+                match entry.optional_str("vhost")? {
+                    Some(v) if v != vhost => {
+                        skipped += 1;
+                        continue;
+                    }
+                    _ => (),
+                }
+                match key.as_str() {
+                    "exchanges" => topology.exchanges.push(entry.exchange()?),
+                    "queues" => topology.queues.push(entry.queue()?),
+                    _ => topology.bindings.push(entry.binding()?),
+                }
+            }
+            if skipped > 0 {
+                warnings.push(format!(
+                    "ignored {} {} in other virtual hosts",
+                    skipped, key
+                ));
+            }
+        }
+
+        Ok(ImportedDefinitions { topology, warnings })
+    }
+
+    /// Export this topology in the RabbitMQ `definitions.json` format, with every entity
+    /// assigned to virtual host `vhost`. The result can be imported by the management plugin or
+    /// loaded back with [`from_definitions`](#method.from_definitions).
+    ///
+    /// The definitions format has no way to describe exclusive queues; queues declared with
+    /// [`exclusive`](struct.QueueDeclareOptions.html#structfield.exclusive) set are exported as
+    /// non-exclusive queues.
+    ///
+    /// This method is only available if amiquip is built with the `serde` feature.
+    pub fn to_definitions(&self, vhost: &str) -> String {
+        let vhost = Value::String(vhost.to_string());
+        let exchanges = self
+            .exchanges
+            .iter()
+            .map(|exchange| {
+                let mut entry = Map::new();
+                entry.insert("name".to_string(), exchange.name.clone().into());
+                entry.insert("vhost".to_string(), vhost.clone());
+                entry.insert("type".to_string(), exchange.type_.as_ref().into());
+                entry.insert("durable".to_string(), exchange.options.durable.into());
+                entry.insert(
+                    "auto_delete".to_string(),
+                    exchange.options.auto_delete.into(),
+                );
+                entry.insert("internal".to_string(), exchange.options.internal.into());
+                entry.insert(
+                    "arguments".to_string(),
+                    plain_table_to_json(&exchange.options.arguments),
+                );
+                Value::Object(entry)
+            })
+            .collect();
+        let queues = self
+            .queues
+            .iter()
+            .map(|queue| {
+                let mut entry = Map::new();
+                entry.insert("name".to_string(), queue.name.clone().into());
+                entry.insert("vhost".to_string(), vhost.clone());
+                entry.insert("durable".to_string(), queue.options.durable.into());
+                entry.insert("auto_delete".to_string(), queue.options.auto_delete.into());
+                entry.insert(
+                    "arguments".to_string(),
+                    plain_table_to_json(&queue.options.arguments),
+                );
+                Value::Object(entry)
+            })
+            .collect();
+        let bindings = self
+            .bindings
+            .iter()
+            .map(|binding| {
+                let destination_type = match binding.destination_type {
+                    BindingDestination::Queue => "queue",
+                    BindingDestination::Exchange => "exchange",
+                };
+                let mut entry = Map::new();
+                entry.insert("source".to_string(), binding.source.clone().into());
+                entry.insert("vhost".to_string(), vhost.clone());
+                entry.insert(
+                    "destination".to_string(),
+                    binding.destination.clone().into(),
+                );
+                entry.insert("destination_type".to_string(), destination_type.into());
+                entry.insert(
+                    "routing_key".to_string(),
+                    binding.routing_key.clone().into(),
+                );
+                entry.insert(
+                    "arguments".to_string(),
+                    plain_table_to_json(&binding.arguments),
+                );
+                Value::Object(entry)
+            })
+            .collect();
+
+        let mut root = Map::new();
+        root.insert("exchanges".to_string(), Value::Array(exchanges));
+        root.insert("queues".to_string(), Value::Array(queues));
+        root.insert("bindings".to_string(), Value::Array(bindings));
+        // unwrap is safe: serializing a Value cannot fail
+        serde_json::to_string_pretty(&Value::Object(root)).unwrap()
+    }
+}
+
+fn invalid<T, S: Into<String>>(message: S) -> Result<T> {
+    InvalidDefinitionsSnafu {
+        message: message.into(),
+    }
+    .fail()
+}
+
+// One element of the "exchanges", "queues", or "bindings" array.
+struct Entry<'a> {
+    section: &'a str,
+    fields: &'a Map<String, Value>,
+}
+
+impl<'a> Entry<'a> {
+    fn new(section: &'a str, value: &'a Value) -> Result<Entry<'a>> {
+        match value {
+            Value::Object(fields) => Ok(Entry { section, fields }),
+            _ => invalid(format!("entry in \"{}\" is not an object", section)),
+        }
+    }
+
+    fn optional_str(&self, field: &str) -> Result<Option<&'a str>> {
+        match self.fields.get(field) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => invalid(format!(
+                "field \"{}\" in \"{}\" is not a string",
+                field, self.section
+            )),
+        }
+    }
+
+    fn str(&self, field: &str) -> Result<&'a str> {
+        match self.optional_str(field)? {
+            Some(s) => Ok(s),
+            None => invalid(format!(
+                "entry in \"{}\" is missing field \"{}\"",
+                self.section, field
+            )),
+        }
+    }
+
+    fn bool(&self, field: &str) -> Result<bool> {
+        match self.fields.get(field) {
+            None => Ok(false),
+            Some(Value::Bool(b)) => Ok(*b),
+            Some(_) => invalid(format!(
+                "field \"{}\" in \"{}\" is not a boolean",
+                field, self.section
+            )),
+        }
+    }
+
+    fn arguments(&self) -> Result<FieldTable> {
+        match self.fields.get("arguments") {
+            None | Some(Value::Null) => Ok(FieldTable::new()),
+            Some(Value::Object(arguments)) => arguments_from_json(arguments),
+            Some(_) => invalid(format!(
+                "field \"arguments\" in \"{}\" is not an object",
+                self.section
+            )),
+        }
+    }
+
+    fn exchange(&self) -> Result<ExchangeDefinition> {
+        let type_ = match self.str("type")? {
+            "direct" => ExchangeType::Direct,
+            "fanout" => ExchangeType::Fanout,
+            "topic" => ExchangeType::Topic,
+            "headers" => ExchangeType::Headers,
+            other => ExchangeType::Custom(other.to_string()),
+        };
+        Ok(ExchangeDefinition {
+            name: self.str("name")?.to_string(),
+            type_,
+            options: ExchangeDeclareOptions {
+                durable: self.bool("durable")?,
+                auto_delete: self.bool("auto_delete")?,
+                internal: self.bool("internal")?,
+                arguments: self.arguments()?,
+            },
+        })
+    }
+
+    fn queue(&self) -> Result<QueueDefinition> {
+        Ok(QueueDefinition {
+            name: self.str("name")?.to_string(),
+            options: QueueDeclareOptions {
+                durable: self.bool("durable")?,
+                exclusive: false,
+                auto_delete: self.bool("auto_delete")?,
+                arguments: self.arguments()?,
+            },
+        })
+    }
+
+    fn binding(&self) -> Result<BindingDefinition> {
+        let destination_type = match self.str("destination_type")? {
+            "queue" => BindingDestination::Queue,
+            "exchange" => BindingDestination::Exchange,
+            other => return invalid(format!("unknown binding destination type \"{}\"", other)),
+        };
+        Ok(BindingDefinition {
+            source: self.str("source")?.to_string(),
+            destination: self.str("destination")?.to_string(),
+            destination_type,
+            routing_key: self.str("routing_key")?.to_string(),
+            arguments: self.arguments()?,
+        })
+    }
+}
+
+fn arguments_from_json(object: &Map<String, Value>) -> Result<FieldTable> {
+    let mut table = FieldTable::new();
+    for (name, value) in object {
+        table.insert(name.clone(), argument_from_json(name, value)?);
+    }
+    Ok(table)
+}
+
+fn argument_from_json(name: &str, value: &Value) -> Result<AmqpValue> {
+    match value {
+        Value::Number(n) if INT32_ARGUMENTS.contains(&name) => {
+            match n.as_i64().map(i32::try_from) {
+                Some(Ok(n)) => Ok(AmqpValue::LongInt(n)),
+                _ => invalid(format!("argument \"{}\" must be a 32-bit integer", name)),
+            }
+        }
+        _ => plain_value_from_json(value).or_else(|err| match err {
+            Error::JsonConversion { message } => {
+                invalid(format!("argument \"{}\": {}", name, message))
+            }
+            err => Err(err),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A definitions file in the format exported by RabbitMQ 3.12, with two virtual hosts.
+    const DEFINITIONS: &str = include_str!("test_definitions.json");
+
+    fn load() -> ImportedDefinitions {
+        Topology::from_definitions(DEFINITIONS, "/").unwrap()
+    }
+
+    #[test]
+    fn imports_exchanges() {
+        let exchanges = load().topology.exchanges;
+        let names = exchanges
+            .iter()
+            .map(|e| e.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["events", "events.dlx", "events.delayed"]);
+
+        let events = &exchanges[0];
+        assert_eq!(events.type_.as_ref(), "topic");
+        assert!(events.options.durable);
+        assert!(!events.options.auto_delete);
+        assert!(!events.options.internal);
+        assert!(events.options.arguments.is_empty());
+
+        let dlx = &exchanges[1];
+        assert_eq!(dlx.type_.as_ref(), "fanout");
+        assert!(dlx.options.internal);
+
+        let delayed = &exchanges[2];
+        assert_eq!(delayed.type_.as_ref(), "x-delayed-message");
+        assert_eq!(
+            delayed.options.arguments.get("x-delayed-type"),
+            Some(&AmqpValue::LongString("direct".to_string()))
+        );
+    }
+
+    #[test]
+    fn imports_queues_with_typed_arguments() {
+        let queues = load().topology.queues;
+        let names = queues.iter().map(|q| q.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["orders", "orders.dead", "jobs"]);
+
+        let orders = &queues[0].options;
+        assert!(orders.durable);
+        assert!(!orders.exclusive);
+        let arg = |name: &str| orders.arguments.get(name).cloned();
+        assert_eq!(arg("x-message-ttl"), Some(AmqpValue::LongLongInt(60000)));
+        assert_eq!(arg("x-max-length"), Some(AmqpValue::LongLongInt(10000)));
+        assert_eq!(
+            arg("x-max-length-bytes"),
+            Some(AmqpValue::LongLongInt(10_737_418_240))
+        );
+        assert_eq!(arg("x-max-priority"), Some(AmqpValue::LongInt(10)));
+        assert_eq!(
+            arg("x-dead-letter-exchange"),
+            Some(AmqpValue::LongString("events.dlx".to_string()))
+        );
+        assert_eq!(
+            arg("x-queue-type"),
+            Some(AmqpValue::LongString("classic".to_string()))
+        );
+
+        let jobs = &queues[2].options;
+        assert_eq!(
+            jobs.arguments.get("x-queue-type"),
+            Some(&AmqpValue::LongString("quorum".to_string()))
+        );
+        assert_eq!(
+            jobs.arguments.get("x-delivery-limit"),
+            Some(&AmqpValue::LongLongInt(5))
+        );
+        assert_eq!(
+            jobs.arguments.get("x-single-active-consumer"),
+            Some(&AmqpValue::Boolean(true))
+        );
+    }
+
+    #[test]
+    fn imports_bindings() {
+        let bindings = load().topology.bindings;
+        assert_eq!(bindings.len(), 3);
+
+        assert_eq!(bindings[0].source, "events");
+        assert_eq!(bindings[0].destination, "orders");
+        assert_eq!(bindings[0].destination_type, BindingDestination::Queue);
+        assert_eq!(bindings[0].routing_key, "order.*");
+
+        assert_eq!(bindings[1].destination, "orders.dead");
+        assert_eq!(bindings[1].routing_key, "");
+
+        assert_eq!(bindings[2].source, "amq.headers");
+        assert_eq!(bindings[2].destination, "events");
+        assert_eq!(bindings[2].destination_type, BindingDestination::Exchange);
+        let mut expected = FieldTable::new();
+        expected.insert(
+            "x-match".to_string(),
+            AmqpValue::LongString("all".to_string()),
+        );
+        expected.insert(
+            "region".to_string(),
+            AmqpValue::LongString("eu".to_string()),
+        );
+        assert_eq!(bindings[2].arguments, expected);
+    }
+
+    #[test]
+    fn reports_ignored_sections() {
+        let mut warnings = load().warnings;
+        warnings.sort();
+        assert_eq!(
+            warnings,
+            [
+                "ignored 1 exchanges in other virtual hosts",
+                "ignored 1 global_parameters",
+                "ignored 1 policies",
+                "ignored 1 queues in other virtual hosts",
+                "ignored 2 permissions",
+                "ignored 2 users",
+                "ignored 2 vhosts",
+            ]
+        );
+    }
+
+    #[test]
+    fn selects_vhost() {
+        let imported = Topology::from_definitions(DEFINITIONS, "staging").unwrap();
+        let names = imported
+            .topology
+            .queues
+            .iter()
+            .map(|q| q.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["staging.orders"]);
+        assert_eq!(imported.topology.exchanges.len(), 1);
+        assert!(imported.topology.bindings.is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let original = load().topology;
+        let exported = original.to_definitions("/");
+        let imported = Topology::from_definitions(&exported, "/").unwrap();
+        assert!(imported.warnings.is_empty());
+
+        let topology = imported.topology;
+        assert_eq!(topology.exchanges.len(), original.exchanges.len());
+        for (a, b) in topology.exchanges.iter().zip(&original.exchanges) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.type_.as_ref(), b.type_.as_ref());
+            assert_eq!(a.options.durable, b.options.durable);
+            assert_eq!(a.options.auto_delete, b.options.auto_delete);
+            assert_eq!(a.options.internal, b.options.internal);
+            assert_eq!(a.options.arguments, b.options.arguments);
+        }
+        assert_eq!(topology.queues.len(), original.queues.len());
+        for (a, b) in topology.queues.iter().zip(&original.queues) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(a.options.durable, b.options.durable);
+            assert_eq!(a.options.auto_delete, b.options.auto_delete);
+            assert_eq!(a.options.arguments, b.options.arguments);
+        }
+        assert_eq!(topology.bindings.len(), original.bindings.len());
+        for (a, b) in topology.bindings.iter().zip(&original.bindings) {
+            assert_eq!(a.source, b.source);
+            assert_eq!(a.destination, b.destination);
+            assert_eq!(a.destination_type, b.destination_type);
+            assert_eq!(a.routing_key, b.routing_key);
+            assert_eq!(a.arguments, b.arguments);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_definitions() {
+        let cases = &[
+            ("[]", "expected a JSON object"),
+            (r#"{"queues": {}}"#, "\"queues\" is not an array"),
+            (
+                r#"{"queues": [{"durable": true}]}"#,
+                "entry in \"queues\" is missing field \"name\"",
+            ),
+            (
+                r#"{"queues": [{"name": "q", "arguments": {"x-max-priority": 1e10}}]}"#,
+                "argument \"x-max-priority\" must be a 32-bit integer",
+            ),
+            (
+                r#"{"queues": [{"name": "q", "arguments": {"x-max-length": 18446744073709551615}}]}"#,
+                "argument \"x-max-length\": integer 18446744073709551615 is out of range",
+            ),
+            (
+                r#"{"bindings": [{"source": "a", "destination": "b", "destination_type": "topic", "routing_key": ""}]}"#,
+                "unknown binding destination type \"topic\"",
+            ),
+        ];
+        for (json, expected) in cases {
+            match Topology::from_definitions(json, "/") {
+                Err(Error::InvalidDefinitions { message }) => assert_eq!(&message, expected),
+                other => panic!("unexpected result for {}: {:?}", json, other),
+            }
+        }
+
+        match Topology::from_definitions("{", "/") {
+            Err(Error::ParseDefinitions { .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{ExchangeDeclareOptions, ExchangeType, FieldTable, QueueDeclareOptions};
+
+mod builder;
+#[cfg(feature = "serde")]
+mod definitions;
+
+pub use builder::TopologyBuilder;
+
+#[cfg(feature = "serde")]
+pub use definitions::ImportedDefinitions;
+
+/// An exchange to be declared as part of a [`Topology`](struct.Topology.html).
+#[derive(Clone, Debug)]
+pub struct ExchangeDefinition {
+    /// Name of the exchange.
+    pub name: String,
+
+    /// Type of the exchange.
+    pub type_: ExchangeType,
+
+    /// Options used when declaring the exchange.
+    pub options: ExchangeDeclareOptions,
+}
+
+/// A queue to be declared as part of a [`Topology`](struct.Topology.html).
+#[derive(Clone, Debug)]
+pub struct QueueDefinition {
+    /// Name of the queue. If empty, the server chooses a name when the queue is declared, which
+    /// the topology's bindings cannot refer to.
+    pub name: String,
+
+    /// Options used when declaring the queue.
+    pub options: QueueDeclareOptions,
+}
+
+/// The kind of entity a [`BindingDefinition`](struct.BindingDefinition.html) routes messages to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BindingDestination {
+    /// The destination is a queue.
+    Queue,
+
+    /// The destination is an exchange (a RabbitMQ exchange-to-exchange binding).
+    Exchange,
+}
+
+/// A binding to be created as part of a [`Topology`](struct.Topology.html).
+#[derive(Clone, Debug)]
+pub struct BindingDefinition {
+    /// Name of the exchange messages are routed from.
+    pub source: String,
+
+    /// Name of the queue or exchange messages are routed to.
+    pub destination: String,
+
+    /// Whether [`destination`](#structfield.destination) names a queue or an exchange.
+    pub destination_type: BindingDestination,
+
+    /// Routing key (or pattern) of the binding.
+    pub routing_key: String,
+
+    /// Binding arguments (e.g., header matching rules for headers exchanges).
+    pub arguments: FieldTable,
+}
+
+/// A set of exchanges, queues, and bindings that can be declared together with
+/// [`Channel::apply_topology`](struct.Channel.html#method.apply_topology) or
+/// [`Channel::apply`](struct.Channel.html#method.apply), and checked against the server with
+/// [`Channel::verify`](struct.Channel.html#method.verify). Build one field by field, or with a
+/// [`TopologyBuilder`](struct.TopologyBuilder.html).
+///
+/// With the `serde` feature enabled, a topology can also be loaded from (and exported to) the
+/// `definitions.json` format used by the RabbitMQ management plugin; see
+/// [`from_definitions`](#method.from_definitions).
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    /// Exchanges to declare.
+    pub exchanges: Vec<ExchangeDefinition>,
+
+    /// Queues to declare.
+    pub queues: Vec<QueueDefinition>,
+
+    /// Bindings to create once all exchanges and queues have been declared.
+    pub bindings: Vec<BindingDefinition>,
+}
+
+impl Topology {
+    /// Create an empty topology.
+    pub fn new() -> Topology {
+        Topology::default()
+    }
+
+    // The declarations that apply this topology, in order: exchanges (skipping the default
+    // exchange), then queues, then bindings.
+    pub(crate) fn steps(&self) -> impl Iterator<Item = TopologyStep<'_>> {
+        let exchanges = self
+            .exchanges
+            .iter()
+            .filter(|exchange| !exchange.name.is_empty())
+            .map(|exchange| {
+                if exchange.name.starts_with("amq.") {
+                    TopologyStep::VerifyExchange(exchange)
+                } else {
+                    TopologyStep::DeclareExchange(exchange)
+                }
+            });
+        let queues = self.queues.iter().map(TopologyStep::DeclareQueue);
+        let bindings = self.bindings.iter().map(TopologyStep::Bind);
+        exchanges.chain(queues).chain(bindings)
+    }
+}
+
+// One declaration made to apply a topology (see `Topology::steps`).
+pub(crate) enum TopologyStep<'a> {
+    // A predeclared `amq.` exchange, which the server only lets clients declare passively.
+    VerifyExchange(&'a ExchangeDefinition),
+    DeclareExchange(&'a ExchangeDefinition),
+    DeclareQueue(&'a QueueDefinition),
+    Bind(&'a BindingDefinition),
+}
+
+/// What [`Channel::apply`](struct.Channel.html#method.apply) declared.
+///
+/// AMQP does not tell clients whether a declaration created an entity or found an identical one
+/// already in place, so entities that already existed are included.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopologyReport {
+    /// Names of the exchanges that were declared.
+    pub exchanges_declared: Vec<String>,
+
+    /// Names of the predeclared (`amq.`) exchanges that were confirmed to exist.
+    pub exchanges_verified: Vec<String>,
+
+    /// Names of the queues that were declared.
+    pub queues_declared: Vec<String>,
+
+    /// Number of bindings that were created.
+    pub bindings_created: usize,
+}
+
+/// A difference between a [`Topology`](struct.Topology.html) and the server, found by
+/// [`Channel::verify`](struct.Channel.html#method.verify).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TopologyMismatch {
+    /// The named exchange does not exist.
+    MissingExchange(String),
+
+    /// The named queue does not exist.
+    MissingQueue(String),
+
+    /// The exchange exists, but its type or options differ from the topology's; `message` is the
+    /// server's explanation.
+    ExchangeDiffers { name: String, message: String },
+
+    /// The queue exists, but its options differ from the topology's; `message` is the server's
+    /// explanation.
+    QueueDiffers { name: String, message: String },
+}
@@ -0,0 +1,382 @@
+use crate::errors::*;
+use crate::logging::{debug, error, warn};
+use crate::{AckPolicy, Acker, ConsumerMessage, Delivery};
+use crossbeam_channel::{select, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use snafu::ResultExt;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, Scope};
+use std::time::Instant;
+
+/// What a [`WorkerPool`](struct.WorkerPool.html) handler wants done with the delivery it was
+/// given.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandlerResult {
+    /// Acknowledge the delivery (following the consumer's [`AckPolicy`](enum.AckPolicy.html)).
+    Ack,
+
+    /// Nack the delivery, asking the server to requeue it if `requeue` is true.
+    Nack { requeue: bool },
+}
+
+/// Options for [`Consumer::run_workers_with_options`](struct.Consumer.html#method.run_workers_with_options).
+///
+/// The [`default`](#impl-Default) implementation uses a single worker and requeues deliveries
+/// whose handler panics.
+#[derive(Clone, Debug)]
+pub struct WorkerPoolOptions {
+    /// Number of threads running the handler. Must be at least 1.
+    pub workers: usize,
+
+    /// Whether deliveries whose handler panics are requeued when they are nacked.
+    pub requeue_on_panic: bool,
+}
+
+impl Default for WorkerPoolOptions {
+    fn default() -> WorkerPoolOptions {
+        WorkerPoolOptions {
+            workers: 1,
+            requeue_on_panic: true,
+        }
+    }
+}
+
+/// Counts of what one [`WorkerPool`](struct.WorkerPool.html) worker has done.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Deliveries the worker acknowledged.
+    pub processed: u64,
+
+    /// Deliveries the worker nacked, because the handler asked it to or panicked.
+    pub failed: u64,
+
+    /// True if the worker has not exited yet.
+    pub running: bool,
+}
+
+#[derive(Default)]
+struct Counters {
+    processed: AtomicU64,
+    failed: AtomicU64,
+    running: AtomicBool,
+}
+
+impl Counters {
+    fn snapshot(&self) -> WorkerStats {
+        WorkerStats {
+            processed: self.processed.load(Ordering::SeqCst),
+            failed: self.failed.load(Ordering::SeqCst),
+            running: self.running.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Threads processing a consumer's deliveries, started by
+/// [`Consumer::run_workers`](struct.Consumer.html#method.run_workers) or
+/// [`Consumer::run_workers_scoped`](struct.Consumer.html#method.run_workers_scoped).
+///
+/// Each worker takes the next delivery from the consumer, runs the handler on it, and then acks
+/// or nacks it according to the handler's [`HandlerResult`](enum.HandlerResult.html). If the
+/// handler panics, the delivery is nacked (and requeued, unless
+/// [`WorkerPoolOptions::requeue_on_panic`](struct.WorkerPoolOptions.html#structfield.requeue_on_panic)
+/// is false) and the worker carries on.
+///
+/// The workers exit once the consumer yields its final message (e.g., because it was cancelled,
+/// or because its channel or connection closed), after handling every delivery that preceded
+/// it. [`join`](#method.join) waits for that; [`stop`](#method.stop) tells the workers to exit
+/// after the delivery they are handling, leaving any others in the consumer's
+/// [`receiver`](struct.Consumer.html#method.receiver). Dropping a `WorkerPool` stops it and waits
+/// for its workers to exit.
+///
+/// # Example
+///
+/// ```rust
+/// use amiquip::{Consumer, HandlerResult, Result, WorkerStats};
+///
+/// fn process(consumer: &Consumer) -> Result<Vec<WorkerStats>> {
+///     let pool = consumer.run_workers(4, |delivery| match std::str::from_utf8(&delivery.body) {
+///         Ok(text) => {
+///             println!("{}", text);
+///             HandlerResult::Ack
+///         }
+///         Err(_) => HandlerResult::Nack { requeue: false },
+///     })?;
+///     // Runs until the consumer is cancelled or its channel closes.
+///     Ok(pool.join())
+/// }
+/// ```
+pub struct WorkerPool {
+    // Dropped to tell the workers to stop.
+    stop_tx: Option<Sender<()>>,
+    // Disconnected once every worker has exited. Taken once we no longer need to wait for them.
+    exited_rx: Option<Receiver<()>>,
+    counters: Vec<Arc<Counters>>,
+}
+
+impl fmt::Debug for WorkerPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WorkerPool")
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        drop(self.stop_tx.take());
+        self.wait();
+    }
+}
+
+impl WorkerPool {
+    pub(crate) fn start<F>(
+        rx: Receiver<ConsumerMessage>,
+        acker: Acker,
+        ack_policy: AckPolicy,
+        options: WorkerPoolOptions,
+        handler: F,
+    ) -> Result<WorkerPool>
+    where
+        F: Fn(Delivery) -> HandlerResult + Send + Sync + 'static,
+    {
+        WorkerPool::start_with(
+            rx,
+            acker,
+            ack_policy,
+            options,
+            handler,
+            |builder, worker| builder.spawn(move || worker.run()).map(drop),
+        )
+    }
+
+    pub(crate) fn start_scoped<'scope, 'env, F>(
+        scope: &'scope Scope<'scope, 'env>,
+        rx: Receiver<ConsumerMessage>,
+        acker: Acker,
+        ack_policy: AckPolicy,
+        options: WorkerPoolOptions,
+        handler: F,
+    ) -> Result<WorkerPool>
+    where
+        F: Fn(Delivery) -> HandlerResult + Send + Sync + 'scope,
+    {
+        WorkerPool::start_with(
+            rx,
+            acker,
+            ack_policy,
+            options,
+            handler,
+            |builder, worker| builder.spawn_scoped(scope, move || worker.run()).map(drop),
+        )
+    }
+
+    fn start_with<'a, F, S>(
+        rx: Receiver<ConsumerMessage>,
+        acker: Acker,
+        ack_policy: AckPolicy,
+        options: WorkerPoolOptions,
+        handler: F,
+        mut spawn: S,
+    ) -> Result<WorkerPool>
+    where
+        F: Fn(Delivery) -> HandlerResult + Send + Sync + 'a,
+        S: FnMut(thread::Builder, Worker<'a>) -> io::Result<()>,
+    {
+        assert!(
+            options.workers > 0,
+            "worker pool requires at least one worker"
+        );
+        // Workers finish deliveries out of order, and a batched (`multiple`) ack of one would
+        // settle earlier deliveries other workers are still handling, so each worker of a pool
+        // acks its own deliveries.
+        let ack_policy = match ack_policy {
+            AckPolicy::Batched { .. } if options.workers > 1 => AckPolicy::Manual,
+            ack_policy => ack_policy,
+        };
+        let (stop_tx, stop_rx) = crossbeam_channel::bounded(0);
+        let (exited_tx, exited_rx) = crossbeam_channel::bounded(0);
+        let handler: Arc<Handler<'a>> = Arc::new(handler);
+
+        // If spawning a worker fails, dropping `pool` stops the ones already running and waits
+        // for them to exit.
+        let mut pool = WorkerPool {
+            stop_tx: Some(stop_tx),
+            exited_rx: Some(exited_rx),
+            counters: Vec::with_capacity(options.workers),
+        };
+        for i in 0..options.workers {
+            let counters = Arc::new(Counters::default());
+            counters.running.store(true, Ordering::SeqCst);
+            let worker = Worker {
+                handler: Arc::clone(&handler),
+                rx: rx.clone(),
+                stop_rx: stop_rx.clone(),
+                acker: acker.clone(),
+                ack_policy,
+                requeue_on_panic: options.requeue_on_panic,
+                counters: Arc::clone(&counters),
+                _exited_tx: exited_tx.clone(),
+            };
+            let builder = thread::Builder::new().name(format!("amiquip-worker-{}", i));
+            spawn(builder, worker).context(ForkFailedSnafu)?;
+            pool.counters.push(counters);
+        }
+        Ok(pool)
+    }
+
+    // Wait until every worker has exited, unless we have already given up on them.
+    fn wait(&mut self) {
+        if let Some(exited_rx) = self.exited_rx.take() {
+            // Nothing is ever sent on `exited_rx`; it only disconnects.
+            let _ = exited_rx.recv();
+        }
+    }
+
+    /// What each worker has done so far, in the order the workers were started.
+    pub fn stats(&self) -> Vec<WorkerStats> {
+        self.counters.iter().map(|c| c.snapshot()).collect()
+    }
+
+    /// Returns true until every worker has exited.
+    pub fn is_running(&self) -> bool {
+        self.counters
+            .iter()
+            .any(|c| c.running.load(Ordering::SeqCst))
+    }
+
+    /// Tell the workers to exit once they finish the delivery they are handling (if any), and
+    /// wait until they have or `deadline` passes. Deliveries the workers have not taken stay in
+    /// the consumer's receiver.
+    ///
+    /// Returns what each worker did. Workers still running at the deadline (i.e., whose handler
+    /// had not returned) have [`running`](struct.WorkerStats.html#structfield.running) set; they
+    /// are left to finish in the background, and still settle their delivery when they do.
+    pub fn stop(mut self, deadline: Instant) -> Vec<WorkerStats> {
+        drop(self.stop_tx.take());
+        if let Some(exited_rx) = self.exited_rx.take() {
+            match exited_rx.recv_deadline(deadline) {
+                Err(RecvTimeoutError::Disconnected) => (),
+                // Nothing is ever sent on `exited_rx`; it only disconnects.
+                Ok(()) | Err(RecvTimeoutError::Timeout) => {
+                    warn!("worker pool stop deadline passed with workers still running")
+                }
+            }
+        }
+        self.stats()
+    }
+
+    /// Wait for the workers to exit after handling every delivery, which happens once the
+    /// consumer yields its final message. Returns what each worker did.
+    pub fn join(mut self) -> Vec<WorkerStats> {
+        self.wait();
+        self.stats()
+    }
+}
+
+type Handler<'a> = dyn Fn(Delivery) -> HandlerResult + Send + Sync + 'a;
+
+struct Worker<'a> {
+    handler: Arc<Handler<'a>>,
+    rx: Receiver<ConsumerMessage>,
+    stop_rx: Receiver<()>,
+    acker: Acker,
+    ack_policy: AckPolicy,
+    requeue_on_panic: bool,
+    counters: Arc<Counters>,
+    // Held (and dropped when the worker exits, after everything above) so the pool can wait for
+    // all workers.
+    _exited_tx: Sender<()>,
+}
+
+impl Drop for Worker<'_> {
+    fn drop(&mut self) {
+        self.counters.running.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Worker<'_> {
+    fn run(self) {
+        loop {
+            // Don't take another delivery once we've been told to stop, even if one is ready.
+            if let Err(TryRecvError::Disconnected) = self.stop_rx.try_recv() {
+                return;
+            }
+            let message = select! {
+                recv(self.rx) -> message => match message {
+                    Ok(message) => message,
+                    // Another worker took the consumer's final message.
+                    Err(_) => return,
+                },
+                recv(self.stop_rx) -> _ => return,
+            };
+            let delivery = match message {
+                ConsumerMessage::Delivery(delivery) => delivery,
+                ConsumerMessage::DeliveryStream(stream) => match stream.read_whole() {
+                    (delivery, Ok(())) => delivery,
+                    (delivery, Err(err)) => {
+                        debug!("worker failed to read streamed delivery: {}", err);
+                        self.requeue(delivery);
+                        continue;
+                    }
+                },
+                message => {
+                    debug!("worker exiting after final consumer message {:?}", message);
+                    return;
+                }
+            };
+            self.handle(delivery);
+        }
+    }
+
+    // Give back a delivery whose body could not be read. The channel has most likely closed, in
+    // which case the server requeues it anyway.
+    fn requeue(&self, delivery: Delivery) {
+        let delivery_tag = delivery.delivery_tag();
+        if let Err(err) = self.acker.nack(delivery, true) {
+            debug!(
+                "worker failed to requeue delivery {}: {}",
+                delivery_tag, err
+            );
+        }
+    }
+
+    fn handle(&self, mut delivery: Delivery) {
+        let delivery_tag = delivery.delivery_tag();
+        // The handler takes the delivery, so keep a copy (without cloning the body) to settle it.
+        let body = mem::take(&mut delivery.body);
+        let settle = delivery.clone();
+        delivery.body = body;
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| (self.handler)(delivery))) {
+            Ok(result) => result,
+            Err(_) => {
+                error!("worker handler panicked on delivery {}", delivery_tag);
+                HandlerResult::Nack {
+                    requeue: self.requeue_on_panic,
+                }
+            }
+        };
+        let settled = match result {
+            HandlerResult::Ack => {
+                self.counters.processed.fetch_add(1, Ordering::SeqCst);
+                match self.ack_policy {
+                    AckPolicy::Manual => self.acker.ack(settle),
+                    AckPolicy::Batched {
+                        max_count,
+                        max_delay,
+                    } => self.acker.batched_ack(delivery_tag, max_count, max_delay),
+                }
+            }
+            HandlerResult::Nack { requeue } => {
+                self.counters.failed.fetch_add(1, Ordering::SeqCst);
+                self.acker.nack(settle, requeue)
+            }
+        };
+        if let Err(err) = settled {
+            debug!("worker failed to settle delivery {}: {}", delivery_tag, err);
+        }
+    }
+}